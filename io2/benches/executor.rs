@@ -0,0 +1,171 @@
+//! Overhead of the executor and the allocator.
+//!
+//! Run with `cargo bench -p io2`. Each iteration of the executor benches starts an executor and runs a batch of
+//! operations on it, `executor_startup` is the cost of an empty run that is included in each of them. Dividing the
+//! rest by the batch size gives the cost of a single operation, e.g. to compare it with another runtime measured the
+//! same way on the same machine.
+
+#![feature(allocator_api)]
+
+use std::alloc::{Allocator, Global, Layout};
+use std::hint::black_box;
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, Bencher, Criterion};
+use io2::executor::{spawn, submit_raw, ExecutorConfig};
+use io2::local_alloc::{LocalAlloc, LocalArena};
+use io2::time::sleep;
+use io_uring::opcode;
+
+const BATCH: usize = 1000;
+
+fn executor_startup(b: &mut Bencher) {
+    b.iter(|| ExecutorConfig::new().run(async {}).unwrap());
+}
+
+fn spawn_and_join(b: &mut Bencher) {
+    b.iter(|| {
+        ExecutorConfig::new()
+            .run(async {
+                for i in 0..BATCH {
+                    black_box(spawn(async move { i }).await);
+                }
+            })
+            .unwrap()
+    });
+}
+
+fn spawn_many(b: &mut Bencher) {
+    b.iter(|| {
+        ExecutorConfig::new()
+            .run(async {
+                let handles = (0..BATCH)
+                    .map(|i| spawn(async move { i }))
+                    .collect::<Vec<_>>();
+                for handle in handles {
+                    black_box(handle.await);
+                }
+            })
+            .unwrap()
+    });
+}
+
+fn nop_io_sequential(b: &mut Bencher) {
+    b.iter(|| {
+        ExecutorConfig::new()
+            .run(async {
+                for _ in 0..BATCH {
+                    assert_eq!(unsafe { submit_raw(opcode::Nop::new().build()) }.await, 0);
+                }
+            })
+            .unwrap()
+    });
+}
+
+fn nop_io_concurrent(b: &mut Bencher) {
+    b.iter(|| {
+        ExecutorConfig::new()
+            .run(async {
+                let handles = (0..BATCH)
+                    .map(|_| {
+                        spawn(async { unsafe { submit_raw(opcode::Nop::new().build()) }.await })
+                    })
+                    .collect::<Vec<_>>();
+                for handle in handles {
+                    assert_eq!(handle.await, 0);
+                }
+            })
+            .unwrap()
+    });
+}
+
+fn nop_io_deep_queue(b: &mut Bencher) {
+    // Completions are routed by the io slot in user_data, this is the cost of the completion path with the ring full of
+    // operations. Each iteration runs DEEP_QUEUE * OPS_PER_TASK operations.
+    const DEEP_QUEUE: usize = 4096;
+    const OPS_PER_TASK: usize = 16;
+    b.iter(|| {
+        ExecutorConfig::new()
+            .ring_depth(4096)
+            .run(async {
+                let handles = (0..DEEP_QUEUE)
+                    .map(|_| {
+                        spawn(async {
+                            for _ in 0..OPS_PER_TASK {
+                                assert_eq!(
+                                    unsafe { submit_raw(opcode::Nop::new().build()) }.await,
+                                    0
+                                );
+                            }
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                for handle in handles {
+                    handle.await;
+                }
+            })
+            .unwrap()
+    });
+}
+
+fn timers(b: &mut Bencher) {
+    b.iter(|| {
+        ExecutorConfig::new()
+            .run(async {
+                let handles = (0..BATCH)
+                    .map(|i| spawn(sleep(Duration::from_micros(i as u64 % 100))))
+                    .collect::<Vec<_>>();
+                for handle in handles {
+                    handle.await;
+                }
+            })
+            .unwrap()
+    });
+}
+
+fn alloc_free<A: Allocator>(alloc: &A) {
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let ptrs = (0..BATCH)
+        .map(|_| alloc.allocate(layout).unwrap())
+        .collect::<Vec<_>>();
+    for ptr in ptrs {
+        unsafe { alloc.deallocate(black_box(ptr).cast(), layout) };
+    }
+}
+
+fn alloc_local(b: &mut Bencher) {
+    let alloc = LocalAlloc::new();
+    // Keeps a page alive so the iterations don't allocate pages from the system.
+    let _keep = Vec::<u8, _>::with_capacity_in(64, alloc);
+    b.iter(|| alloc_free(&alloc));
+}
+
+fn alloc_global(b: &mut Bencher) {
+    b.iter(|| alloc_free(&Global));
+}
+
+fn alloc_arena(b: &mut Bencher) {
+    let mut arena = LocalArena::new();
+    b.iter(|| {
+        for i in 0..BATCH {
+            black_box(arena.alloc([i; 8]));
+        }
+        arena.reset();
+    });
+}
+
+fn benches(c: &mut Criterion) {
+    c.bench_function("executor_startup", executor_startup);
+    c.bench_function("spawn_and_join", spawn_and_join);
+    c.bench_function("spawn_many", spawn_many);
+    c.bench_function("nop_io_sequential", nop_io_sequential);
+    c.bench_function("nop_io_concurrent", nop_io_concurrent);
+    c.bench_function("nop_io_deep_queue", nop_io_deep_queue);
+    c.bench_function("timers", timers);
+    c.bench_function("alloc_local", alloc_local);
+    c.bench_function("alloc_global", alloc_global);
+    c.bench_function("alloc_arena", alloc_arena);
+}
+
+criterion_group!(executor, benches);
+criterion_main!(executor);
@@ -0,0 +1,221 @@
+//! Geometry and management operations of block devices.
+
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::fs::file::File;
+
+// From linux/fs.h
+const BLKDISCARD: libc::c_ulong = 0x1277;
+const BLKZEROOUT: libc::c_ulong = 0x127F;
+
+const SECTOR_SIZE: u64 = 512;
+
+/// A block device, e.g. `/dev/nvme0n1` or a partition of it.
+///
+/// The geometry and queue limits are read from sysfs when the device is opened.
+pub struct Device {
+    file: File,
+    size: u64,
+    logical_block_size: u32,
+    physical_block_size: u32,
+    min_io_size: u32,
+    optimal_io_size: u32,
+    max_io_size: u64,
+    discard_granularity: u32,
+    max_discard_size: u64,
+    max_write_zeroes_size: u64,
+    rotational: bool,
+}
+
+impl Device {
+    /// Opens the block device at `path`, `flags` are passed to open(2) e.g. `libc::O_RDWR | libc::O_DIRECT`.
+    ///
+    /// Fails with ENOTBLK if `path` isn't a block device.
+    pub async fn open(path: &Path, flags: i32) -> io::Result<Self> {
+        let file = File::open(path, flags | libc::O_CLOEXEC, 0)?.await?;
+        let statx = file.statx().await?;
+        if u32::from(statx.stx_mode) & libc::S_IFMT != libc::S_IFBLK {
+            return Err(io::Error::from_raw_os_error(libc::ENOTBLK));
+        }
+
+        let dev = PathBuf::from(format!(
+            "/sys/dev/block/{}:{}",
+            statx.stx_rdev_major, statx.stx_rdev_minor
+        ));
+        // Partitions don't have a queue directory, their limits are the ones of the whole device.
+        let queue = if read_sysfs_u64(&dev.join("partition")).await.is_ok() {
+            dev.join("../queue")
+        } else {
+            dev.join("queue")
+        };
+        let queue_u32 = |name: &'static str| {
+            let path = queue.join(name);
+            async move { u32::try_from(read_sysfs_u64(&path).await?).map_err(io::Error::other) }
+        };
+
+        Ok(Self {
+            size: read_sysfs_u64(&dev.join("size")).await? * SECTOR_SIZE,
+            logical_block_size: queue_u32("logical_block_size").await?,
+            physical_block_size: queue_u32("physical_block_size").await?,
+            min_io_size: queue_u32("minimum_io_size").await?,
+            optimal_io_size: queue_u32("optimal_io_size").await?,
+            max_io_size: read_sysfs_u64(&queue.join("max_sectors_kb")).await? * 1024,
+            discard_granularity: queue_u32("discard_granularity").await?,
+            max_discard_size: read_sysfs_u64(&queue.join("discard_max_bytes")).await?,
+            // Not present on old kernels.
+            max_write_zeroes_size: read_sysfs_u64(&queue.join("write_zeroes_max_bytes"))
+                .await
+                .unwrap_or(0),
+            rotational: read_sysfs_u64(&queue.join("rotational")).await? != 0,
+            file,
+        })
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    pub fn into_file(self) -> File {
+        self.file
+    }
+
+    /// Size of the device in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Smallest unit the device can address, offsets and lengths of direct io have to be aligned to this.
+    pub fn logical_block_size(&self) -> u32 {
+        self.logical_block_size
+    }
+
+    /// Smallest unit the device can write without a read-modify-write cycle.
+    pub fn physical_block_size(&self) -> u32 {
+        self.physical_block_size
+    }
+
+    /// Preferred minimum io size of the device, e.g. the chunk size of a RAID device.
+    pub fn min_io_size(&self) -> u32 {
+        self.min_io_size
+    }
+
+    /// Preferred io size of the device for sustained io, zero if the device doesn't report it.
+    pub fn optimal_io_size(&self) -> u32 {
+        self.optimal_io_size
+    }
+
+    /// Largest io the kernel sends to the device in one request, larger io is split.
+    pub fn max_io_size(&self) -> u64 {
+        self.max_io_size
+    }
+
+    /// Discards are done in units of this size, zero if the device doesn't support discard.
+    pub fn discard_granularity(&self) -> u32 {
+        self.discard_granularity
+    }
+
+    /// Largest range a single discard request can cover, zero if the device doesn't support discard.
+    pub fn max_discard_size(&self) -> u64 {
+        self.max_discard_size
+    }
+
+    /// Largest range the device can zero without data transfer, zero if the device doesn't support it.
+    ///
+    /// [Device::write_zeroes] still works in this case, the kernel writes zeroed buffers instead.
+    pub fn max_write_zeroes_size(&self) -> u64 {
+        self.max_write_zeroes_size
+    }
+
+    pub fn rotational(&self) -> bool {
+        self.rotational
+    }
+
+    /// Tells the device the data in `range` is no longer needed, reading it afterwards returns unspecified data.
+    ///
+    /// `range` has to be aligned to [Device::logical_block_size].
+    pub async fn discard(&self, range: Range<u64>) -> io::Result<()> {
+        self.range_ioctl(BLKDISCARD, range).await
+    }
+
+    /// Zeroes the data in `range`, `range` has to be aligned to [Device::logical_block_size].
+    pub async fn write_zeroes(&self, range: Range<u64>) -> io::Result<()> {
+        self.range_ioctl(BLKZEROOUT, range).await
+    }
+
+    async fn range_ioctl(&self, request: libc::c_ulong, range: Range<u64>) -> io::Result<()> {
+        let align = u64::from(self.logical_block_size);
+        if range.start > range.end || range.start % align != 0 || range.end % align != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "range {:?} isn't aligned to the logical block size {}",
+                    range, align
+                ),
+            ));
+        }
+        if range.start == range.end {
+            return Ok(());
+        }
+
+        // These ioctls block until the device is done.
+        let arg = [range.start, range.end - range.start];
+        unsafe { self.file.ioctl(request, arg) }.await?;
+        Ok(())
+    }
+}
+
+// Values in sysfs are small so a single read is enough.
+async fn read_sysfs_u64(path: &Path) -> io::Result<u64> {
+    let file = File::open(path, libc::O_RDONLY | libc::O_CLOEXEC, 0)?.await?;
+    let mut buf = [0; 64];
+    let n = file.read(&mut buf, 0).await?;
+    std::str::from_utf8(&buf[..n])
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("failed to parse {}", path.display()),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::executor::ExecutorConfig;
+
+    use super::*;
+
+    #[test]
+    fn test_device() {
+        ExecutorConfig::new()
+            .run(async {
+                match Device::open(Path::new("Cargo.toml"), libc::O_RDONLY).await {
+                    Err(e) => assert_eq!(e.raw_os_error(), Some(libc::ENOTBLK)),
+                    Ok(_) => panic!("opened a regular file as a block device"),
+                }
+
+                // Needs permission to open a block device, the rest is skipped without it.
+                let dev = match Device::open(Path::new("/dev/loop0"), libc::O_RDONLY).await {
+                    Ok(dev) => dev,
+                    Err(e)
+                        if matches!(
+                            e.kind(),
+                            io::ErrorKind::PermissionDenied | io::ErrorKind::NotFound
+                        ) || e.raw_os_error() == Some(libc::ENXIO) =>
+                    {
+                        return;
+                    }
+                    Err(e) => panic!("failed to open /dev/loop0: {}", e),
+                };
+                assert!(dev.logical_block_size().is_power_of_two());
+                assert!(dev.physical_block_size() >= dev.logical_block_size());
+                assert!(dev.max_io_size() > 0);
+                let err = dev.discard(1..4096).await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            })
+            .unwrap();
+    }
+}
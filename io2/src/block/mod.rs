@@ -0,0 +1,283 @@
+//! Block level io.
+//!
+//! [BlockFile] stores fixed size blocks with checksums. Each block on disk is `block_size` bytes, the last 4 bytes of
+//! the block hold the CRC32C of the rest of it in little endian. Checksums are computed when writing and verified when
+//! reading so corruption is detected as an error instead of being handed to the caller.
+//!
+//! [Device] exposes the geometry of a block device so direct io can be aligned correctly.
+//!
+//! [nvme] has raw NVMe passthrough for applications that manage a namespace directly.
+
+pub mod device;
+pub mod nvme;
+
+use std::alloc::{Allocator, Layout};
+use std::io;
+use std::path::Path;
+
+use crate::fs::file::{Close, File, SyncAll};
+use crate::io_buffer::{IoBuffer, IoBufferView};
+
+pub use device::Device;
+
+const CHECKSUM_SIZE: usize = 4;
+// Alignment of the block buffers, this is enough for direct io on most devices.
+const BUFFER_ALIGN: usize = 4096;
+
+pub struct BlockFile {
+    file: File,
+    block_size: usize,
+}
+
+impl BlockFile {
+    /// `block_size` has to be a power of two and at least 512 bytes.
+    pub fn new(file: File, block_size: usize) -> Self {
+        assert!(
+            block_size.is_power_of_two() && block_size >= 512,
+            "block size must be a power of two and at least 512"
+        );
+        Self { file, block_size }
+    }
+
+    pub async fn open(path: &Path, flags: i32, mode: i32, block_size: usize) -> io::Result<Self> {
+        let file = File::open(path, flags, mode)?.await?;
+        Ok(Self::new(file, block_size))
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Number of bytes of user data a block can hold.
+    pub fn payload_size(&self) -> usize {
+        self.block_size - CHECKSUM_SIZE
+    }
+
+    /// Number of complete blocks in the file.
+    pub async fn num_blocks(&self) -> io::Result<u64> {
+        let size = self.file.file_size().await?;
+        Ok(size / u64::try_from(self.block_size).unwrap())
+    }
+
+    /// Reads the block at `index` and returns its payload after verifying the checksum.
+    ///
+    /// Returns an error with `InvalidData` kind if the checksum doesn't match.
+    pub async fn read_block<A: Allocator>(
+        &self,
+        index: u64,
+        alloc: A,
+    ) -> io::Result<IoBufferView<A>> {
+        let mut buf = self.alloc_block(alloc)?;
+        self.file
+            .read_exact(buf.as_mut_slice(), self.block_offset(index))
+            .await?;
+
+        let (payload, checksum) = buf.as_slice().split_at(self.payload_size());
+        let expected = u32::from_le_bytes(checksum.try_into().unwrap());
+        let actual = crc32c(payload);
+        if actual != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "checksum mismatch in block {}, expected {:#010x} but found {:#010x}",
+                    index, expected, actual
+                ),
+            ));
+        }
+
+        let payload_size = self.payload_size();
+        Ok(buf.view(0, payload_size))
+    }
+
+    /// Writes `payload` as the block at `index`, it is padded with zeroes if it is shorter than
+    /// [BlockFile::payload_size].
+    pub async fn write_block<A: Allocator>(
+        &self,
+        index: u64,
+        payload: &[u8],
+        alloc: A,
+    ) -> io::Result<()> {
+        assert!(
+            payload.len() <= self.payload_size(),
+            "payload doesn't fit in a block"
+        );
+        let payload_size = self.payload_size();
+        let mut buf = self.alloc_block(alloc)?;
+        let data = buf.as_mut_slice();
+        data[..payload.len()].copy_from_slice(payload);
+        let checksum = crc32c(&data[..payload_size]);
+        data[payload_size..].copy_from_slice(&checksum.to_le_bytes());
+
+        self.file
+            .write_all(buf.as_slice(), self.block_offset(index))
+            .await
+    }
+
+    pub fn sync_all(&self) -> SyncAll {
+        self.file.sync_all()
+    }
+
+    pub fn close(self) -> Close {
+        self.file.close()
+    }
+
+    fn block_offset(&self, index: u64) -> u64 {
+        index
+            .checked_mul(u64::try_from(self.block_size).unwrap())
+            .unwrap()
+    }
+
+    fn alloc_block<A: Allocator>(&self, alloc: A) -> io::Result<IoBuffer<A>> {
+        let layout = Layout::from_size_align(self.block_size, BUFFER_ALIGN).unwrap();
+        IoBuffer::new(layout, alloc)
+            .map_err(|_| io::Error::new(io::ErrorKind::OutOfMemory, "failed to allocate block"))
+    }
+}
+
+/// Computes the CRC32C (Castagnoli) checksum of `data`.
+///
+/// Uses the crc32 instructions on x86_64 (SSE4.2) and aarch64 if the cpu supports them.
+pub fn crc32c(data: &[u8]) -> u32 {
+    crc32c_append(0, data)
+}
+
+/// Continues a checksum returned by [crc32c] with more data, so data that arrives in pieces can be checksummed.
+pub fn crc32c_append(crc: u32, data: &[u8]) -> u32 {
+    #[cfg(target_arch = "x86_64")]
+    if std::arch::is_x86_feature_detected!("sse4.2") {
+        return unsafe { crc32c_sse42(crc, data) };
+    }
+    #[cfg(target_arch = "aarch64")]
+    if std::arch::is_aarch64_feature_detected!("crc") {
+        return unsafe { crc32c_arm(crc, data) };
+    }
+    crc32c_sw(crc, data)
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.2")]
+unsafe fn crc32c_sse42(crc: u32, data: &[u8]) -> u32 {
+    use std::arch::x86_64::{_mm_crc32_u64, _mm_crc32_u8};
+
+    let mut crc = u64::from(!crc);
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        crc = _mm_crc32_u64(crc, u64::from_le_bytes(chunk.try_into().unwrap()));
+    }
+    let mut crc = crc as u32;
+    for &b in chunks.remainder() {
+        crc = _mm_crc32_u8(crc, b);
+    }
+    !crc
+}
+
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "crc")]
+unsafe fn crc32c_arm(crc: u32, data: &[u8]) -> u32 {
+    use std::arch::aarch64::{__crc32cb, __crc32cd};
+
+    let mut crc = !crc;
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        crc = __crc32cd(crc, u64::from_le_bytes(chunk.try_into().unwrap()));
+    }
+    for &b in chunks.remainder() {
+        crc = __crc32cb(crc, b);
+    }
+    !crc
+}
+
+fn crc32c_sw(crc: u32, data: &[u8]) -> u32 {
+    // Reflected polynomial of CRC32C.
+    const POLY: u32 = 0x82F63B78;
+    const TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut j = 0;
+            while j < 8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ POLY
+                } else {
+                    crc >> 1
+                };
+                j += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+
+    let mut crc = !crc;
+    for &b in data {
+        crc = TABLE[usize::from((crc as u8) ^ b)] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::executor::ExecutorConfig;
+    use crate::local_alloc::LocalAlloc;
+
+    use super::*;
+
+    #[test]
+    fn test_crc32c() {
+        assert_eq!(crc32c(b"123456789"), 0xE3069283);
+        let data = (0..1000u32).map(|i| (i * 7) as u8).collect::<Vec<_>>();
+        for len in [0, 1, 7, 8, 9, 100, 1000] {
+            assert_eq!(crc32c(&data[..len]), crc32c_sw(0, &data[..len]));
+        }
+        assert_eq!(
+            crc32c_append(crc32c(&data[..100]), &data[100..]),
+            crc32c(&data)
+        );
+    }
+
+    #[test]
+    fn test_block_file() {
+        let path = std::env::temp_dir().join(format!("io2_test_block_{}", std::process::id()));
+        let test_path = path.clone();
+        ExecutorConfig::new()
+            .run(async move {
+                let file = BlockFile::open(
+                    &test_path,
+                    libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC | libc::O_CLOEXEC,
+                    0o644,
+                    4096,
+                )
+                .await
+                .unwrap();
+                file.write_block(0, b"first", LocalAlloc::new())
+                    .await
+                    .unwrap();
+                file.write_block(1, &[9; 4092], LocalAlloc::new())
+                    .await
+                    .unwrap();
+                assert_eq!(file.num_blocks().await.unwrap(), 2);
+
+                let block = file.read_block(0, LocalAlloc::new()).await.unwrap();
+                assert_eq!(block.len(), 4092);
+                assert_eq!(&block.as_slice()[..5], b"first");
+                assert!(block.as_slice()[5..].iter().all(|&b| b == 0));
+
+                // Flip a bit in the second block.
+                let raw = File::open(&test_path, libc::O_RDWR, 0)
+                    .unwrap()
+                    .await
+                    .unwrap();
+                raw.write_all(&[8], 4096 + 100).await.unwrap();
+
+                let err = match file.read_block(1, LocalAlloc::new()).await {
+                    Err(e) => e,
+                    Ok(_) => panic!("corruption wasn't detected"),
+                };
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            })
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
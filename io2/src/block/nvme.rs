@@ -0,0 +1,337 @@
+//! NVMe passthrough using IORING_OP_URING_CMD.
+//!
+//! Commands are sent to the generic NVMe character device of a namespace (e.g. `/dev/ng0n1`), bypassing the block
+//! layer. NVMe commands need 128 byte submission entries and 32 byte completion entries, they are submitted to the
+//! executor's uring cmd ring so the executor has to be configured with
+//! [ExecutorConfig::uring_cmd_ring](crate::executor::ExecutorConfig::uring_cmd_ring).
+
+use std::io;
+use std::marker::PhantomData;
+use std::os::fd::RawFd;
+use std::path::Path;
+
+use io_uring::{opcode, types::Fd};
+
+use crate::executor::{submit_raw_cmd, uring_cmd_ring_enabled};
+use crate::fs::file::File;
+
+// From linux/nvme_ioctl.h
+const NVME_IOCTL_ID: libc::c_ulong = 0x4E40;
+const NVME_URING_CMD_IO: u32 = 0xC0484E80;
+const NVME_URING_CMD_ADMIN: u32 = 0xC0484E82;
+
+const NVME_ADMIN_IDENTIFY: u8 = 0x06;
+const NVME_CMD_WRITE: u8 = 0x01;
+const NVME_CMD_READ: u8 = 0x02;
+const NVME_ZNS_CMD_ZONE_APPEND: u8 = 0x7D;
+
+const IDENTIFY_CNS_NAMESPACE: u32 = 0x00;
+const IDENTIFY_CNS_CONTROLLER: u32 = 0x01;
+
+/// Size of the data returned by identify commands.
+pub const IDENTIFY_SIZE: usize = 4096;
+
+/// struct nvme_uring_cmd from linux/nvme_ioctl.h
+#[repr(C)]
+#[derive(Default)]
+struct NvmeUringCmd {
+    opcode: u8,
+    flags: u8,
+    rsvd1: u16,
+    nsid: u32,
+    cdw2: u32,
+    cdw3: u32,
+    metadata: u64,
+    addr: u64,
+    metadata_len: u32,
+    data_len: u32,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+    cdw14: u32,
+    cdw15: u32,
+    timeout_ms: u32,
+    rsvd2: u32,
+}
+
+const _: () = assert!(std::mem::size_of::<NvmeUringCmd>() == 72);
+
+/// An NVMe command, fields are named after the NVMe specification.
+///
+/// Data and metadata buffers are passed separately when the command is executed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NvmeCommand {
+    pub opcode: u8,
+    pub nsid: u32,
+    pub cdw2: u32,
+    pub cdw3: u32,
+    pub cdw10: u32,
+    pub cdw11: u32,
+    pub cdw12: u32,
+    pub cdw13: u32,
+    pub cdw14: u32,
+    pub cdw15: u32,
+    /// Zero means the default timeout of the driver.
+    pub timeout_ms: u32,
+}
+
+pub struct NvmeDevice {
+    file: File,
+    nsid: u32,
+    _non_send: PhantomData<*mut ()>,
+}
+
+impl NvmeDevice {
+    /// Opens the generic character device of an NVMe namespace, e.g. `/dev/ng0n1`.
+    ///
+    /// Fails with [io::ErrorKind::Unsupported] if the executor doesn't have a uring cmd ring.
+    pub async fn open(path: &Path) -> io::Result<Self> {
+        check_uring_cmd_ring()?;
+        let file = File::open(path, libc::O_RDWR | libc::O_CLOEXEC, 0)?.await?;
+        let nsid = unsafe { file.ioctl_value(NVME_IOCTL_ID, 0) }.await?;
+        Self::from_file(file, u32::try_from(nsid).unwrap())
+    }
+
+    fn from_file(file: File, nsid: u32) -> io::Result<Self> {
+        check_uring_cmd_ring()?;
+        Ok(Self {
+            file,
+            nsid,
+            _non_send: PhantomData,
+        })
+    }
+
+    /// Namespace id of the device.
+    pub fn nsid(&self) -> u32 {
+        self.nsid
+    }
+
+    /// Runs the identify controller command, `buf` has to be [IDENTIFY_SIZE] bytes.
+    pub async fn identify_controller(&self, buf: &mut [u8]) -> io::Result<()> {
+        assert_eq!(buf.len(), IDENTIFY_SIZE);
+        let cmd = NvmeCommand {
+            opcode: NVME_ADMIN_IDENTIFY,
+            cdw10: IDENTIFY_CNS_CONTROLLER,
+            ..Default::default()
+        };
+        self.admin(cmd, buf).await.map(|_| ())
+    }
+
+    /// Runs the identify namespace command for this namespace, `buf` has to be [IDENTIFY_SIZE] bytes.
+    pub async fn identify_namespace(&self, buf: &mut [u8]) -> io::Result<()> {
+        assert_eq!(buf.len(), IDENTIFY_SIZE);
+        let cmd = NvmeCommand {
+            opcode: NVME_ADMIN_IDENTIFY,
+            nsid: self.nsid,
+            cdw10: IDENTIFY_CNS_NAMESPACE,
+            ..Default::default()
+        };
+        self.admin(cmd, buf).await.map(|_| ())
+    }
+
+    /// Reads `num_blocks` logical blocks starting from `lba` into `data`, and their metadata into `metadata` if the
+    /// namespace is formatted with separate metadata.
+    pub async fn read(
+        &self,
+        lba: u64,
+        num_blocks: u16,
+        data: &mut [u8],
+        metadata: Option<&mut [u8]>,
+    ) -> io::Result<()> {
+        let cmd = self.rw_command(NVME_CMD_READ, lba, num_blocks);
+        let metadata = metadata.map(|m| (m.as_mut_ptr(), m.len()));
+        self.execute(
+            NVME_URING_CMD_IO,
+            cmd,
+            (data.as_mut_ptr(), data.len()),
+            metadata,
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Writes `num_blocks` logical blocks starting from `lba`, see [NvmeDevice::read].
+    pub async fn write(
+        &self,
+        lba: u64,
+        num_blocks: u16,
+        data: &[u8],
+        metadata: Option<&[u8]>,
+    ) -> io::Result<()> {
+        let cmd = self.rw_command(NVME_CMD_WRITE, lba, num_blocks);
+        let metadata = metadata.map(|m| (m.as_ptr() as *mut u8, m.len()));
+        self.execute(
+            NVME_URING_CMD_IO,
+            cmd,
+            (data.as_ptr() as *mut u8, data.len()),
+            metadata,
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Appends `num_blocks` logical blocks to the zone starting at `zone_start_lba` on a zoned namespace.
+    ///
+    /// Returns the lba the data was written at, which is decided by the device.
+    pub async fn zone_append(
+        &self,
+        zone_start_lba: u64,
+        num_blocks: u16,
+        data: &[u8],
+    ) -> io::Result<u64> {
+        let cmd = self.rw_command(NVME_ZNS_CMD_ZONE_APPEND, zone_start_lba, num_blocks);
+        self.execute(
+            NVME_URING_CMD_IO,
+            cmd,
+            (data.as_ptr() as *mut u8, data.len()),
+            None,
+        )
+        .await
+    }
+
+    /// Runs an admin command and returns the command specific result (dword 0 of the completion).
+    ///
+    /// `data` is read or written by the device depending on the command.
+    pub async fn admin(&self, cmd: NvmeCommand, data: &mut [u8]) -> io::Result<u64> {
+        self.execute(
+            NVME_URING_CMD_ADMIN,
+            cmd,
+            (data.as_mut_ptr(), data.len()),
+            None,
+        )
+        .await
+    }
+
+    /// Runs an io command and returns the command specific result of the completion, see [NvmeDevice::admin].
+    pub async fn io(
+        &self,
+        cmd: NvmeCommand,
+        data: &mut [u8],
+        metadata: Option<&mut [u8]>,
+    ) -> io::Result<u64> {
+        let metadata = metadata.map(|m| (m.as_mut_ptr(), m.len()));
+        self.execute(
+            NVME_URING_CMD_IO,
+            cmd,
+            (data.as_mut_ptr(), data.len()),
+            metadata,
+        )
+        .await
+    }
+
+    fn rw_command(&self, opcode: u8, lba: u64, num_blocks: u16) -> NvmeCommand {
+        assert!(num_blocks > 0, "num_blocks must be greater than zero");
+        NvmeCommand {
+            opcode,
+            nsid: self.nsid,
+            cdw10: lba as u32,
+            cdw11: (lba >> 32) as u32,
+            // Number of blocks is zero based.
+            cdw12: u32::from(num_blocks - 1),
+            ..Default::default()
+        }
+    }
+
+    // The buffers are borrowed by the callers for the duration of this future.
+    async fn execute(
+        &self,
+        cmd_op: u32,
+        cmd: NvmeCommand,
+        data: (*mut u8, usize),
+        metadata: Option<(*mut u8, usize)>,
+    ) -> io::Result<u64> {
+        let (metadata_ptr, metadata_len) = metadata.unwrap_or((std::ptr::null_mut(), 0));
+        let raw = NvmeUringCmd {
+            opcode: cmd.opcode,
+            nsid: cmd.nsid,
+            cdw2: cmd.cdw2,
+            cdw3: cmd.cdw3,
+            metadata: metadata_ptr as u64,
+            addr: data.0 as u64,
+            metadata_len: u32::try_from(metadata_len).unwrap(),
+            data_len: u32::try_from(data.1).unwrap(),
+            cdw10: cmd.cdw10,
+            cdw11: cmd.cdw11,
+            cdw12: cmd.cdw12,
+            cdw13: cmd.cdw13,
+            cdw14: cmd.cdw14,
+            cdw15: cmd.cdw15,
+            timeout_ms: cmd.timeout_ms,
+            ..Default::default()
+        };
+        let mut cmd_bytes = [0u8; 80];
+        cmd_bytes[..72].copy_from_slice(unsafe {
+            std::slice::from_raw_parts(&raw as *const NvmeUringCmd as *const u8, 72)
+        });
+        let entry = opcode::UringCmd80::new(Fd(self.file.fd), cmd_op)
+            .cmd(cmd_bytes)
+            .build();
+
+        // The buffers are borrowed by the caller until this returns.
+        let (status, result) = unsafe { submit_raw_cmd(entry) }.await;
+        match status {
+            0 => Ok(result),
+            s if s < 0 => Err(io::Error::from_raw_os_error(-s)),
+            s => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("nvme command failed with status {:#x}", s),
+            )),
+        }
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.file.fd
+    }
+}
+
+fn check_uring_cmd_ring() -> io::Result<()> {
+    if uring_cmd_ring_enabled() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "nvme passthrough needs an executor with ExecutorConfig::uring_cmd_ring",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::executor::ExecutorConfig;
+
+    use super::*;
+
+    #[test]
+    fn test_nvme_not_a_device() {
+        ExecutorConfig::new()
+            .uring_cmd_ring(8)
+            .run(async {
+                // A regular file doesn't support NVMe commands, this checks the error is routed back to the command.
+                let file = File::open(Path::new("Cargo.toml"), libc::O_RDONLY, 0)
+                    .unwrap()
+                    .await
+                    .unwrap();
+                assert!(unsafe { file.ioctl_value(NVME_IOCTL_ID, 0) }.await.is_err());
+                let dev = NvmeDevice::from_file(file, 1).unwrap();
+                let mut buf = vec![0; IDENTIFY_SIZE];
+                let err = dev.identify_controller(&mut buf).await.unwrap_err();
+                assert!(err.raw_os_error().is_some(), "{:?}", err);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_nvme_needs_uring_cmd_ring() {
+        ExecutorConfig::new()
+            .run(async {
+                let file = File::open(Path::new("Cargo.toml"), libc::O_RDONLY, 0)
+                    .unwrap()
+                    .await
+                    .unwrap();
+                let err = NvmeDevice::from_file(file, 1).err().unwrap();
+                assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+            })
+            .unwrap();
+    }
+}
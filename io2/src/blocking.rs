@@ -0,0 +1,177 @@
+//! Running blocking syscalls that don't have an io_uring equivalent without blocking the executor.
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use crate::sync::event_fd::EventFd;
+
+// Jobs wait in the queue when this many threads are busy.
+const MAX_THREADS: usize = 64;
+// Threads exit after they didn't get a job for this long.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// Threads that run blocking jobs, shared by all executors of the process.
+struct Pool {
+    jobs: VecDeque<Job>,
+    num_threads: usize,
+    num_idle: usize,
+}
+
+static POOL: Mutex<Pool> = Mutex::new(Pool {
+    jobs: VecDeque::new(),
+    num_threads: 0,
+    num_idle: 0,
+});
+static JOB_QUEUED: Condvar = Condvar::new();
+
+// Queues a job that must not panic, starts a new thread if there aren't enough idle ones.
+fn spawn_job(job: Job) -> io::Result<()> {
+    let mut pool = POOL.lock().unwrap();
+    pool.jobs.push_back(job);
+    if pool.jobs.len() > pool.num_idle && pool.num_threads < MAX_THREADS {
+        let spawned = std::thread::Builder::new()
+            .name("io2-blocking".to_owned())
+            .spawn(run_worker);
+        match spawned {
+            Ok(_) => pool.num_threads += 1,
+            // The job runs once a running thread gets to it.
+            Err(_) if pool.num_threads > 0 => {}
+            Err(e) => {
+                pool.jobs.pop_back();
+                return Err(e);
+            }
+        }
+    }
+    JOB_QUEUED.notify_one();
+    Ok(())
+}
+
+fn run_worker() {
+    let mut pool = POOL.lock().unwrap();
+    loop {
+        if let Some(job) = pool.jobs.pop_front() {
+            drop(pool);
+            job();
+            pool = POOL.lock().unwrap();
+            continue;
+        }
+        pool.num_idle += 1;
+        let (guard, res) = JOB_QUEUED.wait_timeout(pool, IDLE_TIMEOUT).unwrap();
+        pool = guard;
+        pool.num_idle -= 1;
+        if res.timed_out() && pool.jobs.is_empty() {
+            pool.num_threads -= 1;
+            return;
+        }
+    }
+}
+
+/// Runs `f` on a thread of a shared pool and waits for it to finish.
+///
+/// The thread signals completion with an eventfd that is polled through io_uring so the waiting task doesn't need to be
+/// polled until `f` is done. The pool starts threads as needed up to a limit and keeps them around for a while, calls
+/// that come in when all of them are busy wait for a thread to become free, so `f` shouldn't block indefinitely.
+///
+/// The returned future can be dropped at any time, no memory of it is handed to the kernel. `f` still runs to
+/// completion in that case and its output is dropped.
+pub(crate) async fn run_blocking<T, F>(f: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let efd = Arc::new(EventFd::new()?);
+    let signal = efd.clone();
+
+    let out = Arc::new(Mutex::new(None));
+    let thread_out = out.clone();
+    spawn_job(Box::new(move || {
+        // Catch the panic so the waiting task is always signaled, it is resumed on the executor thread.
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+        *thread_out.lock().unwrap() = Some(res);
+        signal.write(1).expect("failed to signal eventfd");
+    }))?;
+
+    efd.wait().await?;
+
+    let out = out.lock().unwrap().take();
+    match out.expect("blocking thread signaled before writing its result") {
+        Ok(out) => Ok(out),
+        Err(panic) => std::panic::resume_unwind(panic),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+
+    use crate::executor::ExecutorConfig;
+
+    use super::*;
+
+    #[test]
+    fn test_run_blocking() {
+        ExecutorConfig::new()
+            .run(async {
+                let thread_id = std::thread::current().id();
+                let other = run_blocking(move || std::thread::current().id() != thread_id)
+                    .await
+                    .unwrap();
+                assert!(other);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_run_blocking_reuses_threads() {
+        ExecutorConfig::new()
+            .run(async {
+                let mut threads = Vec::new();
+                for _ in 0..200 {
+                    let id = run_blocking(|| std::thread::current().id()).await.unwrap();
+                    if !threads.contains(&id) {
+                        threads.push(id);
+                    }
+                }
+                // Other tests use the pool at the same time, so the jobs don't all run on one thread.
+                assert!(threads.len() <= MAX_THREADS);
+
+                let jobs = (0..8)
+                    .map(|i| crate::executor::spawn(run_blocking(move || i * 2)))
+                    .collect::<Vec<_>>();
+                let mut sum = 0;
+                for job in jobs {
+                    sum += job.await.unwrap();
+                }
+                assert_eq!(sum, 56);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_run_blocking_dropped() {
+        ExecutorConfig::new()
+            .run(async {
+                let done = Arc::new(Mutex::new(false));
+                let thread_done = done.clone();
+                let mut job = Box::pin(run_blocking(move || {
+                    std::thread::sleep(std::time::Duration::from_millis(20));
+                    *thread_done.lock().unwrap() = true;
+                }));
+                let mut timeout = Box::pin(crate::time::sleep(std::time::Duration::from_millis(1)));
+                // Gives up on the job while it is running.
+                std::future::poll_fn(|cx| {
+                    assert!(job.as_mut().poll(cx).is_pending());
+                    timeout.as_mut().poll(cx)
+                })
+                .await;
+                drop(job);
+                crate::time::sleep(std::time::Duration::from_millis(50)).await;
+                assert!(*done.lock().unwrap());
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,511 @@
+//! Userspace page cache for files that are opened with O_DIRECT.
+//!
+//! [PageCache] keeps a fixed number of page sized frames that are allocated once from [LocalAlloc]. Pages are pinned
+//! while they are used, pinned pages are never evicted. Modified pages are marked dirty and written back when they are
+//! evicted, when [PageCache::flush] is called, or periodically by the task started with [PageCache::spawn_writeback].
+//! Writing pages back doesn't change the size of the file, the parts of pages that are past its end are dropped.
+//!
+//! Eviction uses the CLOCK algorithm, a page that was used since the clock hand last passed it gets another round.
+
+use std::alloc::Layout;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::rc::{Rc, Weak};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::executor::{self, JoinHandle};
+use crate::fs::File;
+use crate::io_buffer::IoBuffer;
+use crate::local_alloc::LocalAlloc;
+use crate::slab;
+use crate::time::sleep;
+
+struct Frame {
+    page: Option<u64>,
+    // None while the page is being read into it.
+    buf: Option<IoBuffer<LocalAlloc>>,
+    pin_count: usize,
+    dirty: bool,
+    // Set while a copy of the page is being written, the frame isn't evicted meanwhile so a reload can't see old data.
+    writing: bool,
+    referenced: bool,
+}
+
+struct State {
+    frames: Vec<Frame, LocalAlloc>,
+    pages: HashMap<u64, usize>,
+    clock_hand: usize,
+    // Size of the file the last time it was checked, pages that go past it are checked again before writing them.
+    file_size: Option<u64>,
+    // Page sized buffers that dirty pages are copied to while they are written, reused between writes.
+    write_bufs: Vec<IoBuffer<LocalAlloc>, LocalAlloc>,
+    // Tasks waiting for a page to be loaded, unpinned or written.
+    waiters: VecDeque<slab::Key, LocalAlloc>,
+}
+
+impl State {
+    fn notify_waiters(&mut self) {
+        for task_id in self.waiters.drain(..) {
+            executor::notify_task(task_id);
+        }
+    }
+
+    // Picks a frame to reuse, free frames first.
+    fn find_victim(&mut self) -> Option<usize> {
+        if let Some(idx) = self.frames.iter().position(|f| f.page.is_none()) {
+            return Some(idx);
+        }
+        let n = self.frames.len();
+        // Two rounds, the first one might only clear referenced bits.
+        for _ in 0..2 * n {
+            let idx = self.clock_hand;
+            self.clock_hand = (self.clock_hand + 1) % n;
+            let frame = &mut self.frames[idx];
+            if frame.pin_count > 0 || frame.buf.is_none() || frame.writing {
+                continue;
+            }
+            if frame.referenced {
+                frame.referenced = false;
+                continue;
+            }
+            return Some(idx);
+        }
+        None
+    }
+}
+
+struct Shared {
+    file: File,
+    page_size: usize,
+    state: RefCell<State>,
+}
+
+/// A fixed size cache of the pages of a file, cloning it gives another handle to the same cache.
+#[derive(Clone)]
+pub struct PageCache {
+    shared: Rc<Shared, LocalAlloc>,
+}
+
+enum PinAction {
+    Pinned(usize),
+    Load(usize, IoBuffer<LocalAlloc>),
+    WriteBack(usize),
+    Wait,
+}
+
+impl PageCache {
+    /// Creates a cache of `num_pages` frames over `file`.
+    ///
+    /// Frames are aligned to `page_size`, which has to be a multiple of the direct io alignment of the file.
+    pub fn new(file: File, page_size: usize, num_pages: usize) -> Self {
+        assert!(
+            page_size.is_power_of_two(),
+            "page_size must be a power of two"
+        );
+        assert!(num_pages > 0, "num_pages must be greater than zero");
+        let layout = Layout::from_size_align(page_size, page_size).unwrap();
+        let mut frames = Vec::with_capacity_in(num_pages, LocalAlloc::new());
+        for _ in 0..num_pages {
+            frames.push(Frame {
+                page: None,
+                buf: Some(IoBuffer::new(layout, LocalAlloc::new()).unwrap()),
+                pin_count: 0,
+                dirty: false,
+                writing: false,
+                referenced: false,
+            });
+        }
+        Self {
+            shared: Rc::new_in(
+                Shared {
+                    file,
+                    page_size,
+                    state: RefCell::new(State {
+                        frames,
+                        pages: HashMap::new(),
+                        clock_hand: 0,
+                        file_size: None,
+                        write_bufs: Vec::new_in(LocalAlloc::new()),
+                        waiters: VecDeque::new_in(LocalAlloc::new()),
+                    }),
+                },
+                LocalAlloc::new(),
+            ),
+        }
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.shared.page_size
+    }
+
+    pub fn file(&self) -> &File {
+        &self.shared.file
+    }
+
+    /// Returns the page at `page * page_size`, reading it from the file if it isn't cached.
+    ///
+    /// Waits for a page to be unpinned if all frames are pinned. Parts of the page that are past the end of the file
+    /// read as zeros.
+    pub async fn pin(&self, page: u64) -> io::Result<PinnedPage> {
+        loop {
+            let action = {
+                let mut state = self.shared.state.borrow_mut();
+                self.pin_action(&mut state, page)
+            };
+            match action {
+                PinAction::Pinned(frame) => return Ok(self.pinned(page, frame)),
+                PinAction::Load(frame, buf) => return self.load(page, frame, buf).await,
+                PinAction::WriteBack(frame) => self.write_back(frame).await?,
+                PinAction::Wait => {
+                    WaitForChange {
+                        cache: self,
+                        registered: false,
+                    }
+                    .await
+                }
+            }
+        }
+    }
+
+    fn pin_action(&self, state: &mut State, page: u64) -> PinAction {
+        if let Some(&idx) = state.pages.get(&page) {
+            let frame = &mut state.frames[idx];
+            if frame.buf.is_none() {
+                return PinAction::Wait;
+            }
+            frame.pin_count += 1;
+            frame.referenced = true;
+            return PinAction::Pinned(idx);
+        }
+
+        let idx = match state.find_victim() {
+            Some(idx) => idx,
+            None => return PinAction::Wait,
+        };
+        let frame = &mut state.frames[idx];
+        if frame.dirty {
+            return PinAction::WriteBack(idx);
+        }
+        let old_page = frame.page.replace(page);
+        frame.pin_count = 1;
+        frame.referenced = true;
+        let buf = frame.buf.take().unwrap();
+        if let Some(old_page) = old_page {
+            state.pages.remove(&old_page);
+        }
+        state.pages.insert(page, idx);
+        PinAction::Load(idx, buf)
+    }
+
+    fn pinned(&self, page: u64, frame: usize) -> PinnedPage {
+        PinnedPage {
+            cache: self.clone(),
+            page,
+            frame,
+        }
+    }
+
+    async fn load(
+        &self,
+        page: u64,
+        frame: usize,
+        buf: IoBuffer<LocalAlloc>,
+    ) -> io::Result<PinnedPage> {
+        let mut guard = LoadGuard {
+            cache: self,
+            page,
+            frame,
+            buf: Some(buf),
+            loaded: false,
+        };
+        let res = read_page(
+            &self.shared.file,
+            guard.buf.as_mut().unwrap().as_mut_slice(),
+            page * self.page_size() as u64,
+        )
+        .await;
+        guard.loaded = res.is_ok();
+        drop(guard);
+        res.map(|()| self.pinned(page, frame))
+    }
+
+    // Writes a copy of the page in the frame if it is dirty, so the page stays readable and writable meanwhile.
+    async fn write_back(&self, frame: usize) -> io::Result<()> {
+        let (page, mut copy) = {
+            let mut state = self.shared.state.borrow_mut();
+            let f = &state.frames[frame];
+            if !f.dirty || f.writing {
+                return Ok(());
+            }
+            let page = f.page.unwrap();
+            let copy = match state.write_bufs.pop() {
+                Some(copy) => copy,
+                None => {
+                    let layout =
+                        Layout::from_size_align(self.page_size(), self.page_size()).unwrap();
+                    IoBuffer::new(layout, LocalAlloc::new()).unwrap()
+                }
+            };
+            let f = &mut state.frames[frame];
+            f.dirty = false;
+            f.writing = true;
+            (page, copy)
+        };
+        let res = self.write_page(page, frame, &mut copy).await;
+        let mut state = self.shared.state.borrow_mut();
+        state.write_bufs.push(copy);
+        let f = &mut state.frames[frame];
+        f.writing = false;
+        if res.is_err() {
+            f.dirty = true;
+        }
+        state.notify_waiters();
+        res
+    }
+
+    // Writes the page in the frame through `copy`, without changing the size of the file.
+    async fn write_page(
+        &self,
+        page: u64,
+        frame: usize,
+        copy: &mut IoBuffer<LocalAlloc>,
+    ) -> io::Result<()> {
+        let offset = page * self.page_size() as u64;
+        let end = offset + self.page_size() as u64;
+        let cached_size = self.shared.state.borrow().file_size;
+        let file_size = match cached_size {
+            Some(size) if size >= end => size,
+            _ => {
+                let size = self.shared.file.file_size().await?;
+                self.shared.state.borrow_mut().file_size = Some(size);
+                size
+            }
+        };
+        if offset >= file_size {
+            return Ok(());
+        }
+        {
+            let state = self.shared.state.borrow();
+            let src = state.frames[frame].buf.as_ref().unwrap().as_slice();
+            copy.as_mut_slice().copy_from_slice(src);
+        }
+        self.shared.file.write_all(copy.as_slice(), offset).await?;
+        // Direct io writes whole pages, cut off the part past the end of the file again.
+        if end > file_size {
+            self.shared.file.set_len(file_size).await?;
+        }
+        Ok(())
+    }
+
+    /// Writes all dirty pages and syncs the file.
+    pub async fn flush(&self) -> io::Result<()> {
+        let num_frames = self.shared.state.borrow().frames.len();
+        for frame in 0..num_frames {
+            // Wait for a write that is already running so the page is durable when this returns.
+            while self.shared.state.borrow().frames[frame].writing {
+                WaitForChange {
+                    cache: self,
+                    registered: false,
+                }
+                .await;
+            }
+            self.write_back(frame).await?;
+        }
+        self.shared.file.sync_all().await
+    }
+
+    /// Spawns a task that writes dirty pages every `interval`, it stops when all handles to the cache are dropped.
+    ///
+    /// The pages are written without syncing the file, errors are logged and the pages are retried on the next round.
+    pub fn spawn_writeback(&self, interval: Duration) -> JoinHandle<()> {
+        let shared = Rc::downgrade(&self.shared);
+        executor::spawn(writeback(shared, interval))
+    }
+}
+
+async fn writeback(shared: Weak<Shared, LocalAlloc>, interval: Duration) {
+    loop {
+        sleep(interval).await;
+        let cache = match shared.upgrade() {
+            Some(shared) => PageCache { shared },
+            None => return,
+        };
+        let num_frames = cache.shared.state.borrow().frames.len();
+        for frame in 0..num_frames {
+            if let Err(e) = cache.write_back(frame).await {
+                log::error!("page cache writeback failed: {}", e);
+            }
+        }
+    }
+}
+
+async fn read_page(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    let mut pos = 0;
+    while pos < buf.len() {
+        match file.read(&mut buf[pos..], offset + pos as u64).await {
+            Ok(0) => break,
+            Ok(n) => pos += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    buf[pos..].fill(0);
+    Ok(())
+}
+
+// Puts the buffer back into the frame when loading a page finishes or is dropped, and frees the frame if the page
+// wasn't loaded.
+struct LoadGuard<'cache> {
+    cache: &'cache PageCache,
+    page: u64,
+    frame: usize,
+    buf: Option<IoBuffer<LocalAlloc>>,
+    loaded: bool,
+}
+
+impl Drop for LoadGuard<'_> {
+    fn drop(&mut self) {
+        let mut state = self.cache.shared.state.borrow_mut();
+        let f = &mut state.frames[self.frame];
+        f.buf = self.buf.take();
+        if !self.loaded {
+            f.page = None;
+            f.pin_count = 0;
+            state.pages.remove(&self.page);
+        }
+        state.notify_waiters();
+    }
+}
+
+/// A page that stays in the cache until this is dropped.
+pub struct PinnedPage {
+    cache: PageCache,
+    page: u64,
+    frame: usize,
+}
+
+impl PinnedPage {
+    pub fn page(&self) -> u64 {
+        self.page
+    }
+
+    /// Runs `f` with the contents of the page, `f` must not use the cache.
+    pub fn read<T>(&self, f: impl FnOnce(&[u8]) -> T) -> T {
+        let state = self.cache.shared.state.borrow();
+        f(state.frames[self.frame].buf.as_ref().unwrap().as_slice())
+    }
+
+    /// Runs `f` with the contents of the page and marks it dirty, `f` must not use the cache.
+    pub fn write<T>(&self, f: impl FnOnce(&mut [u8]) -> T) -> T {
+        let mut state = self.cache.shared.state.borrow_mut();
+        let frame = &mut state.frames[self.frame];
+        frame.dirty = true;
+        f(frame.buf.as_mut().unwrap().as_mut_slice())
+    }
+}
+
+impl Drop for PinnedPage {
+    fn drop(&mut self) {
+        let mut state = self.cache.shared.state.borrow_mut();
+        state.frames[self.frame].pin_count -= 1;
+        state.notify_waiters();
+    }
+}
+
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+struct WaitForChange<'cache> {
+    cache: &'cache PageCache,
+    registered: bool,
+}
+
+impl<'cache> Future for WaitForChange<'cache> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let fut = self.get_mut();
+        let task_id = executor::current_task_id();
+        let mut state = fut.cache.shared.state.borrow_mut();
+        if fut.registered {
+            state.waiters.retain(|&id| id != task_id);
+            return Poll::Ready(());
+        }
+        fut.registered = true;
+        state.waiters.push_back(task_id);
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::executor::{spawn, ExecutorConfig};
+
+    #[test]
+    fn test_page_cache() {
+        let path = std::env::temp_dir().join(format!("io2_test_page_cache_{}", std::process::id()));
+        let mut data = vec![1u8; 4096];
+        data.extend_from_slice(&[2u8; 4096]);
+        data.extend_from_slice(&[3u8; 100]);
+        std::fs::write(&path, &data).unwrap();
+
+        let test_path = path.clone();
+        ExecutorConfig::new()
+            .run(async move {
+                let file = File::open(&test_path, libc::O_RDWR | libc::O_CLOEXEC, 0)
+                    .unwrap()
+                    .await
+                    .unwrap();
+                let cache = PageCache::new(file, 4096, 2);
+
+                let p0 = cache.pin(0).await.unwrap();
+                assert!(p0.read(|data| data.iter().all(|&b| b == 1)));
+                let p2 = cache.pin(2).await.unwrap();
+                p2.read(|data| {
+                    assert!(data[..100].iter().all(|&b| b == 3));
+                    assert!(data[100..].iter().all(|&b| b == 0));
+                });
+                p2.write(|data| data[50..100].fill(4));
+
+                // Both frames are pinned, pinning another page waits for one of them.
+                let done = Rc::new(Cell::new(false));
+                let waiter = spawn({
+                    let cache = cache.clone();
+                    let done = done.clone();
+                    async move {
+                        let p1 = cache.pin(1).await.unwrap();
+                        assert!(p1.read(|data| data.iter().all(|&b| b == 2)));
+                        done.set(true);
+                    }
+                });
+                sleep(Duration::from_millis(5)).await;
+                assert!(!done.get());
+                // The dirty page is written back when it is evicted.
+                drop(p2);
+                waiter.await;
+                let on_disk = std::fs::read(&test_path).unwrap();
+                assert_eq!(&on_disk[8192 + 50..], &[4u8; 50]);
+
+                let writeback = cache.spawn_writeback(Duration::from_millis(1));
+                p0.write(|data| data[0] = 9);
+                drop(p0);
+                sleep(Duration::from_millis(20)).await;
+                assert_eq!(std::fs::read(&test_path).unwrap()[0], 9);
+
+                cache.pin(0).await.unwrap().write(|data| data[1] = 8);
+                cache.flush().await.unwrap();
+                assert_eq!(std::fs::read(&test_path).unwrap()[1], 8);
+                // Writing back the partial last page doesn't extend the file.
+                assert_eq!(std::fs::metadata(&test_path).unwrap().len(), 8192 + 100);
+                drop(cache);
+                writeback.await;
+            })
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
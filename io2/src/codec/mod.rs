@@ -0,0 +1,196 @@
+//! Framing of messages over stream sockets.
+//!
+//! [LengthDelimited] prefixes each frame with its length as a big endian u32. This is meant as the base layer of
+//! simple RPC protocols, the frames are opaque bytes.
+//!
+//! [websocket] implements the framing of RFC 6455 for realtime services.
+
+pub mod websocket;
+
+use std::io;
+
+use crate::local_alloc::LocalAlloc;
+use crate::net::{self, socket, StreamSocket};
+
+const HEADER_SIZE: usize = 4;
+const MIN_READ_SIZE: usize = 8 * 1024;
+
+/// Sends and receives length delimited frames over a stream.
+///
+/// `S` can be a reference to a stream, so two of these can be created over the same stream to receive frames in one task
+/// while sending in another.
+pub struct LengthDelimited<S: StreamSocket> {
+    stream: S,
+    max_frame_size: usize,
+    read_buf: Vec<u8, LocalAlloc>,
+    // Range of read_buf that holds data that is read but not consumed yet.
+    start: usize,
+    end: usize,
+    // Length of the frame that was lent out by the last call to next_frame, it is consumed on the next call.
+    lent: usize,
+    write_buf: Vec<u8, LocalAlloc>,
+    done: bool,
+}
+
+impl<S: StreamSocket> LengthDelimited<S> {
+    /// Default maximum frame size is 8 MiB.
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            max_frame_size: 8 * 1024 * 1024,
+            read_buf: Vec::new_in(LocalAlloc::new()),
+            start: 0,
+            end: 0,
+            lent: 0,
+            write_buf: Vec::new_in(LocalAlloc::new()),
+            done: false,
+        }
+    }
+
+    /// Frames larger than this are rejected with an `InvalidData` error when sending or receiving.
+    pub fn max_frame_size(mut self, max_frame_size: usize) -> Self {
+        assert!(
+            u32::try_from(max_frame_size).is_ok(),
+            "max_frame_size must fit in a u32"
+        );
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Returns the stream, data that was read but not returned as a frame yet is lost.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Returns the next frame, `None` means the peer closed the stream at a frame boundary.
+    ///
+    /// After an error is returned, this always returns `None` since the position in the stream is unknown.
+    pub async fn next_frame(&mut self) -> Option<io::Result<&[u8]>> {
+        if self.done {
+            return None;
+        }
+        self.start += std::mem::take(&mut self.lent);
+
+        let fd = net::stream_fd(&self.stream);
+        loop {
+            let available = self.end - self.start;
+            let needed = if available < HEADER_SIZE {
+                HEADER_SIZE
+            } else {
+                let header = &self.read_buf[self.start..self.start + HEADER_SIZE];
+                let len = usize::try_from(u32::from_be_bytes(header.try_into().unwrap())).unwrap();
+                if len > self.max_frame_size {
+                    self.done = true;
+                    return Some(Err(frame_too_large(len, self.max_frame_size)));
+                }
+                if available >= HEADER_SIZE + len {
+                    self.lent = HEADER_SIZE + len;
+                    let frame = self.start + HEADER_SIZE..self.start + HEADER_SIZE + len;
+                    return Some(Ok(&self.read_buf[frame]));
+                }
+                HEADER_SIZE + len
+            };
+
+            // Move the partial frame to the start of the buffer and make room for the rest of it.
+            self.read_buf.copy_within(self.start..self.end, 0);
+            self.end = available;
+            self.start = 0;
+            let len = self.read_buf.len().max(needed).max(MIN_READ_SIZE);
+            self.read_buf.resize(len, 0);
+
+            match socket::Read::new(fd, &mut self.read_buf[self.end..])
+                .deadlines(net::stream_deadlines(&self.stream))
+                .await
+            {
+                Ok(0) => {
+                    self.done = true;
+                    if available == 0 {
+                        return None;
+                    }
+                    return Some(Err(io::Error::from(io::ErrorKind::UnexpectedEof)));
+                }
+                Ok(n) => self.end += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+
+    /// Sends `frame` with its length prefix.
+    pub async fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        if frame.len() > self.max_frame_size {
+            return Err(frame_too_large(frame.len(), self.max_frame_size));
+        }
+        // Copy the frame after the header so it is sent with a single write in most cases.
+        self.write_buf.clear();
+        self.write_buf
+            .extend_from_slice(&u32::try_from(frame.len()).unwrap().to_be_bytes());
+        self.write_buf.extend_from_slice(frame);
+        socket::write_all(
+            net::stream_fd(&self.stream),
+            Some(net::stream_deadlines(&self.stream)),
+            &self.write_buf,
+        )
+        .await
+    }
+}
+
+fn frame_too_large(len: usize, max_frame_size: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "frame of {} bytes is larger than the maximum frame size {}",
+            len, max_frame_size
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::executor::{spawn, ExecutorConfig};
+    use crate::net::unix::UnixStream;
+
+    use super::*;
+
+    #[test]
+    fn test_length_delimited() {
+        ExecutorConfig::new()
+            .run(async {
+                let (a, b) = UnixStream::pair().unwrap();
+                let large = vec![7u8; 100_000];
+                let sent = large.clone();
+                let sender = spawn(async move {
+                    let mut framed = LengthDelimited::new(&a);
+                    framed.send_frame(b"hello").await.unwrap();
+                    framed.send_frame(b"").await.unwrap();
+                    framed.send_frame(&sent).await.unwrap();
+                    // Split a frame over multiple writes.
+                    a.write_all(&[0, 0, 0, 3, b'a']).await.unwrap();
+                    crate::time::sleep(std::time::Duration::from_millis(5)).await;
+                    a.write_all(b"bc").await.unwrap();
+                    a.write_all(&[0, 2, 0, 0]).await.unwrap();
+                });
+
+                let mut framed = LengthDelimited::new(&b).max_frame_size(100_000);
+                assert_eq!(framed.next_frame().await.unwrap().unwrap(), b"hello");
+                assert_eq!(framed.next_frame().await.unwrap().unwrap(), b"");
+                assert_eq!(framed.next_frame().await.unwrap().unwrap(), &large[..]);
+                assert_eq!(framed.next_frame().await.unwrap().unwrap(), b"abc");
+                let err = framed.next_frame().await.unwrap().unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+                assert!(framed.next_frame().await.is_none());
+                sender.await;
+
+                let err = framed.send_frame(&vec![0; 100_001]).await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            })
+            .unwrap();
+    }
+}
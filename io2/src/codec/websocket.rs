@@ -0,0 +1,560 @@
+//! WebSocket frames (RFC 6455) over an already upgraded stream.
+//!
+//! The HTTP upgrade handshake is left to the caller, [WebSocket] takes over the stream after it. Fragmented messages are
+//! reassembled, pings are answered automatically and a received close frame is echoed back.
+
+use std::io;
+
+use crate::local_alloc::LocalAlloc;
+use crate::net::{self, socket, StreamSocket};
+
+const MIN_READ_SIZE: usize = 8 * 1024;
+/// Control frames can't be fragmented and their payload is limited to 125 bytes.
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+/// Which end of the connection this is, clients mask the frames they send and servers don't.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// A message received from the peer.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Message<'a> {
+    Text(&'a str),
+    Binary(&'a [u8]),
+    /// The peer closed the connection, the close frame is already echoed back.
+    Close(Option<CloseFrame<'a>>),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct CloseFrame<'a> {
+    pub code: u16,
+    pub reason: &'a str,
+}
+
+struct FrameHeader {
+    fin: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    header_len: usize,
+    payload_len: usize,
+}
+
+/// Sends and receives WebSocket messages over a stream.
+pub struct WebSocket<S: StreamSocket> {
+    stream: S,
+    role: Role,
+    max_message_size: usize,
+    read_buf: Vec<u8, LocalAlloc>,
+    // Range of read_buf that holds data that is read but not consumed yet.
+    start: usize,
+    end: usize,
+    // Length of the frame that was lent out by the last call to next_message, it is consumed on the next call.
+    lent: usize,
+    // Opcode and payload of a fragmented message that is being received.
+    fragmented: Option<u8>,
+    message: Vec<u8, LocalAlloc>,
+    write_buf: Vec<u8, LocalAlloc>,
+    mask_state: u64,
+    close_sent: bool,
+    done: bool,
+}
+
+impl<S: StreamSocket> WebSocket<S> {
+    /// Default maximum message size is 16 MiB.
+    pub fn new(stream: S, role: Role) -> Self {
+        Self {
+            stream,
+            role,
+            max_message_size: 16 * 1024 * 1024,
+            read_buf: Vec::new_in(LocalAlloc::new()),
+            start: 0,
+            end: 0,
+            lent: 0,
+            fragmented: None,
+            message: Vec::new_in(LocalAlloc::new()),
+            write_buf: Vec::new_in(LocalAlloc::new()),
+            mask_state: random_seed(),
+            close_sent: false,
+            done: false,
+        }
+    }
+
+    /// Messages larger than this, including all of their fragments, are rejected with an `InvalidData` error.
+    pub fn max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Returns the next message, `None` means the connection is closed.
+    ///
+    /// After an error or a close message is returned, this always returns `None`.
+    pub async fn next_message(&mut self) -> Option<io::Result<Message<'_>>> {
+        if self.done {
+            return None;
+        }
+        self.start += std::mem::take(&mut self.lent);
+        if self.fragmented.is_none() {
+            self.message.clear();
+        }
+
+        let fd = net::stream_fd(&self.stream);
+        loop {
+            let available = self.end - self.start;
+            let needed = match self.parse_header() {
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+                Ok(None) => available + 1,
+                Ok(Some(header)) if available < header.header_len + header.payload_len => {
+                    header.header_len + header.payload_len
+                }
+                Ok(Some(header)) => {
+                    let payload = self.start + header.header_len
+                        ..self.start + header.header_len + header.payload_len;
+                    if let Some(mask) = header.mask {
+                        apply_mask(&mut self.read_buf[payload.clone()], mask);
+                    }
+                    let frame_len = header.header_len + header.payload_len;
+
+                    match header.opcode {
+                        OP_PING => {
+                            let payload = self.read_buf[payload].to_vec();
+                            self.start += frame_len;
+                            if self.close_sent {
+                                continue;
+                            }
+                            if let Err(e) = self.send_frame(OP_PONG, &payload).await {
+                                self.done = true;
+                                return Some(Err(e));
+                            }
+                        }
+                        OP_PONG => self.start += frame_len,
+                        OP_CLOSE => {
+                            self.done = true;
+                            self.lent = frame_len;
+                            let close = match parse_close(&self.read_buf[payload.clone()]) {
+                                Ok(close) => close,
+                                Err(e) => return Some(Err(e)),
+                            };
+                            if !self.close_sent {
+                                // Echo the status code back as required by the RFC.
+                                let echo = self.read_buf[payload.clone()]
+                                    .get(..2)
+                                    .map(|code| code.to_vec())
+                                    .unwrap_or_default();
+                                if let Err(e) = self.send_frame(OP_CLOSE, &echo).await {
+                                    return Some(Err(e));
+                                }
+                                self.close_sent = true;
+                            }
+                            let close = close.map(|(code, reason)| CloseFrame {
+                                code,
+                                reason: std::str::from_utf8(
+                                    &self.read_buf[payload.start + 2..payload.start + 2 + reason],
+                                )
+                                .unwrap(),
+                            });
+                            return Some(Ok(Message::Close(close)));
+                        }
+                        OP_TEXT | OP_BINARY if self.fragmented.is_some() => {
+                            self.done = true;
+                            return Some(Err(invalid(
+                                "new message started before the fragmented message was finished",
+                            )));
+                        }
+                        OP_TEXT | OP_BINARY if header.fin => {
+                            if let Err(e) =
+                                check_utf8(header.opcode, &self.read_buf[payload.clone()])
+                            {
+                                self.done = true;
+                                return Some(Err(e));
+                            }
+                            self.lent = frame_len;
+                            return Some(Ok(to_message(header.opcode, &self.read_buf[payload])));
+                        }
+                        OP_TEXT | OP_BINARY | OP_CONTINUATION => {
+                            let opcode = match (header.opcode, self.fragmented) {
+                                (OP_CONTINUATION, None) => {
+                                    self.done = true;
+                                    return Some(Err(invalid(
+                                        "continuation frame without a message to continue",
+                                    )));
+                                }
+                                (OP_CONTINUATION, Some(opcode)) => opcode,
+                                (opcode, _) => opcode,
+                            };
+                            if self.message.len() + header.payload_len > self.max_message_size {
+                                self.done = true;
+                                return Some(Err(message_too_large(
+                                    self.message.len() + header.payload_len,
+                                    self.max_message_size,
+                                )));
+                            }
+                            self.message.extend_from_slice(&self.read_buf[payload]);
+                            self.start += frame_len;
+                            if header.fin {
+                                self.fragmented = None;
+                                if let Err(e) = check_utf8(opcode, &self.message) {
+                                    self.done = true;
+                                    return Some(Err(e));
+                                }
+                                return Some(Ok(to_message(opcode, &self.message)));
+                            }
+                            self.fragmented = Some(opcode);
+                        }
+                        _ => unreachable!(),
+                    }
+                    continue;
+                }
+            };
+
+            // Move the partial frame to the start of the buffer and make room for the rest of it.
+            self.read_buf.copy_within(self.start..self.end, 0);
+            self.end = available;
+            self.start = 0;
+            let len = self.read_buf.len().max(needed).max(MIN_READ_SIZE);
+            self.read_buf.resize(len, 0);
+
+            match socket::Read::new(fd, &mut self.read_buf[self.end..])
+                .deadlines(net::stream_deadlines(&self.stream))
+                .await
+            {
+                Ok(0) => {
+                    self.done = true;
+                    if available == 0 && self.fragmented.is_none() {
+                        return None;
+                    }
+                    return Some(Err(io::Error::from(io::ErrorKind::UnexpectedEof)));
+                }
+                Ok(n) => self.end += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+
+    pub async fn send_text(&mut self, text: &str) -> io::Result<()> {
+        self.send_frame(OP_TEXT, text.as_bytes()).await
+    }
+
+    pub async fn send_binary(&mut self, data: &[u8]) -> io::Result<()> {
+        self.send_frame(OP_BINARY, data).await
+    }
+
+    /// Sends a ping, the pong sent back by the peer is consumed by [WebSocket::next_message].
+    pub async fn send_ping(&mut self, payload: &[u8]) -> io::Result<()> {
+        if payload.len() > MAX_CONTROL_PAYLOAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "ping payload can't be longer than 125 bytes",
+            ));
+        }
+        self.send_frame(OP_PING, payload).await
+    }
+
+    /// Starts the closing handshake, [WebSocket::next_message] returns the close message of the peer when it arrives.
+    pub async fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        if self.close_sent {
+            return Ok(());
+        }
+        if reason.len() > MAX_CONTROL_PAYLOAD - 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "close reason can't be longer than 123 bytes",
+            ));
+        }
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        self.send_frame(OP_CLOSE, &payload).await?;
+        self.close_sent = true;
+        Ok(())
+    }
+
+    async fn send_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        if self.close_sent {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "can't send after the close frame is sent",
+            ));
+        }
+
+        self.write_buf.clear();
+        self.write_buf.push(0x80 | opcode);
+        let mask_bit = match self.role {
+            Role::Client => 0x80,
+            Role::Server => 0,
+        };
+        match payload.len() {
+            len if len < 126 => self.write_buf.push(mask_bit | len as u8),
+            len if len <= usize::from(u16::MAX) => {
+                self.write_buf.push(mask_bit | 126);
+                self.write_buf
+                    .extend_from_slice(&u16::try_from(len).unwrap().to_be_bytes());
+            }
+            len => {
+                self.write_buf.push(mask_bit | 127);
+                self.write_buf
+                    .extend_from_slice(&u64::try_from(len).unwrap().to_be_bytes());
+            }
+        }
+        let payload_start = match self.role {
+            Role::Client => {
+                let mask = self.next_mask();
+                self.write_buf.extend_from_slice(&mask);
+                let payload_start = self.write_buf.len();
+                self.write_buf.extend_from_slice(payload);
+                apply_mask(&mut self.write_buf[payload_start..], mask);
+                payload_start
+            }
+            Role::Server => {
+                let payload_start = self.write_buf.len();
+                self.write_buf.extend_from_slice(payload);
+                payload_start
+            }
+        };
+        debug_assert_eq!(self.write_buf.len() - payload_start, payload.len());
+        socket::write_all(
+            net::stream_fd(&self.stream),
+            Some(net::stream_deadlines(&self.stream)),
+            &self.write_buf,
+        )
+        .await
+    }
+
+    /// Parses the header of the frame at the start of the unconsumed data, `None` means more data is needed.
+    fn parse_header(&self) -> io::Result<Option<FrameHeader>> {
+        let buf = &self.read_buf[self.start..self.end];
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        if buf[0] & 0x70 != 0 {
+            return Err(invalid(
+                "reserved bits are set but no extension was negotiated",
+            ));
+        }
+        let fin = buf[0] & 0x80 != 0;
+        let opcode = buf[0] & 0x0f;
+        let masked = buf[1] & 0x80 != 0;
+
+        match opcode {
+            OP_CONTINUATION | OP_TEXT | OP_BINARY => {}
+            OP_CLOSE | OP_PING | OP_PONG => {
+                if !fin || usize::from(buf[1] & 0x7f) > MAX_CONTROL_PAYLOAD {
+                    return Err(invalid(
+                        "control frames can't be fragmented or longer than 125 bytes",
+                    ));
+                }
+            }
+            _ => return Err(invalid("unknown opcode")),
+        }
+        match (self.role, masked) {
+            (Role::Server, false) => return Err(invalid("frames sent by a client must be masked")),
+            (Role::Client, true) => {
+                return Err(invalid("frames sent by a server must not be masked"))
+            }
+            _ => {}
+        }
+
+        let (payload_len, mut header_len) = match buf[1] & 0x7f {
+            126 => match buf.get(2..4) {
+                Some(len) => (u64::from(u16::from_be_bytes(len.try_into().unwrap())), 4),
+                None => return Ok(None),
+            },
+            127 => match buf.get(2..10) {
+                Some(len) => (u64::from_be_bytes(len.try_into().unwrap()), 10),
+                None => return Ok(None),
+            },
+            len => (u64::from(len), 2),
+        };
+        let payload_len = match usize::try_from(payload_len) {
+            Ok(len) if len <= self.max_message_size => len,
+            _ => {
+                return Err(message_too_large(
+                    usize::try_from(payload_len).unwrap_or(usize::MAX),
+                    self.max_message_size,
+                ))
+            }
+        };
+
+        let mask = if masked {
+            match buf.get(header_len..header_len + 4) {
+                Some(mask) => {
+                    header_len += 4;
+                    Some(mask.try_into().unwrap())
+                }
+                None => return Ok(None),
+            }
+        } else {
+            None
+        };
+
+        Ok(Some(FrameHeader {
+            fin,
+            opcode,
+            mask,
+            header_len,
+            payload_len,
+        }))
+    }
+
+    fn next_mask(&mut self) -> [u8; 4] {
+        // xorshift64, masking keys only need to be unpredictable to the peer's intermediaries.
+        let mut x = self.mask_state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.mask_state = x;
+        (x as u32).to_ne_bytes()
+    }
+}
+
+fn check_utf8(opcode: u8, payload: &[u8]) -> io::Result<()> {
+    match opcode {
+        OP_TEXT if std::str::from_utf8(payload).is_err() => {
+            Err(invalid("text message is not valid UTF-8"))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The payload of text messages has to be checked with [check_utf8] first.
+fn to_message(opcode: u8, payload: &[u8]) -> Message<'_> {
+    match opcode {
+        OP_TEXT => Message::Text(std::str::from_utf8(payload).unwrap()),
+        _ => Message::Binary(payload),
+    }
+}
+
+/// Returns the status code and the length of the reason if the close frame has a body.
+fn parse_close(payload: &[u8]) -> io::Result<Option<(u16, usize)>> {
+    match payload {
+        [] => Ok(None),
+        [_] => Err(invalid("close frame body is too short")),
+        [a, b, reason @ ..] => match std::str::from_utf8(reason) {
+            Ok(_) => Ok(Some((u16::from_be_bytes([*a, *b]), reason.len()))),
+            Err(_) => Err(invalid("close reason is not valid UTF-8")),
+        },
+    }
+}
+
+fn apply_mask(buf: &mut [u8], mask: [u8; 4]) {
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+}
+
+fn random_seed() -> u64 {
+    let mut seed = [0u8; 8];
+    let n = unsafe { libc::getrandom(seed.as_mut_ptr() as *mut libc::c_void, seed.len(), 0) };
+    if n != 8 {
+        // Fall back to the clock, xorshift only needs a non-zero seed.
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        return (nanos as u64) | 1;
+    }
+    u64::from_ne_bytes(seed) | 1
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn message_too_large(len: usize, max_message_size: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "message of {} bytes is larger than the maximum message size {}",
+            len, max_message_size
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::executor::{spawn, ExecutorConfig};
+    use crate::net::unix::UnixStream;
+
+    use super::*;
+
+    #[test]
+    fn test_websocket() {
+        ExecutorConfig::new()
+            .run(async {
+                let (a, b) = UnixStream::pair().unwrap();
+                let large = vec![7u8; 70_000];
+                let sent = large.clone();
+                let client = spawn(async move {
+                    let mut ws = WebSocket::new(&a, Role::Client);
+                    ws.send_text("hello").await.unwrap();
+                    ws.send_ping(b"p").await.unwrap();
+                    ws.send_binary(&sent).await.unwrap();
+
+                    // A fragmented text message from the server with a ping in the middle, the pong is sent
+                    // automatically.
+                    assert_eq!(
+                        ws.next_message().await.unwrap().unwrap(),
+                        Message::Text("héllo")
+                    );
+                    assert_eq!(
+                        ws.next_message().await.unwrap().unwrap(),
+                        Message::Close(Some(CloseFrame {
+                            code: 1000,
+                            reason: "bye"
+                        }))
+                    );
+                    assert!(ws.next_message().await.is_none());
+                });
+
+                let mut ws = WebSocket::new(&b, Role::Server);
+                assert_eq!(
+                    ws.next_message().await.unwrap().unwrap(),
+                    Message::Text("hello")
+                );
+                assert_eq!(
+                    ws.next_message().await.unwrap().unwrap(),
+                    Message::Binary(&large[..])
+                );
+
+                let text = "héllo".as_bytes();
+                b.write_all(&[0x01, 2]).await.unwrap();
+                b.write_all(&text[..2]).await.unwrap();
+                b.write_all(&[0x89, 1, b'x']).await.unwrap();
+                b.write_all(&[0x80, u8::try_from(text.len() - 2).unwrap()])
+                    .await
+                    .unwrap();
+                b.write_all(&text[2..]).await.unwrap();
+                ws.close(1000, "bye").await.unwrap();
+
+                // The pong for the ping above, then the echoed close.
+                assert_eq!(
+                    ws.next_message().await.unwrap().unwrap(),
+                    Message::Close(Some(CloseFrame {
+                        code: 1000,
+                        reason: ""
+                    }))
+                );
+                client.await;
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,622 @@
+//! Helpers for running io2 next to another async runtime (e.g. tokio).
+//!
+//! The io2 executor is single threaded and its futures are not `Send`, so it is run on a dedicated thread
+//! and the other runtime talks to it through the `Send` handles in this module.
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io,
+    os::fd::AsRawFd,
+    pin::{pin, Pin},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll, Waker},
+};
+
+use io_uring::{opcode, types::Fd};
+
+use crate::executor::{self, ExecutorConfig, RawIo};
+use crate::sync::EventFd;
+
+struct Shared<T> {
+    queue: VecDeque<T>,
+    waker: Option<Waker>,
+    closed: bool,
+    receiver_dropped: bool,
+}
+
+impl<T> Shared<T> {
+    fn new() -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Self {
+            queue: VecDeque::new(),
+            waker: None,
+            closed: false,
+            receiver_dropped: false,
+        }))
+    }
+
+    fn push(&mut self, val: T) {
+        self.queue.push_back(val);
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Waits for a value of a [Shared], from an io2 task or from another runtime.
+///
+/// The io2 executor doesn't use wakers, so inside of an executor the waker that is left in the [Shared] writes to an
+/// [EventFd] and the task waits for the eventfd through io_uring. This lets the executor sleep until the other thread
+/// pushes a value or closes it.
+fn poll_pop<T>(
+    shared: &Mutex<Shared<T>>,
+    wakeup: &mut Option<Wakeup>,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<Option<T>>> {
+    loop {
+        {
+            let mut shared = shared.lock().unwrap();
+            if let Some(val) = shared.queue.pop_front() {
+                return Poll::Ready(Ok(Some(val)));
+            }
+            if shared.closed {
+                return Poll::Ready(Ok(None));
+            }
+            if !executor::in_executor() {
+                shared.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            let wakeup = match wakeup {
+                Some(wakeup) => wakeup,
+                None => wakeup.insert(Wakeup::new()?),
+            };
+            shared.waker = Some(wakeup.waker.clone());
+        }
+        // A value pushed after the lock is released writes to the eventfd, so it is seen by the poll.
+        ready!(wakeup.as_mut().unwrap().poll_woken())?;
+    }
+}
+
+/// Eventfd that is written to by the waker of a [Shared] and polled by an io2 task.
+struct Wakeup {
+    efd: Arc<EventFd>,
+    waker: Waker,
+    poll: Option<RawIo>,
+}
+
+impl Wakeup {
+    fn new() -> io::Result<Self> {
+        let efd = Arc::new(EventFd::new()?);
+        Ok(Self {
+            waker: Waker::from(efd.clone()),
+            efd,
+            poll: None,
+        })
+    }
+
+    /// Resolves once the waker was woken since the last time this resolved.
+    fn poll_woken(&mut self) -> Poll<io::Result<()>> {
+        loop {
+            if let Some(poll) = self.poll.as_mut() {
+                let res =
+                    ready!(Pin::new(poll).poll(&mut Context::from_waker(&executor::noop_waker())));
+                self.poll = None;
+                if res < 0 {
+                    return Poll::Ready(Err(io::Error::from_raw_os_error(-res)));
+                }
+            }
+            if self.efd.try_read()?.is_some() {
+                return Poll::Ready(Ok(()));
+            }
+            // Poll doesn't point to any memory so it is fine if this is dropped while it is in flight.
+            self.poll = Some(unsafe {
+                RawIo::memory_free(
+                    opcode::PollAdd::new(Fd(self.efd.as_raw_fd()), libc::POLLIN as u32).build(),
+                )
+            });
+        }
+    }
+}
+
+/// Runs the future created by `make_future` inside an io2 executor on a dedicated thread.
+///
+/// The future is created on the new thread so it doesn't need to be `Send`, only its output does.
+/// The returned handle can be awaited from any runtime.
+pub fn spawn_ring_thread<T, F, Fut>(
+    config: ExecutorConfig,
+    make_future: F,
+) -> io::Result<RingThreadHandle<T>>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: Future<Output = T> + 'static,
+{
+    let shared = Shared::new();
+    let thread_shared = shared.clone();
+
+    std::thread::Builder::new()
+        .name("io2-ring".to_owned())
+        .spawn(move || {
+            let guard = CloseOnDrop(thread_shared.clone());
+            let res = config.run(make_future());
+            thread_shared.lock().unwrap().push(res);
+            std::mem::drop(guard);
+        })?;
+
+    Ok(RingThreadHandle {
+        shared,
+        wakeup: None,
+    })
+}
+
+struct CloseOnDrop<T>(Arc<Mutex<Shared<T>>>);
+
+impl<T> Drop for CloseOnDrop<T> {
+    fn drop(&mut self) {
+        if let Ok(mut shared) = self.0.lock() {
+            shared.close();
+        }
+    }
+}
+
+/// Handle to a future running on a thread spawned by [spawn_ring_thread].
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct RingThreadHandle<T> {
+    shared: Arc<Mutex<Shared<io::Result<T>>>>,
+    wakeup: Option<Wakeup>,
+}
+
+impl<T> Future for RingThreadHandle<T> {
+    type Output = io::Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        match ready!(poll_pop(&this.shared, &mut this.wakeup, cx))? {
+            Some(res) => Poll::Ready(res),
+            None => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::Other,
+                "io2 ring thread panicked",
+            ))),
+        }
+    }
+}
+
+/// Creates an unbounded channel that can be used to send values between an io2 executor and another runtime.
+///
+/// Both halves are `Send` and the receiving half can be awaited from either side.
+pub fn channel<T: Send>() -> (Sender<T>, Receiver<T>) {
+    let shared = Shared::new();
+    let sender = Sender {
+        shared: shared.clone(),
+        num_senders: Arc::new(AtomicUsize::new(1)),
+    };
+    (
+        sender,
+        Receiver {
+            shared,
+            wakeup: None,
+        },
+    )
+}
+
+pub struct Sender<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+    num_senders: Arc<AtomicUsize>,
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.num_senders.fetch_add(1, Ordering::Relaxed);
+        Self {
+            shared: self.shared.clone(),
+            num_senders: self.num_senders.clone(),
+        }
+    }
+}
+
+impl<T> Sender<T> {
+    /// Sends a value to the receiver, returns the value back if the receiver was dropped.
+    pub fn send(&self, val: T) -> Result<(), T> {
+        let mut shared = self.shared.lock().unwrap();
+        if shared.receiver_dropped {
+            return Err(val);
+        }
+        shared.push(val);
+        Ok(())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // Only the sender that takes the count to zero closes the channel, no matter how many are dropped at once.
+        if self.num_senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.lock().unwrap().close();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+    wakeup: Option<Wakeup>,
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        if let Ok(mut shared) = self.shared.lock() {
+            shared.receiver_dropped = true;
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Receives the next value, resolves to `None` once all senders are dropped and the channel is empty.
+    ///
+    /// Panics if it is awaited inside of an io2 executor and the eventfd used for waiting can't be created or polled.
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv { receiver: self }
+    }
+
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.shared.lock().unwrap().queue.pop_front()
+    }
+}
+
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Recv<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<'a, T> Future for Recv<'a, T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let receiver = &mut *self.get_mut().receiver;
+        let res = ready!(poll_pop(&receiver.shared, &mut receiver.wakeup, cx));
+        Poll::Ready(res.expect("failed to wait for the channel"))
+    }
+}
+
+type Job = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = ()>>> + Send>;
+
+/// A fixed set of io2 executors running on their own threads, work can be sent to them from any runtime.
+///
+/// The shard threads are started when the pool is created and are reused by every call. Dropping the pool closes the
+/// job channels of the shards, their threads exit after the jobs that were already sent are started.
+pub struct Pool {
+    shards: Vec<Sender<Job>>,
+}
+
+impl Pool {
+    /// Starts `num_shards` threads, each running an executor created with `make_config`.
+    pub fn new(num_shards: usize, make_config: impl Fn() -> ExecutorConfig) -> io::Result<Self> {
+        assert!(num_shards > 0, "num_shards must be greater than zero");
+        let mut shards = Vec::with_capacity(num_shards);
+        for _ in 0..num_shards {
+            let (tx, mut rx) = channel::<Job>();
+            // The thread isn't joined, it exits by itself once the pool is dropped.
+            std::mem::drop(spawn_ring_thread(make_config(), move || async move {
+                while let Some(job) = rx.recv().await {
+                    std::mem::drop(executor::spawn(job()));
+                }
+            })?);
+            shards.push(tx);
+        }
+        Ok(Self { shards })
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Runs `map` on each of `items` spread over the shards, and folds the results into `init` with `reduce` on the
+    /// calling task.
+    ///
+    /// Items are split into contiguous chunks, one per shard, and the items of a shard are mapped concurrently as
+    /// separate tasks. Results are reduced in the order they arrive, so `reduce` shouldn't depend on the order of the
+    /// items. Returns an error if a shard thread panicked.
+    pub async fn map_reduce<I, T, R, M, Fut, F>(
+        &self,
+        items: Vec<I>,
+        map: M,
+        init: R,
+        mut reduce: F,
+    ) -> io::Result<R>
+    where
+        I: Send + 'static,
+        T: Send + 'static,
+        M: Fn(I) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = T> + 'static,
+        F: FnMut(R, T) -> R,
+    {
+        let num_items = items.len();
+        let map = Arc::new(map);
+        let (tx, mut rx) = channel::<T>();
+        let chunk_size = num_items.div_ceil(self.shards.len()).max(1);
+        let mut items = items.into_iter();
+        for shard in self.shards.iter() {
+            let chunk = items.by_ref().take(chunk_size).collect::<Vec<I>>();
+            if chunk.is_empty() {
+                break;
+            }
+            let map = map.clone();
+            let tx = tx.clone();
+            let job: Job = Box::new(move || {
+                Box::pin(async move {
+                    let tasks = chunk
+                        .into_iter()
+                        .map(|item| executor::spawn(map(item)))
+                        .collect::<Vec<_>>();
+                    for task in tasks {
+                        // Receiver only goes away if the caller is gone.
+                        let _ = tx.send(task.await);
+                    }
+                })
+            });
+            if shard.send(job).is_err() {
+                return Err(shard_panicked());
+            }
+        }
+        std::mem::drop(tx);
+
+        let mut acc = init;
+        let mut num_results = 0;
+        while let Some(v) = rx.recv().await {
+            acc = reduce(acc, v);
+            num_results += 1;
+        }
+        // The senders of a shard that panicked are dropped without sending all of its results.
+        if num_results != num_items {
+            return Err(shard_panicked());
+        }
+        Ok(acc)
+    }
+}
+
+fn shard_panicked() -> io::Error {
+    io::Error::other("io2 pool shard panicked")
+}
+
+/// A [Waker] that can be woken from any thread, for libraries that take a waker and wake it from their own threads.
+///
+/// The io2 executor doesn't use wakers, tasks are polled again when their io completes. Waking the waker of the bridge
+/// writes to an [EventFd] and [WakerBridge::wait] waits for it through io_uring, so a task can poll a future of
+/// another library with [WakerBridge::waker] and sleep until the library wakes it. [run_bridged] does this in a loop.
+///
+/// Types implementing [std::task::Wake], the std counterpart of `ArcWake` from the futures crate, can be turned into
+/// a waker with `Waker::from`, and wake the bridge by calling [Waker::wake_by_ref] on it.
+pub struct WakerBridge {
+    efd: Arc<EventFd>,
+    waker: Waker,
+}
+
+impl WakerBridge {
+    pub fn new() -> io::Result<Self> {
+        let efd = Arc::new(EventFd::new()?);
+        Ok(Self {
+            waker: Waker::from(efd.clone()),
+            efd,
+        })
+    }
+
+    /// Waker that can be cloned and sent to other threads, waking it completes the current or next
+    /// [WakerBridge::wait].
+    pub fn waker(&self) -> &Waker {
+        &self.waker
+    }
+
+    /// Waits until the waker was woken since the last call, wakeups in between are merged into one.
+    pub async fn wait(&self) -> io::Result<()> {
+        self.efd.wait().await.map(|_| ())
+    }
+}
+
+/// Polls `future`, which can be from another library that wakes its waker from other threads, on the current io2
+/// task.
+///
+/// The future is polled with the waker of a [WakerBridge] and the task waits for a wakeup between polls, instead of
+/// polling it on every iteration of the executor loop.
+pub async fn run_bridged<F: Future>(future: F) -> io::Result<F::Output> {
+    let bridge = WakerBridge::new()?;
+    let mut future = pin!(future);
+    loop {
+        if let Poll::Ready(out) = future
+            .as_mut()
+            .poll(&mut Context::from_waker(bridge.waker()))
+        {
+            return Ok(out);
+        }
+        bridge.wait().await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_ring_thread_and_channel() {
+        let (tx, mut rx) = channel::<u64>();
+        let (result_tx, mut result_rx) = channel::<u64>();
+
+        let handle = spawn_ring_thread(ExecutorConfig::new(), move || async move {
+            let mut sum = 0;
+            while let Some(v) = rx.recv().await {
+                sum += v;
+            }
+            result_tx.send(sum).unwrap();
+            sum * 2
+        })
+        .unwrap();
+
+        for i in 0..10 {
+            tx.send(i).unwrap();
+        }
+        std::mem::drop(tx);
+
+        let out = ExecutorConfig::new()
+            .run(async move {
+                let sum = result_rx.recv().await.unwrap();
+                let doubled = handle.await.unwrap();
+                (sum, doubled)
+            })
+            .unwrap();
+
+        assert_eq!(out, (45, 90));
+    }
+
+    #[test]
+    fn test_channel_senders_dropped_concurrently() {
+        for _ in 0..100 {
+            let (tx, mut rx) = channel::<u64>();
+            let barrier = Arc::new(std::sync::Barrier::new(8));
+            let threads = (0..8)
+                .map(|i| {
+                    let tx = tx.clone();
+                    let barrier = barrier.clone();
+                    std::thread::spawn(move || {
+                        tx.send(i).unwrap();
+                        barrier.wait();
+                        std::mem::drop(tx);
+                    })
+                })
+                .collect::<Vec<_>>();
+            std::mem::drop(tx);
+            for thread in threads {
+                thread.join().unwrap();
+            }
+
+            let sum = ExecutorConfig::new()
+                .run(async move {
+                    let mut sum = 0;
+                    while let Some(v) = rx.recv().await {
+                        sum += v;
+                    }
+                    sum
+                })
+                .unwrap();
+            assert_eq!(sum, 28);
+        }
+    }
+
+    #[test]
+    fn test_recv_waits_for_wakeup() {
+        let (tx, mut rx) = channel::<u64>();
+        let thread = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            tx.send(1).unwrap();
+        });
+        let (v, polls) = ExecutorConfig::new()
+            .run(async move {
+                let mut polls = 0;
+                let mut recv = pin!(rx.recv());
+                let v = std::future::poll_fn(|cx| {
+                    polls += 1;
+                    recv.as_mut().poll(cx)
+                })
+                .await;
+                (v, polls)
+            })
+            .unwrap();
+        thread.join().unwrap();
+        assert_eq!(v, Some(1));
+        // The task sleeps until the sender wakes it instead of being polled on every iteration.
+        assert!(polls <= 3, "polled {} times", polls);
+    }
+
+    #[test]
+    fn test_channel_receiver_dropped() {
+        let (tx, rx) = channel::<u64>();
+        let tx2 = tx.clone();
+        std::mem::drop(rx);
+        assert_eq!(tx.send(1), Err(1));
+        assert_eq!(tx2.send(2), Err(2));
+    }
+
+    #[test]
+    fn test_pool_map_reduce() {
+        async fn sum_squares(pool: &Pool) -> (u64, Vec<std::thread::ThreadId>) {
+            pool.map_reduce(
+                (1..=100u64).collect(),
+                |x| async move {
+                    crate::time::sleep(std::time::Duration::from_millis(1)).await;
+                    (x * x, std::thread::current().id())
+                },
+                (0, Vec::new()),
+                |(sum, mut threads), (v, thread)| {
+                    if !threads.contains(&thread) {
+                        threads.push(thread);
+                    }
+                    (sum + v, threads)
+                },
+            )
+            .await
+            .unwrap()
+        }
+
+        let pool = Pool::new(4, ExecutorConfig::new).unwrap();
+        let (first, second) = ExecutorConfig::new()
+            .run(async move { (sum_squares(&pool).await, sum_squares(&pool).await) })
+            .unwrap();
+        assert_eq!(first.0, 338350);
+        assert_eq!(second.0, 338350);
+        assert_eq!(first.1.len(), 4);
+        assert!(!first.1.contains(&std::thread::current().id()));
+        // The same shard threads are used by every call.
+        assert!(second.1.iter().all(|thread| first.1.contains(thread)));
+    }
+
+    #[test]
+    fn test_run_bridged() {
+        // Completes after it was woken three times by another thread.
+        struct Foreign {
+            polls: Arc<AtomicU32>,
+            waker_tx: std::sync::mpsc::Sender<Waker>,
+        }
+
+        impl Future for Foreign {
+            type Output = u32;
+
+            fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u32> {
+                let polls = self.polls.fetch_add(1, Ordering::SeqCst) + 1;
+                if polls > 3 {
+                    return Poll::Ready(polls);
+                }
+                self.waker_tx.send(cx.waker().clone()).unwrap();
+                Poll::Pending
+            }
+        }
+
+        let (waker_tx, waker_rx) = std::sync::mpsc::channel::<Waker>();
+        let thread = std::thread::spawn(move || {
+            for waker in waker_rx {
+                std::thread::sleep(Duration::from_millis(2));
+                waker.wake();
+            }
+        });
+        let polls = Arc::new(AtomicU32::new(0));
+        let out = ExecutorConfig::new()
+            .run(run_bridged(Foreign {
+                polls: polls.clone(),
+                waker_tx,
+            }))
+            .unwrap()
+            .unwrap();
+        thread.join().unwrap();
+        // It is only polled again after each wakeup.
+        assert_eq!(out, 4);
+        assert_eq!(polls.load(Ordering::SeqCst), 4);
+    }
+}
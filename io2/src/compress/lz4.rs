@@ -0,0 +1,232 @@
+//! LZ4 block format, see https://github.com/lz4/lz4/blob/dev/doc/lz4_Block_format.md
+//!
+//! This is a simple greedy compressor, it is faster to compress with than it is good at compressing.
+
+use std::io;
+
+use crate::local_alloc::LocalAlloc;
+
+use super::Codec;
+
+const MIN_MATCH: usize = 4;
+// The last match has to start at least this many bytes before the end of the input.
+const MF_LIMIT: usize = 12;
+// The last bytes of the input are always literals.
+const LAST_LITERALS: usize = 5;
+const HASH_LOG: u32 = 12;
+const MAX_OFFSET: usize = 65535;
+
+pub struct Lz4;
+
+impl Codec for Lz4 {
+    const ID: u8 = 1;
+
+    fn compress(&self, input: &[u8], output: &mut Vec<u8, LocalAlloc>) {
+        compress(input, output)
+    }
+
+    fn decompress(
+        &self,
+        input: &[u8],
+        raw_len: usize,
+        output: &mut Vec<u8, LocalAlloc>,
+    ) -> io::Result<()> {
+        decompress(input, raw_len, output)
+    }
+}
+
+fn read_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap())
+}
+
+fn hash(seq: u32) -> usize {
+    (seq.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize
+}
+
+fn write_len(output: &mut Vec<u8, LocalAlloc>, mut len: usize) {
+    while len >= 255 {
+        output.push(255);
+        len -= 255;
+    }
+    output.push(len as u8);
+}
+
+fn write_sequence(
+    output: &mut Vec<u8, LocalAlloc>,
+    literals: &[u8],
+    offset: u16,
+    match_len: usize,
+) {
+    let lit_len = literals.len();
+    let match_len = match_len - MIN_MATCH;
+    output.push(((lit_len.min(15) as u8) << 4) | match_len.min(15) as u8);
+    if lit_len >= 15 {
+        write_len(output, lit_len - 15);
+    }
+    output.extend_from_slice(literals);
+    output.extend_from_slice(&offset.to_le_bytes());
+    if match_len >= 15 {
+        write_len(output, match_len - 15);
+    }
+}
+
+fn write_last_literals(output: &mut Vec<u8, LocalAlloc>, literals: &[u8]) {
+    let lit_len = literals.len();
+    output.push((lit_len.min(15) as u8) << 4);
+    if lit_len >= 15 {
+        write_len(output, lit_len - 15);
+    }
+    output.extend_from_slice(literals);
+}
+
+pub(crate) fn compress(input: &[u8], output: &mut Vec<u8, LocalAlloc>) {
+    // Positions are stored plus one so zero means the slot is empty.
+    let mut table = [0u32; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut pos = 0;
+
+    if input.len() > MF_LIMIT {
+        let match_limit = input.len() - MF_LIMIT;
+        while pos < match_limit {
+            let seq = read_u32(input, pos);
+            let slot = &mut table[hash(seq)];
+            let candidate = *slot as usize;
+            *slot = u32::try_from(pos + 1).unwrap();
+
+            if candidate > 0 {
+                let candidate = candidate - 1;
+                if pos - candidate <= MAX_OFFSET && read_u32(input, candidate) == seq {
+                    let max_len = input.len() - LAST_LITERALS - pos;
+                    let mut len = MIN_MATCH;
+                    while len < max_len && input[candidate + len] == input[pos + len] {
+                        len += 1;
+                    }
+                    write_sequence(output, &input[anchor..pos], (pos - candidate) as u16, len);
+                    pos += len;
+                    anchor = pos;
+                    continue;
+                }
+            }
+            pos += 1;
+        }
+    }
+
+    write_last_literals(output, &input[anchor..]);
+}
+
+fn corrupted() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "corrupted lz4 block")
+}
+
+fn read_len(input: &[u8], pos: &mut usize, mut len: usize) -> io::Result<usize> {
+    loop {
+        let b = *input.get(*pos).ok_or_else(corrupted)?;
+        *pos += 1;
+        len += usize::from(b);
+        if b != 255 {
+            return Ok(len);
+        }
+    }
+}
+
+pub(crate) fn decompress(
+    input: &[u8],
+    raw_len: usize,
+    output: &mut Vec<u8, LocalAlloc>,
+) -> io::Result<()> {
+    let start = output.len();
+    output.reserve(raw_len);
+    let mut pos = 0;
+
+    loop {
+        let token = *input.get(pos).ok_or_else(corrupted)?;
+        pos += 1;
+
+        let mut lit_len = usize::from(token >> 4);
+        if lit_len == 15 {
+            lit_len = read_len(input, &mut pos, lit_len)?;
+        }
+        let literals = input.get(pos..pos + lit_len).ok_or_else(corrupted)?;
+        if output.len() - start + lit_len > raw_len {
+            return Err(corrupted());
+        }
+        output.extend_from_slice(literals);
+        pos += lit_len;
+
+        if pos == input.len() {
+            break;
+        }
+
+        let offset = input.get(pos..pos + 2).ok_or_else(corrupted)?;
+        let offset = usize::from(u16::from_le_bytes(offset.try_into().unwrap()));
+        pos += 2;
+        if offset == 0 || offset > output.len() - start {
+            return Err(corrupted());
+        }
+
+        let mut match_len = usize::from(token & 15);
+        if match_len == 15 {
+            match_len = read_len(input, &mut pos, match_len)?;
+        }
+        let match_len = match_len + MIN_MATCH;
+        if output.len() - start + match_len > raw_len {
+            return Err(corrupted());
+        }
+
+        // Matches can overlap with the bytes they produce so copy one byte at a time.
+        let match_start = output.len() - offset;
+        for i in 0..match_len {
+            output.push(output[match_start + i]);
+        }
+    }
+
+    if output.len() - start != raw_len {
+        return Err(corrupted());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(data: &[u8]) -> usize {
+        let mut compressed = Vec::new_in(LocalAlloc::new());
+        compress(data, &mut compressed);
+        let mut out = Vec::new_in(LocalAlloc::new());
+        decompress(&compressed, data.len(), &mut out).unwrap();
+        assert_eq!(out.as_slice(), data);
+        compressed.len()
+    }
+
+    #[test]
+    fn test_lz4_roundtrip() {
+        roundtrip(b"");
+        roundtrip(b"a");
+        roundtrip(b"hello hello hello hello");
+
+        let repetitive = b"io2 ".repeat(10000);
+        assert!(roundtrip(&repetitive) < repetitive.len() / 50);
+
+        let mut x = 1u32;
+        let noise = (0..100000)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect::<Vec<_>>();
+        roundtrip(&noise);
+
+        let mut compressed = Vec::new_in(LocalAlloc::new());
+        compress(&repetitive, &mut compressed);
+        let mut out = Vec::new_in(LocalAlloc::new());
+        assert!(decompress(
+            &compressed[..compressed.len() / 2],
+            repetitive.len(),
+            &mut out
+        )
+        .is_err());
+    }
+}
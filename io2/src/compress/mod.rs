@@ -0,0 +1,377 @@
+//! Streams of compressed chunks in a file or over a socket.
+//!
+//! [ChunkWriter] compresses each chunk it is given and appends it to the file as a frame, [ChunkReader] reads the
+//! frames back in order. [CompressedStream] sends and receives the same frames over a stream socket. Compression runs inline on the executor thread with a yield point after each chunk, so chunks
+//! should be kept reasonably small (e.g. up to a few hundred KiB) to not starve other tasks.
+//!
+//! Frame layout, all integers are little endian:
+//!
+//! | compressed length: u32 | uncompressed length: u32 | checksum: u32 | codec id: u8 | data |
+//!
+//! The checksum is the crc32c of the lengths, the codec id and the data, so a corrupted length is detected before the
+//! data is decompressed.
+//!
+//! LZ4 and zstd codecs are built in behind the `lz4` and `zstd` features, other codecs can be used by implementing
+//! [Codec].
+
+#[cfg(feature = "lz4")]
+pub mod lz4;
+mod stream;
+#[cfg(feature = "zstd")]
+pub mod zstd;
+
+use std::io;
+
+use crate::block::{crc32c, crc32c_append};
+use crate::executor::YieldIfNeeded;
+use crate::fs::file::{File, SyncAll};
+use crate::local_alloc::LocalAlloc;
+
+#[cfg(feature = "zstd")]
+pub use self::zstd::Zstd;
+#[cfg(feature = "lz4")]
+pub use lz4::Lz4;
+pub use stream::CompressedStream;
+
+const HEADER_SIZE: usize = 13;
+
+pub trait Codec {
+    /// Identifier written into each frame so reading with the wrong codec fails instead of returning garbage.
+    const ID: u8;
+
+    /// Appends the compressed form of `input` to `output`.
+    fn compress(&self, input: &[u8], output: &mut Vec<u8, LocalAlloc>);
+
+    /// Appends the decompressed form of `input` to `output`, `raw_len` is the length of the uncompressed data.
+    fn decompress(
+        &self,
+        input: &[u8],
+        raw_len: usize,
+        output: &mut Vec<u8, LocalAlloc>,
+    ) -> io::Result<()>;
+}
+
+/// Stores chunks as they are, this is useful to get the framing and checksums without compression.
+pub struct NoCompression;
+
+impl Codec for NoCompression {
+    const ID: u8 = 0;
+
+    fn compress(&self, input: &[u8], output: &mut Vec<u8, LocalAlloc>) {
+        output.extend_from_slice(input);
+    }
+
+    fn decompress(
+        &self,
+        input: &[u8],
+        raw_len: usize,
+        output: &mut Vec<u8, LocalAlloc>,
+    ) -> io::Result<()> {
+        if input.len() != raw_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "uncompressed chunk length doesn't match its header",
+            ));
+        }
+        output.extend_from_slice(input);
+        Ok(())
+    }
+}
+
+pub struct ChunkWriter<C: Codec> {
+    file: File,
+    offset: u64,
+    codec: C,
+    buf: Vec<u8, LocalAlloc>,
+}
+
+impl<C: Codec> ChunkWriter<C> {
+    /// Creates a writer that writes frames starting from `offset`.
+    pub fn new(file: File, offset: u64, codec: C) -> Self {
+        Self {
+            file,
+            offset,
+            codec,
+            buf: Vec::new_in(LocalAlloc::new()),
+        }
+    }
+
+    /// Compresses `data` and writes it as the next frame, returns the offset the frame was written at.
+    ///
+    /// Fails with `InvalidInput` if `data` or its compressed form is 4 GiB or larger.
+    pub async fn write_chunk(&mut self, data: &[u8]) -> io::Result<u64> {
+        encode_frame(&self.codec, data, &mut self.buf).await?;
+
+        let offset = self.offset;
+        self.file.write_all(&self.buf, offset).await?;
+        self.offset += u64::try_from(self.buf.len()).unwrap();
+        Ok(offset)
+    }
+
+    /// Offset the next frame will be written at.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn sync_all(&self) -> SyncAll {
+        self.file.sync_all()
+    }
+
+    pub fn into_file(self) -> File {
+        self.file
+    }
+}
+
+pub struct ChunkReader<C: Codec> {
+    file: File,
+    offset: u64,
+    codec: C,
+    max_chunk_size: usize,
+    buf: Vec<u8, LocalAlloc>,
+}
+
+impl<C: Codec> ChunkReader<C> {
+    /// Creates a reader that reads frames starting from `offset`. Default maximum chunk size is 64 MiB.
+    pub fn new(file: File, offset: u64, codec: C) -> Self {
+        Self {
+            file,
+            offset,
+            codec,
+            max_chunk_size: 64 * 1024 * 1024,
+            buf: Vec::new_in(LocalAlloc::new()),
+        }
+    }
+
+    /// Frames with more than this many bytes of compressed or decompressed data are rejected with an `InvalidData`
+    /// error before their data is read, so a corrupted length doesn't allocate a huge buffer. It has to be at least
+    /// the size of the largest chunk that was written.
+    pub fn max_chunk_size(mut self, max_chunk_size: usize) -> Self {
+        self.max_chunk_size = max_chunk_size;
+        self
+    }
+
+    /// Reads and decompresses the next frame, returns `None` at the end of the file.
+    ///
+    /// Returns an error with `InvalidData` kind if the frame is corrupted or was written with another codec.
+    pub async fn next_chunk(&mut self) -> io::Result<Option<Vec<u8, LocalAlloc>>> {
+        let mut header = [0; HEADER_SIZE];
+        let mut header_read = 0;
+        while header_read < HEADER_SIZE {
+            let offset = self.offset + u64::try_from(header_read).unwrap();
+            match self.file.read(&mut header[header_read..], offset).await {
+                Ok(0) if header_read == 0 => return Ok(None),
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                Ok(n) => header_read += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let (compressed_len, raw_len, checksum) = parse_header::<C>(&header)?;
+        if compressed_len.max(raw_len) > self.max_chunk_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "chunk at offset {} of {} bytes is larger than the maximum chunk size {}",
+                    self.offset,
+                    compressed_len.max(raw_len),
+                    self.max_chunk_size
+                ),
+            ));
+        }
+
+        self.buf.clear();
+        self.buf.resize(compressed_len, 0);
+        self.file
+            .read_exact(
+                &mut self.buf,
+                self.offset + u64::try_from(HEADER_SIZE).unwrap(),
+            )
+            .await?;
+        if frame_checksum(&header, &self.buf) != checksum {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("checksum mismatch in chunk at offset {}", self.offset),
+            ));
+        }
+
+        let mut out = Vec::with_capacity_in(raw_len, LocalAlloc::new());
+        self.codec.decompress(&self.buf, raw_len, &mut out)?;
+        YieldIfNeeded.await;
+
+        self.offset += u64::try_from(HEADER_SIZE + compressed_len).unwrap();
+        Ok(Some(out))
+    }
+
+    /// Offset of the next frame.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn into_file(self) -> File {
+        self.file
+    }
+}
+
+// Replaces the contents of `buf` with the frame of `data`.
+async fn encode_frame<C: Codec>(
+    codec: &C,
+    data: &[u8],
+    buf: &mut Vec<u8, LocalAlloc>,
+) -> io::Result<()> {
+    let too_large = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "chunk is too large for a frame",
+        )
+    };
+    let raw_len = u32::try_from(data.len()).map_err(|_| too_large())?;
+    buf.clear();
+    buf.resize(HEADER_SIZE, 0);
+    codec.compress(data, buf);
+    YieldIfNeeded.await;
+
+    let compressed_len = u32::try_from(buf.len() - HEADER_SIZE).map_err(|_| too_large())?;
+    buf[0..4].copy_from_slice(&compressed_len.to_le_bytes());
+    buf[4..8].copy_from_slice(&raw_len.to_le_bytes());
+    buf[12] = C::ID;
+    let checksum = frame_checksum(&buf[..HEADER_SIZE], &buf[HEADER_SIZE..]);
+    buf[8..12].copy_from_slice(&checksum.to_le_bytes());
+    Ok(())
+}
+
+// Checksum of the header fields other than the checksum itself and of the compressed data.
+fn frame_checksum(header: &[u8], data: &[u8]) -> u32 {
+    let crc = crc32c(&header[0..8]);
+    let crc = crc32c_append(crc, &header[12..HEADER_SIZE]);
+    crc32c_append(crc, data)
+}
+
+// Returns the compressed length, uncompressed length and checksum of a frame.
+fn parse_header<C: Codec>(header: &[u8; HEADER_SIZE]) -> io::Result<(usize, usize, u32)> {
+    let compressed_len =
+        usize::try_from(u32::from_le_bytes(header[0..4].try_into().unwrap())).unwrap();
+    let raw_len = usize::try_from(u32::from_le_bytes(header[4..8].try_into().unwrap())).unwrap();
+    let checksum = u32::from_le_bytes(header[8..12].try_into().unwrap());
+    if header[12] != C::ID {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "chunk was written with codec {} but reader uses codec {}",
+                header[12],
+                C::ID
+            ),
+        ));
+    }
+    Ok((compressed_len, raw_len, checksum))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::executor::ExecutorConfig;
+
+    use super::*;
+
+    async fn roundtrip<C: Codec, F: Fn() -> C>(path: &Path, codec: F) {
+        let file = File::create(path).unwrap().await.unwrap();
+        let mut writer = ChunkWriter::new(file, 0, codec());
+        let chunks = [b"first chunk".repeat(100), Vec::new(), b"second".to_vec()];
+        for chunk in chunks.iter() {
+            writer.write_chunk(chunk).await.unwrap();
+        }
+        let written = writer.offset();
+        std::mem::drop(writer);
+
+        let file = File::open(path, libc::O_RDONLY, 0).unwrap().await.unwrap();
+        let mut reader = ChunkReader::new(file, 0, codec());
+        for chunk in chunks.iter() {
+            assert_eq!(
+                reader.next_chunk().await.unwrap().unwrap().as_slice(),
+                chunk.as_slice()
+            );
+        }
+        assert!(reader.next_chunk().await.unwrap().is_none());
+        assert_eq!(reader.offset(), written);
+    }
+
+    #[test]
+    fn test_chunks() {
+        let path = std::env::temp_dir().join(format!("io2_test_chunks_{}", std::process::id()));
+        let test_path = path.clone();
+        ExecutorConfig::new()
+            .run(async move {
+                roundtrip(&test_path, || NoCompression).await;
+                #[cfg(feature = "lz4")]
+                roundtrip(&test_path, || Lz4).await;
+                #[cfg(feature = "zstd")]
+                roundtrip(&test_path, Zstd::default).await;
+
+                // A corrupted length is detected by the checksum.
+                let file = File::create(&test_path).unwrap().await.unwrap();
+                ChunkWriter::new(file, 0, NoCompression)
+                    .write_chunk(b"data")
+                    .await
+                    .unwrap();
+                let mut data = std::fs::read(&test_path).unwrap();
+                data[4] ^= 1;
+                std::fs::write(&test_path, &data).unwrap();
+                let file = File::open(&test_path, libc::O_RDONLY, 0)
+                    .unwrap()
+                    .await
+                    .unwrap();
+                let err = ChunkReader::new(file, 0, NoCompression)
+                    .next_chunk()
+                    .await
+                    .unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+                // Lengths above the maximum chunk size are rejected before the data is read.
+                data[4] ^= 1;
+                data[0..4].copy_from_slice(&u32::MAX.to_le_bytes());
+                std::fs::write(&test_path, &data).unwrap();
+                let file = File::open(&test_path, libc::O_RDONLY, 0)
+                    .unwrap()
+                    .await
+                    .unwrap();
+                let err = ChunkReader::new(file, 0, NoCompression)
+                    .max_chunk_size(1024)
+                    .next_chunk()
+                    .await
+                    .unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+                // Reading with the wrong codec fails.
+                let file = File::create(&test_path).unwrap().await.unwrap();
+                ChunkWriter::new(file, 0, NoCompression)
+                    .write_chunk(b"data")
+                    .await
+                    .unwrap();
+                struct Other;
+                impl Codec for Other {
+                    const ID: u8 = 200;
+                    fn compress(&self, _: &[u8], _: &mut Vec<u8, LocalAlloc>) {}
+                    fn decompress(
+                        &self,
+                        _: &[u8],
+                        _: usize,
+                        _: &mut Vec<u8, LocalAlloc>,
+                    ) -> io::Result<()> {
+                        Ok(())
+                    }
+                }
+                let file = File::open(&test_path, libc::O_RDONLY, 0)
+                    .unwrap()
+                    .await
+                    .unwrap();
+                let err = ChunkReader::new(file, 0, Other)
+                    .next_chunk()
+                    .await
+                    .unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            })
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
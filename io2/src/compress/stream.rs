@@ -0,0 +1,264 @@
+use std::io;
+
+use crate::executor::YieldIfNeeded;
+use crate::local_alloc::LocalAlloc;
+use crate::net::{self, socket, StreamSocket};
+
+use super::{encode_frame, frame_checksum, parse_header, Codec, HEADER_SIZE};
+
+/// Compresses the data written to a stream socket and decompresses the data read from it.
+///
+/// Written data is collected into chunks of `chunk_size` bytes, each chunk is compressed and sent as a frame with
+/// the same layout as the frames of [super::ChunkWriter]. [CompressedStream::flush] sends a partial chunk, data is not
+/// sent before a chunk is full or the stream is flushed. Frames are compressed independently, so memory use is
+/// bounded by the chunk and frame sizes and the two directions don't share any state.
+///
+/// Any [Codec] can be used, the zstd codec behind the `zstd` feature compresses the most for links on slow networks.
+///
+/// `S` can be a reference to a stream, so two of these can be created over the same stream to read in one task while
+/// writing in another.
+///
+/// Reads and writes can't be cancelled, dropping one of their futures before it finishes leaves the stream at an
+/// unknown position.
+pub struct CompressedStream<S: StreamSocket, C: Codec> {
+    stream: S,
+    codec: C,
+    chunk_size: usize,
+    max_frame_size: usize,
+    // Data that was written but not sent yet.
+    pending: Vec<u8, LocalAlloc>,
+    // Frame that is being sent or received.
+    frame: Vec<u8, LocalAlloc>,
+    // Decompressed data of the last received frame, from `read_pos` on it isn't returned yet.
+    decoded: Vec<u8, LocalAlloc>,
+    read_pos: usize,
+    eof: bool,
+}
+
+impl<S: StreamSocket, C: Codec> CompressedStream<S, C> {
+    /// Default chunk size is 64 KiB and default maximum frame size is 1 MiB.
+    pub fn new(stream: S, codec: C) -> Self {
+        Self {
+            stream,
+            codec,
+            chunk_size: 64 * 1024,
+            max_frame_size: 1024 * 1024,
+            pending: Vec::new_in(LocalAlloc::new()),
+            frame: Vec::new_in(LocalAlloc::new()),
+            decoded: Vec::new_in(LocalAlloc::new()),
+            read_pos: 0,
+            eof: false,
+        }
+    }
+
+    /// Amount of written data that is compressed into one frame.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "chunk_size has to be positive");
+        assert!(
+            u32::try_from(chunk_size).is_ok(),
+            "chunk_size must fit in a u32"
+        );
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Received frames with more than this many bytes of compressed or decompressed data are rejected with an
+    /// `InvalidData` error. It has to be at least the chunk size of the peer.
+    pub fn max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Returns the stream, data that wasn't flushed or wasn't read yet is lost.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Buffers as much of `buf` as fits into the current chunk, sending the chunk if it is full.
+    pub async fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.pending.is_empty() && buf.len() >= self.chunk_size {
+            // Compress straight from the caller's buffer instead of copying a whole chunk.
+            self.send_frame(&buf[..self.chunk_size]).await?;
+            return Ok(self.chunk_size);
+        }
+        let n = buf.len().min(self.chunk_size - self.pending.len());
+        self.pending.extend_from_slice(&buf[..n]);
+        if self.pending.len() == self.chunk_size {
+            self.flush().await?;
+        }
+        Ok(n)
+    }
+
+    pub async fn write_all(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            let n = self.write(buf).await?;
+            buf = &buf[n..];
+        }
+        Ok(())
+    }
+
+    /// Sends the buffered data as a frame.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let pending = std::mem::replace(&mut self.pending, Vec::new_in(LocalAlloc::new()));
+        let res = self.send_frame(&pending).await;
+        self.pending = pending;
+        self.pending.clear();
+        res
+    }
+
+    async fn send_frame(&mut self, data: &[u8]) -> io::Result<()> {
+        encode_frame(&self.codec, data, &mut self.frame).await?;
+        socket::write_all(
+            net::stream_fd(&self.stream),
+            Some(net::stream_deadlines(&self.stream)),
+            &self.frame,
+        )
+        .await
+    }
+
+    /// Reads decompressed data into `buf`, `Ok(0)` means the peer closed the stream.
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.read_pos == self.decoded.len() {
+            if self.eof || !self.recv_frame().await? {
+                self.eof = true;
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.decoded.len() - self.read_pos);
+        buf[..n].copy_from_slice(&self.decoded[self.read_pos..self.read_pos + n]);
+        self.read_pos += n;
+        Ok(n)
+    }
+
+    pub async fn read_exact(&mut self, mut buf: &mut [u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read(buf).await? {
+                0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                n => buf = &mut buf[n..],
+            }
+        }
+        Ok(())
+    }
+
+    // Receives the next frame into `decoded`, returns false if the stream ended before it.
+    async fn recv_frame(&mut self) -> io::Result<bool> {
+        let fd = net::stream_fd(&self.stream);
+        let mut header = [0; HEADER_SIZE];
+        let n = loop {
+            match socket::Read::new(fd, &mut header)
+                .deadlines(net::stream_deadlines(&self.stream))
+                .await
+            {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                res => break res?,
+            }
+        };
+        if n == 0 {
+            return Ok(false);
+        }
+        socket::read_exact(
+            fd,
+            Some(net::stream_deadlines(&self.stream)),
+            &mut header[n..],
+        )
+        .await?;
+
+        let (compressed_len, raw_len, checksum) = parse_header::<C>(&header)?;
+        if compressed_len.max(raw_len) > self.max_frame_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "frame of {} bytes is larger than the maximum frame size {}",
+                    compressed_len.max(raw_len),
+                    self.max_frame_size
+                ),
+            ));
+        }
+        self.frame.clear();
+        self.frame.resize(compressed_len, 0);
+        socket::read_exact(
+            fd,
+            Some(net::stream_deadlines(&self.stream)),
+            &mut self.frame,
+        )
+        .await?;
+        if frame_checksum(&header, &self.frame) != checksum {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "checksum mismatch in received frame",
+            ));
+        }
+
+        self.decoded.clear();
+        self.read_pos = 0;
+        self.codec
+            .decompress(&self.frame, raw_len, &mut self.decoded)?;
+        YieldIfNeeded.await;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress::NoCompression;
+    use crate::executor::{spawn, ExecutorConfig};
+    use crate::net::unix::UnixStream;
+
+    async fn roundtrip<C: Codec + 'static>(codec: impl Fn() -> C) {
+        let (a, b) = UnixStream::pair().unwrap();
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i / 100) as u8).collect();
+        let sent = data.clone();
+        let mut writer = CompressedStream::new(a, codec()).chunk_size(10_000);
+        let sender = spawn(async move {
+            // Writes smaller and larger than a chunk.
+            for piece in sent.chunks(7_777) {
+                writer.write_all(piece).await.unwrap();
+            }
+            writer.write_all(&sent[..50_000]).await.unwrap();
+            writer.flush().await.unwrap();
+        });
+
+        let mut reader = CompressedStream::new(b, codec());
+        let mut out = vec![0; 350_000];
+        reader.read_exact(&mut out).await.unwrap();
+        assert_eq!(out[..300_000], data[..]);
+        assert_eq!(out[300_000..], data[..50_000]);
+        sender.await;
+        assert_eq!(reader.read(&mut out).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn test_compressed_stream() {
+        ExecutorConfig::new()
+            .run(async {
+                roundtrip(|| NoCompression).await;
+                #[cfg(feature = "lz4")]
+                roundtrip(|| crate::compress::Lz4).await;
+                #[cfg(feature = "zstd")]
+                roundtrip(crate::compress::Zstd::default).await;
+                #[cfg(feature = "zstd")]
+                roundtrip(|| crate::compress::Zstd::new(-5)).await;
+
+                // Frames above the maximum size of the reader are rejected.
+                let (a, b) = UnixStream::pair().unwrap();
+                let mut writer = CompressedStream::new(&a, NoCompression);
+                writer.write_all(&[1; 2000]).await.unwrap();
+                writer.flush().await.unwrap();
+                let mut reader = CompressedStream::new(&b, NoCompression).max_frame_size(1000);
+                let err = reader.read(&mut [0; 10]).await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,66 @@
+//! zstd codec using the zstd C library, each chunk is compressed into a single zstd frame.
+
+use std::io;
+
+use zstd::zstd_safe;
+
+use crate::local_alloc::LocalAlloc;
+
+use super::Codec;
+
+pub struct Zstd {
+    level: i32,
+}
+
+impl Zstd {
+    /// Compression level, 1 is the fastest and 19 compresses the most. Negative levels are faster than 1 and
+    /// compress less.
+    pub fn new(level: i32) -> Self {
+        Self { level }
+    }
+}
+
+impl Default for Zstd {
+    /// Uses the default level of zstd, 3.
+    fn default() -> Self {
+        Self::new(zstd::DEFAULT_COMPRESSION_LEVEL)
+    }
+}
+
+impl Codec for Zstd {
+    const ID: u8 = 2;
+
+    fn compress(&self, input: &[u8], output: &mut Vec<u8, LocalAlloc>) {
+        let start = output.len();
+        output.resize(start + zstd_safe::compress_bound(input.len()), 0);
+        // Only fails if the output is smaller than the bound.
+        let n = zstd_safe::compress(&mut output[start..], input, self.level).unwrap();
+        output.truncate(start + n);
+    }
+
+    fn decompress(
+        &self,
+        input: &[u8],
+        raw_len: usize,
+        output: &mut Vec<u8, LocalAlloc>,
+    ) -> io::Result<()> {
+        let start = output.len();
+        output.resize(start + raw_len, 0);
+        let n = zstd_safe::decompress(&mut output[start..], input).map_err(|code| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "failed to decompress zstd chunk: {}",
+                    zstd_safe::get_error_name(code)
+                ),
+            )
+        })?;
+        if n != raw_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "uncompressed chunk length doesn't match its header",
+            ));
+        }
+        Ok(())
+    }
+}
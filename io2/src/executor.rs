@@ -1,45 +1,271 @@
 use std::{
-    cell::RefCell,
-    collections::VecDeque,
+    cell::{Cell, RefCell},
+    cmp::Reverse,
+    collections::{BinaryHeap, VecDeque},
     future::Future,
     io,
-    os::fd::RawFd,
+    marker::PhantomData,
+    ops::ControlFlow,
+    os::fd::{AsRawFd, RawFd},
+    panic::{catch_unwind, AssertUnwindSafe, Location},
     pin::Pin,
     rc::Rc,
     task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
     time::{Duration, Instant},
 };
 
-use io_uring::{cqueue, opcode, squeue, types::Fd, IoUring};
+use io_uring::{
+    cqueue, opcode, squeue,
+    types::{self, Fd},
+    IoUring, Submitter,
+};
+
+use fixed_buffers::FixedBuffers;
+use fixed_files::FixedFiles;
+pub use must_complete::MustComplete;
+
+use crate::{
+    keymap::KeyMap,
+    local_alloc::{self, LocalAlloc},
+    metrics::latency::{self, OpClass},
+    slab,
+    vecmap::VecMap,
+};
 
-use crate::{local_alloc::LocalAlloc, slab, vecmap::VecMap};
+pub mod background;
+pub mod fixed_buffers;
+mod fixed_files;
+mod must_complete;
+
+// Records an instrumentation event, compiled out unless the trace feature is enabled.
+//
+// Events of a task are tagged with `task=<id>` so the records of a task can be grouped into a span by the log consumer.
+macro_rules! trace_event {
+    ($target:literal, $($arg:tt)+) => {
+        #[cfg(feature = "trace")]
+        {
+            #[cfg(test)]
+            tests::capture_trace($target, format_args!($($arg)+));
+            log::trace!(target: $target, $($arg)+);
+        }
+    };
+}
 
 thread_local! {
     pub(crate) static CURRENT_TASK_CONTEXT: RefCell<Option<CurrentTaskContext>> = const { RefCell::new(None) };
     pub(crate) static FILES_TO_CLOSE: RefCell<Vec<RawFd, LocalAlloc>> = RefCell::new(Vec::with_capacity_in(128, LocalAlloc::new()));
+    // Futures registered with defer that weren't spawned yet, and the number of spawned ones that didn't finish.
+    static DEFERRED: RefCell<Vec<(Task, &'static Location<'static>), LocalAlloc>> = RefCell::new(Vec::new_in(LocalAlloc::new()));
+    static NUM_DEFERRED_RUNNING: Cell<usize> = const { Cell::new(0) };
 }
 
-type IoResults = VecMap<slab::Key, i32, LocalAlloc>;
 type ToNotify = VecMap<slab::Key, (), LocalAlloc>;
+
+// Slots of the io slab that the executor keeps for its own operations while it runs: closing files, detached
+// operations and the timeout. Executors with a uring cmd ring keep one more slot to poll it.
+const NUM_INTERNAL_IO: usize = 3;
 type Task = Pin<Box<dyn Future<Output = ()>, LocalAlloc>>;
+type Io = slab::Slab<IoSlot, LocalAlloc>;
+type Multishot = KeyMap<MultishotState, LocalAlloc>;
+type Retries = KeyMap<RetryState, LocalAlloc>;
+type TaskInfos = VecMap<slab::Key, TaskInfo, LocalAlloc>;
+// Operations queued by each task, so the io of a task can be found without scanning the io slab. Keys of operations
+// that were already consumed are pruned lazily, they don't match the slot anymore.
+type TaskIo = KeyMap<Vec<slab::Key, LocalAlloc>, LocalAlloc>;
+// Ring with 128 byte submission and 32 byte completion entries for commands that need them, see
+// ExecutorConfig::uring_cmd_ring.
+type CmdRing = IoUring<squeue::Entry128, cqueue::Entry32>;
+// Time an operation was queued, for operations that are recorded in the latency histograms.
+type IoStarted = KeyMap<(Instant, OpClass), LocalAlloc>;
+// Entries held back by fault injection, with the time to submit them, their io id and whether they are direct io.
+#[cfg(feature = "test_util")]
+type DelayedIo = Vec<(Instant, slab::Key, squeue::Entry, bool), LocalAlloc>;
+
+// An operation that was queued and wasn't consumed by its task yet. The key of the slot is the user_data of the
+// operation, so a completion is written into its slot without a lookup.
+struct IoSlot {
+    task_id: slab::Key,
+    result: Option<i32>,
+    // The entry doesn't point to any memory, e.g. a poll, so the future that owns it can be dropped while it runs.
+    memory_free: bool,
+    // Owner of a memory free operation was dropped, the slot is removed when the completion arrives.
+    abandoned: bool,
+    #[cfg(debug_assertions)]
+    origin: Option<must_complete::IoOrigin>,
+}
+
+// Multishot operations post many completions for a single submission so their results are queued instead of being
+// written into the io slot.
+struct MultishotState {
+    results: VecDeque<(i32, u32), LocalAlloc>,
+    // Owner of the operation is gone, completions are dropped until the kernel posts the final one.
+    abandoned: bool,
+}
+
+// Copy of an operation that is kept so it can be submitted again, see ExecutorConfig::retry_interrupted and
+// ExecutorConfig::continue_short_io.
+struct RetryState {
+    entry: squeue::Entry,
+    direct_io: bool,
+    // Bytes transferred by the previous submissions of a read/write.
+    done: u32,
+}
+
+#[derive(Clone, Copy)]
+struct RetryPolicy {
+    interrupted: bool,
+    short_io: bool,
+}
+
+struct TaskInfo {
+    spawned_at: &'static Location<'static>,
+    // Number of polls that took longer than the preempt duration.
+    num_slow_polls: u64,
+}
+
+// Counters for the RunReport.
+struct RunStats {
+    num_tasks: u64,
+    // Submitted operations by opcode.
+    io_by_opcode: [u64; 256],
+}
+
+// Spawned tasks that are running, see ExecutorConfig::max_tasks.
+struct TaskLimit {
+    max_tasks: usize,
+    policy: SpawnPolicy,
+    num_spawned: usize,
+    // Tasks waiting in spawn_bounded for a spawned task to finish.
+    waiters: VecDeque<slab::Key, LocalAlloc>,
+}
+
+struct Timer {
+    when: Instant,
+    task_id: slab::Key,
+}
 
+// Timers are stored in a slab keyed by TimerId and ordered by a heap of (when, id). Cancelling or resetting a timer
+// doesn't touch the heap, entries that don't match the slab anymore are skipped when they reach the top.
 struct NotifyWhen {
-    timer: Vec<Instant, LocalAlloc>,
-    task_id: Vec<slab::Key, LocalAlloc>,
+    timers: slab::Slab<Timer, LocalAlloc>,
+    heap: BinaryHeap<Reverse<(Instant, u64)>, LocalAlloc>,
+}
+
+impl NotifyWhen {
+    fn with_capacity_in(capacity: usize, alloc: LocalAlloc) -> Self {
+        Self {
+            timers: slab::Slab::with_capacity_in(capacity, alloc),
+            heap: BinaryHeap::with_capacity_in(capacity, alloc),
+        }
+    }
+
+    fn insert(&mut self, when: Instant, task_id: slab::Key) -> TimerId {
+        let key = self.timers.insert(Timer { when, task_id });
+        self.heap.push(Reverse((when, key.into())));
+        TimerId(key)
+    }
+
+    fn remove(&mut self, id: TimerId) {
+        self.timers.remove(id.0);
+        self.compact();
+    }
+
+    fn reset(&mut self, id: TimerId, when: Instant) -> bool {
+        match self.timers.get_mut(id.0) {
+            Some(timer) => {
+                timer.when = when;
+                self.heap.push(Reverse((when, id.0.into())));
+                self.compact();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn is_stale(&self, when: Instant, key: u64) -> bool {
+        self.timers.get(key.into()).is_none_or(|t| t.when != when)
+    }
+
+    /// Returns the deadline of the nearest timer.
+    fn next_deadline(&mut self) -> Option<Instant> {
+        while let Some(&Reverse((when, key))) = self.heap.peek() {
+            if !self.is_stale(when, key) {
+                return Some(when);
+            }
+            self.heap.pop();
+        }
+        None
+    }
+
+    /// Removes the timers that expired at `now` and notifies their tasks.
+    fn notify_expired(&mut self, now: Instant, to_notify: &mut ToNotify) {
+        while let Some(&Reverse((when, key))) = self.heap.peek() {
+            if when > now {
+                break;
+            }
+            self.heap.pop();
+            if !self.is_stale(when, key) {
+                let timer = self.timers.remove(key.into()).unwrap();
+                to_notify.insert(timer.task_id, ());
+            }
+        }
+    }
+
+    // Rebuilds the heap when most of it is stale entries, so timers that are reset or cancelled over and over don't
+    // grow it without bound.
+    fn compact(&mut self) {
+        if self.heap.len() > 64 && self.heap.len() > 2 * self.timers.len() {
+            let timers = &self.timers;
+            self.heap.retain(|&Reverse((when, key))| {
+                timers.get(key.into()).is_some_and(|t| t.when == when)
+            });
+        }
+    }
 }
 
+/// Identifies a timer registered with [CurrentTaskContext::notify_when].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct TimerId(slab::Key);
+
 pub(crate) struct CurrentTaskContext {
     start: Instant,
     task_id: slab::Key,
     tasks: *mut slab::Slab<Task, LocalAlloc>,
-    io_results: *mut IoResults,
+    task_infos: *mut TaskInfos,
     io_queue: *mut VecDeque<squeue::Entry, LocalAlloc>,
     dio_queue: *mut VecDeque<squeue::Entry, LocalAlloc>,
     preempt_duration: Duration,
-    io: *mut slab::Slab<slab::Key, LocalAlloc>,
+    max_sqes_per_poll: usize,
+    // Number of operations the task queued in this poll.
+    num_queued: usize,
+    io: *mut Io,
+    task_io: *mut TaskIo,
+    // Tasks that are dropped instead of polled once none of their io is running, see abort_task.
+    aborted: *mut KeyMap<(), LocalAlloc>,
+    // Null if the executor doesn't have a uring cmd ring.
+    cmd_queue: *mut VecDeque<squeue::Entry128, LocalAlloc>,
+    num_cmd_running: *mut usize,
+    // First 8 bytes of the extra completion data of finished commands, until the result is taken.
+    cmd_results: *mut KeyMap<u64, LocalAlloc>,
     to_notify: *mut ToNotify,
     notify_when: *mut NotifyWhen,
     num_dio_running: *mut usize,
+    multishot: *mut Multishot,
+    retry_policy: RetryPolicy,
+    retries: *mut Retries,
+    latency_metrics: bool,
+    io_started: *mut IoStarted,
+    detached_io_id: slab::Key,
+    task_limit: *mut TaskLimit,
+    num_detached_running: *mut usize,
+    fixed_buffers: *mut Option<FixedBuffers>,
+    fixed_files: *mut Option<FixedFiles>,
+    run_stats: *mut RunStats,
+    io_backtraces: bool,
+    // Ids of the queued operations are pushed here while a MustComplete is polled, null otherwise.
+    queued_io: *mut Vec<slab::Key>,
+    #[cfg(feature = "test_util")]
+    delayed_io: *mut DelayedIo,
 }
 
 // This is to clear data in CURRENT_TASK_CONTEXT in case one of the tasks panic while getting polled
@@ -54,26 +280,34 @@ impl Drop for CurrentTaskContextGuard {
 }
 
 impl CurrentTaskContext {
-    fn notify(&mut self, task_id: slab::Key) {
+    pub(crate) fn notify(&mut self, task_id: slab::Key) {
         unsafe {
             (*self.to_notify).insert(task_id, ());
         }
     }
 
+    /// Returns the index of a registered buffer that contains `ptr..ptr + len` if the executor runs with
+    /// [ExecutorConfig::auto_fixed_buffers] and the buffer is eligible, the read/write should use the fixed variant then.
+    pub(crate) fn fixed_buffer(&mut self, ptr: *const u8, len: usize) -> Option<u16> {
+        unsafe { (*self.fixed_buffers).as_mut()?.buf_index(ptr, len) }
+    }
+
+    /// Returns the fixed file table if the executor runs with [ExecutorConfig::fixed_file_table].
+    pub(crate) fn fixed_files(&mut self) -> Option<&mut FixedFiles> {
+        unsafe { (*self.fixed_files).as_mut() }
+    }
+
     pub(crate) fn take_io_result(&mut self, io_id: slab::Key) -> Option<i32> {
         unsafe {
-            match (*self.io_results).remove(&io_id) {
-                Some(res) => {
-                    (*self.io).remove(io_id);
-                    Some(res)
-                }
-                None => None,
-            }
+            let res = (*self.io).get_mut(io_id)?.result.take()?;
+            (*self.io).remove(io_id);
+            Some(res)
         }
     }
 
     fn yield_if_needed(&self) -> bool {
-        if self.start.elapsed() < self.preempt_duration {
+        if self.num_queued < self.max_sqes_per_poll && self.start.elapsed() < self.preempt_duration
+        {
             false
         } else {
             unsafe { (*self.to_notify).insert(self.task_id, ()) };
@@ -81,10 +315,40 @@ impl CurrentTaskContext {
         }
     }
 
+    /// `spawned_at` is reported in [PreemptionReport] if the task uses too much cpu time.
     pub(crate) fn spawn<T: 'static, F: Future<Output = T> + 'static>(
         &mut self,
         future: F,
+        spawned_at: &'static Location<'static>,
     ) -> JoinHandle<T> {
+        self.spawn_with_id(future, spawned_at).0
+    }
+
+    pub(crate) fn spawn_with_id<T: 'static, F: Future<Output = T> + 'static>(
+        &mut self,
+        future: F,
+        spawned_at: &'static Location<'static>,
+    ) -> (JoinHandle<T>, slab::Key) {
+        match self.try_spawn_with_id(future, spawned_at) {
+            Ok(spawned) => spawned,
+            Err(e) => panic!("{}, use try_spawn or spawn_bounded to handle this", e),
+        }
+    }
+
+    pub(crate) fn try_spawn_with_id<T: 'static, F: Future<Output = T> + 'static>(
+        &mut self,
+        future: F,
+        spawned_at: &'static Location<'static>,
+    ) -> Result<(JoinHandle<T>, slab::Key), SpawnError> {
+        let task_limit = unsafe { &mut *self.task_limit };
+        if task_limit.num_spawned >= task_limit.max_tasks {
+            return Err(SpawnError {
+                max_tasks: task_limit.max_tasks,
+            });
+        }
+        task_limit.num_spawned += 1;
+        unsafe { (*self.run_stats).num_tasks += 1 };
+
         let out = Rc::pin_in(RefCell::new(None), LocalAlloc::new());
         let join_handle = JoinHandle { out: out.clone() };
         let caller_task_id = self.task_id;
@@ -94,14 +358,87 @@ impl CurrentTaskContext {
                 CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
                     let ctx = ctx.as_mut().unwrap();
                     ctx.notify(caller_task_id);
+                    let task_limit = unsafe { &mut *ctx.task_limit };
+                    task_limit.num_spawned -= 1;
+                    if let Some(waiter) = task_limit.waiters.pop_front() {
+                        ctx.notify(waiter);
+                    }
                 });
             },
             LocalAlloc::new(),
         );
 
         let task_id = unsafe { (*self.tasks).insert(task) };
+        unsafe {
+            (*self.task_infos).insert(
+                task_id,
+                TaskInfo {
+                    spawned_at,
+                    num_slow_polls: 0,
+                },
+            )
+        };
+        trace_event!(
+            "io2::task",
+            "spawn task={:?} parent={:?} future={}",
+            task_id,
+            caller_task_id,
+            std::any::type_name::<F>()
+        );
+        self.notify(task_id);
+        Ok((join_handle, task_id))
+    }
+
+    /// Queues cancellation of all io operations the given task is waiting for.
+    ///
+    /// The operations complete with ECANCELED (or their result if they finished before getting cancelled), so the task
+    /// observes the cancellation as errors returned by its io futures.
+    pub(crate) fn cancel_task_io(&mut self, task_id: slab::Key) {
+        unsafe {
+            let Some(task_io) = (*self.task_io).get(&task_id) else {
+                return;
+            };
+            for &io_id in task_io.iter() {
+                if (*self.io)
+                    .get(io_id)
+                    .is_some_and(|slot| slot.task_id == task_id)
+                {
+                    self.cancel_io(io_id);
+                }
+            }
+        }
+    }
+
+    /// Queues cancellation of an operation, it completes with ECANCELED unless it finished before getting cancelled.
+    pub(crate) fn cancel_io(&mut self, io_id: slab::Key) {
+        unsafe {
+            // An operation that is held back by fault injection isn't in the kernel yet.
+            #[cfg(feature = "test_util")]
+            if let Some(i) = (*self.delayed_io).iter().position(|d| d.1 == io_id) {
+                (*self.delayed_io).swap_remove(i);
+                (*self.retries).remove(&io_id);
+                (*self.io_started).remove(&io_id);
+                let slot = (*self.io).get_mut(io_id).unwrap();
+                slot.result = Some(-libc::ECANCELED);
+                let task_id = slot.task_id;
+                self.notify(task_id);
+                return;
+            }
+            self.queue_detached_io(opcode::AsyncCancel::new(io_id.into()).build());
+        }
+    }
+
+    /// Cancels the io of a spawned task and drops its future once none of its io is running in the kernel, instead of
+    /// polling it again. The [JoinHandle] of the task never resolves. Does nothing if the task already finished.
+    pub(crate) fn abort_task(&mut self, task_id: slab::Key) {
+        unsafe {
+            if task_id == self.task_id || (*self.tasks).get(task_id).is_none() {
+                return;
+            }
+            (*self.aborted).insert(task_id, ());
+        }
+        self.cancel_task_io(task_id);
         self.notify(task_id);
-        join_handle
     }
 
     /// Task will be pinned until the entry is completely processed by io_uring.
@@ -112,8 +449,66 @@ impl CurrentTaskContext {
     /// drop the future if it returns Poll::Ready and this might invalidate some io operation it queued
     /// while it is running in the kernel.
     pub(crate) unsafe fn queue_io(&mut self, entry: squeue::Entry, direct_io: bool) -> slab::Key {
-        let io_id = (*self.io).insert(self.task_id);
+        self.num_queued += 1;
+        let io_id = (*self.io).insert(IoSlot {
+            task_id: self.task_id,
+            result: None,
+            memory_free: false,
+            abandoned: false,
+            #[cfg(debug_assertions)]
+            origin: Some(must_complete::IoOrigin {
+                opcode: entry_opcode(&entry),
+                queued_at: self
+                    .io_backtraces
+                    .then(std::backtrace::Backtrace::force_capture),
+            }),
+        });
+        if !self.queued_io.is_null() {
+            (*self.queued_io).push(io_id);
+        }
+        self.track_task_io(io_id);
         let entry = entry.user_data(io_id.into());
+        trace_event!(
+            "io2::io",
+            "queue task={:?} io={:?} opcode={} direct_io={}",
+            self.task_id,
+            io_id,
+            entry_opcode(&entry),
+            direct_io
+        );
+        if self.latency_metrics {
+            if let Some(class) = OpClass::from_opcode(entry_opcode(&entry)) {
+                (*self.io_started).insert(io_id, (Instant::now(), class));
+            }
+        }
+        if self.retry_policy.interrupted
+            || (self.retry_policy.short_io && continues_short_io(entry_opcode(&entry)))
+        {
+            (*self.retries).insert(
+                io_id,
+                RetryState {
+                    entry: entry.clone(),
+                    direct_io,
+                    done: 0,
+                },
+            );
+        }
+        #[cfg(feature = "test_util")]
+        let entry = match crate::test_util::intercept(entry) {
+            crate::test_util::Intercept::Submit(entry) => entry,
+            crate::test_util::Intercept::Complete(res) => {
+                (*self.retries).remove(&io_id);
+                (*self.io_started).remove(&io_id);
+                (*self.io).get_mut(io_id).unwrap().result = Some(res);
+                self.notify(self.task_id);
+                return io_id;
+            }
+            crate::test_util::Intercept::Delay(entry, when) => {
+                (*self.delayed_io).push((when, io_id, entry, direct_io));
+                self.notify_when(when);
+                return io_id;
+            }
+        };
         let queue = if direct_io {
             *self.num_dio_running = (*self.num_dio_running).checked_add(1).unwrap();
             self.dio_queue
@@ -124,150 +519,1075 @@ impl CurrentTaskContext {
         io_id
     }
 
-    pub(crate) fn notify_when(&mut self, when: Instant) {
+    fn track_task_io(&mut self, io_id: slab::Key) {
         unsafe {
-            let n = &mut *self.notify_when;
-            n.timer.push(when);
-            n.task_id.push(self.task_id);
-        };
+            let io = &*self.io;
+            let task_id = self.task_id;
+            let task_io = &mut *self.task_io;
+            match task_io.get_mut(&task_id) {
+                Some(ids) => {
+                    if ids.len() == ids.capacity() {
+                        ids.retain(|&id| io.get(id).is_some_and(|slot| slot.task_id == task_id));
+                    }
+                    ids.push(io_id);
+                }
+                None => {
+                    let mut ids = Vec::with_capacity_in(4, LocalAlloc::new());
+                    ids.push(io_id);
+                    task_io.insert(task_id, ids);
+                }
+            }
+        }
+    }
+
+    /// Marks an operation that doesn't point to any memory so its future can be dropped while it is running, it isn't
+    /// reported by [MustComplete] or the finished task check.
+    pub(crate) fn mark_memory_free(&mut self, io_id: slab::Key) {
+        unsafe {
+            if let Some(slot) = (*self.io).get_mut(io_id) {
+                slot.memory_free = true;
+            }
+        }
+    }
+
+    /// Gives up on the result of a memory free operation whose future is dropped, its slot is removed when it completes.
+    pub(crate) fn abandon_io(&mut self, io_id: slab::Key) {
+        unsafe {
+            let io = &mut *self.io;
+            let Some(slot) = io.get_mut(io_id) else {
+                return;
+            };
+            debug_assert!(slot.memory_free, "only memory free io can be abandoned");
+            if slot.result.is_some() {
+                io.remove(io_id);
+            } else {
+                slot.abandoned = true;
+            }
+        }
+    }
+
+    /// Same as [CurrentTaskContext::queue_io] but for commands that need 128 byte submission entries, they are submitted
+    /// to the uring cmd ring. Returns None if the executor doesn't have one.
+    ///
+    /// Results should be read using [CurrentTaskContext::take_cmd_result].
+    ///
+    /// Safety: Same as [CurrentTaskContext::queue_io].
+    pub(crate) unsafe fn queue_cmd_io(&mut self, entry: squeue::Entry128) -> Option<slab::Key> {
+        if self.cmd_queue.is_null() {
+            return None;
+        }
+        self.num_queued += 1;
+        let io_id = (*self.io).insert(IoSlot {
+            task_id: self.task_id,
+            result: None,
+            memory_free: false,
+            abandoned: false,
+            #[cfg(debug_assertions)]
+            origin: Some(must_complete::IoOrigin {
+                opcode: entry_opcode(&entry),
+                queued_at: self
+                    .io_backtraces
+                    .then(std::backtrace::Backtrace::force_capture),
+            }),
+        });
+        if !self.queued_io.is_null() {
+            (*self.queued_io).push(io_id);
+        }
+        self.track_task_io(io_id);
+        *self.num_cmd_running = (*self.num_cmd_running).checked_add(1).unwrap();
+        (*self.cmd_queue).push_back(entry.user_data(io_id.into()));
+        Some(io_id)
+    }
+
+    /// Returns the result of a command queued with [CurrentTaskContext::queue_cmd_io] and the first 8 bytes of the
+    /// extra data of its completion.
+    pub(crate) fn take_cmd_result(&mut self, io_id: slab::Key) -> Option<(i32, u64)> {
+        let res = self.take_io_result(io_id)?;
+        let extra = unsafe { (*self.cmd_results).remove(&io_id) };
+        Some((res, extra.unwrap_or(0)))
+    }
+
+    /// Same as [CurrentTaskContext::queue_io] but for operations that can post multiple completions.
+    ///
+    /// Results should be read using [CurrentTaskContext::take_multishot_result].
+    ///
+    /// Safety: Same as [CurrentTaskContext::queue_io] but the entry has to stay valid until the final completion is
+    /// taken or the operation is abandoned and the final completion is posted.
+    pub(crate) unsafe fn queue_multishot_io(&mut self, entry: squeue::Entry) -> slab::Key {
+        let io_id = self.queue_io(entry, false);
+        // Multishot operations can't be submitted again transparently.
+        (*self.retries).remove(&io_id);
+        let mut results = VecDeque::with_capacity_in(8, LocalAlloc::new());
+        // Mocked operations complete immediately, deliver it as the final completion.
+        if let Some(res) = (*self.io).get_mut(io_id).unwrap().result.take() {
+            results.push_back((res, 0));
+        }
+        (*self.multishot).insert(
+            io_id,
+            MultishotState {
+                results,
+                abandoned: false,
+            },
+        );
+        io_id
+    }
+
+    /// Returns the next (result, flags) pair posted for this multishot operation.
+    ///
+    /// The operation is finished after a result that doesn't have the IORING_CQE_F_MORE flag is returned.
+    pub(crate) fn take_multishot_result(&mut self, io_id: slab::Key) -> Option<(i32, u32)> {
+        unsafe {
+            let state = (*self.multishot).get_mut(&io_id)?;
+            let (res, flags) = state.results.pop_front()?;
+            if !cqueue::more(flags) {
+                (*self.multishot).remove(&io_id);
+                (*self.io).remove(io_id);
+            }
+            Some((res, flags))
+        }
+    }
+
+    /// Stops delivering the results of this multishot operation to the current task.
+    ///
+    /// This doesn't cancel the operation, caller should queue a cancellation if it is still running.
+    pub(crate) fn abandon_multishot_io(&mut self, io_id: slab::Key) {
+        unsafe {
+            if let Some(state) = (*self.multishot).get_mut(&io_id) {
+                match state.results.back() {
+                    Some(&(_, flags)) if !cqueue::more(flags) => {
+                        (*self.multishot).remove(&io_id);
+                        (*self.io).remove(io_id);
+                    }
+                    _ => {
+                        state.abandoned = true;
+                        state.results.clear();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Queues an operation that isn't owned by any task, its result is ignored.
+    ///
+    /// The executor doesn't exit before all detached operations are complete.
+    ///
+    /// Safety: The entry has to stay valid until the operation is complete, this is easiest to achieve with entries that don't
+    /// point to any memory, e.g. cancellation or close.
+    pub(crate) unsafe fn queue_detached_io(&mut self, entry: squeue::Entry) {
+        *self.num_detached_running = (*self.num_detached_running).checked_add(1).unwrap();
+        (*self.io_queue).push_back(entry.user_data(self.detached_io_id.into()));
+    }
+
+    pub(crate) fn notify_when(&mut self, when: Instant) -> TimerId {
+        unsafe { (*self.notify_when).insert(when, self.task_id) }
+    }
+
+    /// Removes a timer so it doesn't notify its task, does nothing if the timer already fired.
+    pub(crate) fn cancel_timer(&mut self, id: TimerId) {
+        unsafe { (*self.notify_when).remove(id) }
+    }
+
+    /// Changes when a timer fires, returns false if the timer already fired.
+    pub(crate) fn reset_timer(&mut self, id: TimerId, when: Instant) -> bool {
+        unsafe { (*self.notify_when).reset(id, when) }
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        unsafe {
+            let io = &*self.io;
+            // Owner of the executor's own operations, e.g. closing files.
+            let internal_task_id = io.get(self.detached_io_id).unwrap().task_id;
+            let mut tasks = Vec::new();
+            // Index of each task in `tasks`, so counting io and timers stays linear with many tasks.
+            let mut index = KeyMap::with_capacity_in((*self.tasks).len(), LocalAlloc::new());
+            for (task_id, _) in (*self.tasks).iter() {
+                if task_id != internal_task_id {
+                    index.insert(task_id, tasks.len());
+                    tasks.push(TaskSnapshot {
+                        id: task_id.into(),
+                        pending_io: 0,
+                        timers: 0,
+                    });
+                }
+            }
+            for (_, slot) in io.iter() {
+                if let Some(&i) = index.get(&slot.task_id) {
+                    tasks[i].pending_io += 1;
+                }
+            }
+            for (_, timer) in (*self.notify_when).timers.iter() {
+                if let Some(&i) = index.get(&timer.task_id) {
+                    tasks[i].timers += 1;
+                }
+            }
+            let tasks_slab = &*self.tasks;
+            let collections = vec![
+                CollectionStats::new(
+                    "tasks",
+                    tasks_slab.len(),
+                    tasks_slab.capacity(),
+                    tasks_slab.high_water_mark(),
+                ),
+                CollectionStats::new("io", io.len(), io.capacity(), io.high_water_mark()),
+                CollectionStats::of_map("task_infos", &*self.task_infos),
+                CollectionStats::of_map("to_notify", &*self.to_notify),
+            ];
+            Snapshot {
+                tasks,
+                num_dio_running: *self.num_dio_running,
+                collections,
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn num_timers(&self) -> usize {
+        unsafe { (*self.notify_when).timers.len() }
     }
 }
 
+/// Returns true if this is called from inside a task that is being polled by an executor.
+pub(crate) fn in_executor() -> bool {
+    CURRENT_TASK_CONTEXT.with_borrow(|ctx| ctx.is_some())
+}
+
+/// Returns the id of the task that is currently being polled.
+pub(crate) fn current_task_id() -> slab::Key {
+    CURRENT_TASK_CONTEXT.with_borrow(|ctx| ctx.as_ref().unwrap().task_id)
+}
+
+/// Schedules the given task to be polled.
+///
+/// Does nothing if it is called from outside of an executor, e.g. when a value that notifies on drop is dropped after
+/// the executor exits.
+pub(crate) fn notify_task(task_id: slab::Key) {
+    CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+        if let Some(ctx) = ctx.as_mut() {
+            ctx.notify(task_id);
+        }
+    });
+}
+
+/// Aborts a spawned task, see [CurrentTaskContext::abort_task].
+///
+/// Does nothing if it is called from outside of an executor.
+pub(crate) fn abort_task(task_id: slab::Key) {
+    CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+        if let Some(ctx) = ctx.as_mut() {
+            ctx.abort_task(task_id);
+        }
+    });
+}
+
+/// Makes the executor poll the current task again on the next iteration of its loop.
+///
+/// This registers a timer that is already expired, so the executor doesn't sleep until the task is polled again.
+/// Tests use it to let other tasks run once, futures that wait on other threads should use an
+/// [EventFd](crate::sync::EventFd) instead.
+#[cfg(test)]
+pub(crate) fn poll_next_tick() {
+    CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+        let ctx = ctx.as_mut().unwrap();
+        ctx.notify_when(crate::time::now());
+    });
+}
+
 /// Spawns a future to run in the background.
 ///
 /// This should only be used if the future to be spawned is doing significant CPU work,
 /// otherwise it is recommended to just nest it into the current future using mechanisms like `futures::future::join` and similar.
+#[track_caller]
 pub fn spawn<T: 'static, F: Future<Output = T> + 'static>(future: F) -> JoinHandle<T> {
+    let spawned_at = Location::caller();
     CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
         let ctx = ctx.as_mut().unwrap();
-        ctx.spawn(future)
+        ctx.spawn(future, spawned_at)
     })
 }
 
-pub struct ExecutorConfig {
-    ring_depth: u32,
-    preempt_duration: Duration,
+/// Registers a cleanup future that the executor runs before it exits, e.g. for closing a resource from its `Drop`
+/// implementation since `Drop` can't be async.
+///
+/// The future is spawned as a task on the next iteration of the executor loop and the executor doesn't return before
+/// it completes, like it waits for dropped [File](crate::fs::File)s to be closed. This can be called from outside of a
+/// task, e.g. from a value dropped after the executor exited, the future is run by the next executor on this thread in
+/// that case.
+#[track_caller]
+pub fn defer<F: Future<Output = ()> + 'static>(future: F) {
+    let spawned_at = Location::caller();
+    let task: Task = Box::pin_in(
+        async move {
+            future.await;
+            NUM_DEFERRED_RUNNING.set(NUM_DEFERRED_RUNNING.get() - 1);
+        },
+        LocalAlloc::new(),
+    );
+    DEFERRED.with_borrow_mut(|deferred| deferred.push((task, spawned_at)));
 }
 
-impl Default for ExecutorConfig {
-    fn default() -> Self {
-        Self::new()
-    }
+/// State of the executor at the time [snapshot] was called.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub tasks: Vec<TaskSnapshot>,
+    /// Number of direct io operations that are running in the kernel.
+    pub num_dio_running: usize,
+    /// Sizes of the executor's internal collections.
+    pub collections: Vec<CollectionStats>,
 }
 
-impl ExecutorConfig {
-    pub fn new() -> Self {
+/// Size of one of the executor's internal collections, see [Snapshot::collections].
+///
+/// The collections grow with the number of tasks and operations, the executor shrinks them when it is idle and they
+/// are mostly empty.
+#[derive(Clone, Debug)]
+pub struct CollectionStats {
+    pub name: &'static str,
+    pub len: usize,
+    pub capacity: usize,
+    /// Largest `len` since the executor started.
+    pub high_water_mark: usize,
+}
+
+impl CollectionStats {
+    fn new(name: &'static str, len: usize, capacity: usize, high_water_mark: usize) -> Self {
         Self {
-            ring_depth: 64,
-            preempt_duration: Duration::from_millis(10),
+            name,
+            len,
+            capacity,
+            high_water_mark,
         }
     }
 
-    pub fn ring_depth(mut self, ring_depth: u32) -> Self {
-        self.ring_depth = ring_depth;
-        self
+    fn of_map<K: PartialEq, V>(name: &'static str, map: &VecMap<K, V, LocalAlloc>) -> Self {
+        Self::new(name, map.len(), map.capacity(), map.high_water_mark())
     }
+}
 
-    pub fn preempt_duration(mut self, preempt_duration: Duration) -> Self {
-        self.preempt_duration = preempt_duration;
-        self
-    }
+#[derive(Clone, Debug)]
+pub struct TaskSnapshot {
+    pub id: u64,
+    /// Number of io operations the task submitted that didn't complete or weren't consumed by the task yet.
+    pub pending_io: usize,
+    pub timers: usize,
+}
 
-    pub fn run<T: 'static, F: Future<Output = T> + 'static>(self, future: F) -> io::Result<T> {
-        run(self.ring_depth, self.preempt_duration, future)
-    }
+/// Takes a snapshot of the tasks of the executor this is called from, e.g. to find out what a stuck process is
+/// waiting for.
+pub fn snapshot() -> Snapshot {
+    CURRENT_TASK_CONTEXT.with_borrow(|ctx| {
+        let ctx = ctx.as_ref().unwrap();
+        ctx.snapshot()
+    })
 }
 
-// TODO: Don't leak the file descriptors in FILES_TO_CLOSE when returning error.
-// this is almost ok since they will be cleaned when/if another executor runs in this thread. But
-// is a problem if user is spawning more and more threads and running executors in them.
-fn run<T: 'static, F: Future<Output = T> + 'static>(
-    ring_depth: u32,
-    preempt_duration: Duration,
+/// Spawns a future like [spawn] but returns an error instead of panicking if [ExecutorConfig::max_tasks] tasks are
+/// already running.
+#[track_caller]
+pub fn try_spawn<T: 'static, F: Future<Output = T> + 'static>(
     future: F,
-) -> io::Result<T> {
-    // This is to cleanup the thread local variable if there is a panic.
-    // It makes sure we are panic/unwind safe.
-    // If we don't set CURRENT_TASK_CONTEXT to none on panic using this, it will have dangling pointers which will cause memory unsafety.
-    let _current_task_context_guard = CurrentTaskContextGuard;
+) -> Result<JoinHandle<T>, SpawnError> {
+    try_spawn_at(future, Location::caller())
+}
 
-    let mut out = Option::<T>::None;
-    let out_ptr = &mut out as *mut Option<T>;
-    let task = Box::pin_in(
-        async move {
-            unsafe {
-                *out_ptr = Some(future.await);
-            }
-        },
-        LocalAlloc::new(),
-    );
+fn try_spawn_at<T: 'static, F: Future<Output = T> + 'static>(
+    future: F,
+    spawned_at: &'static Location<'static>,
+) -> Result<JoinHandle<T>, SpawnError> {
+    CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+        let ctx = ctx.as_mut().unwrap();
+        ctx.try_spawn_with_id(future, spawned_at)
+            .map(|(join_handle, _)| join_handle)
+    })
+}
+
+/// Spawns a future, handling the task limit according to the [SpawnPolicy] set with [ExecutorConfig::max_tasks].
+#[track_caller]
+pub fn spawn_bounded<T: 'static, F: Future<Output = T> + 'static>(
+    future: F,
+) -> impl Future<Output = Result<JoinHandle<T>, SpawnError>> {
+    let spawned_at = Location::caller();
+    async move {
+        let policy = CURRENT_TASK_CONTEXT
+            .with_borrow(|ctx| unsafe { (*ctx.as_ref().unwrap().task_limit).policy });
+        if policy == SpawnPolicy::WaitForSlot {
+            WaitForSlot { registered: false }.await;
+        }
+        try_spawn_at(future, spawned_at)
+    }
+}
+
+/// Describes a poll of a task that took longer than [ExecutorConfig::preempt_duration], see
+/// [ExecutorConfig::on_preemption].
+#[derive(Clone, Debug)]
+pub struct PreemptionReport {
+    pub task_id: u64,
+    /// Where the task was spawned, or where the executor was started for the future passed to [ExecutorConfig::run].
+    pub spawned_at: &'static Location<'static>,
+    pub poll_duration: Duration,
+    /// Number of polls of this task that took too long, including this one.
+    pub num_slow_polls: u64,
+}
+
+impl std::fmt::Display for PreemptionReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "task {} spawned at {} was polled for {:?}, slow polls: {}",
+            self.task_id, self.spawned_at, self.poll_duration, self.num_slow_polls
+        )
+    }
+}
+
+/// Statistics of a run of the executor, returned by [ExecutorConfig::run_with_report].
+#[derive(Clone, Debug)]
+pub struct RunReport {
+    /// Number of tasks that ran, including the future passed to run and deferred futures.
+    pub num_tasks: u64,
+    /// Number of io operations submitted to io_uring by opcode (e.g. `io_uring::opcode::Read::CODE`), sorted by
+    /// opcode. This includes retries and the operations of the executor itself, like closing dropped files.
+    pub io_by_opcode: Vec<(u8, u64)>,
+    /// Largest amount of memory the allocator of the executor thread had reserved from the system during the run.
+    pub peak_memory: usize,
+    pub wall_time: Duration,
+    /// CPU time used by the executor thread, including time spent in the kernel.
+    pub cpu_time: Duration,
+}
+
+impl RunReport {
+    /// Total number of io operations submitted.
+    pub fn num_io(&self) -> u64 {
+        self.io_by_opcode.iter().map(|&(_, count)| count).sum()
+    }
+}
+
+/// What [spawn_bounded] does when the task limit is reached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpawnPolicy {
+    /// Return a [SpawnError].
+    ErrOnSpawn,
+    /// Wait until one of the spawned tasks finishes.
+    WaitForSlot,
+}
+
+/// What the executor does when a task panics while it is being polled.
+///
+/// Other tasks might have io running in the kernel when a panic unwinds out of [ExecutorConfig::run]. Unwinding drops
+/// those tasks together with their buffers while the kernel can still write into them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PanicPolicy {
+    /// Unwind right away. This is only sound if no task has io running in the kernel when a panic happens.
+    #[default]
+    Unwind,
+    /// Abort the process.
+    Abort,
+    /// Cancel all io, wait until the kernel is done with it and then unwind.
+    ///
+    /// Requires linux kernel version >= 5.19, io that can't be cancelled on older kernels makes the executor wait
+    /// until it completes.
+    DrainIo,
+}
+
+/// Returned when a task can't be spawned because [ExecutorConfig::max_tasks] tasks are already running.
+#[derive(Debug)]
+pub struct SpawnError {
+    max_tasks: usize,
+}
+
+impl std::fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "task limit of {} tasks reached", self.max_tasks)
+    }
+}
+
+impl std::error::Error for SpawnError {}
+
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+struct WaitForSlot {
+    registered: bool,
+}
+
+impl Future for WaitForSlot {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Self::Output> {
+        CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+            let ctx = ctx.as_mut().unwrap();
+            let task_limit = unsafe { &mut *ctx.task_limit };
+            if task_limit.num_spawned < task_limit.max_tasks {
+                // This task might have been woken by a finished task, pass the slot on if it isn't used.
+                task_limit.waiters.retain(|&id| id != ctx.task_id);
+                self.registered = false;
+                Poll::Ready(())
+            } else {
+                if !task_limit.waiters.contains(&ctx.task_id) {
+                    task_limit.waiters.push_back(ctx.task_id);
+                }
+                self.registered = true;
+                Poll::Pending
+            }
+        })
+    }
+}
+
+impl Drop for WaitForSlot {
+    fn drop(&mut self) {
+        if self.registered {
+            CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+                if let Some(ctx) = ctx.as_mut() {
+                    let task_limit = unsafe { &mut *ctx.task_limit };
+                    task_limit.waiters.retain(|&id| id != ctx.task_id);
+                }
+            });
+        }
+    }
+}
+
+// Hooks are Send so the config can be built on one thread and run on another.
+type Hook = Box<dyn FnMut() + Send>;
+type PreemptionHook = Box<dyn FnMut(&PreemptionReport) + Send>;
+
+pub struct ExecutorConfig {
+    ring_depth: u32,
+    preempt_duration: Duration,
+    max_sqes_per_poll: usize,
+    defer_taskrun: bool,
+    retry_policy: RetryPolicy,
+    latency_metrics: bool,
+    max_tasks: usize,
+    spawn_policy: SpawnPolicy,
+    napi_busy_poll_timeout_us: Option<u32>,
+    napi_prefer_busy_poll: bool,
+    auto_fixed_buffers: Option<usize>,
+    fixed_file_table: Option<u32>,
+    panic_policy: PanicPolicy,
+    io_backtraces: bool,
+    uring_cmd_ring: Option<u32>,
+    pin_to_cpu: Option<usize>,
+    #[cfg(feature = "test_util")]
+    virtual_time: bool,
+    on_tick: Option<Hook>,
+    on_preemption: Option<PreemptionHook>,
+    on_idle: Option<Hook>,
+    before_poll: Option<Hook>,
+}
+
+impl Default for ExecutorConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExecutorConfig {
+    pub fn new() -> Self {
+        Self {
+            ring_depth: 64,
+            preempt_duration: Duration::from_millis(10),
+            max_sqes_per_poll: usize::MAX,
+            defer_taskrun: false,
+            retry_policy: RetryPolicy {
+                interrupted: false,
+                short_io: false,
+            },
+            latency_metrics: false,
+            max_tasks: usize::MAX,
+            spawn_policy: SpawnPolicy::ErrOnSpawn,
+            napi_busy_poll_timeout_us: None,
+            napi_prefer_busy_poll: false,
+            auto_fixed_buffers: None,
+            fixed_file_table: None,
+            panic_policy: PanicPolicy::Unwind,
+            io_backtraces: false,
+            uring_cmd_ring: None,
+            pin_to_cpu: None,
+            #[cfg(feature = "test_util")]
+            virtual_time: false,
+            on_tick: None,
+            on_preemption: None,
+            on_idle: None,
+            before_poll: None,
+        }
+    }
+
+    pub fn ring_depth(mut self, ring_depth: u32) -> Self {
+        self.ring_depth = ring_depth;
+        self
+    }
+
+    pub fn preempt_duration(mut self, preempt_duration: Duration) -> Self {
+        self.preempt_duration = preempt_duration;
+        self
+    }
+
+    /// Makes a task yield once it queued `max_sqes_per_poll` io operations in a single poll, in addition to the time
+    /// based preemption.
+    ///
+    /// The executor also stops polling other tasks and submits the queued operations when a task goes over this limit,
+    /// so a task that starts a lot of io at once can't fill up the ring before other tasks get to run.
+    pub fn max_sqes_per_poll(mut self, max_sqes_per_poll: usize) -> Self {
+        assert!(
+            max_sqes_per_poll > 0,
+            "max_sqes_per_poll must be greater than zero"
+        );
+        self.max_sqes_per_poll = max_sqes_per_poll;
+        self
+    }
+
+    /// Records the latency of io operations in the histograms of [crate::metrics::latency].
+    pub fn latency_metrics(mut self, latency_metrics: bool) -> Self {
+        self.latency_metrics = latency_metrics;
+        self
+    }
+
+    /// Limits the number of spawned tasks that can run at the same time, so e.g. an accept loop that spawns a task per
+    /// connection can't exhaust memory.
+    ///
+    /// [spawn] panics when the limit is reached, [try_spawn] returns an error and [spawn_bounded] follows `policy`.
+    pub fn max_tasks(mut self, max_tasks: usize, policy: SpawnPolicy) -> Self {
+        self.max_tasks = max_tasks;
+        self.spawn_policy = policy;
+        self
+    }
+
+    /// Sets up the ring with IORING_SETUP_DEFER_TASKRUN so the kernel only does completion work when the executor asks
+    /// for completions, instead of interrupting the thread whenever an operation completes.
+    ///
+    /// This reduces inter-processor interrupts and context switches, which improves tail latency. Requires linux kernel
+    /// version >= 6.1, the executor fails to start if it isn't supported.
+    pub fn defer_taskrun(mut self, defer_taskrun: bool) -> Self {
+        self.defer_taskrun = defer_taskrun;
+        self
+    }
+
+    /// Makes io operations that fail with EINTR get submitted again instead of returning the error.
+    ///
+    /// A copy of each operation is kept while it is running, so this adds a small cost to every operation.
+    pub fn retry_interrupted(mut self, retry_interrupted: bool) -> Self {
+        self.retry_policy.interrupted = retry_interrupted;
+        self
+    }
+
+    /// Makes reads and writes that transfer less than requested get submitted again for the rest, so they only
+    /// complete short at the end of the file (reads) or if an error happens after some of the data was transferred.
+    ///
+    /// This applies to every read on the executor, so it should only be enabled if reads are expected to fill their
+    /// buffers. A read from a socket or pipe would wait for more data instead of returning what is available.
+    pub fn continue_short_io(mut self, continue_short_io: bool) -> Self {
+        self.retry_policy.short_io = continue_short_io;
+        self
+    }
+
+    /// Makes the kernel busy-poll the network device queues for up to `timeout_us` microseconds when waiting for
+    /// network completions (IORING_REGISTER_NAPI).
+    ///
+    /// This trades cpu time for latency. Requires linux kernel version >= 6.9, the executor fails to start if it isn't supported.
+    pub fn napi_busy_poll(mut self, timeout_us: u32) -> Self {
+        self.napi_busy_poll_timeout_us = Some(timeout_us);
+        self
+    }
+
+    /// Sets SO_PREFER_BUSY_POLL semantics for napi busy polling, has no effect if napi_busy_poll is not set.
+    pub fn napi_prefer_busy_poll(mut self, prefer_busy_poll: bool) -> Self {
+        self.napi_prefer_busy_poll = prefer_busy_poll;
+        self
+    }
+
+    /// Makes file reads and writes of at least `min_len` bytes use io_uring fixed buffers when the buffer is allocated
+    /// from [LocalAlloc], see [fixed_buffers].
+    ///
+    /// Up to [fixed_buffers::NUM_SLOTS] pages are registered, a freed page stays pinned by the kernel until its slot is
+    /// reused. Registered memory counts against RLIMIT_MEMLOCK on some kernels, pages that fail to register fall back to
+    /// regular reads and writes. Requires linux kernel version >= 5.13, the executor fails to start if it isn't
+    /// supported.
+    pub fn auto_fixed_buffers(mut self, min_len: usize) -> Self {
+        self.auto_fixed_buffers = Some(min_len);
+        self
+    }
+
+    /// Registers a table of `size` fixed file slots on the rings, files are put into it with
+    /// [File::register](crate::fs::File::register).
+    ///
+    /// Operations on a registered file skip the file descriptor lookup in the kernel, which helps files that get a lot
+    /// of small operations. Each slot keeps its file open until it is unregistered.
+    pub fn fixed_file_table(mut self, size: u32) -> Self {
+        assert!(size > 0, "fixed file table size has to be positive");
+        self.fixed_file_table = Some(size);
+        self
+    }
+
+    /// Sets what happens when a task panics, see [PanicPolicy]. Defaults to [PanicPolicy::Unwind].
+    pub fn panic_policy(mut self, policy: PanicPolicy) -> Self {
+        self.panic_policy = policy;
+        self
+    }
+
+    /// Sets up a second ring of depth `depth` with 128 byte submission entries and 32 byte completion entries, for
+    /// IORING_OP_URING_CMD commands that don't fit into regular entries, e.g. NVMe passthrough with
+    /// [NvmeDevice](crate::block::nvme::NvmeDevice). Commands are submitted to it with [submit_raw_cmd].
+    ///
+    /// The executor waits on both rings, a poll on the second ring wakes it up when a command completes. Requires
+    /// linux kernel version >= 5.19, the executor fails to start if it isn't supported.
+    pub fn uring_cmd_ring(mut self, depth: u32) -> Self {
+        self.uring_cmd_ring = Some(depth);
+        self
+    }
+
+    /// Pins the executor thread to `cpu` when it starts, and binds the [LocalAlloc] pages that are allocated on the
+    /// thread after that to the NUMA node of the cpu so the executor doesn't access its memory across nodes.
+    ///
+    /// The thread stays pinned after the executor returns. The executor fails to start if the thread can't be pinned.
+    pub fn pin_to_cpu(mut self, cpu: usize) -> Self {
+        self.pin_to_cpu = Some(cpu);
+        self
+    }
+
+    /// Records a backtrace of where each io operation is queued, so [MustComplete] and the finished task check can
+    /// report where the io that was dropped while running came from.
+    ///
+    /// Capturing a backtrace is expensive so this is off by default, and it only has an effect in builds with debug
+    /// assertions.
+    pub fn io_backtraces(mut self, io_backtraces: bool) -> Self {
+        self.io_backtraces = io_backtraces;
+        self
+    }
+
+    /// Makes timers use a virtual clock that only moves when [crate::time::advance] is called.
+    ///
+    /// This makes tests of timeout/retry logic run instantly and deterministically.
+    #[cfg(feature = "test_util")]
+    pub fn virtual_time(mut self) -> Self {
+        self.virtual_time = true;
+        self
+    }
+
+    /// Sets a function that is called at the end of every iteration of the event loop.
+    ///
+    /// It runs after the tasks are polled, their io is submitted and completions/timers are processed. Hooks run
+    /// outside of any task so they can't spawn tasks or start io, they are meant for cheap housekeeping like flushing
+    /// metrics.
+    pub fn on_tick<F: FnMut() + Send + 'static>(mut self, f: F) -> Self {
+        self.on_tick = Some(Box::new(f));
+        self
+    }
+
+    /// Sets a function that is called when a single poll of a task takes longer than the preempt duration, instead of
+    /// logging a warning.
+    ///
+    /// Such a task keeps other tasks from running, calling [YieldIfNeeded] more frequently or using [run_compute]
+    /// should fix it.
+    pub fn on_preemption<F: FnMut(&PreemptionReport) + Send + 'static>(mut self, f: F) -> Self {
+        self.on_preemption = Some(Box::new(f));
+        self
+    }
+
+    /// Sets a function that is called every time the executor runs out of work and starts waiting for io or timers.
+    pub fn on_idle<F: FnMut() + Send + 'static>(mut self, f: F) -> Self {
+        self.on_idle = Some(Box::new(f));
+        self
+    }
+
+    /// Sets a function that is called right before the executor polls the tasks that are ready to run.
+    pub fn before_poll<F: FnMut() + Send + 'static>(mut self, f: F) -> Self {
+        self.before_poll = Some(Box::new(f));
+        self
+    }
+
+    #[track_caller]
+    pub fn run<T: 'static, F: Future<Output = T> + 'static>(self, future: F) -> io::Result<T> {
+        run(self, future, Location::caller()).map(|(out, _)| out)
+    }
+
+    /// Runs the future like [ExecutorConfig::run] and returns statistics of the run along with its output, e.g. for
+    /// batch jobs and benchmarks.
+    #[track_caller]
+    pub fn run_with_report<T: 'static, F: Future<Output = T> + 'static>(
+        self,
+        future: F,
+    ) -> io::Result<(T, RunReport)> {
+        run(self, future, Location::caller())
+    }
+
+    /// Runs each of `futures` as a separate task until all of them finish, returns their outputs in the same order.
+    ///
+    /// Futures of different types can be passed by boxing them, e.g. as `Pin<Box<dyn Future<Output = T>>>`.
+    #[track_caller]
+    pub fn run_all<T: 'static, F: Future<Output = T> + 'static>(
+        self,
+        futures: Vec<F>,
+    ) -> io::Result<Vec<T>> {
+        let spawned_at = Location::caller();
+        let future = async move {
+            let handles = futures
+                .into_iter()
+                .map(|future| {
+                    CURRENT_TASK_CONTEXT
+                        .with_borrow_mut(|ctx| ctx.as_mut().unwrap().spawn(future, spawned_at))
+                })
+                .collect::<Vec<_>>();
+            let mut outputs = Vec::with_capacity(handles.len());
+            for handle in handles {
+                outputs.push(handle.await);
+            }
+            outputs
+        };
+        run(self, future, spawned_at).map(|(out, _)| out)
+    }
+}
+
+// From linux/io_uring.h, io-uring crate doesn't support this yet.
+const IORING_REGISTER_NAPI: libc::c_uint = 27;
+
+#[repr(C)]
+struct IoUringNapi {
+    busy_poll_to: u32,
+    prefer_busy_poll: u8,
+    pad: [u8; 3],
+    resv: u64,
+}
+
+fn register_napi(ring: &IoUring, timeout_us: u32, prefer_busy_poll: bool) -> io::Result<()> {
+    let mut napi = IoUringNapi {
+        busy_poll_to: timeout_us,
+        prefer_busy_poll: u8::from(prefer_busy_poll),
+        pad: [0; 3],
+        resv: 0,
+    };
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_io_uring_register,
+            ring.as_raw_fd(),
+            IORING_REGISTER_NAPI,
+            &mut napi as *mut IoUringNapi,
+            1,
+        )
+    };
+    if res < 0 {
+        let err = io::Error::last_os_error();
+        return Err(io::Error::new(
+            err.kind(),
+            format!(
+                "failed to register napi busy polling, kernel might be too old: {}",
+                err
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// TODO: Don't leak the file descriptors in FILES_TO_CLOSE when returning error.
+// this is almost ok since they will be cleaned when/if another executor runs in this thread. But
+// is a problem if user is spawning more and more threads and running executors in them.
+fn run<T: 'static, F: Future<Output = T> + 'static>(
+    mut config: ExecutorConfig,
+    future: F,
+    spawned_at: &'static Location<'static>,
+) -> io::Result<(T, RunReport)> {
+    if let Some(cpu) = config.pin_to_cpu {
+        local_alloc::pin_to_cpu(cpu)?;
+    }
+    let run_start = Instant::now();
+    let cpu_start = thread_cpu_time();
+    local_alloc::take_peak_reserved_bytes();
+    let ring_depth = config.ring_depth;
+    let preempt_duration = config.preempt_duration;
+    let max_sqes_per_poll = config.max_sqes_per_poll;
+    let mut on_tick = config.on_tick.take();
+    let mut on_preemption = config.on_preemption.take();
+    let mut on_idle = config.on_idle.take();
+    let mut before_poll = config.before_poll.take();
+
+    // This is to cleanup the thread local variable if there is a panic.
+    // It makes sure we are panic/unwind safe.
+    // If we don't set CURRENT_TASK_CONTEXT to none on panic using this, it will have dangling pointers which will cause memory unsafety.
+    let _current_task_context_guard = CurrentTaskContextGuard;
+
+    #[cfg(feature = "test_util")]
+    let _virtual_time_guard = config.virtual_time.then(crate::time::VirtualTimeGuard::new);
+
+    let mut out = Option::<T>::None;
+    let out_ptr = &mut out as *mut Option<T>;
+    let task = Box::pin_in(
+        async move {
+            unsafe {
+                *out_ptr = Some(future.await);
+            }
+        },
+        LocalAlloc::new(),
+    );
 
     let waker = noop_waker();
     let mut poll_ctx = Context::from_waker(&waker);
 
-    let mut ring: IoUring<squeue::Entry, cqueue::Entry> = IoUring::builder()
-        .setup_single_issuer()
-        .setup_submit_all()
-        .setup_coop_taskrun()
-        .build(ring_depth)?;
+    let defer_taskrun = config.defer_taskrun;
+    let mut ring_builder = IoUring::<squeue::Entry, cqueue::Entry>::builder();
+    ring_builder.setup_single_issuer().setup_submit_all();
+    if defer_taskrun {
+        ring_builder.setup_defer_taskrun();
+    } else {
+        ring_builder.setup_coop_taskrun();
+    }
+    let mut ring = ring_builder.build(ring_depth)?;
     let mut dio_ring: IoUring<squeue::Entry, cqueue::Entry> = IoUring::builder()
         .setup_single_issuer()
         .setup_submit_all()
         .setup_coop_taskrun()
         .setup_iopoll()
         .build(ring_depth)?;
+    // Completions of commands are posted with task work, it has to interrupt the thread while it waits on the other
+    // ring so this doesn't use coop_taskrun.
+    let mut cmd_ring: Option<CmdRing> = config
+        .uring_cmd_ring
+        .map(|depth| {
+            IoUring::builder()
+                .setup_single_issuer()
+                .setup_submit_all()
+                .build(depth)
+        })
+        .transpose()?;
+    let mut cmd_queue =
+        VecDeque::<squeue::Entry128, LocalAlloc>::with_capacity_in(16, LocalAlloc::new());
+    let mut cmd_results = KeyMap::<u64, LocalAlloc>::with_capacity_in(16, LocalAlloc::new());
+    let mut num_cmd_running = 0usize;
+
+    if let Some(timeout_us) = config.napi_busy_poll_timeout_us {
+        register_napi(&ring, timeout_us, config.napi_prefer_busy_poll)?;
+    }
+    let mut fixed_buffers = config
+        .auto_fixed_buffers
+        .map(|min_len| FixedBuffers::register([ring.as_raw_fd(), dio_ring.as_raw_fd()], min_len))
+        .transpose()?;
+    let mut fixed_files = config
+        .fixed_file_table
+        .map(|size| FixedFiles::register([ring.as_raw_fd(), dio_ring.as_raw_fd()], size))
+        .transpose()?;
 
     let mut tasks = slab::Slab::<Task, LocalAlloc>::with_capacity_in(128, LocalAlloc::new());
-    let mut io = slab::Slab::<slab::Key, LocalAlloc>::with_capacity_in(128, LocalAlloc::new());
+    let mut io = Io::with_capacity_in(128, LocalAlloc::new());
+    let mut task_io = TaskIo::with_capacity_in(128, LocalAlloc::new());
+    let mut aborted = KeyMap::<(), LocalAlloc>::with_capacity_in(16, LocalAlloc::new());
     let mut io_queue =
         VecDeque::<squeue::Entry, LocalAlloc>::with_capacity_in(128, LocalAlloc::new());
     let mut dio_queue =
         VecDeque::<squeue::Entry, LocalAlloc>::with_capacity_in(128, LocalAlloc::new());
-    let mut io_results =
-        IoResults::with_capacity_in(usize::try_from(ring_depth).unwrap() * 4, LocalAlloc::new());
     let mut to_notify = ToNotify::with_capacity_in(128, LocalAlloc::new());
-    let mut notifying = Vec::<slab::Key, LocalAlloc>::with_capacity_in(128, LocalAlloc::new());
-    let mut notify_when = NotifyWhen {
-        timer: Vec::<Instant, LocalAlloc>::with_capacity_in(128, LocalAlloc::new()),
-        task_id: Vec::<slab::Key, LocalAlloc>::with_capacity_in(128, LocalAlloc::new()),
-    };
+    // Tasks are polled in the order they are notified. Tasks that didn't get to run because the loop was preempted stay
+    // at the front so they run before the tasks that are notified later.
+    let mut run_queue = VecDeque::<slab::Key, LocalAlloc>::with_capacity_in(128, LocalAlloc::new());
+    // Tasks that are in run_queue, so a task that is notified again before it runs isn't queued twice.
+    let mut queued = KeyMap::<(), LocalAlloc>::with_capacity_in(128, LocalAlloc::new());
+    let mut notify_when = NotifyWhen::with_capacity_in(128, LocalAlloc::new());
     let mut num_dio_running = 0usize;
+    let mut multishot = Multishot::with_capacity_in(16, LocalAlloc::new());
+    let mut retries = Retries::with_capacity_in(16, LocalAlloc::new());
+    let mut io_started = IoStarted::with_capacity_in(
+        if config.latency_metrics { 128 } else { 0 },
+        LocalAlloc::new(),
+    );
+    let mut task_limit = TaskLimit {
+        max_tasks: config.max_tasks,
+        policy: config.spawn_policy,
+        num_spawned: 0,
+        waiters: VecDeque::new_in(LocalAlloc::new()),
+    };
+    let mut run_stats = RunStats {
+        num_tasks: 1,
+        io_by_opcode: [0; 256],
+    };
+    #[cfg(feature = "test_util")]
+    let mut delayed_io = DelayedIo::new_in(LocalAlloc::new());
 
     let close_file_task_id = tasks.insert(Box::pin_in(async {}, LocalAlloc::new()));
-    let close_file_io_id = io.insert(close_file_task_id);
+    let internal_slot = || IoSlot {
+        task_id: close_file_task_id,
+        result: None,
+        memory_free: false,
+        abandoned: false,
+        #[cfg(debug_assertions)]
+        origin: None,
+    };
+    let close_file_io_id = io.insert(internal_slot());
     let mut files_closing = 0usize;
-
+    let detached_io_id = io.insert(internal_slot());
+    // Timeout operation that wakes the executor for the nearest timer when it is idle.
+    let timeout_io_id = io.insert(internal_slot());
+    let mut timeout_armed = Option::<Instant>::None;
+    // The kernel reads this when the timeout is submitted.
+    let mut timeout_ts: types::Timespec;
+    // Poll on the uring cmd ring that wakes the executor when a command completes.
+    let cmd_poll_io_id = cmd_ring.as_ref().map(|_| io.insert(internal_slot()));
+    debug_assert_eq!(io.len(), NUM_INTERNAL_IO + usize::from(cmd_ring.is_some()));
+    let mut cmd_poll_armed = false;
+    let mut num_detached_running = 0usize;
+
+    let mut task_infos = TaskInfos::with_capacity_in(128, LocalAlloc::new());
+    let mut last_shrink = Instant::now();
     let task_id = tasks.insert(task);
+    task_infos.insert(
+        task_id,
+        TaskInfo {
+            spawned_at,
+            num_slow_polls: 0,
+        },
+    );
+    trace_event!(
+        "io2::task",
+        "spawn task={:?} future={}",
+        task_id,
+        std::any::type_name::<F>()
+    );
     to_notify.insert(task_id, ());
 
-    while out.is_none() || files_closing > 0 || FILES_TO_CLOSE.with_borrow(|x| !x.is_empty()) {
+    // The count is left over if an executor panicked on this thread before, its tasks were dropped with it.
+    NUM_DEFERRED_RUNNING.set(0);
+    while out.is_none()
+        || files_closing > 0
+        || num_detached_running > 0
+        || num_cmd_running > 0
+        || FILES_TO_CLOSE.with_borrow(|x| !x.is_empty())
+        || NUM_DEFERRED_RUNNING.get() > 0
+        || DEFERRED.with_borrow(|x| !x.is_empty())
+    {
         {
-            let (_, sq, mut cq) = ring.split();
+            let (submitter, mut sq, mut cq) = ring.split();
             let (dio_submitter, dio_sq, mut dio_cq) = dio_ring.split();
 
             // nothing to submit, nothing completed yet and there are no tasks to run
             if sq.is_empty()
                 && cq.is_empty()
                 && to_notify.is_empty()
+                && run_queue.is_empty()
                 && io_queue.is_empty()
                 && FILES_TO_CLOSE.with_borrow(|x| x.is_empty())
+                && DEFERRED.with_borrow(|x| x.is_empty())
                 && dio_sq.is_empty()
                 && dio_cq.is_empty()
                 && dio_queue.is_empty()
+                && cmd_queue.is_empty()
+                && cmd_cq_is_empty(&mut cmd_ring)
             {
+                if let Some(on_idle) = on_idle.as_mut() {
+                    on_idle();
+                }
+                if last_shrink.elapsed() >= SHRINK_INTERVAL {
+                    last_shrink = Instant::now();
+                    shrink_collections(&mut tasks, &mut io, &mut task_infos, &mut to_notify);
+                }
                 'wait: loop {
                     for _ in 0..16 {
-                        if cq.is_empty() && dio_cq.is_empty() && to_notify.is_empty() {
-                            notify_timers(&mut notify_when, &mut to_notify);
+                        if cq.is_empty()
+                            && dio_cq.is_empty()
+                            && to_notify.is_empty()
+                            && cmd_cq_is_empty(&mut cmd_ring)
+                        {
+                            notify_when.notify_expired(crate::time::now(), &mut to_notify);
+                            if defer_taskrun {
+                                if let Err(err) = get_events(&submitter) {
+                                    return Err(ring_failed(err, tasks));
+                                }
+                            }
                             cq.sync();
                             if num_dio_running > 0 {
-                                match dio_submitter.submit_and_wait(0) {
-                                    Ok(_) => (),
-                                    Err(err) => {
-                                        if err.raw_os_error() != Some(libc::EBUSY) {
-                                            panic!("failed to io_uring.submit_and_wait on direct_io ring: {:?}", err);
-                                        }
-                                    }
+                                if let Err(err) = submit(&dio_submitter) {
+                                    return Err(ring_failed(err, tasks));
                                 }
                                 dio_cq.sync();
                             }
@@ -275,19 +1595,76 @@ fn run<T: 'static, F: Future<Output = T> + 'static>(
                             break 'wait;
                         }
                     }
-                    // Not sure if this is the best way to do it. It gives more latency than std::thread::yield_now() (apparently should never use yield_now in linux)
-                    // but it makes cpu usage negligible if all we are doing is waiting for some io.
-                    // Anyway it is better than using 100% cpu when we are only waiting for io.
-                    std::thread::sleep(Duration::from_nanos(1));
+                    if num_dio_running > 0 {
+                        // Completions of the polled ring only show up when it is polled so it can't block.
+                        // Not sure if this is the best way to do it. It gives more latency than std::thread::yield_now() (apparently should never use yield_now in linux)
+                        // but it makes cpu usage negligible if all we are doing is waiting for some io.
+                        // Anyway it is better than using 100% cpu when we are only waiting for io.
+                        std::thread::sleep(Duration::from_nanos(1));
+                        continue;
+                    }
+                    // Block until an operation completes, with a Timeout operation on the ring for the nearest timer
+                    // so the kernel wakes the thread when it expires.
+                    if let Some(deadline) = notify_when.next_deadline() {
+                        if timeout_armed.map_or(true, |armed| armed > deadline) {
+                            if timeout_armed.is_some() {
+                                num_detached_running = num_detached_running.checked_add(1).unwrap();
+                                let remove = opcode::TimeoutRemove::new(timeout_io_id.into())
+                                    .build()
+                                    .user_data(detached_io_id.into());
+                                unsafe { sq.push(&remove).unwrap() };
+                                run_stats.io_by_opcode[usize::from(opcode::TimeoutRemove::CODE)] +=
+                                    1;
+                            }
+                            timeout_ts =
+                                timespec(deadline.saturating_duration_since(crate::time::now()));
+                            let timeout = opcode::Timeout::new(&timeout_ts)
+                                .build()
+                                .user_data(timeout_io_id.into());
+                            unsafe { sq.push(&timeout).unwrap() };
+                            run_stats.io_by_opcode[usize::from(opcode::Timeout::CODE)] += 1;
+                            sq.sync();
+                            timeout_armed = Some(deadline);
+                        }
+                    }
+                    if let (Some(io_id), false, true) =
+                        (cmd_poll_io_id, cmd_poll_armed, num_cmd_running > 0)
+                    {
+                        let cmd_ring_fd = cmd_ring.as_ref().unwrap().as_raw_fd();
+                        let poll = opcode::PollAdd::new(Fd(cmd_ring_fd), libc::POLLIN as u32)
+                            .build()
+                            .user_data(io_id.into());
+                        unsafe { sq.push(&poll).unwrap() };
+                        run_stats.io_by_opcode[usize::from(opcode::PollAdd::CODE)] += 1;
+                        sq.sync();
+                        cmd_poll_armed = true;
+                    }
+                    if let Err(err) = wait_for_completion(&submitter) {
+                        return Err(ring_failed(err, tasks));
+                    }
+                    cq.sync();
                 }
             }
         }
 
         let start = Instant::now();
-        if !to_notify.is_empty() {
-            notifying.extend(to_notify.iter_keys());
+        if !to_notify.is_empty() || !run_queue.is_empty() {
+            if let Some(before_poll) = before_poll.as_mut() {
+                before_poll();
+            }
+            for task_id in to_notify.iter_keys() {
+                if queued.insert(*task_id, ()).is_none() {
+                    run_queue.push_back(*task_id);
+                }
+            }
             to_notify.clear();
-            while let Some(task_id) = notifying.pop() {
+            while let Some(task_id) = run_queue.pop_front() {
+                queued.remove(&task_id);
+                let aborting = aborted.contains_key(&task_id);
+                // An aborted task is notified again when its io completes.
+                if aborting && task_has_running_io(&io, &multishot, &task_io, task_id) {
+                    continue;
+                }
                 let task_start = Instant::now();
                 CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
                     *ctx = Some(CurrentTaskContext {
@@ -298,25 +1675,100 @@ fn run<T: 'static, F: Future<Output = T> + 'static>(
                         // Even if the running tasks spawn another task and the pointer of the running task moves in the slab,
                         // the actual task doesn't move.
                         tasks: &mut tasks,
-                        io_results: &mut io_results,
+                        task_infos: &mut task_infos,
                         io_queue: &mut io_queue,
                         dio_queue: &mut dio_queue,
                         preempt_duration,
+                        max_sqes_per_poll,
+                        num_queued: 0,
                         io: &mut io,
+                        task_io: &mut task_io,
+                        aborted: &mut aborted,
+                        cmd_queue: match cmd_ring {
+                            Some(_) => &mut cmd_queue,
+                            None => std::ptr::null_mut(),
+                        },
+                        num_cmd_running: &mut num_cmd_running,
+                        cmd_results: &mut cmd_results,
                         to_notify: &mut to_notify,
                         notify_when: &mut notify_when,
                         num_dio_running: &mut num_dio_running,
+                        multishot: &mut multishot,
+                        retry_policy: config.retry_policy,
+                        retries: &mut retries,
+                        latency_metrics: config.latency_metrics,
+                        io_started: &mut io_started,
+                        detached_io_id,
+                        task_limit: &mut task_limit,
+                        num_detached_running: &mut num_detached_running,
+                        fixed_buffers: &mut fixed_buffers,
+                        fixed_files: &mut fixed_files,
+                        run_stats: &mut run_stats,
+                        io_backtraces: config.io_backtraces,
+                        queued_io: std::ptr::null_mut(),
+                        #[cfg(feature = "test_util")]
+                        delayed_io: &mut delayed_io,
                     });
                 });
-                let poll_result = tasks
-                    .get_mut(task_id)
-                    .map(|task| task.as_mut().poll(&mut poll_ctx));
-                if task_start.elapsed() > preempt_duration {
-                    log::warn!("a task is using too much cpu time, this might cause other tasks to starve. calling yield_if_needed() more frequently should fix this.");
+                // The future of an aborted task is dropped while the context is set, so the drop code of its io futures
+                // and waiters can reach the executor.
+                let mut poll_task = || match aborting {
+                    true => tasks.remove(task_id).map(|_| Poll::Ready(())),
+                    false => tasks
+                        .get_mut(task_id)
+                        .map(|task| task.as_mut().poll(&mut poll_ctx)),
+                };
+                let poll_result = match config.panic_policy {
+                    PanicPolicy::Unwind => poll_task(),
+                    policy => match catch_unwind(AssertUnwindSafe(&mut poll_task)) {
+                        Ok(poll_result) => poll_result,
+                        Err(payload) => {
+                            CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| *ctx = None);
+                            if policy == PanicPolicy::Abort {
+                                log::error!("task {:?} panicked, aborting", task_id);
+                                std::process::abort();
+                            }
+                            let dio_in_flight = num_dio_running - dio_queue.len();
+                            let cmd_in_flight = num_cmd_running - cmd_queue.len();
+                            if let Err(err) = drain_io(&mut ring, &mut dio_ring, dio_in_flight)
+                                .and_then(|_| drain_cmd_io(cmd_ring.as_mut(), cmd_in_flight))
+                            {
+                                log::error!(
+                                    "task {:?} panicked and waiting for its io failed, aborting: {}",
+                                    task_id,
+                                    err
+                                );
+                                std::process::abort();
+                            }
+                            std::panic::resume_unwind(payload);
+                        }
+                    },
+                };
+                trace_event!(
+                    "io2::task",
+                    "poll task={:?} duration={:?} ready={}",
+                    task_id,
+                    task_start.elapsed(),
+                    matches!(poll_result, Some(Poll::Ready(_)))
+                );
+                let poll_duration = task_start.elapsed();
+                if poll_duration > preempt_duration {
+                    if let Some(info) = task_infos.get_mut(&task_id) {
+                        info.num_slow_polls += 1;
+                        let report = PreemptionReport {
+                            task_id: task_id.into(),
+                            spawned_at: info.spawned_at,
+                            poll_duration,
+                            num_slow_polls: info.num_slow_polls,
+                        };
+                        match on_preemption.as_mut() {
+                            Some(on_preemption) => on_preemption(&report),
+                            None => log::warn!("a task is using too much cpu time, this might cause other tasks to starve. calling yield_if_needed() more frequently should fix this. {}", report),
+                        }
+                    }
                 }
-                CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
-                    let _ = ctx.take().unwrap();
-                });
+                let num_queued =
+                    CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| ctx.take().unwrap().num_queued);
                 let poll_result = match poll_result {
                     Some(p) => p,
                     None => continue,
@@ -325,21 +1777,81 @@ fn run<T: 'static, F: Future<Output = T> + 'static>(
                     Poll::Pending => {}
                     Poll::Ready(_) => {
                         std::mem::drop(tasks.remove(task_id));
+                        task_infos.remove(&task_id);
+                        if aborted.remove(&task_id).is_some() {
+                            if let Some(aborted_io) = task_io.get(&task_id) {
+                                release_aborted_io(
+                                    &mut io,
+                                    &mut multishot,
+                                    &mut retries,
+                                    &mut io_started,
+                                    &mut cmd_results,
+                                    task_id,
+                                    aborted_io,
+                                );
+                            }
+                            // Done by the task itself when it finishes normally.
+                            task_limit.num_spawned -= 1;
+                            if let Some(waiter) = task_limit.waiters.pop_front() {
+                                to_notify.insert(waiter, ());
+                            }
+                        }
+                        #[cfg(not(debug_assertions))]
+                        task_io.remove(&task_id);
+                        #[cfg(debug_assertions)]
+                        if let Some(finished_io) = task_io.remove(&task_id) {
+                            must_complete::check_finished_task(
+                                &io,
+                                &multishot,
+                                task_id,
+                                &finished_io,
+                            );
+                        }
                     }
                 }
 
-                if start.elapsed() > preempt_duration {
+                if start.elapsed() > preempt_duration || num_queued >= max_sqes_per_poll {
                     break;
                 }
 
-                try_submit_io(&mut io_queue, &mut ring, false);
-                try_submit_io(&mut dio_queue, &mut dio_ring, false);
+                let counts = &mut run_stats.io_by_opcode;
+                if let Err(err) = try_submit_io(&mut io_queue, &mut ring, false, counts)
+                    .and_then(|_| try_submit_io(&mut dio_queue, &mut dio_ring, false, counts))
+                    .and_then(|_| submit_cmd_io(&mut cmd_queue, cmd_ring.as_mut(), counts))
+                {
+                    return Err(ring_failed(err, tasks));
+                }
             }
         }
 
-        try_submit_io(&mut io_queue, &mut ring, false);
-        try_submit_io(&mut dio_queue, &mut dio_ring, true);
+        #[cfg(feature = "test_util")]
+        release_delayed_io(
+            &mut delayed_io,
+            &mut io_queue,
+            &mut dio_queue,
+            &mut num_dio_running,
+        );
+
+        let counts = &mut run_stats.io_by_opcode;
+        if let Err(err) = try_submit_io(&mut io_queue, &mut ring, false, counts)
+            .and_then(|_| try_submit_io(&mut dio_queue, &mut dio_ring, true, counts))
+            .and_then(|_| submit_cmd_io(&mut cmd_queue, cmd_ring.as_mut(), counts))
+        {
+            return Err(ring_failed(err, tasks));
+        }
+        // The kernel didn't take all of the submissions, wait for some of the running operations to complete so
+        // their completions can be reaped before trying again.
+        if (!io_queue.is_empty() || !ring.submission().is_empty()) && ring.completion().is_empty() {
+            if let Err(err) = wait_for_completion_timeout(&ring.submitter(), SUBMIT_BACKOFF) {
+                return Err(ring_failed(err, tasks));
+            }
+        }
 
+        if defer_taskrun {
+            if let Err(err) = get_events(&ring.submitter()) {
+                return Err(ring_failed(err, tasks));
+            }
+        }
         let mut dio_cq = dio_ring.completion();
         let mut cq = ring.completion();
         cq.sync();
@@ -351,15 +1863,107 @@ fn run<T: 'static, F: Future<Output = T> + 'static>(
                 files_closing = files_closing.checked_sub(1).unwrap();
                 continue;
             }
-            let task_id = *io.get(io_id).unwrap();
-            io_results.insert(io_id, cqe.result());
-            to_notify.insert(task_id, ());
-        }
-
-        notify_timers(&mut notify_when, &mut to_notify);
-
-        // close files
-        FILES_TO_CLOSE.with_borrow_mut(|files| {
+            if io_id == detached_io_id {
+                num_detached_running = num_detached_running.checked_sub(1).unwrap();
+                continue;
+            }
+            if io_id == timeout_io_id {
+                // A timeout that was replaced by an earlier one completes with ECANCELED.
+                if cqe.result() == -libc::ETIME {
+                    timeout_armed = None;
+                }
+                continue;
+            }
+            if Some(io_id) == cmd_poll_io_id {
+                cmd_poll_armed = false;
+                continue;
+            }
+            let task_id = match io.get(io_id) {
+                Some(slot) if slot.abandoned => {
+                    retries.remove(&io_id);
+                    io_started.remove(&io_id);
+                    io.remove(io_id);
+                    continue;
+                }
+                Some(slot) => slot.task_id,
+                None => {
+                    // Stale completion of an operation that was already removed. Its slot might hold a newer
+                    // operation, the generation in the key keeps this from being routed to it.
+                    log::debug!("ignoring completion of unknown io {:?}", io_id);
+                    continue;
+                }
+            };
+            if let Some(state) = multishot.get_mut(&io_id) {
+                if state.abandoned {
+                    if !cqueue::more(cqe.flags()) {
+                        multishot.remove(&io_id);
+                        io.remove(io_id);
+                    }
+                    continue;
+                }
+                state.results.push_back((cqe.result(), cqe.flags()));
+                to_notify.insert(task_id, ());
+                continue;
+            }
+            let mut res = cqe.result();
+            if let Some(state) = retries.get_mut(&io_id) {
+                match next_retry(state, res, config.retry_policy) {
+                    Ok(entry) => {
+                        if state.direct_io {
+                            num_dio_running = num_dio_running.checked_add(1).unwrap();
+                            dio_queue.push_back(entry);
+                        } else {
+                            io_queue.push_back(entry);
+                        }
+                        continue;
+                    }
+                    Err(final_res) => {
+                        res = final_res;
+                        retries.remove(&io_id);
+                    }
+                }
+            }
+            trace_event!(
+                "io2::io",
+                "complete task={:?} io={:?} res={}",
+                task_id,
+                io_id,
+                res
+            );
+            if let Some((started, class)) = io_started.remove(&io_id) {
+                latency::record(class, started.elapsed());
+            }
+            io.get_mut(io_id).unwrap().result = Some(res);
+            to_notify.insert(task_id, ());
+        }
+
+        if let Some(cmd_ring) = cmd_ring.as_mut() {
+            let mut cmd_cq = cmd_ring.completion();
+            cmd_cq.sync();
+            for cqe in cmd_cq {
+                num_cmd_running = num_cmd_running.checked_sub(1).unwrap();
+                let io_id = slab::Key::from(cqe.user_data());
+                let Some(slot) = io.get_mut(io_id) else {
+                    log::debug!("ignoring completion of unknown command {:?}", io_id);
+                    continue;
+                };
+                trace_event!(
+                    "io2::io",
+                    "complete task={:?} io={:?} res={}",
+                    slot.task_id,
+                    io_id,
+                    cqe.result()
+                );
+                slot.result = Some(cqe.result());
+                cmd_results.insert(io_id, cqe.big_cqe()[0]);
+                to_notify.insert(slot.task_id, ());
+            }
+        }
+
+        notify_when.notify_expired(crate::time::now(), &mut to_notify);
+
+        // close files
+        FILES_TO_CLOSE.with_borrow_mut(|files| {
             for &fd in files.iter() {
                 files_closing = files_closing.checked_add(1).unwrap();
                 io_queue.push_back(
@@ -370,50 +1974,401 @@ fn run<T: 'static, F: Future<Output = T> + 'static>(
             }
             files.clear();
         });
+
+        // spawn deferred futures
+        if DEFERRED.with_borrow(|x| !x.is_empty()) {
+            let deferred = DEFERRED.replace(Vec::new_in(LocalAlloc::new()));
+            for (task, spawned_at) in deferred {
+                NUM_DEFERRED_RUNNING.set(NUM_DEFERRED_RUNNING.get() + 1);
+                run_stats.num_tasks += 1;
+                let task_id = tasks.insert(task);
+                task_infos.insert(
+                    task_id,
+                    TaskInfo {
+                        spawned_at,
+                        num_slow_polls: 0,
+                    },
+                );
+                trace_event!("io2::task", "spawn deferred task={:?}", task_id);
+                to_notify.insert(task_id, ());
+            }
+        }
+
+        if let Some(on_tick) = on_tick.as_mut() {
+            on_tick();
+        }
     }
 
-    Ok(out.unwrap())
+    let report = RunReport {
+        num_tasks: run_stats.num_tasks,
+        io_by_opcode: (0..=u8::MAX)
+            .zip(run_stats.io_by_opcode)
+            .filter(|&(_, count)| count > 0)
+            .collect(),
+        peak_memory: local_alloc::take_peak_reserved_bytes(),
+        wall_time: run_start.elapsed(),
+        cpu_time: thread_cpu_time().saturating_sub(cpu_start),
+    };
+    Ok((out.unwrap(), report))
+}
+
+fn thread_cpu_time() -> Duration {
+    let mut ts: libc::timespec = unsafe { std::mem::zeroed() };
+    unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) };
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
 }
 
-fn notify_timers(notify_when: &mut NotifyWhen, to_notify: &mut VecMap<slab::Key, (), LocalAlloc>) {
-    let time = Instant::now();
+// Collections are shrunk at most this often so a bursty load doesn't reallocate them on every idle period.
+const SHRINK_INTERVAL: Duration = Duration::from_secs(1);
+// A collection is shrunk when its capacity is this many times larger than what it needs.
+const SHRINK_RATIO: usize = 4;
+// Collections aren't shrunk below this capacity.
+const SHRINK_MIN_CAPACITY: usize = 128;
+
+fn shrink_collections(
+    tasks: &mut slab::Slab<Task, LocalAlloc>,
+    io: &mut Io,
+    task_infos: &mut TaskInfos,
+    to_notify: &mut ToNotify,
+) {
+    let target = |len: usize| len.max(SHRINK_MIN_CAPACITY);
+    if tasks.capacity() > SHRINK_RATIO * target(tasks.len()) {
+        tasks.shrink_to(target(tasks.len()));
+    }
+    if io.capacity() > SHRINK_RATIO * target(io.len()) {
+        io.shrink_to(target(io.len()));
+    }
+    if task_infos.capacity() > SHRINK_RATIO * target(task_infos.len()) {
+        task_infos.shrink_to(target(task_infos.len()));
+    }
+    if to_notify.capacity() > SHRINK_RATIO * target(to_notify.len()) {
+        to_notify.shrink_to(target(to_notify.len()));
+    }
+}
+
+#[cfg(feature = "test_util")]
+fn release_delayed_io(
+    delayed_io: &mut DelayedIo,
+    io_queue: &mut VecDeque<squeue::Entry, LocalAlloc>,
+    dio_queue: &mut VecDeque<squeue::Entry, LocalAlloc>,
+    num_dio_running: &mut usize,
+) {
+    let time = crate::time::now();
     let mut i = 0;
-    loop {
-        if i >= notify_when.timer.len() {
-            break;
+    while i < delayed_io.len() {
+        if delayed_io[i].0 > time {
+            i += 1;
+            continue;
+        }
+        let (_, _, entry, direct_io) = delayed_io.swap_remove(i);
+        if direct_io {
+            *num_dio_running = num_dio_running.checked_add(1).unwrap();
+            dio_queue.push_back(entry);
+        } else {
+            io_queue.push_back(entry);
         }
+    }
+}
 
-        let timer = *notify_when.timer.get(i).unwrap();
-        if timer >= time {
-            i += 1;
+fn entry_opcode<E: squeue::EntryMarker>(entry: &E) -> u8 {
+    // opcode is the first byte of io_uring_sqe.
+    unsafe { *(entry as *const E as *const u8) }
+}
+
+/// Returns true for operations that transfer data through a single buffer, the length of the buffer is the `len`
+/// field of their entry.
+pub(crate) fn is_read_write(code: u8) -> bool {
+    code == opcode::Read::CODE
+        || code == opcode::Write::CODE
+        || code == opcode::ReadFixed::CODE
+        || code == opcode::WriteFixed::CODE
+        || code == opcode::Send::CODE
+        || code == opcode::Recv::CODE
+}
+
+// Short recvs aren't continued, a recv returns the data the socket has and waiting for more could block forever.
+fn continues_short_io(code: u8) -> bool {
+    is_read_write(code) && code != opcode::Recv::CODE
+}
+
+/// Decides what to do with a completion of an operation that can be retried, returns the entry to submit again or the
+/// result the operation should complete with.
+fn next_retry(state: &mut RetryState, res: i32, policy: RetryPolicy) -> Result<squeue::Entry, i32> {
+    if res == -libc::EINTR && policy.interrupted {
+        return Ok(state.entry.clone());
+    }
+    let code = entry_opcode(&state.entry);
+    if !policy.short_io || !continues_short_io(code) {
+        return Err(res);
+    }
+    if res <= 0 {
+        // Return the progress that was made before an error or the end of the file.
+        return Err(if state.done > 0 {
+            i32::try_from(state.done).unwrap()
         } else {
-            notify_when.timer.swap_remove(i);
-            let task_id = notify_when.task_id.swap_remove(i);
-            to_notify.insert(task_id, ());
+            res
+        });
+    }
+
+    // squeue::Entry is a repr(C) wrapper around io_uring_sqe but io-uring doesn't expose setters for these fields
+    // after the entry is built. Offsets are from the io_uring_sqe definition in linux/io_uring.h.
+    let sqe = unsafe {
+        std::slice::from_raw_parts_mut(
+            &mut state.entry as *mut squeue::Entry as *mut u8,
+            std::mem::size_of::<squeue::Entry>(),
+        )
+    };
+    let len = u32::from_ne_bytes(sqe[24..28].try_into().unwrap());
+    let n = res.unsigned_abs();
+    state.done += n;
+    if n >= len {
+        return Err(i32::try_from(state.done).unwrap());
+    }
+    let offset = u64::from_ne_bytes(sqe[8..16].try_into().unwrap());
+    // u64::MAX means the current file position, which the kernel advances. Send doesn't use the offset.
+    if offset != u64::MAX && code != opcode::Send::CODE {
+        sqe[8..16].copy_from_slice(&(offset + u64::from(n)).to_ne_bytes());
+    }
+    let addr = u64::from_ne_bytes(sqe[16..24].try_into().unwrap());
+    sqe[16..24].copy_from_slice(&(addr + u64::from(n)).to_ne_bytes());
+    sqe[24..28].copy_from_slice(&(len - n).to_ne_bytes());
+    Ok(state.entry.clone())
+}
+
+// From linux/io_uring.h
+const IORING_ENTER_GETEVENTS: u32 = 1;
+
+/// Runs the deferred task work of a ring that is setup with DEFER_TASKRUN so completions are posted to the
+/// completion queue, without waiting for any.
+fn get_events(submitter: &Submitter) -> io::Result<()> {
+    match unsafe { submitter.enter::<libc::sigset_t>(0, 0, IORING_ENTER_GETEVENTS, None) } {
+        Ok(_) => Ok(()),
+        Err(err) if is_transient_enter_error(&err) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+// Longest wait for a completion before submitting again when the kernel doesn't take new submissions.
+const SUBMIT_BACKOFF: Duration = Duration::from_micros(100);
+const IORING_ENTER_EXT_ARG: u32 = 1 << 3;
+
+// struct io_uring_getevents_arg from linux/io_uring.h.
+#[repr(C)]
+struct IoUringGeteventsArg {
+    sigmask: u64,
+    sigmask_sz: u32,
+    pad: u32,
+    ts: u64,
+}
+
+/// Errors from io_uring_enter that mean the kernel can't take the submissions right now. Entries that weren't
+/// submitted stay in the submission queue and are submitted again on the next iteration of the loop.
+fn is_transient_enter_error(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EBUSY | libc::EAGAIN | libc::ENOMEM | libc::EINTR)
+    )
+}
+
+/// Submits the entries in the submission queue and blocks until at least one operation completes.
+///
+/// Returns early if the wait is interrupted, the caller checks for completions and waits again.
+fn wait_for_completion(submitter: &Submitter) -> io::Result<()> {
+    match submitter.submit_and_wait(1) {
+        Ok(_) => Ok(()),
+        Err(err) if is_transient_enter_error(&err) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Waits until an operation completes or `timeout` passes without submitting anything, so completions can be reaped
+/// while the kernel doesn't take new submissions.
+fn wait_for_completion_timeout(submitter: &Submitter, timeout: Duration) -> io::Result<()> {
+    let ts = libc::timespec {
+        tv_sec: libc::time_t::try_from(timeout.as_secs()).unwrap(),
+        tv_nsec: libc::c_long::from(timeout.subsec_nanos()),
+    };
+    let arg = IoUringGeteventsArg {
+        sigmask: 0,
+        sigmask_sz: 0,
+        pad: 0,
+        ts: &ts as *const libc::timespec as u64,
+    };
+    let flags = IORING_ENTER_GETEVENTS | IORING_ENTER_EXT_ARG;
+    match unsafe { submitter.enter(0, 1, flags, Some(&arg)) } {
+        Ok(_) => Ok(()),
+        // EINVAL means the kernel doesn't support the timeout (< 5.11), submitting is retried right away then.
+        Err(err) if matches!(err.raw_os_error(), Some(libc::ETIME | libc::EINVAL)) => Ok(()),
+        Err(err) if is_transient_enter_error(&err) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+fn timespec(duration: Duration) -> types::Timespec {
+    types::Timespec::new()
+        .sec(duration.as_secs())
+        .nsec(duration.subsec_nanos())
+}
+
+/// Submits the entries in the submission queue, only returns an error if the ring can't be used anymore.
+fn submit(submitter: &Submitter) -> io::Result<()> {
+    loop {
+        match submitter.submit() {
+            Ok(_) => return Ok(()),
+            Err(err) if err.raw_os_error() == Some(libc::EINTR) => continue,
+            Err(err) if is_transient_enter_error(&err) => {
+                // EBUSY means the completion queue is full, EAGAIN and ENOMEM mean the kernel couldn't allocate memory
+                // for the requests. The entries stay in the submission queue, the executor reaps completions and
+                // submits them again.
+                if err.raw_os_error() != Some(libc::EBUSY) {
+                    log::debug!("io_uring submission failed, retrying: {}", err);
+                }
+                return Ok(());
+            }
+            Err(err) => return Err(err),
         }
     }
 }
 
-fn try_submit_io(
-    io_queue: &mut VecDeque<squeue::Entry, LocalAlloc>,
+/// Waits until the kernel is done with all io that was submitted to the rings, so the buffers of the tasks can be freed.
+///
+/// Operations on `ring` are cancelled first so sockets that never become ready and long timeouts don't block this.
+/// Direct io can't be cancelled, the `dio_in_flight` operations that were submitted to `dio_ring` are waited for.
+fn drain_io(
     ring: &mut IoUring,
-    force_submit: bool,
+    dio_ring: &mut IoUring,
+    mut dio_in_flight: usize,
+) -> io::Result<()> {
+    const CANCEL_ID: u64 = u64::MAX - 1;
+    const DRAIN_ID: u64 = u64::MAX;
+
+    let (submitter, mut sq, mut cq) = ring.split();
+    // Make room for the two entries below, entries that were already pushed are submitted with them.
+    while sq.capacity() - sq.len() < 2 {
+        sq.sync();
+        submit(&submitter)?;
+        sq.sync();
+    }
+    let cancel = opcode::AsyncCancel2::new(types::CancelBuilder::any())
+        .build()
+        .user_data(CANCEL_ID);
+    // Completes only after everything that was submitted before it.
+    let drain = opcode::Nop::new()
+        .build()
+        .flags(squeue::Flags::IO_DRAIN)
+        .user_data(DRAIN_ID);
+    unsafe {
+        sq.push(&cancel).unwrap();
+        sq.push(&drain).unwrap();
+    }
+    sq.sync();
+    'drain: loop {
+        wait_for_completion(&submitter)?;
+        cq.sync();
+        for cqe in &mut cq {
+            if cqe.user_data() == DRAIN_ID {
+                break 'drain;
+            }
+        }
+    }
+
+    while dio_in_flight > 0 {
+        wait_for_completion(&dio_ring.submitter())?;
+        let mut dio_cq = dio_ring.completion();
+        dio_cq.sync();
+        dio_in_flight = dio_in_flight.saturating_sub(dio_cq.len());
+        dio_cq.for_each(drop);
+    }
+    Ok(())
+}
+
+fn task_has_running_io(
+    io: &Io,
+    multishot: &Multishot,
+    task_io: &TaskIo,
+    task_id: slab::Key,
+) -> bool {
+    task_io.get(&task_id).is_some_and(|task_io| {
+        task_io.iter().any(|&io_id| {
+            must_complete::running_io(io, multishot, io_id)
+                .is_some_and(|slot| slot.task_id == task_id)
+        })
+    })
+}
+
+// Removes the slots of the finished io of an aborted task, nothing is going to take their results. Memory free
+// operations that are still running are abandoned so their slots are removed when they complete.
+fn release_aborted_io(
+    io: &mut Io,
+    multishot: &mut Multishot,
+    retries: &mut Retries,
+    io_started: &mut IoStarted,
+    cmd_results: &mut KeyMap<u64, LocalAlloc>,
+    task_id: slab::Key,
+    task_io: &[slab::Key],
 ) {
+    for &io_id in task_io {
+        let Some(slot) = io.get_mut(io_id) else {
+            continue;
+        };
+        if slot.task_id != task_id || multishot.get(&io_id).is_some_and(|state| state.abandoned) {
+            continue;
+        }
+        if slot.memory_free && slot.result.is_none() {
+            slot.abandoned = true;
+            continue;
+        }
+        io.remove(io_id);
+        multishot.remove(&io_id);
+        retries.remove(&io_id);
+        io_started.remove(&io_id);
+        cmd_results.remove(&io_id);
+    }
+}
+
+/// Waits until the `in_flight` commands that were submitted to the uring cmd ring complete, they can't be cancelled.
+fn drain_cmd_io(cmd_ring: Option<&mut CmdRing>, mut in_flight: usize) -> io::Result<()> {
+    let Some(cmd_ring) = cmd_ring else {
+        return Ok(());
+    };
+    while in_flight > 0 {
+        wait_for_completion(&cmd_ring.submitter())?;
+        let mut cmd_cq = cmd_ring.completion();
+        cmd_cq.sync();
+        in_flight = in_flight.saturating_sub(cmd_cq.len());
+        cmd_cq.for_each(drop);
+    }
+    Ok(())
+}
+
+/// Converts an error that makes the ring unusable into the error returned from [ExecutorConfig::run].
+///
+/// Tasks might own buffers that the kernel is still using, so they are leaked instead of dropped.
+fn ring_failed(err: io::Error, tasks: slab::Slab<Task, LocalAlloc>) -> io::Error {
+    std::mem::forget(tasks);
+    io::Error::new(
+        err.kind(),
+        format!("failed to submit io to io_uring: {}", err),
+    )
+}
+
+fn try_submit_io<S: squeue::EntryMarker, C: cqueue::EntryMarker>(
+    io_queue: &mut VecDeque<S, LocalAlloc>,
+    ring: &mut IoUring<S, C>,
+    force_submit: bool,
+    io_by_opcode: &mut [u64; 256],
+) -> io::Result<()> {
     let (submitter, mut sq, _) = ring.split();
 
     while !io_queue.is_empty() {
         if sq.is_full() {
             sq.sync();
-            match submitter.submit() {
-                Ok(_) => (),
-                Err(err) => {
-                    if err.raw_os_error() != Some(libc::EBUSY) {
-                        panic!("failed to io_uring.submit_and_wait: {:?}", err);
-                    }
-                    break;
-                }
-            };
+            submit(&submitter)?;
             sq.sync();
+            if sq.is_full() {
+                // Submission failed for a transient reason, try again later.
+                break;
+            }
         }
 
         match io_queue.pop_front() {
@@ -423,6 +2378,7 @@ fn try_submit_io(
                 if let Err(e) = sq.push(&entry) {
                     panic!("io_uring tried to push to sq while it was full: {:?}", e);
                 }
+                io_by_opcode[usize::from(entry_opcode(&entry))] += 1;
             },
             None => break,
         }
@@ -430,16 +2386,32 @@ fn try_submit_io(
 
     if force_submit || !sq.is_empty() {
         sq.sync();
-        match submitter.submit() {
-            Ok(_) => (),
-            Err(err) => {
-                if err.raw_os_error() != Some(libc::EBUSY) {
-                    panic!("failed to io_uring.submit_and_wait: {:?}", err);
-                }
-            }
-        };
+        submit(&submitter)?;
         sq.sync();
     }
+
+    Ok(())
+}
+
+fn cmd_cq_is_empty(cmd_ring: &mut Option<CmdRing>) -> bool {
+    match cmd_ring {
+        Some(cmd_ring) => cmd_ring.completion().is_empty(),
+        None => true,
+    }
+}
+
+// Commands are only queued if the executor has a uring cmd ring.
+fn submit_cmd_io(
+    cmd_queue: &mut VecDeque<squeue::Entry128, LocalAlloc>,
+    cmd_ring: Option<&mut CmdRing>,
+    io_by_opcode: &mut [u64; 256],
+) -> io::Result<()> {
+    match cmd_ring {
+        Some(cmd_ring) if !cmd_queue.is_empty() => {
+            try_submit_io(cmd_queue, cmd_ring, false, io_by_opcode)
+        }
+        _ => Ok(()),
+    }
 }
 
 unsafe fn noop_clone(_data: *const ()) -> RawWaker {
@@ -459,6 +2431,33 @@ pub fn noop_waker() -> Waker {
     unsafe { Waker::from_raw(noop_raw_waker()) }
 }
 
+/// Runs a CPU bound computation in steps, yielding to other tasks between steps once the task used up its time slice
+/// (see [ExecutorConfig::preempt_duration]).
+///
+/// `step` is called until it returns `ControlFlow::Break`, each call should do a small bounded amount of work like
+/// hashing a single chunk of a buffer.
+pub async fn run_compute<T, F: FnMut() -> ControlFlow<T>>(mut step: F) -> T {
+    loop {
+        if let ControlFlow::Break(out) = step() {
+            return out;
+        }
+        YieldIfNeeded.await;
+    }
+}
+
+/// Calls `f` with every item of `iter`, yielding to other tasks between items like [run_compute].
+pub async fn for_each_compute<I: IntoIterator, F: FnMut(I::Item)>(iter: I, mut f: F) {
+    let mut iter = iter.into_iter();
+    run_compute(|| match iter.next() {
+        Some(item) => {
+            f(item);
+            ControlFlow::Continue(())
+        }
+        None => ControlFlow::Break(()),
+    })
+    .await
+}
+
 #[must_use = "futures do nothing unless you `.await` or poll them"]
 pub struct YieldIfNeeded;
 
@@ -477,6 +2476,194 @@ impl Future for YieldIfNeeded {
     }
 }
 
+/// Queues a prebuilt entry and resolves to its raw result.
+///
+/// Safety: Same as [CurrentTaskContext::queue_io], the entry has to stay valid while this future is pinned.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub(crate) struct RawIo {
+    entry: Option<squeue::Entry>,
+    io_id: Option<slab::Key>,
+    memory_free: bool,
+}
+
+impl RawIo {
+    pub(crate) unsafe fn new(entry: squeue::Entry) -> Self {
+        Self {
+            entry: Some(entry),
+            io_id: None,
+            memory_free: false,
+        }
+    }
+
+    /// For entries that don't point to any memory, e.g. a poll on a file descriptor. The future can be dropped while
+    /// the operation is running, its completion is ignored then.
+    ///
+    /// Safety: The entry must not point to any memory.
+    pub(crate) unsafe fn memory_free(entry: squeue::Entry) -> Self {
+        Self {
+            entry: Some(entry),
+            io_id: None,
+            memory_free: true,
+        }
+    }
+}
+
+impl Drop for RawIo {
+    fn drop(&mut self) {
+        if let (true, Some(io_id)) = (self.memory_free, self.io_id) {
+            CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+                if let Some(ctx) = ctx.as_mut() {
+                    ctx.abandon_io(io_id);
+                }
+            });
+        }
+    }
+}
+
+impl Future for RawIo {
+    type Output = i32;
+
+    fn poll(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Self::Output> {
+        CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+            let ctx = ctx.as_mut().unwrap();
+            let fut = self.get_mut();
+            match fut.io_id {
+                None => {
+                    let io_id = unsafe { ctx.queue_io(fut.entry.take().unwrap(), false) };
+                    if fut.memory_free {
+                        ctx.mark_memory_free(io_id);
+                    }
+                    fut.io_id = Some(io_id);
+                    Poll::Pending
+                }
+                Some(io_id) => match ctx.take_io_result(io_id) {
+                    Some(io_result) => {
+                        fut.io_id = None;
+                        Poll::Ready(io_result)
+                    }
+                    None => Poll::Pending,
+                },
+            }
+        })
+    }
+}
+
+/// Submits a prebuilt io_uring entry and returns a future that resolves to its raw result.
+///
+/// This is an escape hatch for operations io2 doesn't have a wrapper for, e.g. `UringCmd` for NVMe passthrough. The
+/// result is the `res` field of the completion, a negative errno on failure. The `user_data` of the entry is
+/// overwritten since the executor uses it to route the completion to the current task. Only single shot operations
+/// are supported.
+///
+/// The entry is queued when the returned future is first polled.
+///
+/// # Safety
+///
+/// All memory the entry points to (buffers, paths, structs etc.) has to stay valid and must not be moved until the
+/// returned future completes. The returned future must be polled to completion once it is polled, dropping it while
+/// the operation is in flight means the kernel might write into memory that is freed.
+pub unsafe fn submit_raw(entry: squeue::Entry) -> RawCompletion {
+    RawCompletion {
+        io: RawIo::new(entry),
+        _non_send: PhantomData,
+    }
+}
+
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct RawCompletion {
+    io: RawIo,
+    _non_send: PhantomData<*mut ()>,
+}
+
+impl Future for RawCompletion {
+    type Output = i32;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.get_mut().io).poll(cx)
+    }
+}
+
+/// Returns true if the executor running on this thread was configured with [ExecutorConfig::uring_cmd_ring].
+///
+/// Panics if called outside of an executor.
+pub fn uring_cmd_ring_enabled() -> bool {
+    CURRENT_TASK_CONTEXT.with_borrow(|ctx| !ctx.as_ref().unwrap().cmd_queue.is_null())
+}
+
+/// Same as [submit_raw] but for entries with 128 byte submission entries, e.g. `UringCmd80`. The entry is submitted
+/// to the ring configured with [ExecutorConfig::uring_cmd_ring].
+///
+/// Resolves to the `res` field of the completion and the first 8 bytes of its extra data, e.g. the `result` field of
+/// an NVMe completion.
+///
+/// The future panics when it is polled if the executor doesn't have a uring cmd ring, [uring_cmd_ring_enabled] can be
+/// used to check.
+///
+/// # Safety
+///
+/// Same as [submit_raw].
+pub unsafe fn submit_raw_cmd(entry: squeue::Entry128) -> RawCmdCompletion {
+    RawCmdCompletion {
+        entry: Some(entry),
+        io_id: None,
+        _non_send: PhantomData,
+    }
+}
+
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct RawCmdCompletion {
+    entry: Option<squeue::Entry128>,
+    io_id: Option<slab::Key>,
+    _non_send: PhantomData<*mut ()>,
+}
+
+impl Future for RawCmdCompletion {
+    type Output = (i32, u64);
+
+    fn poll(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Self::Output> {
+        CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+            let ctx = ctx.as_mut().unwrap();
+            let fut = self.get_mut();
+            match fut.io_id {
+                None => {
+                    let io_id = unsafe { ctx.queue_cmd_io(fut.entry.take().unwrap()) }
+                        .expect("the executor doesn't have a uring cmd ring, see ExecutorConfig::uring_cmd_ring");
+                    fut.io_id = Some(io_id);
+                    Poll::Pending
+                }
+                Some(io_id) => match ctx.take_cmd_result(io_id) {
+                    Some(res) => {
+                        fut.io_id = None;
+                        Poll::Ready(res)
+                    }
+                    None => Poll::Pending,
+                },
+            }
+        })
+    }
+}
+
+/// Resolves to the next (result, flags) pair of a multishot operation queued with
+/// [CurrentTaskContext::queue_multishot_io].
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub(crate) struct NextMultishot {
+    pub(crate) io_id: slab::Key,
+}
+
+impl Future for NextMultishot {
+    type Output = (i32, u32);
+
+    fn poll(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Self::Output> {
+        CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+            let ctx = ctx.as_mut().unwrap();
+            match ctx.take_multishot_result(self.io_id) {
+                Some(res) => Poll::Ready(res),
+                None => Poll::Pending,
+            }
+        })
+    }
+}
+
 pub struct JoinHandle<T> {
     out: Pin<Rc<RefCell<Option<T>>, LocalAlloc>>,
 }
@@ -526,6 +2713,548 @@ mod tests {
         assert_eq!(r, 0);
     }
 
+    #[test]
+    fn test_run_all() {
+        let services: Vec<Pin<Box<dyn Future<Output = u32>>>> = vec![
+            Box::pin(async {
+                crate::time::sleep(Duration::from_millis(10)).await;
+                1
+            }),
+            Box::pin(async { 2 }),
+            Box::pin(async {
+                YieldIfNeeded.await;
+                3
+            }),
+        ];
+        let out = ExecutorConfig::new().run_all(services).unwrap();
+        assert_eq!(out, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_max_tasks() {
+        ExecutorConfig::new()
+            .max_tasks(2, SpawnPolicy::WaitForSlot)
+            .run(async {
+                let running = Rc::new(std::cell::Cell::new(0));
+                let max_running = Rc::new(std::cell::Cell::new(0));
+                let mut handles = Vec::new();
+                for i in 0..5 {
+                    if i == 2 {
+                        assert!(try_spawn(async {}).is_err());
+                    }
+                    let running = running.clone();
+                    let max_running = max_running.clone();
+                    let handle = spawn_bounded(async move {
+                        running.set(running.get() + 1);
+                        max_running.set(max_running.get().max(running.get()));
+                        crate::time::sleep(Duration::from_millis(2)).await;
+                        running.set(running.get() - 1);
+                    })
+                    .await
+                    .unwrap();
+                    handles.push(handle);
+                }
+                for handle in handles {
+                    handle.await;
+                }
+                assert_eq!(max_running.get(), 2);
+                try_spawn(async {}).unwrap().await;
+            })
+            .unwrap();
+
+        ExecutorConfig::new()
+            .max_tasks(1, SpawnPolicy::ErrOnSpawn)
+            .run(async {
+                let handle = spawn(crate::time::sleep(Duration::from_millis(1)));
+                let err = spawn_bounded(async {}).await.err().unwrap();
+                assert_eq!(err.to_string(), "task limit of 1 tasks reached");
+                handle.await;
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_shrink_collections() {
+        ExecutorConfig::new()
+            .run(async {
+                let handles = (0..2000)
+                    .map(|_| spawn(crate::time::sleep(Duration::from_millis(1))))
+                    .collect::<Vec<_>>();
+                for handle in handles {
+                    handle.await;
+                }
+                let tasks = |snapshot: Snapshot| {
+                    snapshot
+                        .collections
+                        .into_iter()
+                        .find(|c| c.name == "tasks")
+                        .unwrap()
+                };
+                let before = tasks(snapshot());
+                assert!(before.high_water_mark > 2000);
+                assert!(before.capacity >= 2000);
+
+                // The executor shrinks the collections the first time it becomes idle after the interval.
+                crate::time::sleep(SHRINK_INTERVAL).await;
+                crate::time::sleep(Duration::from_millis(1)).await;
+                let after = tasks(snapshot());
+                assert!(after.capacity < before.capacity);
+                assert_eq!(after.high_water_mark, before.high_water_mark);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        fn thread_cpu_time() -> Duration {
+            let mut usage = unsafe { std::mem::zeroed::<libc::rusage>() };
+            assert_eq!(
+                unsafe { libc::getrusage(libc::RUSAGE_THREAD, &mut usage) },
+                0
+            );
+            let micros = |t: libc::timeval| t.tv_sec as u64 * 1_000_000 + t.tv_usec as u64;
+            Duration::from_micros(micros(usage.ru_utime) + micros(usage.ru_stime))
+        }
+
+        ExecutorConfig::new()
+            .run(async {
+                // The executor blocks with a timeout for this timer while it waits for the blocking call.
+                let long = spawn(crate::time::sleep(Duration::from_secs(3600)));
+                crate::blocking::run_blocking(|| std::thread::sleep(Duration::from_millis(5)))
+                    .await
+                    .unwrap();
+
+                // An earlier timer replaces the armed timeout.
+                let start = Instant::now();
+                let cpu_start = thread_cpu_time();
+                crate::time::sleep(Duration::from_millis(50)).await;
+                let elapsed = start.elapsed();
+                assert!(elapsed >= Duration::from_millis(50));
+                assert!(elapsed < Duration::from_secs(1));
+                assert!(thread_cpu_time() - cpu_start < Duration::from_millis(10));
+                std::mem::drop(long);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_run_compute() {
+        ExecutorConfig::new()
+            .preempt_duration(Duration::from_millis(1))
+            .run(async {
+                let ticks = Rc::new(std::cell::Cell::new(0));
+                let ticker_ticks = ticks.clone();
+                let ticker = spawn(async move {
+                    while ticker_ticks.get() < 3 {
+                        ticker_ticks.set(ticker_ticks.get() + 1);
+                        crate::time::sleep(Duration::ZERO).await;
+                    }
+                });
+
+                // Spins until the other task got to run a few times, this would never finish without yielding.
+                let mut steps = 0u64;
+                let steps = run_compute(|| {
+                    steps += 1;
+                    if ticks.get() < 3 {
+                        ControlFlow::Continue(())
+                    } else {
+                        ControlFlow::Break(steps)
+                    }
+                })
+                .await;
+                assert!(steps > 1);
+                ticker.await;
+
+                let mut sum = 0;
+                for_each_compute(1..=100, |i| sum += i).await;
+                assert_eq!(sum, 5050);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_latency_metrics() {
+        ExecutorConfig::new()
+            .latency_metrics(true)
+            .run(async {
+                let file =
+                    crate::fs::File::open(std::path::Path::new("Cargo.toml"), libc::O_RDONLY, 0)
+                        .unwrap()
+                        .await
+                        .unwrap();
+                let mut buf = [0; 16];
+                for _ in 0..10 {
+                    file.read(&mut buf, 0).await.unwrap();
+                }
+                file.sync_all().await.unwrap();
+
+                let reads = latency::histogram(OpClass::Read);
+                assert_eq!(reads.count(), 10);
+                assert!(reads.percentile(99.0).unwrap() <= reads.max().unwrap());
+                assert_eq!(latency::histogram(OpClass::Fsync).count(), 1);
+                assert_eq!(latency::histogram(OpClass::Write).count(), 0);
+                latency::reset();
+                assert_eq!(latency::histogram(OpClass::Read).count(), 0);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_preemption_report() {
+        // Both locations are the caller of this function.
+        #[track_caller]
+        fn spawn_here<F: Future<Output = ()> + 'static>(
+            future: F,
+        ) -> (JoinHandle<()>, &'static Location<'static>) {
+            (spawn(future), Location::caller())
+        }
+
+        let reports = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let hook_reports = reports.clone();
+        let spawned_at = ExecutorConfig::new()
+            .preempt_duration(Duration::from_millis(1))
+            .on_preemption(move |report| hook_reports.lock().unwrap().push(report.clone()))
+            .run(async {
+                let (busy, spawned_at) = spawn_here(async {
+                    for _ in 0..2 {
+                        std::thread::sleep(Duration::from_millis(2));
+                        crate::time::sleep(Duration::ZERO).await;
+                    }
+                });
+                busy.await;
+                spawned_at
+            })
+            .unwrap();
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 2);
+        for (i, report) in reports.iter().enumerate() {
+            assert_eq!(report.spawned_at, spawned_at);
+            assert!(report.poll_duration >= Duration::from_millis(2));
+            assert_eq!(report.num_slow_polls, u64::try_from(i).unwrap() + 1);
+        }
+    }
+
+    #[test]
+    fn test_run_with_report() {
+        let (out, report) = ExecutorConfig::new()
+            .run_with_report(async {
+                let handles = (0..3)
+                    .map(|_| {
+                        spawn(async { unsafe { RawIo::new(opcode::Nop::new().build()) }.await })
+                    })
+                    .collect::<Vec<_>>();
+                for handle in handles {
+                    assert_eq!(handle.await, 0);
+                }
+                defer(async {});
+                7
+            })
+            .unwrap();
+        assert_eq!(out, 7);
+        assert_eq!(report.num_tasks, 5);
+        assert!(report.io_by_opcode.contains(&(opcode::Nop::CODE, 3)));
+        assert_eq!(report.num_io(), 3);
+        assert!(report.peak_memory > 0);
+        assert!(report.wall_time > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_submit_error() {
+        let mut ring = IoUring::new(8).unwrap();
+        let mut queue = VecDeque::new_in(LocalAlloc::new());
+        queue.push_back(opcode::Nop::new().build());
+        try_submit_io(&mut queue, &mut ring, false, &mut [0; 256]).unwrap();
+
+        // Replace the ring fd with something that isn't a ring so io_uring_enter fails.
+        let null = std::fs::File::open("/dev/null").unwrap();
+        assert!(unsafe { libc::dup2(null.as_raw_fd(), ring.as_raw_fd()) } >= 0);
+        queue.push_back(opcode::Nop::new().build());
+        let err = try_submit_io(&mut queue, &mut ring, false, &mut [0; 256]).unwrap_err();
+        assert!(!is_transient_enter_error(&err));
+    }
+
+    #[test]
+    fn test_next_retry() {
+        let policy = RetryPolicy {
+            interrupted: true,
+            short_io: true,
+        };
+        let mut buf = [0u8; 100];
+        let mut state = RetryState {
+            entry: opcode::Read::new(Fd(0), buf.as_mut_ptr(), 100)
+                .offset(10)
+                .build(),
+            direct_io: false,
+            done: 0,
+        };
+        assert!(next_retry(&mut state, -libc::EINTR, policy).is_ok());
+        let entry = next_retry(&mut state, 30, policy).unwrap();
+        let sqe = unsafe {
+            std::slice::from_raw_parts(
+                &entry as *const squeue::Entry as *const u8,
+                std::mem::size_of::<squeue::Entry>(),
+            )
+        };
+        assert_eq!(u64::from_ne_bytes(sqe[8..16].try_into().unwrap()), 40);
+        assert_eq!(
+            u64::from_ne_bytes(sqe[16..24].try_into().unwrap()),
+            buf[30..].as_ptr() as u64
+        );
+        assert_eq!(u32::from_ne_bytes(sqe[24..28].try_into().unwrap()), 70);
+        assert_eq!(next_retry(&mut state, 0, policy).unwrap_err(), 30);
+
+        let mut state = RetryState {
+            entry: opcode::Nop::new().build(),
+            direct_io: false,
+            done: 0,
+        };
+        assert_eq!(next_retry(&mut state, 5, policy).unwrap_err(), 5);
+    }
+
+    #[cfg(feature = "test_util")]
+    #[test]
+    fn test_continue_short_io() {
+        use crate::fs::file::File;
+        use crate::test_util::{inject_faults, Fault, FaultPolicy, FaultRule};
+
+        ExecutorConfig::new()
+            .continue_short_io(true)
+            .run(async {
+                let file = File::open(std::path::Path::new("Cargo.toml"), libc::O_RDONLY, 0)
+                    .unwrap()
+                    .await
+                    .unwrap();
+                let _guard = inject_faults(
+                    FaultPolicy::new(0)
+                        .rule(FaultRule::new(Fault::Short(3)).opcode(opcode::Read::CODE)),
+                );
+                let mut buf = [0; 64];
+                assert_eq!(file.read(&mut buf, 0).await.unwrap(), 64);
+                assert_eq!(&buf[..], &std::fs::read("Cargo.toml").unwrap()[..64]);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_napi_busy_poll() {
+        let r = ExecutorConfig::new()
+            .napi_busy_poll(50)
+            .napi_prefer_busy_poll(true)
+            .run(async {
+                YieldIfNeeded.await;
+                1
+            });
+        match r {
+            Ok(v) => assert_eq!(v, 1),
+            // kernel is older than 6.9
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+        }
+    }
+
+    // Yields once by notifying the current task.
+    struct YieldNow(bool);
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Self::Output> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            notify_task(current_task_id());
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn test_fifo_fairness() {
+        let order = Rc::new(RefCell::new(Vec::new()));
+        ExecutorConfig::new()
+            // The loop is preempted after each poll.
+            .preempt_duration(Duration::ZERO)
+            .run({
+                let order = order.clone();
+                async move {
+                    let handles = (0..4)
+                        .map(|i| {
+                            let order = order.clone();
+                            spawn(async move {
+                                for _ in 0..10 {
+                                    order.borrow_mut().push(i);
+                                    YieldNow(false).await;
+                                }
+                            })
+                        })
+                        .collect::<Vec<_>>();
+                    for handle in handles {
+                        handle.await;
+                    }
+                }
+            })
+            .unwrap();
+
+        // Tasks that keep waking themselves up take turns.
+        let order = order.borrow();
+        assert_eq!(order.len(), 40);
+        for round in order.chunks(4) {
+            let mut round = round.to_vec();
+            round.sort();
+            assert_eq!(round, [0, 1, 2, 3]);
+        }
+    }
+
+    #[test]
+    fn test_max_sqes_per_poll() {
+        ExecutorConfig::new()
+            .max_sqes_per_poll(4)
+            .run(async {
+                let mut ios = (0..8)
+                    .map(|_| Box::pin(unsafe { RawIo::new(opcode::Nop::new().build()) }))
+                    .collect::<Vec<_>>();
+                let should_yield = std::future::poll_fn(|cx| {
+                    for io in ios.iter_mut() {
+                        assert!(io.as_mut().poll(cx).is_pending());
+                    }
+                    Poll::Ready(
+                        CURRENT_TASK_CONTEXT
+                            .with_borrow(|ctx| ctx.as_ref().unwrap().yield_if_needed()),
+                    )
+                })
+                .await;
+                assert!(should_yield);
+                for io in ios {
+                    assert_eq!(io.await, 0);
+                }
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_loop_hooks() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let idles = Arc::new(AtomicUsize::new(0));
+        let polls = Arc::new(AtomicUsize::new(0));
+        let (t, i, p) = (ticks.clone(), idles.clone(), polls.clone());
+        ExecutorConfig::new()
+            .on_tick(move || {
+                t.fetch_add(1, Ordering::Relaxed);
+            })
+            .on_idle(move || {
+                i.fetch_add(1, Ordering::Relaxed);
+            })
+            .before_poll(move || {
+                p.fetch_add(1, Ordering::Relaxed);
+            })
+            .run(async {
+                crate::time::sleep(Duration::from_millis(5)).await;
+            })
+            .unwrap();
+        // Polled once to start the sleep and once when the timer fires.
+        let polls = polls.load(Ordering::Relaxed);
+        assert!(polls >= 2);
+        assert!(idles.load(Ordering::Relaxed) >= 1);
+        assert!(ticks.load(Ordering::Relaxed) >= polls);
+    }
+
+    #[test]
+    fn test_defer_taskrun() {
+        let r = ExecutorConfig::new().defer_taskrun(true).run(async {
+            let res = unsafe { RawIo::new(opcode::Nop::new().build()) }.await;
+            crate::time::sleep(Duration::from_millis(1)).await;
+            let file = crate::fs::File::open(std::path::Path::new("Cargo.toml"), libc::O_RDONLY, 0)
+                .unwrap()
+                .await
+                .unwrap();
+            let mut buf = [0; 8];
+            file.read(&mut buf, 0).await.unwrap();
+            res
+        });
+        match r {
+            Ok(res) => assert_eq!(res, 0),
+            // kernel is older than 6.1
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+        }
+    }
+
+    #[cfg(feature = "trace")]
+    thread_local! {
+        // Trace events of the test thread, captured while this is Some.
+        static TRACE_CAPTURE: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+    }
+
+    #[cfg(feature = "trace")]
+    pub(super) fn capture_trace(target: &str, args: std::fmt::Arguments) {
+        TRACE_CAPTURE.with_borrow_mut(|records| {
+            if let Some(records) = records {
+                records.push(format!("{} {}", target, args));
+            }
+        });
+    }
+
+    #[cfg(feature = "trace")]
+    #[test]
+    fn test_trace() {
+        TRACE_CAPTURE.set(Some(Vec::new()));
+
+        ExecutorConfig::new()
+            .run(async {
+                spawn(async {
+                    assert_eq!(unsafe { submit_raw(opcode::Nop::new().build()) }.await, 0);
+                })
+                .await;
+            })
+            .unwrap();
+
+        let records = TRACE_CAPTURE
+            .take()
+            .unwrap()
+            .iter()
+            .map(|record| record.split(' ').take(2).collect::<Vec<_>>().join(" "))
+            .collect::<Vec<_>>();
+        let expected = [
+            "io2::task spawn",
+            "io2::task spawn",
+            "io2::task poll",
+            // The io is queued while the spawned task is being polled.
+            "io2::io queue",
+            "io2::task poll",
+            "io2::io complete",
+            "io2::task poll",
+            "io2::task poll",
+        ];
+        assert_eq!(records, expected);
+    }
+
+    #[test]
+    fn test_submit_raw() {
+        ExecutorConfig::new()
+            .run(async {
+                assert_eq!(unsafe { submit_raw(opcode::Nop::new().build()) }.await, 0);
+
+                let file =
+                    crate::fs::File::open(std::path::Path::new("Cargo.toml"), libc::O_RDONLY, 0)
+                        .unwrap()
+                        .await
+                        .unwrap();
+                let mut buf = [0u8; 9];
+                let res = unsafe {
+                    submit_raw(opcode::Read::new(Fd(file.fd), buf.as_mut_ptr(), 9).build())
+                }
+                .await;
+                assert_eq!(res, 9);
+                assert_eq!(&buf, b"[package]");
+
+                let res = unsafe { submit_raw(opcode::Fsync::new(Fd(-1)).build()) }.await;
+                assert_eq!(res, -libc::EBADF);
+            })
+            .unwrap();
+    }
+
     #[test]
     fn test_unwind_cleanup() {
         let _ = catch_unwind(|| {
@@ -536,4 +3265,178 @@ mod tests {
 
         assert!(CURRENT_TASK_CONTEXT.with_borrow_mut(|x| x.is_none()));
     }
+
+    // Stress tests for the raw pointers the executor hands to tasks. They need io_uring, so unlike the allocator and
+    // collection tests they can't run under Miri, see the Testing section of the README.
+
+    #[test]
+    fn test_spawn_storm() {
+        fn spawn_tree(depth: u32) -> Pin<Box<dyn Future<Output = u64>>> {
+            Box::pin(async move {
+                if depth == 0 {
+                    return 1;
+                }
+                let handles = (0..4)
+                    .map(|_| spawn(spawn_tree(depth - 1)))
+                    .collect::<Vec<_>>();
+                let res = unsafe { RawIo::new(opcode::Nop::new().build()) }.await;
+                assert_eq!(res, 0);
+                let mut sum = 0;
+                for handle in handles {
+                    sum += handle.await;
+                }
+                sum
+            })
+        }
+
+        ExecutorConfig::new()
+            .run(async {
+                for _ in 0..3 {
+                    assert_eq!(spawn_tree(6).await, 4096);
+                }
+                let snapshot = snapshot();
+                // Only the main task is left.
+                assert_eq!(snapshot.tasks.len(), 1);
+                let io = snapshot
+                    .collections
+                    .iter()
+                    .find(|c| c.name == "io")
+                    .unwrap();
+                // There is no uring cmd ring, so it doesn't have a slot.
+                assert_eq!(io.len, NUM_INTERNAL_IO);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_panic_during_poll() {
+        let res = catch_unwind(|| {
+            ExecutorConfig::new()
+                .run(async {
+                    for i in 0..100u64 {
+                        drop(spawn(crate::time::sleep(Duration::from_millis(i))));
+                        drop(spawn(async {
+                            unsafe { RawIo::new(opcode::Nop::new().build()) }.await
+                        }));
+                    }
+                    spawn(async { panic!("panic while other tasks have io in flight") }).await
+                })
+                .unwrap();
+        });
+        assert!(res.is_err());
+        assert!(CURRENT_TASK_CONTEXT.with_borrow(|ctx| ctx.is_none()));
+
+        // The tasks and their io were freed with the executor, a new executor on the thread starts clean.
+        let res = ExecutorConfig::new()
+            .run(async { spawn(async { 5 }).await })
+            .unwrap();
+        assert_eq!(res, 5);
+    }
+
+    #[test]
+    fn test_panic_policy_drain_io() {
+        let start = Instant::now();
+        let res = catch_unwind(|| {
+            ExecutorConfig::new()
+                .panic_policy(PanicPolicy::DrainIo)
+                .run(async {
+                    let (a, b) = crate::net::unix::UnixStream::pair().unwrap();
+                    // Nothing is ever written to `b`, the read stays in the kernel until it is cancelled.
+                    drop(spawn(async move {
+                        let mut buf = [0; 64];
+                        b.read(&mut buf).await
+                    }));
+                    drop(spawn(crate::time::sleep(Duration::from_secs(60))));
+                    crate::time::sleep(Duration::from_millis(1)).await;
+                    spawn(async { panic!("panic while other tasks have io in flight") }).await;
+                    drop(a);
+                })
+                .unwrap();
+        });
+        assert!(res.is_err());
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(CURRENT_TASK_CONTEXT.with_borrow(|ctx| ctx.is_none()));
+    }
+
+    #[test]
+    fn test_defer() {
+        struct Resource(Rc<Cell<u32>>);
+
+        impl Drop for Resource {
+            fn drop(&mut self) {
+                let closed = self.0.clone();
+                defer(async move {
+                    crate::time::sleep(Duration::from_millis(5)).await;
+                    closed.set(closed.get() + 1);
+                });
+            }
+        }
+
+        let closed = Rc::new(Cell::new(0));
+        // Registered outside of an executor, it runs in the next one.
+        drop(Resource(closed.clone()));
+        let resource = Resource(closed.clone());
+        ExecutorConfig::new()
+            .run(async move {
+                drop(resource);
+            })
+            .unwrap();
+        assert_eq!(closed.get(), 2);
+    }
+
+    #[test]
+    fn test_drop_during_io() {
+        for _ in 0..10 {
+            ExecutorConfig::new()
+                .run(async {
+                    // Tasks are still waiting for their io when the main task returns and the executor drops them.
+                    for i in 0..200u64 {
+                        drop(spawn(async move {
+                            loop {
+                                unsafe { RawIo::new(opcode::Nop::new().build()) }.await;
+                                crate::time::sleep(Duration::from_micros(i)).await;
+                            }
+                        }));
+                    }
+                    for _ in 0..10 {
+                        YieldIfNeeded.await;
+                    }
+                })
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_timer_heap() {
+        let mut timers = NotifyWhen::with_capacity_in(4, LocalAlloc::new());
+        let mut to_notify = ToNotify::with_capacity_in(4, LocalAlloc::new());
+        let task_a = slab::Key::from(1);
+        let task_b = slab::Key::from(2);
+        let start = Instant::now();
+        let a = timers.insert(start + Duration::from_secs(1), task_a);
+        let b = timers.insert(start + Duration::from_secs(2), task_b);
+        assert_eq!(timers.next_deadline(), Some(start + Duration::from_secs(1)));
+
+        // Resetting a timer over and over doesn't grow the heap without bound.
+        for i in 0..1000 {
+            assert!(timers.reset(a, start + Duration::from_secs(10 + i)));
+        }
+        assert!(timers.heap.len() <= 65);
+        assert_eq!(timers.next_deadline(), Some(start + Duration::from_secs(2)));
+
+        timers.remove(b);
+        assert_eq!(
+            timers.next_deadline(),
+            Some(start + Duration::from_secs(1009))
+        );
+        timers.notify_expired(start + Duration::from_secs(100), &mut to_notify);
+        assert!(to_notify.is_empty());
+
+        timers.notify_expired(start + Duration::from_secs(1009), &mut to_notify);
+        assert!(to_notify.get(&task_a).is_some());
+        assert!(to_notify.get(&task_b).is_none());
+        assert!(timers.timers.is_empty());
+        assert!(!timers.reset(a, start));
+        assert_eq!(timers.next_deadline(), None);
+    }
 }
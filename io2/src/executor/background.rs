@@ -0,0 +1,301 @@
+//! Named periodic background jobs with a shared lifecycle.
+//!
+//! Maintenance work like trimming caches, checkpointing a WAL or exporting metrics is registered on a [Background]
+//! with an interval. Each job runs in its own task, so a job never overlaps with itself: the next run is scheduled
+//! after the current one finishes. [Background::shutdown] stops all jobs, waiting for the runs that are in progress.
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::Poll;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::executor::{self, JoinHandle};
+use crate::slab;
+use crate::sync::CancellationToken;
+use crate::time::{now, sleep};
+
+#[derive(Clone)]
+pub struct JobConfig {
+    interval: Duration,
+    jitter: Duration,
+    run_on_start: bool,
+}
+
+impl JobConfig {
+    /// Runs the job `interval` after the previous run finishes.
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            jitter: Duration::ZERO,
+            run_on_start: false,
+        }
+    }
+
+    /// Adds a random delay of up to `jitter` to every interval, so jobs of many executors don't run in lockstep.
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Runs the job right after it is registered instead of waiting for the first interval.
+    pub fn run_on_start(mut self, run_on_start: bool) -> Self {
+        self.run_on_start = run_on_start;
+        self
+    }
+}
+
+/// Statistics of a job, see [Background::stats].
+#[derive(Clone, Debug)]
+pub struct JobStats {
+    pub name: String,
+    pub runs: u64,
+    pub running: bool,
+    pub last_duration: Option<Duration>,
+}
+
+struct Job {
+    stats: JobStats,
+    // Set by Background::trigger, the job runs as soon as it isn't running.
+    triggered: bool,
+    task_id: Option<slab::Key>,
+}
+
+struct Shared {
+    jobs: Vec<Job>,
+    handles: Vec<JoinHandle<()>>,
+    rng: u64,
+}
+
+/// A set of background jobs, cloning it gives another handle to the same set.
+#[derive(Clone)]
+pub struct Background {
+    shared: Rc<RefCell<Shared>>,
+    token: CancellationToken,
+}
+
+impl Background {
+    pub fn new() -> Self {
+        Self::with_token(&CancellationToken::new())
+    }
+
+    /// Creates a set of jobs that is also shut down when `token` is cancelled, e.g. by the application's shutdown
+    /// signal. Runs in progress are not interrupted, [Background::shutdown] can still be used to wait for them.
+    pub fn with_token(token: &CancellationToken) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        Self {
+            shared: Rc::new(RefCell::new(Shared {
+                jobs: Vec::new(),
+                handles: Vec::new(),
+                rng: seed | 1,
+            })),
+            token: token.child_token(),
+        }
+    }
+
+    /// Registers a job that runs `job().await` periodically until shutdown.
+    ///
+    /// Panics if a job with the same name is already registered or the set is shut down.
+    pub fn register<F, Fut>(&self, name: &str, config: JobConfig, job: F)
+    where
+        F: FnMut() -> Fut + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        assert!(
+            !self.token.is_cancelled(),
+            "can't register a job after shutdown"
+        );
+        let idx = {
+            let mut shared = self.shared.borrow_mut();
+            assert!(
+                shared.jobs.iter().all(|job| job.stats.name != name),
+                "job {} is already registered",
+                name
+            );
+            shared.jobs.push(Job {
+                stats: JobStats {
+                    name: name.to_owned(),
+                    runs: 0,
+                    running: false,
+                    last_duration: None,
+                },
+                triggered: config.run_on_start,
+                task_id: None,
+            });
+            shared.jobs.len() - 1
+        };
+        let handle = executor::spawn(run_job(self.clone(), idx, config, job));
+        self.shared.borrow_mut().handles.push(handle);
+    }
+
+    /// Runs the job now instead of waiting for its interval. If it is running, it runs once more after the current run
+    /// finishes, triggers don't queue up beyond that.
+    ///
+    /// Returns false if there is no job with this name.
+    pub fn trigger(&self, name: &str) -> bool {
+        let mut shared = self.shared.borrow_mut();
+        let job = match shared.jobs.iter_mut().find(|job| job.stats.name == name) {
+            Some(job) => job,
+            None => return false,
+        };
+        job.triggered = true;
+        if let Some(task_id) = job.task_id {
+            if !job.stats.running {
+                executor::notify_task(task_id);
+            }
+        }
+        true
+    }
+
+    pub fn stats(&self) -> Vec<JobStats> {
+        self.shared
+            .borrow()
+            .jobs
+            .iter()
+            .map(|job| job.stats.clone())
+            .collect()
+    }
+
+    /// Stops all jobs and waits for the runs that are in progress to finish.
+    pub async fn shutdown(&self) {
+        self.token.cancel();
+        let handles = std::mem::take(&mut self.shared.borrow_mut().handles);
+        for handle in handles {
+            handle.await;
+        }
+    }
+
+    fn next_delay(&self, config: &JobConfig) -> Duration {
+        if config.jitter.is_zero() {
+            return config.interval;
+        }
+        let mut shared = self.shared.borrow_mut();
+        // xorshift64
+        shared.rng ^= shared.rng << 13;
+        shared.rng ^= shared.rng >> 7;
+        shared.rng ^= shared.rng << 17;
+        let jitter_nanos = u64::try_from(config.jitter.as_nanos()).unwrap_or(u64::MAX);
+        config.interval + Duration::from_nanos(shared.rng % jitter_nanos)
+    }
+}
+
+impl Default for Background {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+async fn run_job<F, Fut>(background: Background, idx: usize, config: JobConfig, mut job: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    background.shared.borrow_mut().jobs[idx].task_id = Some(executor::current_task_id());
+    loop {
+        let mut delay = sleep(background.next_delay(&config));
+        let mut cancelled = background.token.cancelled();
+        let run = std::future::poll_fn(|cx| {
+            if Pin::new(&mut cancelled).poll(cx).is_ready() {
+                return Poll::Ready(false);
+            }
+            if background.shared.borrow().jobs[idx].triggered {
+                return Poll::Ready(true);
+            }
+            Pin::new(&mut delay).poll(cx).map(|()| true)
+        })
+        .await;
+        if !run {
+            return;
+        }
+
+        let start = now();
+        {
+            let mut shared = background.shared.borrow_mut();
+            let job = &mut shared.jobs[idx];
+            job.triggered = false;
+            job.stats.running = true;
+        }
+        job().await;
+        let mut shared = background.shared.borrow_mut();
+        let stats = &mut shared.jobs[idx].stats;
+        stats.running = false;
+        stats.runs += 1;
+        stats.last_duration = Some(now() - start);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::executor::ExecutorConfig;
+
+    #[test]
+    fn test_background() {
+        ExecutorConfig::new()
+            .run(async {
+                let background = Background::new();
+                let fast_runs = Rc::new(Cell::new(0));
+                background.register(
+                    "fast",
+                    JobConfig::new(Duration::from_millis(2)).jitter(Duration::from_millis(2)),
+                    {
+                        let fast_runs = fast_runs.clone();
+                        move || {
+                            fast_runs.set(fast_runs.get() + 1);
+                            async {}
+                        }
+                    },
+                );
+
+                let running = Rc::new(Cell::new(0));
+                let max_running = Rc::new(Cell::new(0));
+                background.register(
+                    "slow",
+                    JobConfig::new(Duration::from_secs(3600)).run_on_start(true),
+                    {
+                        let running = running.clone();
+                        let max_running = max_running.clone();
+                        move || {
+                            let running = running.clone();
+                            let max_running = max_running.clone();
+                            async move {
+                                running.set(running.get() + 1);
+                                max_running.set(max_running.get().max(running.get()));
+                                sleep(Duration::from_millis(20)).await;
+                                running.set(running.get() - 1);
+                            }
+                        }
+                    },
+                );
+
+                sleep(Duration::from_millis(5)).await;
+                // Triggers while running coalesce into a single extra run.
+                assert!(background.trigger("slow"));
+                assert!(background.trigger("slow"));
+                assert!(!background.trigger("missing"));
+                sleep(Duration::from_millis(60)).await;
+
+                let stats = background.stats();
+                assert_eq!(stats[1].name, "slow");
+                assert_eq!(stats[1].runs, 2);
+                assert_eq!(max_running.get(), 1);
+                assert!(stats[0].runs >= 5);
+
+                // Shutdown waits for the run that is in progress.
+                background.trigger("slow");
+                sleep(Duration::from_millis(5)).await;
+                background.shutdown().await;
+                assert_eq!(running.get(), 0);
+                let runs = fast_runs.get();
+                sleep(Duration::from_millis(10)).await;
+                assert_eq!(fast_runs.get(), runs);
+            })
+            .unwrap();
+    }
+}
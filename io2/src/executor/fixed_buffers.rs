@@ -0,0 +1,296 @@
+//! Automatic registration of [LocalAlloc](crate::local_alloc::LocalAlloc) pages as io_uring fixed buffers.
+//!
+//! With [ExecutorConfig::auto_fixed_buffers](super::ExecutorConfig::auto_fixed_buffers) the executor registers a
+//! sparse buffer table on its rings. Reads and writes of files that are at least `min_len` bytes and point into a page
+//! of the thread's allocator use the page as a fixed buffer (READ_FIXED/WRITE_FIXED), so the kernel doesn't have to
+//! pin the memory on every operation. Pages are registered the first time they are used, a slot of a page that was
+//! freed is reused for a new page.
+//!
+//! [stats] shows how many of the eligible operations used a fixed buffer.
+
+use std::cell::Cell;
+use std::io;
+use std::os::fd::RawFd;
+
+use crate::local_alloc;
+
+// From linux/io_uring.h, io-uring crate doesn't support sparse buffer tables yet.
+const IORING_REGISTER_BUFFERS2: libc::c_uint = 15;
+const IORING_REGISTER_BUFFERS_UPDATE: libc::c_uint = 16;
+const IORING_RSRC_REGISTER_SPARSE: u32 = 1;
+
+/// Number of pages that can be registered at the same time.
+pub const NUM_SLOTS: u16 = 64;
+
+#[repr(C)]
+struct IoUringRsrcRegister {
+    nr: u32,
+    flags: u32,
+    resv2: u64,
+    data: u64,
+    tags: u64,
+}
+
+#[repr(C)]
+struct IoUringRsrcUpdate2 {
+    offset: u32,
+    resv: u32,
+    data: u64,
+    tags: u64,
+    nr: u32,
+    resv2: u32,
+}
+
+thread_local! {
+    static STATS: Cell<FixedBufferStats> = const { Cell::new(FixedBufferStats::new()) };
+}
+
+/// Counters of the automatic fixed buffer path of the executor running on the current thread.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FixedBufferStats {
+    /// Reads and writes that were large enough to use a fixed buffer.
+    pub eligible: u64,
+    /// Eligible operations that used a fixed buffer.
+    pub hits: u64,
+    /// Eligible operations with a buffer that isn't allocated from LocalAlloc.
+    pub not_local: u64,
+    /// Eligible operations that couldn't use a fixed buffer because all slots were used by live pages.
+    pub table_full: u64,
+    /// Pages that were registered.
+    pub registrations: u64,
+    /// Pages that the kernel refused to register, e.g. because of RLIMIT_MEMLOCK. They aren't tried again.
+    pub registration_failures: u64,
+}
+
+impl FixedBufferStats {
+    const fn new() -> Self {
+        Self {
+            eligible: 0,
+            hits: 0,
+            not_local: 0,
+            table_full: 0,
+            registrations: 0,
+            registration_failures: 0,
+        }
+    }
+
+    /// Fraction of the eligible operations that used a fixed buffer, 0 if there weren't any.
+    pub fn hit_rate(&self) -> f64 {
+        if self.eligible == 0 {
+            0.0
+        } else {
+            self.hits as f64 / self.eligible as f64
+        }
+    }
+}
+
+/// Returns the counters of the current thread.
+pub fn stats() -> FixedBufferStats {
+    STATS.get()
+}
+
+/// Clears the counters of the current thread.
+pub fn reset_stats() {
+    STATS.set(FixedBufferStats::new());
+}
+
+fn update_stats(f: impl FnOnce(&mut FixedBufferStats)) {
+    let mut stats = STATS.get();
+    f(&mut stats);
+    STATS.set(stats);
+}
+
+pub(crate) struct FixedBuffers {
+    // Rings that have the buffer table, every slot is registered on all of them with the same index.
+    ring_fds: [RawFd; 2],
+    min_len: usize,
+    // Id of the page registered in each slot.
+    slots: Vec<Option<u64>>,
+    // Pages that failed to register.
+    failed: Vec<u64>,
+}
+
+impl FixedBuffers {
+    pub(crate) fn register(ring_fds: [RawFd; 2], min_len: usize) -> io::Result<Self> {
+        for fd in ring_fds {
+            let mut rr = IoUringRsrcRegister {
+                nr: u32::from(NUM_SLOTS),
+                flags: IORING_RSRC_REGISTER_SPARSE,
+                resv2: 0,
+                data: 0,
+                tags: 0,
+            };
+            register(
+                fd,
+                IORING_REGISTER_BUFFERS2,
+                &mut rr as *mut IoUringRsrcRegister as *mut libc::c_void,
+                std::mem::size_of::<IoUringRsrcRegister>(),
+            )
+            .map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!(
+                        "failed to register sparse buffer table, kernel might be too old: {}",
+                        err
+                    ),
+                )
+            })?;
+        }
+        Ok(Self {
+            ring_fds,
+            min_len,
+            slots: vec![None; usize::from(NUM_SLOTS)],
+            failed: Vec::new(),
+        })
+    }
+
+    /// Returns the index of the registered buffer that contains `ptr..ptr + len`, registering its page if needed.
+    pub(crate) fn buf_index(&mut self, ptr: *const u8, len: usize) -> Option<u16> {
+        if len < self.min_len {
+            return None;
+        }
+        update_stats(|s| s.eligible += 1);
+
+        let page = match local_alloc::find_page(ptr, len) {
+            Some(page) => page,
+            None => {
+                update_stats(|s| s.not_local += 1);
+                return None;
+            }
+        };
+        if let Some(idx) = self.slots.iter().position(|&slot| slot == Some(page.id)) {
+            update_stats(|s| s.hits += 1);
+            return Some(u16::try_from(idx).unwrap());
+        }
+        if self.failed.contains(&page.id) {
+            return None;
+        }
+
+        let idx = match self.slots.iter().position(|&slot| match slot {
+            None => true,
+            Some(page_id) => !local_alloc::page_exists(page_id),
+        }) {
+            Some(idx) => idx,
+            None => {
+                update_stats(|s| s.table_full += 1);
+                return None;
+            }
+        };
+        // The kernel keeps its own reference to the pages of a replaced buffer until the operations using it finish.
+        if let Err(e) = self.update_slot(idx, page.ptr, page.size) {
+            log::debug!("failed to register fixed buffer: {}", e);
+            self.failed.retain(|&id| local_alloc::page_exists(id));
+            self.failed.push(page.id);
+            update_stats(|s| s.registration_failures += 1);
+            return None;
+        }
+        self.slots[idx] = Some(page.id);
+        update_stats(|s| {
+            s.registrations += 1;
+            s.hits += 1;
+        });
+        Some(u16::try_from(idx).unwrap())
+    }
+
+    fn update_slot(&mut self, idx: usize, ptr: *mut u8, size: usize) -> io::Result<()> {
+        let iov = libc::iovec {
+            iov_base: ptr as *mut libc::c_void,
+            iov_len: size,
+        };
+        for (i, &fd) in self.ring_fds.iter().enumerate() {
+            let res = update(fd, idx, &iov);
+            if let Err(e) = res {
+                // Keep the rings consistent, the slot is free after this.
+                for &fd in &self.ring_fds[..i] {
+                    let _ = update(fd, idx, &EMPTY_IOVEC);
+                }
+                self.slots[idx] = None;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+const EMPTY_IOVEC: libc::iovec = libc::iovec {
+    iov_base: std::ptr::null_mut(),
+    iov_len: 0,
+};
+
+fn update(fd: RawFd, idx: usize, iov: &libc::iovec) -> io::Result<()> {
+    let mut up = IoUringRsrcUpdate2 {
+        offset: u32::try_from(idx).unwrap(),
+        resv: 0,
+        data: iov as *const libc::iovec as u64,
+        tags: 0,
+        nr: 1,
+        resv2: 0,
+    };
+    register(
+        fd,
+        IORING_REGISTER_BUFFERS_UPDATE,
+        &mut up as *mut IoUringRsrcUpdate2 as *mut libc::c_void,
+        std::mem::size_of::<IoUringRsrcUpdate2>(),
+    )
+}
+
+pub(super) fn register(
+    fd: RawFd,
+    opcode: libc::c_uint,
+    arg: *mut libc::c_void,
+    nr_args: usize,
+) -> io::Result<()> {
+    let res = unsafe { libc::syscall(libc::SYS_io_uring_register, fd, opcode, arg, nr_args) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Large allocations don't come from pages with debug-alloc.
+#[cfg(all(test, not(feature = "debug-alloc")))]
+mod tests {
+    use super::*;
+    use crate::executor::ExecutorConfig;
+    use crate::fs::File;
+    use crate::local_alloc::LocalAlloc;
+
+    #[test]
+    fn test_auto_fixed_buffers() {
+        ExecutorConfig::new()
+            .auto_fixed_buffers(4096)
+            .run(async {
+                reset_stats();
+                let path =
+                    std::env::temp_dir().join(format!("io2_fixed_buffers_{}", std::process::id()));
+                let file = File::open(&path, libc::O_RDWR | libc::O_CREAT | libc::O_CLOEXEC, 0o644)
+                    .unwrap()
+                    .await
+                    .unwrap();
+
+                let mut data = Vec::with_capacity_in(64 * 1024, LocalAlloc::new());
+                data.extend((0..64 * 1024).map(|i| i as u8));
+                file.write_all(&data, 0).await.unwrap();
+                let mut out = vec![0u8; 64 * 1024];
+                out.copy_from_slice(&data);
+                file.write_all(&out, 64 * 1024).await.unwrap();
+                file.write_all(b"small", 128 * 1024).await.unwrap();
+
+                let mut buf = Vec::with_capacity_in(128 * 1024, LocalAlloc::new());
+                buf.resize(128 * 1024, 0u8);
+                file.read_exact(&mut buf, 0).await.unwrap();
+                assert_eq!(&buf[..64 * 1024], data.as_slice());
+                assert_eq!(&buf[64 * 1024..], data.as_slice());
+
+                let stats = stats();
+                assert_eq!(stats.eligible, 3);
+                assert_eq!(stats.not_local, 1);
+                assert_eq!(stats.hits + stats.registration_failures, 2);
+                assert!(stats.registrations >= 1);
+
+                file.close().await.unwrap();
+                std::fs::remove_file(&path).unwrap();
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,114 @@
+//! Table of io_uring fixed files.
+//!
+//! With [ExecutorConfig::fixed_file_table](super::ExecutorConfig::fixed_file_table) the executor registers a table of
+//! file slots on its rings. [File::register](crate::fs::File::register) puts a file into a free slot and operations on
+//! the returned [FixedFile](crate::fs::FixedFile) refer to the slot instead of the descriptor, so the kernel doesn't
+//! look up the descriptor and take a reference to the file on every operation. [OpenFixed](crate::fs::OpenFixed)
+//! opens a file directly into a slot of the main ring, without a descriptor.
+
+use std::io;
+use std::os::fd::RawFd;
+
+use super::fixed_buffers::register;
+
+// From linux/io_uring.h.
+const IORING_REGISTER_FILES: libc::c_uint = 2;
+const IORING_REGISTER_FILES_UPDATE: libc::c_uint = 6;
+
+#[repr(C)]
+struct IoUringFilesUpdate {
+    offset: u32,
+    resv: u32,
+    fds: u64,
+}
+
+pub(crate) struct FixedFiles {
+    // Rings that have the table, every file is registered on all of them with the same index.
+    ring_fds: [RawFd; 2],
+    free: Vec<u32>,
+}
+
+impl FixedFiles {
+    pub(crate) fn register(ring_fds: [RawFd; 2], size: u32) -> io::Result<Self> {
+        // Slots with -1 are empty.
+        let mut fds = vec![-1 as RawFd; usize::try_from(size).unwrap()];
+        for fd in ring_fds {
+            register(
+                fd,
+                IORING_REGISTER_FILES,
+                fds.as_mut_ptr() as *mut libc::c_void,
+                fds.len(),
+            )
+            .map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("failed to register fixed file table: {}", err),
+                )
+            })?;
+        }
+        Ok(Self {
+            ring_fds,
+            // Lower slots are handed out first.
+            free: (0..size).rev().collect(),
+        })
+    }
+
+    /// Puts `fd` into a free slot and returns its index.
+    pub(crate) fn insert(&mut self, fd: RawFd) -> io::Result<u32> {
+        let Some(slot) = self.free.pop() else {
+            return Err(io::Error::other("fixed file table is full"));
+        };
+        if let Err(e) = self.update_slot(slot, fd) {
+            self.free.push(slot);
+            return Err(e);
+        }
+        Ok(slot)
+    }
+
+    /// Takes a free slot without putting anything into it, for an open that installs the file into the slot itself.
+    pub(crate) fn reserve(&mut self) -> io::Result<u32> {
+        self.free
+            .pop()
+            .ok_or_else(|| io::Error::other("fixed file table is full"))
+    }
+
+    /// Returns a reserved slot that is still empty.
+    pub(crate) fn release(&mut self, slot: u32) {
+        self.free.push(slot);
+    }
+
+    /// Empties the slot, operations that are still running on it keep their reference to the file.
+    pub(crate) fn remove(&mut self, slot: u32) {
+        if let Err(e) = self.update_slot(slot, -1) {
+            log::debug!("failed to unregister fixed file: {}", e);
+        }
+        self.free.push(slot);
+    }
+
+    fn update_slot(&mut self, slot: u32, fd: RawFd) -> io::Result<()> {
+        for (i, &ring_fd) in self.ring_fds.iter().enumerate() {
+            if let Err(e) = update(ring_fd, slot, fd) {
+                // Keep the rings consistent, the slot is empty after this.
+                for &ring_fd in &self.ring_fds[..i] {
+                    let _ = update(ring_fd, slot, -1);
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn update(ring_fd: RawFd, slot: u32, fd: RawFd) -> io::Result<()> {
+    let mut up = IoUringFilesUpdate {
+        offset: slot,
+        resv: 0,
+        fds: &fd as *const RawFd as u64,
+    };
+    register(
+        ring_fd,
+        IORING_REGISTER_FILES_UPDATE,
+        &mut up as *mut IoUringFilesUpdate as *mut libc::c_void,
+        1,
+    )
+}
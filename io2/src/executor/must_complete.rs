@@ -0,0 +1,245 @@
+//! Checks for io futures that are dropped while their io is running in the kernel.
+//!
+//! The kernel keeps writing into the buffers of such a future after they are freed, which silently corrupts memory.
+//! [MustComplete] turns this into a panic that names the operation. Builds with debug assertions record the opcode and
+//! a backtrace of where each operation was queued, and also panic when a task finishes while it still has io running.
+//! The backtrace is only captured if it is enabled with [ExecutorConfig::io_backtraces](super::ExecutorConfig::io_backtraces).
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+#[cfg(debug_assertions)]
+use std::{backtrace::Backtrace, fmt::Write};
+
+use pin_project_lite::pin_project;
+
+use crate::slab;
+
+use super::{Io, IoSlot, Multishot, CURRENT_TASK_CONTEXT};
+
+/// Where an operation was queued, recorded in builds with debug assertions.
+#[cfg(debug_assertions)]
+pub(super) struct IoOrigin {
+    pub(super) opcode: u8,
+    pub(super) queued_at: Option<Backtrace>,
+}
+
+pin_project! {
+    /// Wraps a future that owns io and panics if it is dropped while any of that io is still running in the kernel.
+    ///
+    /// Io futures have to be polled until they complete, e.g. a future that loses a `select` has to be cancelled and
+    /// then awaited instead of being dropped. Nothing is checked if the future is dropped while the thread is already
+    /// panicking.
+    #[must_use = "futures do nothing unless you `.await` or poll them"]
+    pub struct MustComplete<F> {
+        #[pin]
+        future: F,
+        // Operations the future queued that might still be running.
+        io: Vec<slab::Key>,
+    }
+
+    impl<F> PinnedDrop for MustComplete<F> {
+        fn drop(this: Pin<&mut Self>) {
+            let this = this.project();
+            if this.io.is_empty() || std::thread::panicking() {
+                return;
+            }
+            let report = CURRENT_TASK_CONTEXT.with_borrow(|ctx| {
+                let ctx = ctx.as_ref()?;
+                let (io, multishot) = unsafe { (&*ctx.io, &*ctx.multishot) };
+                this.io.iter().find_map(|&io_id| {
+                    running_io(io, multishot, io_id).map(|slot| report(io_id, slot))
+                })
+            });
+            if let Some(report) = report {
+                panic!(
+                    "future was dropped while its io is running in the kernel: {}",
+                    report
+                );
+            }
+        }
+    }
+}
+
+impl<F> MustComplete<F> {
+    pub fn new(future: F) -> Self {
+        Self {
+            future,
+            io: Vec::new(),
+        }
+    }
+}
+
+impl<F: Future> Future for MustComplete<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let io: *mut Vec<slab::Key> = this.io;
+        // Only set while the wrapped future is polled, a nested MustComplete swaps in its own list and restores this one.
+        let outer = CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+            ctx.as_mut()
+                .map(|ctx| std::mem::replace(&mut ctx.queued_io, io))
+        });
+        let res = this.future.poll(cx);
+        CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+            if let (Some(ctx), Some(outer)) = (ctx.as_mut(), outer) {
+                ctx.queued_io = outer;
+                let (io, multishot) = unsafe { (&*ctx.io, &*ctx.multishot) };
+                this.io
+                    .retain(|&io_id| running_io(io, multishot, io_id).is_some());
+            }
+        });
+        if res.is_ready() {
+            this.io.clear();
+        }
+        res
+    }
+}
+
+/// Returns the slot of the operation if the kernel might still be using its memory.
+///
+/// Operations whose completion was already posted are done even if their result wasn't taken, this includes the final
+/// completion of a multishot operation. Memory free and abandoned multishot operations don't point to memory of the
+/// task.
+pub(super) fn running_io<'io>(
+    io: &'io Io,
+    multishot: &Multishot,
+    io_id: slab::Key,
+) -> Option<&'io IoSlot> {
+    let slot = io.get(io_id)?;
+    if slot.result.is_some() || slot.memory_free {
+        return None;
+    }
+    match multishot.get(&io_id) {
+        Some(state) if state.abandoned => None,
+        // The final completion was posted but not taken yet.
+        Some(state)
+            if state
+                .results
+                .back()
+                .is_some_and(|&(_, flags)| !io_uring::cqueue::more(flags)) =>
+        {
+            None
+        }
+        _ => Some(slot),
+    }
+}
+
+/// Panics if a task that finished still has io running, `task_io` is the io the task queued.
+#[cfg(debug_assertions)]
+pub(super) fn check_finished_task(
+    io: &Io,
+    multishot: &Multishot,
+    task_id: slab::Key,
+    task_io: &[slab::Key],
+) {
+    for &io_id in task_io {
+        let Some(slot) = running_io(io, multishot, io_id) else {
+            continue;
+        };
+        if slot.task_id == task_id {
+            panic!(
+                "task {:?} finished while its io is running in the kernel, a future that owns io was dropped before it completed: {}",
+                task_id,
+                report(io_id, slot)
+            );
+        }
+    }
+}
+
+fn report(io_id: slab::Key, slot: &IoSlot) -> String {
+    let mut out = format!("io={:?}", io_id);
+    #[cfg(debug_assertions)]
+    if let Some(origin) = &slot.origin {
+        write!(out, " opcode={}", origin.opcode).unwrap();
+        match &origin.queued_at {
+            Some(queued_at) => write!(out, "\nqueued at:\n{}", queued_at).unwrap(),
+            None => {
+                out.push_str(" (enable ExecutorConfig::io_backtraces to see where it was queued)")
+            }
+        }
+    }
+    #[cfg(not(debug_assertions))]
+    {
+        let _ = slot;
+        out.push_str(" (build with debug assertions to see the opcode and where it was queued)");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    use crate::executor::{spawn, ExecutorConfig};
+    use crate::net::unix::UnixStream;
+
+    use super::*;
+
+    #[test]
+    fn test_must_complete() {
+        ExecutorConfig::new()
+            .io_backtraces(true)
+            .run(async {
+                let (a, b) = UnixStream::pair().unwrap();
+                let mut buf = [0; 16];
+
+                // Completed futures can be dropped.
+                a.write_all(b"hello").await.unwrap();
+                let n = MustComplete::new(b.read(&mut buf)).await.unwrap();
+                assert_eq!(&buf[..n], b"hello");
+
+                let mut read = Box::pin(MustComplete::new(b.read(&mut buf)));
+                let waker = super::super::noop_waker();
+                let mut cx = Context::from_waker(&waker);
+                assert!(read.as_mut().poll(&mut cx).is_pending());
+                let res = catch_unwind(AssertUnwindSafe(|| drop(read)));
+                let msg = res.unwrap_err().downcast::<String>().unwrap();
+                assert!(msg.contains("dropped while its io is running"), "{}", msg);
+                #[cfg(debug_assertions)]
+                assert!(
+                    msg.contains(&format!("opcode={}", io_uring::opcode::Recv::CODE))
+                        || msg.contains(&format!("opcode={}", io_uring::opcode::Read::CODE)),
+                    "{}",
+                    msg
+                );
+                #[cfg(debug_assertions)]
+                assert!(msg.contains("queued at"), "{}", msg);
+
+                // The read is still running and points into `buf`, wake it up before the buffer goes away.
+                a.write_all(b"x").await.unwrap();
+                crate::time::sleep(std::time::Duration::from_millis(10)).await;
+                spawn(async {}).await;
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_drop_memory_free_io() {
+        ExecutorConfig::new()
+            .run(async {
+                let efd = std::rc::Rc::new(crate::sync::EventFd::new().unwrap());
+                let waker = super::super::noop_waker();
+
+                // A task that gives up waiting on a poll can finish while the poll is running.
+                let task_efd = efd.clone();
+                spawn(async move {
+                    let mut wait = Box::pin(MustComplete::new(task_efd.wait()));
+                    assert!(wait
+                        .as_mut()
+                        .poll(&mut Context::from_waker(&waker))
+                        .is_pending());
+                    drop(wait);
+                })
+                .await;
+
+                // The abandoned poll completes and is cleaned up.
+                efd.write(1).unwrap();
+                crate::time::sleep(std::time::Duration::from_millis(10)).await;
+                assert_eq!(efd.wait().await.unwrap(), 1);
+            })
+            .unwrap();
+    }
+}
@@ -2,6 +2,7 @@ use std::{
     alloc::{Allocator, Layout},
     io,
     marker::PhantomData,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, IntoRawFd, OwnedFd, RawFd},
     path::Path,
 };
 
@@ -63,15 +64,35 @@ impl DioFile {
         self.file.sync_all()
     }
 
-    fn assert_alignment(&self, buf: &[u8], offset: u64) {
-        assert_eq!(
-            buf.as_ptr()
-                .align_offset(usize::try_from(self.dio_mem_align).unwrap()),
-            0
-        );
+    /// Checks the alignment requirements of direct io so a misaligned buffer is reported with its actual values instead
+    /// of an EINVAL from the kernel.
+    fn check_alignment(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        if buf
+            .as_ptr()
+            .align_offset(usize::try_from(self.dio_mem_align).unwrap())
+            != 0
+        {
+            return invalid(format!(
+                "buffer at {:p} is not {}-aligned",
+                buf.as_ptr(),
+                self.dio_mem_align
+            ));
+        }
         let len = u32::try_from(buf.len()).unwrap();
-        assert_eq!(align_up(len, self.dio_offset_align), len);
-        assert_eq!(align_down(offset, u64::from(self.dio_offset_align)), offset);
+        if align_up(len, self.dio_offset_align) != len {
+            return invalid(format!(
+                "length {} is not a multiple of the logical block size {}",
+                len, self.dio_offset_align
+            ));
+        }
+        if align_down(offset, u64::from(self.dio_offset_align)) != offset {
+            return invalid(format!(
+                "offset {} is not a multiple of the logical block size {}",
+                offset, self.dio_offset_align
+            ));
+        }
+        Ok(())
     }
 
     pub fn read_aligned<'file, 'buf>(
@@ -79,7 +100,7 @@ impl DioFile {
         buf: &'buf mut [u8],
         offset: u64,
     ) -> Read<'file, 'buf> {
-        self.assert_alignment(buf, offset);
+        let invalid = self.check_alignment(buf, offset).err();
 
         Read {
             file: &self.file,
@@ -87,6 +108,10 @@ impl DioFile {
             buf,
             io_id: None,
             direct_io: true,
+            rw_flags: 0,
+            ioprio: 0,
+            blocking: None,
+            invalid,
             _non_send: PhantomData,
         }
     }
@@ -96,7 +121,7 @@ impl DioFile {
         buf: &'buf [u8],
         offset: u64,
     ) -> Write<'file, 'buf> {
-        self.assert_alignment(buf, offset);
+        let invalid = self.check_alignment(buf, offset).err();
 
         Write {
             offset,
@@ -104,6 +129,10 @@ impl DioFile {
             file: &self.file,
             io_id: None,
             direct_io: true,
+            rw_flags: 0,
+            ioprio: 0,
+            invalid,
+            epoch: None,
             _non_send: PhantomData,
         }
     }
@@ -280,6 +309,32 @@ impl DioFile {
     }
 }
 
+// There is no FromRawFd since the alignment requirements are read with statx when opening, an fd can be wrapped with
+// File::from_raw_fd instead.
+impl AsRawFd for DioFile {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl AsFd for DioFile {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+impl IntoRawFd for DioFile {
+    fn into_raw_fd(self) -> RawFd {
+        self.file.into_raw_fd()
+    }
+}
+
+impl From<DioFile> for OwnedFd {
+    fn from(file: DioFile) -> Self {
+        file.file.into()
+    }
+}
+
 fn align_up(v: u32, align: u32) -> u32 {
     (v + align - 1) & !(align - 1)
 }
@@ -315,4 +370,38 @@ mod tests {
         assert_eq!(x, 5);
         dbg!(x);
     }
+
+    #[test]
+    fn test_alignment_errors() {
+        ExecutorConfig::new()
+            .run(async {
+                // Alignment is checked before submitting so this doesn't need a file that supports direct io.
+                let file = DioFile {
+                    file: File::open(Path::new("Cargo.toml"), libc::O_RDONLY, 0)
+                        .unwrap()
+                        .await
+                        .unwrap(),
+                    dio_mem_align: 4096,
+                    dio_offset_align: 512,
+                };
+                let layout = Layout::from_size_align(8192, 4096).unwrap();
+                let mut buf = IoBuffer::new(layout, LocalAlloc::new()).unwrap();
+                let buf = buf.as_mut_slice();
+
+                let err = file.read_aligned(&mut buf[1..513], 0).await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+                assert!(err.to_string().ends_with("is not 4096-aligned"));
+                let err = file.read_aligned(&mut buf[..100], 0).await.unwrap_err();
+                assert_eq!(
+                    err.to_string(),
+                    "length 100 is not a multiple of the logical block size 512"
+                );
+                let err = file.write_aligned(&buf[..512], 100).await.unwrap_err();
+                assert_eq!(
+                    err.to_string(),
+                    "offset 100 is not a multiple of the logical block size 512"
+                );
+            })
+            .unwrap();
+    }
 }
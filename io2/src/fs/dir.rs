@@ -0,0 +1,299 @@
+use std::future::Future;
+use std::io;
+use std::marker::PhantomData;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use io_uring::opcode;
+use io_uring::types::Fd;
+use pin_project_lite::pin_project;
+
+use crate::executor::CURRENT_TASK_CONTEXT;
+use crate::slab;
+
+use super::file::{self, File, LocalCString, Open, Rename, SyncAll, Unlink};
+
+/// An open directory that paths can be resolved relative to.
+///
+/// Operations that take a path resolve it relative to this directory instead of the current working directory, so
+/// the directory can be renamed or moved without affecting them. [Dir::open_at] and [Dir::open_dir_at] don't allow
+/// the path to escape the directory, so a `Dir` can be handed out to give access to a subtree of the filesystem.
+pub struct Dir {
+    file: File,
+}
+
+impl Dir {
+    pub async fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(
+            path,
+            libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+            0,
+        )?
+        .await?;
+        Ok(Self { file })
+    }
+
+    /// Opens a file under this directory.
+    ///
+    /// Fails with EXDEV if `path` is absolute or resolves to somewhere outside of this directory, e.g. with `..` or
+    /// a symlink.
+    pub fn open_at(&self, path: &Path, flags: i32, mode: i32) -> io::Result<Open> {
+        File::open_at(self.file.fd, path, flags, mode, libc::RESOLVE_BENEATH)
+    }
+
+    /// Opens a directory under this directory, with the same restrictions as [Dir::open_at].
+    pub async fn open_dir_at(&self, path: &Path) -> io::Result<Dir> {
+        let file = self
+            .open_at(
+                path,
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+                0,
+            )?
+            .await?;
+        Ok(Dir { file })
+    }
+
+    pub fn unlink_at(&self, path: &Path) -> io::Result<Unlink> {
+        file::unlink_at(self.file.fd, path, 0)
+    }
+
+    /// Removes an empty directory.
+    pub fn remove_dir_at(&self, path: &Path) -> io::Result<Unlink> {
+        file::unlink_at(self.file.fd, path, libc::AT_REMOVEDIR)
+    }
+
+    /// Renames `from` in this directory to `to` in `to_dir`, which can be this directory.
+    pub fn rename_at(&self, from: &Path, to_dir: &Dir, to: &Path) -> io::Result<Rename> {
+        file::rename_at(self.file.fd, from, to_dir.file.fd, to)
+    }
+
+    pub fn mkdir_at(&self, path: &Path, mode: u32) -> io::Result<MkDir<'_>> {
+        Ok(MkDir {
+            dir: self,
+            path: LocalCString::from_path(path)?,
+            mode,
+            io_id: None,
+            _non_send: PhantomData,
+        })
+    }
+
+    /// Returns metadata of `path` without following it if it is a symlink.
+    pub fn statx_at(&self, path: &Path) -> io::Result<StatxAt<'_>> {
+        Ok(StatxAt {
+            dir: self,
+            path: LocalCString::from_path(path)?,
+            io_id: None,
+            statx: unsafe { std::mem::zeroed() },
+            _non_send: PhantomData,
+        })
+    }
+
+    /// Syncs the directory entries, this is needed for created/renamed/removed files to survive a crash.
+    pub fn sync_all(&self) -> SyncAll<'_> {
+        self.file.sync_all()
+    }
+}
+
+impl AsRawFd for Dir {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl AsFd for Dir {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+impl IntoRawFd for Dir {
+    fn into_raw_fd(self) -> RawFd {
+        self.file.into_raw_fd()
+    }
+}
+
+impl FromRawFd for Dir {
+    /// `fd` has to be an open directory, e.g. one opened with O_PATH or O_DIRECTORY.
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        Self {
+            file: File::from_raw_fd(fd),
+        }
+    }
+}
+
+impl From<OwnedFd> for Dir {
+    fn from(fd: OwnedFd) -> Self {
+        unsafe { Self::from_raw_fd(fd.into_raw_fd()) }
+    }
+}
+
+impl From<Dir> for OwnedFd {
+    fn from(dir: Dir) -> Self {
+        dir.file.into()
+    }
+}
+
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct MkDir<'dir> {
+    dir: &'dir Dir,
+    path: LocalCString,
+    mode: u32,
+    io_id: Option<slab::Key>,
+    _non_send: PhantomData<*mut ()>,
+}
+
+impl<'dir> Future for MkDir<'dir> {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+            let ctx = ctx.as_mut().unwrap();
+            let fut = self.get_mut();
+            match fut.io_id {
+                None => {
+                    fut.io_id = Some(unsafe {
+                        ctx.queue_io(
+                            opcode::MkDirAt::new(Fd(fut.dir.file.fd), fut.path.as_c_str())
+                                .mode(fut.mode)
+                                .build(),
+                            false,
+                        )
+                    });
+                    Poll::Pending
+                }
+                Some(io_id) => {
+                    let io_result = match ctx.take_io_result(io_id) {
+                        Some(io_result) => io_result,
+                        None => {
+                            return Poll::Pending;
+                        }
+                    };
+
+                    if io_result < 0 {
+                        Poll::Ready(Err(io::Error::from_raw_os_error(-io_result)))
+                    } else {
+                        Poll::Ready(Ok(()))
+                    }
+                }
+            }
+        })
+    }
+}
+
+pin_project! {
+    #[must_use = "futures do nothing unless you `.await` or poll them"]
+    pub struct StatxAt<'dir> {
+        dir: &'dir Dir,
+        path: LocalCString,
+        io_id: Option<slab::Key>,
+        #[pin] statx: libc::statx,
+        _non_send: PhantomData<*mut ()>,
+    }
+}
+
+impl<'dir> Future for StatxAt<'dir> {
+    type Output = io::Result<libc::statx>;
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+            let ctx = ctx.as_mut().unwrap();
+            let fut = self.project();
+            match fut.io_id {
+                None => {
+                    *fut.io_id = Some(unsafe {
+                        ctx.queue_io(
+                            opcode::Statx::new(
+                                Fd(fut.dir.file.fd),
+                                fut.path.as_c_str(),
+                                &*fut.statx as *const libc::statx as *mut _,
+                            )
+                            .flags(libc::AT_SYMLINK_NOFOLLOW)
+                            .mask(libc::STATX_BASIC_STATS)
+                            .build(),
+                            false,
+                        )
+                    });
+                    Poll::Pending
+                }
+                Some(io_id) => {
+                    let io_result = match ctx.take_io_result(*io_id) {
+                        Some(io_result) => io_result,
+                        None => {
+                            return Poll::Pending;
+                        }
+                    };
+
+                    if io_result < 0 {
+                        Poll::Ready(Err(io::Error::from_raw_os_error(-io_result)))
+                    } else {
+                        Poll::Ready(Ok(*fut.statx))
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::executor::ExecutorConfig;
+
+    use super::*;
+
+    #[test]
+    fn test_dir() {
+        let root = std::env::temp_dir().join(format!("io2_test_dir_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+
+        let test_root = root.clone();
+        ExecutorConfig::new()
+            .run(async move {
+                let dir = Dir::open(&test_root).await.unwrap();
+                dir.mkdir_at(Path::new("sub"), 0o755)
+                    .unwrap()
+                    .await
+                    .unwrap();
+                let sub = dir.open_dir_at(Path::new("sub")).await.unwrap();
+
+                let file = sub
+                    .open_at(
+                        Path::new("a"),
+                        libc::O_WRONLY | libc::O_CREAT | libc::O_CLOEXEC,
+                        0o644,
+                    )
+                    .unwrap()
+                    .await
+                    .unwrap();
+                file.write_all(b"hello", 0).await.unwrap();
+                file.close().await.unwrap();
+
+                sub.rename_at(Path::new("a"), &dir, Path::new("b"))
+                    .unwrap()
+                    .await
+                    .unwrap();
+                let statx = dir.statx_at(Path::new("b")).unwrap().await.unwrap();
+                assert_eq!(statx.stx_size, 5);
+
+                // Paths can't escape the directory.
+                match sub
+                    .open_at(Path::new("../b"), libc::O_RDONLY, 0)
+                    .unwrap()
+                    .await
+                {
+                    Err(e) => assert_eq!(e.raw_os_error(), Some(libc::EXDEV)),
+                    Ok(_) => panic!("opened a file outside of the directory"),
+                }
+
+                dir.unlink_at(Path::new("b")).unwrap().await.unwrap();
+                std::mem::drop(sub);
+                dir.remove_dir_at(Path::new("sub")).unwrap().await.unwrap();
+                dir.sync_all().await.unwrap();
+            })
+            .unwrap();
+
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 0);
+        std::fs::remove_dir(&root).unwrap();
+    }
+}
@@ -1,26 +1,167 @@
 use std::alloc::Allocator;
+use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::future::Future;
 use std::io;
 use std::marker::PhantomData;
-use std::os::fd::RawFd;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
 use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 use io_uring::opcode;
-use io_uring::types::Fd;
+use io_uring::types::{self, Fd};
 use pin_project_lite::pin_project;
 
-use crate::executor::{CURRENT_TASK_CONTEXT, FILES_TO_CLOSE};
+use crate::executor::{self, CURRENT_TASK_CONTEXT, FILES_TO_CLOSE};
 use crate::local_alloc::LocalAlloc;
 use crate::slab;
+use crate::time;
+
+// Builds an entry on the fixed file slot of `$file` if it is registered, on its descriptor otherwise.
+macro_rules! file_entry {
+    ($file:expr, |$target:ident| $entry:expr) => {
+        match $file.fixed_slot {
+            Some(slot) => {
+                let $target = types::Fixed(slot);
+                $entry
+            }
+            None => {
+                let $target = Fd($file.fd);
+                $entry
+            }
+        }
+    };
+}
 
 pub struct File {
     pub(crate) fd: RawFd,
+    // See Open::blocking_reads.
+    blocking_reads: bool,
+    // Slot in the fixed file table if the file is owned by a FixedFile.
+    pub(crate) fixed_slot: Option<u32>,
+    // Orders writes around barriers, see File::barrier.
+    write_order: RefCell<WriteOrder>,
     _non_send: PhantomData<*mut ()>,
 }
 
+// Writes to a file are grouped into epochs that are separated by barriers. A barrier completes once the writes of its
+// epoch and of the epochs before it are done, and a write is only submitted once all barriers before it completed.
+struct WriteOrder {
+    // Oldest epoch whose barrier didn't complete, writes of this epoch are submitted right away.
+    base_epoch: u64,
+    // Epochs from base_epoch on that are closed by a barrier.
+    closed: VecDeque<ClosedEpoch, LocalAlloc>,
+    // Unfinished writes of the current epoch, which comes after the closed ones.
+    open_writes: usize,
+    // Tasks waiting for a barrier to complete or for their writes to be allowed to start.
+    waiters: Vec<slab::Key, LocalAlloc>,
+}
+
+struct ClosedEpoch {
+    // Writes of the epoch that were started and didn't complete yet.
+    writes: usize,
+    // The barrier closing the epoch was dropped before it completed, nothing waits for it.
+    barrier_dropped: bool,
+}
+
+impl WriteOrder {
+    fn new() -> Self {
+        Self {
+            base_epoch: 0,
+            closed: VecDeque::new_in(LocalAlloc::new()),
+            open_writes: 0,
+            waiters: Vec::new_in(LocalAlloc::new()),
+        }
+    }
+
+    fn current_epoch(&self) -> u64 {
+        self.base_epoch + u64::try_from(self.closed.len()).unwrap()
+    }
+
+    fn writes_mut(&mut self, epoch: u64) -> &mut usize {
+        let idx = usize::try_from(epoch - self.base_epoch).unwrap();
+        match self.closed.get_mut(idx) {
+            Some(closed) => &mut closed.writes,
+            None => &mut self.open_writes,
+        }
+    }
+
+    /// Registers a write, returns its epoch.
+    fn start_write(&mut self) -> u64 {
+        self.open_writes += 1;
+        self.current_epoch()
+    }
+
+    fn finish_write(&mut self, epoch: u64) {
+        let writes = self.writes_mut(epoch);
+        *writes -= 1;
+        if *writes == 0 && epoch == self.base_epoch {
+            // The barrier of the epoch can complete now.
+            self.skip_dropped_barriers();
+            self.wake();
+        }
+    }
+
+    /// Closes the current epoch, returns the epoch the barrier waits for.
+    fn start_barrier(&mut self) -> u64 {
+        let epoch = self.current_epoch();
+        self.closed.push_back(ClosedEpoch {
+            writes: std::mem::take(&mut self.open_writes),
+            barrier_dropped: false,
+        });
+        epoch
+    }
+
+    fn try_complete_barrier(&mut self, epoch: u64) -> bool {
+        if epoch != self.base_epoch || self.closed.front().unwrap().writes > 0 {
+            return false;
+        }
+        self.closed.pop_front();
+        self.base_epoch += 1;
+        self.skip_dropped_barriers();
+        // Writes of the next epoch can start.
+        self.wake();
+        true
+    }
+
+    fn drop_barrier(&mut self, epoch: u64) {
+        let idx = usize::try_from(epoch - self.base_epoch).unwrap();
+        self.closed[idx].barrier_dropped = true;
+        if self.skip_dropped_barriers() {
+            self.wake();
+        }
+    }
+
+    fn skip_dropped_barriers(&mut self) -> bool {
+        let mut skipped = false;
+        while let Some(&ClosedEpoch {
+            writes: 0,
+            barrier_dropped: true,
+        }) = self.closed.front()
+        {
+            self.closed.pop_front();
+            self.base_epoch += 1;
+            skipped = true;
+        }
+        skipped
+    }
+
+    fn wait(&mut self, task_id: slab::Key) {
+        if !self.waiters.contains(&task_id) {
+            self.waiters.push(task_id);
+        }
+    }
+
+    fn wake(&mut self) {
+        for task_id in self.waiters.drain(..) {
+            executor::notify_task(task_id);
+        }
+    }
+}
+
 #[must_use = "futures do nothing unless you `.await` or poll them"]
 pub struct Close {
     io_id: Option<slab::Key>,
@@ -64,9 +205,13 @@ impl Future for Close {
 pin_project! {
     #[must_use = "futures do nothing unless you `.await` or poll them"]
     pub struct Open {
+        dirfd: RawFd,
         path: LocalCString,
         #[pin] how: libc::open_how,
         io_id: Option<slab::Key>,
+        blocking_reads: bool,
+        // Slot of the fixed file table to open the file into instead of a descriptor, see Open::fixed.
+        pub(crate) file_index: Option<u32>,
         _non_send: PhantomData<*mut ()>,
     }
 }
@@ -83,10 +228,13 @@ impl Future for Open {
                     *fut.io_id = Some(unsafe {
                         ctx.queue_io(
                             opcode::OpenAt2::new(
-                                Fd(libc::AT_FDCWD),
+                                Fd(*fut.dirfd),
                                 fut.path.as_c_str(),
                                 &*fut.how as *const libc::open_how as *const _,
                             )
+                            .file_index(fut.file_index.map(|slot| {
+                                types::DestinationSlot::try_from_slot_target(slot).unwrap()
+                            }))
                             .build(),
                             false,
                         )
@@ -108,7 +256,11 @@ impl Future for Open {
                     };
 
                     Poll::Ready(Ok(File {
-                        fd,
+                        // A file that is opened into a slot only exists in the table.
+                        fd: if fut.file_index.is_some() { -1 } else { fd },
+                        blocking_reads: *fut.blocking_reads,
+                        fixed_slot: *fut.file_index,
+                        write_order: RefCell::new(WriteOrder::new()),
                         _non_send: PhantomData,
                     }))
                 }
@@ -117,6 +269,35 @@ impl Future for Open {
     }
 }
 
+impl Open {
+    /// Makes reads of the opened file run on a blocking thread with `preadv2` instead of going through io_uring.
+    ///
+    /// This is meant for files in procfs and sysfs. Their read handlers generate the contents on every read and some of
+    /// them behave differently, or block, when they are called from io_uring workers. Each read is a round trip to the
+    /// blocking thread pool, so this shouldn't be used for regular files.
+    pub fn blocking_reads(mut self) -> Self {
+        self.blocking_reads = true;
+        self
+    }
+
+    /// Opens the file with O_DSYNC, every write completes after its data is durable, so commit paths don't need a
+    /// separate [File::sync_all] round trip. Use [Write::dsync] to do this only for some writes.
+    pub fn dsync(mut self) -> Self {
+        self.how.flags |= libc::O_DSYNC as u64;
+        self
+    }
+
+    /// Opens the file with O_SYNC, like [Open::dsync] but writes also wait for the metadata that isn't needed to read
+    /// the data back, e.g. modification times.
+    pub fn sync(mut self) -> Self {
+        self.how.flags |= libc::O_SYNC as u64;
+        self
+    }
+}
+
+// Outer error is from running the blocking thread, inner one is from the read.
+type BlockingRead = Pin<Box<dyn Future<Output = io::Result<io::Result<Vec<u8>>>>, LocalAlloc>>;
+
 #[must_use = "futures do nothing unless you `.await` or poll them"]
 pub struct Read<'file, 'buf> {
     pub(crate) file: &'file File,
@@ -124,30 +305,46 @@ pub struct Read<'file, 'buf> {
     pub(crate) buf: &'buf mut [u8],
     pub(crate) io_id: Option<slab::Key>,
     pub(crate) direct_io: bool,
+    pub(crate) rw_flags: i32,
+    pub(crate) ioprio: u16,
+    // Read running on a blocking thread if the file was opened with Open::blocking_reads.
+    pub(crate) blocking: Option<BlockingRead>,
+    // Error found before submitting the read, e.g. a misaligned direct io buffer.
+    pub(crate) invalid: Option<io::Error>,
     pub(crate) _non_send: PhantomData<*mut ()>,
 }
 
 impl<'file, 'buf> Future for Read<'file, 'buf> {
     type Output = io::Result<usize>;
 
-    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let fut = self.get_mut();
+        if let Some(err) = fut.invalid.take() {
+            return Poll::Ready(Err(err));
+        }
+        if fut.file.blocking_reads {
+            return fut.poll_blocking(cx);
+        }
         CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
             let ctx = ctx.as_mut().unwrap();
-            let fut = self.get_mut();
             match fut.io_id {
                 None => {
-                    fut.io_id = Some(unsafe {
-                        ctx.queue_io(
-                            opcode::Read::new(
-                                Fd(fut.file.fd),
-                                fut.buf.as_mut_ptr(),
-                                fut.buf.len().try_into().unwrap(),
-                            )
+                    let ptr = fut.buf.as_mut_ptr();
+                    let len = fut.buf.len().try_into().unwrap();
+                    let buf_index = ctx.fixed_buffer(ptr, fut.buf.len());
+                    let entry = file_entry!(fut.file, |fd| match buf_index {
+                        Some(buf_index) => opcode::ReadFixed::new(fd, ptr, len, buf_index)
                             .offset(fut.offset)
+                            .rw_flags(fut.rw_flags)
+                            .ioprio(fut.ioprio)
+                            .build(),
+                        None => opcode::Read::new(fd, ptr, len)
+                            .offset(fut.offset)
+                            .rw_flags(fut.rw_flags)
+                            .ioprio(fut.ioprio)
                             .build(),
-                            fut.direct_io,
-                        )
                     });
+                    fut.io_id = Some(unsafe { ctx.queue_io(entry, fut.direct_io) });
                     Poll::Pending
                 }
                 Some(io_id) => {
@@ -169,6 +366,92 @@ impl<'file, 'buf> Future for Read<'file, 'buf> {
     }
 }
 
+impl<'file, 'buf> Read<'file, 'buf> {
+    fn poll_blocking(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        if self.blocking.is_none() {
+            let fd = match self.file.dup_for_blocking() {
+                Ok(fd) => fd,
+                Err(e) => return Poll::Ready(Err(e)),
+            };
+            // The thread reads into its own buffer so it doesn't write into `buf` if this future is dropped.
+            let len = self.buf.len();
+            // -1 reads from the file position, like u64::MAX does for io_uring reads.
+            let offset = match self.offset {
+                u64::MAX => -1,
+                offset => match i64::try_from(offset) {
+                    Ok(offset) => offset,
+                    Err(_) => {
+                        return Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "read offset is too large",
+                        )))
+                    }
+                },
+            };
+            let rw_flags = self.rw_flags;
+            self.blocking = Some(Box::pin_in(
+                crate::blocking::run_blocking(move || {
+                    let mut buf = vec![0u8; len];
+                    let iov = libc::iovec {
+                        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                        iov_len: len,
+                    };
+                    let res = unsafe { libc::preadv2(fd.as_raw_fd(), &iov, 1, offset, rw_flags) };
+                    if res < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    buf.truncate(usize::try_from(res).unwrap());
+                    Ok(buf)
+                }),
+                LocalAlloc::new(),
+            ));
+        }
+        match self.blocking.as_mut().unwrap().as_mut().poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(res) => {
+                self.blocking = None;
+                let data = res??;
+                self.buf[..data.len()].copy_from_slice(&data);
+                Poll::Ready(Ok(data.len()))
+            }
+        }
+    }
+
+    /// Sets the `RWF_*` flags of this read, see `preadv2(2)`.
+    pub fn rw_flags(mut self, rw_flags: i32) -> Self {
+        self.rw_flags = rw_flags;
+        self
+    }
+
+    /// Fails with EAGAIN instead of waiting for the disk if the data isn't in the page cache.
+    ///
+    /// This can be used to serve cached reads inline and only hand off the rest to a slower path.
+    pub fn nowait(self) -> Self {
+        let rw_flags = self.rw_flags | libc::RWF_NOWAIT;
+        self.rw_flags(rw_flags)
+    }
+
+    /// Asks for high priority polled completion, this only has an effect on direct io.
+    pub fn hipri(self) -> Self {
+        let rw_flags = self.rw_flags | libc::RWF_HIPRI;
+        self.rw_flags(rw_flags)
+    }
+
+    /// Sets the priority the block layer gives to this read, see [IoPriority]. It has no effect on reads of a file
+    /// opened with [Open::blocking_reads].
+    pub fn priority(mut self, priority: IoPriority) -> Self {
+        self.ioprio = priority.to_raw();
+        self
+    }
+
+    /// Queues cancellation of a running read, the future still has to be polled until it completes.
+    pub(crate) fn cancel(&self) {
+        if let Some(io_id) = self.io_id {
+            CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| ctx.as_mut().unwrap().cancel_io(io_id));
+        }
+    }
+}
+
 #[must_use = "futures do nothing unless you `.await` or poll them"]
 pub struct Write<'file, 'buf> {
     pub(crate) file: &'file File,
@@ -176,6 +459,12 @@ pub struct Write<'file, 'buf> {
     pub(crate) buf: &'buf [u8],
     pub(crate) io_id: Option<slab::Key>,
     pub(crate) direct_io: bool,
+    pub(crate) rw_flags: i32,
+    pub(crate) ioprio: u16,
+    // Error found before submitting the write, e.g. a misaligned direct io buffer.
+    pub(crate) invalid: Option<io::Error>,
+    // Epoch of the write in the file's WriteOrder once it is registered, until it completes.
+    pub(crate) epoch: Option<u64>,
     pub(crate) _non_send: PhantomData<*mut ()>,
 }
 
@@ -183,23 +472,39 @@ impl<'file, 'buf> Future for Write<'file, 'buf> {
     type Output = io::Result<usize>;
 
     fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
-        CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+        let fut = self.get_mut();
+        if let Some(err) = fut.invalid.take() {
+            return Poll::Ready(Err(err));
+        }
+        if fut.io_id.is_none() {
+            let mut order = fut.file.write_order.borrow_mut();
+            let epoch = *fut.epoch.get_or_insert_with(|| order.start_write());
+            if epoch != order.base_epoch {
+                // A barrier before this write didn't complete yet.
+                order.wait(executor::current_task_id());
+                return Poll::Pending;
+            }
+        }
+        let res = CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
             let ctx = ctx.as_mut().unwrap();
-            let fut = self.get_mut();
             match fut.io_id {
                 None => {
-                    fut.io_id = Some(unsafe {
-                        ctx.queue_io(
-                            opcode::Write::new(
-                                Fd(fut.file.fd),
-                                fut.buf.as_ptr(),
-                                fut.buf.len().try_into().unwrap(),
-                            )
+                    let ptr = fut.buf.as_ptr();
+                    let len = fut.buf.len().try_into().unwrap();
+                    let buf_index = ctx.fixed_buffer(ptr, fut.buf.len());
+                    let entry = file_entry!(fut.file, |fd| match buf_index {
+                        Some(buf_index) => opcode::WriteFixed::new(fd, ptr, len, buf_index)
                             .offset(fut.offset)
+                            .rw_flags(fut.rw_flags)
+                            .ioprio(fut.ioprio)
+                            .build(),
+                        None => opcode::Write::new(fd, ptr, len)
+                            .offset(fut.offset)
+                            .rw_flags(fut.rw_flags)
+                            .ioprio(fut.ioprio)
                             .build(),
-                            fut.direct_io,
-                        )
                     });
+                    fut.io_id = Some(unsafe { ctx.queue_io(entry, fut.direct_io) });
                     Poll::Pending
                 }
                 Some(io_id) => {
@@ -217,7 +522,93 @@ impl<'file, 'buf> Future for Write<'file, 'buf> {
                     }
                 }
             }
-        })
+        });
+        if res.is_ready() {
+            if let Some(epoch) = fut.epoch.take() {
+                fut.file.write_order.borrow_mut().finish_write(epoch);
+            }
+        }
+        res
+    }
+}
+
+impl<'file, 'buf> Drop for Write<'file, 'buf> {
+    fn drop(&mut self) {
+        if let Some(epoch) = self.epoch.take() {
+            self.file.write_order.borrow_mut().finish_write(epoch);
+        }
+    }
+}
+
+impl<'file, 'buf> Write<'file, 'buf> {
+    /// Sets the `RWF_*` flags of this write, see `pwritev2(2)`.
+    pub fn rw_flags(mut self, rw_flags: i32) -> Self {
+        self.rw_flags = rw_flags;
+        self
+    }
+
+    /// Fails with EAGAIN instead of blocking, e.g. if the write would have to wait for writeback.
+    pub fn nowait(self) -> Self {
+        let rw_flags = self.rw_flags | libc::RWF_NOWAIT;
+        self.rw_flags(rw_flags)
+    }
+
+    /// Asks for high priority polled completion, this only has an effect on direct io.
+    pub fn hipri(self) -> Self {
+        let rw_flags = self.rw_flags | libc::RWF_HIPRI;
+        self.rw_flags(rw_flags)
+    }
+
+    /// Makes this write durable before it completes, like opening the file with O_DSYNC but only for this write.
+    pub fn dsync(self) -> Self {
+        let rw_flags = self.rw_flags | libc::RWF_DSYNC;
+        self.rw_flags(rw_flags)
+    }
+
+    /// Makes this write and the file's metadata durable before it completes, like opening the file with O_SYNC but
+    /// only for this write.
+    pub fn synchronized(self) -> Self {
+        let rw_flags = self.rw_flags | libc::RWF_SYNC;
+        self.rw_flags(rw_flags)
+    }
+
+    /// Sets the priority the block layer gives to this write, see [IoPriority].
+    pub fn priority(mut self, priority: IoPriority) -> Self {
+        self.ioprio = priority.to_raw();
+        self
+    }
+}
+
+/// Priority of a read or write in the block layer, see `ioprio_set(2)`.
+///
+/// The IO scheduler of the device (e.g. BFQ or mq-deadline) uses it to order requests, so background work like
+/// compaction can run at [IoPriority::Idle] without slowing down foreground reads. Devices without a scheduler ignore
+/// it. Levels go from 0 (highest) to 7, higher levels are treated as 7.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoPriority {
+    /// Served before the other classes, needs CAP_SYS_ADMIN.
+    RealTime(u8),
+    /// The class IO gets by default.
+    BestEffort(u8),
+    /// Only served when the device has no other IO to do.
+    Idle,
+}
+
+// From linux/ioprio.h.
+const IOPRIO_CLASS_SHIFT: u16 = 13;
+const IOPRIO_CLASS_RT: u16 = 1;
+const IOPRIO_CLASS_BE: u16 = 2;
+const IOPRIO_CLASS_IDLE: u16 = 3;
+
+impl IoPriority {
+    /// Value of the `ioprio` field of the submission.
+    pub fn to_raw(self) -> u16 {
+        let (class, level) = match self {
+            Self::RealTime(level) => (IOPRIO_CLASS_RT, level),
+            Self::BestEffort(level) => (IOPRIO_CLASS_BE, level),
+            Self::Idle => (IOPRIO_CLASS_IDLE, 0),
+        };
+        class << IOPRIO_CLASS_SHIFT | u16::from(level.min(7))
     }
 }
 
@@ -280,9 +671,148 @@ impl<'file> Future for Statx<'file> {
     }
 }
 
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Rename {
+    from_dirfd: RawFd,
+    from: LocalCString,
+    to_dirfd: RawFd,
+    to: LocalCString,
+    io_id: Option<slab::Key>,
+    _non_send: PhantomData<*mut ()>,
+}
+
+impl Future for Rename {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+            let ctx = ctx.as_mut().unwrap();
+            let fut = self.get_mut();
+            match fut.io_id {
+                None => {
+                    // The path buffers are heap allocated so they don't move even if this future isn't pinned.
+                    fut.io_id = Some(unsafe {
+                        ctx.queue_io(
+                            opcode::RenameAt::new(
+                                Fd(fut.from_dirfd),
+                                fut.from.as_c_str(),
+                                Fd(fut.to_dirfd),
+                                fut.to.as_c_str(),
+                            )
+                            .build(),
+                            false,
+                        )
+                    });
+                    Poll::Pending
+                }
+                Some(io_id) => {
+                    let io_result = match ctx.take_io_result(io_id) {
+                        Some(io_result) => io_result,
+                        None => {
+                            return Poll::Pending;
+                        }
+                    };
+
+                    if io_result < 0 {
+                        Poll::Ready(Err(io::Error::from_raw_os_error(-io_result)))
+                    } else {
+                        Poll::Ready(Ok(()))
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Unlink {
+    dirfd: RawFd,
+    path: LocalCString,
+    flags: i32,
+    io_id: Option<slab::Key>,
+    _non_send: PhantomData<*mut ()>,
+}
+
+impl Future for Unlink {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+            let ctx = ctx.as_mut().unwrap();
+            let fut = self.get_mut();
+            match fut.io_id {
+                None => {
+                    fut.io_id = Some(unsafe {
+                        ctx.queue_io(
+                            opcode::UnlinkAt::new(Fd(fut.dirfd), fut.path.as_c_str())
+                                .flags(fut.flags)
+                                .build(),
+                            false,
+                        )
+                    });
+                    Poll::Pending
+                }
+                Some(io_id) => {
+                    let io_result = match ctx.take_io_result(io_id) {
+                        Some(io_result) => io_result,
+                        None => {
+                            return Poll::Pending;
+                        }
+                    };
+
+                    if io_result < 0 {
+                        Poll::Ready(Err(io::Error::from_raw_os_error(-io_result)))
+                    } else {
+                        Poll::Ready(Ok(()))
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Barrier<'file> {
+    file: &'file File,
+    // Epoch the barrier closed, set on the first poll until the barrier completes.
+    epoch: Option<u64>,
+    done: bool,
+    _non_send: PhantomData<*mut ()>,
+}
+
+impl<'file> Future for Barrier<'file> {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let fut = self.get_mut();
+        if fut.done {
+            return Poll::Ready(Ok(()));
+        }
+        let mut order = fut.file.write_order.borrow_mut();
+        let epoch = *fut.epoch.get_or_insert_with(|| order.start_barrier());
+        if order.try_complete_barrier(epoch) {
+            fut.epoch = None;
+            fut.done = true;
+            Poll::Ready(Ok(()))
+        } else {
+            order.wait(executor::current_task_id());
+            Poll::Pending
+        }
+    }
+}
+
+impl<'file> Drop for Barrier<'file> {
+    fn drop(&mut self) {
+        if let Some(epoch) = self.epoch.take() {
+            self.file.write_order.borrow_mut().drop_barrier(epoch);
+        }
+    }
+}
+
 #[must_use = "futures do nothing unless you `.await` or poll them"]
 pub struct SyncAll<'file> {
     file: &'file File,
+    flags: types::FsyncFlags,
     io_id: Option<slab::Key>,
     _non_send: PhantomData<*mut ()>,
 }
@@ -297,7 +827,69 @@ impl<'file> Future for SyncAll<'file> {
             match fut.io_id {
                 None => {
                     fut.io_id = Some(unsafe {
-                        ctx.queue_io(opcode::Fsync::new(Fd(fut.file.fd)).build(), false)
+                        ctx.queue_io(
+                            file_entry!(fut.file, |fd| opcode::Fsync::new(fd)
+                                .flags(fut.flags)
+                                .build()),
+                            false,
+                        )
+                    });
+                    Poll::Pending
+                }
+                Some(io_id) => {
+                    let io_result = match ctx.take_io_result(io_id) {
+                        Some(io_result) => io_result,
+                        None => {
+                            return Poll::Pending;
+                        }
+                    };
+
+                    if io_result < 0 {
+                        Poll::Ready(Err(io::Error::from_raw_os_error(-io_result)))
+                    } else {
+                        Poll::Ready(Ok(()))
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Allocate<'file> {
+    file: &'file File,
+    offset: u64,
+    len: u64,
+    mode: i32,
+    io_id: Option<slab::Key>,
+    _non_send: PhantomData<*mut ()>,
+}
+
+impl<'file> Allocate<'file> {
+    /// Sets the `FALLOC_FL_*` flags, e.g. `FALLOC_FL_KEEP_SIZE` to allocate without changing the file size.
+    pub fn mode(mut self, mode: i32) -> Self {
+        self.mode = mode;
+        self
+    }
+}
+
+impl<'file> Future for Allocate<'file> {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+            let ctx = ctx.as_mut().unwrap();
+            let fut = self.get_mut();
+            match fut.io_id {
+                None => {
+                    fut.io_id = Some(unsafe {
+                        ctx.queue_io(
+                            file_entry!(fut.file, |fd| opcode::Fallocate::new(fd, fut.len)
+                                .offset(fut.offset)
+                                .mode(fut.mode)
+                                .build()),
+                            false,
+                        )
                     });
                     Poll::Pending
                 }
@@ -321,12 +913,12 @@ impl<'file> Future for SyncAll<'file> {
 }
 
 // This is because std CString doesn't support allocator api
-struct LocalCString {
+pub(crate) struct LocalCString {
     path: Vec<u8, LocalAlloc>,
 }
 
 impl LocalCString {
-    fn from_path(path: &Path) -> io::Result<Self> {
+    pub(crate) fn from_path(path: &Path) -> io::Result<Self> {
         let path_ref = path.as_os_str().as_bytes();
 
         if path_ref.contains(&b'\0') {
@@ -345,25 +937,49 @@ impl LocalCString {
         Ok(Self { path })
     }
 
-    fn as_c_str(&self) -> *const libc::c_char {
+    pub(crate) fn as_c_str(&self) -> *const libc::c_char {
         self.path.as_ptr() as *const libc::c_char
     }
 }
 
 impl File {
     pub fn open(path: &Path, flags: i32, mode: i32) -> io::Result<Open> {
+        Self::open_at(libc::AT_FDCWD, path, flags, mode, 0)
+    }
+
+    /// Opens `path` relative to `dirfd`, `resolve` is the `RESOLVE_*` flags passed to openat2.
+    pub(crate) fn open_at(
+        dirfd: RawFd,
+        path: &Path,
+        flags: i32,
+        mode: i32,
+        resolve: u64,
+    ) -> io::Result<Open> {
         let path = LocalCString::from_path(path)?;
         let mut how: libc::open_how = unsafe { std::mem::zeroed() };
         how.flags = flags as u64;
         how.mode = mode as u64;
+        how.resolve = resolve;
         Ok(Open {
+            dirfd,
             path,
             how,
             io_id: None,
+            blocking_reads: false,
+            file_index: None,
             _non_send: PhantomData,
         })
     }
 
+    /// Opens a file for writing, creating it if it doesn't exist and truncating it if it does.
+    pub fn create(path: &Path) -> io::Result<Open> {
+        Self::open(
+            path,
+            libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_CLOEXEC,
+            0o644,
+        )
+    }
+
     pub fn read<'file, 'buf>(&'file self, buf: &'buf mut [u8], offset: u64) -> Read<'file, 'buf> {
         Read {
             offset,
@@ -371,6 +987,10 @@ impl File {
             file: self,
             io_id: None,
             direct_io: false,
+            rw_flags: 0,
+            ioprio: 0,
+            blocking: None,
+            invalid: None,
             _non_send: PhantomData,
         }
     }
@@ -422,6 +1042,142 @@ impl File {
         }
     }
 
+    /// Reads `len` bytes starting at `offset` with up to `parallelism` reads of `chunk` bytes in flight at a time.
+    ///
+    /// A single large read is split up by the kernel and completes at the speed of one request, issuing the chunks
+    /// concurrently keeps more requests in the device queue which is what NVMe drives need to reach their full
+    /// throughput. Returns `UnexpectedEof` if the file ends before the range does.
+    pub async fn read_range_parallel(
+        &self,
+        offset: u64,
+        len: usize,
+        chunk: usize,
+        parallelism: usize,
+    ) -> io::Result<(Vec<u8, LocalAlloc>, ReadRangeStats)> {
+        assert!(chunk > 0, "chunk has to be positive");
+        assert!(parallelism > 0, "parallelism has to be positive");
+        let start = Instant::now();
+        let mut buf = Vec::with_capacity_in(len, LocalAlloc::new());
+        buf.resize(len, 0);
+
+        let res = {
+            let mut chunks = buf.chunks_mut(chunk).enumerate();
+            let mut in_flight = Vec::with_capacity_in(parallelism, LocalAlloc::new());
+            let mut res = Ok(());
+            loop {
+                // Stop starting reads after an error, but wait for the ones in flight since they write into the buffer.
+                while res.is_ok() && in_flight.len() < parallelism {
+                    let Some((idx, chunk_buf)) = chunks.next() else {
+                        break;
+                    };
+                    let chunk_offset = offset + u64::try_from(idx * chunk).unwrap();
+                    in_flight.push(Box::pin_in(
+                        self.read_exact(chunk_buf, chunk_offset),
+                        LocalAlloc::new(),
+                    ));
+                }
+                if in_flight.is_empty() {
+                    break;
+                }
+                let chunk_res = std::future::poll_fn(|cx| {
+                    for idx in 0..in_flight.len() {
+                        if let Poll::Ready(res) = in_flight[idx].as_mut().poll(cx) {
+                            drop(in_flight.swap_remove(idx));
+                            return Poll::Ready(res);
+                        }
+                    }
+                    Poll::Pending
+                })
+                .await;
+                if res.is_ok() {
+                    res = chunk_res;
+                }
+            }
+            res
+        };
+        res?;
+
+        let stats = ReadRangeStats {
+            bytes: u64::try_from(len).unwrap(),
+            num_chunks: u64::try_from(len.div_ceil(chunk)).unwrap(),
+            elapsed: start.elapsed(),
+        };
+        Ok((buf, stats))
+    }
+
+    /// Reads into `buf` at `offset`, issuing a second read of the same range if the first one didn't complete within
+    /// `delay`.
+    ///
+    /// This cuts the tail latency on drives that occasionally stall a request for much longer than usual. The result
+    /// of the read that completes first is returned and the other one is cancelled. The second read goes into a
+    /// separate buffer, so both reads are waited for before this returns, a cancelled read that was already running
+    /// can still take as long as the device does.
+    pub async fn read_hedged(
+        &self,
+        buf: &mut [u8],
+        offset: u64,
+        delay: Duration,
+    ) -> io::Result<(usize, HedgeStats)> {
+        let start = Instant::now();
+        let len = buf.len();
+        let mut first = self.read(buf, offset);
+        let first_res = {
+            let mut sleep = time::sleep(delay);
+            std::future::poll_fn(|cx| {
+                if let Poll::Ready(res) = Pin::new(&mut first).poll(cx) {
+                    return Poll::Ready(Some(res));
+                }
+                Pin::new(&mut sleep).poll(cx).map(|()| None)
+            })
+            .await
+        };
+        if let Some(res) = first_res {
+            let stats = HedgeStats {
+                hedged: false,
+                hedge_won: false,
+                elapsed: start.elapsed(),
+            };
+            return res.map(|n| (n, stats));
+        }
+
+        let mut hedge_buf = Vec::with_capacity_in(len, LocalAlloc::new());
+        hedge_buf.resize(len, 0);
+        let mut hedge = self.read(&mut hedge_buf, offset);
+        let (hedge_won, res) = std::future::poll_fn(|cx| {
+            if let Poll::Ready(res) = Pin::new(&mut first).poll(cx) {
+                return Poll::Ready((false, res));
+            }
+            Pin::new(&mut hedge).poll(cx).map(|res| (true, res))
+        })
+        .await;
+        let loser = if hedge_won { &mut first } else { &mut hedge };
+        // If the winner failed, the other read can still succeed so it isn't cancelled.
+        let (hedge_won, res) = match res {
+            Ok(n) => {
+                // Reads on a blocking thread use their own buffer, so they can be dropped instead.
+                if !self.blocking_reads {
+                    loser.cancel();
+                    let _ = loser.await;
+                }
+                (hedge_won, Ok(n))
+            }
+            Err(_) => (!hedge_won, loser.await),
+        };
+        drop(first);
+        drop(hedge);
+
+        let n = res?;
+        if hedge_won {
+            buf[..n].copy_from_slice(&hedge_buf[..n]);
+        }
+        let stats = HedgeStats {
+            hedged: true,
+            hedge_won,
+            elapsed: start.elapsed(),
+        };
+        Ok((n, stats))
+    }
+
     pub fn write<'file, 'buf>(&'file self, buf: &'buf [u8], offset: u64) -> Write<'file, 'buf> {
         Write {
             offset,
@@ -429,6 +1185,25 @@ impl File {
             file: self,
             io_id: None,
             direct_io: false,
+            rw_flags: 0,
+            ioprio: 0,
+            invalid: None,
+            epoch: None,
+            _non_send: PhantomData,
+        }
+    }
+
+    /// Returns a future that completes after every write to this file that was started before it is done, writes
+    /// to this file that are started after it are held back until then.
+    ///
+    /// Only writes made through this `File` are ordered, other operations on the ring aren't affected. This orders the
+    /// writes without flushing anything to disk, use [File::sync_all] where the writes have to be durable. Dropping the
+    /// barrier before it completes releases the writes it holds back.
+    pub fn barrier(&self) -> Barrier {
+        Barrier {
+            file: self,
+            epoch: None,
+            done: false,
             _non_send: PhantomData,
         }
     }
@@ -436,11 +1211,53 @@ impl File {
     pub fn sync_all(&self) -> SyncAll {
         SyncAll {
             file: self,
+            flags: types::FsyncFlags::empty(),
             io_id: None,
             _non_send: PhantomData,
         }
     }
 
+    /// Like [File::sync_all] but uses fdatasync, metadata that isn't needed to read the data back, like the
+    /// modification time, isn't flushed.
+    pub fn sync_data(&self) -> SyncAll {
+        SyncAll {
+            file: self,
+            flags: types::FsyncFlags::DATASYNC,
+            io_id: None,
+            _non_send: PhantomData,
+        }
+    }
+
+    /// Allocates disk space for `len` bytes starting at `offset` with fallocate, so later writes to the range don't
+    /// have to allocate blocks and can't fail with ENOSPC.
+    pub fn allocate(&self, offset: u64, len: u64) -> Allocate {
+        Allocate {
+            file: self,
+            offset,
+            len,
+            mode: 0,
+            io_id: None,
+            _non_send: PhantomData,
+        }
+    }
+
+    /// Truncates or extends the file to `len` bytes, like ftruncate.
+    ///
+    /// io_uring only has ftruncate on recent kernels, so this runs on a separate thread.
+    pub async fn set_len(&self, len: u64) -> io::Result<()> {
+        let len = i64::try_from(len)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "length is too large"))?;
+        let fd = self.dup_for_blocking()?;
+        crate::blocking::run_blocking(move || {
+            if unsafe { libc::ftruncate(fd.as_raw_fd(), len) } == -1 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        })
+        .await?
+    }
+
     pub fn close(self) -> Close {
         let fd = self.fd;
         std::mem::forget(self);
@@ -451,6 +1268,25 @@ impl File {
         }
     }
 
+    /// Duplicates the file descriptor, the new file shares the file offset and status flags with this one.
+    ///
+    /// The new descriptor has O_CLOEXEC set. This can be used to hand a file to a library that takes ownership of
+    /// the descriptor while keeping this one open.
+    pub async fn dup(&self) -> io::Result<File> {
+        // io_uring has no dup operation, F_DUPFD_CLOEXEC doesn't block so it runs inline.
+        let fd = unsafe { libc::fcntl(self.fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(File {
+            fd,
+            blocking_reads: self.blocking_reads,
+            fixed_slot: None,
+            write_order: RefCell::new(WriteOrder::new()),
+            _non_send: PhantomData,
+        })
+    }
+
     pub(crate) fn statx(&self) -> Statx<'_> {
         Statx {
             file: self,
@@ -466,22 +1302,133 @@ impl File {
     }
 }
 
+/// Stats of a [File::read_range_parallel] call.
+#[derive(Debug, Clone, Copy)]
+pub struct ReadRangeStats {
+    pub bytes: u64,
+    pub num_chunks: u64,
+    pub elapsed: Duration,
+}
+
+impl ReadRangeStats {
+    /// Bytes read per second.
+    pub fn throughput(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// Stats of a [File::read_hedged] call.
+#[derive(Debug, Clone, Copy)]
+pub struct HedgeStats {
+    /// A second read was issued because the first one didn't complete in time.
+    pub hedged: bool,
+    /// The result came from the second read.
+    pub hedge_won: bool,
+    pub elapsed: Duration,
+}
+
 impl Drop for File {
     fn drop(&mut self) {
+        // Files that were opened into a fixed file slot don't have a descriptor.
+        if self.fd == -1 {
+            return;
+        }
         FILES_TO_CLOSE.with_borrow_mut(|files| {
             files.push(self.fd);
         });
     }
 }
 
+impl AsRawFd for File {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl AsFd for File {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        unsafe { BorrowedFd::borrow_raw(self.fd) }
+    }
+}
+
+impl IntoRawFd for File {
+    /// Returns the descriptor without closing it, the caller is responsible for closing it.
+    fn into_raw_fd(self) -> RawFd {
+        let fd = self.fd;
+        std::mem::forget(self);
+        fd
+    }
+}
+
+impl FromRawFd for File {
+    /// Takes ownership of `fd`, e.g. one that was opened by another library. It is closed when the file is dropped.
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        Self {
+            fd,
+            blocking_reads: false,
+            fixed_slot: None,
+            write_order: RefCell::new(WriteOrder::new()),
+            _non_send: PhantomData,
+        }
+    }
+}
+
+impl From<OwnedFd> for File {
+    fn from(fd: OwnedFd) -> Self {
+        unsafe { Self::from_raw_fd(fd.into_raw_fd()) }
+    }
+}
+
+impl From<File> for OwnedFd {
+    fn from(file: File) -> Self {
+        unsafe { OwnedFd::from_raw_fd(file.into_raw_fd()) }
+    }
+}
+
 pub async fn read<A: Allocator>(path: &Path, alloc: A) -> io::Result<Vec<u8, A>> {
     let file = File::open(path, libc::O_RDONLY, 0)?.await?;
     let file_size = file.file_size().await?;
     let mut buf = Vec::with_capacity_in(usize::try_from(file_size).unwrap(), alloc);
+    buf.resize(usize::try_from(file_size).unwrap(), 0);
     file.read_exact(&mut buf, 0).await?;
     Ok(buf)
 }
 
+pub fn rename(from: &Path, to: &Path) -> io::Result<Rename> {
+    rename_at(libc::AT_FDCWD, from, libc::AT_FDCWD, to)
+}
+
+pub(crate) fn rename_at(
+    from_dirfd: RawFd,
+    from: &Path,
+    to_dirfd: RawFd,
+    to: &Path,
+) -> io::Result<Rename> {
+    Ok(Rename {
+        from_dirfd,
+        from: LocalCString::from_path(from)?,
+        to_dirfd,
+        to: LocalCString::from_path(to)?,
+        io_id: None,
+        _non_send: PhantomData,
+    })
+}
+
+pub fn remove_file(path: &Path) -> io::Result<Unlink> {
+    unlink_at(libc::AT_FDCWD, path, 0)
+}
+
+/// `flags` can be `AT_REMOVEDIR` to remove a directory instead of a file.
+pub(crate) fn unlink_at(dirfd: RawFd, path: &Path, flags: i32) -> io::Result<Unlink> {
+    Ok(Unlink {
+        dirfd,
+        path: LocalCString::from_path(path)?,
+        flags,
+        io_id: None,
+        _non_send: PhantomData,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use crate::executor::ExecutorConfig;
@@ -514,4 +1461,351 @@ mod tests {
         assert_eq!(x, 5);
         dbg!(x);
     }
+
+    #[test]
+    fn test_barrier() {
+        let path = std::env::temp_dir().join(format!("io2_test_barrier_{}", std::process::id()));
+        let test_path = path.clone();
+        ExecutorConfig::new()
+            .run(async move {
+                let file = std::rc::Rc::new(File::create(&test_path).unwrap().await.unwrap());
+                let mut writes = Vec::new();
+                for i in 0..4u8 {
+                    let file = file.clone();
+                    writes.push(crate::executor::spawn(async move {
+                        file.write_all(&[i; 512], u64::from(i) * 512).await.unwrap();
+                    }));
+                }
+                // Let the writes get queued before the barrier.
+                let mut yielded = false;
+                std::future::poll_fn(|_| {
+                    if yielded {
+                        return Poll::Ready(());
+                    }
+                    yielded = true;
+                    crate::executor::poll_next_tick();
+                    Poll::Pending
+                })
+                .await;
+
+                // Io that never completes on its own doesn't hold up a barrier of another file.
+                let (a, b) = crate::net::unix::UnixStream::pair().unwrap();
+                let idle_read = crate::executor::spawn(async move {
+                    let mut buf = [0; 16];
+                    b.read(&mut buf).await.unwrap()
+                });
+
+                file.barrier().await.unwrap();
+                let data = std::fs::read(&test_path).unwrap();
+                assert_eq!(data.len(), 2048);
+                for (i, chunk) in data.chunks(512).enumerate() {
+                    assert!(chunk.iter().all(|&b| usize::from(b) == i));
+                }
+                for write in writes {
+                    write.await;
+                }
+                a.write_all(b"x").await.unwrap();
+                assert_eq!(idle_read.await, 1);
+            })
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_barrier_holds_back_writes() {
+        use std::sync::{Arc, Mutex};
+
+        fn poll_once<F: Future + Unpin>(fut: &mut F) -> Poll<F::Output> {
+            let waker = crate::executor::noop_waker();
+            Pin::new(fut).poll(&mut Context::from_waker(&waker))
+        }
+
+        ExecutorConfig::new()
+            .run(async {
+                let mut fds = [0; 2];
+                assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }, 0);
+                let reader = unsafe { std::fs::File::from_raw_fd(fds[0]) };
+                let file = unsafe { File::from_raw_fd(fds[1]) };
+
+                // More than the pipe buffer holds, the write stays in flight until the pipe is read.
+                let big = vec![1u8; 1 << 20];
+                let mut first = file.write(&big, 0);
+                assert!(poll_once(&mut first).is_pending());
+                let mut barrier = file.barrier();
+                assert!(poll_once(&mut barrier).is_pending());
+                let mut second = file.write(b"2", 0);
+                assert!(poll_once(&mut second).is_pending());
+                crate::time::sleep(Duration::from_millis(10)).await;
+                assert!(poll_once(&mut barrier).is_pending());
+
+                let drained = Arc::new(Mutex::new(Vec::new()));
+                let drainer = {
+                    let drained = drained.clone();
+                    std::thread::spawn(move || {
+                        let mut buf = vec![0; 64 * 1024];
+                        loop {
+                            let n = std::io::Read::read(&mut &reader, &mut buf).unwrap();
+                            if n == 0 {
+                                break;
+                            }
+                            drained.lock().unwrap().extend_from_slice(&buf[..n]);
+                        }
+                    })
+                };
+                // Writes to a pipe can complete short.
+                let n1 = std::future::poll_fn(|_| poll_once(&mut first))
+                    .await
+                    .unwrap();
+                assert!(n1 > 0);
+                // The second write isn't submitted before the barrier completes.
+                assert!(poll_once(&mut second).is_pending());
+                crate::time::sleep(Duration::from_millis(10)).await;
+                assert_eq!(drained.lock().unwrap().len(), n1);
+                barrier.await.unwrap();
+                assert_eq!(second.await.unwrap(), 1);
+
+                // A dropped barrier doesn't hold back the writes after it once the writes before it are done.
+                assert!(poll_once(&mut file.barrier()).is_ready());
+                let mut third = file.write(&big, 0);
+                assert!(poll_once(&mut third).is_pending());
+                let mut barrier = file.barrier();
+                assert!(poll_once(&mut barrier).is_pending());
+                drop(barrier);
+                let mut fourth = file.write(b"4", 0);
+                assert!(poll_once(&mut fourth).is_pending());
+                let n3 = third.await.unwrap();
+                assert_eq!(fourth.await.unwrap(), 1);
+
+                drop(first);
+                file.close().await.unwrap();
+                drainer.join().unwrap();
+                let drained = drained.lock().unwrap();
+                assert_eq!(drained.len(), n1 + n3 + 2);
+                assert_eq!(drained[n1], b'2');
+                assert!(drained[n1 + 1..n1 + n3 + 1].iter().all(|&b| b == 1));
+                assert_eq!(drained[n1 + n3 + 1], b'4');
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_read_range_parallel() {
+        let path = std::env::temp_dir().join(format!("io2_test_parallel_{}", std::process::id()));
+        let data = (0..1_000_000u32).map(|i| i as u8).collect::<Vec<u8>>();
+        std::fs::write(&path, &data).unwrap();
+        let test_path = path.clone();
+        ExecutorConfig::new()
+            .run(async move {
+                let file = File::open(&test_path, libc::O_RDONLY, 0)
+                    .unwrap()
+                    .await
+                    .unwrap();
+                let (buf, stats) = file
+                    .read_range_parallel(1000, 900_000, 64 * 1024, 4)
+                    .await
+                    .unwrap();
+                assert_eq!(buf.as_slice(), &data[1000..901_000]);
+                assert_eq!(stats.bytes, 900_000);
+                assert_eq!(stats.num_chunks, 14);
+                assert!(stats.throughput() > 0.0);
+
+                let err = file
+                    .read_range_parallel(500_000, 600_000, 64 * 1024, 4)
+                    .await
+                    .unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+            })
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_hedged() {
+        let path = std::env::temp_dir().join(format!("io2_test_hedged_{}", std::process::id()));
+        let data = (0..100_000u32)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<u8>>();
+        std::fs::write(&path, &data).unwrap();
+        let test_path = path.clone();
+        ExecutorConfig::new()
+            .run(async move {
+                let file = File::open(&test_path, libc::O_RDONLY, 0)
+                    .unwrap()
+                    .await
+                    .unwrap();
+                let mut buf = vec![0; 10_000];
+                let (n, stats) = file
+                    .read_hedged(&mut buf, 5000, Duration::from_secs(10))
+                    .await
+                    .unwrap();
+                assert_eq!(&buf[..n], &data[5000..5000 + n]);
+                assert!(!stats.hedged);
+
+                // With no delay the second read is issued right away, whichever read wins the data is the same.
+                for offset in [0, 50_000, 95_000] {
+                    buf.fill(0);
+                    let (n, _) = file
+                        .read_hedged(&mut buf, offset, Duration::ZERO)
+                        .await
+                        .unwrap();
+                    let offset = offset as usize;
+                    assert_eq!(n, (data.len() - offset).min(buf.len()));
+                    assert_eq!(&buf[..n], &data[offset..offset + n]);
+                }
+
+                // The first read stalls, so the second read wins and the first one is cancelled instead of waited for.
+                #[cfg(feature = "test_util")]
+                {
+                    use crate::test_util::{inject_faults, Fault, FaultPolicy, FaultRule};
+
+                    let _guard = inject_faults(
+                        FaultPolicy::new(0).rule(
+                            FaultRule::new(Fault::Latency(Duration::from_secs(60)))
+                                .opcode(opcode::Read::CODE)
+                                .times(1),
+                        ),
+                    );
+                    buf.fill(0);
+                    let start = Instant::now();
+                    let (n, stats) = file
+                        .read_hedged(&mut buf, 1000, Duration::from_millis(1))
+                        .await
+                        .unwrap();
+                    assert!(start.elapsed() < Duration::from_secs(10));
+                    assert!(stats.hedged && stats.hedge_won);
+                    assert_eq!(&buf[..n], &data[1000..1000 + n]);
+                }
+            })
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_blocking_reads() {
+        ExecutorConfig::new()
+            .run(async {
+                let file = File::open(Path::new("/proc/self/status"), libc::O_RDONLY, 0)
+                    .unwrap()
+                    .blocking_reads()
+                    .await
+                    .unwrap();
+                // procfs reports a size of 0, read until EOF.
+                let mut out = Vec::new();
+                let mut buf = [0; 256];
+                loop {
+                    let n = file
+                        .read(&mut buf, u64::try_from(out.len()).unwrap())
+                        .await
+                        .unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    out.extend_from_slice(&buf[..n]);
+                }
+                let out = String::from_utf8(out).unwrap();
+                assert!(out.starts_with("Name:"));
+                assert!(out.contains(&format!("Pid:\t{}\n", std::process::id())));
+
+                // u64::MAX reads from the file position.
+                let n = file.read(&mut buf, u64::MAX).await.unwrap();
+                assert!(buf[..n].starts_with(b"Name:"));
+                let n = file.read(&mut buf, u64::MAX).await.unwrap();
+                assert!(n > 0 && !buf[..n].starts_with(b"Name:"));
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_raw_fd() {
+        let path = std::env::temp_dir().join(format!("io2_test_raw_fd_{}", std::process::id()));
+        std::fs::write(&path, b"shared").unwrap();
+        let test_path = path.clone();
+        ExecutorConfig::new()
+            .run(async move {
+                let file = File::open(&test_path, libc::O_RDWR, 0)
+                    .unwrap()
+                    .await
+                    .unwrap();
+                let dup = file.dup().await.unwrap();
+                assert_ne!(dup.as_raw_fd(), file.as_raw_fd());
+                assert!(
+                    unsafe { libc::fcntl(dup.as_raw_fd(), libc::F_GETFD) } & libc::FD_CLOEXEC != 0
+                );
+                file.close().await.unwrap();
+                let mut buf = [0; 6];
+                dup.read_exact(&mut buf, 0).await.unwrap();
+                assert_eq!(&buf, b"shared");
+
+                // Ownership moves to std and back without closing the descriptor.
+                let fd = dup.into_raw_fd();
+                let std_file = unsafe { std::fs::File::from_raw_fd(fd) };
+                let file = File::from(OwnedFd::from(std_file));
+                assert_eq!(file.as_raw_fd(), fd);
+                assert_eq!(file.file_size().await.unwrap(), 6);
+            })
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rw_flags() {
+        let path = std::env::temp_dir().join(format!("io2_test_rw_flags_{}", std::process::id()));
+        let test_path = path.clone();
+        ExecutorConfig::new()
+            .run(async move {
+                let file = File::open(
+                    &test_path,
+                    libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC | libc::O_CLOEXEC,
+                    0o644,
+                )
+                .unwrap()
+                .await
+                .unwrap();
+                assert_eq!(file.write(b"durable", 0).dsync().await.unwrap(), 7);
+
+                // The data was just written so it is in the page cache.
+                let mut buf = [0; 7];
+                match file.read(&mut buf, 0).nowait().await {
+                    Ok(n) => assert_eq!(&buf[..n], &b"durable"[..n]),
+                    // Filesystem doesn't support nowait reads.
+                    Err(e) => assert_eq!(e.raw_os_error(), Some(libc::EAGAIN)),
+                }
+
+                let n = file
+                    .write(b"background", 7)
+                    .priority(IoPriority::Idle)
+                    .await
+                    .unwrap();
+                assert_eq!(n, 10);
+                let mut buf = [0; 17];
+                file.read(&mut buf, 0)
+                    .priority(IoPriority::BestEffort(0))
+                    .await
+                    .unwrap();
+                assert_eq!(&buf, b"durablebackground");
+                assert_eq!(IoPriority::Idle.to_raw(), 3 << 13);
+                assert_eq!(IoPriority::BestEffort(4).to_raw(), 2 << 13 | 4);
+                assert_eq!(IoPriority::RealTime(200).to_raw(), 1 << 13 | 7);
+                assert_eq!(file.write(b"!", 17).synchronized().await.unwrap(), 1);
+
+                for (open, flag) in [
+                    (
+                        File::open(&test_path, libc::O_WRONLY, 0).unwrap().dsync(),
+                        libc::O_DSYNC,
+                    ),
+                    (
+                        File::open(&test_path, libc::O_WRONLY, 0).unwrap().sync(),
+                        libc::O_SYNC,
+                    ),
+                ] {
+                    let synced = open.await.unwrap();
+                    let flags = unsafe { libc::fcntl(synced.fd, libc::F_GETFL) };
+                    assert_eq!(flags & flag, flag);
+                    assert_eq!(synced.write(b"D", 0).await.unwrap(), 1);
+                    synced.close().await.unwrap();
+                }
+            })
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,299 @@
+use std::future::Future;
+use std::io;
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use pin_project_lite::pin_project;
+
+use crate::executor::CURRENT_TASK_CONTEXT;
+
+use super::file::Open;
+use super::File;
+
+impl File {
+    /// Puts the file into the fixed file table of the executor, see
+    /// [ExecutorConfig::fixed_file_table](crate::executor::ExecutorConfig::fixed_file_table).
+    ///
+    /// Reads, writes, syncs and allocations through the returned handle use the slot of the file. Fails if the
+    /// executor doesn't have a table or all of its slots are taken, the file is returned with the error.
+    pub fn register(mut self) -> Result<FixedFile, (File, io::Error)> {
+        let res =
+            CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| match ctx.as_mut().unwrap().fixed_files() {
+                Some(fixed_files) => fixed_files.insert(self.fd),
+                None => Err(no_table()),
+            });
+        match res {
+            Ok(slot) => {
+                self.fixed_slot = Some(slot);
+                Ok(FixedFile {
+                    file: ManuallyDrop::new(self),
+                })
+            }
+            Err(e) => Err((self, e)),
+        }
+    }
+}
+
+impl Open {
+    /// Opens the file directly into a free slot of the fixed file table instead of a descriptor, so it doesn't go
+    /// through the descriptor table at all.
+    ///
+    /// The file only exists in the table, operations that need a descriptor, like [File::dup] or blocking reads, fail
+    /// with EBADF and [FixedFile::into_file] returns the handle back. Closing the handle or dropping it closes the file.
+    /// Fails if the executor doesn't have a table or all of its slots are taken. Requires linux kernel version >= 5.15.
+    pub fn fixed(self) -> OpenFixed {
+        OpenFixed {
+            open: self,
+            slot: None,
+        }
+    }
+}
+
+pin_project! {
+    /// Future of [Open::fixed].
+    #[must_use = "futures do nothing unless you `.await` or poll them"]
+    pub struct OpenFixed {
+        #[pin]
+        open: Open,
+        // Reserved slot while the open is running.
+        slot: Option<u32>,
+    }
+
+    impl PinnedDrop for OpenFixed {
+        fn drop(this: Pin<&mut Self>) {
+            // The open might still install the file, emptying the slot now means it is either closed when the open
+            // completes into a reused slot or stays in the table until the slot is reused.
+            if let Some(slot) = this.project().slot.take() {
+                free_slot(slot);
+            }
+        }
+    }
+}
+
+impl Future for OpenFixed {
+    type Output = io::Result<FixedFile>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = self.project();
+        if this.slot.is_none() {
+            let res = CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+                match ctx.as_mut().unwrap().fixed_files() {
+                    Some(fixed_files) => fixed_files.reserve(),
+                    None => Err(no_table()),
+                }
+            });
+            let slot = match res {
+                Ok(slot) => slot,
+                Err(e) => return Poll::Ready(Err(e)),
+            };
+            *this.slot = Some(slot);
+            *this.open.as_mut().project().file_index = Some(slot);
+        }
+        let res = ready!(this.open.poll(cx));
+        let slot = this.slot.take().unwrap();
+        match res {
+            Ok(file) => Poll::Ready(Ok(FixedFile {
+                file: ManuallyDrop::new(file),
+            })),
+            Err(e) => {
+                CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+                    ctx.as_mut().unwrap().fixed_files().unwrap().release(slot)
+                });
+                Poll::Ready(Err(e))
+            }
+        }
+    }
+}
+
+fn no_table() -> io::Error {
+    io::Error::other("executor doesn't have a fixed file table")
+}
+
+// The table is gone if the executor stopped.
+fn free_slot(slot: u32) {
+    CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+        if let Some(fixed_files) = ctx.as_mut().and_then(|ctx| ctx.fixed_files()) {
+            fixed_files.remove(slot);
+        }
+    });
+}
+
+/// A [File] in the fixed file table of the executor, created with [File::register] or [Open::fixed].
+///
+/// The slot is freed when the file is closed or dropped. The slot is only valid on the executor the file was
+/// registered on.
+pub struct FixedFile {
+    file: ManuallyDrop<File>,
+}
+
+impl FixedFile {
+    /// Index of the slot in the fixed file table.
+    pub fn slot(&self) -> u32 {
+        self.file.fixed_slot.unwrap()
+    }
+
+    // Opened into the slot with Open::fixed, emptying the slot closes it.
+    fn is_direct(&self) -> bool {
+        self.file.fd == -1
+    }
+
+    /// Frees the slot and returns the file, operations on it use the descriptor again.
+    ///
+    /// Returns the handle back if the file was opened with [Open::fixed], it doesn't have a descriptor.
+    pub fn into_file(mut self) -> Result<File, FixedFile> {
+        if self.is_direct() {
+            return Err(self);
+        }
+        self.unregister();
+        let file = unsafe { ManuallyDrop::take(&mut self.file) };
+        std::mem::forget(self);
+        Ok(file)
+    }
+
+    /// Frees the slot and closes the file.
+    pub async fn close(self) -> io::Result<()> {
+        match self.into_file() {
+            Ok(file) => file.close().await,
+            // Dropping it empties the slot, which closes the file.
+            Err(fixed) => {
+                drop(fixed);
+                Ok(())
+            }
+        }
+    }
+
+    fn unregister(&mut self) {
+        if let Some(slot) = self.file.fixed_slot.take() {
+            free_slot(slot);
+        }
+    }
+}
+
+impl Deref for FixedFile {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.file
+    }
+}
+
+impl Drop for FixedFile {
+    fn drop(&mut self) {
+        self.unregister();
+        unsafe { ManuallyDrop::drop(&mut self.file) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::AsRawFd;
+
+    use super::*;
+    use crate::executor::ExecutorConfig;
+
+    #[test]
+    fn test_fixed_file() {
+        let path = std::env::temp_dir().join(format!("io2_test_fixed_file_{}", std::process::id()));
+        let test_path = path.clone();
+        ExecutorConfig::new()
+            .fixed_file_table(2)
+            .run(async move {
+                let open = || async {
+                    File::open(&test_path, libc::O_RDWR | libc::O_CREAT, 0o644)
+                        .unwrap()
+                        .await
+                        .unwrap()
+                };
+                let file = open().await.register().map_err(|(_, e)| e).unwrap();
+                assert_eq!(file.slot(), 0);
+                file.write_all(b"hello fixed", 0).await.unwrap();
+                file.sync_all().await.unwrap();
+                let mut buf = [0; 11];
+                file.read_exact(&mut buf, 0).await.unwrap();
+                assert_eq!(&buf, b"hello fixed");
+
+                // The table has two slots, a dropped file frees its slot.
+                let second = open().await.register().map_err(|(_, e)| e).unwrap();
+                let Err((rejected, err)) = open().await.register() else {
+                    panic!("registered a file in a full table");
+                };
+                assert_eq!(err.kind(), io::ErrorKind::Other);
+                // The file is handed back with the error.
+                rejected.read_exact(&mut buf, 0).await.unwrap();
+                assert_eq!(&buf, b"hello fixed");
+                rejected.close().await.unwrap();
+                drop(second);
+                let third = open().await.register().map_err(|(_, e)| e).unwrap();
+                assert_eq!(third.slot(), 1);
+
+                let Ok(file) = file.into_file() else {
+                    panic!("registered file has a descriptor");
+                };
+                file.read_exact(&mut buf, 0).await.unwrap();
+                assert_eq!(&buf, b"hello fixed");
+                file.close().await.unwrap();
+                third.close().await.unwrap();
+            })
+            .unwrap();
+
+        // Registering fails without a table.
+        let test_path = path.clone();
+        ExecutorConfig::new()
+            .run(async move {
+                let file = File::open(&test_path, libc::O_RDONLY, 0)
+                    .unwrap()
+                    .await
+                    .unwrap();
+                assert!(file.register().is_err());
+                let open = File::open(&test_path, libc::O_RDONLY, 0).unwrap();
+                assert!(open.fixed().await.is_err());
+            })
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_open_fixed() {
+        let path = std::env::temp_dir().join(format!("io2_test_open_fixed_{}", std::process::id()));
+        let test_path = path.clone();
+        ExecutorConfig::new()
+            .fixed_file_table(1)
+            .run(async move {
+                let open = || {
+                    File::open(&test_path, libc::O_RDWR | libc::O_CREAT, 0o644)
+                        .unwrap()
+                        .fixed()
+                };
+                let file = open().await.unwrap();
+                assert_eq!(file.slot(), 0);
+                assert_eq!(file.as_raw_fd(), -1);
+                file.write_all(b"direct", 0).await.unwrap();
+                file.sync_all().await.unwrap();
+                let mut buf = [0; 6];
+                file.read_exact(&mut buf, 0).await.unwrap();
+                assert_eq!(&buf, b"direct");
+
+                assert!(open().await.is_err());
+                let Err(file) = file.into_file() else {
+                    panic!("directly opened file has a descriptor");
+                };
+                file.close().await.unwrap();
+
+                // A failed open gives the slot back.
+                let missing = test_path.with_extension("missing");
+                let err = File::open(&missing, libc::O_RDONLY, 0)
+                    .unwrap()
+                    .fixed()
+                    .await
+                    .err()
+                    .unwrap();
+                assert_eq!(err.kind(), io::ErrorKind::NotFound);
+                let file = open().await.unwrap();
+                assert_eq!(file.slot(), 0);
+            })
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,101 @@
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use crate::blocking::run_blocking;
+
+use super::file::File;
+
+impl File {
+    /// Runs the ioctl `request` with a pointer to `arg` as its argument, returns the value returned by the ioctl and
+    /// `arg` after the kernel read or wrote it.
+    ///
+    /// Most ioctls don't have an io_uring equivalent so this runs on a separate thread. The future can be dropped
+    /// before the ioctl finishes: `arg` and a duplicate of the descriptor are moved to that thread, and the thread's
+    /// completion is waited for without handing any memory of the future to the kernel. The ioctl still runs to the
+    /// end in that case and `arg` is dropped on the thread.
+    ///
+    /// # Safety
+    ///
+    /// `T` has to match the type the kernel expects for `request`, it is read and written as raw memory.
+    pub async unsafe fn ioctl<T: Send + 'static>(
+        &self,
+        request: libc::c_ulong,
+        mut arg: T,
+    ) -> io::Result<(i32, T)> {
+        let fd = self.dup_for_blocking()?;
+        run_blocking(move || {
+            let res = unsafe { libc::ioctl(fd.as_raw_fd(), request, &mut arg as *mut T) };
+            if res < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok((res, arg))
+            }
+        })
+        .await?
+    }
+
+    /// Runs the ioctl `request` with `value` as its argument, for ioctls that take an integer instead of a pointer.
+    ///
+    /// Like [File::ioctl] the future can be dropped before the ioctl finishes.
+    ///
+    /// # Safety
+    ///
+    /// `request` has to be an ioctl that doesn't interpret `value` as a pointer.
+    pub async unsafe fn ioctl_value(
+        &self,
+        request: libc::c_ulong,
+        value: libc::c_ulong,
+    ) -> io::Result<i32> {
+        let fd = self.dup_for_blocking()?;
+        run_blocking(move || {
+            let res = unsafe { libc::ioctl(fd.as_raw_fd(), request, value) };
+            if res < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(res)
+            }
+        })
+        .await?
+    }
+
+    // Duplicates the fd so the blocking thread doesn't use a closed or reused fd if the waiting future is dropped.
+    pub(crate) fn dup_for_blocking(&self) -> io::Result<OwnedFd> {
+        let fd = unsafe { libc::fcntl(self.fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::executor::ExecutorConfig;
+
+    use super::*;
+
+    // From linux/fs.h
+    const FIGETBSZ: libc::c_ulong = 2;
+    const BLKFLSBUF: libc::c_ulong = 0x1261;
+
+    #[test]
+    fn test_ioctl() {
+        ExecutorConfig::new()
+            .run(async {
+                let file = File::open(Path::new("Cargo.toml"), libc::O_RDONLY, 0)
+                    .unwrap()
+                    .await
+                    .unwrap();
+                let (res, block_size) = unsafe { file.ioctl(FIGETBSZ, 0i32) }.await.unwrap();
+                assert_eq!(res, 0);
+                assert!(block_size > 0);
+
+                // A block device ioctl on a regular file.
+                let err = unsafe { file.ioctl_value(BLKFLSBUF, 0) }.await.unwrap_err();
+                assert_eq!(err.raw_os_error(), Some(libc::ENOTTY));
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,329 @@
+//! Files of length prefixed records and k-way merging of sorted record files.
+//!
+//! A record file is a sequence of records, each stored as its length as a little endian u32 followed by its bytes.
+
+use std::cmp::Ordering;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::executor::{spawn, JoinHandle};
+use crate::local_alloc::LocalAlloc;
+
+use super::File;
+
+const DEFAULT_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Writes records to a file through a buffer.
+pub struct RecordWriter {
+    file: File,
+    offset: u64,
+    buf: Vec<u8, LocalAlloc>,
+    buffer_size: usize,
+}
+
+impl RecordWriter {
+    pub async fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self::new(File::create(path)?.await?, DEFAULT_BUFFER_SIZE))
+    }
+
+    /// Writes to `file` from its start, the buffer is written out when it reaches `buffer_size` bytes.
+    pub fn new(file: File, buffer_size: usize) -> Self {
+        Self {
+            file,
+            offset: 0,
+            buf: Vec::with_capacity_in(buffer_size, LocalAlloc::new()),
+            buffer_size,
+        }
+    }
+
+    pub async fn write(&mut self, record: &[u8]) -> io::Result<()> {
+        let len = u32::try_from(record.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "record is too large"))?;
+        self.buf.extend_from_slice(&len.to_le_bytes());
+        self.buf.extend_from_slice(record);
+        if self.buf.len() >= self.buffer_size {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.file.write_all(&self.buf, self.offset).await?;
+        self.offset += u64::try_from(self.buf.len()).unwrap();
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Writes the buffered records and closes the file, returns the size of the file.
+    pub async fn finish(mut self) -> io::Result<u64> {
+        self.flush().await?;
+        self.file.close().await?;
+        Ok(self.offset)
+    }
+}
+
+/// Reads the records of a file sequentially.
+///
+/// The file is read in chunks and the next chunk is read in the background while the records of the current one are
+/// consumed, so a merge over many files doesn't wait for a read every time one of its inputs runs out of buffered
+/// records.
+pub struct RecordReader {
+    file: Rc<File, LocalAlloc>,
+    next_offset: u64,
+    chunk_size: usize,
+    buf: Vec<u8, LocalAlloc>,
+    // Start of the unconsumed part of buf.
+    start: usize,
+    current: Option<Range<usize>>,
+    eof: bool,
+    prefetch: Option<JoinHandle<io::Result<Vec<u8, LocalAlloc>>>>,
+}
+
+impl RecordReader {
+    pub async fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path, libc::O_RDONLY | libc::O_CLOEXEC, 0)?.await?;
+        Ok(Self::new(file, DEFAULT_BUFFER_SIZE))
+    }
+
+    /// Reads `file` from its start in reads of `chunk_size` bytes.
+    pub fn new(file: File, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "chunk_size has to be positive");
+        Self {
+            file: Rc::new_in(file, LocalAlloc::new()),
+            next_offset: 0,
+            chunk_size,
+            buf: Vec::new_in(LocalAlloc::new()),
+            start: 0,
+            current: None,
+            eof: false,
+            prefetch: None,
+        }
+    }
+
+    /// Record that the last call to [RecordReader::advance] moved to.
+    pub fn current(&self) -> Option<&[u8]> {
+        self.current.clone().map(|range| &self.buf[range])
+    }
+
+    /// Moves to the next record, returns false at the end of the file.
+    pub async fn advance(&mut self) -> io::Result<bool> {
+        if let Some(range) = self.current.take() {
+            self.start = range.end;
+        }
+        loop {
+            let avail = &self.buf[self.start..];
+            if avail.len() >= 4 {
+                let len = u32::from_le_bytes(avail[..4].try_into().unwrap());
+                let end = 4 + usize::try_from(len).unwrap();
+                if avail.len() >= end {
+                    self.current = Some(self.start + 4..self.start + end);
+                    return Ok(true);
+                }
+            }
+            if self.eof {
+                if avail.is_empty() {
+                    return Ok(false);
+                }
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "record file ends with a partial record",
+                ));
+            }
+            self.fill().await?;
+        }
+    }
+
+    // Appends the next chunk to the buffer and starts reading the one after it.
+    async fn fill(&mut self) -> io::Result<()> {
+        self.buf.drain(..self.start);
+        self.start = 0;
+        let chunk = match self.prefetch.take() {
+            Some(prefetch) => prefetch.await?,
+            None => read_chunk(&self.file, self.next_offset, self.chunk_size).await?,
+        };
+        self.next_offset += u64::try_from(chunk.len()).unwrap();
+        if chunk.len() < self.chunk_size {
+            self.eof = true;
+        }
+        self.buf.extend_from_slice(&chunk);
+        if !self.eof {
+            let file = self.file.clone();
+            let (offset, len) = (self.next_offset, self.chunk_size);
+            self.prefetch = Some(spawn(async move { read_chunk(&file, offset, len).await }));
+        }
+        Ok(())
+    }
+}
+
+async fn read_chunk(file: &File, offset: u64, len: usize) -> io::Result<Vec<u8, LocalAlloc>> {
+    let mut buf = Vec::with_capacity_in(len, LocalAlloc::new());
+    buf.resize(len, 0);
+    let mut filled = 0;
+    while filled < len {
+        match file
+            .read(&mut buf[filled..], offset + u64::try_from(filled).unwrap())
+            .await?
+        {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    buf.truncate(filled);
+    Ok(buf)
+}
+
+/// Merges record files that are each sorted by `cmp` into one sorted stream.
+///
+/// The inputs are merged with a loser tree, so each record takes `log2(k)` comparisons for `k` inputs. Records that
+/// compare equal are returned in the order of their inputs.
+pub fn merge_sorted_readers<F>(readers: Vec<RecordReader>, cmp: F) -> MergeSorted<F>
+where
+    F: FnMut(&[u8], &[u8]) -> Ordering,
+{
+    MergeSorted {
+        tree: vec![0; readers.len()],
+        readers,
+        cmp,
+        started: false,
+        last_winner: None,
+    }
+}
+
+/// Stream of merged records, see [merge_sorted_readers].
+pub struct MergeSorted<F> {
+    readers: Vec<RecordReader>,
+    // tree[0] is the input with the smallest record, the other nodes hold the input that lost the comparison at that
+    // node. Input i is the leaf at node k + i.
+    tree: Vec<usize>,
+    cmp: F,
+    started: bool,
+    // Input of the record that was returned last, it is advanced on the next call.
+    last_winner: Option<usize>,
+}
+
+impl<F: FnMut(&[u8], &[u8]) -> Ordering> MergeSorted<F> {
+    /// Returns the next record, `None` after all inputs are exhausted.
+    pub async fn next(&mut self) -> Option<io::Result<&[u8]>> {
+        if self.readers.is_empty() {
+            return None;
+        }
+        if !self.started {
+            self.started = true;
+            for reader in &mut self.readers {
+                if let Err(e) = reader.advance().await {
+                    return Some(Err(e));
+                }
+            }
+            self.tree[0] = self.init(1);
+        } else if let Some(winner) = self.last_winner.take() {
+            if let Err(e) = self.readers[winner].advance().await {
+                return Some(Err(e));
+            }
+            self.replay(winner);
+        }
+        let winner = self.tree[0];
+        let record = self.readers[winner].current()?;
+        self.last_winner = Some(winner);
+        Some(Ok(record))
+    }
+
+    // Returns whether input a's record goes before input b's, exhausted inputs go last.
+    fn less(&mut self, a: usize, b: usize) -> bool {
+        match (self.readers[a].current(), self.readers[b].current()) {
+            (Some(x), Some(y)) => match (self.cmp)(x, y) {
+                Ordering::Less => true,
+                Ordering::Equal => a < b,
+                Ordering::Greater => false,
+            },
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => a < b,
+        }
+    }
+
+    // Plays the matches of the subtree at `node`, returns the winner.
+    fn init(&mut self, node: usize) -> usize {
+        let k = self.readers.len();
+        if node >= k {
+            return node - k;
+        }
+        let a = self.init(2 * node);
+        let b = self.init(2 * node + 1);
+        let (winner, loser) = if self.less(a, b) { (a, b) } else { (b, a) };
+        self.tree[node] = loser;
+        winner
+    }
+
+    // Replays the matches on the path from the leaf of `input` to the root after its record changed.
+    fn replay(&mut self, input: usize) {
+        let mut winner = input;
+        let mut node = (input + self.readers.len()) / 2;
+        while node > 0 {
+            if self.less(self.tree[node], winner) {
+                std::mem::swap(&mut self.tree[node], &mut winner);
+            }
+            node /= 2;
+        }
+        self.tree[0] = winner;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::ExecutorConfig;
+
+    #[test]
+    fn test_merge_sorted_readers() {
+        let dir = std::env::temp_dir().join(format!("io2_test_merge_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let test_dir = dir.clone();
+        ExecutorConfig::new()
+            .run(async move {
+                // Five inputs with interleaved keys and different lengths, one of them empty.
+                let mut expected = Vec::new();
+                let mut readers = Vec::new();
+                for input in 0..5u32 {
+                    let path = test_dir.join(format!("run{}", input));
+                    let mut writer = RecordWriter::create(&path).await.unwrap();
+                    for i in 0..(input * 300) {
+                        let record = format!("{:08}-{}", i * 7 % 1000 + i * 1000, input);
+                        expected.push(record);
+                    }
+                    let start = expected.len() - usize::try_from(input * 300).unwrap();
+                    expected[start..].sort();
+                    for record in &expected[start..] {
+                        writer.write(record.as_bytes()).await.unwrap();
+                    }
+                    writer.finish().await.unwrap();
+                    let file = File::open(&path, libc::O_RDONLY, 0).unwrap().await.unwrap();
+                    // Small chunks so records span chunk boundaries.
+                    readers.push(RecordReader::new(file, 100));
+                }
+                expected.sort();
+
+                let mut merged = merge_sorted_readers(readers, |a, b| a.cmp(b));
+                let mut out = Vec::new();
+                while let Some(record) = merged.next().await {
+                    out.push(String::from_utf8(record.unwrap().to_vec()).unwrap());
+                }
+                assert_eq!(out, expected);
+                assert!(merged.next().await.is_none());
+
+                // A truncated file is an error instead of a silently dropped record.
+                let path = test_dir.join("truncated");
+                std::fs::write(&path, [10, 0, 0, 0, b'a']).unwrap();
+                let mut merged =
+                    merge_sorted_readers(vec![RecordReader::open(&path).await.unwrap()], |a, b| {
+                        a.cmp(b)
+                    });
+                let err = merged.next().await.unwrap().unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            })
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,2 +1,125 @@
 pub mod dio_file;
+pub mod dir;
 pub mod file;
+mod fixed_file;
+mod ioctl;
+mod merge;
+mod read_dir;
+mod region_lock;
+pub mod statfs;
+mod sync_coalescer;
+mod tail;
+mod walk_dir;
+
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::local_alloc::LocalAlloc;
+
+pub use dir::Dir;
+pub use file::{remove_file, rename, File, HedgeStats, IoPriority, ReadRangeStats};
+pub use fixed_file::{FixedFile, OpenFixed};
+pub use merge::{merge_sorted_readers, MergeSorted, RecordReader, RecordWriter};
+pub use read_dir::{for_each_file_concurrent, read_dir, DirEntry};
+pub use region_lock::{RegionGuard, RegionLock};
+pub use statfs::{statvfs, FsStats};
+pub use sync_coalescer::SyncCoalescer;
+pub use tail::Tail;
+pub use walk_dir::{walk_dir, WalkDir, WalkEntry, WalkErrorPolicy};
+
+static NEXT_TEMP_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Reads the whole file into memory.
+pub async fn read(path: &Path) -> io::Result<Vec<u8, LocalAlloc>> {
+    file::read(path, LocalAlloc::new()).await
+}
+
+/// Writes `data` to the file at `path`, replacing its contents.
+///
+/// A crash while this is running can leave the file partially written, use [write_atomic] if that is a problem.
+pub async fn write(path: &Path, data: &[u8]) -> io::Result<()> {
+    let file = File::create(path)?.await?;
+    file.write_all(data, 0).await?;
+    file.close().await
+}
+
+/// Writes `data` to the file at `path` so the file either has its old contents or `data`, even after a crash.
+///
+/// The data is written to a temporary file in the same directory which is synced and then renamed over `path`,
+/// the directory is synced after the rename so the new file survives a crash.
+pub async fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp_path = temp_path_for(path)?;
+    let res = write_synced(&tmp_path, data).await;
+    let res = match res {
+        Ok(()) => rename(&tmp_path, path)?.await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = res {
+        let _ = remove_file(&tmp_path)?.await;
+        return Err(e);
+    }
+
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let dir = File::open(dir, libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC, 0)?.await?;
+    dir.sync_all().await?;
+    dir.close().await
+}
+
+async fn write_synced(path: &Path, data: &[u8]) -> io::Result<()> {
+    let file = File::create(path)?.await?;
+    file.write_all(data, 0).await?;
+    file.sync_all().await?;
+    file.close().await
+}
+
+fn temp_path_for(path: &Path) -> io::Result<PathBuf> {
+    let file_name = path.file_name().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "path doesn't have a file name")
+    })?;
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(format!(
+        ".tmp.{}.{}",
+        std::process::id(),
+        NEXT_TEMP_ID.fetch_add(1, Ordering::Relaxed)
+    ));
+    Ok(path.with_file_name(tmp_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::executor::ExecutorConfig;
+
+    use super::*;
+
+    #[test]
+    fn test_read_write() {
+        let dir = std::env::temp_dir().join(format!("io2_test_fs_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data");
+
+        let test_path = path.clone();
+        ExecutorConfig::new()
+            .run(async move {
+                write(&test_path, b"first").await.unwrap();
+                assert_eq!(read(&test_path).await.unwrap().as_slice(), b"first");
+
+                write_atomic(&test_path, b"second version").await.unwrap();
+                assert_eq!(
+                    read(&test_path).await.unwrap().as_slice(),
+                    b"second version"
+                );
+            })
+            .unwrap();
+
+        // Only the target file is left, the temporary file was renamed over it.
+        let entries = std::fs::read_dir(&dir).unwrap().count();
+        assert_eq!(entries, 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,191 @@
+//! Listing directories and processing the files under a directory.
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::blocking::run_blocking;
+use crate::executor;
+use crate::local_alloc::LocalAlloc;
+use crate::sync::Semaphore;
+
+use super::File;
+
+/// An entry of a directory listed by [read_dir].
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    path: PathBuf,
+    file_type: std::fs::FileType,
+}
+
+impl DirEntry {
+    /// Path of the entry, it is the listed directory joined with the entry's name.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Type of the entry itself, symlinks are not followed.
+    pub fn file_type(&self) -> std::fs::FileType {
+        self.file_type
+    }
+}
+
+/// Lists the entries of a directory, `.` and `..` are not included.
+///
+/// io_uring has no getdents so the listing runs on a blocking thread, the whole directory is read at once.
+pub async fn read_dir(path: &Path) -> io::Result<Vec<DirEntry>> {
+    let path = path.to_owned();
+    run_blocking(move || {
+        std::fs::read_dir(&path)?
+            .map(|entry| {
+                let entry = entry?;
+                Ok(DirEntry {
+                    path: entry.path(),
+                    file_type: entry.file_type()?,
+                })
+            })
+            .collect()
+    })
+    .await?
+}
+
+/// Opens every regular file under `root` and passes it to `f`, running at most `concurrency` calls at a time.
+///
+/// Directories are walked depth first and symlinks are skipped. Each call runs in its own task, a file is only
+/// opened once a slot is free so at most `concurrency` files are open. The first error stops new files from being
+/// started, it is returned after the calls that are already running finish.
+pub async fn for_each_file_concurrent<F, Fut>(
+    root: &Path,
+    concurrency: usize,
+    f: F,
+) -> io::Result<()>
+where
+    F: Fn(PathBuf, File) -> Fut,
+    Fut: Future<Output = io::Result<()>> + 'static,
+{
+    assert!(concurrency > 0, "concurrency has to be positive");
+    let semaphore = Semaphore::new(concurrency);
+    let first_err: Rc<RefCell<Option<io::Error>>, LocalAlloc> =
+        Rc::new_in(RefCell::new(None), LocalAlloc::new());
+
+    let res = walk_files(root, &semaphore, &first_err, &f).await;
+
+    // Taking every permit waits for the running calls to finish.
+    let mut permits = Vec::with_capacity(concurrency);
+    for _ in 0..concurrency {
+        permits.push(semaphore.acquire().await);
+    }
+
+    res?;
+    let first_err = first_err.borrow_mut().take();
+    match first_err {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+async fn walk_files<F, Fut>(
+    root: &Path,
+    semaphore: &Semaphore,
+    first_err: &Rc<RefCell<Option<io::Error>>, LocalAlloc>,
+    f: &F,
+) -> io::Result<()>
+where
+    F: Fn(PathBuf, File) -> Fut,
+    Fut: Future<Output = io::Result<()>> + 'static,
+{
+    let mut dirs = vec![root.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        for entry in read_dir(&dir).await? {
+            if entry.file_type.is_dir() {
+                dirs.push(entry.path);
+                continue;
+            }
+            if !entry.file_type.is_file() {
+                continue;
+            }
+
+            let permit = semaphore.acquire().await;
+            if first_err.borrow().is_some() {
+                return Ok(());
+            }
+            let file = File::open(&entry.path, libc::O_RDONLY | libc::O_CLOEXEC, 0)?.await?;
+            let fut = f(entry.path, file);
+            let first_err = first_err.clone();
+            // The handle isn't needed, completion is tracked through the permits.
+            drop(executor::spawn(async move {
+                if let Err(e) = fut.await {
+                    first_err.borrow_mut().get_or_insert(e);
+                }
+                drop(permit);
+            }));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::executor::ExecutorConfig;
+
+    #[test]
+    fn test_for_each_file_concurrent() {
+        let root = std::env::temp_dir().join(format!("io2_test_read_dir_{}", std::process::id()));
+        for i in 0..20 {
+            let dir = root.join(format!("d{}", i % 3)).join(format!("e{}", i % 2));
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join(format!("f{}", i)), vec![1u8; i + 1]).unwrap();
+        }
+        std::os::unix::fs::symlink(root.join("d0"), root.join("link")).unwrap();
+
+        let test_root = root.clone();
+        ExecutorConfig::new()
+            .run(async move {
+                let entries = read_dir(&test_root).await.unwrap();
+                assert_eq!(entries.len(), 4);
+                assert!(entries
+                    .iter()
+                    .any(|e| e.path() == test_root.join("link") && e.file_type().is_symlink()));
+
+                let running = Rc::new(Cell::new(0));
+                let max_running = Rc::new(Cell::new(0));
+                let total = Rc::new(Cell::new(0));
+                for_each_file_concurrent(&test_root, 3, |_path, file| {
+                    let running = running.clone();
+                    let max_running = max_running.clone();
+                    let total = total.clone();
+                    async move {
+                        running.set(running.get() + 1);
+                        max_running.set(max_running.get().max(running.get()));
+                        let mut data = vec![0; usize::try_from(file.file_size().await?).unwrap()];
+                        file.read_exact(&mut data, 0).await?;
+                        total.set(total.get() + data.len());
+                        running.set(running.get() - 1);
+                        file.close().await
+                    }
+                })
+                .await
+                .unwrap();
+                assert_eq!(total.get(), (1..=20).sum::<usize>());
+                assert_eq!(max_running.get(), 3);
+
+                let err = for_each_file_concurrent(&test_root, 2, |path, _file| async move {
+                    if path.ends_with("f7") {
+                        Err(io::Error::other("bad file"))
+                    } else {
+                        Ok(())
+                    }
+                })
+                .await
+                .unwrap_err();
+                assert_eq!(err.to_string(), "bad file");
+            })
+            .unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
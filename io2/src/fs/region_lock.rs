@@ -0,0 +1,319 @@
+//! Byte range locks over a shared file.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::ops::Range;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::executor;
+use crate::local_alloc::LocalAlloc;
+use crate::slab;
+use crate::time::sleep;
+
+use super::File;
+
+const MIN_OFD_RETRY_DELAY: Duration = Duration::from_millis(1);
+const MAX_OFD_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+struct Shared {
+    file: File,
+    ofd_locks: bool,
+    state: RefCell<State>,
+}
+
+struct State {
+    // Ranges that are currently held by a RegionGuard, they never overlap.
+    held: Vec<Range<u64>, LocalAlloc>,
+    // Tasks waiting for a range to be released.
+    waiters: VecDeque<slab::Key, LocalAlloc>,
+    // Offset of the next reservation made by lock_append, read from the file on first use.
+    append_offset: Option<u64>,
+}
+
+/// Coordinates exclusive ownership of byte ranges of a file between tasks, so concurrent writers don't interleave
+/// their writes.
+///
+/// The lock owns the file, cloning it gives another handle to the same lock. A task locks a range, writes to it through
+/// [RegionLock::file] and drops the [RegionGuard] to release it.
+///
+/// With [RegionLock::with_ofd_locks] ranges are also locked with open file description locks (F_OFD_SETLK), so they
+/// are exclusive against other processes that lock the same file. OFD locks can't block without blocking the executor
+/// thread, so a range that is locked by another process is retried with a backoff until it is free.
+#[derive(Clone)]
+pub struct RegionLock {
+    shared: Rc<Shared, LocalAlloc>,
+}
+
+impl RegionLock {
+    /// Creates a lock that only coordinates tasks inside this process.
+    pub fn new(file: File) -> Self {
+        Self::with_config(file, false)
+    }
+
+    /// Creates a lock that also takes OFD locks so the ranges are exclusive against other processes.
+    pub fn with_ofd_locks(file: File) -> Self {
+        Self::with_config(file, true)
+    }
+
+    fn with_config(file: File, ofd_locks: bool) -> Self {
+        Self {
+            shared: Rc::new_in(
+                Shared {
+                    file,
+                    ofd_locks,
+                    state: RefCell::new(State {
+                        held: Vec::new_in(LocalAlloc::new()),
+                        waiters: VecDeque::new_in(LocalAlloc::new()),
+                        append_offset: None,
+                    }),
+                },
+                LocalAlloc::new(),
+            ),
+        }
+    }
+
+    /// Reads and writes to a range should be done while holding a guard for it.
+    pub fn file(&self) -> &File {
+        &self.shared.file
+    }
+
+    /// Locks `len` bytes starting at `offset`, waiting until no other guard overlaps with the range.
+    pub async fn lock(&self, offset: u64, len: u64) -> io::Result<RegionGuard> {
+        let range = checked_range(offset, len)?;
+        loop {
+            if self.try_reserve(&range) {
+                break;
+            }
+            WaitForRelease {
+                lock: self,
+                registered: false,
+            }
+            .await;
+        }
+        self.lock_ofd(range).await
+    }
+
+    /// Locks the range if no other guard in this process overlaps with it, returns `None` otherwise.
+    ///
+    /// This doesn't wait for OFD locks held by other processes, `WouldBlock` is returned in that case.
+    pub fn try_lock(&self, offset: u64, len: u64) -> io::Result<Option<RegionGuard>> {
+        let range = checked_range(offset, len)?;
+        if !self.try_reserve(&range) {
+            return Ok(None);
+        }
+        let mut guard = self.guard(range);
+        guard.set_ofd_lock(libc::F_WRLCK)?;
+        Ok(Some(guard))
+    }
+
+    /// Reserves and locks the next `len` bytes at the end of the file, for tasks appending records to a shared file.
+    ///
+    /// The end of the file is read when this is first called and advanced by every reservation, so reservations are
+    /// only coordinated between users of this lock. Other processes should lock explicit ranges.
+    pub async fn lock_append(&self, len: u64) -> io::Result<RegionGuard> {
+        if self.shared.state.borrow().append_offset.is_none() {
+            let file_size = self.shared.file.file_size().await?;
+            // Another task might have read the size while this one was waiting.
+            let mut state = self.shared.state.borrow_mut();
+            state.append_offset.get_or_insert(file_size);
+        }
+        let offset = {
+            let mut state = self.shared.state.borrow_mut();
+            let offset = state.append_offset.unwrap();
+            state.append_offset = Some(checked_range(offset, len)?.end);
+            offset
+        };
+        self.lock(offset, len).await
+    }
+
+    fn try_reserve(&self, range: &Range<u64>) -> bool {
+        let mut state = self.shared.state.borrow_mut();
+        if state
+            .held
+            .iter()
+            .any(|r| r.start < range.end && range.start < r.end)
+        {
+            return false;
+        }
+        state.held.push(range.clone());
+        true
+    }
+
+    fn guard(&self, range: Range<u64>) -> RegionGuard {
+        RegionGuard {
+            lock: self.clone(),
+            range,
+            ofd_locked: false,
+        }
+    }
+
+    async fn lock_ofd(&self, range: Range<u64>) -> io::Result<RegionGuard> {
+        // The range is reserved in this process from here on, the guard releases it if this fails.
+        let mut guard = self.guard(range);
+        let mut delay = MIN_OFD_RETRY_DELAY;
+        loop {
+            match guard.set_ofd_lock(libc::F_WRLCK) {
+                Ok(()) => return Ok(guard),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    sleep(delay).await;
+                    delay = (delay * 2).min(MAX_OFD_RETRY_DELAY);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+fn checked_range(offset: u64, len: u64) -> io::Result<Range<u64>> {
+    match offset.checked_add(len) {
+        Some(end) if len > 0 && i64::try_from(end).is_ok() => Ok(offset..end),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "region has to be non-empty and end before i64::MAX",
+        )),
+    }
+}
+
+/// Exclusive ownership of a range of the file, the range is released when this is dropped.
+pub struct RegionGuard {
+    lock: RegionLock,
+    range: Range<u64>,
+    ofd_locked: bool,
+}
+
+impl RegionGuard {
+    pub fn offset(&self) -> u64 {
+        self.range.start
+    }
+
+    pub fn range(&self) -> Range<u64> {
+        self.range.clone()
+    }
+
+    pub fn lock(&self) -> &RegionLock {
+        &self.lock
+    }
+
+    fn set_ofd_lock(&mut self, lock_type: i32) -> io::Result<()> {
+        let shared = &self.lock.shared;
+        if !shared.ofd_locks {
+            return Ok(());
+        }
+        let mut flock: libc::flock = unsafe { std::mem::zeroed() };
+        flock.l_type = lock_type as libc::c_short;
+        flock.l_whence = libc::SEEK_SET as libc::c_short;
+        flock.l_start = i64::try_from(self.range.start).unwrap();
+        flock.l_len = i64::try_from(self.range.end - self.range.start).unwrap();
+        if unsafe { libc::fcntl(shared.file.fd, libc::F_OFD_SETLK, &flock) } == -1 {
+            let err = io::Error::last_os_error();
+            return Err(match err.raw_os_error() {
+                Some(libc::EACCES) => io::Error::from(io::ErrorKind::WouldBlock),
+                _ => err,
+            });
+        }
+        self.ofd_locked = lock_type != libc::F_UNLCK;
+        Ok(())
+    }
+}
+
+impl Drop for RegionGuard {
+    fn drop(&mut self) {
+        if self.ofd_locked {
+            if let Err(e) = self.set_ofd_lock(libc::F_UNLCK) {
+                log::error!("failed to release OFD lock: {}", e);
+            }
+        }
+        let mut state = self.lock.shared.state.borrow_mut();
+        let pos = state.held.iter().position(|r| *r == self.range).unwrap();
+        state.held.swap_remove(pos);
+        // Waiters might be waiting for any range so all of them check again.
+        for task_id in state.waiters.drain(..) {
+            executor::notify_task(task_id);
+        }
+    }
+}
+
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+struct WaitForRelease<'lock> {
+    lock: &'lock RegionLock,
+    registered: bool,
+}
+
+impl<'lock> Future for WaitForRelease<'lock> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let fut = self.get_mut();
+        let task_id = executor::current_task_id();
+        let mut state = fut.lock.shared.state.borrow_mut();
+        if fut.registered {
+            state.waiters.retain(|&id| id != task_id);
+            return Poll::Ready(());
+        }
+        fut.registered = true;
+        state.waiters.push_back(task_id);
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::executor::{spawn, ExecutorConfig};
+
+    #[test]
+    fn test_region_lock() {
+        ExecutorConfig::new()
+            .run(async {
+                let path =
+                    std::env::temp_dir().join(format!("io2_region_lock_{}", std::process::id()));
+                let open =
+                    || File::open(&path, libc::O_RDWR | libc::O_CREAT | libc::O_CLOEXEC, 0o644);
+                let lock = RegionLock::with_ofd_locks(open().unwrap().await.unwrap());
+
+                let guard = lock.lock(0, 100).await.unwrap();
+                assert!(lock.try_lock(50, 100).unwrap().is_none());
+                let other = lock.try_lock(100, 100).unwrap().unwrap();
+
+                // Another open file description conflicts with the OFD locks.
+                let other_process = RegionLock::with_ofd_locks(open().unwrap().await.unwrap());
+                let err = other_process.try_lock(150, 10).map(|_| ()).unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+                drop(other);
+                other_process.try_lock(150, 10).unwrap().unwrap();
+
+                let locked = Rc::new(Cell::new(false));
+                let waiter = spawn({
+                    let lock = lock.clone();
+                    let locked = locked.clone();
+                    async move {
+                        let guard = lock.lock(99, 1).await.unwrap();
+                        locked.set(true);
+                        lock.file().write_all(b"b", guard.offset()).await.unwrap();
+                    }
+                });
+                crate::time::sleep(Duration::from_millis(5)).await;
+                assert!(!locked.get());
+                lock.file().write_all(&[b'a'; 100], 0).await.unwrap();
+                drop(guard);
+                waiter.await;
+
+                let a = lock.lock_append(10).await.unwrap();
+                let b = lock.lock_append(5).await.unwrap();
+                assert_eq!((a.range(), b.range()), (100..110, 110..115));
+
+                let data = crate::fs::read(&path).await.unwrap();
+                assert_eq!(&data[..99], &[b'a'; 99]);
+                assert_eq!(data[99], b'b');
+                std::fs::remove_file(&path).unwrap();
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,105 @@
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::blocking::run_blocking;
+
+use super::file::File;
+
+/// Filesystem statistics, see `statfs(2)`.
+#[derive(Clone, Copy, Debug)]
+pub struct FsStats {
+    /// Magic number of the filesystem type, e.g. `libc::EXT4_SUPER_MAGIC`.
+    pub fs_type: i64,
+    pub block_size: u64,
+    pub blocks: u64,
+    pub blocks_free: u64,
+    /// Free blocks available to unprivileged users, this can be less than `blocks_free` because of reserved blocks.
+    pub blocks_available: u64,
+    pub files: u64,
+    pub files_free: u64,
+}
+
+impl FsStats {
+    fn from_raw(raw: &libc::statfs) -> Self {
+        Self {
+            fs_type: raw.f_type,
+            block_size: raw.f_bsize.try_into().unwrap(),
+            blocks: raw.f_blocks,
+            blocks_free: raw.f_bfree,
+            blocks_available: raw.f_bavail,
+            files: raw.f_files,
+            files_free: raw.f_ffree,
+        }
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.blocks * self.block_size
+    }
+
+    /// Bytes that can be written by an unprivileged user.
+    pub fn available_bytes(&self) -> u64 {
+        self.blocks_available * self.block_size
+    }
+}
+
+/// Returns statistics of the filesystem that contains `path`.
+///
+/// There is no io_uring operation for this so the syscall runs on a separate thread, it can block for a long time on
+/// network filesystems.
+pub async fn statvfs(path: &Path) -> io::Result<FsStats> {
+    // LocalCString can't be sent to another thread.
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "null value in path"))?;
+    run_blocking(move || {
+        let mut raw: libc::statfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statfs(path.as_ptr(), &mut raw) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(FsStats::from_raw(&raw))
+    })
+    .await?
+}
+
+impl File {
+    /// Returns statistics of the filesystem this file is on, see [statvfs].
+    pub async fn statfs(&self) -> io::Result<FsStats> {
+        let fd = self.fd;
+        // The fd stays open while this borrows the file.
+        run_blocking(move || {
+            let mut raw: libc::statfs = unsafe { std::mem::zeroed() };
+            if unsafe { libc::fstatfs(fd, &mut raw) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(FsStats::from_raw(&raw))
+        })
+        .await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::executor::ExecutorConfig;
+
+    use super::*;
+
+    #[test]
+    fn test_statfs() {
+        ExecutorConfig::new()
+            .run(async {
+                let by_path = statvfs(Path::new("Cargo.toml")).await.unwrap();
+                let file = File::open(Path::new("Cargo.toml"), libc::O_RDONLY, 0)
+                    .unwrap()
+                    .await
+                    .unwrap();
+                let by_file = file.statfs().await.unwrap();
+
+                assert_eq!(by_path.fs_type, by_file.fs_type);
+                assert_eq!(by_path.total_bytes(), by_file.total_bytes());
+                assert!(by_path.total_bytes() > 0);
+                assert!(by_path.available_bytes() <= by_path.total_bytes());
+            })
+            .unwrap();
+    }
+}
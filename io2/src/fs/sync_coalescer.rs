@@ -0,0 +1,202 @@
+//! Group commit of fsyncs on a shared file.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use crate::executor;
+use crate::local_alloc::LocalAlloc;
+use crate::slab;
+
+use super::File;
+
+struct Shared {
+    file: File,
+    state: RefCell<State>,
+}
+
+struct State {
+    // Number of syncs that were started, the running one included.
+    started: u64,
+    // Number of syncs that finished.
+    finished: u64,
+    running: bool,
+    // Set after a sync fails. The kernel can drop dirty pages after a failed writeback, so a later successful sync
+    // doesn't mean earlier writes are durable and every sync fails from then on.
+    failed: Option<(io::ErrorKind, String)>,
+    waiters: VecDeque<slab::Key, LocalAlloc>,
+}
+
+impl State {
+    fn check_failed(&self) -> io::Result<()> {
+        match &self.failed {
+            Some((kind, msg)) => Err(io::Error::new(
+                *kind,
+                format!("sync failed earlier: {}", msg),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Coalesces concurrent [File::sync_data] calls on a file into a single fdatasync.
+///
+/// A call returns after a sync that started after it was made finished, so every write that completed before the call
+/// is durable when it returns. Only one sync runs at a time and the calls that are made while it runs share the next
+/// one, so the number of fsyncs doesn't grow with the number of tasks that are syncing.
+///
+/// The coalescer owns the file, cloning it gives another handle to the same coalescer. If a sync fails, the error is
+/// returned to every task waiting for it and all later calls fail too, the file has to be opened again.
+#[derive(Clone)]
+pub struct SyncCoalescer {
+    shared: Rc<Shared, LocalAlloc>,
+}
+
+impl SyncCoalescer {
+    pub fn new(file: File) -> Self {
+        Self {
+            shared: Rc::new_in(
+                Shared {
+                    file,
+                    state: RefCell::new(State {
+                        started: 0,
+                        finished: 0,
+                        running: false,
+                        failed: None,
+                        waiters: VecDeque::new_in(LocalAlloc::new()),
+                    }),
+                },
+                LocalAlloc::new(),
+            ),
+        }
+    }
+
+    pub fn file(&self) -> &File {
+        &self.shared.file
+    }
+
+    /// Number of fdatasyncs that were issued, for comparing against the number of [SyncCoalescer::sync_data] calls.
+    pub fn num_syncs(&self) -> u64 {
+        self.shared.state.borrow().started
+    }
+
+    /// Waits until the writes that completed before this call are durable.
+    pub async fn sync_data(&self) -> io::Result<()> {
+        // If no sync is running the next one starts right away, otherwise the running one might have started before
+        // the writes of the caller completed so the one after it is needed.
+        let target = self.shared.state.borrow().started + 1;
+        loop {
+            {
+                let mut state = self.shared.state.borrow_mut();
+                state.check_failed()?;
+                if state.finished >= target {
+                    return Ok(());
+                }
+                if !state.running {
+                    state.running = true;
+                    state.started += 1;
+                    break;
+                }
+            }
+            WaitForSync {
+                coalescer: self,
+                registered: false,
+            }
+            .await;
+        }
+
+        let guard = RunningGuard { coalescer: self };
+        let res = self.shared.file.sync_data().await;
+        let mut state = self.shared.state.borrow_mut();
+        match &res {
+            Ok(()) => state.finished = state.started,
+            Err(e) => state.failed = Some((e.kind(), e.to_string())),
+        }
+        drop(state);
+        drop(guard);
+        res
+    }
+}
+
+// Lets another task start a sync if the task running it is dropped, and wakes the waiting tasks.
+struct RunningGuard<'coalescer> {
+    coalescer: &'coalescer SyncCoalescer,
+}
+
+impl Drop for RunningGuard<'_> {
+    fn drop(&mut self) {
+        let mut state = self.coalescer.shared.state.borrow_mut();
+        state.running = false;
+        for task_id in state.waiters.drain(..) {
+            executor::notify_task(task_id);
+        }
+    }
+}
+
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+struct WaitForSync<'coalescer> {
+    coalescer: &'coalescer SyncCoalescer,
+    registered: bool,
+}
+
+impl<'coalescer> Future for WaitForSync<'coalescer> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let fut = self.get_mut();
+        let task_id = executor::current_task_id();
+        let mut state = fut.coalescer.shared.state.borrow_mut();
+        if fut.registered {
+            state.waiters.retain(|&id| id != task_id);
+            return Poll::Ready(());
+        }
+        fut.registered = true;
+        state.waiters.push_back(task_id);
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::{spawn, ExecutorConfig};
+
+    #[test]
+    fn test_sync_coalescer() {
+        ExecutorConfig::new()
+            .run(async {
+                let path =
+                    std::env::temp_dir().join(format!("io2_sync_coalescer_{}", std::process::id()));
+                let file = File::create(&path).unwrap().await.unwrap();
+                let coalescer = SyncCoalescer::new(file);
+
+                coalescer.sync_data().await.unwrap();
+                assert_eq!(coalescer.num_syncs(), 1);
+
+                let handles = (0..32u64)
+                    .map(|i| {
+                        let coalescer = coalescer.clone();
+                        spawn(async move {
+                            coalescer.file().write_all(&[i as u8], i).await.unwrap();
+                            coalescer.sync_data().await.unwrap();
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                for handle in handles {
+                    handle.await;
+                }
+                // Tasks whose writes complete while a sync is running share the next one.
+                let num_syncs = coalescer.num_syncs() - 1;
+                assert!((1..8).contains(&num_syncs), "{}", num_syncs);
+
+                let data = crate::fs::read(&path).await.unwrap();
+                assert_eq!(data.as_slice(), (0..32).collect::<Vec<u8>>());
+                std::fs::remove_file(&path).unwrap();
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,194 @@
+//! Following a file while another process appends to it.
+
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use io_uring::{opcode, types::Fd};
+
+use crate::executor::RawIo;
+
+use super::file::File;
+
+impl File {
+    /// Waits until the file is at least `size` bytes long, returns its size at that point.
+    ///
+    /// Changes to the file are watched with inotify, so this doesn't poll the size on a timer.
+    pub async fn wait_for_size(&self, size: u64) -> io::Result<u64> {
+        let watch = SizeWatch::new(self)?;
+        watch.wait_for_size(self, size).await
+    }
+
+    /// Returns a reader that reads the file from `offset` and waits for more data at the end of the file instead of
+    /// returning EOF, like `tail -f`.
+    pub fn tail(&self, offset: u64) -> Tail<'_> {
+        Tail {
+            file: self,
+            offset,
+            watch: None,
+        }
+    }
+}
+
+/// Reader created by [File::tail].
+pub struct Tail<'file> {
+    file: &'file File,
+    offset: u64,
+    // Created on the first EOF and kept for the next ones.
+    watch: Option<SizeWatch>,
+}
+
+impl Tail<'_> {
+    /// Offset of the next read.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Reads into `buf`, waiting until the file grows if the reader is at its end.
+    ///
+    /// If the file is truncated to below the current offset, for example when a log is rotated by copying and
+    /// truncating it, reading continues from the start of the file.
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let n = self.file.read(buf, self.offset).await?;
+            if n > 0 {
+                self.offset += u64::try_from(n).unwrap();
+                return Ok(n);
+            }
+            let watch = match &mut self.watch {
+                Some(watch) => watch,
+                None => self.watch.insert(SizeWatch::new(self.file)?),
+            };
+            let size = watch.wait_for_change(self.file, self.offset).await?;
+            if size < self.offset {
+                self.offset = 0;
+            }
+        }
+    }
+}
+
+// Inotify instance watching a single file.
+struct SizeWatch {
+    inotify: OwnedFd,
+}
+
+impl SizeWatch {
+    fn new(file: &File) -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let inotify = unsafe { OwnedFd::from_raw_fd(fd) };
+        // The fd link in procfs resolves to the open file, so this works for files that were renamed or unlinked.
+        let path = format!("/proc/self/fd/{}\0", file.fd);
+        let res = unsafe {
+            libc::inotify_add_watch(
+                inotify.as_raw_fd(),
+                path.as_ptr() as *const libc::c_char,
+                libc::IN_MODIFY | libc::IN_ATTRIB,
+            )
+        };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { inotify })
+    }
+
+    async fn wait_for_size(&self, file: &File, size: u64) -> io::Result<u64> {
+        loop {
+            // Events that arrive after the size is read stay queued, so the wait below doesn't miss them.
+            let file_size = file.file_size().await?;
+            if file_size >= size {
+                return Ok(file_size);
+            }
+            self.wait_event().await?;
+        }
+    }
+
+    // Waits until the size of the file is different from `size`.
+    async fn wait_for_change(&self, file: &File, size: u64) -> io::Result<u64> {
+        loop {
+            let file_size = file.file_size().await?;
+            if file_size != size {
+                return Ok(file_size);
+            }
+            self.wait_event().await?;
+        }
+    }
+
+    async fn wait_event(&self) -> io::Result<()> {
+        let fd = self.inotify.as_raw_fd();
+        // Poll doesn't point to any memory so it is fine if this future is dropped while it is in flight.
+        let res = unsafe {
+            RawIo::memory_free(opcode::PollAdd::new(Fd(fd), libc::POLLIN as u32).build())
+        }
+        .await;
+        if res < 0 {
+            return Err(io::Error::from_raw_os_error(-res));
+        }
+        // Only the wakeup matters, drain the queued events.
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if n <= 0 {
+                let err = io::Error::last_os_error();
+                if n == 0 || err.kind() == io::ErrorKind::WouldBlock {
+                    return Ok(());
+                }
+                return Err(err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::executor::{spawn, ExecutorConfig};
+    use crate::time::sleep;
+
+    #[test]
+    fn test_tail() {
+        let path = std::env::temp_dir().join(format!("io2_test_tail_{}", std::process::id()));
+        std::fs::write(&path, b"first\n").unwrap();
+        let test_path = path.clone();
+        ExecutorConfig::new()
+            .run(async move {
+                let file = File::open(&test_path, libc::O_RDONLY, 0)
+                    .unwrap()
+                    .await
+                    .unwrap();
+                let writer = spawn({
+                    let path = test_path.clone();
+                    async move {
+                        let file = File::open(&path, libc::O_WRONLY, 0).unwrap().await.unwrap();
+                        sleep(Duration::from_millis(10)).await;
+                        file.write_all(b"second\n", 6).await.unwrap();
+                        sleep(Duration::from_millis(10)).await;
+                        file.write_all(b"third\n", 13).await.unwrap();
+                        sleep(Duration::from_millis(10)).await;
+                        std::fs::write(&path, b"new\n").unwrap();
+                    }
+                });
+
+                assert_eq!(file.wait_for_size(10).await.unwrap(), 13);
+
+                let mut tail = file.tail(0);
+                let mut out = Vec::new();
+                let mut buf = [0; 64];
+                while !out.ends_with(b"new\n") {
+                    let n = tail.read(&mut buf).await.unwrap();
+                    out.extend_from_slice(&buf[..n]);
+                }
+                assert_eq!(out, b"first\nsecond\nthird\nnew\n");
+                assert_eq!(tail.offset(), 4);
+                writer.await;
+            })
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
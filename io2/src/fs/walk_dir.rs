@@ -0,0 +1,319 @@
+//! Recursive directory traversal.
+
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::blocking::run_blocking;
+
+/// What [WalkDir::next] does when a directory can't be listed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalkErrorPolicy {
+    /// Return the error and end the walk.
+    Stop,
+    /// Return the error and continue with the next entry.
+    Report,
+    /// Skip the directory, the error is logged at debug level.
+    Skip,
+}
+
+/// An entry found by [WalkDir].
+#[derive(Debug, Clone)]
+pub struct WalkEntry {
+    path: PathBuf,
+    file_type: std::fs::FileType,
+    depth: usize,
+    is_symlink: bool,
+    // Device and inode of directories, only set when following symlinks.
+    dir_id: Option<(u64, u64)>,
+}
+
+impl WalkEntry {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Type of the entry, this is the type of the target if the entry is a symlink that is followed.
+    pub fn file_type(&self) -> std::fs::FileType {
+        self.file_type
+    }
+
+    /// Number of directories between the root and the entry, entries of the root have depth 1.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Whether the entry itself is a symlink, even if it was followed.
+    pub fn path_is_symlink(&self) -> bool {
+        self.is_symlink
+    }
+}
+
+struct Level {
+    entries: std::vec::IntoIter<WalkEntry>,
+    dir_id: Option<(u64, u64)>,
+}
+
+type Filter = Box<dyn FnMut(&WalkEntry) -> bool>;
+
+/// Walks a directory tree depth first, created with [walk_dir].
+///
+/// Directories are returned before their contents and the order of entries inside a directory is the order the
+/// filesystem lists them in. Symlinks aren't followed by default, with [WalkDir::follow_symlinks] a symlink that
+/// points to one of the directories it is in is returned but not walked again.
+pub struct WalkDir {
+    root: PathBuf,
+    min_depth: usize,
+    max_depth: usize,
+    follow_symlinks: bool,
+    on_error: WalkErrorPolicy,
+    filter: Option<Filter>,
+    started: bool,
+    stack: Vec<Level>,
+    // Directory that was returned last, it is listed when the next entry is requested.
+    pending_dir: Option<WalkEntry>,
+}
+
+/// Returns a walker over the entries under `root`, the root itself isn't returned.
+pub fn walk_dir(root: &Path) -> WalkDir {
+    WalkDir {
+        root: root.to_owned(),
+        min_depth: 1,
+        max_depth: usize::MAX,
+        follow_symlinks: false,
+        on_error: WalkErrorPolicy::Report,
+        filter: None,
+        started: false,
+        stack: Vec::new(),
+        pending_dir: None,
+    }
+}
+
+impl WalkDir {
+    /// Entries above this depth aren't returned, their directories are still walked.
+    pub fn min_depth(mut self, depth: usize) -> Self {
+        self.min_depth = depth;
+        self
+    }
+
+    /// Entries below this depth aren't returned and directories at this depth aren't listed.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Returns the type of the target for symlinks and walks symlinked directories. Symlinks that don't resolve are
+    /// returned as symlinks.
+    pub fn follow_symlinks(mut self, follow: bool) -> Self {
+        self.follow_symlinks = follow;
+        self
+    }
+
+    /// Defaults to [WalkErrorPolicy::Report].
+    pub fn on_error(mut self, policy: WalkErrorPolicy) -> Self {
+        self.on_error = policy;
+        self
+    }
+
+    /// Entries for which `filter` returns false aren't returned, and directories aren't walked.
+    pub fn filter_entry(mut self, filter: impl FnMut(&WalkEntry) -> bool + 'static) -> Self {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Returns the next entry, `None` after the last one.
+    pub async fn next(&mut self) -> Option<io::Result<WalkEntry>> {
+        if !self.started {
+            self.started = true;
+            let root = self.root.clone();
+            if let Err(e) = self.push_dir(&root, 0).await {
+                return self.handle_error(&root, e);
+            }
+        }
+        loop {
+            if let Some(dir) = self.pending_dir.take() {
+                if let Err(e) = self.push_dir(&dir.path, dir.depth).await {
+                    match self.handle_error(&dir.path, e) {
+                        Some(res) => return Some(res),
+                        None => continue,
+                    }
+                }
+            }
+
+            let level = self.stack.last_mut()?;
+            let Some(entry) = level.entries.next() else {
+                self.stack.pop();
+                continue;
+            };
+            if let Some(filter) = &mut self.filter {
+                if !filter(&entry) {
+                    continue;
+                }
+            }
+
+            let walk = entry.file_type.is_dir()
+                && entry.depth < self.max_depth
+                && !self.is_ancestor(entry.dir_id);
+            if walk {
+                self.pending_dir = Some(entry.clone());
+            }
+            if entry.depth >= self.min_depth {
+                return Some(Ok(entry));
+            }
+        }
+    }
+
+    fn is_ancestor(&self, dir_id: Option<(u64, u64)>) -> bool {
+        dir_id.is_some() && self.stack.iter().any(|level| level.dir_id == dir_id)
+    }
+
+    fn handle_error(&mut self, path: &Path, e: io::Error) -> Option<io::Result<WalkEntry>> {
+        match self.on_error {
+            WalkErrorPolicy::Stop => {
+                self.stack.clear();
+                Some(Err(with_path(path, e)))
+            }
+            WalkErrorPolicy::Report => Some(Err(with_path(path, e))),
+            WalkErrorPolicy::Skip => {
+                log::debug!("skipping {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    async fn push_dir(&mut self, path: &Path, depth: usize) -> io::Result<()> {
+        let (dir_id, entries) = list_dir(path, depth + 1, self.follow_symlinks).await?;
+        self.stack.push(Level {
+            entries: entries.into_iter(),
+            dir_id,
+        });
+        Ok(())
+    }
+}
+
+fn with_path(path: &Path, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
+
+// Lists a directory on a blocking thread, like read_dir. Symlinks are resolved on the same thread when following
+// them, and the ids of the directory and its subdirectories are returned for finding loops.
+async fn list_dir(
+    path: &Path,
+    depth: usize,
+    follow_symlinks: bool,
+) -> io::Result<(Option<(u64, u64)>, Vec<WalkEntry>)> {
+    let path = path.to_owned();
+    run_blocking(move || {
+        let dir_id = match follow_symlinks {
+            true => {
+                let md = std::fs::metadata(&path)?;
+                Some((md.dev(), md.ino()))
+            }
+            false => None,
+        };
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(&path)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            let mut walk_entry = WalkEntry {
+                path: entry.path(),
+                file_type,
+                depth,
+                is_symlink: file_type.is_symlink(),
+                dir_id: None,
+            };
+            if follow_symlinks {
+                let md = if file_type.is_symlink() {
+                    std::fs::metadata(&walk_entry.path).ok()
+                } else if file_type.is_dir() {
+                    Some(entry.metadata()?)
+                } else {
+                    None
+                };
+                if let Some(md) = md {
+                    walk_entry.file_type = md.file_type();
+                    if md.is_dir() {
+                        walk_entry.dir_id = Some((md.dev(), md.ino()));
+                    }
+                }
+            }
+            entries.push(walk_entry);
+        }
+        Ok((dir_id, entries))
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::ExecutorConfig;
+
+    async fn collect(mut walk: WalkDir, root: &Path) -> Vec<(String, usize)> {
+        let mut out = Vec::new();
+        while let Some(entry) = walk.next().await {
+            let entry = entry.unwrap();
+            let rel = entry.path().strip_prefix(root).unwrap();
+            out.push((rel.to_str().unwrap().to_owned(), entry.depth()));
+        }
+        out.sort();
+        out
+    }
+
+    #[test]
+    fn test_walk_dir() {
+        let root = std::env::temp_dir().join(format!("io2_test_walk_dir_{}", std::process::id()));
+        std::fs::create_dir_all(root.join("a/b/c")).unwrap();
+        std::fs::create_dir_all(root.join("skip")).unwrap();
+        std::fs::write(root.join("a/f1"), b"1").unwrap();
+        std::fs::write(root.join("a/b/c/f2"), b"2").unwrap();
+        std::fs::write(root.join("skip/f3"), b"3").unwrap();
+        // Points to an ancestor, following it would loop forever.
+        std::os::unix::fs::symlink(root.join("a"), root.join("a/b/up")).unwrap();
+
+        let test_root = root.clone();
+        ExecutorConfig::new()
+            .run(async move {
+                let root = &test_root;
+                let all = collect(walk_dir(root), root).await;
+                assert_eq!(all.len(), 8);
+                assert!(all.contains(&("a/b/c/f2".to_owned(), 4)));
+
+                let filtered = collect(
+                    walk_dir(root)
+                        .min_depth(2)
+                        .max_depth(3)
+                        .filter_entry(|e| !e.path().ends_with("skip")),
+                    root,
+                )
+                .await;
+                let names = filtered.iter().map(|(p, _)| p.as_str()).collect::<Vec<_>>();
+                assert_eq!(names, ["a/b", "a/b/c", "a/b/up", "a/f1"]);
+
+                let mut walk = walk_dir(root).follow_symlinks(true);
+                let mut up = None;
+                let mut count = 0;
+                while let Some(entry) = walk.next().await {
+                    let entry = entry.unwrap();
+                    if entry.path_is_symlink() {
+                        up = Some(entry.clone());
+                    }
+                    count += 1;
+                }
+                let up = up.unwrap();
+                assert!(up.file_type().is_dir());
+                assert_eq!(count, 8);
+
+                let mut walk = walk_dir(&root.join("missing"));
+                assert_eq!(
+                    walk.next().await.unwrap().unwrap_err().kind(),
+                    io::ErrorKind::NotFound
+                );
+                assert!(walk.next().await.is_none());
+                let mut walk = walk_dir(&root.join("missing")).on_error(WalkErrorPolicy::Skip);
+                assert!(walk.next().await.is_none());
+            })
+            .unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
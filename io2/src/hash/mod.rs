@@ -0,0 +1,207 @@
+//! Checksums and digests computed while data flows through a stream.
+//!
+//! [HashingReader] and [HashingWriter] wrap a stream and feed every byte that passes through them to a [Hasher], so
+//! the two ends of a transfer can compare digests for end-to-end integrity verification. Hashing is done in pieces
+//! with yield points in between so large buffers don't block other tasks.
+
+mod sha256;
+mod xxhash;
+
+pub use sha256::{sha256, Sha256};
+pub use xxhash::XxHash64;
+
+use std::io;
+
+use crate::block::crc32c_append;
+use crate::executor::YieldIfNeeded;
+use crate::net::{self, socket, StreamSocket};
+
+// Amount of data hashed between yield points.
+const HASH_CHUNK_SIZE: usize = 256 * 1024;
+
+/// A rolling checksum or digest.
+pub trait Hasher {
+    type Output;
+
+    fn update(&mut self, data: &[u8]);
+
+    /// Returns the digest of the data so far, more data can be added after this.
+    fn finish(&self) -> Self::Output;
+}
+
+/// CRC-32C (Castagnoli), hardware accelerated where available.
+#[derive(Clone, Default)]
+pub struct Crc32c {
+    crc: u32,
+}
+
+impl Crc32c {
+    pub fn new() -> Self {
+        Self { crc: 0 }
+    }
+}
+
+impl Hasher for Crc32c {
+    type Output = u32;
+
+    fn update(&mut self, data: &[u8]) {
+        self.crc = crc32c_append(self.crc, data);
+    }
+
+    fn finish(&self) -> u32 {
+        self.crc
+    }
+}
+
+async fn update_yielding<H: Hasher>(hasher: &mut H, data: &[u8]) {
+    for chunk in data.chunks(HASH_CHUNK_SIZE) {
+        hasher.update(chunk);
+        YieldIfNeeded.await;
+    }
+}
+
+/// Hashes all data that is read from the stream.
+pub struct HashingReader<S: StreamSocket, H: Hasher> {
+    stream: S,
+    hasher: H,
+}
+
+impl<S: StreamSocket, H: Hasher> HashingReader<S, H> {
+    pub fn new(stream: S, hasher: H) -> Self {
+        Self { stream, hasher }
+    }
+
+    /// Reads into `buf` and hashes the bytes that were read, returns zero at end of stream.
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = socket::Read::new(net::stream_fd(&self.stream), buf)
+            .deadlines(net::stream_deadlines(&self.stream))
+            .await?;
+        update_yielding(&mut self.hasher, &buf[..n]).await;
+        Ok(n)
+    }
+
+    /// Fills `buf` completely, fails with `UnexpectedEof` if the stream ends before that.
+    pub async fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let mut pos = 0;
+        while pos < buf.len() {
+            match self.read(&mut buf[pos..]).await {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                Ok(n) => pos += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn hasher(&self) -> &H {
+        &self.hasher
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn into_parts(self) -> (S, H) {
+        (self.stream, self.hasher)
+    }
+}
+
+/// Hashes all data that is written to the stream.
+pub struct HashingWriter<S: StreamSocket, H: Hasher> {
+    stream: S,
+    hasher: H,
+}
+
+impl<S: StreamSocket, H: Hasher> HashingWriter<S, H> {
+    pub fn new(stream: S, hasher: H) -> Self {
+        Self { stream, hasher }
+    }
+
+    /// Writes all of `buf`, it is hashed before it is written so it counts towards the digest even if the write fails.
+    pub async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        update_yielding(&mut self.hasher, buf).await;
+        socket::write_all(
+            net::stream_fd(&self.stream),
+            Some(net::stream_deadlines(&self.stream)),
+            buf,
+        )
+        .await
+    }
+
+    pub fn hasher(&self) -> &H {
+        &self.hasher
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn into_parts(self) -> (S, H) {
+        (self.stream, self.hasher)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::{spawn, ExecutorConfig};
+    use crate::net::unix::UnixStream;
+
+    fn digest<H: Hasher>(mut hasher: H, data: &[u8]) -> H::Output {
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    #[test]
+    fn test_hashers() {
+        assert_eq!(digest(XxHash64::new(0), b""), 0xEF46DB3751D8E999);
+        assert_eq!(digest(XxHash64::new(0), b"abc"), 0x44BC2CF5AD770999);
+        assert_eq!(
+            digest(XxHash64::new(0), b"Nobody inspects the spammish repetition"),
+            0xFBCEA83C8A378BF1
+        );
+
+        // Updating in pieces gives the same result as hashing at once.
+        let data = (0..1000u32)
+            .map(|x| (x * 7 + x / 13) as u8)
+            .collect::<Vec<u8>>();
+        let mut xx = XxHash64::new(123);
+        let mut sha = Sha256::new();
+        let mut crc = Crc32c::new();
+        for piece in data.chunks(37) {
+            xx.update(piece);
+            sha.update(piece);
+            crc.update(piece);
+        }
+        assert_eq!(xx.finish(), digest(XxHash64::new(123), &data));
+        assert_eq!(sha.finish(), sha256(&data));
+        assert_eq!(crc.finish(), crate::block::crc32c(&data));
+    }
+
+    #[test]
+    fn test_hashing_stream() {
+        ExecutorConfig::new()
+            .run(async {
+                let (a, b) = UnixStream::pair().unwrap();
+                let data = vec![7u8; 1 << 20];
+
+                let send = data.clone();
+                let writer = spawn(async move {
+                    let mut writer = HashingWriter::new(a, Crc32c::new());
+                    for chunk in send.chunks(100_000) {
+                        writer.write_all(chunk).await.unwrap();
+                    }
+                    writer.hasher().finish()
+                });
+
+                let mut reader = HashingReader::new(b, Crc32c::new());
+                let mut buf = vec![0; data.len()];
+                reader.read_exact(&mut buf).await.unwrap();
+                assert_eq!(buf, data);
+                assert_eq!(writer.await, reader.hasher().finish());
+                assert_eq!(reader.hasher().finish(), crate::block::crc32c(&data));
+            })
+            .unwrap();
+    }
+}
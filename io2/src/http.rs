@@ -0,0 +1,729 @@
+//! A minimal HTTP/1.1 client, for calling webhooks and object storage APIs.
+//!
+//! [Client] keeps a [Pool] of keep-alive connections per host. Response bodies are streamed with [Response::chunk], a
+//! connection goes back to the pool once the body of its response is read to the end.
+//!
+//! The crate has no TLS implementation, `https` urls need a handshake function set with [Client::tls_handshake]. It
+//! runs the handshake with a TLS library (e.g. rustls) and returns the negotiated keys, which are handed to the kernel
+//! with [kTLS](crate::net::ktls) so the connection is read and written like a plain one afterwards.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::rc::Rc;
+
+use crate::local_alloc::LocalAlloc;
+use crate::net::ktls::KtlsKeys;
+use crate::net::lookup_host;
+use crate::net::pool::{Pool, PoolConfig, PooledConn};
+use crate::net::socket;
+use crate::net::tcp::TcpStream;
+
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+const MIN_READ_SIZE: usize = 16 * 1024;
+
+// Connection pools by whether the connection uses TLS, host and port.
+type Pools = Rc<RefCell<HashMap<(bool, String, u16), Pool<Conn>>>>;
+// Runs the TLS handshake over a connected stream for the given server name, returns the keys for sending and receiving.
+type TlsHandshake = Rc<
+    dyn for<'a> Fn(
+        &'a TcpStream,
+        &'a str,
+    ) -> Pin<Box<dyn Future<Output = io::Result<(KtlsKeys, KtlsKeys)>> + 'a>>,
+>;
+
+/// An HTTP/1.1 client, cloning it gives another handle to the same connection pools.
+#[derive(Clone)]
+pub struct Client {
+    pools: Pools,
+    pool_config: PoolConfig,
+    tls_handshake: Option<TlsHandshake>,
+}
+
+impl Client {
+    pub fn new() -> Self {
+        Self::with_pool_config(PoolConfig::new())
+    }
+
+    /// Creates a client with the given configuration for the connection pool of each host.
+    pub fn with_pool_config(pool_config: PoolConfig) -> Self {
+        Self {
+            pools: Rc::new(RefCell::new(HashMap::new())),
+            pool_config,
+            tls_handshake: None,
+        }
+    }
+
+    /// Enables `https` urls. `handshake` is called with each new connection and the host name of the url, it runs the
+    /// TLS handshake over the stream and returns the negotiated keys for sending and receiving. kTLS is enabled on the
+    /// connection with these keys, see [TcpStream::enable_ktls].
+    ///
+    /// Records other than application data can't be received after the handshake, so the handshake has to read
+    /// post-handshake messages the server sends right away (e.g. TLS 1.3 session tickets) before returning.
+    pub fn tls_handshake<F>(mut self, handshake: F) -> Self
+    where
+        F: for<'a> Fn(
+                &'a TcpStream,
+                &'a str,
+            )
+                -> Pin<Box<dyn Future<Output = io::Result<(KtlsKeys, KtlsKeys)>> + 'a>>
+            + 'static,
+    {
+        self.tls_handshake = Some(Rc::new(handshake));
+        self
+    }
+
+    /// Sends the request and returns the response once its head is received, the body is read from the response.
+    ///
+    /// Idempotent requests are retried once on a new connection if a pooled connection turns out to be closed by the
+    /// server.
+    pub async fn request(&self, req: &Request<'_>) -> io::Result<Response> {
+        let url = Url::parse(req.url)?;
+        let head = req.head(&url)?;
+        let pool = self.pool(&url)?;
+        let is_head = req.method.eq_ignore_ascii_case("HEAD");
+
+        let mut retried = false;
+        loop {
+            let mut conn = pool.acquire().await?;
+            let reused = conn.reused;
+            let res = async {
+                conn.send(&head, req.body).await?;
+                conn.read_head().await
+            }
+            .await;
+            match res {
+                Ok(head) => return Ok(Response::new(head, conn, is_head)),
+                Err(e) if reused && !retried && req.is_idempotent() && is_stale(&e) => {
+                    conn.discard();
+                    retried = true;
+                }
+                Err(e) => {
+                    conn.discard();
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Sends a GET request and reads the whole response body.
+    pub async fn get(&self, url: &str) -> io::Result<(Response, Vec<u8>)> {
+        let mut res = self.request(&Request::new("GET", url)).await?;
+        let body = res.bytes().await?;
+        Ok((res, body))
+    }
+
+    fn pool(&self, url: &Url) -> io::Result<Pool<Conn>> {
+        let tls = match (url.https, &self.tls_handshake) {
+            (false, _) => None,
+            (true, Some(handshake)) => Some(handshake.clone()),
+            (true, None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "https needs a TLS handshake, see Client::tls_handshake",
+                ))
+            }
+        };
+        let mut pools = self.pools.borrow_mut();
+        let pool = pools
+            .entry((url.https, url.host.to_owned(), url.port))
+            .or_insert_with(|| {
+                let (host, port) = (url.host.to_owned(), url.port);
+                Pool::new(self.pool_config.clone(), move || {
+                    let host = host.clone();
+                    let tls = tls.clone();
+                    async move { Conn::connect(&host, port, tls).await }
+                })
+            })
+            .clone();
+        Ok(pool)
+    }
+}
+
+/// A request to send with [Client::request].
+pub struct Request<'a> {
+    method: &'a str,
+    url: &'a str,
+    headers: Vec<(&'a str, &'a str)>,
+    body: &'a [u8],
+}
+
+impl<'a> Request<'a> {
+    pub fn new(method: &'a str, url: &'a str) -> Self {
+        Self {
+            method,
+            url,
+            headers: Vec::new(),
+            body: &[],
+        }
+    }
+
+    /// Adds a header, `Host` and `Content-Length` are set by the client.
+    ///
+    /// The request fails with `InvalidInput` if the name or the value contains a CR or LF, or the name is empty or
+    /// contains a colon, so headers can't be injected into the request.
+    pub fn header(mut self, name: &'a str, value: &'a str) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    pub fn body(mut self, body: &'a [u8]) -> Self {
+        self.body = body;
+        self
+    }
+
+    fn is_idempotent(&self) -> bool {
+        ["GET", "HEAD", "PUT", "DELETE", "OPTIONS"]
+            .iter()
+            .any(|method| self.method.eq_ignore_ascii_case(method))
+    }
+
+    fn head(&self, url: &Url) -> io::Result<Vec<u8>> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        if self.method.is_empty()
+            || !self
+                .method
+                .bytes()
+                .all(|b| b.is_ascii_graphic() && b != b':')
+        {
+            return Err(invalid("invalid request method"));
+        }
+        for (name, value) in self.headers.iter() {
+            if name.is_empty() || name.contains([':', '\r', '\n']) || value.contains(['\r', '\n']) {
+                return Err(invalid(
+                    "header names and values can't contain CR or LF, names can't contain a colon",
+                ));
+            }
+        }
+
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nHost: {}",
+            self.method, url.path, url.host
+        );
+        if url.port != url.default_port() {
+            head.push_str(&format!(":{}", url.port));
+        }
+        head.push_str("\r\n");
+        let bodyless = ["GET", "HEAD"]
+            .iter()
+            .any(|method| self.method.eq_ignore_ascii_case(method));
+        if !self.body.is_empty() || !bodyless {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        for (name, value) in self.headers.iter() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        Ok(head.into_bytes())
+    }
+}
+
+struct Url<'a> {
+    https: bool,
+    host: &'a str,
+    port: u16,
+    path: &'a str,
+}
+
+impl<'a> Url<'a> {
+    fn parse(url: &'a str) -> io::Result<Self> {
+        let invalid =
+            |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", msg, url));
+        // The url goes into the request line as it is.
+        if url.bytes().any(|b| !b.is_ascii_graphic()) {
+            return Err(invalid(
+                "url can't contain whitespace or control characters",
+            ));
+        }
+        let (https, rest) = match url.split_once("://") {
+            Some(("http", rest)) => (false, rest),
+            Some(("https", rest)) => (true, rest),
+            _ => return Err(invalid("url must start with http:// or https://")),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        // The port separator of an IPv6 literal comes after the closing bracket.
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (
+                host,
+                port.parse().map_err(|_| invalid("invalid port in url"))?,
+            ),
+            _ => (authority, if https { 443 } else { 80 }),
+        };
+        if host.is_empty() {
+            return Err(invalid("url has no host"));
+        }
+        Ok(Self {
+            https,
+            host,
+            port,
+            path,
+        })
+    }
+
+    fn default_port(&self) -> u16 {
+        if self.https {
+            443
+        } else {
+            80
+        }
+    }
+}
+
+fn is_stale(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset | io::ErrorKind::BrokenPipe
+    )
+}
+
+struct Head {
+    status: u16,
+    headers: Vec<(String, String)>,
+    keep_alive: bool,
+}
+
+/// A pooled connection and the data that was read from it but not consumed yet.
+struct Conn {
+    stream: TcpStream,
+    buf: Vec<u8, LocalAlloc>,
+    start: usize,
+    end: usize,
+    // Whether a response was already read from this connection, so it came from the pool.
+    reused: bool,
+}
+
+impl Conn {
+    async fn connect(host: &str, port: u16, tls: Option<TlsHandshake>) -> io::Result<Self> {
+        let addrs = lookup_host(host, port).await?;
+        let stream = TcpStream::connect_addrs(&addrs).await?;
+        stream.set_nodelay(true)?;
+        if let Some(handshake) = tls {
+            let (tx, rx) = handshake(&stream, host).await?;
+            stream.enable_ktls(Some(&tx), Some(&rx))?;
+        }
+        Ok(Self {
+            stream,
+            buf: Vec::new_in(LocalAlloc::new()),
+            start: 0,
+            end: 0,
+            reused: false,
+        })
+    }
+
+    async fn send(&mut self, head: &[u8], body: &[u8]) -> io::Result<()> {
+        self.start = 0;
+        self.end = 0;
+        // Small bodies are copied after the head so the request is sent with a single write.
+        if body.len() <= MIN_READ_SIZE {
+            let mut request = Vec::with_capacity_in(head.len() + body.len(), LocalAlloc::new());
+            request.extend_from_slice(head);
+            request.extend_from_slice(body);
+            return socket::write_all(self.stream.fd, Some(&self.stream.deadlines), &request).await;
+        }
+        socket::write_all(self.stream.fd, Some(&self.stream.deadlines), head).await?;
+        socket::write_all(self.stream.fd, Some(&self.stream.deadlines), body).await
+    }
+
+    /// Reads more data into the buffer, returns the number of bytes read which is zero at EOF.
+    async fn fill(&mut self) -> io::Result<usize> {
+        self.buf.copy_within(self.start..self.end, 0);
+        self.end -= self.start;
+        self.start = 0;
+        let len = self.buf.len().max(self.end + MIN_READ_SIZE);
+        self.buf.resize(len, 0);
+        loop {
+            match socket::Read::new(self.stream.fd, &mut self.buf[self.end..])
+                .deadlines(&self.stream.deadlines)
+                .await
+            {
+                Ok(n) => {
+                    self.end += n;
+                    return Ok(n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Reads a line ending with CRLF and consumes it, the returned line doesn't include the CRLF.
+    async fn read_line(&mut self) -> io::Result<String> {
+        loop {
+            let available = &self.buf[self.start..self.end];
+            if let Some(pos) = available.windows(2).position(|w| w == b"\r\n") {
+                let line = String::from_utf8(available[..pos].to_vec())
+                    .map_err(|_| invalid("response head is not valid UTF-8"))?;
+                self.start += pos + 2;
+                return Ok(line);
+            }
+            if available.len() >= MAX_HEAD_SIZE {
+                return Err(invalid("response head is too large"));
+            }
+            if self.fill().await? == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+        }
+    }
+
+    async fn read_head(&mut self) -> io::Result<Head> {
+        loop {
+            let status_line = self.read_line().await?;
+            let mut parts = status_line.splitn(3, ' ');
+            let version = parts.next().unwrap();
+            let status: u16 = parts
+                .next()
+                .and_then(|status| status.parse().ok())
+                .ok_or_else(|| invalid("malformed status line"))?;
+            if !version.starts_with("HTTP/1.") {
+                return Err(invalid("unsupported HTTP version"));
+            }
+
+            let mut headers = Vec::new();
+            loop {
+                let line = self.read_line().await?;
+                if line.is_empty() {
+                    break;
+                }
+                let (name, value) = line
+                    .split_once(':')
+                    .ok_or_else(|| invalid("malformed header"))?;
+                headers.push((name.trim().to_owned(), value.trim().to_owned()));
+            }
+
+            // Informational responses like 100 Continue are followed by the actual response.
+            if (100..200).contains(&status) {
+                continue;
+            }
+
+            let close = headers.iter().any(|(name, value)| {
+                name.eq_ignore_ascii_case("connection") && value.eq_ignore_ascii_case("close")
+            });
+            return Ok(Head {
+                status,
+                headers,
+                keep_alive: version == "HTTP/1.1" && !close,
+            });
+        }
+    }
+}
+
+enum Body {
+    Length(u64),
+    /// Remaining bytes of the current chunk, `None` before the size line of the next chunk.
+    Chunked(Option<u64>),
+    /// The body ends when the server closes the connection.
+    Eof,
+    Done,
+}
+
+/// A response whose body is read with [Response::chunk] or [Response::bytes].
+///
+/// The connection is closed instead of being returned to the pool if this is dropped before the body is read to the
+/// end.
+pub struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    keep_alive: bool,
+    body: Body,
+    conn: Option<PooledConn<Conn>>,
+    // Length of the body data that was lent out by the last call to chunk, it is consumed on the next call.
+    lent: usize,
+}
+
+impl Response {
+    fn new(head: Head, conn: PooledConn<Conn>, is_head: bool) -> Self {
+        let header = |name: &str| {
+            head.headers
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        };
+        let no_body = is_head || head.status == 204 || head.status == 304;
+        let mut keep_alive = head.keep_alive;
+        let body = if no_body {
+            Body::Done
+        } else if header("transfer-encoding")
+            .is_some_and(|te| te.to_ascii_lowercase().contains("chunked"))
+        {
+            Body::Chunked(None)
+        } else if let Some(len) = header("content-length").and_then(|len| len.parse().ok()) {
+            Body::Length(len)
+        } else {
+            keep_alive = false;
+            Body::Eof
+        };
+        let mut res = Self {
+            status: head.status,
+            headers: head.headers,
+            keep_alive,
+            body,
+            conn: Some(conn),
+            lent: 0,
+        };
+        if let Body::Done = res.body {
+            res.finish();
+        }
+        res
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// Returns the value of the first header with the given name, compared case insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// Returns the next piece of the body, `None` means the body was read to the end.
+    pub async fn chunk(&mut self) -> io::Result<Option<&[u8]>> {
+        let conn = match self.conn.as_mut() {
+            Some(conn) => conn,
+            None => return Ok(None),
+        };
+        conn.start += std::mem::take(&mut self.lent);
+
+        let lend = loop {
+            let available = conn.end - conn.start;
+            match self.body {
+                Body::Done => break None,
+                Body::Length(0) => {
+                    self.body = Body::Done;
+                    continue;
+                }
+                Body::Chunked(Some(0)) => {
+                    if available < 2 {
+                        if conn.fill().await? == 0 {
+                            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                        }
+                        continue;
+                    }
+                    if &conn.buf[conn.start..conn.start + 2] != b"\r\n" {
+                        return Err(invalid("chunk is not followed by CRLF"));
+                    }
+                    conn.start += 2;
+                    self.body = Body::Chunked(None);
+                    continue;
+                }
+                Body::Chunked(None) => {
+                    let line = conn.read_line().await?;
+                    let size = line.split(';').next().unwrap().trim();
+                    let size = u64::from_str_radix(size, 16)
+                        .map_err(|_| invalid("malformed chunk size"))?;
+                    if size > 0 {
+                        self.body = Body::Chunked(Some(size));
+                        continue;
+                    }
+                    // Skip the trailers.
+                    while !conn.read_line().await?.is_empty() {}
+                    self.body = Body::Done;
+                    continue;
+                }
+                Body::Length(remaining) | Body::Chunked(Some(remaining)) if available > 0 => {
+                    let n =
+                        usize::try_from(remaining.min(u64::try_from(available).unwrap())).unwrap();
+                    let left = remaining - u64::try_from(n).unwrap();
+                    self.body = match self.body {
+                        Body::Length(_) => Body::Length(left),
+                        _ => Body::Chunked(Some(left)),
+                    };
+                    self.lent = n;
+                    break Some(conn.start..conn.start + n);
+                }
+                Body::Eof if available > 0 => {
+                    self.lent = available;
+                    break Some(conn.start..conn.end);
+                }
+                Body::Length(_) | Body::Chunked(Some(_)) | Body::Eof => {}
+            }
+
+            if conn.fill().await? == 0 {
+                match self.body {
+                    Body::Eof => self.body = Body::Done,
+                    _ => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                }
+            }
+        };
+
+        match lend {
+            Some(range) => Ok(Some(&self.conn.as_ref().unwrap().buf[range])),
+            None => {
+                self.finish();
+                Ok(None)
+            }
+        }
+    }
+
+    /// Reads the rest of the body.
+    pub async fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        while let Some(chunk) = self.chunk().await? {
+            body.extend_from_slice(chunk);
+        }
+        Ok(body)
+    }
+
+    /// Returns the connection to the pool if it can be reused, otherwise closes it.
+    fn finish(&mut self) {
+        if let Some(mut conn) = self.conn.take() {
+            if self.keep_alive {
+                conn.reused = true;
+            } else {
+                conn.discard();
+            }
+        }
+    }
+}
+
+impl Drop for Response {
+    fn drop(&mut self) {
+        // The rest of the body is still in the connection so it can't be reused.
+        if let Some(conn) = self.conn.take() {
+            conn.discard();
+        }
+    }
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::executor::{spawn, ExecutorConfig};
+    use crate::net::tcp::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn test_client() {
+        ExecutorConfig::new()
+            .run(async {
+                let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+                let port = listener.local_addr().unwrap().port();
+
+                let server = spawn(async move {
+                    // Both requests come over the same connection.
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let mut received = Vec::new();
+                    let mut buf = [0; 4096];
+                    let responses: [&[u8]; 2] = [
+                        b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello",
+                        b"HTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\nX-Id: 7\r\n\r\n3;ext\r\nabc\r\n2\r\nde\r\n0\r\n\r\n",
+                    ];
+                    for response in responses {
+                        loop {
+                            let n = stream.read(&mut buf).await.unwrap();
+                            received.extend_from_slice(&buf[..n]);
+                            let text = String::from_utf8_lossy(&received);
+                            if text.ends_with("\r\n\r\n") || text.ends_with("body") {
+                                break;
+                            }
+                        }
+                        stream.write_all(response).await.unwrap();
+                    }
+                    String::from_utf8(received).unwrap()
+                });
+
+                let client = Client::new();
+                let url = format!("http://127.0.0.1:{}/a?b=c", port);
+                let (res, body) = client.get(&url).await.unwrap();
+                assert_eq!(res.status(), 200);
+                assert_eq!(body, b"hello");
+
+                let url = format!("http://127.0.0.1:{}/upload", port);
+                let req = Request::new("POST", &url)
+                    .header("Content-Type", "text/plain")
+                    .body(b"body");
+                let mut res = client.request(&req).await.unwrap();
+                assert_eq!(res.status(), 201);
+                assert_eq!(res.header("x-id"), Some("7"));
+                assert_eq!(res.bytes().await.unwrap(), b"abcde");
+
+                let received = server.await;
+                assert!(received.starts_with(&format!(
+                    "GET /a?b=c HTTP/1.1\r\nHost: 127.0.0.1:{}\r\n\r\nPOST /upload HTTP/1.1\r\n",
+                    port
+                )));
+                assert!(received.ends_with("Content-Length: 4\r\nContent-Type: text/plain\r\n\r\nbody"));
+
+                let err = client
+                    .get("https://example.com/")
+                    .await
+                    .map(|_| ())
+                    .unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_tls_handshake() {
+        ExecutorConfig::new()
+            .run(async {
+                let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+                let port = listener.local_addr().unwrap().port();
+                let server = spawn(async move { listener.accept().await.unwrap() });
+
+                // The handshake runs over the new connection with the host of the url, its error fails the request.
+                let server_names = Rc::new(RefCell::new(Vec::new()));
+                let names = server_names.clone();
+                let client = Client::new().tls_handshake(move |_stream, server_name| {
+                    names.borrow_mut().push(server_name.to_owned());
+                    Box::pin(async {
+                        Err(io::Error::new(
+                            io::ErrorKind::PermissionDenied,
+                            "bad certificate",
+                        ))
+                    })
+                });
+                let err = client
+                    .get(&format!("https://127.0.0.1:{}/", port))
+                    .await
+                    .map(|_| ())
+                    .unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+                assert_eq!(*server_names.borrow(), ["127.0.0.1"]);
+                server.await;
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_request_head() {
+        let url = Url::parse("https://example.com/a").unwrap();
+        assert_eq!(url.port, 443);
+        // The method is compared case insensitively and the default port isn't in the Host header.
+        let head = Request::new("get", "").head(&url).unwrap();
+        assert_eq!(head, b"get /a HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        let head = Request::new("put", "").head(&url).unwrap();
+        assert_eq!(
+            head,
+            b"put /a HTTP/1.1\r\nHost: example.com\r\nContent-Length: 0\r\n\r\n"
+        );
+
+        for req in [
+            Request::new("GET", "").header("X-A", "b\r\nX-Injected: c"),
+            Request::new("GET", "").header("X-A\n", "b"),
+            Request::new("GET", "").header("X-A: b", "c"),
+            Request::new("GET /x HTTP/1.1\r\n", ""),
+        ] {
+            let err = req.head(&url).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        let err = Url::parse("http://example.com/a b").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,154 @@
+//! Live runtime state over a Unix socket, for debugging stuck processes.
+//!
+//! [serve] is spawned as a task of the executor that should be inspected. Every connection to the socket gets a JSON
+//! document describing the executor and is then closed, so it can be read with e.g. `socat - UNIX-CONNECT:<path>`:
+//!
+//! ```text
+//! {"tasks":[{"id":4294967298,"pending_io":1,"timers":0}],"num_dio_running":0,
+//!  "collections":[{"name":"tasks","len":3,"capacity":128,"high_water_mark":5},...],
+//!  "alloc":{"num_pages":1,"reserved_bytes":2097152,"free_bytes":2080768}}
+//! ```
+
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::executor::{snapshot, spawn, Snapshot};
+use crate::local_alloc::{self, Stats};
+use crate::net::unix::UnixListener;
+
+/// Time a client has to read the response before the connection is dropped.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves snapshots of the current executor on a Unix socket at `path`.
+///
+/// This runs until accepting a connection fails, errors of individual connections are ignored. Each response is
+/// written by its own task so a client that doesn't read can't hold up the others.
+pub async fn serve(path: &Path) -> io::Result<()> {
+    let listener = UnixListener::bind(path)?;
+    loop {
+        let stream = listener.accept().await?;
+        let json = to_json(&snapshot(), &local_alloc::stats());
+        spawn(async move {
+            stream.set_write_deadline(Some(WRITE_TIMEOUT));
+            if let Err(e) = stream.write_all(json.as_bytes()).await {
+                log::debug!("failed to write inspector response: {}", e);
+            }
+        });
+    }
+}
+
+fn to_json(snapshot: &Snapshot, alloc: &Stats) -> String {
+    let mut out = String::from("{\"tasks\":[");
+    for (i, task) in snapshot.tasks.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write!(
+            out,
+            "{{\"id\":{},\"pending_io\":{},\"timers\":{}}}",
+            task.id, task.pending_io, task.timers
+        )
+        .unwrap();
+    }
+    write!(
+        out,
+        "],\"num_dio_running\":{},\"collections\":[",
+        snapshot.num_dio_running
+    )
+    .unwrap();
+    for (i, c) in snapshot.collections.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write!(
+            out,
+            "{{\"name\":\"{}\",\"len\":{},\"capacity\":{},\"high_water_mark\":{}}}",
+            c.name, c.len, c.capacity, c.high_water_mark
+        )
+        .unwrap();
+    }
+    writeln!(
+        out,
+        "],\"alloc\":{{\"num_pages\":{},\"reserved_bytes\":{},\"free_bytes\":{}}}}}",
+        alloc.num_pages, alloc.reserved_bytes, alloc.free_bytes
+    )
+    .unwrap();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::executor::ExecutorConfig;
+    use crate::net::unix::UnixStream;
+    use crate::time::sleep;
+
+    use super::*;
+
+    #[test]
+    fn test_inspector() {
+        let path = std::env::temp_dir().join(format!("io2_test_inspector_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let test_path = path.clone();
+        ExecutorConfig::new()
+            .run(async move {
+                let server_path = test_path.clone();
+                spawn(async move { serve(&server_path).await.unwrap() });
+                spawn(sleep(Duration::from_secs(10)));
+                sleep(Duration::from_millis(1)).await;
+
+                let response = read_response(&test_path).await;
+                assert!(response.starts_with("{\"tasks\":[{\"id\":"));
+                // The sleeping task.
+                assert!(response.contains("\"pending_io\":0,\"timers\":1}"));
+                assert!(response.contains("\"collections\":[{\"name\":\"tasks\","));
+                assert!(response.contains("\"alloc\":{\"num_pages\":"));
+            })
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_inspector_client_not_reading() {
+        let path = std::env::temp_dir().join(format!(
+            "io2_test_inspector_not_reading_{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let test_path = path.clone();
+        ExecutorConfig::new()
+            .run(async move {
+                let server_path = test_path.clone();
+                spawn(async move { serve(&server_path).await.unwrap() });
+                // Enough tasks to make the response bigger than the socket buffer.
+                for _ in 0..6000 {
+                    spawn(sleep(Duration::from_secs(10)));
+                }
+                sleep(Duration::from_millis(1)).await;
+
+                let _stuck = UnixStream::connect(&test_path).await.unwrap();
+                sleep(Duration::from_millis(10)).await;
+                let start = std::time::Instant::now();
+                let response = read_response(&test_path).await;
+                assert!(response.len() > 128 * 1024);
+                assert!(start.elapsed() < WRITE_TIMEOUT);
+            })
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    async fn read_response(path: &Path) -> String {
+        let stream = UnixStream::connect(path).await.unwrap();
+        let mut response = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            response.extend_from_slice(&buf[..n]);
+        }
+        String::from_utf8(response).unwrap()
+    }
+}
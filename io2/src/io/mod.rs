@@ -0,0 +1,7 @@
+pub mod pipe_buffer;
+pub mod poll;
+pub mod stdio;
+
+pub use pipe_buffer::{pipe_buffer, PipeReader, PipeWriter};
+pub use poll::{poll_stream, PollStream};
+pub use stdio::{stderr, stdin, stdout, Stderr, Stdin, Stdout};
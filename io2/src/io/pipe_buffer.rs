@@ -0,0 +1,238 @@
+//! In-memory byte pipe between two tasks.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::{self, Read as _};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use crate::executor;
+use crate::local_alloc::LocalAlloc;
+use crate::slab;
+
+struct State {
+    buf: VecDeque<u8, LocalAlloc>,
+    capacity: usize,
+    reader_waiting: Option<slab::Key>,
+    writer_waiting: Option<slab::Key>,
+    reader_closed: bool,
+    writer_closed: bool,
+}
+
+impl State {
+    fn wake_reader(&mut self) {
+        if let Some(task_id) = self.reader_waiting.take() {
+            executor::notify_task(task_id);
+        }
+    }
+
+    fn wake_writer(&mut self) {
+        if let Some(task_id) = self.writer_waiting.take() {
+            executor::notify_task(task_id);
+        }
+    }
+}
+
+type Shared = Rc<RefCell<State>, LocalAlloc>;
+
+/// Creates a pipe that buffers up to `capacity` bytes between a writing task and a reading task.
+///
+/// Writes wait while the buffer is full, so a fast producer can't run ahead of the consumer by more than `capacity`
+/// bytes. This is for splitting processing stages, e.g. decompressing and parsing, into separate tasks without the
+/// syscalls of an OS pipe.
+pub fn pipe_buffer(capacity: usize) -> (PipeWriter, PipeReader) {
+    assert!(capacity > 0, "capacity has to be positive");
+    let shared = Rc::new_in(
+        RefCell::new(State {
+            buf: VecDeque::with_capacity_in(capacity, LocalAlloc::new()),
+            capacity,
+            reader_waiting: None,
+            writer_waiting: None,
+            reader_closed: false,
+            writer_closed: false,
+        }),
+        LocalAlloc::new(),
+    );
+    (
+        PipeWriter {
+            shared: shared.clone(),
+        },
+        PipeReader { shared },
+    )
+}
+
+/// Writing half of a [pipe_buffer], dropping it ends the stream for the reader.
+pub struct PipeWriter {
+    shared: Shared,
+}
+
+impl PipeWriter {
+    /// Writes as much of `buf` as fits, waiting until there is space in the pipe.
+    ///
+    /// Fails with `BrokenPipe` if the reader was dropped.
+    pub async fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            {
+                let mut state = self.shared.borrow_mut();
+                if state.reader_closed {
+                    return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+                }
+                let n = buf.len().min(state.capacity - state.buf.len());
+                if n > 0 {
+                    state.buf.extend(&buf[..n]);
+                    state.wake_reader();
+                    return Ok(n);
+                }
+            }
+            Wait {
+                shared: &self.shared,
+                reader: false,
+                registered: false,
+            }
+            .await;
+        }
+    }
+
+    pub async fn write_all(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            let n = self.write(buf).await?;
+            buf = &buf[n..];
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        let mut state = self.shared.borrow_mut();
+        state.writer_closed = true;
+        state.wake_reader();
+    }
+}
+
+/// Reading half of a [pipe_buffer].
+pub struct PipeReader {
+    shared: Shared,
+}
+
+impl PipeReader {
+    /// Reads the buffered bytes into `buf`, waiting until there are some. `Ok(0)` means the writer was dropped and
+    /// everything it wrote was read.
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            {
+                let mut state = self.shared.borrow_mut();
+                if !state.buf.is_empty() {
+                    let n = state.buf.read(buf)?;
+                    state.wake_writer();
+                    return Ok(n);
+                }
+                if state.writer_closed {
+                    return Ok(0);
+                }
+            }
+            Wait {
+                shared: &self.shared,
+                reader: true,
+                registered: false,
+            }
+            .await;
+        }
+    }
+
+    /// Reads until the writer is dropped, appending to `out`.
+    pub async fn read_to_end(&mut self, out: &mut Vec<u8>) -> io::Result<usize> {
+        let start = out.len();
+        let mut chunk = [0; 4096];
+        loop {
+            match self.read(&mut chunk).await? {
+                0 => return Ok(out.len() - start),
+                n => out.extend_from_slice(&chunk[..n]),
+            }
+        }
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        let mut state = self.shared.borrow_mut();
+        state.reader_closed = true;
+        state.buf.clear();
+        state.wake_writer();
+    }
+}
+
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+struct Wait<'a> {
+    shared: &'a Shared,
+    reader: bool,
+    registered: bool,
+}
+
+impl Future for Wait<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let fut = self.get_mut();
+        let mut state = fut.shared.borrow_mut();
+        let waiting = match fut.reader {
+            true => &mut state.reader_waiting,
+            false => &mut state.writer_waiting,
+        };
+        if fut.registered {
+            *waiting = None;
+            return Poll::Ready(());
+        }
+        fut.registered = true;
+        *waiting = Some(executor::current_task_id());
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::executor::{spawn, ExecutorConfig};
+
+    #[test]
+    fn test_pipe_buffer() {
+        ExecutorConfig::new()
+            .run(async {
+                let (mut writer, mut reader) = pipe_buffer(16);
+                let max_buffered = Rc::new(Cell::new(0));
+                let producer = spawn({
+                    let max_buffered = max_buffered.clone();
+                    async move {
+                        for i in 0..100u8 {
+                            writer.write_all(&[i; 7]).await.unwrap();
+                            max_buffered
+                                .set(max_buffered.get().max(writer.shared.borrow().buf.len()));
+                        }
+                    }
+                });
+
+                let mut out = Vec::new();
+                reader.read_to_end(&mut out).await.unwrap();
+                producer.await;
+                assert_eq!(out.len(), 700);
+                assert!(out.chunks(7).enumerate().all(|(i, c)| c == [i as u8; 7]));
+                assert!(max_buffered.get() <= 16);
+
+                let (mut writer, reader) = pipe_buffer(4);
+                drop(reader);
+                let err = writer.write(b"x").await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,138 @@
+//! Readiness notifications for file descriptors.
+//!
+//! This is for driving libraries that do their own non-blocking io on an fd and only need to be told when to retry,
+//! e.g. the async modes of hiredis or libpq. Io that io2 does itself should use the io_uring based operations instead.
+
+use std::io;
+use std::marker::PhantomData;
+use std::os::fd::RawFd;
+
+use io_uring::cqueue;
+use io_uring::opcode;
+use io_uring::types::Fd;
+
+use crate::executor::{NextMultishot, RawIo, CURRENT_TASK_CONTEXT};
+use crate::slab;
+
+/// Returns a stream of readiness events of `fd` for `events`, e.g. `libc::POLLIN | libc::POLLOUT`.
+///
+/// The fd isn't owned by the stream and has to stay open until the stream is closed or dropped.
+pub fn poll_stream(fd: RawFd, events: i16) -> PollStream {
+    PollStream {
+        fd,
+        events: u32::from(events as u16),
+        io_id: None,
+        _non_send: PhantomData,
+    }
+}
+
+/// Readiness events of an fd using a multishot poll, so a single submission keeps reporting events.
+///
+/// An event is posted each time the fd becomes ready, not while it stays ready. So the caller should do io on the fd
+/// until it fails with EAGAIN before waiting for the next event, same as with edge triggered epoll.
+pub struct PollStream {
+    fd: RawFd,
+    events: u32,
+    io_id: Option<slab::Key>,
+    _non_send: PhantomData<*mut ()>,
+}
+
+impl PollStream {
+    /// Waits for the next readiness event and returns the ready events, e.g. `libc::POLLIN`.
+    ///
+    /// `libc::POLLHUP` and `libc::POLLERR` can be returned even if they weren't requested.
+    pub async fn next(&mut self) -> io::Result<i16> {
+        let io_id = match self.io_id {
+            Some(io_id) => io_id,
+            None => {
+                let entry = opcode::PollAdd::new(Fd(self.fd), self.events)
+                    .multi(true)
+                    .build();
+                let io_id = CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| unsafe {
+                    ctx.as_mut().unwrap().queue_multishot_io(entry)
+                });
+                self.io_id = Some(io_id);
+                io_id
+            }
+        };
+
+        let (res, flags) = NextMultishot { io_id }.await;
+        // The kernel can stop a multishot poll, it is restarted on the next call.
+        if !cqueue::more(flags) {
+            self.io_id = None;
+        }
+        if res < 0 {
+            return Err(io::Error::from_raw_os_error(-res));
+        }
+        Ok(res as u16 as i16)
+    }
+
+    /// Stops the poll if it is running.
+    pub async fn close(mut self) {
+        if let Some(io_id) = self.io_id {
+            let cancel = opcode::PollRemove::new(io_id.into()).build();
+            unsafe { RawIo::new(cancel) }.await;
+            while let Some(io_id) = self.io_id {
+                let (_, flags) = NextMultishot { io_id }.await;
+                if !cqueue::more(flags) {
+                    self.io_id = None;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for PollStream {
+    fn drop(&mut self) {
+        if let Some(io_id) = self.io_id {
+            CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+                if let Some(ctx) = ctx.as_mut() {
+                    ctx.abandon_multishot_io(io_id);
+                    unsafe {
+                        ctx.queue_detached_io(opcode::PollRemove::new(io_id.into()).build());
+                    }
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::executor::ExecutorConfig;
+
+    use super::*;
+
+    #[test]
+    fn test_poll_stream() {
+        ExecutorConfig::new()
+            .run(async {
+                let mut fds = [0; 2];
+                assert_eq!(
+                    unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) },
+                    0
+                );
+                let [read_fd, write_fd] = fds;
+
+                let mut stream = poll_stream(read_fd, libc::POLLIN);
+                let mut buf = [0u8; 16];
+                for i in 0..3u8 {
+                    assert_eq!(unsafe { libc::write(write_fd, [i].as_ptr().cast(), 1) }, 1);
+                    let events = stream.next().await.unwrap();
+                    assert_ne!(events & libc::POLLIN, 0);
+                    assert_eq!(
+                        unsafe { libc::read(read_fd, buf.as_mut_ptr().cast(), buf.len()) },
+                        1
+                    );
+                    assert_eq!(buf[0], i);
+                }
+
+                unsafe { libc::close(write_fd) };
+                let events = stream.next().await.unwrap();
+                assert_ne!(events & libc::POLLHUP, 0);
+                stream.close().await;
+                unsafe { libc::close(read_fd) };
+            })
+            .unwrap();
+    }
+}
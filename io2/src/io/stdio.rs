@@ -0,0 +1,276 @@
+//! Handles to the standard streams of the process.
+//!
+//! Standard streams are usually ttys or pipes which aren't seekable, so all operations use the current file position
+//! (offset -1) instead of an explicit offset. The file descriptors are never closed by these handles.
+
+use std::cell::RefCell;
+use std::io;
+use std::marker::PhantomData;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::rc::Rc;
+
+use io_uring::opcode;
+use io_uring::types::Fd;
+
+use crate::executor::RawIo;
+
+// Tells io_uring to use and advance the current file position, which is required for non-seekable files.
+const CURRENT_POSITION: u64 = u64::MAX;
+
+// Size of the reads read_line does, input after the newline is kept for the next read.
+const STDIN_READ_SIZE: usize = 4096;
+
+async fn read(fd: RawFd, buf: &mut [u8]) -> io::Result<usize> {
+    let entry = opcode::Read::new(Fd(fd), buf.as_mut_ptr(), buf.len().try_into().unwrap())
+        .offset(CURRENT_POSITION)
+        .build();
+    match unsafe { RawIo::new(entry) }.await {
+        res if res < 0 => Err(io::Error::from_raw_os_error(-res)),
+        res => Ok(res.try_into().unwrap()),
+    }
+}
+
+async fn write(fd: RawFd, buf: &[u8]) -> io::Result<usize> {
+    let entry = opcode::Write::new(Fd(fd), buf.as_ptr(), buf.len().try_into().unwrap())
+        .offset(CURRENT_POSITION)
+        .build();
+    match unsafe { RawIo::new(entry) }.await {
+        res if res < 0 => Err(io::Error::from_raw_os_error(-res)),
+        res => Ok(res.try_into().unwrap()),
+    }
+}
+
+async fn write_all(fd: RawFd, buf: &[u8]) -> io::Result<()> {
+    let mut buf = buf;
+
+    while !buf.is_empty() {
+        match write(fd, buf).await {
+            Ok(0) => {
+                return Err(io::Error::from(io::ErrorKind::WriteZero));
+            }
+            Ok(n) => {
+                buf = &buf[n..];
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+// Input that was read from stdin but wasn't returned yet.
+#[derive(Default)]
+struct ReadBuffer {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl ReadBuffer {
+    // Moves buffered input into `buf`, returns the number of bytes moved.
+    fn take_into(&mut self, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.data.len() - self.pos);
+        buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+        self.pos += n;
+        n
+    }
+}
+
+thread_local! {
+    // Shared by the handles of a thread, so input that one handle read past a newline isn't lost when it is dropped.
+    static STDIN_BUFFER: Rc<RefCell<ReadBuffer>> = Rc::new(RefCell::new(ReadBuffer::default()));
+}
+
+pub struct Stdin {
+    fd: RawFd,
+    buffer: Rc<RefCell<ReadBuffer>>,
+    _non_send: PhantomData<*mut ()>,
+}
+
+pub fn stdin() -> Stdin {
+    Stdin {
+        fd: libc::STDIN_FILENO,
+        buffer: STDIN_BUFFER.with(Rc::clone),
+        _non_send: PhantomData,
+    }
+}
+
+impl Stdin {
+    /// Reads available input into `buf`, `Ok(0)` means end of input.
+    ///
+    /// On a tty this resolves when a line is entered.
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.buffer.borrow_mut().take_into(buf);
+        if n > 0 {
+            return Ok(n);
+        }
+        read(self.fd, buf).await
+    }
+
+    /// Reads a line into `line` including the newline, returns the number of bytes read. `Ok(0)` means end of input.
+    ///
+    /// Reads in chunks of 4 KiB, input after the newline is buffered and returned by the next read.
+    pub async fn read_line(&self, line: &mut Vec<u8>) -> io::Result<usize> {
+        let start = line.len();
+        loop {
+            let mut chunk = {
+                let mut buffer = self.buffer.borrow_mut();
+                let pending = &buffer.data[buffer.pos..];
+                if let Some(i) = pending.iter().position(|&b| b == b'\n') {
+                    line.extend_from_slice(&pending[..=i]);
+                    buffer.pos += i + 1;
+                    return Ok(line.len() - start);
+                }
+                line.extend_from_slice(pending);
+                buffer.pos = 0;
+                std::mem::take(&mut buffer.data)
+            };
+            chunk.clear();
+            chunk.resize(STDIN_READ_SIZE, 0);
+            let n = match read(self.fd, &mut chunk).await {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            chunk.truncate(n);
+            let mut buffer = self.buffer.borrow_mut();
+            if buffer.data.is_empty() {
+                buffer.data = chunk;
+            } else {
+                // Another handle read while this one was waiting.
+                buffer.data.extend_from_slice(&chunk);
+            }
+        }
+        Ok(line.len() - start)
+    }
+}
+
+pub struct Stdout {
+    _non_send: PhantomData<*mut ()>,
+}
+
+pub fn stdout() -> Stdout {
+    Stdout {
+        _non_send: PhantomData,
+    }
+}
+
+impl Stdout {
+    pub async fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        write(libc::STDOUT_FILENO, buf).await
+    }
+
+    pub async fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        write_all(libc::STDOUT_FILENO, buf).await
+    }
+}
+
+pub struct Stderr {
+    _non_send: PhantomData<*mut ()>,
+}
+
+pub fn stderr() -> Stderr {
+    Stderr {
+        _non_send: PhantomData,
+    }
+}
+
+impl Stderr {
+    pub async fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        write(libc::STDERR_FILENO, buf).await
+    }
+
+    pub async fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        write_all(libc::STDERR_FILENO, buf).await
+    }
+}
+
+// The standard streams are never closed, so they can be borrowed for as long as a handle exists.
+macro_rules! impl_stdio_fd {
+    ($($t:ty => $fd:expr),*) => {
+        $(
+            impl AsRawFd for $t {
+                fn as_raw_fd(&self) -> RawFd {
+                    $fd
+                }
+            }
+
+            impl AsFd for $t {
+                fn as_fd(&self) -> BorrowedFd<'_> {
+                    unsafe { BorrowedFd::borrow_raw($fd) }
+                }
+            }
+        )*
+    };
+}
+
+impl_stdio_fd!(
+    Stdin => libc::STDIN_FILENO,
+    Stdout => libc::STDOUT_FILENO,
+    Stderr => libc::STDERR_FILENO
+);
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::os::fd::{FromRawFd, OwnedFd};
+
+    use crate::executor::ExecutorConfig;
+
+    use super::*;
+
+    #[test]
+    fn smoke_test_stdio() {
+        ExecutorConfig::new()
+            .run(async {
+                stdout()
+                    .write_all(b"hello from io2 stdout\n")
+                    .await
+                    .unwrap();
+                stderr()
+                    .write_all(b"hello from io2 stderr\n")
+                    .await
+                    .unwrap();
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_read_line() {
+        ExecutorConfig::new()
+            .run(async {
+                let mut fds = [0; 2];
+                assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }, 0);
+                let reader = unsafe { OwnedFd::from_raw_fd(fds[0]) };
+                let mut writer = std::fs::File::from(unsafe { OwnedFd::from_raw_fd(fds[1]) });
+                writer.write_all(b"first line\nsecond line\nrest").unwrap();
+                drop(writer);
+
+                let stdin = Stdin {
+                    fd: reader.as_raw_fd(),
+                    buffer: Rc::new(RefCell::new(ReadBuffer::default())),
+                    _non_send: PhantomData,
+                };
+                let mut line = Vec::new();
+                assert_eq!(stdin.read_line(&mut line).await.unwrap(), 11);
+                assert_eq!(line, b"first line\n");
+                // The whole input was read at once, the rest is buffered.
+                assert_eq!(stdin.buffer.borrow().data.len(), 27);
+
+                line.clear();
+                assert_eq!(stdin.read_line(&mut line).await.unwrap(), 12);
+                assert_eq!(line, b"second line\n");
+
+                let mut buf = [0; 2];
+                assert_eq!(stdin.read(&mut buf).await.unwrap(), 2);
+                assert_eq!(&buf, b"re");
+
+                line.clear();
+                assert_eq!(stdin.read_line(&mut line).await.unwrap(), 2);
+                assert_eq!(line, b"st");
+                assert_eq!(stdin.read_line(&mut line).await.unwrap(), 0);
+            })
+            .unwrap();
+    }
+}
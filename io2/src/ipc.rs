@@ -0,0 +1,251 @@
+//! Typed message channels between processes over unix sockets.
+//!
+//! Messages are encoded with [Message] and sent as frames of [LengthDelimited]. This is meant for architectures that
+//! run an executor per process and pass work between them, e.g. a process per shard. The socket can be inherited by
+//! a child process or sent to another process with [UnixStream::send_fd].
+//!
+//! Types are encoded by implementing [Message], which can be a thin wrapper over a serialization library like
+//! postcard or bincode. With the `serde` feature [Postcard] does this for any serde type.
+
+use std::io;
+use std::marker::PhantomData;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+
+use crate::codec::LengthDelimited;
+use crate::local_alloc::LocalAlloc;
+use crate::net::unix::UnixStream;
+
+/// A value that can be sent through a [Sender].
+pub trait Message: Sized {
+    /// Appends the encoded form of the value to `out`.
+    fn encode(&self, out: &mut Vec<u8, LocalAlloc>) -> io::Result<()>;
+
+    /// Decodes a value from the bytes written by [Message::encode].
+    fn decode(bytes: &[u8]) -> io::Result<Self>;
+}
+
+impl Message for Vec<u8> {
+    fn encode(&self, out: &mut Vec<u8, LocalAlloc>) -> io::Result<()> {
+        out.extend_from_slice(self);
+        Ok(())
+    }
+
+    fn decode(bytes: &[u8]) -> io::Result<Self> {
+        Ok(bytes.to_vec())
+    }
+}
+
+impl Message for String {
+    fn encode(&self, out: &mut Vec<u8, LocalAlloc>) -> io::Result<()> {
+        out.extend_from_slice(self.as_bytes());
+        Ok(())
+    }
+
+    fn decode(bytes: &[u8]) -> io::Result<Self> {
+        String::from_utf8(bytes.to_vec()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+macro_rules! impl_message_int {
+    ($($t:ty),*) => {
+        $(
+            impl Message for $t {
+                fn encode(&self, out: &mut Vec<u8, LocalAlloc>) -> io::Result<()> {
+                    out.extend_from_slice(&self.to_le_bytes());
+                    Ok(())
+                }
+
+                fn decode(bytes: &[u8]) -> io::Result<Self> {
+                    let bytes = bytes.try_into().map_err(|_| {
+                        io::Error::new(io::ErrorKind::InvalidData, "integer message has the wrong length")
+                    })?;
+                    Ok(<$t>::from_le_bytes(bytes))
+                }
+            }
+        )*
+    };
+}
+
+impl_message_int!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+/// Sends a serde type through a channel, encoded with postcard.
+#[cfg(feature = "serde")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Postcard<T>(pub T);
+
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> Message for Postcard<T> {
+    fn encode(&self, out: &mut Vec<u8, LocalAlloc>) -> io::Result<()> {
+        postcard::serialize_with_flavor(&self.0, ExtendVec(out))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    fn decode(bytes: &[u8]) -> io::Result<Self> {
+        match postcard::take_from_bytes(bytes) {
+            Ok((value, [])) => Ok(Self(value)),
+            Ok(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "message has trailing bytes",
+            )),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+// Serializes straight into the send buffer.
+#[cfg(feature = "serde")]
+struct ExtendVec<'a>(&'a mut Vec<u8, LocalAlloc>);
+
+#[cfg(feature = "serde")]
+impl postcard::ser_flavors::Flavor for ExtendVec<'_> {
+    type Output = ();
+
+    fn try_extend(&mut self, data: &[u8]) -> postcard::Result<()> {
+        self.0.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn try_push(&mut self, data: u8) -> postcard::Result<()> {
+        self.0.push(data);
+        Ok(())
+    }
+
+    fn finalize(self) -> postcard::Result<()> {
+        Ok(())
+    }
+}
+
+/// Creates a connected pair of channel ends.
+pub fn channel<T: Message>() -> io::Result<(Sender<T>, Receiver<T>)> {
+    let (a, b) = UnixStream::pair()?;
+    Ok((Sender::new(a), Receiver::new(b)))
+}
+
+pub struct Sender<T: Message> {
+    framed: LengthDelimited<UnixStream>,
+    buf: Vec<u8, LocalAlloc>,
+    _message: PhantomData<fn(T)>,
+}
+
+impl<T: Message> Sender<T> {
+    pub fn new(stream: UnixStream) -> Self {
+        Self {
+            framed: LengthDelimited::new(stream),
+            buf: Vec::new_in(LocalAlloc::new()),
+            _message: PhantomData,
+        }
+    }
+
+    pub async fn send(&mut self, msg: &T) -> io::Result<()> {
+        self.buf.clear();
+        msg.encode(&mut self.buf)?;
+        self.framed.send_frame(&self.buf).await
+    }
+
+    pub fn into_stream(self) -> UnixStream {
+        self.framed.into_inner()
+    }
+}
+
+pub struct Receiver<T: Message> {
+    framed: LengthDelimited<UnixStream>,
+    _message: PhantomData<fn() -> T>,
+}
+
+impl<T: Message> Receiver<T> {
+    pub fn new(stream: UnixStream) -> Self {
+        Self {
+            framed: LengthDelimited::new(stream),
+            _message: PhantomData,
+        }
+    }
+
+    /// Receives the next message, `None` means the sender closed the channel.
+    pub async fn recv(&mut self) -> Option<io::Result<T>> {
+        match self.framed.next_frame().await? {
+            Ok(frame) => Some(T::decode(frame)),
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    pub fn into_stream(self) -> UnixStream {
+        self.framed.into_inner()
+    }
+}
+
+impl<T: Message> AsRawFd for Sender<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.framed.get_ref().as_raw_fd()
+    }
+}
+
+impl<T: Message> AsFd for Sender<T> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.framed.get_ref().as_fd()
+    }
+}
+
+impl<T: Message> AsRawFd for Receiver<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.framed.get_ref().as_raw_fd()
+    }
+}
+
+impl<T: Message> AsFd for Receiver<T> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.framed.get_ref().as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::executor::{spawn, ExecutorConfig};
+
+    use super::*;
+
+    #[test]
+    fn test_ipc_channel() {
+        ExecutorConfig::new()
+            .run(async {
+                let (mut tx, mut rx) = channel::<String>().unwrap();
+                let sender = spawn(async move {
+                    for i in 0..10 {
+                        tx.send(&format!("message {}", i)).await.unwrap();
+                    }
+                });
+                for i in 0..10 {
+                    assert_eq!(rx.recv().await.unwrap().unwrap(), format!("message {}", i));
+                }
+                sender.await;
+                assert!(rx.recv().await.is_none());
+
+                // Decoding errors are returned instead of a corrupted message.
+                let (mut tx, rx) = channel::<u16>().unwrap();
+                let mut rx = Receiver::<u64>::new(rx.into_stream());
+                tx.send(&7).await.unwrap();
+                let err = rx.recv().await.unwrap().unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_postcard_channel() {
+        ExecutorConfig::new()
+            .run(async {
+                type Msg = Postcard<(u32, String, Vec<u64>)>;
+                let (mut tx, mut rx) = channel::<Msg>().unwrap();
+                let msg = Postcard((7, "shard".to_owned(), vec![1, u64::MAX]));
+                tx.send(&msg).await.unwrap();
+                assert_eq!(rx.recv().await.unwrap().unwrap(), msg);
+
+                // A message of another type doesn't decode.
+                let mut rx = Receiver::<Postcard<(u32, u32)>>::new(rx.into_stream());
+                tx.send(&msg).await.unwrap();
+                let err = rx.recv().await.unwrap().unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,227 @@
+use std::alloc::Allocator;
+
+use crate::slab::Key;
+
+/// Map from [Key] to values, stored in an open addressing hash table.
+///
+/// Lookups don't depend on the number of entries like they do with [VecMap](crate::vecmap::VecMap), so this is used
+/// for maps that can hold thousands of entries, e.g. results of in flight operations.
+pub struct KeyMap<V, A: Allocator + Copy> {
+    // Length is zero or a power of two, entries are placed with linear probing.
+    buckets: Vec<Option<(Key, V)>, A>,
+    len: usize,
+    high_water_mark: usize,
+    alloc: A,
+}
+
+const MIN_BUCKETS: usize = 16;
+
+impl<V, A: Allocator + Copy> KeyMap<V, A> {
+    pub fn with_capacity_in(capacity: usize, alloc: A) -> Self {
+        Self {
+            buckets: new_buckets(num_buckets(capacity), alloc),
+            len: 0,
+            high_water_mark: 0,
+            alloc,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of entries the map can hold without allocating.
+    pub fn capacity(&self) -> usize {
+        self.buckets.len() / 4 * 3
+    }
+
+    /// Largest number of entries the map held at the same time.
+    pub fn high_water_mark(&self) -> usize {
+        self.high_water_mark
+    }
+
+    /// Frees memory that isn't needed for the current entries, keeping space for at least `min_capacity` of them.
+    pub fn shrink_to(&mut self, min_capacity: usize) {
+        let num_buckets = num_buckets(self.len.max(min_capacity));
+        if num_buckets < self.buckets.len() {
+            self.rehash(num_buckets);
+        }
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        self.shrink_to(0);
+    }
+
+    pub fn clear(&mut self) {
+        for bucket in self.buckets.iter_mut() {
+            *bucket = None;
+        }
+        self.len = 0;
+    }
+
+    pub fn insert(&mut self, key: Key, value: V) -> Option<V> {
+        if let Some(idx) = self.find(key) {
+            let (_, v) = self.buckets[idx].as_mut().unwrap();
+            return Some(std::mem::replace(v, value));
+        }
+        if self.len + 1 > self.capacity() {
+            self.rehash(num_buckets(self.len + 1).max(self.buckets.len() * 2));
+        }
+        let mask = self.buckets.len() - 1;
+        let mut idx = bucket_of(key, mask);
+        while self.buckets[idx].is_some() {
+            idx = (idx + 1) & mask;
+        }
+        self.buckets[idx] = Some((key, value));
+        self.len += 1;
+        self.high_water_mark = self.high_water_mark.max(self.len);
+        None
+    }
+
+    pub fn get(&self, key: &Key) -> Option<&V> {
+        let idx = self.find(*key)?;
+        self.buckets[idx].as_ref().map(|(_, v)| v)
+    }
+
+    pub fn get_mut(&mut self, key: &Key) -> Option<&mut V> {
+        let idx = self.find(*key)?;
+        self.buckets[idx].as_mut().map(|(_, v)| v)
+    }
+
+    pub fn contains_key(&self, key: &Key) -> bool {
+        self.find(*key).is_some()
+    }
+
+    pub fn remove(&mut self, key: &Key) -> Option<V> {
+        let mut hole = self.find(*key)?;
+        let entry = self.buckets[hole].take();
+        self.len -= 1;
+
+        // Move the entries after the hole back so probing for them doesn't stop at the hole.
+        let mask = self.buckets.len() - 1;
+        let mut idx = hole;
+        loop {
+            idx = (idx + 1) & mask;
+            let Some((k, _)) = &self.buckets[idx] else {
+                break;
+            };
+            let home = bucket_of(*k, mask);
+            // The entry can move to the hole if its home bucket isn't cyclically between the hole and it.
+            let dist_to_idx = idx.wrapping_sub(home) & mask;
+            let dist_to_hole = hole.wrapping_sub(home) & mask;
+            if dist_to_hole < dist_to_idx {
+                self.buckets[hole] = self.buckets[idx].take();
+                hole = idx;
+            }
+        }
+        entry.map(|(_, v)| v)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Key, &V)> {
+        self.buckets
+            .iter()
+            .filter_map(|bucket| bucket.as_ref().map(|(k, v)| (k, v)))
+    }
+
+    pub fn iter_keys(&self) -> impl Iterator<Item = &Key> {
+        self.iter().map(|(k, _)| k)
+    }
+
+    fn find(&self, key: Key) -> Option<usize> {
+        if self.buckets.is_empty() {
+            return None;
+        }
+        let mask = self.buckets.len() - 1;
+        let mut idx = bucket_of(key, mask);
+        loop {
+            match &self.buckets[idx] {
+                None => return None,
+                Some((k, _)) if *k == key => return Some(idx),
+                Some(_) => idx = (idx + 1) & mask,
+            }
+        }
+    }
+
+    fn rehash(&mut self, num_buckets: usize) {
+        let old = std::mem::replace(&mut self.buckets, new_buckets(num_buckets, self.alloc));
+        let mask = num_buckets - 1;
+        for (key, value) in old.into_iter().flatten() {
+            let mut idx = bucket_of(key, mask);
+            while self.buckets[idx].is_some() {
+                idx = (idx + 1) & mask;
+            }
+            self.buckets[idx] = Some((key, value));
+        }
+    }
+}
+
+// Buckets needed to hold `capacity` entries with a load factor of at most 3/4.
+fn num_buckets(capacity: usize) -> usize {
+    (capacity * 4)
+        .div_ceil(3)
+        .next_power_of_two()
+        .max(MIN_BUCKETS)
+}
+
+fn new_buckets<V, A: Allocator>(num_buckets: usize, alloc: A) -> Vec<Option<(Key, V)>, A> {
+    let mut buckets = Vec::with_capacity_in(num_buckets, alloc);
+    buckets.resize_with(num_buckets, || None);
+    buckets
+}
+
+fn bucket_of(key: Key, mask: usize) -> usize {
+    // Fibonacci hashing, keys of a slab are mostly consecutive indices so the high bits of the product are used.
+    let hash = u64::from(key).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    (hash >> 32) as usize & mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local_alloc::LocalAlloc;
+    use crate::slab::Slab;
+
+    #[test]
+    fn test_keymap() {
+        let mut slab = Slab::with_capacity_in(16, LocalAlloc::new());
+        let keys = (0..5000).map(|i| slab.insert(i)).collect::<Vec<_>>();
+        let mut map = KeyMap::with_capacity_in(4, LocalAlloc::new());
+        for (i, &key) in keys.iter().enumerate() {
+            assert_eq!(map.insert(key, i), None);
+        }
+        assert_eq!(map.insert(keys[7], 70), Some(7));
+        assert_eq!(map.len(), 5000);
+
+        // Removing entries in the middle of probe sequences keeps the other entries reachable.
+        for &key in keys.iter().step_by(3) {
+            assert!(map.remove(&key).is_some());
+        }
+        for (i, key) in keys.iter().enumerate() {
+            let expected = match i {
+                _ if i % 3 == 0 => None,
+                7 => Some(70),
+                _ => Some(i),
+            };
+            assert_eq!(map.get(key).copied(), expected);
+        }
+
+        // A key of a removed slab value doesn't match a new value in the same slot.
+        slab.remove(keys[1]);
+        let reused = slab.insert(0);
+        assert_eq!(map.get(&reused), None);
+
+        for key in &keys[100..] {
+            map.remove(key);
+        }
+        map.shrink_to_fit();
+        assert_eq!(map.len(), 66);
+        assert_eq!(map.iter().count(), 66);
+        assert_eq!(map.get(&keys[98]).copied(), Some(98));
+        assert_eq!(map.high_water_mark(), 5000);
+        assert!(map.capacity() < 5000);
+    }
+}
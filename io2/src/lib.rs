@@ -1,10 +1,34 @@
 #![feature(allocator_api)]
 #![allow(clippy::new_without_default)]
 
+pub mod block;
+mod blocking;
+pub mod cache;
+pub mod codec;
+pub mod compat;
+pub mod compress;
 pub mod executor;
 pub mod fs;
+pub mod hash;
+pub mod http;
+pub mod inspector;
+pub mod io;
 pub mod io_buffer;
+pub mod ipc;
+pub mod keymap;
 pub mod local_alloc;
+pub mod logger;
+pub mod metrics;
+pub mod net;
+pub mod queue;
+#[cfg(feature = "s3")]
+pub mod s3;
 pub mod slab;
+pub mod sort;
+pub mod sst;
+pub mod sync;
+#[cfg(feature = "test_util")]
+pub mod test_util;
 pub mod time;
 pub mod vecmap;
+pub mod wal;
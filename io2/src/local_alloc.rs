@@ -1,40 +1,89 @@
+mod arena;
+#[cfg(feature = "debug-alloc")]
+mod debug;
+mod numa;
+
 use std::{
     alloc::{AllocError, Allocator, Layout},
     cell::RefCell,
+    collections::BTreeSet,
     io,
     marker::PhantomData,
     ptr::NonNull,
+    sync::atomic::{AtomicBool, Ordering},
 };
 
+pub use arena::LocalArena;
+
+use crate::io_buffer::IoBuffer;
+
 thread_local! {
     static STATE: RefCell<State> = RefCell::new(State::new());
+    // Kept outside of STATE so the callback can allocate and free memory.
+    static PRESSURE_HOOK: RefCell<Option<PressureHook>> = const { RefCell::new(None) };
 }
 
+type PressureHook = Box<dyn FnMut(&MemoryPressure)>;
+
 struct State {
     alloc: unsafe fn(size: usize) -> io::Result<NonNull<[u8]>>,
     free: unsafe fn(ptr: *mut u8, length: usize) -> io::Result<()>,
+    // Pages are allocated in multiples of this.
+    page_size: usize,
+    // Pages are mapped from hugetlbfs instead of relying on transparent huge pages.
+    explicit_huge_pages: bool,
+    // Fail allocations if explicit huge pages can't be mapped instead of falling back to regular pages.
+    require_huge_pages: bool,
+    // New pages are bound to this NUMA node.
+    numa_node: Option<u32>,
     // TODO: do allocation of these vectors with a good strategy instead of using global allocator
     pages: Vec<Page>,
     free_list: Vec<Vec<FreeRange>>,
+    // Every free range as (len, start address), so allocations take the smallest range that fits without scanning
+    // all of the pages.
+    free_index: BTreeSet<(usize, usize)>,
+    // Pages get a new id when they are allocated so an id isn't reused after its page is freed.
+    next_page_id: u64,
+    reserved_bytes: usize,
+    // Largest reserved_bytes since the last call to take_peak_reserved_bytes.
+    peak_reserved_bytes: usize,
+    max_bytes: Option<usize>,
+    // Fractions of max_bytes in increasing order.
+    pressure_thresholds: Vec<f64>,
+    // Number of thresholds that reserved_bytes is currently at or above.
+    pressure_level: usize,
+    // Event to pass to the pressure hook once STATE isn't borrowed anymore.
+    pending_pressure: Option<MemoryPressure>,
 }
 
 impl State {
+    // Miri can't run the system calls, pages come from the global allocator so the rest of the allocator can be
+    // checked.
+    #[cfg(miri)]
     fn new() -> Self {
-        let (alloc, free): (unsafe fn(_) -> _, unsafe fn(_, _) -> _) = match std::env::var(
-            HUGE_PAGE_SIZE_ENV_VAR_NAME,
-        ) {
+        Self::with_pages(alloc_global, free_global, TWO_MB, false)
+    }
+
+    #[cfg(not(miri))]
+    fn new() -> Self {
+        let (alloc, free, page_size, explicit_huge_pages): (
+            unsafe fn(_) -> _,
+            unsafe fn(_, _) -> _,
+            _,
+            _,
+        ) = match std::env::var(HUGE_PAGE_SIZE_ENV_VAR_NAME) {
             Err(e) => {
                 log::trace!("failed to read {} from environment: {}\nDefaulting using regular 2MB aligned allocations", HUGE_PAGE_SIZE_ENV_VAR_NAME, e);
-                (alloc_2mb, free_wrapper)
+                (alloc_2mb, free_wrapper, TWO_MB, false)
             }
             Ok(huge_page_size) => match huge_page_size.as_str() {
                 "2MB" => {
                     log::trace!("using explicit 2MB huge pages");
-                    (alloc_2mb_explicit, munmap_wrapper)
+                    (alloc_2mb_explicit, munmap_wrapper, TWO_MB, true)
                 }
                 "1GB" => {
                     log::trace!("using explicit 1GB huge pages");
-                    (alloc_1gb_explicit, munmap_wrapper)
+                    (alloc_1gb_explicit, munmap_wrapper, ONE_GB, true)
                 }
                 _ => {
                     log::trace!(
@@ -42,24 +91,234 @@ impl State {
                         HUGE_PAGE_SIZE_ENV_VAR_NAME,
                         huge_page_size
                     );
-                    (alloc_2mb, free_wrapper)
+                    (alloc_2mb, free_wrapper, TWO_MB, false)
                 }
             },
         };
 
+        Self::with_pages(alloc, free, page_size, explicit_huge_pages)
+    }
+
+    fn with_pages(
+        alloc: unsafe fn(size: usize) -> io::Result<NonNull<[u8]>>,
+        free: unsafe fn(ptr: *mut u8, length: usize) -> io::Result<()>,
+        page_size: usize,
+        explicit_huge_pages: bool,
+    ) -> Self {
         Self {
             alloc,
             free,
+            page_size,
+            explicit_huge_pages,
+            require_huge_pages: false,
+            numa_node: None,
             pages: Vec::with_capacity(128),
             free_list: Vec::with_capacity(128),
+            free_index: BTreeSet::new(),
+            next_page_id: 0,
+            reserved_bytes: 0,
+            peak_reserved_bytes: 0,
+            max_bytes: None,
+            pressure_thresholds: Vec::new(),
+            pressure_level: 0,
+            pending_pressure: None,
+        }
+    }
+
+    /// Returns the index of the page that contains all of `ptr..ptr + len`, pages are sorted by address.
+    fn page_index(&self, ptr: *const u8, len: usize) -> Option<usize> {
+        let idx = self
+            .pages
+            .partition_point(|page| page.ptr as usize <= ptr as usize)
+            .checked_sub(1)?;
+        let page = self.pages[idx];
+        let end = (ptr as usize).checked_add(len)?;
+        (end <= page.ptr as usize + page.size).then_some(idx)
+    }
+
+    /// Fails with [AllocOutcome::LimitReached] if reserving `bytes` more would go over max_bytes.
+    fn check_limit(&mut self, bytes: usize) -> Result<(), AllocOutcome> {
+        if let Some(max_bytes) = self.max_bytes {
+            if self.reserved_bytes + bytes > max_bytes {
+                self.pending_pressure = Some(MemoryPressure {
+                    reserved_bytes: self.reserved_bytes,
+                    max_bytes,
+                    threshold: 1.0,
+                });
+                return Err(AllocOutcome::LimitReached);
+            }
+        }
+        Ok(())
+    }
+
+    fn add_reserved(&mut self, bytes: usize) {
+        self.reserved_bytes += bytes;
+        self.peak_reserved_bytes = self.peak_reserved_bytes.max(self.reserved_bytes);
+        self.update_pressure_level();
+    }
+
+    fn remove_reserved(&mut self, bytes: usize) {
+        self.reserved_bytes -= bytes;
+        self.update_pressure_level();
+    }
+
+    fn update_pressure_level(&mut self) {
+        let Some(max_bytes) = self.max_bytes else {
+            return;
+        };
+        let level = self
+            .pressure_thresholds
+            .iter()
+            .take_while(|&&t| self.reserved_bytes as f64 >= t * max_bytes as f64)
+            .count();
+        if level > self.pressure_level {
+            self.pending_pressure = Some(MemoryPressure {
+                reserved_bytes: self.reserved_bytes,
+                max_bytes,
+                threshold: self.pressure_thresholds[level - 1],
+            });
+        }
+        self.pressure_level = level;
+    }
+}
+
+/// Limits and memory pressure notifications of the allocator of the current thread.
+///
+/// Usage is measured as the memory reserved from the system, which grows a page at a time. Once `max_bytes` is
+/// reached allocations that need a new page fail instead of asking the system for more memory. The memory pressure
+/// callback is called when usage goes over one of the thresholds and right before an allocation fails because of
+/// the limit, so caches built on this allocator can shed entries. The failing allocation is tried again after the
+/// callback returns.
+///
+/// The callback is called from inside the allocator, it can allocate and free memory but it isn't called again until
+/// it returns.
+pub struct LocalAllocConfig {
+    require_huge_pages: bool,
+    numa_node: Option<u32>,
+    max_bytes: Option<usize>,
+    pressure_thresholds: Vec<f64>,
+    on_memory_pressure: Option<PressureHook>,
+}
+
+impl Default for LocalAllocConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LocalAllocConfig {
+    pub fn new() -> Self {
+        Self {
+            require_huge_pages: false,
+            numa_node: None,
+            max_bytes: None,
+            pressure_thresholds: vec![0.75, 0.9],
+            on_memory_pressure: None,
         }
     }
+
+    /// With explicit huge pages (LOCAL_ALLOC_HUGE_PAGE_SIZE is set), fail allocations that need a new page if it can't
+    /// be mapped as a huge page, e.g. because there is no hugetlb pool. By default the allocator falls back to regular
+    /// pages and logs a warning the first time it happens.
+    pub fn require_huge_pages(mut self, require: bool) -> Self {
+        self.require_huge_pages = require;
+        self
+    }
+
+    /// Binds the pages allocated after the config is applied to this NUMA node, so a thread that runs on the node
+    /// doesn't access its memory across nodes. Pages that can't be bound are still used, the failure is logged.
+    pub fn numa_node(mut self, node: u32) -> Self {
+        self.numa_node = Some(node);
+        self
+    }
+
+    /// Sets [LocalAllocConfig::numa_node] to the node of the CPUs the current thread is pinned to, e.g. with
+    /// `sched_setaffinity`. Nothing changes if the thread can run on CPUs of more than one node.
+    pub fn pinned_numa_node(mut self) -> Self {
+        match numa::node_of_affinity() {
+            Ok(Some(node)) => self.numa_node = Some(node),
+            Ok(None) => {
+                log::debug!("thread isn't pinned to a single numa node, not binding memory")
+            }
+            Err(e) => log::debug!("failed to find the numa node of the thread: {}", e),
+        }
+        self
+    }
+
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Fractions of `max_bytes` that trigger the memory pressure callback, 0.75 and 0.9 by default.
+    pub fn pressure_thresholds(mut self, thresholds: &[f64]) -> Self {
+        assert!(
+            thresholds.iter().all(|t| (0.0..=1.0).contains(t)),
+            "thresholds have to be between 0 and 1"
+        );
+        let mut thresholds = thresholds.to_vec();
+        thresholds.sort_by(f64::total_cmp);
+        self.pressure_thresholds = thresholds;
+        self
+    }
+
+    pub fn on_memory_pressure<F: FnMut(&MemoryPressure) + 'static>(mut self, f: F) -> Self {
+        self.on_memory_pressure = Some(Box::new(f));
+        self
+    }
+
+    /// Applies the config to the allocator of the current thread, replacing the previous config.
+    pub fn apply(self) {
+        STATE.with_borrow_mut(|state| {
+            state.require_huge_pages = self.require_huge_pages;
+            state.numa_node = self.numa_node;
+            state.max_bytes = self.max_bytes;
+            state.pressure_thresholds = self.pressure_thresholds;
+            state.pressure_level = 0;
+            state.pending_pressure = None;
+            state.update_pressure_level();
+            // Usage that is already over thresholds isn't reported.
+            state.pending_pressure = None;
+        });
+        PRESSURE_HOOK.with_borrow_mut(|hook| *hook = self.on_memory_pressure);
+    }
+}
+
+/// Passed to the memory pressure callback, see [LocalAllocConfig].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemoryPressure {
+    /// Memory reserved from the system when the event happened.
+    pub reserved_bytes: usize,
+    pub max_bytes: usize,
+    /// The threshold that was crossed as a fraction of `max_bytes`, 1.0 means an allocation is about to fail because of
+    /// the limit.
+    pub threshold: f64,
+}
+
+fn call_pressure_hook() {
+    let Some(pressure) = STATE.with_borrow_mut(|state| state.pending_pressure.take()) else {
+        return;
+    };
+    // The hook is taken out while it runs so allocations it makes don't call it again.
+    let hook = PRESSURE_HOOK.with_borrow_mut(|hook| hook.take());
+    if let Some(mut hook) = hook {
+        hook(&pressure);
+        PRESSURE_HOOK.with_borrow_mut(|slot| {
+            // The hook might have applied a new config while it was running.
+            if slot.is_none() {
+                *slot = Some(hook);
+            }
+        });
+    }
 }
 
 #[derive(Clone, Copy)]
 struct Page {
+    id: u64,
     ptr: *mut u8,
     size: usize,
+    // Allocated with regular pages because explicit huge pages couldn't be mapped.
+    fallback: bool,
 }
 
 #[derive(Clone, Copy)]
@@ -68,6 +327,12 @@ struct FreeRange {
     len: usize,
 }
 
+impl FreeRange {
+    fn key(&self) -> (usize, usize) {
+        (self.len, self.start as usize)
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct LocalAlloc {
     _non_send: PhantomData<*mut ()>,
@@ -88,143 +353,437 @@ unsafe impl Allocator for LocalAlloc {
             return Err(AllocError);
         }
 
-        STATE.with_borrow_mut(|state| {
-            for free_ranges in state.free_list.iter_mut() {
-                let mut found = None;
-                for (idx, range) in free_ranges.iter_mut().enumerate() {
-                    let start = range.start.align_offset(layout.align());
-                    if range.len >= start + layout.size() {
-                        if start == 0 && layout.size() == range.len {
-                            found = Some((
-                                idx,
-                                NonNull::slice_from_raw_parts(
-                                    NonNull::new(range.start).unwrap(),
-                                    layout.size(),
-                                ),
-                                (None, None),
-                            ));
-                        } else {
-                            let mut new_ranges = (None, None);
-                            unsafe {
-                                if start == 0 {
-                                    new_ranges.0 = Some(FreeRange {
-                                        start: range.start.add(layout.size()),
-                                        len: range.len - layout.size(),
-                                    });
-                                } else {
-                                    new_ranges.0 = Some(FreeRange {
-                                        start: range.start.add(start),
-                                        len: start,
-                                    });
-                                    if start + layout.size() < range.len {
-                                        let offset = start + layout.size();
-                                        new_ranges.1 = Some(FreeRange {
-                                            start: range.start.add(offset),
-                                            len: range.len - offset,
-                                        });
-                                    }
-                                }
-                            }
-                            found = Some((
-                                idx,
-                                NonNull::slice_from_raw_parts(
-                                    unsafe { NonNull::new(range.start.add(start)).unwrap() },
-                                    layout.size(),
-                                ),
-                                new_ranges,
-                            ));
-                        }
-
-                        break;
-                    }
-                }
-                if let Some((idx, allocated_slice, new_ranges)) = found {
-                    free_ranges.swap_remove(idx);
-                    if let Some(x) = new_ranges.0 {
-                        free_ranges.push(x);
+        let res = STATE.with_borrow_mut(|state| allocate_in(state, layout));
+        call_pressure_hook();
+        match res {
+            Err(AllocOutcome::LimitReached) => {
+                // The pressure callback might have freed enough memory.
+                let res = STATE.with_borrow_mut(|state| {
+                    let res = allocate_in(state, layout);
+                    if let Err(AllocOutcome::LimitReached) = res {
+                        // The callback was already told about this allocation.
+                        state.pending_pressure = None;
                     }
-                    if let Some(x) = new_ranges.1 {
-                        free_ranges.push(x);
-                    }
-                    return Ok(allocated_slice);
-                }
+                    res
+                });
+                call_pressure_hook();
+                res.map_err(|_| AllocError)
             }
-
-            let page = unsafe {
-                match (state.alloc)(layout.size()) {
-                    Ok(mut page) => page.as_mut(),
-                    Err(e) => {
-                        log::trace!("failed to allocate a page: {}", e);
-                        return Err(AllocError);
-                    }
-                }
-            };
-            let page = Page {
-                ptr: page.as_mut_ptr(),
-                size: page.len(),
-            };
-            let free_range = FreeRange {
-                start: unsafe { page.ptr.add(layout.size()) },
-                len: page.size.checked_sub(layout.size()).unwrap(),
-            };
-            let mut free_ranges = Vec::with_capacity(16);
-            free_ranges.push(free_range);
-
-            state.pages.push(page);
-            state.free_list.push(free_ranges);
-
-            Ok(NonNull::slice_from_raw_parts(
-                NonNull::new(page.ptr).unwrap(),
-                layout.size(),
-            ))
-        })
+            res => res.map_err(|_| AllocError),
+        }
     }
 
     unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
         let ptr = ptr.as_ptr();
+        #[cfg(feature = "debug-alloc")]
+        if let Some(map_len) = debug::record_deallocation(ptr, layout) {
+            STATE.with_borrow_mut(|state| state.remove_reserved(map_len));
+            return;
+        }
         let size = layout.size();
-        let end_ptr = ptr.add(size.checked_add(1).unwrap());
+        let end_ptr = ptr.add(size);
 
         STATE.with_borrow_mut(|state| {
-            let (page_idx, &page) = state
-                .pages
-                .iter()
-                .enumerate()
-                .find(|(_, page)| page.ptr <= ptr && page.ptr.add(page.size) >= ptr.add(size))
-                .expect("bad deallocate, couldn't find the page that contains this allocation");
+            let page_idx = state.page_index(ptr, size).expect(
+                "bad deallocate, couldn't find the page that contains this allocation. Enable the debug-alloc \
+                 feature to see where it was allocated and freed",
+            );
+            let page = state.pages[page_idx];
             let free_ranges = state.free_list.get_mut(page_idx).unwrap();
+            let free_index = &mut state.free_index;
 
-            let mut found = false;
-            for free in free_ranges.iter_mut() {
-                if free.start == end_ptr {
-                    free.start = ptr;
-                    free.len += size;
-                    found = true;
+            // Free ranges are sorted by address and never adjacent, so only the ranges right before and after the
+            // allocation can be merged with it.
+            let idx = free_ranges.partition_point(|free| free.start < ptr);
+            let prev_end = idx
+                .checked_sub(1)
+                .map(|i| free_ranges[i].start.add(free_ranges[i].len));
+            let next_start = free_ranges.get(idx).map(|free| free.start);
+            assert!(
+                prev_end.map_or(true, |end| end <= ptr)
+                    && next_start.map_or(true, |start| start >= end_ptr),
+                "bad deallocate, part of the memory is already free"
+            );
+            let merged = match (prev_end == Some(ptr), next_start == Some(end_ptr)) {
+                (true, true) => {
+                    free_index.remove(&free_ranges[idx - 1].key());
+                    free_index.remove(&free_ranges[idx].key());
+                    free_ranges[idx - 1].len += size + free_ranges[idx].len;
+                    free_ranges.remove(idx);
+                    free_ranges[idx - 1]
                 }
-
-                if free.start.add(free.len.checked_add(1).unwrap()) == ptr {
-                    free.len += size;
-                    found = true;
+                (true, false) => {
+                    free_index.remove(&free_ranges[idx - 1].key());
+                    free_ranges[idx - 1].len += size;
+                    free_ranges[idx - 1]
                 }
+                (false, true) => {
+                    free_index.remove(&free_ranges[idx].key());
+                    free_ranges[idx].start = ptr;
+                    free_ranges[idx].len += size;
+                    free_ranges[idx]
+                }
+                (false, false) => {
+                    let range = FreeRange {
+                        start: ptr,
+                        len: size,
+                    };
+                    free_ranges.insert(idx, range);
+                    range
+                }
+            };
+
+            if merged.start == page.ptr && merged.len == page.size {
+                state.pages.remove(page_idx);
+                state.free_list.remove(page_idx);
+                state.remove_reserved(page.size);
+                unsafe { (state.free)(page.ptr, page.size).expect("free a page") };
+            } else {
+                free_index.insert(merged.key());
             }
+        })
+    }
+}
 
-            if !found {
-                free_ranges.push(FreeRange {
-                    start: ptr,
-                    len: size,
-                });
+enum AllocOutcome {
+    Failed,
+    // Allocating would go over LocalAllocConfig::max_bytes.
+    LimitReached,
+}
+
+fn allocate_in(state: &mut State, layout: Layout) -> Result<NonNull<[u8]>, AllocOutcome> {
+    #[cfg(feature = "debug-alloc")]
+    if let Some(map_len) = debug::guarded_len(layout) {
+        // Guarded mappings count against max_bytes like pages do.
+        state.check_limit(map_len)?;
+        if let Some(ptr) = debug::allocate_guarded(layout, map_len) {
+            state.add_reserved(map_len);
+            return Ok(ptr);
+        }
+    }
+
+    let ptr = allocate_from_pages(state, layout)?;
+    #[cfg(feature = "debug-alloc")]
+    debug::record_allocation(ptr.as_ptr() as *mut u8, layout, None);
+    Ok(ptr)
+}
+
+fn allocate_from_pages(state: &mut State, layout: Layout) -> Result<NonNull<[u8]>, AllocOutcome> {
+    // Smallest range that fits the allocation after aligning its start, larger ranges are only split when no
+    // smaller one fits so they stay available for large allocations.
+    let fit = state
+        .free_index
+        .range((layout.size(), 0)..)
+        .find(|&&(len, start)| {
+            start.next_multiple_of(layout.align()) - start + layout.size() <= len
+        })
+        .copied();
+    if let Some((len, start_addr)) = fit {
+        state.free_index.remove(&(len, start_addr));
+        let page_idx = state
+            .pages
+            .partition_point(|page| page.ptr as usize <= start_addr)
+            - 1;
+        let free_ranges = &mut state.free_list[page_idx];
+        let idx = free_ranges.partition_point(|free| (free.start as usize) < start_addr);
+        let range = free_ranges[idx];
+        let start = range.start.align_offset(layout.align());
+        let end = start + layout.size();
+        // The parts of the range before and after the allocation stay free, at the same place in the sorted list.
+        unsafe {
+            let after = FreeRange {
+                start: range.start.add(end),
+                len: range.len - end,
+            };
+            match (start > 0, end < range.len) {
+                (false, false) => {
+                    free_ranges.remove(idx);
+                }
+                (false, true) => free_ranges[idx] = after,
+                (true, false) => free_ranges[idx].len = start,
+                (true, true) => {
+                    free_ranges[idx].len = start;
+                    free_ranges.insert(idx + 1, after);
+                }
             }
+            if start > 0 {
+                state.free_index.insert((start, start_addr));
+            }
+            if end < range.len {
+                state.free_index.insert(after.key());
+            }
+            return Ok(NonNull::slice_from_raw_parts(
+                NonNull::new(range.start.add(start)).unwrap(),
+                layout.size(),
+            ));
+        }
+    }
 
-            if free_ranges.len() == 1 {
-                let range = free_ranges.first().unwrap();
-                if range.start == page.ptr && range.len == page.size {
-                    state.pages.swap_remove(page_idx);
-                    state.free_list.swap_remove(page_idx);
-                    unsafe { (state.free)(page.ptr, page.size).expect("free a page") };
+    let page_size = layout.size().next_multiple_of(state.page_size);
+    state.check_limit(page_size)?;
+
+    let mut fallback = false;
+    let page = unsafe {
+        match (state.alloc)(layout.size()) {
+            Ok(mut page) => page.as_mut(),
+            Err(e) if state.explicit_huge_pages && !state.require_huge_pages => {
+                if !HUGE_PAGE_FALLBACK_WARNED.swap(true, Ordering::Relaxed) {
+                    log::warn!(
+                        "failed to map explicit huge pages, falling back to regular pages. Configure a hugetlb pool or \
+                         unset {} to get rid of this warning: {}",
+                        HUGE_PAGE_SIZE_ENV_VAR_NAME,
+                        e
+                    );
+                }
+                // Freed with munmap like the huge pages.
+                match alloc_fallback(page_size) {
+                    Ok(mut page) => {
+                        fallback = true;
+                        page.as_mut()
+                    }
+                    Err(e) => {
+                        log::trace!("failed to allocate a page: {}", e);
+                        return Err(AllocOutcome::Failed);
+                    }
+                }
+            }
+            Err(e) => {
+                if state.explicit_huge_pages {
+                    log::error!("failed to map explicit huge pages: {}", e);
+                } else {
+                    log::trace!("failed to allocate a page: {}", e);
                 }
+                return Err(AllocOutcome::Failed);
             }
+        }
+    };
+    let page = Page {
+        id: state.next_page_id,
+        ptr: page.as_mut_ptr(),
+        size: page.len(),
+        fallback,
+    };
+    if let Some(node) = state.numa_node {
+        if let Err(e) = unsafe { numa::bind(page.ptr, page.size, node) } {
+            log::debug!("failed to bind a page to numa node {}: {}", node, e);
+        }
+    }
+    let free_range = FreeRange {
+        start: unsafe { page.ptr.add(layout.size()) },
+        len: page.size.checked_sub(layout.size()).unwrap(),
+    };
+    let mut free_ranges = Vec::with_capacity(16);
+    if free_range.len > 0 {
+        free_ranges.push(free_range);
+        state.free_index.insert(free_range.key());
+    }
+
+    state.next_page_id += 1;
+    let page_idx = state.pages.partition_point(|p| p.ptr < page.ptr);
+    state.pages.insert(page_idx, page);
+    state.free_list.insert(page_idx, free_ranges);
+    state.add_reserved(page.size);
+
+    Ok(NonNull::slice_from_raw_parts(
+        NonNull::new(page.ptr).unwrap(),
+        layout.size(),
+    ))
+}
+
+/// Allocates a zeroed buffer of `len` bytes aligned to `align` from the allocator of the current thread, e.g. for
+/// O_DIRECT reads and writes that need 512 or 4096 byte aligned memory.
+///
+/// Fails if `len` is zero or `align` is larger than 2MB. Panics if `align` isn't a power of two.
+pub fn alloc_aligned(len: usize, align: usize) -> Result<IoBuffer<LocalAlloc>, AllocError> {
+    assert!(align.is_power_of_two(), "alignment must be a power of two");
+    let layout = Layout::from_size_align(len, align).map_err(|_| AllocError)?;
+    IoBuffer::new(layout, LocalAlloc::new())
+}
+
+/// Memory usage of the allocator of the current thread.
+#[derive(Clone, Copy, Debug)]
+pub struct Stats {
+    /// Number of pages allocated from the system.
+    pub num_pages: usize,
+    /// Total size of the pages.
+    pub reserved_bytes: usize,
+    /// Part of the pages that isn't allocated.
+    pub free_bytes: usize,
+    /// Number of separate free ranges in the pages, free memory is more fragmented the higher this is.
+    pub num_free_ranges: usize,
+}
+
+pub fn stats() -> Stats {
+    STATE.with_borrow(|state| Stats {
+        num_pages: state.pages.len(),
+        reserved_bytes: state.pages.iter().map(|page| page.size).sum(),
+        free_bytes: state
+            .free_list
+            .iter()
+            .flat_map(|ranges| ranges.iter())
+            .map(|range| range.len)
+            .sum(),
+        num_free_ranges: state.free_list.iter().map(|ranges| ranges.len()).sum(),
+    })
+}
+
+/// How much of the memory of the allocator of the current thread is backed by huge pages.
+#[derive(Clone, Debug)]
+pub struct HugePageStats {
+    /// Size of the pages allocated from the system.
+    pub reserved_bytes: usize,
+    /// Part of `reserved_bytes` that is backed by huge pages.
+    pub huge_page_bytes: usize,
+    /// Pages are allocated as explicit huge pages (LOCAL_ALLOC_HUGE_PAGE_SIZE is set), so all of them are huge pages
+    /// except for the fallback pages.
+    pub explicit: bool,
+    /// Part of `reserved_bytes` that was allocated with regular pages because explicit huge pages couldn't be mapped.
+    pub fallback_bytes: usize,
+    /// Mode of transparent huge pages, e.g. `always`, `madvise` or `never`. `None` if the kernel doesn't support THP.
+    pub thp_mode: Option<String>,
+}
+
+/// Reports how much of the allocator's memory is backed by huge pages.
+///
+/// Pages are allocated 2MB aligned and marked with MADV_HUGEPAGE but the kernel only backs them with transparent huge
+/// pages if THP is enabled and it finds free huge pages, this shows if that worked. It reads /proc/self/smaps, so it
+/// shouldn't be called frequently. Memory that wasn't touched yet isn't backed by any page.
+pub fn huge_page_stats() -> io::Result<HugePageStats> {
+    let (pages, explicit) = STATE.with_borrow(|state| {
+        let pages = state
+            .pages
+            .iter()
+            .map(|page| {
+                (
+                    page.ptr as usize,
+                    page.ptr as usize + page.size,
+                    page.fallback,
+                )
+            })
+            .collect::<Vec<_>>();
+        (pages, state.explicit_huge_pages)
+    });
+    let reserved_bytes = pages
+        .iter()
+        .map(|(start, end, _)| end - start)
+        .sum::<usize>();
+    // Fallback pages are regular pages that might be backed by transparent huge pages.
+    let thp_pages = pages
+        .iter()
+        .filter(|&&(_, _, fallback)| !explicit || fallback)
+        .map(|&(start, end, _)| (start, end))
+        .collect::<Vec<_>>();
+    let fallback_bytes = if explicit {
+        thp_pages.iter().map(|(start, end)| end - start).sum()
+    } else {
+        0
+    };
+    let thp_mode = match std::fs::read_to_string("/sys/kernel/mm/transparent_hugepage/enabled") {
+        Ok(modes) => parse_thp_mode(&modes),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    let mut huge_page_bytes = if explicit {
+        reserved_bytes - fallback_bytes
+    } else {
+        0
+    };
+    if !thp_pages.is_empty() {
+        huge_page_bytes +=
+            anon_huge_page_bytes(&std::fs::read_to_string("/proc/self/smaps")?, &thp_pages);
+    }
+    Ok(HugePageStats {
+        reserved_bytes,
+        huge_page_bytes,
+        explicit,
+        fallback_bytes,
+        thp_mode,
+    })
+}
+
+// The active mode is the one in brackets, e.g. `always [madvise] never`.
+fn parse_thp_mode(modes: &str) -> Option<String> {
+    let start = modes.find('[')? + 1;
+    let end = start + modes[start..].find(']')?;
+    Some(modes[start..end].to_owned())
+}
+
+// Sums AnonHugePages of the mappings that overlap the pages. A mapping can be larger than the pages in it since the
+// kernel merges adjacent mappings, so each mapping counts at most the bytes it shares with the pages.
+fn anon_huge_page_bytes(smaps: &str, pages: &[(usize, usize)]) -> usize {
+    let mut total = 0;
+    let mut overlap = 0;
+    for line in smaps.lines() {
+        let mut fields = line.split_whitespace();
+        let Some(first) = fields.next() else {
+            continue;
+        };
+        if let Some((start, end)) = first.split_once('-').and_then(|(start, end)| {
+            Some((
+                usize::from_str_radix(start, 16).ok()?,
+                usize::from_str_radix(end, 16).ok()?,
+            ))
+        }) {
+            // Header line of a new mapping.
+            overlap = pages
+                .iter()
+                .map(|&(page_start, page_end)| {
+                    page_end.min(end).saturating_sub(page_start.max(start))
+                })
+                .sum();
+        } else if first == "AnonHugePages:" && overlap > 0 {
+            let kb = fields
+                .next()
+                .and_then(|kb| kb.parse::<usize>().ok())
+                .unwrap_or(0);
+            total += (kb * 1024).min(overlap);
+        }
+    }
+    total
+}
+
+/// A page of the allocator of the current thread, see [find_page].
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PageInfo {
+    pub(crate) id: u64,
+    pub(crate) ptr: *mut u8,
+    pub(crate) size: usize,
+}
+
+/// Returns the page that contains all of `ptr..ptr + len`, if the memory was allocated from this thread's allocator.
+pub(crate) fn find_page(ptr: *const u8, len: usize) -> Option<PageInfo> {
+    STATE.with_borrow(|state| {
+        let page = state.pages[state.page_index(ptr, len)?];
+        Some(PageInfo {
+            id: page.id,
+            ptr: page.ptr,
+            size: page.size,
         })
+    })
+}
+
+/// Pins the current thread to `cpu` and binds the pages allocated after this to the NUMA node of the cpu, see
+/// [ExecutorConfig::pin_to_cpu](crate::executor::ExecutorConfig::pin_to_cpu).
+pub(crate) fn pin_to_cpu(cpu: usize) -> io::Result<()> {
+    numa::pin_to_cpu(cpu)?;
+    match numa::node_of_cpu(cpu) {
+        Ok(node) => STATE.with_borrow_mut(|state| state.numa_node = Some(node)),
+        Err(e) => log::debug!("failed to find the numa node of cpu {}: {}", cpu, e),
     }
+    Ok(())
+}
+
+/// Returns the largest amount of memory that was reserved from the system since the last call, and starts tracking
+/// from the current amount.
+pub(crate) fn take_peak_reserved_bytes() -> usize {
+    STATE.with_borrow_mut(|state| {
+        std::mem::replace(&mut state.peak_reserved_bytes, state.reserved_bytes)
+    })
+}
+
+/// Returns true if the page with this id wasn't freed yet.
+pub(crate) fn page_exists(id: u64) -> bool {
+    STATE.with_borrow(|state| state.pages.iter().any(|page| page.id == id))
 }
 
 unsafe fn alloc_2mb(size: usize) -> io::Result<NonNull<[u8]>> {
@@ -232,25 +791,13 @@ unsafe fn alloc_2mb(size: usize) -> io::Result<NonNull<[u8]>> {
     let mut ptr = std::ptr::null_mut();
     match libc::posix_memalign(&mut ptr, TWO_MB, size) {
         0 => {
-            match libc::madvise(ptr, size, libc::MADV_HUGEPAGE) {
-                0 => (),
-                -1 => {
-                    let errno = *libc::__errno_location();
-                    let err = std::io::Error::from_raw_os_error(errno);
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        format!("failed to madvise: {}", err),
-                    ));
-                }
-                x => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        format!(
-                            "unexpected return value from madvise: {}. Expected 0 or -1",
-                            x
-                        ),
-                    ));
-                }
+            // Memory is still usable without huge pages, e.g. if THP is disabled in the kernel. huge_page_stats shows
+            // how much of it ended up backed by huge pages.
+            if libc::madvise(ptr, size, libc::MADV_HUGEPAGE) != 0 {
+                log::debug!(
+                    "failed to madvise(MADV_HUGEPAGE): {}",
+                    io::Error::last_os_error()
+                );
             }
             Ok(NonNull::slice_from_raw_parts(
                 NonNull::new(ptr as *mut u8).unwrap(),
@@ -274,6 +821,29 @@ unsafe fn alloc_1gb_explicit(size: usize) -> io::Result<NonNull<[u8]>> {
     mmap_wrapper(size, libc::MAP_HUGE_1GB | libc::MAP_HUGETLB)
 }
 
+// Maps regular pages, 2MB aligned so they can be backed by transparent huge pages.
+unsafe fn alloc_fallback(size: usize) -> io::Result<NonNull<[u8]>> {
+    let map = mmap_wrapper(size + TWO_MB, 0)?.cast::<u8>().as_ptr();
+    let offset = map.align_offset(TWO_MB);
+    if offset > 0 {
+        munmap_wrapper(map, offset)?;
+    }
+    let ptr = map.add(offset);
+    if TWO_MB - offset > 0 {
+        munmap_wrapper(ptr.add(size), TWO_MB - offset)?;
+    }
+    if libc::madvise(ptr as *mut libc::c_void, size, libc::MADV_HUGEPAGE) != 0 {
+        log::debug!(
+            "failed to madvise(MADV_HUGEPAGE): {}",
+            io::Error::last_os_error()
+        );
+    }
+    Ok(NonNull::slice_from_raw_parts(
+        NonNull::new(ptr).unwrap(),
+        size,
+    ))
+}
+
 unsafe fn mmap_wrapper(len: usize, huge_page_flag: libc::c_int) -> io::Result<NonNull<[u8]>> {
     match libc::mmap(
         std::ptr::null_mut(),
@@ -327,14 +897,298 @@ unsafe fn free_wrapper(ptr: *mut u8, _length: usize) -> io::Result<()> {
     Ok(())
 }
 
+#[cfg(miri)]
+unsafe fn alloc_global(size: usize) -> io::Result<NonNull<[u8]>> {
+    let size = size.next_multiple_of(TWO_MB);
+    let layout = Layout::from_size_align(size, TWO_MB).unwrap();
+    match NonNull::new(std::alloc::alloc(layout)) {
+        Some(ptr) => Ok(NonNull::slice_from_raw_parts(ptr, size)),
+        None => Err(io::Error::from(io::ErrorKind::OutOfMemory)),
+    }
+}
+
+#[cfg(miri)]
+unsafe fn free_global(ptr: *mut u8, length: usize) -> io::Result<()> {
+    std::alloc::dealloc(ptr, Layout::from_size_align(length, TWO_MB).unwrap());
+    Ok(())
+}
+
 const ONE_GB: usize = 1024 * 1024 * 1024;
 const TWO_MB: usize = 2 * 1024 * 1024;
 const HUGE_PAGE_SIZE_ENV_VAR_NAME: &str = "LOCAL_ALLOC_HUGE_PAGE_SIZE";
 
+// The fallback warning is logged once per process.
+static HUGE_PAGE_FALLBACK_WARNED: AtomicBool = AtomicBool::new(false);
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    // Large allocations don't come from pages with debug-alloc.
+    #[cfg(not(feature = "debug-alloc"))]
+    fn test_memory_pressure() {
+        use std::rc::Rc;
+
+        // Runs on its own thread so it gets a fresh allocator.
+        std::thread::spawn(|| {
+            let events = Rc::new(RefCell::new(Vec::new()));
+            // Stands in for a cache that sheds its entries when the limit is hit.
+            let cache = Rc::new(RefCell::new(None));
+            LocalAllocConfig::new()
+                .max_bytes(8 * TWO_MB)
+                .pressure_thresholds(&[0.5])
+                .on_memory_pressure({
+                    let events = events.clone();
+                    let cache = cache.clone();
+                    move |pressure| {
+                        events.borrow_mut().push(*pressure);
+                        if pressure.threshold == 1.0 {
+                            cache.borrow_mut().take();
+                        }
+                    }
+                })
+                .apply();
+
+            *cache.borrow_mut() = Some(Vec::<u8, _>::with_capacity_in(
+                3 * TWO_MB,
+                LocalAlloc::new(),
+            ));
+            assert!(events.borrow().is_empty());
+            let b = Vec::<u8, _>::with_capacity_in(3 * TWO_MB, LocalAlloc::new());
+            assert_eq!(
+                events.borrow().as_slice(),
+                &[MemoryPressure {
+                    reserved_bytes: 6 * TWO_MB,
+                    max_bytes: 8 * TWO_MB,
+                    threshold: 0.5,
+                }]
+            );
+
+            // Goes over the limit, succeeds after the cache is dropped by the callback. Usage dropped below the
+            // threshold so crossing it again is reported again.
+            let c = Vec::<u8, _>::with_capacity_in(3 * TWO_MB, LocalAlloc::new());
+            let thresholds = events
+                .borrow()
+                .iter()
+                .map(|e| e.threshold)
+                .collect::<Vec<_>>();
+            assert_eq!(thresholds, [0.5, 1.0, 0.5]);
+            assert!(cache.borrow().is_none());
+            assert_eq!(stats().reserved_bytes, 6 * TWO_MB);
+
+            let mut d = Vec::<u8, _>::new_in(LocalAlloc::new());
+            assert!(d.try_reserve(3 * TWO_MB).is_err());
+            assert_eq!(events.borrow().len(), 4);
+            drop((b, c));
+            assert_eq!(stats().reserved_bytes, 0);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_free_range_coalescing() {
+        std::thread::spawn(|| {
+            let alloc = LocalAlloc::new();
+            let layout = Layout::from_size_align(100, 8).unwrap();
+            let mut ptrs = (0..1000)
+                .map(|_| alloc.allocate(layout).unwrap().cast::<u8>())
+                .collect::<Vec<_>>();
+            assert_eq!(stats().num_pages, 1);
+
+            // Free every other allocation, each freed range only merges with the alignment gap next to it.
+            let mut rest = Vec::new();
+            for (i, ptr) in ptrs.drain(..).enumerate() {
+                if i % 2 == 0 {
+                    unsafe { alloc.deallocate(ptr, layout) };
+                } else {
+                    rest.push(ptr);
+                }
+            }
+            assert_eq!(stats().num_free_ranges, 501);
+            // Fits in one of the ranges in the middle of the page.
+            let small = Layout::from_size_align(90, 8).unwrap();
+            let reused = alloc.allocate(small).unwrap().cast::<u8>();
+            unsafe { alloc.deallocate(reused, small) };
+
+            // Free the rest in a scrambled order, every range gets merged back into the page.
+            let mut x = 88172645463325252u64;
+            while !rest.is_empty() {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                let ptr = rest.swap_remove(x as usize % rest.len());
+                unsafe { alloc.deallocate(ptr, layout) };
+                assert!(stats().num_free_ranges <= 501);
+            }
+            let stats = stats();
+            assert_eq!(
+                (stats.num_pages, stats.reserved_bytes, stats.num_free_ranges),
+                (0, 0, 0)
+            );
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_small_allocations_keep_large_ranges() {
+        std::thread::spawn(|| {
+            let alloc = LocalAlloc::new();
+            let small = Layout::from_size_align(64, 8).unwrap();
+            let large = Layout::from_size_align(64 * 1024, 8).unwrap();
+            let mut ptrs = Vec::new();
+            for _ in 0..16 {
+                ptrs.push((
+                    alloc.allocate(small).unwrap().cast::<u8>(),
+                    alloc.allocate(large).unwrap().cast::<u8>(),
+                ));
+            }
+            assert_eq!(stats().num_pages, 1);
+
+            // The lowest free range is large, the small ones come after it.
+            let large_hole = ptrs[0].1;
+            unsafe { alloc.deallocate(large_hole, large) };
+            let small_holes = [3, 5, 7, 9].map(|i| ptrs[i].0);
+            for ptr in small_holes {
+                unsafe { alloc.deallocate(ptr, small) };
+            }
+            assert_eq!(stats().num_free_ranges, 6);
+
+            // Small allocations fill the small ranges instead of splitting the first range that fits.
+            let mut reused = (0..4)
+                .map(|_| alloc.allocate(small).unwrap().cast::<u8>())
+                .collect::<Vec<_>>();
+            reused.sort();
+            assert_eq!(reused, small_holes);
+            assert_eq!(alloc.allocate(large).unwrap().cast::<u8>(), large_hole);
+            let stats = stats();
+            assert_eq!((stats.num_pages, stats.num_free_ranges), (1, 1));
+
+            for (a, b) in ptrs {
+                unsafe {
+                    alloc.deallocate(a, small);
+                    alloc.deallocate(b, large);
+                }
+            }
+            assert_eq!(super::stats().num_pages, 0);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    // Reads /proc and maps memory, which Miri doesn't support.
+    #[cfg_attr(miri, ignore)]
+    fn test_huge_page_stats() {
+        let smaps = "\
+00400000-00600000 rw-p 00000000 00:00 0
+Size:               2048 kB
+AnonHugePages:      2048 kB
+7f0000000000-7f0000800000 rw-p 00000000 00:00 0
+AnonHugePages:      6144 kB
+";
+        let pages = [(0x7f0000200000, 0x7f0000600000)];
+        assert_eq!(anon_huge_page_bytes(smaps, &pages), 4 * 1024 * 1024);
+        assert_eq!(
+            parse_thp_mode("always [madvise] never\n").as_deref(),
+            Some("madvise")
+        );
+
+        std::thread::spawn(|| {
+            let mut v = Vec::<u8, _>::with_capacity_in(4 * TWO_MB, LocalAlloc::new());
+            v.resize(4 * TWO_MB, 1);
+            let stats = huge_page_stats().unwrap();
+            assert_eq!(stats.reserved_bytes, super::stats().reserved_bytes);
+            assert!(stats.huge_page_bytes <= stats.reserved_bytes);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    // Allocations are guarded instead of coming from pages with debug-alloc.
+    #[cfg(not(feature = "debug-alloc"))]
+    fn test_alignment_padding_stays_free() {
+        std::thread::spawn(|| {
+            let alloc = LocalAlloc::new();
+            let small = Layout::from_size_align(16, 8).unwrap();
+            let aligned = Layout::from_size_align(4096, 4096).unwrap();
+            let a = alloc.allocate(small).unwrap().cast::<u8>();
+            // Needs padding after `a` to get to the next 4096 byte boundary.
+            let b = alloc.allocate(aligned).unwrap().cast::<u8>();
+            assert_eq!(b.as_ptr().align_offset(4096), 0);
+
+            // The padding is reused, but none of the aligned allocation is handed out again.
+            let b_range = b.as_ptr() as usize..b.as_ptr() as usize + aligned.size();
+            let rest = (0..16)
+                .map(|_| alloc.allocate(small).unwrap().cast::<u8>())
+                .collect::<Vec<_>>();
+            for ptr in rest.iter() {
+                let start = ptr.as_ptr() as usize;
+                assert!(start + small.size() <= b_range.start || start >= b_range.end);
+            }
+            assert!(rest
+                .iter()
+                .any(|ptr| (ptr.as_ptr() as usize) < b_range.start));
+
+            for ptr in rest {
+                unsafe { alloc.deallocate(ptr, small) };
+            }
+            unsafe {
+                alloc.deallocate(b, aligned);
+                alloc.deallocate(a, small);
+            }
+            assert_eq!(stats().num_pages, 0);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_alloc_aligned() {
+        for align in [512, 4096, TWO_MB] {
+            let mut buf = alloc_aligned(1000, align).unwrap();
+            assert_eq!(buf.as_slice().as_ptr().align_offset(align), 0);
+            assert_eq!(buf.size(), 1000);
+            assert!(buf.as_slice().iter().all(|&b| b == 0));
+            buf.as_mut_slice().fill(3);
+        }
+        assert!(alloc_aligned(0, 512).is_err());
+        assert!(alloc_aligned(10, 2 * TWO_MB).is_err());
+    }
+
+    #[test]
+    // Reads /proc and maps memory, which Miri doesn't support.
+    #[cfg_attr(miri, ignore)]
+    fn test_huge_page_fallback() {
+        unsafe fn no_hugetlb_pool(_size: usize) -> io::Result<NonNull<[u8]>> {
+            Err(io::Error::from_raw_os_error(libc::ENOMEM))
+        }
+
+        std::thread::spawn(|| {
+            STATE.with_borrow_mut(|state| {
+                state.alloc = no_hugetlb_pool;
+                state.free = munmap_wrapper;
+                state.explicit_huge_pages = true;
+            });
+            let mut v = Vec::<u8, _>::with_capacity_in(1024, LocalAlloc::new());
+            v.resize(1024, 1);
+            let page = find_page(v.as_ptr(), v.len()).unwrap();
+            assert_eq!(page.ptr.align_offset(TWO_MB), 0);
+            let stats = huge_page_stats().unwrap();
+            assert_eq!(stats.fallback_bytes, TWO_MB);
+            drop(v);
+            assert_eq!(super::stats().num_pages, 0);
+
+            LocalAllocConfig::new().require_huge_pages(true).apply();
+            assert!(LocalAlloc::new().allocate(Layout::new::<u64>()).is_err());
+        })
+        .join()
+        .unwrap();
+    }
+
     #[test]
     #[ignore]
     fn check_thp_allocation() {
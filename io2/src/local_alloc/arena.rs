@@ -0,0 +1,210 @@
+//! Bump allocation for memory that is freed all at once.
+
+use std::alloc::{AllocError, Allocator, Layout};
+use std::cell::{Cell, RefCell};
+use std::ptr::NonNull;
+
+use super::LocalAlloc;
+
+const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+const CHUNK_ALIGN: usize = 64;
+
+struct Chunk {
+    ptr: NonNull<u8>,
+    size: usize,
+}
+
+/// Allocates from chunks of [LocalAlloc] memory by bumping an offset, all allocations are freed together with
+/// [LocalArena::reset] or when the arena is dropped.
+///
+/// This is meant for memory that is scoped to a request or a task, e.g. the temporaries of a parser, so they don't
+/// each go through the free list of LocalAlloc. `&LocalArena` implements [Allocator] so collections can be allocated
+/// in it, freeing a collection only gives its memory back if it was the last allocation.
+///
+/// Values allocated with [LocalArena::alloc] are not dropped, so they shouldn't own resources.
+pub struct LocalArena {
+    chunk_size: usize,
+    chunks: RefCell<Vec<Chunk, LocalAlloc>>,
+    // Chunk that allocations are made from, chunks after it are empty.
+    current: Cell<usize>,
+    // Offset of the free part of the current chunk.
+    offset: Cell<usize>,
+    allocated_bytes: Cell<usize>,
+}
+
+impl Default for LocalArena {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Every allocation returns new memory, so the mutable references handed out by a shared arena never alias.
+#[allow(clippy::mut_from_ref)]
+impl LocalArena {
+    pub fn new() -> Self {
+        Self::with_chunk_size(DEFAULT_CHUNK_SIZE)
+    }
+
+    /// Allocations that don't fit into a chunk of this size get a chunk of their own.
+    pub fn with_chunk_size(chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "chunk size has to be positive");
+        Self {
+            chunk_size,
+            chunks: RefCell::new(Vec::new_in(LocalAlloc::new())),
+            current: Cell::new(0),
+            offset: Cell::new(0),
+            allocated_bytes: Cell::new(0),
+        }
+    }
+
+    pub fn alloc<T>(&self, value: T) -> &mut T {
+        let ptr = self.bump(Layout::new::<T>()).cast::<T>();
+        unsafe {
+            ptr.as_ptr().write(value);
+            &mut *ptr.as_ptr()
+        }
+    }
+
+    pub fn alloc_slice_copy<T: Copy>(&self, src: &[T]) -> &mut [T] {
+        let ptr = self.bump(Layout::for_value(src)).cast::<T>().as_ptr();
+        unsafe {
+            std::ptr::copy_nonoverlapping(src.as_ptr(), ptr, src.len());
+            std::slice::from_raw_parts_mut(ptr, src.len())
+        }
+    }
+
+    pub fn alloc_str(&self, src: &str) -> &mut str {
+        let bytes = self.alloc_slice_copy(src.as_bytes());
+        unsafe { std::str::from_utf8_unchecked_mut(bytes) }
+    }
+
+    /// Bytes handed out since the arena was created or reset, including alignment padding.
+    pub fn allocated_bytes(&self) -> usize {
+        self.allocated_bytes.get()
+    }
+
+    /// Total size of the chunks, they are kept when the arena is reset.
+    pub fn reserved_bytes(&self) -> usize {
+        self.chunks.borrow().iter().map(|chunk| chunk.size).sum()
+    }
+
+    /// Frees all allocations, the chunks are reused by the allocations made after this.
+    pub fn reset(&mut self) {
+        self.current.set(0);
+        self.offset.set(0);
+        self.allocated_bytes.set(0);
+    }
+
+    fn bump(&self, layout: Layout) -> NonNull<u8> {
+        match self.try_bump(layout) {
+            Some(ptr) => ptr,
+            None => std::alloc::handle_alloc_error(layout),
+        }
+    }
+
+    fn try_bump(&self, layout: Layout) -> Option<NonNull<u8>> {
+        if layout.size() == 0 {
+            // Dangling but aligned, without making up a pointer from an integer.
+            return NonNull::new(std::ptr::null_mut::<u8>().wrapping_add(layout.align()));
+        }
+        let mut chunks = self.chunks.borrow_mut();
+        loop {
+            if let Some(chunk) = chunks.get(self.current.get()) {
+                let start = unsafe { chunk.ptr.as_ptr().add(self.offset.get()) };
+                let padding = start.align_offset(layout.align());
+                if padding + layout.size() <= chunk.size - self.offset.get() {
+                    let used = padding + layout.size();
+                    self.offset.set(self.offset.get() + used);
+                    self.allocated_bytes.set(self.allocated_bytes.get() + used);
+                    return NonNull::new(unsafe { start.add(padding) });
+                }
+                if self.current.get() + 1 < chunks.len() {
+                    self.current.set(self.current.get() + 1);
+                    self.offset.set(0);
+                    continue;
+                }
+            }
+
+            let size = self
+                .chunk_size
+                .max(layout.size().checked_add(layout.align())?);
+            let chunk_layout = Layout::from_size_align(size, CHUNK_ALIGN).ok()?;
+            let ptr = LocalAlloc::new().allocate(chunk_layout).ok()?.cast::<u8>();
+            // The new chunk goes right after the current one so the empty chunks stay after it.
+            let idx = if chunks.is_empty() {
+                0
+            } else {
+                self.current.get() + 1
+            };
+            chunks.insert(idx, Chunk { ptr, size });
+            self.current.set(idx);
+            self.offset.set(0);
+        }
+    }
+}
+
+impl Drop for LocalArena {
+    fn drop(&mut self) {
+        for chunk in self.chunks.borrow_mut().drain(..) {
+            unsafe {
+                LocalAlloc::new().deallocate(
+                    chunk.ptr,
+                    Layout::from_size_align(chunk.size, CHUNK_ALIGN).unwrap(),
+                )
+            };
+        }
+    }
+}
+
+unsafe impl Allocator for &LocalArena {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = self.try_bump(layout).ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        // Only the last allocation can be given back, e.g. a vector that is freed right after it grows.
+        let chunks = self.chunks.borrow();
+        if let Some(chunk) = chunks.get(self.current.get()) {
+            let end = chunk.ptr.as_ptr().add(self.offset.get());
+            if layout.size() > 0 && ptr.as_ptr().add(layout.size()) == end {
+                self.offset.set(self.offset.get() - layout.size());
+                self.allocated_bytes
+                    .set(self.allocated_bytes.get() - layout.size());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_arena() {
+        let mut arena = LocalArena::with_chunk_size(1024);
+        let x = arena.alloc(5u64);
+        let s = arena.alloc_str("hello");
+        *x += 1;
+        s.make_ascii_uppercase();
+        assert_eq!((*x, &*s), (6, "HELLO"));
+
+        let mut v = Vec::new_in(&arena);
+        v.extend(0..1000u32);
+        assert_eq!(v.iter().sum::<u32>(), 499500);
+        // The vector outgrew the chunk size so it got chunks of its own.
+        assert!(arena.reserved_bytes() >= 4000);
+        drop(v);
+
+        let reserved = arena.reserved_bytes();
+        arena.reset();
+        assert_eq!(arena.allocated_bytes(), 0);
+        for i in 0..100u64 {
+            assert_eq!(*arena.alloc(i), i);
+        }
+        let big = arena.alloc_slice_copy(&[7u8; 3000]);
+        assert!(big.iter().all(|&b| b == 7));
+        // Chunks from before the reset are reused.
+        assert_eq!(arena.reserved_bytes(), reserved);
+    }
+}
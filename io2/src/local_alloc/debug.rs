@@ -0,0 +1,294 @@
+//! Checks that are enabled with the `debug-alloc` feature.
+//!
+//! Every allocation is recorded with a backtrace of the place it was made, so a bad free can report where the memory
+//! came from. Freed memory is overwritten with [POISON] so reads of it are easy to spot. The backtraces of the last
+//! [QUARANTINE_LEN] frees are kept in a FIFO quarantine, so a double free that happens soon after the first one
+//! reports both frees without keeping a record of every free forever.
+//!
+//! Allocations of at least [GUARD_MIN_SIZE] bytes get their own mapping with an inaccessible page before and after it.
+//! The allocation is placed at the end of its pages so writing past it faults right away. The mapping is unmapped when
+//! the allocation is freed, so a use after free also faults. The mappings count against
+//! [LocalAllocConfig::max_bytes](super::LocalAllocConfig::max_bytes) like pages do.
+
+use std::alloc::Layout;
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::ptr::NonNull;
+
+/// Freed memory is filled with this byte.
+pub const POISON: u8 = 0xDE;
+
+/// Allocations of at least this size are surrounded by guard pages.
+pub const GUARD_MIN_SIZE: usize = 1024 * 1024;
+
+/// Number of frees that are remembered to report double frees.
+pub const QUARANTINE_LEN: usize = 4096;
+
+thread_local! {
+    static ALLOCS: RefCell<Allocs> = RefCell::new(Allocs {
+        live: HashMap::new(),
+        freed: HashMap::new(),
+        freed_order: VecDeque::new(),
+        next_free: 0,
+    });
+}
+
+struct Allocs {
+    live: HashMap<usize, Live>,
+    freed: HashMap<usize, Freed>,
+    // Addresses in `freed` with the number of their free, oldest first. An address can be in here more than once if
+    // it was allocated and freed again, only the entry with the number of the current record removes it.
+    freed_order: VecDeque<(usize, u64)>,
+    next_free: u64,
+}
+
+struct Live {
+    layout: Layout,
+    // Mapping that contains the allocation and its guard pages.
+    guarded: Option<(*mut u8, usize)>,
+    allocated_at: Backtrace,
+}
+
+struct Freed {
+    number: u64,
+    layout: Layout,
+    allocated_at: Backtrace,
+    freed_at: Backtrace,
+}
+
+/// Length of the mapping that holds the allocation and its guard pages, None if the allocation isn't guarded.
+pub(super) fn guarded_len(layout: Layout) -> Option<usize> {
+    let page_size = page_size();
+    if layout.size() < GUARD_MIN_SIZE || layout.align() > page_size {
+        return None;
+    }
+    Some(layout.size().next_multiple_of(page_size) + 2 * page_size)
+}
+
+/// Allocates a mapping of `map_len` bytes, from [guarded_len], with guard pages around the allocation.
+pub(super) fn allocate_guarded(layout: Layout, map_len: usize) -> Option<NonNull<[u8]>> {
+    let page_size = page_size();
+    let data_len = map_len - 2 * page_size;
+    let base = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            map_len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    if base == libc::MAP_FAILED {
+        log::trace!(
+            "failed to map guarded allocation: {}",
+            std::io::Error::last_os_error()
+        );
+        return None;
+    }
+    let base = base as *mut u8;
+    unsafe {
+        let tail_guard = base.add(page_size + data_len);
+        if libc::mprotect(base as *mut libc::c_void, page_size, libc::PROT_NONE) != 0
+            || libc::mprotect(tail_guard as *mut libc::c_void, page_size, libc::PROT_NONE) != 0
+        {
+            libc::munmap(base as *mut libc::c_void, map_len);
+            return None;
+        }
+    }
+    let offset = (data_len - layout.size()) / layout.align() * layout.align();
+    let ptr = unsafe { base.add(page_size + offset) };
+    record_allocation(ptr, layout, Some((base, map_len)));
+    Some(NonNull::slice_from_raw_parts(
+        NonNull::new(ptr).unwrap(),
+        layout.size(),
+    ))
+}
+
+// Thread locals that use LocalAlloc can be dropped after ALLOCS when the thread exits, allocations aren't checked
+// after that.
+fn with_allocs<R>(f: impl FnOnce(&mut Allocs) -> R) -> Option<R> {
+    ALLOCS.try_with(|allocs| f(&mut allocs.borrow_mut())).ok()
+}
+
+pub(super) fn record_allocation(ptr: *mut u8, layout: Layout, guarded: Option<(*mut u8, usize)>) {
+    let allocated_at = Backtrace::force_capture();
+    with_allocs(|allocs| {
+        allocs.freed.remove(&(ptr as usize));
+        allocs.live.insert(
+            ptr as usize,
+            Live {
+                layout,
+                guarded,
+                allocated_at,
+            },
+        );
+    });
+}
+
+/// Checks that `ptr` is a live allocation with this layout and poisons it, panics otherwise.
+///
+/// Returns the length of the mapping if the allocation was guarded, it is unmapped already and shouldn't be returned to
+/// its page.
+pub(super) unsafe fn record_deallocation(ptr: *mut u8, layout: Layout) -> Option<usize> {
+    let freed_at = Backtrace::force_capture();
+    let live = match with_allocs(|allocs| allocs.live.remove(&(ptr as usize))) {
+        Some(Some(live)) => live,
+        Some(None) => {
+            let msg = with_allocs(|allocs| {
+                match allocs.freed.get(&(ptr as usize)) {
+                Some(freed) => format!(
+                    "double free of {:?} ({} bytes)\nallocated at:\n{}\nfirst freed at:\n{}\nfreed again at:\n{}",
+                    ptr, freed.layout.size(), freed.allocated_at, freed.freed_at, freed_at
+                ),
+                None => format!(
+                    "invalid free of {:?} ({} bytes), it wasn't allocated by LocalAlloc on this thread\nfreed at:\n{}",
+                    ptr,
+                    layout.size(),
+                    freed_at
+                ),
+            }
+            });
+            panic!("{}", msg.unwrap());
+        }
+        None => {
+            std::ptr::write_bytes(ptr, POISON, layout.size());
+            return None;
+        }
+    };
+    if live.layout != layout {
+        panic!(
+            "free of {:?} with a different layout than it was allocated with, allocated with {:?} and freed with {:?}\nallocated at:\n{}\nfreed at:\n{}",
+            ptr, live.layout, layout, live.allocated_at, freed_at
+        );
+    }
+
+    let guarded = match live.guarded {
+        Some((base, map_len)) => {
+            libc::munmap(base as *mut libc::c_void, map_len);
+            Some(map_len)
+        }
+        None => {
+            std::ptr::write_bytes(ptr, POISON, layout.size());
+            None
+        }
+    };
+    with_allocs(|allocs| {
+        let number = allocs.next_free;
+        allocs.next_free += 1;
+        allocs.freed.insert(
+            ptr as usize,
+            Freed {
+                number,
+                layout,
+                allocated_at: live.allocated_at,
+                freed_at,
+            },
+        );
+        allocs.freed_order.push_back((ptr as usize, number));
+        if allocs.freed_order.len() > QUARANTINE_LEN {
+            let (addr, number) = allocs.freed_order.pop_front().unwrap();
+            if allocs.freed.get(&addr).is_some_and(|f| f.number == number) {
+                allocs.freed.remove(&addr);
+            }
+        }
+    });
+    guarded
+}
+
+fn page_size() -> usize {
+    usize::try_from(unsafe { libc::sysconf(libc::_SC_PAGESIZE) }).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::Allocator;
+
+    use super::*;
+    use crate::local_alloc::LocalAlloc;
+
+    #[test]
+    // Guard pages are mapped with mmap, which Miri doesn't support.
+    #[cfg_attr(miri, ignore)]
+    fn test_debug_alloc() {
+        let alloc = LocalAlloc::new();
+        // Keeps the page alive so the freed memory can be checked for the poison.
+        let _keep = Vec::<u8, _>::with_capacity_in(16, alloc);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let ptr = alloc.allocate(layout).unwrap().cast::<u8>();
+        unsafe { alloc.deallocate(ptr, layout) };
+        let poisoned = unsafe { std::slice::from_raw_parts(ptr.as_ptr(), 64) };
+        assert!(poisoned.iter().all(|&b| b == POISON));
+
+        let err =
+            std::panic::catch_unwind(|| unsafe { alloc.deallocate(ptr, layout) }).unwrap_err();
+        let msg = err.downcast_ref::<String>().unwrap();
+        assert!(msg.starts_with("double free"), "{}", msg);
+        assert!(msg.contains("first freed at:"), "{}", msg);
+
+        let local = 0u64;
+        let err = std::panic::catch_unwind(|| unsafe {
+            alloc.deallocate(NonNull::from(&local).cast(), Layout::new::<u64>())
+        })
+        .unwrap_err();
+        let msg = err.downcast_ref::<String>().unwrap();
+        assert!(msg.starts_with("invalid free"), "{}", msg);
+
+        let layout = Layout::from_size_align(GUARD_MIN_SIZE + 10, 8).unwrap();
+        let ptr = alloc.allocate(layout).unwrap().cast::<u8>();
+        assert!(crate::local_alloc::find_page(ptr.as_ptr(), layout.size()).is_none());
+        // The allocation ends right before the guard page.
+        let end = ptr.as_ptr() as usize + layout.size();
+        assert!(end.next_multiple_of(page_size()) - end < layout.align());
+        unsafe { alloc.deallocate(ptr, layout) };
+    }
+
+    #[test]
+    fn test_quarantine_is_bounded() {
+        std::thread::spawn(|| {
+            let alloc = LocalAlloc::new();
+            let layout = Layout::from_size_align(64, 8).unwrap();
+            let ptrs = (0..2 * QUARANTINE_LEN)
+                .map(|_| alloc.allocate(layout).unwrap().cast::<u8>())
+                .collect::<Vec<_>>();
+            for &ptr in ptrs.iter() {
+                unsafe { alloc.deallocate(ptr, layout) };
+            }
+            let (num_freed, num_order) =
+                with_allocs(|allocs| (allocs.freed.len(), allocs.freed_order.len())).unwrap();
+            assert_eq!((num_freed, num_order), (QUARANTINE_LEN, QUARANTINE_LEN));
+
+            // The oldest frees are forgotten, the latest one is still reported as a double free.
+            let last = *ptrs.last().unwrap();
+            let err =
+                std::panic::catch_unwind(|| unsafe { alloc.deallocate(last, layout) }).unwrap_err();
+            let msg = err.downcast_ref::<String>().unwrap();
+            assert!(msg.starts_with("double free"), "{}", msg);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    // Guard pages are mapped with mmap, which Miri doesn't support.
+    #[cfg_attr(miri, ignore)]
+    fn test_guarded_allocations_count_against_limit() {
+        std::thread::spawn(|| {
+            let layout = Layout::from_size_align(GUARD_MIN_SIZE, 8).unwrap();
+            let map_len = guarded_len(layout).unwrap();
+            crate::local_alloc::LocalAllocConfig::new()
+                .max_bytes(map_len)
+                .apply();
+            let alloc = LocalAlloc::new();
+            let ptr = alloc.allocate(layout).unwrap().cast::<u8>();
+            assert!(alloc.allocate(layout).is_err());
+            unsafe { alloc.deallocate(ptr, layout) };
+            // The limit is released with the mapping.
+            let ptr = alloc.allocate(layout).unwrap().cast::<u8>();
+            unsafe { alloc.deallocate(ptr, layout) };
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+//! Binding pages to a NUMA node, see [LocalAllocConfig::numa_node](super::LocalAllocConfig::numa_node).
+
+use std::io;
+
+// From linux/mempolicy.h, libc doesn't have these.
+const MPOL_BIND: libc::c_int = 2;
+const MPOL_MF_MOVE: libc::c_uint = 1 << 1;
+#[cfg(test)]
+const MPOL_F_ADDR: libc::c_ulong = 1 << 1;
+
+const MAX_NODES: usize = 1024;
+const NODEMASK_WORDS: usize = MAX_NODES / libc::c_ulong::BITS as usize;
+
+/// Binds `ptr..ptr + len` to `node`, memory that was already touched is moved to the node.
+pub(super) unsafe fn bind(ptr: *mut u8, len: usize, node: u32) -> io::Result<()> {
+    let node = usize::try_from(node).unwrap();
+    if node >= MAX_NODES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("numa node {} is out of range", node),
+        ));
+    }
+    let bits = libc::c_ulong::BITS as usize;
+    let mut mask = [0 as libc::c_ulong; NODEMASK_WORDS];
+    mask[node / bits] |= 1 << (node % bits);
+    let res = libc::syscall(
+        libc::SYS_mbind,
+        ptr,
+        len,
+        MPOL_BIND,
+        mask.as_ptr(),
+        MAX_NODES as libc::c_ulong,
+        MPOL_MF_MOVE,
+    );
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Pins the current thread to `cpu`.
+pub(super) fn pin_to_cpu(cpu: usize) -> io::Result<()> {
+    if cpu >= libc::CPU_SETSIZE as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("cpu {} is out of range", cpu),
+        ));
+    }
+    let mut set = unsafe { std::mem::zeroed::<libc::cpu_set_t>() };
+    unsafe { libc::CPU_SET(cpu, &mut set) };
+    if unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Returns the node of the CPUs the current thread can run on, `None` if they are on more than one node.
+pub(super) fn node_of_affinity() -> io::Result<Option<u32>> {
+    let mut set = unsafe { std::mem::zeroed::<libc::cpu_set_t>() };
+    if unsafe { libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) } != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    let mut node = None;
+    for cpu in 0..libc::CPU_SETSIZE as usize {
+        if !unsafe { libc::CPU_ISSET(cpu, &set) } {
+            continue;
+        }
+        let cpu_node = node_of_cpu(cpu)?;
+        match node {
+            None => node = Some(cpu_node),
+            Some(node) if node != cpu_node => return Ok(None),
+            Some(_) => (),
+        }
+    }
+    Ok(node)
+}
+
+// The cpu directory in sysfs has a `nodeN` link to its node.
+pub(super) fn node_of_cpu(cpu: usize) -> io::Result<u32> {
+    for entry in std::fs::read_dir(format!("/sys/devices/system/cpu/cpu{}", cpu))? {
+        let name = entry?.file_name();
+        if let Some(node) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("node"))
+            .and_then(|node| node.parse().ok())
+        {
+            return Ok(node);
+        }
+    }
+    // Kernels without NUMA support don't have the link, everything is on node 0.
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::ExecutorConfig;
+    use crate::local_alloc::{find_page, LocalAlloc, LocalAllocConfig, STATE};
+
+    fn first_allowed_cpu() -> usize {
+        let mut set = unsafe { std::mem::zeroed::<libc::cpu_set_t>() };
+        assert_eq!(
+            unsafe { libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) },
+            0
+        );
+        (0..libc::CPU_SETSIZE as usize)
+            .find(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+            .unwrap()
+    }
+
+    #[test]
+    // Miri doesn't support the memory policy system calls.
+    #[cfg_attr(miri, ignore)]
+    fn test_numa_node() {
+        std::thread::spawn(|| {
+            // An unpinned thread can run on more than one node.
+            pin_to_cpu(first_allowed_cpu()).unwrap();
+            let node = node_of_affinity().unwrap().unwrap();
+            LocalAllocConfig::new().pinned_numa_node().apply();
+            let mut v = Vec::<u8, _>::with_capacity_in(1024, LocalAlloc::new());
+            v.resize(1024, 1);
+            let page = find_page(v.as_ptr(), v.len()).unwrap();
+
+            let mut mode: libc::c_int = -1;
+            let mut mask = [0 as libc::c_ulong; NODEMASK_WORDS];
+            let res = unsafe {
+                libc::syscall(
+                    libc::SYS_get_mempolicy,
+                    &mut mode,
+                    mask.as_mut_ptr(),
+                    MAX_NODES as libc::c_ulong,
+                    page.ptr,
+                    MPOL_F_ADDR,
+                )
+            };
+            // Kernels without NUMA support don't have memory policies.
+            if res == 0 {
+                assert_eq!(mode, MPOL_BIND);
+                assert_eq!(mask[0], 1 << node);
+            }
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    // Miri doesn't support the memory policy system calls.
+    #[cfg_attr(miri, ignore)]
+    fn test_executor_pin_to_cpu() {
+        std::thread::spawn(|| {
+            let cpu = first_allowed_cpu();
+            let node = node_of_cpu(cpu).unwrap();
+            ExecutorConfig::new()
+                .pin_to_cpu(cpu)
+                .run(async move {
+                    assert_eq!(node_of_affinity().unwrap(), Some(node));
+                    assert_eq!(STATE.with_borrow(|state| state.numa_node), Some(node));
+                })
+                .unwrap();
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,466 @@
+//! A [log] implementation that buffers records in memory and writes them out from a task on the executor.
+//!
+//! Writing log records to a file or a pipe can block the executor thread, e.g. when the disk is slow or the reader
+//! of stderr is gone. [RingLogger] instead formats records into a fixed size buffer of the logging thread, and
+//! [run_flusher] writes that buffer out with io_uring periodically. Records that don't fit into the buffer are
+//! dropped and counted, so logging never allocates or waits.
+//!
+//! The buffer belongs to the thread that runs the flusher. Records logged on threads that don't run a flusher are
+//! written to stderr directly.
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::io::{self, Write as _};
+use std::os::fd::FromRawFd;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::{Duration, SystemTime};
+
+use crate::fs::{rename, File};
+use crate::local_alloc::LocalAlloc;
+use crate::sync::CancellationToken;
+use crate::time::sleep;
+
+thread_local! {
+    static BUFFER: RefCell<Option<Buffer>> = const { RefCell::new(None) };
+}
+
+struct Buffer {
+    data: Box<[u8], LocalAlloc>,
+    len: usize,
+    dropped: u64,
+}
+
+impl Buffer {
+    fn new(capacity: usize) -> Self {
+        let mut data = Vec::with_capacity_in(capacity, LocalAlloc::new());
+        data.resize(capacity, 0);
+        Self {
+            data: data.into_boxed_slice(),
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, record: &log::Record) {
+        let mut cursor = io::Cursor::new(&mut self.data[self.len..]);
+        match write_record(&mut cursor, record) {
+            Ok(()) => self.len += usize::try_from(cursor.position()).unwrap(),
+            Err(_) => self.dropped += 1,
+        }
+    }
+}
+
+fn write_record(w: &mut impl io::Write, record: &log::Record) -> io::Result<()> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    writeln!(
+        w,
+        "{}.{:06} {:<5} {}: {}",
+        now.as_secs(),
+        now.subsec_micros(),
+        record.level(),
+        record.target(),
+        record.args()
+    )
+}
+
+/// The logger, install it with [RingLogger::init].
+pub struct RingLogger;
+
+static LOGGER: RingLogger = RingLogger;
+
+impl RingLogger {
+    /// Sets [RingLogger] as the global logger, records above `level` are ignored.
+    pub fn init(level: log::LevelFilter) -> Result<(), log::SetLoggerError> {
+        log::set_logger(&LOGGER)?;
+        log::set_max_level(level);
+        Ok(())
+    }
+}
+
+impl log::Log for RingLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        // The buffer is borrowed if something logs while a record is being formatted, and it is gone while the
+        // thread exits.
+        let buffered = BUFFER
+            .try_with(|buffer| match buffer.try_borrow_mut() {
+                Ok(mut buffer) => match buffer.as_mut() {
+                    Some(buffer) => {
+                        buffer.push(record);
+                        true
+                    }
+                    None => false,
+                },
+                Err(_) => false,
+            })
+            .unwrap_or(false);
+        if !buffered {
+            let _ = write_record(&mut io::stderr().lock(), record);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Where [run_flusher] writes the records.
+#[derive(Debug, Clone)]
+pub enum LogTarget {
+    Stderr,
+    /// Appends to the file, it is rotated when it reaches [RingLoggerConfig::max_file_size].
+    File(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+pub struct RingLoggerConfig {
+    capacity: usize,
+    flush_interval: Duration,
+    target: LogTarget,
+    max_file_size: u64,
+    max_files: usize,
+}
+
+impl RingLoggerConfig {
+    pub fn new() -> Self {
+        Self {
+            capacity: 1 << 20,
+            flush_interval: Duration::from_millis(100),
+            target: LogTarget::Stderr,
+            max_file_size: 64 << 20,
+            max_files: 4,
+        }
+    }
+
+    /// Size of the buffer in bytes, records that are logged while the buffer is full are dropped. Defaults to 1MB.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Defaults to 100ms.
+    pub fn flush_interval(mut self, flush_interval: Duration) -> Self {
+        self.flush_interval = flush_interval;
+        self
+    }
+
+    /// Defaults to [LogTarget::Stderr].
+    pub fn target(mut self, target: LogTarget) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// The log file is renamed to `<path>.1` when writing to it would make it larger than this, the older files are
+    /// moved to `<path>.2` and so on. Defaults to 64MB.
+    pub fn max_file_size(mut self, max_file_size: u64) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    /// Number of rotated files that are kept, the oldest one is removed when there are more. Defaults to 4.
+    pub fn max_files(mut self, max_files: usize) -> Self {
+        self.max_files = max_files;
+        self
+    }
+}
+
+impl Default for RingLoggerConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Buffers the records logged on this thread and writes them to the target of `config` every flush interval, until
+/// `token` is cancelled.
+///
+/// This should be spawned as a task on the executor. The records that are still buffered when the token is cancelled
+/// are written before this returns, records logged after that go to stderr. Records that can't be written to the
+/// target are dropped and counted, the count is written with the next records that can be written. If the last write
+/// failed, its error is returned when the token is cancelled.
+pub async fn run_flusher(config: RingLoggerConfig, token: CancellationToken) -> io::Result<()> {
+    let mut sink = Sink::open(&config).await?;
+    BUFFER.with_borrow_mut(|buffer| *buffer = Some(Buffer::new(config.capacity)));
+    let mut spare = Buffer::new(config.capacity);
+    // Records that were dropped because writing them failed, and the error of the last failed write.
+    let mut failed = 0u64;
+    let mut error = None;
+    loop {
+        let done = token.is_cancelled();
+        BUFFER.with_borrow_mut(|buffer| match done {
+            true => spare = buffer.take().unwrap(),
+            false => std::mem::swap(buffer.as_mut().unwrap(), &mut spare),
+        });
+
+        if spare.len > 0 {
+            let data = &spare.data[..spare.len];
+            if let Err(e) = sink.write(data).await {
+                failed += u64::try_from(data.iter().filter(|&&b| b == b'\n').count()).unwrap();
+                error = Some(e);
+            }
+        }
+        if spare.dropped > 0 || failed > 0 {
+            let mut msg = Vec::new_in(LocalAlloc::new());
+            if spare.dropped > 0 {
+                writeln!(
+                    msg,
+                    "{} log records were dropped because the buffer was full",
+                    spare.dropped
+                )?;
+            }
+            if let Some(e) = error.as_ref().filter(|_| failed > 0) {
+                writeln!(
+                    msg,
+                    "{} log records were dropped because writing them failed: {}",
+                    failed, e
+                )?;
+            }
+            match sink.write(&msg).await {
+                Ok(()) => {
+                    failed = 0;
+                    error = None;
+                }
+                Err(e) => {
+                    failed += spare.dropped;
+                    error = Some(e);
+                }
+            }
+        }
+        spare.len = 0;
+        spare.dropped = 0;
+
+        if done {
+            sink.close().await?;
+            return match error {
+                Some(e) => Err(e),
+                None => Ok(()),
+            };
+        }
+        // Cancelling the token flushes right away instead of at the end of the interval.
+        let mut interval = sleep(config.flush_interval);
+        let mut cancelled = token.cancelled();
+        std::future::poll_fn(|cx| {
+            if Pin::new(&mut interval).poll(cx).is_ready()
+                || Pin::new(&mut cancelled).poll(cx).is_ready()
+            {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await;
+    }
+}
+
+struct Sink {
+    file: File,
+    // Set if the target is a file.
+    path: Option<PathBuf>,
+    size: u64,
+    max_file_size: u64,
+    max_files: usize,
+}
+
+impl Sink {
+    async fn open(config: &RingLoggerConfig) -> io::Result<Self> {
+        let (file, path, size) = match &config.target {
+            LogTarget::Stderr => {
+                let fd = unsafe { libc::fcntl(libc::STDERR_FILENO, libc::F_DUPFD_CLOEXEC, 0) };
+                if fd == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                (unsafe { File::from_raw_fd(fd) }, None, 0)
+            }
+            LogTarget::File(path) => {
+                let file = open_log(path).await?;
+                let size = file.file_size().await?;
+                (file, Some(path.clone()), size)
+            }
+        };
+        Ok(Self {
+            file,
+            path,
+            size,
+            max_file_size: config.max_file_size,
+            max_files: config.max_files,
+        })
+    }
+
+    async fn write(&mut self, mut data: &[u8]) -> io::Result<()> {
+        let len = u64::try_from(data.len()).unwrap();
+        if self.path.is_some() && self.size > 0 && self.size + len > self.max_file_size {
+            self.rotate().await?;
+        }
+        while !data.is_empty() {
+            // Stderr might not be seekable, offset -1 writes at the current position.
+            let offset = match self.path {
+                Some(_) => self.size,
+                None => u64::MAX,
+            };
+            let n = self.file.write(data, offset).await?;
+            if n == 0 {
+                return Err(io::Error::from(io::ErrorKind::WriteZero));
+            }
+            data = &data[n..];
+            self.size += u64::try_from(n).unwrap();
+        }
+        Ok(())
+    }
+
+    async fn rotate(&mut self) -> io::Result<()> {
+        let path = self.path.clone().unwrap();
+        if self.max_files > 0 {
+            for i in (1..self.max_files).rev() {
+                match rename(&rotated_path(&path, i), &rotated_path(&path, i + 1))?.await {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => (),
+                }
+            }
+            rename(&path, &rotated_path(&path, 1))?.await?;
+        }
+        let file = File::create(&path)?.await?;
+        std::mem::replace(&mut self.file, file).close().await?;
+        self.size = 0;
+        Ok(())
+    }
+
+    async fn close(self) -> io::Result<()> {
+        self.file.close().await
+    }
+}
+
+async fn open_log(path: &Path) -> io::Result<File> {
+    File::open(
+        path,
+        libc::O_WRONLY | libc::O_CREAT | libc::O_CLOEXEC,
+        0o644,
+    )?
+    .await
+}
+
+fn rotated_path(path: &Path, i: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", i));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::{spawn, ExecutorConfig};
+
+    // The logger isn't installed since other tests set their own, records are passed to it directly.
+    fn log(i: usize) {
+        log::Log::log(
+            &LOGGER,
+            &log::Record::builder()
+                .args(format_args!("record {:03}", i))
+                .level(log::Level::Info)
+                .target("test")
+                .build(),
+        )
+    }
+
+    // Runs the flusher on its own thread until the records are logged, the buffer is thread local.
+    fn run_flusher_with(config: RingLoggerConfig, num_records: usize) -> io::Result<()> {
+        std::thread::spawn(move || {
+            ExecutorConfig::new()
+                .run(async move {
+                    let token = CancellationToken::new();
+                    let flusher = spawn(run_flusher(
+                        // Records are only written when the token is cancelled.
+                        config.flush_interval(Duration::from_secs(3600)),
+                        token.clone(),
+                    ));
+                    while BUFFER.with_borrow(|buffer| buffer.is_none()) {
+                        sleep(Duration::ZERO).await;
+                    }
+                    for i in 0..num_records {
+                        log(i);
+                    }
+                    token.cancel();
+                    flusher.await
+                })
+                .unwrap()
+        })
+        .join()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_ring_logger() {
+        let dir = std::env::temp_dir().join(format!("io2_test_logger_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("log");
+
+        // More than fits into the buffer.
+        run_flusher_with(
+            RingLoggerConfig::new()
+                .capacity(256)
+                .target(LogTarget::File(path.clone()))
+                .max_file_size(300),
+            60,
+        )
+        .unwrap();
+
+        // The records filled the file, so the file was rotated before the message about the dropped records.
+        let rotated = std::fs::read_to_string(rotated_path(&path, 1)).unwrap();
+        let records = rotated.lines().collect::<Vec<_>>();
+        assert!(!records.is_empty());
+        for (i, record) in records.iter().enumerate() {
+            assert!(record.ends_with(&format!(" INFO  test: record {:03}", i)));
+        }
+        let current = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            current,
+            format!(
+                "{} log records were dropped because the buffer was full\n",
+                60 - records.len()
+            )
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir =
+            std::env::temp_dir().join(format!("io2_test_log_rotation_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("log");
+
+        let config = RingLoggerConfig::new()
+            .target(LogTarget::File(path.clone()))
+            .max_file_size(300)
+            .max_files(2);
+        ExecutorConfig::new()
+            .run(async move {
+                let mut sink = Sink::open(&config).await.unwrap();
+                for i in 0..5u8 {
+                    sink.write(&[b'0' + i; 200]).await.unwrap();
+                }
+                sink.close().await.unwrap();
+            })
+            .unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), [b'4'; 200]);
+        assert_eq!(std::fs::read(rotated_path(&path, 1)).unwrap(), [b'3'; 200]);
+        assert_eq!(std::fs::read(rotated_path(&path, 2)).unwrap(), [b'2'; 200]);
+        assert!(!rotated_path(&path, 3).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_error() {
+        // Writes to /dev/full fail with ENOSPC.
+        let err = run_flusher_with(
+            RingLoggerConfig::new().target(LogTarget::File(PathBuf::from("/dev/full"))),
+            10,
+        )
+        .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOSPC));
+    }
+}
@@ -0,0 +1,216 @@
+//! Latency histograms of io operations.
+//!
+//! The executor records the time from queueing an operation to its completion when it runs with
+//! [ExecutorConfig::latency_metrics](crate::executor::ExecutorConfig::latency_metrics). Histograms are kept per thread,
+//! [histogram] returns the one of the executor running on the current thread.
+//!
+//! Buckets are log-linear like HDR histograms, every power of two is split into 16 buckets so a recorded value is off
+//! by at most 1/16 (~6%) of itself.
+
+use std::cell::RefCell;
+use std::time::Duration;
+
+use io_uring::opcode;
+
+/// Kinds of operations that have their own histogram.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpClass {
+    Read,
+    Write,
+    Fsync,
+    Accept,
+    Recv,
+    Send,
+}
+
+const NUM_CLASSES: usize = 6;
+
+impl OpClass {
+    /// Returns the class of an io_uring opcode, operations that don't fall in any class aren't recorded.
+    pub(crate) fn from_opcode(code: u8) -> Option<Self> {
+        match code {
+            opcode::Read::CODE | opcode::Readv::CODE | opcode::ReadFixed::CODE => Some(Self::Read),
+            opcode::Write::CODE | opcode::Writev::CODE | opcode::WriteFixed::CODE => {
+                Some(Self::Write)
+            }
+            opcode::Fsync::CODE | opcode::SyncFileRange::CODE => Some(Self::Fsync),
+            opcode::Accept::CODE => Some(Self::Accept),
+            opcode::Recv::CODE | opcode::RecvMsg::CODE => Some(Self::Recv),
+            opcode::Send::CODE | opcode::SendMsg::CODE | opcode::SendZc::CODE => Some(Self::Send),
+            _ => None,
+        }
+    }
+}
+
+thread_local! {
+    static HISTOGRAMS: RefCell<Vec<Histogram>> = RefCell::new((0..NUM_CLASSES).map(|_| Histogram::new()).collect());
+}
+
+/// Returns a copy of the histogram of the given class.
+pub fn histogram(class: OpClass) -> Histogram {
+    HISTOGRAMS.with_borrow(|h| h[class as usize].clone())
+}
+
+/// Clears the histograms of all classes.
+pub fn reset() {
+    HISTOGRAMS.with_borrow_mut(|h| h.iter_mut().for_each(Histogram::reset));
+}
+
+pub(crate) fn record(class: OpClass, latency: Duration) {
+    HISTOGRAMS.with_borrow_mut(|h| h[class as usize].record(latency));
+}
+
+const SUB_BITS: u32 = 4;
+const SUB_BUCKETS: usize = 1 << SUB_BITS;
+const NUM_BUCKETS: usize = (64 - SUB_BITS as usize + 1) * SUB_BUCKETS;
+
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    count: u64,
+    sum: u128,
+    min: u64,
+    max: u64,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; NUM_BUCKETS],
+            count: 0,
+            sum: 0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+
+    pub fn record(&mut self, value: Duration) {
+        let nanos = u64::try_from(value.as_nanos()).unwrap_or(u64::MAX);
+        self.counts[bucket_index(nanos)] += 1;
+        self.count += 1;
+        self.sum += u128::from(nanos);
+        self.min = self.min.min(nanos);
+        self.max = self.max.max(nanos);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        (self.count > 0).then(|| Duration::from_nanos(self.min))
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        (self.count > 0).then(|| Duration::from_nanos(self.max))
+    }
+
+    /// Sum of the recorded values, saturating at `u64::MAX` nanoseconds.
+    pub fn sum(&self) -> Duration {
+        Duration::from_nanos(u64::try_from(self.sum).unwrap_or(u64::MAX))
+    }
+
+    /// Returns the number of recorded values that are less than or equal to `value`.
+    ///
+    /// Values are only counted if the upper end of their bucket is at most `value`, so values in the same bucket as
+    /// `value` can be left out.
+    pub fn count_at_most(&self, value: Duration) -> u64 {
+        let nanos = u64::try_from(value.as_nanos()).unwrap_or(u64::MAX);
+        self.counts
+            .iter()
+            .enumerate()
+            .take_while(|&(index, _)| bucket_upper_bound(index) <= nanos)
+            .map(|(_, &count)| count)
+            .sum()
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        (self.count > 0).then(|| {
+            Duration::from_nanos(u64::try_from(self.sum / u128::from(self.count)).unwrap())
+        })
+    }
+
+    /// Returns the value that `percentile` percent of the recorded values are less than or equal to, e.g. 99.9 for
+    /// p99.9.
+    ///
+    /// The result is the upper end of the bucket the value falls in, so it can be higher than the actual value.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        assert!(
+            (0.0..=100.0).contains(&percentile),
+            "percentile must be between 0 and 100"
+        );
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((percentile / 100.0 * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let value = bucket_upper_bound(index).min(self.max);
+                return Some(Duration::from_nanos(value));
+            }
+        }
+        unreachable!()
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+    let shift = 63 - value.leading_zeros() - SUB_BITS;
+    let sub = (value >> shift) as usize & (SUB_BUCKETS - 1);
+    (shift as usize + 1) * SUB_BUCKETS + sub
+}
+
+fn bucket_upper_bound(index: usize) -> u64 {
+    if index < SUB_BUCKETS {
+        return index as u64;
+    }
+    let shift = (index / SUB_BUCKETS - 1) as u32;
+    let sub = (index % SUB_BUCKETS) as u64;
+    let low = (SUB_BUCKETS as u64 + sub) << shift;
+    low + ((1u64 << shift) - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let mut h = Histogram::new();
+        assert_eq!(h.percentile(50.0), None);
+        for i in 1..=1000 {
+            h.record(Duration::from_micros(i));
+        }
+        assert_eq!(h.count(), 1000);
+        assert_eq!(h.min(), Some(Duration::from_micros(1)));
+        assert_eq!(h.max(), Some(Duration::from_micros(1000)));
+        assert_eq!(h.mean(), Some(Duration::from_nanos(500_500)));
+        for (p, expected) in [(50.0, 500), (99.0, 990), (99.9, 999), (100.0, 1000)] {
+            let value = h.percentile(p).unwrap().as_nanos() as f64;
+            let expected = f64::from(expected) * 1000.0;
+            assert!(value >= expected && value <= expected * 1.0625, "p{}", p);
+        }
+        h.reset();
+        assert_eq!(h.count(), 0);
+
+        for value in [0, 1, 15, 16, 17, 1000, u64::MAX] {
+            let index = bucket_index(value);
+            assert!(index < NUM_BUCKETS);
+            assert!(bucket_upper_bound(index) >= value);
+        }
+    }
+}
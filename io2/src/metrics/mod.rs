@@ -0,0 +1,5 @@
+//! Runtime metrics that are collected by the executor.
+
+pub mod latency;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
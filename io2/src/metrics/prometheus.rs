@@ -0,0 +1,273 @@
+//! Metrics of the executor in the Prometheus text format.
+//!
+//! [serve] is spawned as a task of the executor whose metrics should be exposed, it answers `GET /metrics` requests
+//! over HTTP with the output of [render]:
+//!
+//! ```text
+//! # HELP io2_tasks Number of tasks of the executor.
+//! # TYPE io2_tasks gauge
+//! io2_tasks 3
+//! ...
+//! io2_io_latency_seconds_bucket{op="read",le="0.0001"} 1520
+//! ```
+//!
+//! Metrics are kept per thread, so each executor thread serves its own metrics on its own port. The io latency
+//! histograms are only filled when the executor runs with
+//! [ExecutorConfig::latency_metrics](crate::executor::ExecutorConfig::latency_metrics).
+
+use std::fmt::Write;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::executor::{snapshot, spawn};
+use crate::local_alloc;
+use crate::net::tcp::{TcpListener, TcpStream};
+
+use super::latency::{self, OpClass};
+
+const MAX_REQUEST_SIZE: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+const OP_CLASSES: [(OpClass, &str); 6] = [
+    (OpClass::Read, "read"),
+    (OpClass::Write, "write"),
+    (OpClass::Fsync, "fsync"),
+    (OpClass::Accept, "accept"),
+    (OpClass::Recv, "recv"),
+    (OpClass::Send, "send"),
+];
+
+// Upper bounds of the latency histogram buckets in seconds.
+const LATENCY_BUCKETS: [f64; 15] = [
+    0.00001, 0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25,
+    0.5, 1.0,
+];
+
+/// Serves the metrics of the current executor over HTTP on `addr`.
+///
+/// Each connection gets one response and is then closed. This runs until accepting a connection fails, errors of
+/// individual connections are ignored.
+pub async fn serve(addr: SocketAddr) -> io::Result<()> {
+    serve_listener(TcpListener::bind(addr)?).await
+}
+
+/// Like [serve] on a listener that is already bound, e.g. one passed in by the service manager.
+pub async fn serve_listener(listener: TcpListener) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        spawn(async move {
+            if let Err(e) = handle(stream).await {
+                log::debug!("failed to serve metrics request: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle(stream: TcpStream) -> io::Result<()> {
+    stream.set_read_deadline(Some(REQUEST_TIMEOUT));
+    stream.set_write_deadline(Some(REQUEST_TIMEOUT));
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        if request.len() >= MAX_REQUEST_SIZE {
+            return respond(&stream, "431 Request Header Fields Too Large", "").await;
+        }
+        match stream.read(&mut buf).await? {
+            0 => return Ok(()),
+            n => request.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    let mut parts = request.split(|&b| b == b' ');
+    match (parts.next(), parts.next()) {
+        (Some(b"GET"), Some(b"/metrics")) => respond(&stream, "200 OK", &render()).await,
+        (Some(b"GET"), Some(_)) => respond(&stream, "404 Not Found", "").await,
+        _ => respond(&stream, "405 Method Not Allowed", "").await,
+    }
+}
+
+async fn respond(stream: &TcpStream, status: &str, body: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await
+}
+
+/// Returns the metrics of the current executor in the Prometheus text format.
+pub fn render() -> String {
+    let mut out = String::new();
+    let snapshot = snapshot();
+    gauge(
+        &mut out,
+        "io2_tasks",
+        "Number of tasks of the executor.",
+        snapshot.tasks.len(),
+    );
+    gauge(
+        &mut out,
+        "io2_dio_running",
+        "Direct io operations that are running in the kernel.",
+        snapshot.num_dio_running,
+    );
+    header(
+        &mut out,
+        "io2_collection_len",
+        "Number of entries in the internal collections of the executor.",
+        "gauge",
+    );
+    for c in &snapshot.collections {
+        writeln!(
+            out,
+            "io2_collection_len{{collection=\"{}\"}} {}",
+            c.name, c.len
+        )
+        .unwrap();
+    }
+    header(
+        &mut out,
+        "io2_collection_capacity",
+        "Capacity of the internal collections of the executor.",
+        "gauge",
+    );
+    for c in &snapshot.collections {
+        writeln!(
+            out,
+            "io2_collection_capacity{{collection=\"{}\"}} {}",
+            c.name, c.capacity
+        )
+        .unwrap();
+    }
+
+    let alloc = local_alloc::stats();
+    gauge(
+        &mut out,
+        "io2_alloc_pages",
+        "Pages the allocator of the thread reserved from the system.",
+        alloc.num_pages,
+    );
+    gauge(
+        &mut out,
+        "io2_alloc_reserved_bytes",
+        "Total size of the pages of the allocator.",
+        alloc.reserved_bytes,
+    );
+    gauge(
+        &mut out,
+        "io2_alloc_free_bytes",
+        "Part of the pages of the allocator that isn't allocated.",
+        alloc.free_bytes,
+    );
+    gauge(
+        &mut out,
+        "io2_alloc_free_ranges",
+        "Number of separate free ranges in the pages of the allocator.",
+        alloc.num_free_ranges,
+    );
+
+    header(
+        &mut out,
+        "io2_io_latency_seconds",
+        "Time from queueing an io operation to its completion.",
+        "histogram",
+    );
+    for (class, op) in OP_CLASSES {
+        let histogram = latency::histogram(class);
+        for le in LATENCY_BUCKETS {
+            writeln!(
+                out,
+                "io2_io_latency_seconds_bucket{{op=\"{}\",le=\"{}\"}} {}",
+                op,
+                le,
+                histogram.count_at_most(Duration::from_secs_f64(le))
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "io2_io_latency_seconds_bucket{{op=\"{}\",le=\"+Inf\"}} {}",
+            op,
+            histogram.count()
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "io2_io_latency_seconds_sum{{op=\"{}\"}} {}",
+            op,
+            histogram.sum().as_secs_f64()
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "io2_io_latency_seconds_count{{op=\"{}\"}} {}",
+            op,
+            histogram.count()
+        )
+        .unwrap();
+    }
+    out
+}
+
+fn header(out: &mut String, name: &str, help: &str, ty: &str) {
+    writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, ty).unwrap();
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: usize) {
+    header(out, name, help, "gauge");
+    writeln!(out, "{} {}", name, value).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::executor::ExecutorConfig;
+    use crate::fs::File;
+
+    use super::*;
+
+    #[test]
+    fn test_prometheus() {
+        ExecutorConfig::new()
+            .latency_metrics(true)
+            .run(async {
+                let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+                let addr = listener.local_addr().unwrap();
+                spawn(async move { serve_listener(listener).await.unwrap() });
+
+                let file = File::open(std::path::Path::new("/proc/self/stat"), libc::O_RDONLY, 0)
+                    .unwrap()
+                    .await
+                    .unwrap();
+                file.read(&mut [0; 64], 0).await.unwrap();
+
+                let get = |path: &'static str| async move {
+                    let stream = TcpStream::connect(addr).await.unwrap();
+                    let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+                    stream.write_all(request.as_bytes()).await.unwrap();
+                    let mut response = Vec::new();
+                    let mut buf = [0; 4096];
+                    loop {
+                        match stream.read(&mut buf).await.unwrap() {
+                            0 => break,
+                            n => response.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    String::from_utf8(response).unwrap()
+                };
+
+                let response = get("/metrics").await;
+                assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+                let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+                assert!(body.contains("# TYPE io2_tasks gauge\nio2_tasks "));
+                assert!(body.contains("io2_collection_len{collection=\"tasks\"} "));
+                assert!(body.contains("io2_alloc_reserved_bytes "));
+                assert!(body.contains("io2_io_latency_seconds_bucket{op=\"read\",le=\"+Inf\"} 1\n"));
+                assert!(body.contains("io2_io_latency_seconds_count{op=\"send\"} "));
+
+                assert!(get("/other").await.starts_with("HTTP/1.1 404 Not Found\r\n"));
+            })
+            .unwrap();
+    }
+}
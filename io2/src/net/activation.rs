@@ -0,0 +1,150 @@
+//! systemd socket activation.
+//!
+//! systemd passes the sockets it listens on to the started process as fds starting at 3, the number of them is in the
+//! `LISTEN_FDS` environment variable and `LISTEN_PID` is set to the pid of the process they are meant for.
+
+use std::io;
+use std::os::fd::RawFd;
+
+use super::socket;
+use super::tcp::TcpListener;
+use super::unix::{UnixDatagram, UnixListener};
+
+const LISTEN_FDS_START: RawFd = 3;
+
+/// A socket inherited from systemd.
+pub enum ListenFd {
+    Tcp(TcpListener),
+    /// A SOCK_STREAM or SOCK_SEQPACKET unix listener.
+    Unix(UnixListener),
+    UnixDatagram(UnixDatagram),
+}
+
+/// Takes the sockets passed by systemd socket activation, in the order they are configured in the socket unit.
+///
+/// Returns an empty list if the process wasn't started with socket activation. The environment isn't modified, child
+/// processes don't inherit the fds since they are made close-on-exec and `LISTEN_PID` doesn't match their pid. This
+/// should be called once, a second call wraps the same fds again.
+///
+/// All fds are validated before any of them is wrapped, an fd that isn't a listening TCP socket, a listening unix socket
+/// or a unix datagram socket results in an `InvalidInput` error.
+pub fn from_listen_fds() -> io::Result<Vec<ListenFd>> {
+    listen_fds(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+    )
+}
+
+fn listen_fds(pid: Option<&str>, num_fds: Option<&str>) -> io::Result<Vec<ListenFd>> {
+    let (pid, num_fds) = match (pid, num_fds) {
+        (Some(pid), Some(num_fds)) => (pid, num_fds),
+        _ => return Ok(Vec::new()),
+    };
+    if pid.parse::<libc::pid_t>().ok() != Some(unsafe { libc::getpid() }) {
+        return Ok(Vec::new());
+    }
+    let num_fds: RawFd = num_fds.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid LISTEN_FDS value: {}", num_fds),
+        )
+    })?;
+
+    let fds = LISTEN_FDS_START..LISTEN_FDS_START + num_fds;
+    let kinds = fds
+        .clone()
+        .map(socket_kind)
+        .collect::<io::Result<Vec<_>>>()?;
+
+    fds.zip(kinds)
+        .map(|(fd, kind)| {
+            // Inherited fds don't have close-on-exec set.
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(kind.wrap(fd))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SocketKind {
+    Tcp,
+    Unix,
+    UnixDatagram,
+}
+
+impl SocketKind {
+    fn wrap(self, fd: RawFd) -> ListenFd {
+        match self {
+            SocketKind::Tcp => ListenFd::Tcp(TcpListener::from_fd(fd)),
+            SocketKind::Unix => ListenFd::Unix(UnixListener::from_fd(fd)),
+            SocketKind::UnixDatagram => ListenFd::UnixDatagram(UnixDatagram::from_fd(fd)),
+        }
+    }
+}
+
+fn socket_kind(fd: RawFd) -> io::Result<SocketKind> {
+    let invalid = |what: &str| {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("inherited fd {} is {}", fd, what),
+        ))
+    };
+
+    let ty = match socket::getsockopt_int(fd, libc::SOL_SOCKET, libc::SO_TYPE) {
+        Ok(ty) => ty,
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSOCK) => return invalid("not a socket"),
+        Err(e) => return Err(e),
+    };
+    let domain = socket::getsockopt_int(fd, libc::SOL_SOCKET, libc::SO_DOMAIN)?;
+    let listening = socket::getsockopt_int(fd, libc::SOL_SOCKET, libc::SO_ACCEPTCONN)? != 0;
+
+    match (domain, ty, listening) {
+        (libc::AF_INET | libc::AF_INET6, libc::SOCK_STREAM, true) => Ok(SocketKind::Tcp),
+        (libc::AF_UNIX, libc::SOCK_STREAM | libc::SOCK_SEQPACKET, true) => Ok(SocketKind::Unix),
+        (libc::AF_UNIX, libc::SOCK_DGRAM, _) => Ok(SocketKind::UnixDatagram),
+        (_, libc::SOCK_STREAM | libc::SOCK_SEQPACKET, false) => invalid("not listening"),
+        _ => invalid("not a supported socket type"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::os::fd::AsRawFd;
+
+    use crate::executor::ExecutorConfig;
+
+    use super::*;
+
+    #[test]
+    fn test_socket_kind() {
+        ExecutorConfig::new()
+            .run(async {
+                let tcp = TcpListener::bind("127.0.0.1:0".parse::<SocketAddr>().unwrap()).unwrap();
+                assert_eq!(socket_kind(tcp.fd).unwrap(), SocketKind::Tcp);
+                let (dgram, _) = UnixDatagram::pair().unwrap();
+                assert_eq!(socket_kind(dgram.fd).unwrap(), SocketKind::UnixDatagram);
+
+                let file = std::fs::File::open("Cargo.toml").unwrap();
+                let err = socket_kind(file.as_raw_fd()).unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+                assert!(err.to_string().ends_with("is not a socket"));
+
+                let stream = socket::new_socket(libc::AF_INET, libc::SOCK_STREAM).unwrap();
+                let err = socket_kind(stream).unwrap_err();
+                assert!(err.to_string().ends_with("is not listening"));
+                socket::close_fd(stream);
+
+                // Not started with socket activation.
+                assert!(listen_fds(None, None).unwrap().is_empty());
+                // Not meant for this process.
+                assert!(listen_fds(Some("0"), Some("1")).unwrap().is_empty());
+                let pid = unsafe { libc::getpid() }.to_string();
+                let err = listen_fds(Some(&pid), Some("x")).err().unwrap();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            })
+            .unwrap();
+    }
+}
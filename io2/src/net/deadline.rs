@@ -0,0 +1,214 @@
+//! Read/write deadlines and idle timeouts of stream sockets.
+//!
+//! A stream keeps its timeouts in [Deadlines] and reads and writes on it carry an [OpDeadline]. When the deadline of an
+//! operation passes, the operation is cancelled and completes with a `TimedOut` error. The idle deadline moves every
+//! time an operation on the stream completes, so a read that waits while writes make progress doesn't time out.
+
+use std::cell::Cell;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::Context;
+use std::time::{Duration, Instant};
+
+use crate::time::{self, NotifyWhen};
+
+// Public because the sealed StreamSocket trait returns it, the module is private so it can't be named outside.
+pub struct Deadlines {
+    read: Cell<Option<Duration>>,
+    write: Cell<Option<Duration>>,
+    idle: Cell<Option<Duration>>,
+    last_activity: Cell<Instant>,
+    // Time all reads have to complete by, unlike `read` this doesn't move with each read.
+    read_until: Cell<Option<Instant>>,
+}
+
+impl Deadlines {
+    pub(crate) fn new() -> Self {
+        Self {
+            read: Cell::new(None),
+            write: Cell::new(None),
+            idle: Cell::new(None),
+            last_activity: Cell::new(time::now()),
+            read_until: Cell::new(None),
+        }
+    }
+
+    pub(crate) fn set_read(&self, timeout: Option<Duration>) {
+        self.read.set(timeout);
+    }
+
+    pub(crate) fn set_write(&self, timeout: Option<Duration>) {
+        self.write.set(timeout);
+    }
+
+    pub(crate) fn set_read_until(&self, deadline: Option<Instant>) {
+        self.read_until.set(deadline);
+    }
+
+    pub(crate) fn set_idle(&self, timeout: Option<Duration>) {
+        // The connection wasn't idle before it had an idle timeout.
+        self.last_activity.set(time::now());
+        self.idle.set(timeout);
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Op {
+    Read,
+    Write,
+}
+
+/// Deadline of a single read or write on a stream.
+pub(crate) struct OpDeadline<'socket> {
+    deadlines: &'socket Deadlines,
+    op: Op,
+    started: Option<Instant>,
+    // Timer and the deadline it was set for.
+    timer: Option<(Instant, NotifyWhen)>,
+    timed_out: bool,
+}
+
+impl<'socket> OpDeadline<'socket> {
+    pub(crate) fn new(deadlines: &'socket Deadlines, op: Op) -> Self {
+        Self {
+            deadlines,
+            op,
+            started: None,
+            timer: None,
+            timed_out: false,
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        let timeout = match self.op {
+            Op::Read => self.deadlines.read.get(),
+            Op::Write => self.deadlines.write.get(),
+        };
+        let mut op = timeout.zip(self.started).map(|(t, started)| started + t);
+        if let (Op::Read, Some(until)) = (self.op, self.deadlines.read_until.get()) {
+            op = Some(op.map_or(until, |op| op.min(until)));
+        }
+        let idle = self
+            .deadlines
+            .idle
+            .get()
+            .map(|t| self.deadlines.last_activity.get() + t);
+        match (op, idle) {
+            (Some(op), Some(idle)) => Some(op.min(idle)),
+            (op, idle) => op.or(idle),
+        }
+    }
+
+    /// Called before the operation is submitted, returns a `TimedOut` error if the stream is already past its idle
+    /// deadline.
+    pub(crate) fn start(&mut self) -> io::Result<()> {
+        let now = time::now();
+        self.started = Some(now);
+        if self.deadline().is_some_and(|deadline| deadline <= now) {
+            return Err(io::Error::from(io::ErrorKind::TimedOut));
+        }
+        Ok(())
+    }
+
+    /// Returns true once when the deadline passes, the operation should be cancelled then.
+    pub(crate) fn poll_expired(&mut self, cx: &mut Context<'_>) -> bool {
+        if self.timed_out {
+            return false;
+        }
+        loop {
+            let Some(deadline) = self.deadline() else {
+                self.timer = None;
+                return false;
+            };
+            if self
+                .timer
+                .as_ref()
+                .is_none_or(|(armed, _)| *armed != deadline)
+            {
+                self.timer = Some((deadline, time::sleep_until(deadline)));
+            }
+            let (_, timer) = self.timer.as_mut().unwrap();
+            if Pin::new(timer).poll(cx).is_pending() {
+                return false;
+            }
+            // The deadline might have moved while the timer was running, it is armed again then.
+            self.timer = None;
+            if self.deadline() == Some(deadline) {
+                self.timed_out = true;
+                return true;
+            }
+        }
+    }
+
+    /// Maps the result of the operation, recording the activity on the stream.
+    pub(crate) fn complete(&mut self, res: io::Result<usize>) -> io::Result<usize> {
+        self.timer = None;
+        match res {
+            Err(e) if self.timed_out && e.raw_os_error() == Some(libc::ECANCELED) => {
+                Err(io::Error::from(io::ErrorKind::TimedOut))
+            }
+            Ok(n) => {
+                self.deadlines.last_activity.set(time::now());
+                Ok(n)
+            }
+            res => res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::executor::{spawn, ExecutorConfig};
+    use crate::net::unix::UnixStream;
+    use crate::time::sleep;
+
+    use super::*;
+
+    #[test]
+    fn test_deadlines() {
+        ExecutorConfig::new()
+            .run(async {
+                let (a, b) = UnixStream::pair().unwrap();
+                let mut buf = [0; 16];
+
+                b.set_read_deadline(Some(Duration::from_millis(20)));
+                let start = Instant::now();
+                let err = b.read(&mut buf).await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+                assert!(start.elapsed() >= Duration::from_millis(20));
+                // The cancelled read didn't consume anything.
+                a.write_all(b"x").await.unwrap();
+                assert_eq!(b.read(&mut buf).await.unwrap(), 1);
+                b.set_read_deadline(None);
+
+                // Activity keeps the connection alive, the read after the writer stops times out.
+                b.set_idle_timeout(Some(Duration::from_millis(50)));
+                let writer = spawn(async move {
+                    for _ in 0..4 {
+                        sleep(Duration::from_millis(20)).await;
+                        a.write_all(b"y").await.unwrap();
+                    }
+                    a
+                });
+                for _ in 0..4 {
+                    assert_eq!(b.read(&mut buf).await.unwrap(), 1);
+                }
+                let a = writer.await;
+                let err = b.read(&mut buf).await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+                // The connection is still idle, so the next read fails right away.
+                let start = Instant::now();
+                let err = b.read_exact(&mut buf).await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+                assert!(start.elapsed() < Duration::from_millis(20));
+
+                // Writes time out when the peer doesn't read.
+                a.set_write_deadline(Some(Duration::from_millis(20)));
+                let data = vec![0; 16 << 20];
+                let err = a.write_all(&data).await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,33 @@
+//! Name resolution.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+
+use crate::blocking::run_blocking;
+
+/// Resolves `host` to the addresses it can be reached at on `port`.
+///
+/// IP address literals are returned as is, names are resolved with the system resolver (getaddrinfo) on a separate
+/// thread since it blocks.
+pub async fn lookup_host(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    // IPv6 literals in URLs are enclosed in brackets.
+    let literal = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = literal.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+
+    let host = host.to_owned();
+    let addrs: Vec<SocketAddr> = run_blocking(move || {
+        (host.as_str(), port)
+            .to_socket_addrs()
+            .map(|addrs| addrs.collect())
+    })
+    .await??;
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "host name resolved to no addresses",
+        ));
+    }
+    Ok(addrs)
+}
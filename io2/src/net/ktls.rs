@@ -0,0 +1,196 @@
+//! Kernel TLS offload.
+//!
+//! After the handshake is done in userspace (e.g. with rustls), the negotiated keys can be handed to the kernel so
+//! records are encrypted/decrypted by the kernel and data can be moved with plain read/write operations.
+//!
+//! Non-data records (alerts, post-handshake messages) that arrive after kTLS is enabled for receiving make plain reads
+//! fail with EIO, so the protocol shouldn't expect any of these after the handshake.
+
+use std::io;
+use std::mem::size_of;
+use std::os::fd::RawFd;
+
+use super::socket;
+
+// From linux/tls.h, defined here so we don't depend on a recent libc version.
+const TLS_TX: i32 = 1;
+const TLS_RX: i32 = 2;
+const TLS_1_2_VERSION: u16 = 0x0303;
+const TLS_1_3_VERSION: u16 = 0x0304;
+const TLS_CIPHER_AES_GCM_128: u16 = 51;
+const TLS_CIPHER_AES_GCM_256: u16 = 52;
+const TLS_CIPHER_CHACHA20_POLY1305: u16 = 54;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+/// Traffic secrets for one direction of a connection, as negotiated by the TLS library.
+///
+/// `rec_seq` is the sequence number of the next record in this direction, in big endian.
+#[derive(Clone)]
+pub enum CryptoInfo {
+    Aes128Gcm {
+        key: [u8; 16],
+        iv: [u8; 8],
+        salt: [u8; 4],
+        rec_seq: [u8; 8],
+    },
+    Aes256Gcm {
+        key: [u8; 32],
+        iv: [u8; 8],
+        salt: [u8; 4],
+        rec_seq: [u8; 8],
+    },
+    Chacha20Poly1305 {
+        key: [u8; 32],
+        iv: [u8; 12],
+        rec_seq: [u8; 8],
+    },
+}
+
+#[derive(Clone)]
+pub struct KtlsKeys {
+    pub version: TlsVersion,
+    pub crypto: CryptoInfo,
+}
+
+#[repr(C)]
+struct CryptoInfoHeader {
+    version: u16,
+    cipher_type: u16,
+}
+
+#[repr(C)]
+struct AesGcm128 {
+    info: CryptoInfoHeader,
+    iv: [u8; 8],
+    key: [u8; 16],
+    salt: [u8; 4],
+    rec_seq: [u8; 8],
+}
+
+#[repr(C)]
+struct AesGcm256 {
+    info: CryptoInfoHeader,
+    iv: [u8; 8],
+    key: [u8; 32],
+    salt: [u8; 4],
+    rec_seq: [u8; 8],
+}
+
+#[repr(C)]
+struct Chacha20Poly1305 {
+    info: CryptoInfoHeader,
+    iv: [u8; 12],
+    key: [u8; 32],
+    salt: [u8; 0],
+    rec_seq: [u8; 8],
+}
+
+fn set_keys(fd: RawFd, direction: i32, keys: &KtlsKeys) -> io::Result<()> {
+    let version = match keys.version {
+        TlsVersion::Tls12 => TLS_1_2_VERSION,
+        TlsVersion::Tls13 => TLS_1_3_VERSION,
+    };
+    match keys.crypto.clone() {
+        CryptoInfo::Aes128Gcm {
+            key,
+            iv,
+            salt,
+            rec_seq,
+        } => socket::setsockopt(
+            fd,
+            libc::SOL_TLS,
+            direction,
+            AesGcm128 {
+                info: CryptoInfoHeader {
+                    version,
+                    cipher_type: TLS_CIPHER_AES_GCM_128,
+                },
+                iv,
+                key,
+                salt,
+                rec_seq,
+            },
+        ),
+        CryptoInfo::Aes256Gcm {
+            key,
+            iv,
+            salt,
+            rec_seq,
+        } => socket::setsockopt(
+            fd,
+            libc::SOL_TLS,
+            direction,
+            AesGcm256 {
+                info: CryptoInfoHeader {
+                    version,
+                    cipher_type: TLS_CIPHER_AES_GCM_256,
+                },
+                iv,
+                key,
+                salt,
+                rec_seq,
+            },
+        ),
+        CryptoInfo::Chacha20Poly1305 { key, iv, rec_seq } => socket::setsockopt(
+            fd,
+            libc::SOL_TLS,
+            direction,
+            Chacha20Poly1305 {
+                info: CryptoInfoHeader {
+                    version,
+                    cipher_type: TLS_CIPHER_CHACHA20_POLY1305,
+                },
+                iv,
+                key,
+                salt: [],
+                rec_seq,
+            },
+        ),
+    }
+}
+
+/// Attaches the tls ULP to the socket and installs the given keys.
+///
+/// Either direction can be left as `None` to keep doing that direction in userspace. Fails with `Unsupported` if the
+/// kernel doesn't have the tls ULP, e.g. when the tls module isn't loaded.
+pub(crate) fn enable(fd: RawFd, tx: Option<&KtlsKeys>, rx: Option<&KtlsKeys>) -> io::Result<()> {
+    let ulp = b"tls";
+    if unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_TCP,
+            libc::TCP_ULP,
+            ulp.as_ptr() as *const libc::c_void,
+            libc::socklen_t::try_from(ulp.len()).unwrap(),
+        )
+    } == -1
+    {
+        let err = io::Error::last_os_error();
+        return Err(match err.raw_os_error() {
+            Some(libc::ENOENT | libc::ENOPROTOOPT) => io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "failed to attach tls ULP, the tls kernel module might not be loaded: {}",
+                    err
+                ),
+            ),
+            _ => err,
+        });
+    }
+    if let Some(tx) = tx {
+        set_keys(fd, TLS_TX, tx)?;
+    }
+    if let Some(rx) = rx {
+        set_keys(fd, TLS_RX, rx)?;
+    }
+    Ok(())
+}
+
+const _: () = assert!(size_of::<AesGcm128>() == 40);
+const _: () = assert!(size_of::<AesGcm256>() == 56);
+const _: () = assert!(size_of::<Chacha20Poly1305>() == 56);
@@ -0,0 +1,161 @@
+mod activation;
+mod deadline;
+mod dns;
+pub mod ktls;
+pub mod pool;
+pub mod proxy_protocol;
+pub mod socket;
+pub mod tcp;
+pub mod unix;
+
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+
+pub use activation::{from_listen_fds, ListenFd};
+pub use dns::lookup_host;
+
+/// A connected stream socket, this lets utilities like [crate::codec::LengthDelimited] work with any of them.
+///
+/// This trait is sealed, it is implemented by [tcp::TcpStream], [unix::UnixStream] and references to them.
+pub trait StreamSocket: sealed::Sealed {}
+
+mod sealed {
+    use std::os::fd::RawFd;
+
+    pub trait Sealed {
+        fn socket_fd(&self) -> RawFd;
+
+        fn socket_deadlines(&self) -> &super::deadline::Deadlines;
+    }
+}
+
+impl sealed::Sealed for tcp::TcpStream {
+    fn socket_fd(&self) -> RawFd {
+        self.fd
+    }
+
+    fn socket_deadlines(&self) -> &deadline::Deadlines {
+        &self.deadlines
+    }
+}
+
+impl StreamSocket for tcp::TcpStream {}
+
+impl sealed::Sealed for unix::UnixStream {
+    fn socket_fd(&self) -> RawFd {
+        self.fd
+    }
+
+    fn socket_deadlines(&self) -> &deadline::Deadlines {
+        &self.deadlines
+    }
+}
+
+impl StreamSocket for unix::UnixStream {}
+
+impl<S: StreamSocket> sealed::Sealed for &S {
+    fn socket_fd(&self) -> RawFd {
+        (**self).socket_fd()
+    }
+
+    fn socket_deadlines(&self) -> &deadline::Deadlines {
+        (**self).socket_deadlines()
+    }
+}
+
+impl<S: StreamSocket> StreamSocket for &S {}
+
+pub(crate) fn stream_fd<S: StreamSocket>(stream: &S) -> RawFd {
+    stream.socket_fd()
+}
+
+/// Deadlines that reads and writes on the stream should apply, see [tcp::TcpStream::set_read_deadline].
+pub(crate) fn stream_deadlines<S: StreamSocket>(stream: &S) -> &deadline::Deadlines {
+    stream.socket_deadlines()
+}
+
+// Implements the std fd ownership traits for a socket type that owns `fd` and closes it when dropped.
+macro_rules! impl_socket_fd {
+    ($($t:ty),*) => {
+        $(
+            impl AsRawFd for $t {
+                fn as_raw_fd(&self) -> RawFd {
+                    self.fd
+                }
+            }
+
+            impl AsFd for $t {
+                fn as_fd(&self) -> BorrowedFd<'_> {
+                    unsafe { BorrowedFd::borrow_raw(self.fd) }
+                }
+            }
+
+            impl IntoRawFd for $t {
+                fn into_raw_fd(self) -> RawFd {
+                    let fd = self.fd;
+                    std::mem::forget(self);
+                    fd
+                }
+            }
+
+            impl FromRawFd for $t {
+                unsafe fn from_raw_fd(fd: RawFd) -> Self {
+                    Self::from_fd(fd)
+                }
+            }
+
+            impl From<OwnedFd> for $t {
+                fn from(fd: OwnedFd) -> Self {
+                    Self::from_fd(fd.into_raw_fd())
+                }
+            }
+
+            impl From<$t> for OwnedFd {
+                fn from(socket: $t) -> Self {
+                    unsafe { OwnedFd::from_raw_fd(socket.into_raw_fd()) }
+                }
+            }
+        )*
+    };
+}
+
+impl_socket_fd!(
+    tcp::TcpListener,
+    tcp::TcpStream,
+    unix::UnixListener,
+    unix::UnixStream,
+    unix::UnixDatagram
+);
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use super::*;
+    use crate::executor::ExecutorConfig;
+
+    #[test]
+    fn test_fd_conversions() {
+        ExecutorConfig::new()
+            .run(async {
+                let (a, b) = unix::UnixStream::pair().unwrap();
+                let fd = b.as_raw_fd();
+                assert_eq!(b.as_fd().as_raw_fd(), fd);
+
+                // The socket is handed to std and back without closing it.
+                let mut std_stream = std::os::unix::net::UnixStream::from(OwnedFd::from(b));
+                a.write_all(b"ping").await.unwrap();
+                let mut buf = [0; 4];
+                std_stream.read_exact(&mut buf).unwrap();
+                assert_eq!(&buf, b"ping");
+                std_stream.write_all(b"pong").unwrap();
+
+                let b = unix::UnixStream::from(OwnedFd::from(std_stream));
+                assert_eq!(b.as_raw_fd(), fd);
+                a.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"pong");
+                drop(a);
+                assert_eq!(b.read(&mut buf).await.unwrap(), 0);
+            })
+            .unwrap();
+    }
+}
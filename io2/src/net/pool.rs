@@ -0,0 +1,435 @@
+//! A pool of reusable connections.
+//!
+//! The pool is meant to be used by tasks of a single executor, connections never move between threads. Idle
+//! connections are reused in LIFO order so the ones that were used most recently, and are most likely to still be
+//! alive, are handed out first.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::rc::{Rc, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use crate::executor;
+use crate::local_alloc::LocalAlloc;
+use crate::slab;
+use crate::time::{now, sleep, sleep_until, NotifyWhen};
+
+type Connect<T> = Box<dyn Fn() -> Pin<Box<dyn Future<Output = io::Result<T>>>>>;
+// Rc so the check can run without holding a borrow of the pool.
+type Validate<T> = Rc<dyn for<'a> Fn(&'a mut T) -> Pin<Box<dyn Future<Output = bool> + 'a>>>;
+
+#[derive(Clone)]
+pub struct PoolConfig {
+    max_size: usize,
+    acquire_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+}
+
+impl PoolConfig {
+    pub fn new() -> Self {
+        Self {
+            max_size: 16,
+            acquire_timeout: None,
+            idle_timeout: None,
+        }
+    }
+
+    /// Maximum number of open connections, including the ones that are in use.
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        assert!(max_size > 0, "max_size must be greater than zero");
+        self.max_size = max_size;
+        self
+    }
+
+    /// Makes [Pool::acquire] fail with `TimedOut` if it can't get a connection in this duration.
+    pub fn acquire_timeout(mut self, acquire_timeout: Duration) -> Self {
+        self.acquire_timeout = Some(acquire_timeout);
+        self
+    }
+
+    /// Closes connections that stay idle in the pool for longer than this.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Inner<T> {
+    config: PoolConfig,
+    connect: Connect<T>,
+    validate: Option<Validate<T>>,
+    // Idle connections and the time they were returned to the pool, most recently returned is at the back.
+    idle: VecDeque<(T, Instant), LocalAlloc>,
+    num_open: usize,
+    // Tasks waiting for a connection to be returned or closed.
+    waiters: VecDeque<slab::Key, LocalAlloc>,
+}
+
+impl<T> Inner<T> {
+    fn remove_expired(&mut self) {
+        if let Some(idle_timeout) = self.config.idle_timeout {
+            let now = now();
+            let before = self.idle.len();
+            self.idle
+                .retain(|(_, at)| now.duration_since(*at) < idle_timeout);
+            self.num_open -= before - self.idle.len();
+        }
+    }
+
+    fn notify_waiter(&mut self) {
+        if let Some(task_id) = self.waiters.pop_front() {
+            executor::notify_task(task_id);
+        }
+    }
+}
+
+/// A pool of connections of type `T`, cloning it gives another handle to the same pool.
+pub struct Pool<T> {
+    inner: Rc<RefCell<Inner<T>>, LocalAlloc>,
+}
+
+impl<T> Clone for Pool<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T: 'static> Pool<T> {
+    /// Creates a pool that opens new connections with `connect`.
+    ///
+    /// If [PoolConfig::idle_timeout] is set this spawns a task that closes expired connections, so it has to be called
+    /// from inside an executor.
+    pub fn new<F, Fut>(config: PoolConfig, connect: F) -> Self
+    where
+        F: Fn() -> Fut + 'static,
+        Fut: Future<Output = io::Result<T>> + 'static,
+    {
+        let idle_timeout = config.idle_timeout;
+        let inner = Rc::new_in(
+            RefCell::new(Inner {
+                config,
+                connect: Box::new(move || Box::pin(connect())),
+                validate: None,
+                idle: VecDeque::new_in(LocalAlloc::new()),
+                num_open: 0,
+                waiters: VecDeque::new_in(LocalAlloc::new()),
+            }),
+            LocalAlloc::new(),
+        );
+        if let Some(idle_timeout) = idle_timeout {
+            executor::spawn(reap_idle(Rc::downgrade(&inner), idle_timeout));
+        }
+        Self { inner }
+    }
+
+    /// Sets a health check that is run on an idle connection before it is handed out, connections that fail it are
+    /// closed.
+    pub fn with_validation<F>(self, validate: F) -> Self
+    where
+        F: for<'a> Fn(&'a mut T) -> Pin<Box<dyn Future<Output = bool> + 'a>> + 'static,
+    {
+        self.inner.borrow_mut().validate = Some(Rc::new(validate));
+        self
+    }
+
+    /// Returns an idle connection or opens a new one if the pool isn't full, otherwise waits for a connection to be
+    /// returned.
+    pub async fn acquire(&self) -> io::Result<PooledConn<T>> {
+        let deadline = self
+            .inner
+            .borrow()
+            .config
+            .acquire_timeout
+            .map(|timeout| now() + timeout);
+
+        loop {
+            let idle = {
+                let mut inner = self.inner.borrow_mut();
+                inner.remove_expired();
+                inner.idle.pop_back()
+            };
+            if let Some((mut conn, _)) = idle {
+                if self.validate(&mut conn).await {
+                    return Ok(self.wrap(conn));
+                }
+                let mut inner = self.inner.borrow_mut();
+                inner.num_open -= 1;
+                inner.notify_waiter();
+                continue;
+            }
+
+            let connect = {
+                let mut inner = self.inner.borrow_mut();
+                if inner.num_open < inner.config.max_size {
+                    inner.num_open += 1;
+                    Some((inner.connect)())
+                } else {
+                    None
+                }
+            };
+            if let Some(connect) = connect {
+                return match connect.await {
+                    Ok(conn) => Ok(self.wrap(conn)),
+                    Err(e) => {
+                        let mut inner = self.inner.borrow_mut();
+                        inner.num_open -= 1;
+                        inner.notify_waiter();
+                        Err(e)
+                    }
+                };
+            }
+
+            if let Some(deadline) = deadline {
+                if now() >= deadline {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "timed out waiting for a connection",
+                    ));
+                }
+            }
+            WaitForConn {
+                inner: &self.inner,
+                timer: deadline.map(sleep_until),
+                registered: false,
+            }
+            .await;
+        }
+    }
+
+    /// Number of open connections, including the ones that are in use.
+    pub fn num_open(&self) -> usize {
+        self.inner.borrow().num_open
+    }
+
+    /// Number of idle connections in the pool.
+    pub fn num_idle(&self) -> usize {
+        self.inner.borrow().idle.len()
+    }
+
+    async fn validate(&self, conn: &mut T) -> bool {
+        let validate = self.inner.borrow().validate.clone();
+        match validate {
+            Some(validate) => validate(conn).await,
+            None => true,
+        }
+    }
+
+    fn wrap(&self, conn: T) -> PooledConn<T> {
+        PooledConn {
+            conn: Some(conn),
+            pool: Rc::downgrade(&self.inner),
+        }
+    }
+}
+
+async fn reap_idle<T>(inner: Weak<RefCell<Inner<T>>, LocalAlloc>, idle_timeout: Duration) {
+    loop {
+        sleep(idle_timeout / 2).await;
+        let inner = match inner.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+        let mut inner = inner.borrow_mut();
+        let before = inner.num_open;
+        inner.remove_expired();
+        for _ in inner.num_open..before {
+            inner.notify_waiter();
+        }
+    }
+}
+
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+struct WaitForConn<'pool, T> {
+    inner: &'pool Rc<RefCell<Inner<T>>, LocalAlloc>,
+    // Wakes the task at the acquire deadline, it is removed when this is dropped.
+    timer: Option<NotifyWhen>,
+    registered: bool,
+}
+
+impl<'pool, T> Future for WaitForConn<'pool, T> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let fut = self.get_mut();
+        let task_id = executor::current_task_id();
+        let mut inner = fut.inner.borrow_mut();
+        if fut.registered {
+            // Woken by a returned connection or the deadline, the caller checks which one.
+            inner.waiters.retain(|&id| id != task_id);
+            fut.registered = false;
+            return Poll::Ready(());
+        }
+        fut.registered = true;
+        inner.waiters.push_back(task_id);
+        if let Some(timer) = fut.timer.as_mut() {
+            let _ = Pin::new(timer).poll(cx);
+        }
+        Poll::Pending
+    }
+}
+
+impl<'pool, T> Drop for WaitForConn<'pool, T> {
+    fn drop(&mut self) {
+        if self.registered {
+            let task_id = executor::current_task_id();
+            let mut inner = self.inner.borrow_mut();
+            let len = inner.waiters.len();
+            inner.waiters.retain(|&id| id != task_id);
+            // The task was already woken for a returned connection, pass it on to the next waiter.
+            if inner.waiters.len() == len {
+                inner.notify_waiter();
+            }
+        }
+    }
+}
+
+/// A connection that is returned to the pool when it is dropped.
+pub struct PooledConn<T> {
+    conn: Option<T>,
+    pool: Weak<RefCell<Inner<T>>, LocalAlloc>,
+}
+
+impl<T> PooledConn<T> {
+    /// Closes the connection instead of returning it to the pool, e.g. after an io error left it in an unknown state.
+    pub fn discard(mut self) {
+        let conn = self.conn.take();
+        if let Some(inner) = self.pool.upgrade() {
+            let mut inner = inner.borrow_mut();
+            inner.num_open -= 1;
+            inner.notify_waiter();
+        }
+        std::mem::drop(conn);
+    }
+}
+
+impl<T> Deref for PooledConn<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for PooledConn<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for PooledConn<T> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            if let Some(inner) = self.pool.upgrade() {
+                let mut inner = inner.borrow_mut();
+                inner.idle.push_back((conn, now()));
+                inner.notify_waiter();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use crate::executor::{spawn, ExecutorConfig};
+
+    use super::*;
+
+    #[test]
+    fn test_pool() {
+        ExecutorConfig::new()
+            .run(async {
+                let next_id = Rc::new(Cell::new(0u32));
+                let connect_id = next_id.clone();
+                let pool = Pool::new(
+                    PoolConfig::new()
+                        .max_size(2)
+                        .acquire_timeout(Duration::from_millis(20))
+                        .idle_timeout(Duration::from_millis(50)),
+                    move || {
+                        let id = connect_id.get();
+                        connect_id.set(id + 1);
+                        async move { Ok(id) }
+                    },
+                )
+                // Connection 1 is broken.
+                .with_validation(|conn| Box::pin(async move { *conn != 1 }));
+
+                let a = pool.acquire().await.unwrap();
+                let b = pool.acquire().await.unwrap();
+                assert_eq!((*a, *b), (0, 1));
+                let err = pool.acquire().await.err().unwrap();
+                assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+                // A waiter gets the connection that is returned.
+                let waiter_pool = pool.clone();
+                let waiter = spawn(async move { *waiter_pool.acquire().await.unwrap() });
+                sleep(Duration::from_millis(5)).await;
+                std::mem::drop(a);
+                assert_eq!(waiter.await, 0);
+
+                // Connection 1 fails validation so a new one is opened.
+                std::mem::drop(b);
+                assert_eq!(pool.num_idle(), 2);
+                let c = pool.acquire().await.unwrap();
+                assert_eq!(*c, 0);
+                let d = pool.acquire().await.unwrap();
+                assert_eq!(*d, 2);
+                d.discard();
+                std::mem::drop(c);
+                assert_eq!((pool.num_open(), pool.num_idle()), (1, 1));
+
+                // Idle connections are closed after the idle timeout.
+                sleep(Duration::from_millis(100)).await;
+                assert_eq!((pool.num_open(), pool.num_idle()), (0, 0));
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_dropped_waiter() {
+        ExecutorConfig::new()
+            .run(async {
+                let pool = Pool::new(
+                    PoolConfig::new()
+                        .max_size(1)
+                        .acquire_timeout(Duration::from_secs(1)),
+                    || async { Ok(0u32) },
+                );
+                let conn = pool.acquire().await.unwrap();
+
+                // A dropped waiter is removed from the queue.
+                let waker = executor::noop_waker();
+                let mut cx = Context::from_waker(&waker);
+                let mut dropped = Box::pin(pool.acquire());
+                assert!(dropped.as_mut().poll(&mut cx).is_pending());
+                drop(dropped);
+                assert!(pool.inner.borrow().waiters.is_empty());
+
+                // A waiter that is dropped after it was woken for a returned connection passes it on.
+                let mut dropped = Box::pin(pool.acquire());
+                assert!(dropped.as_mut().poll(&mut cx).is_pending());
+                let waiter_pool = pool.clone();
+                let waiter = spawn(async move { *waiter_pool.acquire().await.unwrap() });
+                sleep(Duration::from_millis(5)).await;
+                drop(conn);
+                drop(dropped);
+                assert_eq!(waiter.await, 0);
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,265 @@
+//! PROXY protocol (v1 and v2) headers, for servers that run behind a load balancer like HAProxy or AWS NLB.
+//!
+//! The load balancer sends a header with the address of the original client before the data of the connection.
+//! [read_header] consumes only the header, so the stream can be handed to the application afterwards.
+//!
+//! Reading the header waits for the peer, so it is best done in the task that handles the connection. [accept] reads it
+//! in the accept loop with a deadline.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use super::socket::{self, Read};
+use super::tcp::{TcpListener, TcpStream};
+use super::{stream_deadlines, stream_fd, StreamSocket};
+use crate::time;
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Maximum length of a v1 header including the CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// Addresses sent in a PROXY protocol header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyHeader {
+    /// Address of the original client, `None` if the header doesn't carry addresses, e.g. health checks of the load
+    /// balancer (v2 LOCAL command) or v1 UNKNOWN.
+    pub source: Option<SocketAddr>,
+    /// Address the original client connected to.
+    pub destination: Option<SocketAddr>,
+}
+
+/// Accepts a connection and reads its PROXY protocol header, returning the original client address.
+///
+/// The address of the load balancer is returned if the header doesn't carry addresses. Reading the header fails with a
+/// `TimedOut` error if the whole header doesn't arrive within `timeout`, so a peer that stalls holds up an accept loop
+/// that calls this for at most `timeout`. [read_header] can be used in the task of the connection instead to not hold
+/// it up at all. The listener can still be used after an error from reading the header.
+pub async fn accept(
+    listener: &TcpListener,
+    timeout: Duration,
+) -> io::Result<(TcpStream, SocketAddr)> {
+    let (stream, peer) = listener.accept().await?;
+    let deadlines = stream_deadlines(&stream);
+    deadlines.set_read_until(Some(time::now() + timeout));
+    let header = read_header(&stream).await;
+    deadlines.set_read_until(None);
+    Ok((stream, header?.source.unwrap_or(peer)))
+}
+
+/// Reads a v1 or v2 PROXY protocol header from the start of `stream`.
+///
+/// Returns an `InvalidData` error if the stream doesn't start with a valid header.
+pub async fn read_header<S: StreamSocket>(stream: &S) -> io::Result<ProxyHeader> {
+    let fd = stream_fd(stream);
+    let deadlines = Some(stream_deadlines(stream));
+
+    // The shortest v1 header ("PROXY UNKNOWN\r\n") is longer than the v2 signature.
+    let mut start = [0; 12];
+    socket::read_exact(fd, deadlines, &mut start).await?;
+
+    if start == V2_SIGNATURE {
+        let mut info = [0; 4];
+        socket::read_exact(fd, deadlines, &mut info).await?;
+        let len = usize::from(u16::from_be_bytes([info[2], info[3]]));
+        let mut addrs = vec![0; len];
+        socket::read_exact(fd, deadlines, &mut addrs).await?;
+        return parse_v2(info[0], info[1], &addrs);
+    }
+
+    if !start.starts_with(b"PROXY ") {
+        return Err(invalid("missing PROXY protocol header"));
+    }
+
+    let mut line = start.to_vec();
+    let mut buf = [0; V1_MAX_LEN];
+    while !line.ends_with(b"\n") {
+        let remaining = V1_MAX_LEN - line.len();
+        if remaining == 0 {
+            return Err(invalid("PROXY protocol v1 header is too long"));
+        }
+        // Peek so the data after the header isn't consumed, only the part that belongs to the header is read.
+        let n = Read::new(fd, &mut buf[..remaining])
+            .flags(libc::MSG_PEEK)
+            .deadlines(stream_deadlines(stream))
+            .await?;
+        if n == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        let len = match buf[..n].iter().position(|&b| b == b'\n') {
+            Some(pos) => pos + 1,
+            None => n,
+        };
+        socket::read_exact(fd, deadlines, &mut buf[..len]).await?;
+        line.extend_from_slice(&buf[..len]);
+    }
+
+    parse_v1(&line)
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<ProxyHeader> {
+    let line = line
+        .strip_suffix(b"\r\n")
+        .and_then(|line| std::str::from_utf8(line).ok())
+        .ok_or_else(|| invalid("malformed PROXY protocol v1 header"))?;
+    let mut parts = line.split(' ').skip(1);
+
+    match parts.next() {
+        Some("UNKNOWN") => {
+            return Ok(ProxyHeader {
+                source: None,
+                destination: None,
+            })
+        }
+        Some("TCP4") | Some("TCP6") => {}
+        _ => return Err(invalid("unsupported protocol in PROXY protocol v1 header")),
+    }
+
+    let fields: Vec<&str> = parts.collect();
+    let [src_ip, dst_ip, src_port, dst_port] = fields[..] else {
+        return Err(invalid("malformed PROXY protocol v1 header"));
+    };
+    let addr = |ip: &str, port: &str| -> io::Result<SocketAddr> {
+        let ip: IpAddr = ip
+            .parse()
+            .map_err(|_| invalid("invalid address in PROXY protocol v1 header"))?;
+        let port: u16 = port
+            .parse()
+            .map_err(|_| invalid("invalid port in PROXY protocol v1 header"))?;
+        Ok(SocketAddr::new(ip, port))
+    };
+
+    Ok(ProxyHeader {
+        source: Some(addr(src_ip, src_port)?),
+        destination: Some(addr(dst_ip, dst_port)?),
+    })
+}
+
+fn parse_v2(ver_cmd: u8, family: u8, addrs: &[u8]) -> io::Result<ProxyHeader> {
+    if ver_cmd >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    let none = ProxyHeader {
+        source: None,
+        destination: None,
+    };
+    match ver_cmd & 0xf {
+        // LOCAL, the connection was made by the load balancer itself.
+        0 => return Ok(none),
+        // PROXY
+        1 => {}
+        _ => return Err(invalid("unsupported PROXY protocol v2 command")),
+    }
+
+    let port = |b: &[u8]| u16::from_be_bytes([b[0], b[1]]);
+    // Only TCP and UDP over IPv4/IPv6 carry socket addresses, the rest of the header is TLVs which are ignored.
+    match family >> 4 {
+        1 if addrs.len() >= 12 => {
+            let src = Ipv4Addr::from(<[u8; 4]>::try_from(&addrs[0..4]).unwrap());
+            let dst = Ipv4Addr::from(<[u8; 4]>::try_from(&addrs[4..8]).unwrap());
+            Ok(ProxyHeader {
+                source: Some(SocketAddr::new(src.into(), port(&addrs[8..10]))),
+                destination: Some(SocketAddr::new(dst.into(), port(&addrs[10..12]))),
+            })
+        }
+        2 if addrs.len() >= 36 => {
+            let src = Ipv6Addr::from(<[u8; 16]>::try_from(&addrs[0..16]).unwrap());
+            let dst = Ipv6Addr::from(<[u8; 16]>::try_from(&addrs[16..32]).unwrap());
+            Ok(ProxyHeader {
+                source: Some(SocketAddr::new(src.into(), port(&addrs[32..34]))),
+                destination: Some(SocketAddr::new(dst.into(), port(&addrs[34..36]))),
+            })
+        }
+        1 | 2 => Err(invalid(
+            "PROXY protocol v2 header is too short for its addresses",
+        )),
+        _ => Ok(none),
+    }
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::executor::ExecutorConfig;
+    use crate::net::unix::UnixStream;
+
+    use super::*;
+
+    #[test]
+    fn test_read_header() {
+        ExecutorConfig::new()
+            .run(async {
+                let (a, b) = UnixStream::pair().unwrap();
+
+                a.write_all(b"PROXY TCP4 192.168.0.1 10.0.0.1 56324 443\r\nGET /")
+                    .await
+                    .unwrap();
+                let header = read_header(&b).await.unwrap();
+                assert_eq!(header.source, Some("192.168.0.1:56324".parse().unwrap()));
+                assert_eq!(header.destination, Some("10.0.0.1:443".parse().unwrap()));
+                let mut rest = [0; 5];
+                b.read_exact(&mut rest).await.unwrap();
+                assert_eq!(&rest, b"GET /");
+
+                let mut v2 = V2_SIGNATURE.to_vec();
+                v2.extend_from_slice(&[0x21, 0x21, 0, 36 + 3]);
+                v2.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+                v2.extend_from_slice(&Ipv6Addr::UNSPECIFIED.octets());
+                v2.extend_from_slice(&1234u16.to_be_bytes());
+                v2.extend_from_slice(&80u16.to_be_bytes());
+                // A TLV with no value.
+                v2.extend_from_slice(&[0x04, 0, 0]);
+                v2.extend_from_slice(b"data");
+                a.write_all(&v2).await.unwrap();
+                let header = read_header(&b).await.unwrap();
+                assert_eq!(header.source, Some("[::1]:1234".parse().unwrap()));
+                assert_eq!(header.destination, Some("[::]:80".parse().unwrap()));
+                let mut rest = [0; 4];
+                b.read_exact(&mut rest).await.unwrap();
+                assert_eq!(&rest, b"data");
+
+                a.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+                let err = read_header(&b).await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_accept() {
+        ExecutorConfig::new()
+            .run(async {
+                let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+                let addr = listener.local_addr().unwrap();
+
+                // Sends the start of a header and stalls.
+                let stalled = TcpStream::connect(addr).await.unwrap();
+                stalled.write_all(b"PROXY TCP4 ").await.unwrap();
+                let start = std::time::Instant::now();
+                let err = accept(&listener, Duration::from_millis(20))
+                    .await
+                    .err()
+                    .unwrap();
+                assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+                assert!(start.elapsed() < Duration::from_secs(5));
+
+                let client = TcpStream::connect(addr).await.unwrap();
+                client
+                    .write_all(b"PROXY TCP4 192.168.0.1 10.0.0.1 56324 443\r\nGET /")
+                    .await
+                    .unwrap();
+                let (stream, source) = accept(&listener, Duration::from_millis(200)).await.unwrap();
+                assert_eq!(source, "192.168.0.1:56324".parse().unwrap());
+                // The header deadline doesn't apply to the reads after it.
+                time::sleep(Duration::from_millis(250)).await;
+                let mut rest = [0; 5];
+                stream.read_exact(&mut rest).await.unwrap();
+                assert_eq!(&rest, b"GET /");
+                drop(stalled);
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,1214 @@
+use std::cell::Cell;
+use std::future::Future;
+use std::io;
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::fd::RawFd;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use io_uring::types::Fd;
+use io_uring::{cqueue, opcode};
+use pin_project_lite::pin_project;
+
+use crate::executor::{NextMultishot, RawIo, CURRENT_TASK_CONTEXT, FILES_TO_CLOSE};
+use crate::local_alloc::LocalAlloc;
+use crate::slab;
+
+use super::deadline::{self, Deadlines, OpDeadline};
+
+pub(crate) fn new_socket(domain: i32, ty: i32) -> io::Result<RawFd> {
+    match unsafe { libc::socket(domain, ty | libc::SOCK_CLOEXEC, 0) } {
+        -1 => Err(io::Error::last_os_error()),
+        fd => Ok(fd),
+    }
+}
+
+fn cancel_io(io_id: Option<slab::Key>) {
+    if let Some(io_id) = io_id {
+        CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| ctx.as_mut().unwrap().cancel_io(io_id));
+    }
+}
+
+/// Queues the fd to be closed by the executor, same as dropping a `File`.
+pub(crate) fn close_fd(fd: RawFd) {
+    FILES_TO_CLOSE.with_borrow_mut(|files| {
+        files.push(fd);
+    });
+}
+
+pub(crate) fn setsockopt<T>(fd: RawFd, level: i32, name: i32, val: T) -> io::Result<()> {
+    match unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &val as *const T as *const libc::c_void,
+            libc::socklen_t::try_from(size_of::<T>()).unwrap(),
+        )
+    } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn getsockopt_int(fd: RawFd, level: i32, name: i32) -> io::Result<i32> {
+    let mut val: i32 = 0;
+    let mut len = libc::socklen_t::try_from(size_of::<i32>()).unwrap();
+    match unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            &mut val as *mut i32 as *mut libc::c_void,
+            &mut len,
+        )
+    } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(val),
+    }
+}
+
+pub(crate) fn socket_addr_to_raw(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let raw = &mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in;
+            unsafe {
+                (*raw).sin_family = libc::AF_INET as libc::sa_family_t;
+                (*raw).sin_port = addr.port().to_be();
+                (*raw).sin_addr = libc::in_addr {
+                    s_addr: u32::from_ne_bytes(addr.ip().octets()),
+                };
+            }
+            size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let raw = &mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6;
+            unsafe {
+                (*raw).sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                (*raw).sin6_port = addr.port().to_be();
+                (*raw).sin6_flowinfo = addr.flowinfo();
+                (*raw).sin6_addr = libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                };
+                (*raw).sin6_scope_id = addr.scope_id();
+            }
+            size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    (storage, libc::socklen_t::try_from(len).unwrap())
+}
+
+pub(crate) fn raw_to_socket_addr(storage: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+    match i32::from(storage.ss_family) {
+        libc::AF_INET => {
+            let raw =
+                unsafe { &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in) };
+            Ok(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(raw.sin_addr.s_addr.to_ne_bytes()),
+                u16::from_be(raw.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let raw = unsafe {
+                &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in6)
+            };
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(raw.sin6_addr.s6_addr),
+                u16::from_be(raw.sin6_port),
+                raw.sin6_flowinfo,
+                raw.sin6_scope_id,
+            )))
+        }
+        family => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unsupported address family: {}", family),
+        )),
+    }
+}
+
+fn getname(
+    fd: RawFd,
+    f: unsafe extern "C" fn(i32, *mut libc::sockaddr, *mut libc::socklen_t) -> i32,
+) -> io::Result<SocketAddr> {
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = libc::socklen_t::try_from(size_of::<libc::sockaddr_storage>()).unwrap();
+    match unsafe {
+        f(
+            fd,
+            &mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+        )
+    } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => raw_to_socket_addr(&storage),
+    }
+}
+
+pub(crate) fn local_addr(fd: RawFd) -> io::Result<SocketAddr> {
+    getname(fd, libc::getsockname)
+}
+
+pub(crate) fn peer_addr(fd: RawFd) -> io::Result<SocketAddr> {
+    getname(fd, libc::getpeername)
+}
+
+pin_project! {
+    #[must_use = "futures do nothing unless you `.await` or poll them"]
+    pub(crate) struct Accept {
+        fd: RawFd,
+        #[pin] addr: libc::sockaddr_storage,
+        #[pin] addr_len: libc::socklen_t,
+        io_id: Option<slab::Key>,
+        _non_send: PhantomData<*mut ()>,
+    }
+}
+
+impl Accept {
+    pub(crate) fn new(fd: RawFd) -> Self {
+        Self {
+            fd,
+            addr: unsafe { std::mem::zeroed() },
+            addr_len: libc::socklen_t::try_from(size_of::<libc::sockaddr_storage>()).unwrap(),
+            io_id: None,
+            _non_send: PhantomData,
+        }
+    }
+}
+
+impl Future for Accept {
+    type Output = io::Result<(RawFd, libc::sockaddr_storage)>;
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+            let ctx = ctx.as_mut().unwrap();
+            let fut = self.project();
+            match fut.io_id {
+                None => {
+                    *fut.io_id = Some(unsafe {
+                        ctx.queue_io(
+                            opcode::Accept::new(
+                                Fd(*fut.fd),
+                                &*fut.addr as *const libc::sockaddr_storage as *mut _,
+                                &*fut.addr_len as *const libc::socklen_t as *mut _,
+                            )
+                            .flags(libc::SOCK_CLOEXEC)
+                            .build(),
+                            false,
+                        )
+                    });
+                    Poll::Pending
+                }
+                Some(io_id) => {
+                    let io_result = match ctx.take_io_result(*io_id) {
+                        Some(io_result) => io_result,
+                        None => {
+                            return Poll::Pending;
+                        }
+                    };
+
+                    if io_result < 0 {
+                        Poll::Ready(Err(io::Error::from_raw_os_error(-io_result)))
+                    } else {
+                        Poll::Ready(Ok((io_result, *fut.addr)))
+                    }
+                }
+            }
+        })
+    }
+}
+
+pin_project! {
+    #[must_use = "futures do nothing unless you `.await` or poll them"]
+    pub(crate) struct Connect {
+        fd: RawFd,
+        #[pin] addr: libc::sockaddr_storage,
+        addr_len: libc::socklen_t,
+        io_id: Option<slab::Key>,
+        _non_send: PhantomData<*mut ()>,
+    }
+}
+
+impl Connect {
+    pub(crate) fn new(fd: RawFd, addr: &SocketAddr) -> Self {
+        let (addr, addr_len) = socket_addr_to_raw(addr);
+        Self::from_raw(fd, addr, addr_len)
+    }
+
+    pub(crate) fn from_raw(
+        fd: RawFd,
+        addr: libc::sockaddr_storage,
+        addr_len: libc::socklen_t,
+    ) -> Self {
+        Self {
+            fd,
+            addr,
+            addr_len,
+            io_id: None,
+            _non_send: PhantomData,
+        }
+    }
+
+    /// Queues cancellation of a running connect, the future still has to be polled until it completes.
+    pub(crate) fn cancel(&self) {
+        cancel_io(self.io_id);
+    }
+}
+
+impl Future for Connect {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+            let ctx = ctx.as_mut().unwrap();
+            let fut = self.project();
+            match fut.io_id {
+                None => {
+                    *fut.io_id = Some(unsafe {
+                        ctx.queue_io(
+                            opcode::Connect::new(
+                                Fd(*fut.fd),
+                                &*fut.addr as *const libc::sockaddr_storage as *const _,
+                                *fut.addr_len,
+                            )
+                            .build(),
+                            false,
+                        )
+                    });
+                    Poll::Pending
+                }
+                Some(io_id) => {
+                    let io_result = match ctx.take_io_result(*io_id) {
+                        Some(io_result) => io_result,
+                        None => {
+                            return Poll::Pending;
+                        }
+                    };
+
+                    if io_result < 0 {
+                        Poll::Ready(Err(io::Error::from_raw_os_error(-io_result)))
+                    } else {
+                        Poll::Ready(Ok(()))
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// Shuts down the read half, the write half or both halves of a connection.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Shutdown<'socket> {
+    fd: RawFd,
+    how: i32,
+    io_id: Option<slab::Key>,
+    _socket: PhantomData<&'socket ()>,
+    _non_send: PhantomData<*mut ()>,
+}
+
+impl<'socket> Shutdown<'socket> {
+    pub(crate) fn new(fd: RawFd, how: std::net::Shutdown) -> Self {
+        let how = match how {
+            std::net::Shutdown::Read => libc::SHUT_RD,
+            std::net::Shutdown::Write => libc::SHUT_WR,
+            std::net::Shutdown::Both => libc::SHUT_RDWR,
+        };
+        Self {
+            fd,
+            how,
+            io_id: None,
+            _socket: PhantomData,
+            _non_send: PhantomData,
+        }
+    }
+}
+
+impl<'socket> Future for Shutdown<'socket> {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+            let ctx = ctx.as_mut().unwrap();
+            let fut = self.get_mut();
+            match fut.io_id {
+                None => {
+                    fut.io_id = Some(unsafe {
+                        ctx.queue_io(opcode::Shutdown::new(Fd(fut.fd), fut.how).build(), false)
+                    });
+                    Poll::Pending
+                }
+                Some(io_id) => {
+                    let io_result = match ctx.take_io_result(io_id) {
+                        Some(io_result) => io_result,
+                        None => {
+                            return Poll::Pending;
+                        }
+                    };
+
+                    if io_result < 0 {
+                        Poll::Ready(Err(io::Error::from_raw_os_error(-io_result)))
+                    } else {
+                        Poll::Ready(Ok(()))
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Read<'socket, 'buf> {
+    pub(crate) fd: RawFd,
+    pub(crate) buf: &'buf mut [u8],
+    pub(crate) flags: i32,
+    pub(crate) io_id: Option<slab::Key>,
+    pub(crate) deadline: Option<OpDeadline<'socket>>,
+    pub(crate) _socket: PhantomData<&'socket ()>,
+    pub(crate) _non_send: PhantomData<*mut ()>,
+}
+
+impl<'socket, 'buf> Read<'socket, 'buf> {
+    pub(crate) fn new(fd: RawFd, buf: &'buf mut [u8]) -> Self {
+        Self {
+            fd,
+            buf,
+            flags: 0,
+            io_id: None,
+            deadline: None,
+            _socket: PhantomData,
+            _non_send: PhantomData,
+        }
+    }
+
+    /// Adds `MSG_*` flags to the recv, e.g. `libc::MSG_PEEK` or `libc::MSG_OOB`.
+    pub fn flags(mut self, flags: i32) -> Self {
+        self.flags |= flags;
+        self
+    }
+
+    /// Queues cancellation of a running read, the future still has to be polled until it completes.
+    pub(crate) fn cancel(&self) {
+        cancel_io(self.io_id);
+    }
+
+    /// Applies the read deadline and idle timeout of the stream.
+    pub(crate) fn deadlines(mut self, deadlines: &'socket Deadlines) -> Self {
+        self.deadline = Some(OpDeadline::new(deadlines, deadline::Op::Read));
+        self
+    }
+}
+
+impl<'socket, 'buf> Future for Read<'socket, 'buf> {
+    type Output = io::Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let fut = self.get_mut();
+        if fut.io_id.is_none() {
+            if let Some(Err(e)) = fut.deadline.as_mut().map(|deadline| deadline.start()) {
+                return Poll::Ready(Err(e));
+            }
+        }
+        match fut.poll_io() {
+            Poll::Ready(res) => match &mut fut.deadline {
+                Some(deadline) => Poll::Ready(deadline.complete(res)),
+                None => Poll::Ready(res),
+            },
+            Poll::Pending => {
+                if fut
+                    .deadline
+                    .as_mut()
+                    .is_some_and(|deadline| deadline.poll_expired(cx))
+                {
+                    // The operation can't be dropped while it is running, it completes with ECANCELED.
+                    cancel_io(fut.io_id);
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<'socket, 'buf> Read<'socket, 'buf> {
+    fn poll_io(&mut self) -> Poll<io::Result<usize>> {
+        CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+            let ctx = ctx.as_mut().unwrap();
+            let fut = self;
+            match fut.io_id {
+                None => {
+                    fut.io_id = Some(unsafe {
+                        ctx.queue_io(
+                            opcode::Recv::new(
+                                Fd(fut.fd),
+                                fut.buf.as_mut_ptr(),
+                                fut.buf.len().try_into().unwrap(),
+                            )
+                            .flags(fut.flags)
+                            .build(),
+                            false,
+                        )
+                    });
+                    Poll::Pending
+                }
+                Some(io_id) => {
+                    let io_result = match ctx.take_io_result(io_id) {
+                        Some(io_result) => io_result,
+                        None => {
+                            return Poll::Pending;
+                        }
+                    };
+
+                    if io_result < 0 {
+                        Poll::Ready(Err(io::Error::from_raw_os_error(-io_result)))
+                    } else {
+                        Poll::Ready(Ok(io_result.try_into().unwrap()))
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Write<'socket, 'buf> {
+    pub(crate) fd: RawFd,
+    pub(crate) buf: &'buf [u8],
+    pub(crate) flags: i32,
+    pub(crate) io_id: Option<slab::Key>,
+    pub(crate) deadline: Option<OpDeadline<'socket>>,
+    pub(crate) _socket: PhantomData<&'socket ()>,
+    pub(crate) _non_send: PhantomData<*mut ()>,
+}
+
+impl<'socket, 'buf> Write<'socket, 'buf> {
+    pub(crate) fn new(fd: RawFd, buf: &'buf [u8]) -> Self {
+        Self {
+            fd,
+            buf,
+            // Don't raise SIGPIPE if the peer closed the connection, return EPIPE instead.
+            flags: libc::MSG_NOSIGNAL,
+            io_id: None,
+            deadline: None,
+            _socket: PhantomData,
+            _non_send: PhantomData,
+        }
+    }
+
+    /// Applies the write deadline and idle timeout of the stream.
+    pub(crate) fn deadlines(mut self, deadlines: &'socket Deadlines) -> Self {
+        self.deadline = Some(OpDeadline::new(deadlines, deadline::Op::Write));
+        self
+    }
+}
+
+impl<'socket, 'buf> Future for Write<'socket, 'buf> {
+    type Output = io::Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let fut = self.get_mut();
+        if fut.io_id.is_none() {
+            if let Some(Err(e)) = fut.deadline.as_mut().map(|deadline| deadline.start()) {
+                return Poll::Ready(Err(e));
+            }
+        }
+        match fut.poll_io() {
+            Poll::Ready(res) => match &mut fut.deadline {
+                Some(deadline) => Poll::Ready(deadline.complete(res)),
+                None => Poll::Ready(res),
+            },
+            Poll::Pending => {
+                if fut
+                    .deadline
+                    .as_mut()
+                    .is_some_and(|deadline| deadline.poll_expired(cx))
+                {
+                    // The operation can't be dropped while it is running, it completes with ECANCELED.
+                    cancel_io(fut.io_id);
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<'socket, 'buf> Write<'socket, 'buf> {
+    fn poll_io(&mut self) -> Poll<io::Result<usize>> {
+        CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+            let ctx = ctx.as_mut().unwrap();
+            let fut = self;
+            match fut.io_id {
+                None => {
+                    fut.io_id = Some(unsafe {
+                        ctx.queue_io(
+                            opcode::Send::new(
+                                Fd(fut.fd),
+                                fut.buf.as_ptr(),
+                                fut.buf.len().try_into().unwrap(),
+                            )
+                            .flags(fut.flags)
+                            .build(),
+                            false,
+                        )
+                    });
+                    Poll::Pending
+                }
+                Some(io_id) => {
+                    let io_result = match ctx.take_io_result(io_id) {
+                        Some(io_result) => io_result,
+                        None => {
+                            return Poll::Pending;
+                        }
+                    };
+
+                    if io_result < 0 {
+                        Poll::Ready(Err(io::Error::from_raw_os_error(-io_result)))
+                    } else {
+                        Poll::Ready(Ok(io_result.try_into().unwrap()))
+                    }
+                }
+            }
+        })
+    }
+}
+
+pub(crate) async fn write_all(
+    fd: RawFd,
+    deadlines: Option<&Deadlines>,
+    buf: &[u8],
+) -> io::Result<()> {
+    let mut buf = buf;
+
+    while !buf.is_empty() {
+        let mut write = Write::new(fd, buf);
+        if let Some(deadlines) = deadlines {
+            write = write.deadlines(deadlines);
+        }
+        match write.await {
+            Ok(0) => {
+                return Err(io::Error::from(io::ErrorKind::WriteZero));
+            }
+            Ok(n) => {
+                buf = &buf[n..];
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn read_exact(
+    fd: RawFd,
+    deadlines: Option<&Deadlines>,
+    buf: &mut [u8],
+) -> io::Result<()> {
+    let mut buf = buf;
+
+    while !buf.is_empty() {
+        let mut read = Read::new(fd, buf);
+        if let Some(deadlines) = deadlines {
+            read = read.deadlines(deadlines);
+        }
+        match read.await {
+            Ok(0) => break,
+            Ok(n) => {
+                buf = &mut buf[n..];
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    if !buf.is_empty() {
+        Err(io::Error::from(io::ErrorKind::UnexpectedEof))
+    } else {
+        Ok(())
+    }
+}
+
+/// Allocates a buffer that can hold a SCM_RIGHTS control message with `num_fds` file descriptors.
+///
+/// It is allocated as u64 so it is aligned for `libc::cmsghdr`.
+fn cmsg_buffer(num_fds: usize) -> Vec<u64, LocalAlloc> {
+    let space = unsafe { libc::CMSG_SPACE(u32::try_from(num_fds * size_of::<RawFd>()).unwrap()) };
+    let len = usize::try_from(space).unwrap().div_ceil(size_of::<u64>());
+    let mut buf = Vec::with_capacity_in(len, LocalAlloc::new());
+    buf.resize(len, 0);
+    buf
+}
+
+/// Sends data along with file descriptors (SCM_RIGHTS) over a unix socket.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct SendMsg<'socket, 'buf, 'fds> {
+    fd: RawFd,
+    buf: &'buf [u8],
+    fds: &'fds [RawFd],
+    iov: libc::iovec,
+    msghdr: libc::msghdr,
+    cmsg: Vec<u64, LocalAlloc>,
+    io_id: Option<slab::Key>,
+    _socket: PhantomData<&'socket ()>,
+    _non_send: PhantomData<*mut ()>,
+}
+
+impl<'socket, 'buf, 'fds> SendMsg<'socket, 'buf, 'fds> {
+    pub(crate) fn new(fd: RawFd, buf: &'buf [u8], fds: &'fds [RawFd]) -> Self {
+        Self {
+            fd,
+            buf,
+            fds,
+            iov: unsafe { std::mem::zeroed() },
+            msghdr: unsafe { std::mem::zeroed() },
+            cmsg: cmsg_buffer(fds.len()),
+            io_id: None,
+            _socket: PhantomData,
+            _non_send: PhantomData,
+        }
+    }
+}
+
+impl<'socket, 'buf, 'fds> Future for SendMsg<'socket, 'buf, 'fds> {
+    type Output = io::Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+            let ctx = ctx.as_mut().unwrap();
+            let fut = self.get_mut();
+            match fut.io_id {
+                None => {
+                    // msghdr points into the future itself so it is filled here, after the future is pinned.
+                    fut.iov.iov_base = fut.buf.as_ptr() as *mut libc::c_void;
+                    fut.iov.iov_len = fut.buf.len();
+                    fut.msghdr.msg_iov = &mut fut.iov;
+                    fut.msghdr.msg_iovlen = 1;
+                    if !fut.fds.is_empty() {
+                        fut.msghdr.msg_control = fut.cmsg.as_mut_ptr() as *mut libc::c_void;
+                        fut.msghdr.msg_controllen = fut.cmsg.len() * size_of::<u64>();
+                        unsafe {
+                            let cmsg = libc::CMSG_FIRSTHDR(&fut.msghdr);
+                            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+                            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+                            (*cmsg).cmsg_len = usize::try_from(libc::CMSG_LEN(
+                                u32::try_from(std::mem::size_of_val(fut.fds)).unwrap(),
+                            ))
+                            .unwrap();
+                            std::ptr::copy_nonoverlapping(
+                                fut.fds.as_ptr(),
+                                libc::CMSG_DATA(cmsg) as *mut RawFd,
+                                fut.fds.len(),
+                            );
+                        }
+                    }
+                    fut.io_id = Some(unsafe {
+                        ctx.queue_io(
+                            opcode::SendMsg::new(Fd(fut.fd), &fut.msghdr)
+                                .flags(u32::try_from(libc::MSG_NOSIGNAL).unwrap())
+                                .build(),
+                            false,
+                        )
+                    });
+                    Poll::Pending
+                }
+                Some(io_id) => {
+                    let io_result = match ctx.take_io_result(io_id) {
+                        Some(io_result) => io_result,
+                        None => {
+                            return Poll::Pending;
+                        }
+                    };
+
+                    if io_result < 0 {
+                        Poll::Ready(Err(io::Error::from_raw_os_error(-io_result)))
+                    } else {
+                        Poll::Ready(Ok(io_result.try_into().unwrap()))
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// Receives data along with file descriptors (SCM_RIGHTS) over a unix socket.
+///
+/// Resolves to the number of bytes and the number of file descriptors received.
+/// Received file descriptors are written to the start of `fds` and they are owned by the caller.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct RecvMsg<'socket, 'buf, 'fds> {
+    fd: RawFd,
+    buf: &'buf mut [u8],
+    fds: &'fds mut [RawFd],
+    flags: i32,
+    iov: libc::iovec,
+    msghdr: libc::msghdr,
+    cmsg: Vec<u64, LocalAlloc>,
+    io_id: Option<slab::Key>,
+    _socket: PhantomData<&'socket ()>,
+    _non_send: PhantomData<*mut ()>,
+}
+
+impl<'socket, 'buf, 'fds> RecvMsg<'socket, 'buf, 'fds> {
+    pub(crate) fn new(fd: RawFd, buf: &'buf mut [u8], fds: &'fds mut [RawFd]) -> Self {
+        let cmsg = cmsg_buffer(fds.len());
+        Self {
+            fd,
+            buf,
+            fds,
+            flags: libc::MSG_CMSG_CLOEXEC,
+            iov: unsafe { std::mem::zeroed() },
+            msghdr: unsafe { std::mem::zeroed() },
+            cmsg,
+            io_id: None,
+            _socket: PhantomData,
+            _non_send: PhantomData,
+        }
+    }
+
+    /// Adds `MSG_*` flags to the recv, e.g. `libc::MSG_PEEK` or `libc::MSG_OOB`.
+    pub fn flags(mut self, flags: i32) -> Self {
+        self.flags |= flags;
+        self
+    }
+}
+
+impl<'socket, 'buf, 'fds> Future for RecvMsg<'socket, 'buf, 'fds> {
+    type Output = io::Result<(usize, usize)>;
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+            let ctx = ctx.as_mut().unwrap();
+            let fut = self.get_mut();
+            match fut.io_id {
+                None => {
+                    // msghdr points into the future itself so it is filled here, after the future is pinned.
+                    fut.iov.iov_base = fut.buf.as_mut_ptr() as *mut libc::c_void;
+                    fut.iov.iov_len = fut.buf.len();
+                    fut.msghdr.msg_iov = &mut fut.iov;
+                    fut.msghdr.msg_iovlen = 1;
+                    if !fut.fds.is_empty() {
+                        fut.msghdr.msg_control = fut.cmsg.as_mut_ptr() as *mut libc::c_void;
+                        fut.msghdr.msg_controllen = fut.cmsg.len() * size_of::<u64>();
+                    }
+                    fut.io_id = Some(unsafe {
+                        ctx.queue_io(
+                            opcode::RecvMsg::new(Fd(fut.fd), &mut fut.msghdr)
+                                .flags(u32::try_from(fut.flags).unwrap())
+                                .build(),
+                            false,
+                        )
+                    });
+                    Poll::Pending
+                }
+                Some(io_id) => {
+                    let io_result = match ctx.take_io_result(io_id) {
+                        Some(io_result) => io_result,
+                        None => {
+                            return Poll::Pending;
+                        }
+                    };
+
+                    if io_result < 0 {
+                        return Poll::Ready(Err(io::Error::from_raw_os_error(-io_result)));
+                    }
+
+                    let mut num_fds = 0;
+                    unsafe {
+                        let mut cmsg = libc::CMSG_FIRSTHDR(&fut.msghdr);
+                        while !cmsg.is_null() {
+                            if (*cmsg).cmsg_level == libc::SOL_SOCKET
+                                && (*cmsg).cmsg_type == libc::SCM_RIGHTS
+                            {
+                                let data_len = (*cmsg).cmsg_len
+                                    - usize::try_from(libc::CMSG_LEN(0)).unwrap();
+                                let n = data_len / size_of::<RawFd>();
+                                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                                for i in 0..n {
+                                    let fd = data.add(i).read_unaligned();
+                                    match fut.fds.get_mut(num_fds) {
+                                        Some(slot) => {
+                                            *slot = fd;
+                                            num_fds += 1;
+                                        }
+                                        // Kernel shouldn't send more than the buffer can hold, but don't leak them if it does.
+                                        None => close_fd(fd),
+                                    }
+                                }
+                            }
+                            cmsg = libc::CMSG_NXTHDR(&fut.msghdr, cmsg);
+                        }
+                    }
+
+                    if fut.msghdr.msg_flags & libc::MSG_CTRUNC != 0 {
+                        log::warn!("control message was truncated while receiving file descriptors, some of them were dropped by the kernel");
+                    }
+
+                    Poll::Ready(Ok((io_result.try_into().unwrap(), num_fds)))
+                }
+            }
+        })
+    }
+}
+
+/// Sends a datagram to the given address.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct SendTo<'socket, 'buf> {
+    fd: RawFd,
+    buf: &'buf [u8],
+    addr: libc::sockaddr_storage,
+    iov: libc::iovec,
+    msghdr: libc::msghdr,
+    io_id: Option<slab::Key>,
+    _socket: PhantomData<&'socket ()>,
+    _non_send: PhantomData<*mut ()>,
+}
+
+impl<'socket, 'buf> SendTo<'socket, 'buf> {
+    pub(crate) fn new(
+        fd: RawFd,
+        buf: &'buf [u8],
+        addr: libc::sockaddr_storage,
+        addr_len: libc::socklen_t,
+    ) -> Self {
+        let mut msghdr: libc::msghdr = unsafe { std::mem::zeroed() };
+        msghdr.msg_namelen = addr_len;
+        Self {
+            fd,
+            buf,
+            addr,
+            iov: unsafe { std::mem::zeroed() },
+            msghdr,
+            io_id: None,
+            _socket: PhantomData,
+            _non_send: PhantomData,
+        }
+    }
+}
+
+impl<'socket, 'buf> Future for SendTo<'socket, 'buf> {
+    type Output = io::Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+            let ctx = ctx.as_mut().unwrap();
+            let fut = self.get_mut();
+            match fut.io_id {
+                None => {
+                    // msghdr points into the future itself so it is filled here, after the future is pinned.
+                    fut.iov.iov_base = fut.buf.as_ptr() as *mut libc::c_void;
+                    fut.iov.iov_len = fut.buf.len();
+                    fut.msghdr.msg_iov = &mut fut.iov;
+                    fut.msghdr.msg_iovlen = 1;
+                    fut.msghdr.msg_name =
+                        &mut fut.addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+                    fut.io_id = Some(unsafe {
+                        ctx.queue_io(
+                            opcode::SendMsg::new(Fd(fut.fd), &fut.msghdr)
+                                .flags(u32::try_from(libc::MSG_NOSIGNAL).unwrap())
+                                .build(),
+                            false,
+                        )
+                    });
+                    Poll::Pending
+                }
+                Some(io_id) => {
+                    let io_result = match ctx.take_io_result(io_id) {
+                        Some(io_result) => io_result,
+                        None => {
+                            return Poll::Pending;
+                        }
+                    };
+
+                    if io_result < 0 {
+                        Poll::Ready(Err(io::Error::from_raw_os_error(-io_result)))
+                    } else {
+                        Poll::Ready(Ok(io_result.try_into().unwrap()))
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// Receives a datagram along with the address it was sent from.
+///
+/// Resolves to the number of bytes received and the raw address of the sender, the address length is zero if the
+/// sender is unnamed.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct RecvFrom<'socket, 'buf> {
+    fd: RawFd,
+    buf: &'buf mut [u8],
+    flags: i32,
+    addr: libc::sockaddr_storage,
+    iov: libc::iovec,
+    msghdr: libc::msghdr,
+    io_id: Option<slab::Key>,
+    _socket: PhantomData<&'socket ()>,
+    _non_send: PhantomData<*mut ()>,
+}
+
+impl<'socket, 'buf> RecvFrom<'socket, 'buf> {
+    pub(crate) fn new(fd: RawFd, buf: &'buf mut [u8]) -> Self {
+        Self {
+            fd,
+            buf,
+            flags: 0,
+            addr: unsafe { std::mem::zeroed() },
+            iov: unsafe { std::mem::zeroed() },
+            msghdr: unsafe { std::mem::zeroed() },
+            io_id: None,
+            _socket: PhantomData,
+            _non_send: PhantomData,
+        }
+    }
+
+    /// Adds `MSG_*` flags to the recv, e.g. `libc::MSG_PEEK`.
+    pub fn flags(mut self, flags: i32) -> Self {
+        self.flags |= flags;
+        self
+    }
+}
+
+impl<'socket, 'buf> Future for RecvFrom<'socket, 'buf> {
+    type Output = io::Result<(usize, libc::sockaddr_storage, libc::socklen_t)>;
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+            let ctx = ctx.as_mut().unwrap();
+            let fut = self.get_mut();
+            match fut.io_id {
+                None => {
+                    // msghdr points into the future itself so it is filled here, after the future is pinned.
+                    fut.iov.iov_base = fut.buf.as_mut_ptr() as *mut libc::c_void;
+                    fut.iov.iov_len = fut.buf.len();
+                    fut.msghdr.msg_iov = &mut fut.iov;
+                    fut.msghdr.msg_iovlen = 1;
+                    fut.msghdr.msg_name =
+                        &mut fut.addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+                    fut.msghdr.msg_namelen =
+                        libc::socklen_t::try_from(size_of::<libc::sockaddr_storage>()).unwrap();
+                    fut.io_id = Some(unsafe {
+                        ctx.queue_io(
+                            opcode::RecvMsg::new(Fd(fut.fd), &mut fut.msghdr)
+                                .flags(u32::try_from(fut.flags).unwrap())
+                                .build(),
+                            false,
+                        )
+                    });
+                    Poll::Pending
+                }
+                Some(io_id) => {
+                    let io_result = match ctx.take_io_result(io_id) {
+                        Some(io_result) => io_result,
+                        None => {
+                            return Poll::Pending;
+                        }
+                    };
+
+                    if io_result < 0 {
+                        return Poll::Ready(Err(io::Error::from_raw_os_error(-io_result)));
+                    }
+
+                    Poll::Ready(Ok((
+                        io_result.try_into().unwrap(),
+                        fut.addr,
+                        fut.msghdr.msg_namelen,
+                    )))
+                }
+            }
+        })
+    }
+}
+
+thread_local! {
+    static NEXT_BUFFER_GROUP: Cell<u16> = const { Cell::new(0) };
+}
+
+/// Receives data using a multishot recv, so a single submission keeps receiving until the connection is closed.
+///
+/// The kernel picks a buffer from a group of buffers provided by this stream for each completion.
+/// Only one buffer is lent to the caller at a time, it is given back to the kernel on the next call to [RecvStream::next].
+///
+/// [RecvStream::close] should be awaited before dropping this if the stream didn't reach the end. Dropping it while the
+/// recv is still running cancels it but leaks the buffers since the kernel might still be writing to them.
+pub struct RecvStream<'socket> {
+    fd: RawFd,
+    buf_group: u16,
+    buf_size: usize,
+    num_bufs: u16,
+    bufs: Vec<u8, LocalAlloc>,
+    provided: bool,
+    lent: Option<u16>,
+    io_id: Option<slab::Key>,
+    done: bool,
+    _socket: PhantomData<&'socket ()>,
+    _non_send: PhantomData<*mut ()>,
+}
+
+impl<'socket> RecvStream<'socket> {
+    pub(crate) fn new(fd: RawFd, buf_size: usize, num_bufs: u16) -> Self {
+        assert!(buf_size > 0 && num_bufs > 0);
+        let buf_group = NEXT_BUFFER_GROUP.with(|next| {
+            let group = next.get();
+            next.set(group.wrapping_add(1));
+            group
+        });
+        let len = buf_size.checked_mul(usize::from(num_bufs)).unwrap();
+        let mut bufs = Vec::with_capacity_in(len, LocalAlloc::new());
+        bufs.resize(len, 0);
+        Self {
+            fd,
+            buf_group,
+            buf_size,
+            num_bufs,
+            bufs,
+            provided: false,
+            lent: None,
+            io_id: None,
+            done: false,
+            _socket: PhantomData,
+            _non_send: PhantomData,
+        }
+    }
+
+    async fn provide(&mut self, start: u16, num: u16) -> io::Result<()> {
+        let ptr = unsafe {
+            self.bufs
+                .as_mut_ptr()
+                .add(usize::from(start) * self.buf_size)
+        };
+        let entry = opcode::ProvideBuffers::new(
+            ptr,
+            i32::try_from(self.buf_size).unwrap(),
+            num,
+            self.buf_group,
+            start,
+        )
+        .build();
+        match unsafe { RawIo::new(entry) }.await {
+            res if res < 0 => Err(io::Error::from_raw_os_error(-res)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the next chunk of received data, `None` means the peer closed the connection.
+    pub async fn next(&mut self) -> Option<io::Result<&[u8]>> {
+        if self.done {
+            return None;
+        }
+
+        let res = if !self.provided {
+            self.provided = true;
+            self.provide(0, self.num_bufs).await
+        } else if let Some(buf_id) = self.lent.take() {
+            self.provide(buf_id, 1).await
+        } else {
+            Ok(())
+        };
+        if let Err(e) = res {
+            self.done = true;
+            return Some(Err(e));
+        }
+
+        loop {
+            let io_id = match self.io_id {
+                Some(io_id) => io_id,
+                None => {
+                    let entry = opcode::RecvMulti::new(Fd(self.fd), self.buf_group).build();
+                    let io_id = CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| unsafe {
+                        ctx.as_mut().unwrap().queue_multishot_io(entry)
+                    });
+                    self.io_id = Some(io_id);
+                    io_id
+                }
+            };
+
+            let (res, flags) = NextMultishot { io_id }.await;
+            if !cqueue::more(flags) {
+                self.io_id = None;
+            }
+
+            if res == -libc::ENOBUFS {
+                // All buffers were used before we could give them back, buffers were given back when the queued
+                // completions were consumed so it is ok to restart.
+                continue;
+            }
+            if res < 0 {
+                self.done = true;
+                return Some(Err(io::Error::from_raw_os_error(-res)));
+            }
+            if res == 0 {
+                self.done = true;
+                return None;
+            }
+
+            let buf_id = cqueue::buffer_select(flags).unwrap();
+            self.lent = Some(buf_id);
+            let start = usize::from(buf_id) * self.buf_size;
+            let len = usize::try_from(res).unwrap();
+            return Some(Ok(&self.bufs[start..start + len]));
+        }
+    }
+
+    /// Cancels the recv if it is running and gives the buffers back.
+    pub async fn close(mut self) -> io::Result<()> {
+        if let Some(io_id) = self.io_id {
+            let cancel = opcode::AsyncCancel::new(io_id.into()).build();
+            unsafe { RawIo::new(cancel) }.await;
+            // Wait for the final completion so the kernel isn't using the buffers anymore.
+            while let Some(io_id) = self.io_id {
+                let (_, flags) = NextMultishot { io_id }.await;
+                if !cqueue::more(flags) {
+                    self.io_id = None;
+                }
+            }
+        }
+        if self.provided {
+            self.provided = false;
+            let remove = opcode::RemoveBuffers::new(self.num_bufs, self.buf_group).build();
+            let res = unsafe { RawIo::new(remove) }.await;
+            // ENOENT means all buffers were already consumed.
+            if res < 0 && res != -libc::ENOENT {
+                return Err(io::Error::from_raw_os_error(-res));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'socket> Drop for RecvStream<'socket> {
+    fn drop(&mut self) {
+        CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+            let ctx = match ctx.as_mut() {
+                Some(ctx) => ctx,
+                None => {
+                    if self.io_id.is_some() || self.provided {
+                        std::mem::forget(std::mem::replace(
+                            &mut self.bufs,
+                            Vec::new_in(LocalAlloc::new()),
+                        ));
+                    }
+                    return;
+                }
+            };
+            if let Some(io_id) = self.io_id {
+                log::warn!("RecvStream dropped while receiving, leaking its buffers. RecvStream::close should be awaited before dropping it.");
+                ctx.abandon_multishot_io(io_id);
+                unsafe {
+                    ctx.queue_detached_io(opcode::AsyncCancel::new(io_id.into()).build());
+                }
+                std::mem::forget(std::mem::replace(
+                    &mut self.bufs,
+                    Vec::new_in(LocalAlloc::new()),
+                ));
+            }
+            if self.provided {
+                unsafe {
+                    ctx.queue_detached_io(
+                        opcode::RemoveBuffers::new(self.num_bufs, self.buf_group).build(),
+                    );
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,740 @@
+use std::cell::RefCell;
+use std::future::Future;
+use std::io;
+use std::marker::PhantomData;
+use std::net::{Shutdown, SocketAddr};
+use std::os::fd::RawFd;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::executor;
+use crate::local_alloc::LocalAlloc;
+use crate::slab;
+use crate::time::{self, now, sleep_until};
+
+use super::deadline::Deadlines;
+use super::ktls::{self, KtlsKeys};
+use super::socket::{self, Accept, Connect, Read, RecvStream, Write};
+
+pub struct TcpListener {
+    pub(crate) fd: RawFd,
+    _non_send: PhantomData<*mut ()>,
+}
+
+impl TcpListener {
+    pub(crate) fn from_fd(fd: RawFd) -> Self {
+        Self {
+            fd,
+            _non_send: PhantomData,
+        }
+    }
+
+    /// Creates a listener bound to the given address.
+    ///
+    /// Socket creation, bind and listen don't block so they are done with regular syscalls.
+    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+        Self::bind_with(addr, false)
+    }
+
+    /// Creates a listener with SO_REUSEPORT bound to the given address.
+    ///
+    /// Every executor thread can bind its own listener to the same address this way and accept connections without
+    /// sharing a socket, the kernel spreads new connections over the listeners by a hash of the connection. Binding
+    /// fails if the address is used by a socket that doesn't have SO_REUSEPORT or belongs to another user.
+    pub fn bind_reuseport(addr: SocketAddr) -> io::Result<Self> {
+        Self::bind_with(addr, true)
+    }
+
+    fn bind_with(addr: SocketAddr, reuseport: bool) -> io::Result<Self> {
+        let listener = Self {
+            fd: socket::new_socket(domain_of(&addr), libc::SOCK_STREAM)?,
+            _non_send: PhantomData,
+        };
+        socket::setsockopt(listener.fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1i32)?;
+        if reuseport {
+            socket::setsockopt(listener.fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1i32)?;
+        }
+        let (raw_addr, addr_len) = socket::socket_addr_to_raw(&addr);
+        if unsafe {
+            libc::bind(
+                listener.fd,
+                &raw_addr as *const libc::sockaddr_storage as *const libc::sockaddr,
+                addr_len,
+            )
+        } == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { libc::listen(listener.fd, 1024) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(listener)
+    }
+
+    /// Makes the kernel pick the listener of a new connection by the CPU that received it, instead of by a hash.
+    ///
+    /// This attaches a BPF program to the SO_REUSEPORT group of this listener that returns the CPU number, which the
+    /// kernel uses as the index of the listener in the group. Listeners are indexed in the order they were bound, so
+    /// with thread `i` pinned to CPU `i` binding its listener `i`-th, connections are accepted on the CPU that handles
+    /// their packets when the NIC queues are also steered that way. Connections on a CPU without a listener fall back
+    /// to the hash. It only has to be called on one listener of the group.
+    pub fn attach_cpu_steering(&self) -> io::Result<()> {
+        let mut filter = [
+            libc::sock_filter {
+                code: (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16,
+                jt: 0,
+                jf: 0,
+                k: (libc::SKF_AD_OFF + libc::SKF_AD_CPU) as u32,
+            },
+            libc::sock_filter {
+                code: (libc::BPF_RET | libc::BPF_A) as u16,
+                jt: 0,
+                jf: 0,
+                k: 0,
+            },
+        ];
+        let prog = libc::sock_fprog {
+            len: filter.len() as u16,
+            filter: filter.as_mut_ptr(),
+        };
+        socket::setsockopt(
+            self.fd,
+            libc::SOL_SOCKET,
+            libc::SO_ATTACH_REUSEPORT_CBPF,
+            prog,
+        )
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        socket::local_addr(self.fd)
+    }
+
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let (fd, addr) = Accept::new(self.fd).await?;
+        let stream = TcpStream::from_fd(fd);
+        let addr = socket::raw_to_socket_addr(&addr)?;
+        Ok((stream, addr))
+    }
+
+    /// Returns an accept loop over this listener.
+    pub fn incoming(&self) -> Incoming<'_> {
+        Incoming { listener: self }
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        socket::close_fd(self.fd);
+    }
+}
+
+pub struct Incoming<'listener> {
+    listener: &'listener TcpListener,
+}
+
+impl<'listener> Incoming<'listener> {
+    pub async fn next(&mut self) -> io::Result<(TcpStream, SocketAddr)> {
+        self.listener.accept().await
+    }
+
+    /// Stops accepting new connections while `limit` connections are being processed.
+    ///
+    /// Each accepted connection comes with a [ConnectionPermit] and accepting resumes automatically once
+    /// enough of the permits are dropped.
+    pub fn limit_concurrent(self, limit: usize) -> LimitConcurrent<'listener> {
+        assert!(limit > 0, "concurrency limit must be greater than zero");
+        LimitConcurrent {
+            listener: self.listener,
+            state: Rc::new_in(
+                RefCell::new(LimitState {
+                    limit,
+                    active: 0,
+                    waiting: None,
+                }),
+                LocalAlloc::new(),
+            ),
+        }
+    }
+}
+
+struct LimitState {
+    limit: usize,
+    active: usize,
+    waiting: Option<slab::Key>,
+}
+
+pub struct LimitConcurrent<'listener> {
+    listener: &'listener TcpListener,
+    state: Rc<RefCell<LimitState>, LocalAlloc>,
+}
+
+impl<'listener> LimitConcurrent<'listener> {
+    /// Waits for a free slot and then accepts the next connection.
+    ///
+    /// The listener isn't accepting while there is no free slot so new connections queue up in the kernel backlog.
+    pub async fn next(&mut self) -> io::Result<(TcpStream, SocketAddr, ConnectionPermit)> {
+        let permit = WaitForSlot { state: &self.state }.await;
+        let (stream, addr) = self.listener.accept().await?;
+        Ok((stream, addr, permit))
+    }
+
+    /// Number of connections that are currently holding a permit.
+    pub fn active(&self) -> usize {
+        self.state.borrow().active
+    }
+}
+
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+struct WaitForSlot<'a> {
+    state: &'a Rc<RefCell<LimitState>, LocalAlloc>,
+}
+
+impl<'a> Future for WaitForSlot<'a> {
+    type Output = ConnectionPermit;
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.borrow_mut();
+        if state.active < state.limit {
+            state.active += 1;
+            state.waiting = None;
+            Poll::Ready(ConnectionPermit {
+                state: self.state.clone(),
+            })
+        } else {
+            state.waiting = Some(executor::current_task_id());
+            Poll::Pending
+        }
+    }
+}
+
+/// Marks a connection as being processed, dropping it allows [LimitConcurrent] to accept another connection.
+pub struct ConnectionPermit {
+    state: Rc<RefCell<LimitState>, LocalAlloc>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut state = self.state.borrow_mut();
+        state.active = state.active.checked_sub(1).unwrap();
+        if let Some(task_id) = state.waiting.take() {
+            executor::notify_task(task_id);
+        }
+    }
+}
+
+pub struct TcpStream {
+    pub(crate) fd: RawFd,
+    pub(crate) deadlines: Deadlines,
+    _non_send: PhantomData<*mut ()>,
+}
+
+impl TcpStream {
+    pub(crate) fn from_fd(fd: RawFd) -> Self {
+        Self {
+            fd,
+            deadlines: Deadlines::new(),
+            _non_send: PhantomData,
+        }
+    }
+
+    pub async fn connect(addr: SocketAddr) -> io::Result<Self> {
+        let stream = Self::from_fd(socket::new_socket(domain_of(&addr), libc::SOCK_STREAM)?);
+        Connect::new(stream.fd, &addr).await?;
+        Ok(stream)
+    }
+
+    /// Connects to the first of `addrs` that accepts the connection, racing the attempts as described in RFC 8305
+    /// (Happy Eyeballs).
+    ///
+    /// Addresses are tried alternating between IPv6 and IPv4, starting with IPv6. A new attempt is started when the
+    /// previous one fails or after [CONNECTION_ATTEMPT_DELAY] without waiting for it, the first connection that succeeds
+    /// is returned and the rest of the attempts are cancelled. The error of the last attempt is returned if all of them
+    /// fail.
+    pub async fn connect_addrs(addrs: &[SocketAddr]) -> io::Result<Self> {
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no addresses to connect to",
+            ));
+        }
+
+        let mut addrs = interleave_families(addrs).into_iter();
+        // Connects are boxed because the kernel reads the address from the future after it is submitted.
+        let mut attempts: Vec<(TcpStream, Pin<Box<Connect>>)> = Vec::new();
+        let mut last_err = None;
+        let mut delay = time::sleep(Duration::ZERO);
+
+        let res = std::future::poll_fn(|cx| {
+            let mut start_next = true;
+            loop {
+                if start_next {
+                    match addrs.next() {
+                        Some(addr) => match socket::new_socket(domain_of(&addr), libc::SOCK_STREAM)
+                        {
+                            Ok(fd) => {
+                                attempts
+                                    .push((Self::from_fd(fd), Box::pin(Connect::new(fd, &addr))));
+                                delay = time::sleep(CONNECTION_ATTEMPT_DELAY);
+                            }
+                            Err(e) => {
+                                last_err = Some(e);
+                                continue;
+                            }
+                        },
+                        None if attempts.is_empty() => {
+                            return Poll::Ready(Err(last_err.take().unwrap()))
+                        }
+                        None => delay.cancel(),
+                    }
+                }
+
+                start_next = false;
+                let mut i = 0;
+                while i < attempts.len() {
+                    match attempts[i].1.as_mut().poll(cx) {
+                        Poll::Pending => i += 1,
+                        Poll::Ready(Ok(())) => return Poll::Ready(Ok(attempts.swap_remove(i).0)),
+                        Poll::Ready(Err(e)) => {
+                            // The attempt is complete so it can be dropped, this closes its socket.
+                            drop(attempts.swap_remove(i));
+                            last_err = Some(e);
+                            start_next = true;
+                        }
+                    }
+                }
+
+                if !start_next {
+                    start_next = Pin::new(&mut delay).poll(cx).is_ready();
+                }
+                if !start_next {
+                    return Poll::Pending;
+                }
+            }
+        })
+        .await;
+
+        // The losing attempts have to complete before their futures can be dropped.
+        for (_, connect) in attempts.iter() {
+            connect.cancel();
+        }
+        for (_, connect) in attempts.iter_mut() {
+            let _ = connect.as_mut().await;
+        }
+
+        res
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        socket::local_addr(self.fd)
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        socket::peer_addr(self.fd)
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        socket::setsockopt(
+            self.fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_NODELAY,
+            i32::from(nodelay),
+        )
+    }
+
+    pub fn read<'stream, 'buf>(&'stream self, buf: &'buf mut [u8]) -> Read<'stream, 'buf> {
+        Read::new(self.fd, buf).deadlines(&self.deadlines)
+    }
+
+    /// Receives data without removing it from the socket, the next read returns the same bytes.
+    ///
+    /// This is useful for detecting the protocol of a connection, e.g. telling TLS apart from plaintext.
+    pub fn recv_peek<'stream, 'buf>(&'stream self, buf: &'buf mut [u8]) -> Read<'stream, 'buf> {
+        Read::new(self.fd, buf)
+            .flags(libc::MSG_PEEK)
+            .deadlines(&self.deadlines)
+    }
+
+    pub fn write<'stream, 'buf>(&'stream self, buf: &'buf [u8]) -> Write<'stream, 'buf> {
+        Write::new(self.fd, buf).deadlines(&self.deadlines)
+    }
+
+    pub async fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        socket::write_all(self.fd, Some(&self.deadlines), buf).await
+    }
+
+    pub async fn read_exact(&self, buf: &mut [u8]) -> io::Result<()> {
+        socket::read_exact(self.fd, Some(&self.deadlines), buf).await
+    }
+
+    /// Makes reads fail with a `TimedOut` error if they don't complete within `timeout`, `None` removes the limit.
+    ///
+    /// This applies to reads through the methods of the stream and through wrappers like [crate::codec::LengthDelimited].
+    /// A read that times out is cancelled, so no data is lost, but a `read_exact` that timed out leaves the stream at
+    /// an unknown position.
+    pub fn set_read_deadline(&self, timeout: Option<Duration>) {
+        self.deadlines.set_read(timeout);
+    }
+
+    /// Like [TcpStream::set_read_deadline] for writes.
+    pub fn set_write_deadline(&self, timeout: Option<Duration>) {
+        self.deadlines.set_write(timeout);
+    }
+
+    /// Makes reads and writes fail with a `TimedOut` error once nothing was read from or written to the stream for
+    /// `timeout`, `None` removes the limit.
+    ///
+    /// Every completed read or write counts as activity, so a read that waits for the next request doesn't time out
+    /// while a response is still being written. This is meant for shedding clients that stopped talking without
+    /// wrapping every call in a timeout.
+    pub fn set_idle_timeout(&self, timeout: Option<Duration>) {
+        self.deadlines.set_idle(timeout);
+    }
+
+    /// Shuts down one or both halves of the connection, the peer reads EOF after the write half is shut down.
+    pub fn shutdown(&self, how: Shutdown) -> socket::Shutdown<'_> {
+        socket::Shutdown::new(self.fd, how)
+    }
+
+    /// Closes the connection after making sure the peer received everything that was written.
+    ///
+    /// This shuts down the write half and discards incoming data until the peer closes its side too. Closing a socket
+    /// that has unread data makes the kernel send a reset, which can make the peer lose data it didn't read yet.
+    ///
+    /// Returns a `TimedOut` error if the peer doesn't close its side within `timeout`, the connection is closed in
+    /// either case.
+    pub async fn graceful_close(self, timeout: Duration) -> io::Result<()> {
+        self.shutdown(Shutdown::Write).await?;
+
+        let mut timer = sleep_until(now() + timeout);
+        let mut timed_out = false;
+        let mut buf = [0; 4096];
+        loop {
+            let mut read = Read::new(self.fd, &mut buf);
+            let res = std::future::poll_fn(|cx| {
+                if let Poll::Ready(res) = Pin::new(&mut read).poll(cx) {
+                    return Poll::Ready(res);
+                }
+                if !timed_out && Pin::new(&mut timer).poll(cx).is_ready() {
+                    // The read can't be dropped while it is running, it completes with ECANCELED.
+                    read.cancel();
+                    timed_out = true;
+                }
+                Poll::Pending
+            })
+            .await;
+
+            match res {
+                _ if timed_out => return Err(io::Error::from(io::ErrorKind::TimedOut)),
+                Ok(0) => return Ok(()),
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Hands the TLS session keys to the kernel so the given directions are encrypted/decrypted by the kernel.
+    ///
+    /// This should be called right after the handshake, before any application data is read or written in
+    /// userspace. After this, [TcpStream::read] and [TcpStream::write] operate on plaintext.
+    ///
+    /// Fails with `Unsupported` if the kernel doesn't have the tls ULP, e.g. when the tls module isn't loaded.
+    pub fn enable_ktls(&self, tx: Option<&KtlsKeys>, rx: Option<&KtlsKeys>) -> io::Result<()> {
+        ktls::enable(self.fd, tx, rx)
+    }
+
+    /// Starts a multishot recv that keeps receiving into `num_bufs` buffers of `buf_size` bytes.
+    ///
+    /// This saves a submission per read compared to [TcpStream::read] which is useful for chatty protocols.
+    pub fn recv_stream(&self, buf_size: usize, num_bufs: u16) -> RecvStream<'_> {
+        RecvStream::new(self.fd, buf_size, num_bufs)
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        socket::close_fd(self.fd);
+    }
+}
+
+/// Time to wait for a connection attempt before starting the next one in [TcpStream::connect_addrs].
+///
+/// This is the default "Connection Attempt Delay" recommended by RFC 8305.
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Orders addresses alternating between IPv6 and IPv4 starting with IPv6, keeping the order within each family.
+fn interleave_families(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let mut v6 = addrs.iter().filter(|addr| addr.is_ipv6());
+    let mut v4 = addrs.iter().filter(|addr| addr.is_ipv4());
+    let mut out = Vec::with_capacity(addrs.len());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return out,
+            (a, b) => out.extend(a.into_iter().chain(b).copied()),
+        }
+    }
+}
+
+fn domain_of(addr: &SocketAddr) -> i32 {
+    match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use crate::executor::{spawn, ExecutorConfig};
+    use crate::time::sleep;
+
+    use super::*;
+
+    #[test]
+    fn test_connect_addrs() {
+        let addrs: Vec<SocketAddr> = ["1.1.1.1:1", "2.2.2.2:1", "[::1]:1", "3.3.3.3:1", "[::2]:1"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let order: Vec<String> = interleave_families(&addrs)
+            .iter()
+            .map(|addr| addr.to_string())
+            .collect();
+        assert_eq!(
+            order,
+            ["[::1]:1", "1.1.1.1:1", "[::2]:1", "2.2.2.2:1", "3.3.3.3:1"]
+        );
+
+        ExecutorConfig::new()
+            .run(async {
+                let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+                let addr = listener.local_addr().unwrap();
+                // Closed right away, unlike sockets of this crate which are closed by the executor.
+                let closed = std::net::TcpListener::bind("127.0.0.1:0")
+                    .unwrap()
+                    .local_addr()
+                    .unwrap();
+                let closed_v6 = SocketAddr::new("::1".parse().unwrap(), closed.port());
+
+                // The failing attempts make the next one start right away.
+                let start = std::time::Instant::now();
+                let stream = TcpStream::connect_addrs(&[closed, addr, closed_v6])
+                    .await
+                    .unwrap();
+                assert_eq!(stream.peer_addr().unwrap(), addr);
+                assert!(start.elapsed() < CONNECTION_ATTEMPT_DELAY);
+
+                let err = TcpStream::connect_addrs(&[closed])
+                    .await
+                    .map(|_| ())
+                    .unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_recv_peek() {
+        ExecutorConfig::new()
+            .run(async {
+                let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+                let addr = listener.local_addr().unwrap();
+                let client = spawn(async move {
+                    let stream = TcpStream::connect(addr).await.unwrap();
+                    // TLS handshake record header.
+                    stream.write_all(&[0x16, 0x03, 0x01]).await.unwrap();
+                    stream
+                });
+                let (stream, _) = listener.accept().await.unwrap();
+                let _client = client.await;
+
+                let mut first = [0; 1];
+                assert_eq!(stream.recv_peek(&mut first).await.unwrap(), 1);
+                assert_eq!(first[0], 0x16);
+                let mut buf = [0; 3];
+                stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(buf, [0x16, 0x03, 0x01]);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_graceful_close() {
+        ExecutorConfig::new()
+            .run(async {
+                let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+                let addr = listener.local_addr().unwrap();
+
+                let client = spawn(async move {
+                    let stream = TcpStream::connect(addr).await.unwrap();
+                    stream.write_all(b"request").await.unwrap();
+                    stream.graceful_close(Duration::from_secs(10)).await
+                });
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 7];
+                stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"request");
+                // The client half-closed so this reads EOF, then closing this side lets the client finish.
+                assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+                stream.write_all(b"response").await.unwrap();
+                stream.shutdown(Shutdown::Both).await.unwrap();
+                client.await.unwrap();
+
+                // A peer that never closes its side.
+                let client = spawn(async move {
+                    let stream = TcpStream::connect(addr).await.unwrap();
+                    stream.graceful_close(Duration::from_millis(10)).await
+                });
+                let (_stream, _) = listener.accept().await.unwrap();
+                let err = client.await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_bind_reuseport() {
+        ExecutorConfig::new()
+            .run(async {
+                let a = TcpListener::bind_reuseport("127.0.0.1:0".parse().unwrap()).unwrap();
+                let addr = a.local_addr().unwrap();
+                let b = TcpListener::bind_reuseport(addr).unwrap();
+                let err = TcpListener::bind(addr).err().unwrap();
+                assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+                a.attach_cpu_steering().unwrap();
+
+                let stream = TcpStream::connect(addr).await.unwrap();
+                stream.write_all(b"x").await.unwrap();
+                // The connection is queued on one of the listeners.
+                let mut fds = [a.fd, b.fd].map(|fd| libc::pollfd {
+                    fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                });
+                assert_eq!(unsafe { libc::poll(fds.as_mut_ptr(), 2, 1000) }, 1);
+                let listener = if fds[0].revents != 0 { &a } else { &b };
+                let (accepted, _) = listener.accept().await.unwrap();
+                let mut buf = [0];
+                accepted.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"x");
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_limit_concurrent() {
+        ExecutorConfig::new()
+            .run(async {
+                let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+                let addr = listener.local_addr().unwrap();
+
+                let client = spawn(async move {
+                    let mut readers = Vec::new();
+                    for i in 0..6u8 {
+                        let stream = TcpStream::connect(addr).await.unwrap();
+                        stream.write_all(&[i]).await.unwrap();
+                        readers.push(spawn(async move {
+                            let mut buf = [0];
+                            stream.read_exact(&mut buf).await.unwrap();
+                            assert_eq!(buf[0], i);
+                        }));
+                    }
+                    for reader in readers {
+                        reader.await;
+                    }
+                });
+
+                let max_seen = Rc::new(Cell::new(0));
+                let mut incoming = listener.incoming().limit_concurrent(2);
+                let mut handlers = Vec::new();
+                for _ in 0..6 {
+                    let (stream, _, permit) = incoming.next().await.unwrap();
+                    max_seen.set(max_seen.get().max(incoming.active()));
+                    handlers.push(spawn(async move {
+                        let mut buf = [0];
+                        stream.read_exact(&mut buf).await.unwrap();
+                        sleep(std::time::Duration::from_millis(5)).await;
+                        stream.write_all(&buf).await.unwrap();
+                        std::mem::drop(permit);
+                    }));
+                }
+                for handler in handlers {
+                    handler.await;
+                }
+                client.await;
+
+                assert_eq!(max_seen.get(), 2);
+                assert_eq!(incoming.active(), 0);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_ktls() {
+        use crate::net::ktls::{CryptoInfo, TlsVersion};
+
+        let keys = KtlsKeys {
+            version: TlsVersion::Tls13,
+            crypto: CryptoInfo::Aes128Gcm {
+                key: [7; 16],
+                iv: [3; 8],
+                salt: [1; 4],
+                rec_seq: [0; 8],
+            },
+        };
+
+        ExecutorConfig::new()
+            .run(async move {
+                let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+                let addr = listener.local_addr().unwrap();
+                let client = TcpStream::connect(addr).await.unwrap();
+                let (server, _) = listener.accept().await.unwrap();
+
+                match client.enable_ktls(Some(&keys), None) {
+                    Ok(()) => {}
+                    // The tls module isn't available in this environment, there is nothing to test.
+                    Err(e) if e.kind() == io::ErrorKind::Unsupported => return,
+                    Err(e) => panic!("failed to enable ktls: {}", e),
+                }
+                server.enable_ktls(None, Some(&keys)).unwrap();
+
+                client.write_all(b"encrypted by the kernel").await.unwrap();
+                let mut buf = [0; 23];
+                server.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"encrypted by the kernel");
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_recv_stream() {
+        ExecutorConfig::new()
+            .run(async {
+                let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+                let addr = listener.local_addr().unwrap();
+
+                let client = spawn(async move {
+                    let stream = TcpStream::connect(addr).await.unwrap();
+                    for i in 0..64u32 {
+                        stream.write_all(&i.to_le_bytes()).await.unwrap();
+                    }
+                });
+
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut recv = stream.recv_stream(16, 2);
+                let mut received = Vec::new();
+                while let Some(data) = recv.next().await {
+                    received.extend_from_slice(data.unwrap());
+                }
+                recv.close().await.unwrap();
+                client.await;
+
+                let expected = (0..64u32).flat_map(|i| i.to_le_bytes()).collect::<Vec<_>>();
+                assert_eq!(received, expected);
+            })
+            .unwrap();
+    }
+}
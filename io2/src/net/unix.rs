@@ -0,0 +1,475 @@
+use std::io;
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::deadline::Deadlines;
+use super::socket::{self, Accept, Connect, Read, RecvFrom, RecvMsg, SendMsg, SendTo, Write};
+
+pub struct UnixListener {
+    pub(crate) fd: RawFd,
+    _non_send: PhantomData<*mut ()>,
+}
+
+impl UnixListener {
+    pub(crate) fn from_fd(fd: RawFd) -> Self {
+        Self {
+            fd,
+            _non_send: PhantomData,
+        }
+    }
+
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        Self::bind_with_type(path, libc::SOCK_STREAM)
+    }
+
+    /// Binds a SOCK_SEQPACKET listener, connections accepted from it keep message boundaries.
+    ///
+    /// Each read on an accepted stream returns at most one message and the rest of a message that doesn't fit into the
+    /// buffer is discarded.
+    pub fn bind_seqpacket(path: &Path) -> io::Result<Self> {
+        Self::bind_with_type(path, libc::SOCK_SEQPACKET)
+    }
+
+    fn bind_with_type(path: &Path, ty: i32) -> io::Result<Self> {
+        let listener = Self {
+            fd: socket::new_socket(libc::AF_UNIX, ty)?,
+            _non_send: PhantomData,
+        };
+        let (addr, addr_len) = unix_addr(path)?;
+        if unsafe {
+            libc::bind(
+                listener.fd,
+                &addr as *const libc::sockaddr_storage as *const libc::sockaddr,
+                addr_len,
+            )
+        } == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { libc::listen(listener.fd, 1024) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(listener)
+    }
+
+    pub async fn accept(&self) -> io::Result<UnixStream> {
+        let (fd, _) = Accept::new(self.fd).await?;
+        Ok(UnixStream::from_fd(fd))
+    }
+}
+
+impl Drop for UnixListener {
+    fn drop(&mut self) {
+        socket::close_fd(self.fd);
+    }
+}
+
+pub struct UnixStream {
+    pub(crate) fd: RawFd,
+    pub(crate) deadlines: Deadlines,
+    _non_send: PhantomData<*mut ()>,
+}
+
+impl UnixStream {
+    pub(crate) fn from_fd(fd: RawFd) -> Self {
+        Self {
+            fd,
+            deadlines: Deadlines::new(),
+            _non_send: PhantomData,
+        }
+    }
+
+    pub async fn connect(path: &Path) -> io::Result<Self> {
+        Self::connect_with_type(path, libc::SOCK_STREAM).await
+    }
+
+    /// Connects to a listener created with [UnixListener::bind_seqpacket].
+    pub async fn connect_seqpacket(path: &Path) -> io::Result<Self> {
+        Self::connect_with_type(path, libc::SOCK_SEQPACKET).await
+    }
+
+    async fn connect_with_type(path: &Path, ty: i32) -> io::Result<Self> {
+        let stream = Self::from_fd(socket::new_socket(libc::AF_UNIX, ty)?);
+        let (addr, addr_len) = unix_addr(path)?;
+        Connect::from_raw(stream.fd, addr, addr_len).await?;
+        Ok(stream)
+    }
+
+    /// Creates a pair of connected streams.
+    pub fn pair() -> io::Result<(Self, Self)> {
+        let mut fds = [0; 2];
+        if unsafe {
+            libc::socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_STREAM | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            )
+        } == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((Self::from_fd(fds[0]), Self::from_fd(fds[1])))
+    }
+
+    pub fn read<'stream, 'buf>(&'stream self, buf: &'buf mut [u8]) -> Read<'stream, 'buf> {
+        Read::new(self.fd, buf).deadlines(&self.deadlines)
+    }
+
+    /// Receives data without removing it from the socket, the next read returns the same bytes.
+    ///
+    /// This is useful for detecting the protocol of a connection, e.g. telling TLS apart from plaintext.
+    pub fn recv_peek<'stream, 'buf>(&'stream self, buf: &'buf mut [u8]) -> Read<'stream, 'buf> {
+        Read::new(self.fd, buf)
+            .flags(libc::MSG_PEEK)
+            .deadlines(&self.deadlines)
+    }
+
+    pub fn write<'stream, 'buf>(&'stream self, buf: &'buf [u8]) -> Write<'stream, 'buf> {
+        Write::new(self.fd, buf).deadlines(&self.deadlines)
+    }
+
+    pub async fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        socket::write_all(self.fd, Some(&self.deadlines), buf).await
+    }
+
+    pub async fn read_exact(&self, buf: &mut [u8]) -> io::Result<()> {
+        socket::read_exact(self.fd, Some(&self.deadlines), buf).await
+    }
+
+    /// Makes reads fail with a `TimedOut` error if they don't complete within `timeout`, `None` removes the limit.
+    ///
+    /// This applies to reads through the methods of the stream and through wrappers like [crate::codec::LengthDelimited].
+    /// A read that times out is cancelled, so no data is lost, but a `read_exact` that timed out leaves the stream at
+    /// an unknown position.
+    pub fn set_read_deadline(&self, timeout: Option<Duration>) {
+        self.deadlines.set_read(timeout);
+    }
+
+    /// Like [UnixStream::set_read_deadline] for writes.
+    pub fn set_write_deadline(&self, timeout: Option<Duration>) {
+        self.deadlines.set_write(timeout);
+    }
+
+    /// Makes reads and writes fail with a `TimedOut` error once nothing was read from or written to the stream for
+    /// `timeout`, `None` removes the limit.
+    ///
+    /// Every completed read or write counts as activity, so a read that waits for the next request doesn't time out
+    /// while a response is still being written. This is meant for shedding clients that stopped talking without
+    /// wrapping every call in a timeout.
+    pub fn set_idle_timeout(&self, timeout: Option<Duration>) {
+        self.deadlines.set_idle(timeout);
+    }
+
+    /// Sends `buf` along with the given file descriptors.
+    ///
+    /// The file descriptors are duplicated into the receiving process by the kernel, the caller keeps ownership of them.
+    pub fn send_with_fds<'stream, 'buf, 'fds>(
+        &'stream self,
+        buf: &'buf [u8],
+        fds: &'fds [RawFd],
+    ) -> SendMsg<'stream, 'buf, 'fds> {
+        SendMsg::new(self.fd, buf, fds)
+    }
+
+    /// Receives data into `buf` and up to `fds.len()` file descriptors into `fds`.
+    pub fn recv_with_fds<'stream, 'buf, 'fds>(
+        &'stream self,
+        buf: &'buf mut [u8],
+        fds: &'fds mut [RawFd],
+    ) -> RecvMsg<'stream, 'buf, 'fds> {
+        RecvMsg::new(self.fd, buf, fds)
+    }
+
+    /// Sends a single file descriptor to the peer.
+    pub async fn send_fd(&self, fd: RawFd) -> io::Result<()> {
+        // At least one byte of data has to be sent along with the control message.
+        match self.send_with_fds(&[0], &[fd]).await? {
+            0 => Err(io::Error::from(io::ErrorKind::WriteZero)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Receives a single file descriptor sent by [UnixStream::send_fd].
+    pub async fn recv_fd(&self) -> io::Result<OwnedFd> {
+        let mut buf = [0];
+        let mut fds = [-1];
+        match self.recv_with_fds(&mut buf, &mut fds).await? {
+            (0, _) => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            (_, 0) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "message didn't contain a file descriptor",
+            )),
+            _ => Ok(unsafe { OwnedFd::from_raw_fd(fds[0]) }),
+        }
+    }
+}
+
+impl Drop for UnixStream {
+    fn drop(&mut self) {
+        socket::close_fd(self.fd);
+    }
+}
+
+/// A unix datagram socket, e.g. for talking to syslog or sending systemd notifications.
+pub struct UnixDatagram {
+    pub(crate) fd: RawFd,
+    _non_send: PhantomData<*mut ()>,
+}
+
+impl UnixDatagram {
+    pub(crate) fn from_fd(fd: RawFd) -> Self {
+        Self {
+            fd,
+            _non_send: PhantomData,
+        }
+    }
+
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        let socket = Self::unbound()?;
+        let (addr, addr_len) = unix_addr(path)?;
+        if unsafe {
+            libc::bind(
+                socket.fd,
+                &addr as *const libc::sockaddr_storage as *const libc::sockaddr,
+                addr_len,
+            )
+        } == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(socket)
+    }
+
+    /// Creates a socket that isn't bound to an address, it can only send datagrams.
+    pub fn unbound() -> io::Result<Self> {
+        Ok(Self::from_fd(socket::new_socket(
+            libc::AF_UNIX,
+            libc::SOCK_DGRAM,
+        )?))
+    }
+
+    /// Creates a pair of connected sockets.
+    pub fn pair() -> io::Result<(Self, Self)> {
+        let mut fds = [0; 2];
+        if unsafe {
+            libc::socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            )
+        } == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((Self::from_fd(fds[0]), Self::from_fd(fds[1])))
+    }
+
+    /// Sets the default destination for [UnixDatagram::send] and only receives datagrams sent from `path`.
+    ///
+    /// Connecting a datagram socket doesn't block so this isn't async.
+    pub fn connect(&self, path: &Path) -> io::Result<()> {
+        let (addr, addr_len) = unix_addr(path)?;
+        if unsafe {
+            libc::connect(
+                self.fd,
+                &addr as *const libc::sockaddr_storage as *const libc::sockaddr,
+                addr_len,
+            )
+        } == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Sends a datagram to the connected address.
+    pub fn send<'socket, 'buf>(&'socket self, buf: &'buf [u8]) -> Write<'socket, 'buf> {
+        Write::new(self.fd, buf)
+    }
+
+    /// Receives a datagram, the part of it that doesn't fit into `buf` is discarded.
+    pub fn recv<'socket, 'buf>(&'socket self, buf: &'buf mut [u8]) -> Read<'socket, 'buf> {
+        Read::new(self.fd, buf)
+    }
+
+    pub async fn send_to(&self, buf: &[u8], path: &Path) -> io::Result<usize> {
+        let (addr, addr_len) = unix_addr(path)?;
+        SendTo::new(self.fd, buf, addr, addr_len).await
+    }
+
+    /// Receives a datagram and the path of the socket that sent it.
+    ///
+    /// The path is `None` if the sender is unbound.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, Option<PathBuf>)> {
+        let (n, addr, addr_len) = RecvFrom::new(self.fd, buf).await?;
+        Ok((n, addr_to_path(&addr, addr_len)))
+    }
+}
+
+impl Drop for UnixDatagram {
+    fn drop(&mut self) {
+        socket::close_fd(self.fd);
+    }
+}
+
+fn addr_to_path(storage: &libc::sockaddr_storage, len: libc::socklen_t) -> Option<PathBuf> {
+    let raw = unsafe { &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_un) };
+    let path_len = usize::try_from(len)
+        .unwrap()
+        .checked_sub(size_of::<libc::sa_family_t>())?
+        .min(raw.sun_path.len());
+    let mut path: Vec<u8> = raw.sun_path[..path_len].iter().map(|&c| c as u8).collect();
+    // Pathname addresses can include the null terminator, abstract ones start with a null byte and are kept as is.
+    if path.first() != Some(&0) {
+        if let Some(end) = path.iter().position(|&c| c == 0) {
+            path.truncate(end);
+        }
+    }
+    if path.is_empty() {
+        return None;
+    }
+    Some(PathBuf::from(std::ffi::OsString::from_vec(path)))
+}
+
+fn unix_addr(path: &Path) -> io::Result<(libc::sockaddr_storage, libc::socklen_t)> {
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let raw =
+        unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_un) };
+    let path = path.as_os_str().as_bytes();
+
+    // Leave room for the null terminator.
+    if path.len() >= raw.sun_path.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "path is too long for a unix socket address",
+        ));
+    }
+    if path.contains(&b'\0') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "null value in path",
+        ));
+    }
+
+    raw.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (dst, src) in raw.sun_path.iter_mut().zip(path.iter()) {
+        *dst = *src as libc::c_char;
+    }
+
+    let len = size_of::<libc::sa_family_t>() + path.len() + 1;
+    Ok((storage, libc::socklen_t::try_from(len).unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::AsRawFd;
+
+    use crate::executor::{spawn, ExecutorConfig};
+    use crate::fs::file::File;
+
+    use super::*;
+
+    fn inode(fd: RawFd) -> u64 {
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        assert_eq!(unsafe { libc::fstat(fd, &mut stat) }, 0);
+        stat.st_ino
+    }
+
+    #[test]
+    fn test_unix_fd_passing() {
+        let path = std::env::temp_dir().join(format!("io2_test_unix_{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let server_path = path.clone();
+        ExecutorConfig::new()
+            .run(async move {
+                let listener = UnixListener::bind(&server_path).unwrap();
+                let client_path = server_path.clone();
+                let client = spawn(async move {
+                    let stream = UnixStream::connect(&client_path).await.unwrap();
+                    let file = File::open(Path::new("Cargo.toml"), libc::O_RDONLY, 0)
+                        .unwrap()
+                        .await
+                        .unwrap();
+                    stream.send_fd(file.fd).await.unwrap();
+                    stream.write_all(b"hello").await.unwrap();
+                    inode(file.fd)
+                });
+
+                let stream = listener.accept().await.unwrap();
+                let fd = stream.recv_fd().await.unwrap();
+                let mut buf = [0; 5];
+                stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello");
+
+                assert_eq!(client.await, inode(fd.as_raw_fd()));
+            })
+            .unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_unix_datagram() {
+        let dir = std::env::temp_dir();
+        let server_path = dir.join(format!("io2_test_dgram_{}.sock", std::process::id()));
+        let client_path = dir.join(format!("io2_test_dgram_client_{}.sock", std::process::id()));
+        let seqpacket_path = dir.join(format!("io2_test_seqpacket_{}.sock", std::process::id()));
+        for path in [&server_path, &client_path, &seqpacket_path] {
+            let _ = std::fs::remove_file(path);
+        }
+
+        let paths = [
+            server_path.clone(),
+            client_path.clone(),
+            seqpacket_path.clone(),
+        ];
+        ExecutorConfig::new()
+            .run(async move {
+                let server = UnixDatagram::bind(&server_path).unwrap();
+                let client = UnixDatagram::bind(&client_path).unwrap();
+                let unbound = UnixDatagram::unbound().unwrap();
+
+                assert_eq!(client.send_to(b"hello", &server_path).await.unwrap(), 5);
+                assert_eq!(unbound.send_to(b"hi", &server_path).await.unwrap(), 2);
+
+                let mut buf = [0; 16];
+                let (n, from) = server.recv_from(&mut buf).await.unwrap();
+                assert_eq!(&buf[..n], b"hello");
+                assert_eq!(from.as_deref(), Some(client_path.as_path()));
+                let (n, from) = server.recv_from(&mut buf).await.unwrap();
+                assert_eq!(&buf[..n], b"hi");
+                assert_eq!(from, None);
+
+                server.connect(&client_path).unwrap();
+                server.send(b"reply").await.unwrap();
+                let n = client.recv(&mut buf).await.unwrap();
+                assert_eq!(&buf[..n], b"reply");
+
+                // Message boundaries are kept on seqpacket connections.
+                let listener = UnixListener::bind_seqpacket(&seqpacket_path).unwrap();
+                let stream = UnixStream::connect_seqpacket(&seqpacket_path)
+                    .await
+                    .unwrap();
+                let accepted = listener.accept().await.unwrap();
+                stream.write_all(b"one").await.unwrap();
+                stream.write_all(b"two").await.unwrap();
+                assert_eq!(accepted.read(&mut buf).await.unwrap(), 3);
+                assert_eq!(&buf[..3], b"one");
+                assert_eq!(accepted.read(&mut buf).await.unwrap(), 3);
+                assert_eq!(&buf[..3], b"two");
+            })
+            .unwrap();
+
+        for path in &paths {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
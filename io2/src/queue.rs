@@ -0,0 +1,339 @@
+//! Persistent append-only queue.
+//!
+//! [DurableQueue] stores records in a [Wal] and keeps the offset up to which a consumer has processed them in a
+//! separate ack file. Offsets are the sequence numbers of the log, so they are dense and ordered, and records keep
+//! their offsets across restarts. Segments of the log that only hold acked records are removed.
+
+use std::cell::RefCell;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::fs;
+use crate::local_alloc::LocalAlloc;
+use crate::sync::Semaphore;
+use crate::wal::{Replay, Wal, WalConfig};
+
+const ACK_FILE: &str = "ack";
+
+/// When pushed records are synced to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Each push returns after its record is durable, concurrent pushes are synced together.
+    Always,
+    /// Pushes return once the record is queued for writing and every n-th push waits until all records before it are
+    /// durable. [DurableQueue::sync] makes the rest durable.
+    Every(u64),
+}
+
+#[derive(Clone)]
+pub struct QueueConfig {
+    wal: WalConfig,
+    sync_policy: SyncPolicy,
+}
+
+impl QueueConfig {
+    pub fn new() -> Self {
+        Self {
+            wal: WalConfig::new(),
+            sync_policy: SyncPolicy::Always,
+        }
+    }
+
+    /// Configuration of the log that stores the records, e.g. its segment size.
+    pub fn wal(mut self, wal: WalConfig) -> Self {
+        self.wal = wal;
+        self
+    }
+
+    /// Defaults to [SyncPolicy::Always].
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        if let SyncPolicy::Every(n) = sync_policy {
+            assert!(n > 0, "sync interval has to be positive");
+        }
+        self.sync_policy = sync_policy;
+        self
+    }
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct State {
+    ack_path: PathBuf,
+    sync_policy: SyncPolicy,
+    // Records below this offset are processed.
+    acked: u64,
+    // Pushes since the last sync with SyncPolicy::Every.
+    unsynced: u64,
+}
+
+/// A queue of records in a directory, cloning it gives another handle to the same queue.
+#[derive(Clone)]
+pub struct DurableQueue {
+    wal: Wal,
+    // Keeps acks from writing the ack file concurrently, so an older offset can't overwrite a newer one.
+    ack_lock: Semaphore,
+    state: Rc<RefCell<State>, LocalAlloc>,
+}
+
+impl DurableQueue {
+    /// Opens the queue in `dir`, creating it if it doesn't exist.
+    pub async fn open(dir: &Path, config: QueueConfig) -> io::Result<Self> {
+        let wal = Wal::open(dir, config.wal).await?;
+        let ack_path = dir.join(ACK_FILE);
+        let acked = match fs::read(&ack_path).await {
+            Ok(data) => {
+                let bytes = <[u8; 8]>::try_from(data.as_slice()).map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "ack file has the wrong size")
+                })?;
+                u64::from_le_bytes(bytes)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        if acked > wal.next_seq() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "ack offset {} is past the end of the log at {}",
+                    acked,
+                    wal.next_seq()
+                ),
+            ));
+        }
+        Ok(Self {
+            wal,
+            ack_lock: Semaphore::new(1),
+            state: Rc::new_in(
+                RefCell::new(State {
+                    ack_path,
+                    sync_policy: config.sync_policy,
+                    acked,
+                    unsynced: 0,
+                }),
+                LocalAlloc::new(),
+            ),
+        })
+    }
+
+    /// Appends a record, returns its offset. When this returns the record is durable or not depending on the
+    /// [SyncPolicy].
+    pub async fn push(&self, record: &[u8]) -> io::Result<u64> {
+        let sync_every = match self.state.borrow().sync_policy {
+            SyncPolicy::Always => None,
+            SyncPolicy::Every(n) => Some(n),
+        };
+        let Some(n) = sync_every else {
+            return self.wal.append(record).await;
+        };
+        let offset = self.wal.append_buffered(record)?;
+        let sync = {
+            let mut state = self.state.borrow_mut();
+            state.unsynced += 1;
+            if state.unsynced >= n {
+                state.unsynced = 0;
+            }
+            state.unsynced == 0
+        };
+        if sync {
+            self.wal.sync().await?;
+        }
+        Ok(offset)
+    }
+
+    /// Waits until all pushed records are durable.
+    pub async fn sync(&self) -> io::Result<()> {
+        self.wal.sync().await
+    }
+
+    /// Offset the next pushed record gets.
+    pub fn end_offset(&self) -> u64 {
+        self.wal.next_seq()
+    }
+
+    /// Offset of the first record that isn't acked.
+    pub fn acked_offset(&self) -> u64 {
+        self.state.borrow().acked
+    }
+
+    /// Returns the durable records starting from `offset`.
+    ///
+    /// Acked records can still be read until the segment they are in is removed.
+    pub fn read_from(&self, offset: u64) -> QueueReader {
+        QueueReader {
+            wal: self.wal.clone(),
+            replay: self.wal.replay(offset),
+            done: false,
+        }
+    }
+
+    /// Returns the durable records that aren't acked yet.
+    pub fn read_unacked(&self) -> QueueReader {
+        self.read_from(self.acked_offset())
+    }
+
+    /// Marks the records below `offset` as processed.
+    ///
+    /// The offset is written to the ack file and synced before this returns, so the records aren't returned by
+    /// [DurableQueue::read_unacked] again after a restart. Acking an offset below the current one does nothing.
+    ///
+    /// Records below `offset` that aren't durable yet, see [SyncPolicy::Every], are synced before the ack is written,
+    /// so the ack file never points past the end of the log on disk.
+    pub async fn ack(&self, offset: u64) -> io::Result<()> {
+        if offset > self.wal.next_seq() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "can't ack offset {}, the queue ends at {}",
+                    offset,
+                    self.wal.next_seq()
+                ),
+            ));
+        }
+        if offset > self.wal.durable_seq() {
+            self.wal.sync().await?;
+        }
+        let _permit = self.ack_lock.acquire().await;
+        let ack_path = {
+            let state = self.state.borrow();
+            if offset <= state.acked {
+                return Ok(());
+            }
+            state.ack_path.clone()
+        };
+        fs::write_atomic(&ack_path, &offset.to_le_bytes()).await?;
+        self.state.borrow_mut().acked = offset;
+        self.wal.remove_before(offset).await
+    }
+}
+
+/// Reads records of a [DurableQueue], see [DurableQueue::read_from].
+pub struct QueueReader {
+    wal: Wal,
+    replay: Replay,
+    done: bool,
+}
+
+impl QueueReader {
+    /// Returns the next record and its offset, `None` after the last record that was durable when it was read.
+    ///
+    /// Records that become durable after this returned `None` are read with a new reader.
+    pub async fn next(&mut self) -> Option<io::Result<(u64, &[u8])>> {
+        if self.done {
+            return None;
+        }
+        let durable = self.wal.durable_seq();
+        match self.replay.next().await {
+            Some(Ok((offset, _))) if offset >= durable => {
+                self.done = true;
+                None
+            }
+            res => res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::ExecutorConfig;
+
+    async fn read_all(mut reader: QueueReader) -> Vec<u64> {
+        let mut offsets = Vec::new();
+        while let Some(res) = reader.next().await {
+            let (offset, record) = res.unwrap();
+            assert_eq!(record, format!("record {}", offset).as_bytes());
+            offsets.push(offset);
+        }
+        offsets
+    }
+
+    #[test]
+    fn test_durable_queue() {
+        let dir = std::env::temp_dir().join(format!("io2_test_queue_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let test_dir = dir.clone();
+        ExecutorConfig::new()
+            .run(async move {
+                let dir = test_dir;
+                let config = QueueConfig::new()
+                    .wal(WalConfig::new().segment_size(512).preallocate(false))
+                    .sync_policy(SyncPolicy::Every(4));
+                let queue = DurableQueue::open(&dir, config.clone()).await.unwrap();
+                for i in 0..150 {
+                    let offset = queue
+                        .push(format!("record {}", i).as_bytes())
+                        .await
+                        .unwrap();
+                    assert_eq!(offset, i);
+                }
+                // Only the records up to the last sync are visible to readers.
+                assert_eq!(
+                    read_all(queue.read_unacked()).await,
+                    (0..148).collect::<Vec<_>>()
+                );
+                queue.sync().await.unwrap();
+                assert_eq!(
+                    read_all(queue.read_from(140)).await,
+                    (140..150).collect::<Vec<_>>()
+                );
+
+                let segments = std::fs::read_dir(&dir).unwrap().count();
+                queue.ack(120).await.unwrap();
+                queue.ack(100).await.unwrap();
+                assert_eq!(queue.acked_offset(), 120);
+                assert!(std::fs::read_dir(&dir).unwrap().count() < segments);
+                let err = queue.ack(151).await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+                drop(queue);
+
+                // The ack offset and the records survive reopening.
+                let queue = DurableQueue::open(&dir, config).await.unwrap();
+                assert_eq!(queue.acked_offset(), 120);
+                assert_eq!(queue.end_offset(), 150);
+                assert_eq!(
+                    read_all(queue.read_unacked()).await,
+                    (120..150).collect::<Vec<_>>()
+                );
+            })
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_ack_before_sync() {
+        let dir = std::env::temp_dir().join(format!("io2_test_queue_ack_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let test_dir = dir.clone();
+        ExecutorConfig::new()
+            .run(async move {
+                let dir = test_dir;
+                let config = QueueConfig::new()
+                    .wal(WalConfig::new().preallocate(false))
+                    .sync_policy(SyncPolicy::Every(100));
+                let queue = DurableQueue::open(&dir, config.clone()).await.unwrap();
+                for i in 0..3 {
+                    queue
+                        .push(format!("record {}", i).as_bytes())
+                        .await
+                        .unwrap();
+                }
+                // The records aren't durable yet, acking them makes them durable first.
+                queue.ack(2).await.unwrap();
+                drop(queue);
+
+                let queue = DurableQueue::open(&dir, config).await.unwrap();
+                assert_eq!(queue.acked_offset(), 2);
+                assert_eq!(queue.end_offset(), 3);
+                assert_eq!(read_all(queue.read_unacked()).await, vec![2]);
+            })
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,697 @@
+//! A small client for S3 compatible object storage.
+//!
+//! Requests are signed with Signature Version 4 and use path style addressing (`<endpoint>/<bucket>/<key>`), which is
+//! supported by AWS and by self hosted implementations like MinIO. It is built on [crate::http::Client] so it has the
+//! same limitation of only supporting plain HTTP endpoints.
+//!
+//! Object bodies can be streamed from a [Response] or copied between files and objects with
+//! [S3Client::get_object_to_file] and [S3Client::put_file], large files are uploaded in parts.
+
+mod sigv4;
+
+pub use sigv4::Credentials;
+
+use std::io;
+
+use crate::fs::file::File;
+use crate::http::{Client, Request, Response};
+
+use crate::hash::sha256;
+use sigv4::{amz_date_now, authorization, canonical_query, hex, uri_encode, SignRequest};
+
+/// S3 doesn't accept parts smaller than this, except for the last part of an upload.
+pub const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+/// An object returned by [S3Client::list_objects].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Object {
+    pub key: String,
+    pub size: u64,
+    pub etag: String,
+}
+
+/// A page of a listing, the next page is requested with `next_continuation_token`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListPage {
+    pub objects: Vec<Object>,
+    pub next_continuation_token: Option<String>,
+}
+
+/// A part uploaded with [S3Client::upload_part].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Part {
+    pub part_number: u32,
+    pub etag: String,
+}
+
+pub struct S3Client {
+    http: Client,
+    endpoint: String,
+    host: String,
+    region: String,
+    credentials: Credentials,
+}
+
+impl S3Client {
+    /// Creates a client for the service at `endpoint`, e.g. `http://127.0.0.1:9000`.
+    pub fn new(endpoint: &str, region: &str, credentials: Credentials) -> io::Result<Self> {
+        Self::with_http_client(Client::new(), endpoint, region, credentials)
+    }
+
+    /// Creates a client that sends its requests through `http`, to share its connection pools.
+    pub fn with_http_client(
+        http: Client,
+        endpoint: &str,
+        region: &str,
+        credentials: Credentials,
+    ) -> io::Result<Self> {
+        let endpoint = endpoint.trim_end_matches('/');
+        let authority = endpoint.strip_prefix("http://").ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "S3 endpoint must start with http://",
+            )
+        })?;
+        if authority.contains('/') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "S3 endpoint can't have a path",
+            ));
+        }
+        // Same as the Host header sent by the http client, which leaves out the default port.
+        let host = authority.strip_suffix(":80").unwrap_or(authority);
+        Ok(Self {
+            http,
+            endpoint: endpoint.to_owned(),
+            host: host.to_owned(),
+            region: region.to_owned(),
+            credentials,
+        })
+    }
+
+    /// Returns the response of a GET request for the object, its body is read from the response.
+    pub async fn get_object(&self, bucket: &str, key: &str) -> io::Result<Response> {
+        self.send("GET", bucket, Some(key), &[], &[], &[]).await
+    }
+
+    /// Writes the object into `file` starting at `offset`, returns the size of the object.
+    pub async fn get_object_to_file(
+        &self,
+        bucket: &str,
+        key: &str,
+        file: &File,
+        offset: u64,
+    ) -> io::Result<u64> {
+        let mut res = self.get_object(bucket, key).await?;
+        let mut written = 0;
+        while let Some(chunk) = res.chunk().await? {
+            file.write_all(chunk, offset + written).await?;
+            written += u64::try_from(chunk.len()).unwrap();
+        }
+        Ok(written)
+    }
+
+    pub async fn put_object(&self, bucket: &str, key: &str, body: &[u8]) -> io::Result<()> {
+        self.send("PUT", bucket, Some(key), &[], &[], body)
+            .await?
+            .bytes()
+            .await?;
+        Ok(())
+    }
+
+    /// Uploads the contents of `file` as an object.
+    ///
+    /// Files larger than `part_size` are uploaded with a multipart upload, so only one part is held in memory at a time.
+    /// The upload is aborted if one of the parts fails. `part_size` has to be at least [MIN_PART_SIZE] for files that
+    /// are uploaded in parts.
+    pub async fn put_file(
+        &self,
+        bucket: &str,
+        key: &str,
+        file: &File,
+        part_size: usize,
+    ) -> io::Result<()> {
+        let size = file.file_size().await?;
+        let part_size_u64 = u64::try_from(part_size).unwrap();
+        if size <= part_size_u64 {
+            let mut body = vec![0; usize::try_from(size).unwrap()];
+            file.read_exact(&mut body, 0).await?;
+            return self.put_object(bucket, key, &body).await;
+        }
+        if part_size < MIN_PART_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("part size must be at least {} bytes", MIN_PART_SIZE),
+            ));
+        }
+
+        let upload_id = self.create_multipart_upload(bucket, key).await?;
+        let upload = async {
+            let mut parts = Vec::new();
+            let mut buf = vec![0; part_size];
+            let mut offset = 0;
+            while offset < size {
+                let len = usize::try_from((size - offset).min(part_size_u64)).unwrap();
+                file.read_exact(&mut buf[..len], offset).await?;
+                let part_number = u32::try_from(parts.len() + 1).unwrap();
+                let etag = self
+                    .upload_part(bucket, key, &upload_id, part_number, &buf[..len])
+                    .await?;
+                parts.push(Part { part_number, etag });
+                offset += u64::try_from(len).unwrap();
+            }
+            self.complete_multipart_upload(bucket, key, &upload_id, &parts)
+                .await
+        }
+        .await;
+
+        if let Err(e) = upload {
+            if let Err(abort_err) = self.abort_multipart_upload(bucket, key, &upload_id).await {
+                log::warn!(
+                    "failed to abort multipart upload {} of {}: {}",
+                    upload_id,
+                    key,
+                    abort_err
+                );
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Starts a multipart upload and returns its id.
+    pub async fn create_multipart_upload(&self, bucket: &str, key: &str) -> io::Result<String> {
+        let query = [("uploads".to_owned(), String::new())];
+        let body = self
+            .send("POST", bucket, Some(key), &query, &[], &[])
+            .await?
+            .bytes()
+            .await?;
+        let body = String::from_utf8_lossy(&body);
+        tag(&body, "UploadId")
+            .map(unescape)
+            .ok_or_else(|| invalid("CreateMultipartUpload response has no UploadId"))
+    }
+
+    /// Uploads a part and returns its ETag, part numbers start from 1.
+    pub async fn upload_part(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        part_number: u32,
+        body: &[u8],
+    ) -> io::Result<String> {
+        let query = [
+            ("partNumber".to_owned(), part_number.to_string()),
+            ("uploadId".to_owned(), upload_id.to_owned()),
+        ];
+        let mut res = self
+            .send("PUT", bucket, Some(key), &query, &[], body)
+            .await?;
+        let etag = res
+            .header("etag")
+            .map(|etag| etag.to_owned())
+            .ok_or_else(|| invalid("UploadPart response has no ETag"))?;
+        res.bytes().await?;
+        Ok(etag)
+    }
+
+    pub async fn complete_multipart_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: &[Part],
+    ) -> io::Result<()> {
+        let query = [("uploadId".to_owned(), upload_id.to_owned())];
+        let mut body = String::from("<CompleteMultipartUpload>");
+        for part in parts {
+            body.push_str(&format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                part.part_number,
+                escape(&part.etag)
+            ));
+        }
+        body.push_str("</CompleteMultipartUpload>");
+        let res = self
+            .send("POST", bucket, Some(key), &query, &[], body.as_bytes())
+            .await?
+            .bytes()
+            .await?;
+        // Errors that happen after the upload started are reported in a 200 response.
+        let res = String::from_utf8_lossy(&res);
+        if res.contains("<Error>") {
+            return Err(io::Error::other(format!(
+                "CompleteMultipartUpload failed: {}",
+                res
+            )));
+        }
+        Ok(())
+    }
+
+    pub async fn abort_multipart_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+    ) -> io::Result<()> {
+        let query = [("uploadId".to_owned(), upload_id.to_owned())];
+        self.send("DELETE", bucket, Some(key), &query, &[], &[])
+            .await?
+            .bytes()
+            .await?;
+        Ok(())
+    }
+
+    /// Lists objects whose keys start with `prefix`, up to 1000 per page.
+    pub async fn list_objects(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        continuation_token: Option<&str>,
+    ) -> io::Result<ListPage> {
+        let mut query = vec![
+            ("list-type".to_owned(), "2".to_owned()),
+            ("prefix".to_owned(), prefix.to_owned()),
+        ];
+        if let Some(token) = continuation_token {
+            query.push(("continuation-token".to_owned(), token.to_owned()));
+        }
+        let body = self
+            .send("GET", bucket, None, &query, &[], &[])
+            .await?
+            .bytes()
+            .await?;
+        parse_list(&String::from_utf8_lossy(&body))
+    }
+
+    async fn send(
+        &self,
+        method: &str,
+        bucket: &str,
+        key: Option<&str>,
+        query: &[(String, String)],
+        headers: &[(String, String)],
+        body: &[u8],
+    ) -> io::Result<Response> {
+        let mut path = format!("/{}", uri_encode(bucket, true));
+        if let Some(key) = key {
+            path.push('/');
+            path.push_str(&uri_encode(key, false));
+        }
+        let payload_hash = hex(&sha256(body));
+        let amz_date = amz_date_now();
+
+        let mut headers = headers.to_vec();
+        headers.push(("x-amz-content-sha256".to_owned(), payload_hash.clone()));
+        headers.push(("x-amz-date".to_owned(), amz_date.clone()));
+        if let Some(token) = self.credentials.session_token.as_ref() {
+            headers.push(("x-amz-security-token".to_owned(), token.clone()));
+        }
+        let auth = authorization(
+            &SignRequest {
+                method,
+                host: &self.host,
+                path: &path,
+                query,
+                headers: &headers,
+                payload_hash: &payload_hash,
+            },
+            &self.credentials,
+            &self.region,
+            &amz_date,
+        );
+
+        let mut url = format!("{}{}", self.endpoint, path);
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&canonical_query(query));
+        }
+        let mut req = Request::new(method, &url)
+            .body(body)
+            .header("Authorization", &auth);
+        for (name, value) in headers.iter() {
+            req = req.header(name, value);
+        }
+
+        let mut res = self.http.request(&req).await?;
+        if !(200..300).contains(&res.status()) {
+            let status = res.status();
+            let body = res.bytes().await.unwrap_or_default();
+            return Err(io::Error::other(format!(
+                "S3 {} request failed with status {}: {}",
+                method,
+                status,
+                String::from_utf8_lossy(&body)
+            )));
+        }
+        Ok(res)
+    }
+}
+
+fn parse_list(xml: &str) -> io::Result<ListPage> {
+    let mut objects = Vec::new();
+    for contents in tags(xml, "Contents") {
+        let key = tag(contents, "Key").ok_or_else(|| invalid("listed object has no Key"))?;
+        let size = tag(contents, "Size")
+            .and_then(|size| size.parse().ok())
+            .ok_or_else(|| invalid("listed object has no valid Size"))?;
+        objects.push(Object {
+            key: unescape(key),
+            size,
+            etag: tag(contents, "ETag").map(unescape).unwrap_or_default(),
+        });
+    }
+    let truncated = tag(xml, "IsTruncated") == Some("true");
+    Ok(ListPage {
+        objects,
+        next_continuation_token: tag(xml, "NextContinuationToken")
+            .filter(|_| truncated)
+            .map(unescape),
+    })
+}
+
+/// Returns the contents of the first element with the given name, S3 responses don't use attributes or namespace
+/// prefixes on the elements that are read here.
+fn tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    tags(xml, name).next()
+}
+
+fn tags<'a>(xml: &'a str, name: &str) -> impl Iterator<Item = &'a str> {
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+    let mut rest = xml;
+    std::iter::from_fn(move || {
+        let start = rest.find(&open)? + open.len();
+        let len = rest[start..].find(&close)?;
+        let contents = &rest[start..start + len];
+        rest = &rest[start + len + close.len()..];
+        Some(contents)
+    })
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&#34;", "\"")
+        .replace("&amp;", "&")
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::{BTreeMap, HashMap};
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::executor::{spawn, ExecutorConfig};
+    use crate::net::tcp::{TcpListener, TcpStream};
+
+    #[derive(Default)]
+    struct Store {
+        objects: HashMap<String, Vec<u8>>,
+        uploads: HashMap<String, BTreeMap<u32, Vec<u8>>>,
+        next_upload: u32,
+    }
+
+    struct FakeRequest {
+        method: String,
+        path: String,
+        query: HashMap<String, String>,
+        headers: HashMap<String, String>,
+        body: Vec<u8>,
+    }
+
+    // Reads the next request of the connection, None once the client closed it.
+    async fn read_request(stream: &TcpStream, buf: &mut Vec<u8>) -> Option<FakeRequest> {
+        let mut chunk = vec![0; 64 * 1024];
+        let head_end = loop {
+            if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+            match stream.read(&mut chunk).await.unwrap() {
+                0 => return None,
+                n => buf.extend_from_slice(&chunk[..n]),
+            }
+        };
+        let head = String::from_utf8(buf[..head_end].to_vec()).unwrap();
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next().unwrap().split(' ');
+        let method = request_line.next().unwrap().to_owned();
+        let target = request_line.next().unwrap();
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let path = path.to_owned();
+        let query = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (name.to_owned(), value.to_owned())
+            })
+            .collect();
+        let headers = lines
+            .filter_map(|line| line.split_once(": "))
+            .map(|(name, value)| (name.to_ascii_lowercase(), value.to_owned()))
+            .collect::<HashMap<_, _>>();
+        let len = headers
+            .get("content-length")
+            .map_or(0, |len| len.parse::<usize>().unwrap());
+        while buf.len() < head_end + len {
+            let n = stream.read(&mut chunk).await.unwrap();
+            assert!(n > 0, "connection closed in the middle of a request body");
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        let body = buf[head_end..head_end + len].to_vec();
+        buf.drain(..head_end + len);
+        Some(FakeRequest {
+            method,
+            path,
+            query,
+            headers,
+            body,
+        })
+    }
+
+    // Handles the requests the client makes, fails the second part of uploads to the `fail` key.
+    fn handle(store: &mut Store, req: FakeRequest) -> (u16, Vec<(String, String)>, Vec<u8>) {
+        assert!(req.headers["authorization"].starts_with("AWS4-HMAC-SHA256 Credential=key/"));
+        assert_eq!(
+            req.headers["x-amz-content-sha256"],
+            hex(&sha256(&req.body)),
+            "payload hash doesn't match the body"
+        );
+        let key = req.path.strip_prefix("/bucket/").unwrap().to_owned();
+        let upload_id = req.query.get("uploadId").cloned();
+        match (req.method.as_str(), upload_id) {
+            ("POST", None) => {
+                assert!(req.query.contains_key("uploads"));
+                store.next_upload += 1;
+                let id = format!("upload{}", store.next_upload);
+                store.uploads.insert(id.clone(), BTreeMap::new());
+                let body = format!(
+                    "<InitiateMultipartUploadResult><UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+                    id
+                );
+                (200, Vec::new(), body.into_bytes())
+            }
+            ("PUT", Some(id)) => {
+                let part_number = req.query["partNumber"].parse::<u32>().unwrap();
+                if key == "fail" && part_number == 2 {
+                    return (500, Vec::new(), b"<Error>InternalError</Error>".to_vec());
+                }
+                store
+                    .uploads
+                    .get_mut(&id)
+                    .unwrap()
+                    .insert(part_number, req.body);
+                let etag = format!("\"etag{}\"", part_number);
+                (200, vec![("ETag".to_owned(), etag)], Vec::new())
+            }
+            ("POST", Some(id)) => {
+                let parts = store.uploads.remove(&id).unwrap();
+                let body = String::from_utf8(req.body).unwrap();
+                let mut object = Vec::new();
+                for (part_number, data) in parts {
+                    assert!(body.contains(&format!(
+                        "<Part><PartNumber>{}</PartNumber><ETag>&quot;etag{}&quot;</ETag></Part>",
+                        part_number, part_number
+                    )));
+                    object.extend_from_slice(&data);
+                }
+                store.objects.insert(key, object);
+                (
+                    200,
+                    Vec::new(),
+                    b"<CompleteMultipartUploadResult/>".to_vec(),
+                )
+            }
+            ("DELETE", Some(id)) => {
+                store.uploads.remove(&id).unwrap();
+                (204, Vec::new(), Vec::new())
+            }
+            ("PUT", None) => {
+                store.objects.insert(key, req.body);
+                (200, Vec::new(), Vec::new())
+            }
+            ("GET", None) => match store.objects.get(&key) {
+                Some(object) => (200, Vec::new(), object.clone()),
+                None => (404, Vec::new(), b"<Error>NoSuchKey</Error>".to_vec()),
+            },
+            (method, _) => panic!("unexpected {} request", method),
+        }
+    }
+
+    async fn serve(listener: TcpListener, store: Rc<RefCell<Store>>) {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let store = store.clone();
+            spawn(async move {
+                let mut buf = Vec::new();
+                while let Some(req) = read_request(&stream, &mut buf).await {
+                    let (status, headers, body) = handle(&mut store.borrow_mut(), req);
+                    let mut res = format!(
+                        "HTTP/1.1 {} Fake\r\nContent-Length: {}\r\n",
+                        status,
+                        body.len()
+                    );
+                    for (name, value) in headers {
+                        res.push_str(&format!("{}: {}\r\n", name, value));
+                    }
+                    res.push_str("\r\n");
+                    let mut res = res.into_bytes();
+                    res.extend_from_slice(&body);
+                    stream.write_all(&res).await.unwrap();
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn test_file_transfers() {
+        let path = std::env::temp_dir().join(format!("io2_test_s3_{}", std::process::id()));
+        let test_path = path.clone();
+        ExecutorConfig::new()
+            .run(async move {
+                let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+                let port = listener.local_addr().unwrap().port();
+                let store = Rc::new(RefCell::new(Store::default()));
+                // The server task is dropped when the test returns.
+                let _server = spawn(serve(listener, store.clone()));
+                let credentials = Credentials {
+                    access_key: "key".to_owned(),
+                    secret_key: "secret".to_owned(),
+                    session_token: None,
+                };
+                let client = S3Client::new(
+                    &format!("http://127.0.0.1:{}", port),
+                    "us-east-1",
+                    credentials,
+                )
+                .unwrap();
+
+                // Two full parts and a shorter last one.
+                let data = (0..2 * MIN_PART_SIZE + 12345)
+                    .map(|i| (i % 251) as u8)
+                    .collect::<Vec<u8>>();
+                let file = File::open(
+                    &test_path,
+                    libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC,
+                    0o644,
+                )
+                .unwrap()
+                .await
+                .unwrap();
+                file.write_all(&data, 0).await.unwrap();
+                client
+                    .put_file("bucket", "large", &file, MIN_PART_SIZE)
+                    .await
+                    .unwrap();
+                assert!(store.borrow().uploads.is_empty());
+                assert!(store.borrow().objects["large"] == data);
+
+                // Small files are sent in a single request.
+                file.set_len(100).await.unwrap();
+                client
+                    .put_file("bucket", "small", &file, MIN_PART_SIZE)
+                    .await
+                    .unwrap();
+                assert_eq!(store.borrow().next_upload, 1);
+                assert_eq!(store.borrow().objects["small"], data[..100]);
+
+                // A failed part aborts the upload.
+                file.write_all(&data, 0).await.unwrap();
+                let err = client
+                    .put_file("bucket", "fail", &file, MIN_PART_SIZE)
+                    .await
+                    .unwrap_err();
+                assert!(err.to_string().contains("status 500"), "{}", err);
+                assert!(store.borrow().uploads.is_empty());
+                assert!(!store.borrow().objects.contains_key("fail"));
+
+                // Objects are written at the offset, the size is returned.
+                let n = client
+                    .get_object_to_file("bucket", "large", &file, 10)
+                    .await
+                    .unwrap();
+                assert_eq!(n, u64::try_from(data.len()).unwrap());
+                let mut read = vec![0; data.len()];
+                file.read_exact(&mut read, 10).await.unwrap();
+                assert!(read == data);
+                let err = client
+                    .get_object_to_file("bucket", "missing", &file, 0)
+                    .await
+                    .unwrap_err();
+                assert!(err.to_string().contains("status 404"), "{}", err);
+
+                file.close().await.unwrap();
+            })
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_list() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<ListBucketResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+  <Name>bucket</Name><Prefix>logs/</Prefix><KeyCount>2</KeyCount><IsTruncated>true</IsTruncated>
+  <NextContinuationToken>1ueGcxLPRx1Tr/XYExHnhbYLgveDs2J/wm36Hy4vbOwM=</NextContinuationToken>
+  <Contents><Key>logs/a&amp;b.log</Key><Size>434234</Size><ETag>&quot;fba9dede5f27731c9771645a39863328&quot;</ETag></Contents>
+  <Contents><Key>logs/c.log</Key><LastModified>2009-10-12T17:50:30.000Z</LastModified><Size>0</Size></Contents>
+</ListBucketResult>"#;
+        let page = parse_list(xml).unwrap();
+        assert_eq!(
+            page.objects,
+            [
+                Object {
+                    key: "logs/a&b.log".to_owned(),
+                    size: 434234,
+                    etag: "\"fba9dede5f27731c9771645a39863328\"".to_owned(),
+                },
+                Object {
+                    key: "logs/c.log".to_owned(),
+                    size: 0,
+                    etag: String::new(),
+                },
+            ]
+        );
+        assert_eq!(
+            page.next_continuation_token.as_deref(),
+            Some("1ueGcxLPRx1Tr/XYExHnhbYLgveDs2J/wm36Hy4vbOwM=")
+        );
+        assert_eq!(uri_encode("a b/c+d", false), "a%20b/c%2Bd");
+    }
+}
@@ -4,6 +4,8 @@ pub struct Slab<T, A: Allocator> {
     elems: Vec<Entry<T>, A>,
     first_free_entry: u32,
     current_generation: u32,
+    len: usize,
+    high_water_mark: usize,
 }
 
 impl<T, A: Allocator> Slab<T, A> {
@@ -24,6 +26,8 @@ impl<T, A: Allocator> Slab<T, A> {
             elems,
             first_free_entry: 0,
             current_generation: 0,
+            len: 0,
+            high_water_mark: 0,
         }
     }
 
@@ -53,6 +57,8 @@ impl<T, A: Allocator> Slab<T, A> {
             }
             _ => unreachable!(),
         }
+        self.len += 1;
+        self.high_water_mark = self.high_water_mark.max(self.len);
 
         Key {
             generation: self.current_generation,
@@ -64,7 +70,7 @@ impl<T, A: Allocator> Slab<T, A> {
         match self.elems.get(usize::try_from(key.index).unwrap()) {
             Some(entry) => match entry {
                 Entry::Occupied { generation, val } => {
-                    if *generation > key.generation {
+                    if *generation != key.generation {
                         None
                     } else {
                         Some(val)
@@ -80,7 +86,7 @@ impl<T, A: Allocator> Slab<T, A> {
         match self.elems.get_mut(usize::try_from(key.index).unwrap()) {
             Some(entry) => match entry {
                 Entry::Occupied { generation, val } => {
-                    if *generation > key.generation {
+                    if *generation != key.generation {
                         None
                     } else {
                         Some(val)
@@ -96,7 +102,7 @@ impl<T, A: Allocator> Slab<T, A> {
         match self.elems.get_mut(usize::try_from(key.index).unwrap()) {
             Some(entry) => match entry {
                 Entry::Occupied { generation, .. } => {
-                    if *generation > key.generation {
+                    if *generation != key.generation {
                         None
                     } else {
                         let entry = std::mem::replace(
@@ -107,6 +113,7 @@ impl<T, A: Allocator> Slab<T, A> {
                         );
                         self.first_free_entry = key.index;
                         self.current_generation = self.current_generation.wrapping_add(1);
+                        self.len -= 1;
                         match entry {
                             Entry::Occupied { val, .. } => Some(val),
                             _ => unreachable!(),
@@ -120,12 +127,81 @@ impl<T, A: Allocator> Slab<T, A> {
     }
 }
 
+impl<T, A: Allocator> Slab<T, A> {
+    /// Number of values in the slab.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of values the slab can hold without allocating.
+    pub fn capacity(&self) -> usize {
+        self.elems.capacity()
+    }
+
+    /// Largest number of values the slab held at the same time.
+    pub fn high_water_mark(&self) -> usize {
+        self.high_water_mark
+    }
+
+    /// Frees the slots after the last value, keeping at least `min_capacity` of them.
+    ///
+    /// Slots before the last value can't be freed since that would change the keys of the values after them.
+    pub fn shrink_to(&mut self, min_capacity: usize) {
+        let used = self
+            .elems
+            .iter()
+            .rposition(|entry| matches!(entry, Entry::Occupied { .. }))
+            .map_or(0, |idx| idx + 1);
+        let new_len = used.max(min_capacity).min(self.elems.len());
+        self.elems.truncate(new_len);
+        self.elems.shrink_to(new_len);
+
+        // The free list might point into the removed slots, link the remaining free slots again.
+        let mut next_free = u32::try_from(new_len).unwrap();
+        for (idx, entry) in self.elems.iter_mut().enumerate().rev() {
+            if let Entry::Free { next_free: next } = entry {
+                *next = next_free;
+                next_free = u32::try_from(idx).unwrap();
+            }
+        }
+        self.first_free_entry = next_free;
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        self.shrink_to(0);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Key, &T)> {
+        self.elems
+            .iter()
+            .enumerate()
+            .filter_map(|(index, entry)| match entry {
+                Entry::Occupied { generation, val } => Some((
+                    Key {
+                        index: u32::try_from(index).unwrap(),
+                        generation: *generation,
+                    },
+                    val,
+                )),
+                Entry::Free { .. } => None,
+            })
+    }
+}
+
 enum Entry<T> {
     Occupied { generation: u32, val: T },
     Free { next_free: u32 },
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+/// Key of a value in a [Slab].
+///
+/// Keys carry the generation of the slot they were created for, so a key that outlived its value doesn't resolve to a
+/// value that was inserted into the same slot later.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct Key {
     index: u32,
     generation: u32,
@@ -145,3 +221,60 @@ impl From<Key> for u64 {
         key.index as u64 | (key.generation as u64) << 32
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::local_alloc::LocalAlloc;
+
+    use super::*;
+
+    #[test]
+    fn test_stale_key() {
+        let mut slab = Slab::with_capacity_in(4, LocalAlloc::new());
+        let a = slab.insert(1);
+        assert_eq!(slab.remove(a), Some(1));
+        // The slot is reused for b.
+        let b = slab.insert(2);
+        assert_eq!(u64::from(a) as u32, u64::from(b) as u32);
+        assert_eq!(slab.get(a), None);
+        assert_eq!(slab.remove(a), None);
+        assert_eq!(slab.get(b), Some(&2));
+
+        // Generations wrap around without making stale keys valid.
+        slab.current_generation = u32::MAX;
+        let c = slab.insert(3);
+        slab.remove(c);
+        let d = slab.insert(4);
+        assert_eq!(slab.get(c), None);
+        assert_eq!(slab.get_mut(d), Some(&mut 4));
+        assert_eq!(Key::from(u64::from(d)), d);
+    }
+
+    #[test]
+    fn test_shrink() {
+        let mut slab = Slab::with_capacity_in(4, LocalAlloc::new());
+        let keys = (0..100).map(|i| slab.insert(i)).collect::<Vec<_>>();
+        for &key in &keys[10..] {
+            slab.remove(key);
+        }
+        slab.remove(keys[3]);
+        assert_eq!((slab.len(), slab.high_water_mark()), (9, 100));
+
+        slab.shrink_to(4);
+        assert!(slab.capacity() < 100);
+        assert_eq!(slab.get(keys[9]), Some(&9));
+        assert_eq!(slab.get(keys[50]), None);
+        // The free slot before the last value is reused first, then the slab grows again.
+        let a = slab.insert(3);
+        assert_eq!(u64::from(a) as u32, 3);
+        let b = slab.insert(10);
+        assert_eq!(u64::from(b) as u32, 10);
+        assert_eq!(slab.iter().count(), 11);
+
+        for key in [keys[0], keys[9], a, b] {
+            slab.remove(key);
+        }
+        slab.shrink_to_fit();
+        assert_eq!(u64::from(slab.insert(0)) as u32, 0);
+    }
+}
@@ -0,0 +1,308 @@
+//! Sorting record files that don't fit into memory.
+//!
+//! [external_sort] reads records into memory until the memory budget is used up, sorts them and writes them to a
+//! spill file, and finally merges the spill files with [merge_sorted_readers], in several passes if there are more
+//! of them than fit into the memory budget.
+
+use std::cmp::Ordering;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{self, AtomicU64};
+
+use crate::executor::YieldIfNeeded;
+use crate::fs::{merge_sorted_readers, remove_file, File, MergeSorted, RecordReader};
+use crate::io_buffer::IoBuffer;
+use crate::local_alloc::{alloc_aligned, LocalAlloc};
+
+// Number of records that are sorted or merged between checks of the preemption budget.
+const SORT_BLOCK: usize = 1024;
+const SPILL_BUFFER_SIZE: usize = 1 << 20;
+const READ_CHUNK_SIZE: usize = 256 * 1024;
+// A reader of a spill file holds the current chunk and the prefetched next chunk.
+const RUN_READER_MEMORY: usize = 2 * READ_CHUNK_SIZE;
+const MIN_ALIGN: usize = 4096;
+
+static NEXT_SPILL_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Sorts the records of `input` by `cmp`, using about `memory_budget` bytes of memory for the records that are
+/// sorted in memory.
+///
+/// Records are read into memory until they take up `memory_budget` bytes, this run of records is sorted and written
+/// to a spill file in [std::env::temp_dir]. The returned stream merges the runs. If there are more runs than the
+/// readers that fit into `memory_budget`, groups of them are merged into larger runs first, so the merge also stays
+/// within the budget (but it always merges at least two runs at a time). Sorting yields to other tasks when the
+/// preemption budget of the task runs out, so a large budget doesn't stall the executor.
+///
+/// Spill files are written with O_DIRECT so the runs don't push other data out of the page cache, falling back to
+/// buffered writes if the file system doesn't support it. They are unlinked once they are opened for merging, so
+/// nothing is left behind if the process exits before the merge is finished.
+///
+/// Records that compare equal are returned in the order they were read in.
+pub async fn external_sort<F>(
+    input: &mut RecordReader,
+    mut cmp: F,
+    memory_budget: usize,
+) -> io::Result<MergeSorted<F>>
+where
+    F: FnMut(&[u8], &[u8]) -> Ordering,
+{
+    let dir = std::env::temp_dir();
+    let mut data = Vec::new_in(LocalAlloc::new());
+    let mut records = Vec::new_in(LocalAlloc::new());
+    let mut runs = Vec::new();
+    loop {
+        let more = input.advance().await?;
+        if let Some(record) = input.current() {
+            let start = data.len();
+            data.extend_from_slice(record);
+            records.push(start..data.len());
+        }
+        // The ranges are counted twice since merging them needs a second buffer of the same size.
+        let used = data.len() + 2 * records.len() * std::mem::size_of::<Range<usize>>();
+        if !records.is_empty() && (used >= memory_budget || !more) {
+            sort_records(&data, &mut records, &mut cmp).await;
+            runs.push(spill_run(&dir, &data, &records).await?);
+            data.clear();
+            records.clear();
+        }
+        if !more {
+            break;
+        }
+    }
+    drop((data, records));
+
+    // Merging also writes to a spill file, so its buffer is taken out of the budget.
+    let fan_in = (memory_budget.saturating_sub(SPILL_BUFFER_SIZE) / RUN_READER_MEMORY).max(2);
+    while runs.len() > fan_in {
+        // Groups of neighbouring runs are merged into a run at the same position, so equal records stay in order.
+        let mut merged = Vec::with_capacity(runs.len().div_ceil(fan_in));
+        let mut rest = runs.into_iter();
+        loop {
+            let group = rest.by_ref().take(fan_in).collect::<Vec<_>>();
+            match group.len() {
+                0 => break,
+                1 => merged.extend(group),
+                _ => merged.push(merge_runs(&dir, group, &mut cmp).await?),
+            }
+        }
+        runs = merged;
+    }
+    Ok(merge_sorted_readers(runs, cmp))
+}
+
+// Stable merge sort of the records, sorting blocks and then merging pairs of sorted ranges of doubling width.
+async fn sort_records<F>(data: &[u8], records: &mut Vec<Range<usize>, LocalAlloc>, cmp: &mut F)
+where
+    F: FnMut(&[u8], &[u8]) -> Ordering,
+{
+    for block in records.chunks_mut(SORT_BLOCK) {
+        block.sort_by(|a, b| cmp(&data[a.clone()], &data[b.clone()]));
+        YieldIfNeeded.await;
+    }
+
+    let len = records.len();
+    let mut scratch = Vec::with_capacity_in(len, LocalAlloc::new());
+    let mut width = SORT_BLOCK;
+    while width < len {
+        scratch.clear();
+        for start in (0..len).step_by(2 * width) {
+            let (mut i, mid) = (start, (start + width).min(len));
+            let (mut j, end) = (mid, (start + 2 * width).min(len));
+            while i < mid || j < end {
+                let take_left = j == end
+                    || (i < mid
+                        && cmp(&data[records[i].clone()], &data[records[j].clone()]).is_le());
+                if take_left {
+                    scratch.push(records[i].clone());
+                    i += 1;
+                } else {
+                    scratch.push(records[j].clone());
+                    j += 1;
+                }
+                if scratch.len() % SORT_BLOCK == 0 {
+                    YieldIfNeeded.await;
+                }
+            }
+        }
+        std::mem::swap(records, &mut scratch);
+        width *= 2;
+    }
+}
+
+// Writes the records to a new spill file and opens a reader for it, the file is unlinked before this returns.
+async fn spill_run(dir: &Path, data: &[u8], records: &[Range<usize>]) -> io::Result<RecordReader> {
+    let path = spill_path(dir);
+    let res = async {
+        let mut writer = SpillWriter::create(&path).await?;
+        for record in records {
+            writer.write_record(&data[record.clone()]).await?;
+        }
+        writer.finish().await
+    }
+    .await;
+    open_run(&path, res).await
+}
+
+// Merges the runs into a new spill file and opens a reader for it.
+async fn merge_runs<F>(dir: &Path, runs: Vec<RecordReader>, cmp: &mut F) -> io::Result<RecordReader>
+where
+    F: FnMut(&[u8], &[u8]) -> Ordering,
+{
+    let path = spill_path(dir);
+    let res = async {
+        let mut writer = SpillWriter::create(&path).await?;
+        let mut merged = merge_sorted_readers(runs, |a: &[u8], b: &[u8]| cmp(a, b));
+        let mut count = 0usize;
+        while let Some(record) = merged.next().await {
+            writer.write_record(record?).await?;
+            count += 1;
+            if count == SORT_BLOCK {
+                count = 0;
+                YieldIfNeeded.await;
+            }
+        }
+        writer.finish().await
+    }
+    .await;
+    open_run(&path, res).await
+}
+
+// Opens a reader for the spill file at `path` if writing it succeeded, the file is unlinked before this returns.
+async fn open_run(path: &Path, written: io::Result<()>) -> io::Result<RecordReader> {
+    let res = match written {
+        Ok(()) => File::open(path, libc::O_RDONLY | libc::O_CLOEXEC, 0)?.await,
+        Err(e) => Err(e),
+    };
+    let removed = remove_file(path)?.await;
+    let file = res?;
+    removed?;
+    Ok(RecordReader::new(file, READ_CHUNK_SIZE))
+}
+
+fn spill_path(dir: &Path) -> PathBuf {
+    let id = NEXT_SPILL_ID.fetch_add(1, atomic::Ordering::Relaxed);
+    dir.join(format!("io2-sort-{}-{}", std::process::id(), id))
+}
+
+// Buffers records in the format of RecordReader and writes them to a spill file in aligned chunks.
+struct SpillWriter {
+    file: File,
+    align: usize,
+    buf: IoBuffer<LocalAlloc>,
+    len: usize,
+    offset: u64,
+}
+
+impl SpillWriter {
+    async fn create(path: &Path) -> io::Result<Self> {
+        let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_CLOEXEC;
+        let file = match File::open(path, flags | libc::O_DIRECT, 0o600)?.await {
+            // tmpfs and some other file systems don't support O_DIRECT.
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                File::open(path, flags, 0o600)?.await?
+            }
+            res => res?,
+        };
+        let statx = file.statx().await?;
+        let align = usize::try_from(statx.stx_dio_mem_align.max(statx.stx_dio_offset_align))
+            .unwrap()
+            .max(MIN_ALIGN);
+        let buf = alloc_aligned(SPILL_BUFFER_SIZE.next_multiple_of(align), align)
+            .map_err(|_| io::Error::from(io::ErrorKind::OutOfMemory))?;
+        Ok(Self {
+            file,
+            align,
+            buf,
+            len: 0,
+            offset: 0,
+        })
+    }
+
+    async fn write_record(&mut self, record: &[u8]) -> io::Result<()> {
+        let len = u32::try_from(record.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "record is too large"))?;
+        self.write(&len.to_le_bytes()).await?;
+        self.write(record).await
+    }
+
+    async fn write(&mut self, mut bytes: &[u8]) -> io::Result<()> {
+        while !bytes.is_empty() {
+            let n = bytes.len().min(self.buf.size() - self.len);
+            self.buf.as_mut_slice()[self.len..self.len + n].copy_from_slice(&bytes[..n]);
+            self.len += n;
+            bytes = &bytes[n..];
+            if self.len == self.buf.size() {
+                self.file
+                    .write_all(self.buf.as_slice(), self.offset)
+                    .await?;
+                self.offset += u64::try_from(self.len).unwrap();
+                self.len = 0;
+            }
+        }
+        Ok(())
+    }
+
+    async fn finish(mut self) -> io::Result<()> {
+        // Direct writes have to be a multiple of the alignment, the padding is cut off again after the write.
+        if self.len > 0 {
+            let size = self.offset + u64::try_from(self.len).unwrap();
+            let padded = self.len.next_multiple_of(self.align);
+            self.buf.as_mut_slice()[self.len..padded].fill(0);
+            self.file
+                .write_all(&self.buf.as_slice()[..padded], self.offset)
+                .await?;
+            self.file.set_len(size).await?;
+        }
+        self.file.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::ExecutorConfig;
+    use crate::fs::RecordWriter;
+
+    #[test]
+    fn test_external_sort() {
+        let path = std::env::temp_dir().join(format!("io2_test_sort_{}", std::process::id()));
+        let test_path = path.clone();
+        ExecutorConfig::new()
+            .run(async move {
+                let mut writer = RecordWriter::create(&test_path).await.unwrap();
+                let mut expected = Vec::new();
+                for i in 0..20_000u64 {
+                    let key = i.wrapping_mul(0x9E37_79B9_7F4A_7C15) % 5000;
+                    // Records of different lengths, with duplicate keys to check the order is stable.
+                    let record = format!("{:05}:{}", key, "x".repeat((i % 13) as usize));
+                    writer.write(record.as_bytes()).await.unwrap();
+                    expected.push((key, i, record));
+                }
+                writer.finish().await.unwrap();
+                expected.sort();
+
+                let mut input = RecordReader::open(&test_path).await.unwrap();
+                // The budget only fits two readers, so the runs are merged in several passes.
+                let mut sorted = external_sort(&mut input, |a, b| a[..5].cmp(&b[..5]), 64 * 1024)
+                    .await
+                    .unwrap();
+                let mut out = Vec::new();
+                while let Some(record) = sorted.next().await {
+                    out.push(String::from_utf8(record.unwrap().to_vec()).unwrap());
+                }
+                let expected: Vec<_> = expected.into_iter().map(|(_, _, r)| r).collect();
+                assert_eq!(out, expected);
+
+                // The spill files are unlinked while they are still being merged.
+                let prefix = format!("io2-sort-{}-", std::process::id());
+                assert!(std::fs::read_dir(std::env::temp_dir()).unwrap().all(|e| !e
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(&prefix)));
+            })
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,847 @@
+//! Sorted string tables, immutable files of sorted key value pairs as used by LSM trees.
+//!
+//! A table is a sequence of data blocks followed by an index block, a bloom filter and a footer:
+//!
+//! | data block | ... | data block | index block | bloom filter | footer |
+//!
+//! Blocks hold entries with keys in increasing order. Each key is stored as the length of the prefix it shares with the
+//! previous key and the rest of it, except at restart points where the whole key is stored so a block can be binary
+//! searched over its restart points. An entry is:
+//!
+//! | shared: varint | unshared: varint | value length: varint | unshared key bytes | value |
+//!
+//! The entries are followed by the offsets of the restart points and their count as u32, then by the crc32c of the
+//! block. The index block has the same format, with the last key of each data block mapping to the offset and size of
+//! the block. Integers are little endian.
+//!
+//! Reads of consecutive blocks are merged into large reads when iterating, point lookups read a single block after
+//! checking the bloom filter. Tables are read with O_DIRECT into aligned buffers if the file system supports it, so
+//! scans don't push other data out of the page cache.
+
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+
+use crate::block::crc32c;
+use crate::fs::File;
+use crate::hash::{Hasher, XxHash64};
+use crate::io_buffer::IoBuffer;
+use crate::local_alloc::{alloc_aligned, LocalAlloc};
+
+const FOOTER_SIZE: usize = 40;
+const MAGIC: u64 = 0x5353_5432_4f49_0001;
+// Buffered output is written to the file after it grows past this.
+const WRITE_BUFFER_SIZE: usize = 256 * 1024;
+// Consecutive blocks are read together up to this size while iterating.
+const READ_AHEAD_SIZE: usize = 256 * 1024;
+// Reads are aligned to at least this, also when the file isn't opened with O_DIRECT.
+const MIN_ALIGN: usize = 4096;
+
+#[derive(Clone)]
+pub struct SstConfig {
+    block_size: usize,
+    restart_interval: usize,
+    prefix_compression: bool,
+    bloom_bits_per_key: usize,
+}
+
+impl SstConfig {
+    pub fn new() -> Self {
+        Self {
+            block_size: 4096,
+            restart_interval: 16,
+            prefix_compression: true,
+            bloom_bits_per_key: 10,
+        }
+    }
+
+    /// A data block is finished once its size reaches this, so blocks can be slightly larger.
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        assert!(block_size > 0, "block_size must be greater than zero");
+        self.block_size = block_size;
+        self
+    }
+
+    /// Number of entries between restart points, a larger interval saves space and makes lookups scan more entries.
+    pub fn restart_interval(mut self, restart_interval: usize) -> Self {
+        assert!(
+            restart_interval > 0,
+            "restart_interval must be greater than zero"
+        );
+        self.restart_interval = restart_interval;
+        self
+    }
+
+    /// Stores every key in full if disabled, which is faster to decode when keys don't share prefixes.
+    pub fn prefix_compression(mut self, prefix_compression: bool) -> Self {
+        self.prefix_compression = prefix_compression;
+        self
+    }
+
+    /// Size of the bloom filter, 10 bits per key gives about 1% false positives. Zero disables the filter.
+    pub fn bloom_bits_per_key(mut self, bloom_bits_per_key: usize) -> Self {
+        self.bloom_bits_per_key = bloom_bits_per_key;
+        self
+    }
+}
+
+impl Default for SstConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct BlockBuilder {
+    buf: Vec<u8, LocalAlloc>,
+    restarts: Vec<u32, LocalAlloc>,
+    num_entries: usize,
+    last_key: Vec<u8, LocalAlloc>,
+}
+
+impl BlockBuilder {
+    fn new() -> Self {
+        Self {
+            buf: Vec::new_in(LocalAlloc::new()),
+            restarts: Vec::new_in(LocalAlloc::new()),
+            num_entries: 0,
+            last_key: Vec::new_in(LocalAlloc::new()),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.num_entries == 0
+    }
+
+    fn size(&self) -> usize {
+        self.buf.len() + self.restarts.len() * 4 + 8
+    }
+
+    fn add(&mut self, key: &[u8], value: &[u8], restart_interval: usize) {
+        let shared = if self.num_entries % restart_interval == 0 {
+            self.restarts.push(u32::try_from(self.buf.len()).unwrap());
+            0
+        } else {
+            self.last_key
+                .iter()
+                .zip(key)
+                .take_while(|(a, b)| a == b)
+                .count()
+        };
+        put_varint(&mut self.buf, shared as u64);
+        put_varint(&mut self.buf, (key.len() - shared) as u64);
+        put_varint(&mut self.buf, value.len() as u64);
+        self.buf.extend_from_slice(&key[shared..]);
+        self.buf.extend_from_slice(value);
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        self.num_entries += 1;
+    }
+
+    // Appends the finished block with its checksum to `out` and resets the builder.
+    fn finish(&mut self, out: &mut Vec<u8, LocalAlloc>) -> usize {
+        for restart in self.restarts.iter() {
+            self.buf.extend_from_slice(&restart.to_le_bytes());
+        }
+        self.buf
+            .extend_from_slice(&u32::try_from(self.restarts.len()).unwrap().to_le_bytes());
+        out.extend_from_slice(&self.buf);
+        out.extend_from_slice(&crc32c(&self.buf).to_le_bytes());
+        let size = self.buf.len() + 4;
+        self.buf.clear();
+        self.restarts.clear();
+        self.num_entries = 0;
+        size
+    }
+}
+
+/// Writes a table, keys have to be added in strictly increasing order.
+pub struct SstWriter {
+    file: File,
+    config: SstConfig,
+    // Finished blocks that are not written to the file yet.
+    out: Vec<u8, LocalAlloc>,
+    // File offset of out[0].
+    out_offset: u64,
+    block: BlockBuilder,
+    index: BlockBuilder,
+    key_hashes: Vec<u64, LocalAlloc>,
+    num_entries: u64,
+}
+
+impl SstWriter {
+    /// Creates the file at `path`, truncating it if it exists.
+    pub async fn create(path: &Path, config: SstConfig) -> io::Result<Self> {
+        let file = File::create(path)?.await?;
+        Ok(Self {
+            file,
+            config,
+            out: Vec::new_in(LocalAlloc::new()),
+            out_offset: 0,
+            block: BlockBuilder::new(),
+            index: BlockBuilder::new(),
+            key_hashes: Vec::new_in(LocalAlloc::new()),
+            num_entries: 0,
+        })
+    }
+
+    pub async fn add(&mut self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        if self.num_entries > 0 && key <= self.block_or_index_last_key() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "keys have to be added in strictly increasing order",
+            ));
+        }
+        let restart_interval = if self.config.prefix_compression {
+            self.config.restart_interval
+        } else {
+            1
+        };
+        self.block.add(key, value, restart_interval);
+        if self.config.bloom_bits_per_key > 0 {
+            self.key_hashes.push(hash_key(key));
+        }
+        self.num_entries += 1;
+
+        if self.block.size() >= self.config.block_size {
+            self.finish_block();
+            if self.out.len() >= WRITE_BUFFER_SIZE {
+                self.flush().await?;
+            }
+        }
+        Ok(())
+    }
+
+    // The last added key, the data block is empty right after it is finished.
+    fn block_or_index_last_key(&self) -> &[u8] {
+        if self.block.is_empty() {
+            &self.index.last_key
+        } else {
+            &self.block.last_key
+        }
+    }
+
+    fn finish_block(&mut self) {
+        let offset = self.out_offset + self.out.len() as u64;
+        let size = self.block.finish(&mut self.out);
+        let mut handle = [0u8; 12];
+        handle[..8].copy_from_slice(&offset.to_le_bytes());
+        handle[8..].copy_from_slice(&u32::try_from(size).unwrap().to_le_bytes());
+        let last_key = std::mem::replace(&mut self.block.last_key, Vec::new_in(LocalAlloc::new()));
+        self.index
+            .add(&last_key, &handle, self.config.restart_interval);
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.file.write_all(&self.out, self.out_offset).await?;
+        self.out_offset += self.out.len() as u64;
+        self.out.clear();
+        Ok(())
+    }
+
+    /// Writes the index, bloom filter and footer, then syncs and closes the file. Returns the number of entries.
+    pub async fn finish(mut self) -> io::Result<u64> {
+        if !self.block.is_empty() {
+            self.finish_block();
+        }
+        let index_offset = self.out_offset + self.out.len() as u64;
+        let index_size = self.index.finish(&mut self.out);
+
+        let bloom_offset = self.out_offset + self.out.len() as u64;
+        let bloom = build_bloom(&self.key_hashes, self.config.bloom_bits_per_key);
+        self.out.extend_from_slice(&bloom);
+
+        self.out.extend_from_slice(&index_offset.to_le_bytes());
+        self.out
+            .extend_from_slice(&u32::try_from(index_size).unwrap().to_le_bytes());
+        self.out.extend_from_slice(&bloom_offset.to_le_bytes());
+        self.out
+            .extend_from_slice(&u32::try_from(bloom.len()).unwrap().to_le_bytes());
+        self.out.extend_from_slice(&self.num_entries.to_le_bytes());
+        self.out.extend_from_slice(&MAGIC.to_le_bytes());
+
+        self.flush().await?;
+        self.file.sync_all().await?;
+        let num_entries = self.num_entries;
+        self.file.close().await?;
+        Ok(num_entries)
+    }
+}
+
+struct IndexEntry {
+    last_key: Vec<u8, LocalAlloc>,
+    offset: u64,
+    size: usize,
+}
+
+/// Reads a table written by [SstWriter]. The index and the bloom filter are kept in memory.
+pub struct SstReader {
+    file: File,
+    // Alignment of read offsets, lengths and buffers.
+    align: usize,
+    index: Vec<IndexEntry, LocalAlloc>,
+    bloom: Vec<u8, LocalAlloc>,
+    num_entries: u64,
+}
+
+impl SstReader {
+    pub async fn open(path: &Path) -> io::Result<Self> {
+        let flags = libc::O_RDONLY | libc::O_CLOEXEC;
+        let file = match File::open(path, flags | libc::O_DIRECT, 0)?.await {
+            // tmpfs and some other file systems don't support O_DIRECT.
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => File::open(path, flags, 0)?.await?,
+            res => res?,
+        };
+        let statx = file.statx().await?;
+        let align = usize::try_from(statx.stx_dio_mem_align.max(statx.stx_dio_offset_align))
+            .unwrap()
+            .max(MIN_ALIGN);
+        let file_size = file.file_size().await?;
+        let mut sst = Self {
+            file,
+            align,
+            index: Vec::new_in(LocalAlloc::new()),
+            bloom: Vec::new_in(LocalAlloc::new()),
+            num_entries: 0,
+        };
+        if file_size < FOOTER_SIZE as u64 {
+            return Err(corrupted("file is smaller than the footer"));
+        }
+
+        let mut buf = None;
+        let range = sst
+            .read_aligned(&mut buf, file_size - FOOTER_SIZE as u64, FOOTER_SIZE)
+            .await?;
+        let footer = &buf.as_ref().unwrap().as_slice()[range];
+        let u64_at = |at: usize| u64::from_le_bytes(footer[at..at + 8].try_into().unwrap());
+        let u32_at = |at: usize| u32::from_le_bytes(footer[at..at + 4].try_into().unwrap());
+        if u64_at(32) != MAGIC {
+            return Err(corrupted("bad magic number"));
+        }
+        let (index_offset, index_size) = (u64_at(0), u32_at(8));
+        let (bloom_offset, bloom_size) = (u64_at(12), u32_at(20));
+        sst.num_entries = u64_at(24);
+        // The bloom filter directly follows the index block.
+        let index_end = index_offset.checked_add(u64::from(index_size));
+        let bloom_end = bloom_offset
+            .checked_add(u64::from(bloom_size))
+            .and_then(|end| end.checked_add(FOOTER_SIZE as u64));
+        if index_end != Some(bloom_offset) || bloom_end != Some(file_size) {
+            return Err(corrupted("footer doesn't match the file size"));
+        }
+
+        let index_size = usize::try_from(index_size).unwrap();
+        let len = index_size
+            .checked_add(usize::try_from(bloom_size).unwrap())
+            .ok_or_else(|| corrupted("index is too large"))?;
+        let range = sst.read_aligned(&mut buf, index_offset, len).await?;
+        let data = &buf.as_ref().unwrap().as_slice()[range];
+        let index_block = check_block(&data[..index_size])?;
+
+        let mut iter = BlockIter::new(index_block)?;
+        let mut prev_end = 0;
+        while let Some((key, handle)) = iter.next()? {
+            if handle.len() != 12 {
+                return Err(corrupted("bad block handle in index"));
+            }
+            let offset = u64::from_le_bytes(handle[..8].try_into().unwrap());
+            let size = u32::from_le_bytes(handle[8..].try_into().unwrap());
+            // Blocks are stored in order before the index, this keeps the offset arithmetic of reads from
+            // overflowing.
+            match offset.checked_add(u64::from(size)) {
+                Some(end) if offset >= prev_end && end <= index_offset => prev_end = end,
+                _ => return Err(corrupted("block handle is out of range")),
+            }
+            let mut last_key = Vec::new_in(LocalAlloc::new());
+            last_key.extend_from_slice(key);
+            sst.index.push(IndexEntry {
+                last_key,
+                offset,
+                size: usize::try_from(size).unwrap(),
+            });
+        }
+
+        sst.bloom.extend_from_slice(&data[index_size..]);
+        Ok(sst)
+    }
+
+    // Reads `len` bytes at `offset` with an aligned read, into `buf` if it is large enough or into a new buffer that is
+    // left in `buf`. Returns the position of the bytes in the buffer.
+    async fn read_aligned(
+        &self,
+        buf: &mut Option<IoBuffer<LocalAlloc>>,
+        offset: u64,
+        len: usize,
+    ) -> io::Result<Range<usize>> {
+        let start = offset - offset % self.align as u64;
+        let skip = usize::try_from(offset - start).unwrap();
+        let end = skip
+            .checked_add(len)
+            .ok_or_else(|| corrupted("read is too large"))?;
+        let read_len = end.next_multiple_of(self.align);
+        if !buf.as_ref().is_some_and(|buf| buf.size() >= read_len) {
+            *buf = None;
+            *buf = Some(
+                alloc_aligned(read_len, self.align)
+                    .map_err(|_| io::Error::from(io::ErrorKind::OutOfMemory))?,
+            );
+        }
+        let data = &mut buf.as_mut().unwrap().as_mut_slice()[..read_len];
+        // The read is short at the end of the file, which is fine as long as the range is covered.
+        let mut filled = 0;
+        while filled < end {
+            let n = self
+                .file
+                .read(&mut data[filled..], start + filled as u64)
+                .await?;
+            if n == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            filled += n;
+        }
+        Ok(skip..end)
+    }
+
+    pub fn num_entries(&self) -> u64 {
+        self.num_entries
+    }
+
+    /// Returns the value of `key`.
+    pub async fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8, LocalAlloc>>> {
+        if !bloom_may_contain(&self.bloom, hash_key(key)) {
+            return Ok(None);
+        }
+        let block_idx = self
+            .index
+            .partition_point(|entry| &entry.last_key[..] < key);
+        let entry = match self.index.get(block_idx) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+
+        let mut buf = None;
+        let range = self
+            .read_aligned(&mut buf, entry.offset, entry.size)
+            .await?;
+        let data = check_block(&buf.as_ref().unwrap().as_slice()[range])?;
+        let mut iter = BlockIter::new(data)?;
+        if !iter.seek(key)? || iter.key != key {
+            return Ok(None);
+        }
+        let mut value = Vec::new_in(LocalAlloc::new());
+        value.extend_from_slice(&data[iter.last_value]);
+        Ok(Some(value))
+    }
+
+    /// Returns the entries in key order, starting from the first key that is greater than or equal to `start`.
+    pub fn iter_from<'sst>(&'sst self, start: &[u8]) -> Iter<'sst> {
+        let first_block = self
+            .index
+            .partition_point(|entry| &entry.last_key[..] < start);
+        let mut seek_to = Vec::new_in(LocalAlloc::new());
+        seek_to.extend_from_slice(start);
+        Iter {
+            sst: self,
+            next_block: first_block,
+            chunk: None,
+            blocks: Vec::new_in(LocalAlloc::new()),
+            current: None,
+            seek_to: Some(seek_to),
+        }
+    }
+
+    /// Returns all entries in key order.
+    pub fn iter(&self) -> Iter<'_> {
+        self.iter_from(&[])
+    }
+
+    pub fn close(self) -> crate::fs::file::Close {
+        self.file.close()
+    }
+}
+
+/// Iterator over the entries of a table, see [SstReader::iter].
+pub struct Iter<'sst> {
+    sst: &'sst SstReader,
+    // Index of the first block that isn't read yet.
+    next_block: usize,
+    // Consecutive blocks that were read with a single read, the buffer is reused for the next read if it is large
+    // enough.
+    chunk: Option<IoBuffer<LocalAlloc>>,
+    // Ranges of the blocks in chunk that are not iterated yet, in reverse order.
+    blocks: Vec<Range<usize>, LocalAlloc>,
+    // Position in the block that is being iterated, the block is at the end of chunk's range list.
+    current: Option<BlockPos>,
+    seek_to: Option<Vec<u8, LocalAlloc>>,
+}
+
+struct BlockPos {
+    range: Range<usize>,
+    offset: usize,
+    key: Vec<u8, LocalAlloc>,
+}
+
+impl BlockPos {
+    // Moves to the next entry of the block, or to the first entry at or after `seek_to` if it is set. Returns the range
+    // of the value in `chunk`, the key is left in `self.key`.
+    fn advance(
+        &mut self,
+        chunk: &[u8],
+        seek_to: &mut Option<Vec<u8, LocalAlloc>>,
+    ) -> io::Result<Option<Range<usize>>> {
+        let mut iter = BlockIter::new(&chunk[self.range.clone()])?;
+        iter.offset = self.offset;
+        iter.key = std::mem::replace(&mut self.key, Vec::new_in(LocalAlloc::new()));
+        let found = match seek_to.take() {
+            Some(target) => iter.seek(&target)?,
+            None => iter.next()?.is_some(),
+        };
+        let value = iter.last_value.clone();
+        self.offset = iter.offset;
+        self.key = iter.key;
+        Ok(found.then(|| self.range.start + value.start..self.range.start + value.end))
+    }
+}
+
+impl<'sst> Iter<'sst> {
+    /// Returns the next key and value.
+    pub async fn next(&mut self) -> Option<io::Result<(&[u8], &[u8])>> {
+        let value = loop {
+            if let Some(pos) = self.current.as_mut() {
+                match pos.advance(chunk_data(&self.chunk), &mut self.seek_to) {
+                    Ok(Some(value)) => break value,
+                    Ok(None) => self.current = None,
+                    Err(e) => return Some(Err(e)),
+                }
+                continue;
+            }
+
+            if let Some(range) = self.blocks.pop() {
+                if let Err(e) = check_block(&chunk_data(&self.chunk)[range.clone()]) {
+                    return Some(Err(e));
+                }
+                self.current = Some(BlockPos {
+                    // Without the checksum.
+                    range: range.start..range.end - 4,
+                    offset: 0,
+                    key: Vec::new_in(LocalAlloc::new()),
+                });
+                continue;
+            }
+
+            if let Err(e) = self.read_chunk().await {
+                return Some(Err(e));
+            }
+            if self.blocks.is_empty() {
+                return None;
+            }
+        };
+        let pos = self.current.as_ref().unwrap();
+        Some(Ok((&pos.key, &chunk_data(&self.chunk)[value])))
+    }
+
+    // Reads as many of the next blocks as fit in READ_AHEAD_SIZE, at least one.
+    async fn read_chunk(&mut self) -> io::Result<()> {
+        let index = &self.sst.index;
+        let first = self.next_block;
+        if first >= index.len() {
+            return Ok(());
+        }
+        let start = index[first].offset;
+        let mut end = first + 1;
+        while end < index.len()
+            && (index[end].offset + index[end].size as u64 - start) as usize <= READ_AHEAD_SIZE
+        {
+            end += 1;
+        }
+        let last = &index[end - 1];
+        let len = usize::try_from(last.offset + last.size as u64 - start).unwrap();
+        let range = self.sst.read_aligned(&mut self.chunk, start, len).await?;
+        for entry in index[first..end].iter().rev() {
+            let block_start = range.start + usize::try_from(entry.offset - start).unwrap();
+            self.blocks.push(block_start..block_start + entry.size);
+        }
+        self.next_block = end;
+        Ok(())
+    }
+}
+
+fn chunk_data(chunk: &Option<IoBuffer<LocalAlloc>>) -> &[u8] {
+    match chunk {
+        Some(chunk) => chunk.as_slice(),
+        None => &[],
+    }
+}
+
+// Decodes the entries of a block that passed check_block.
+struct BlockIter<'a> {
+    data: &'a [u8],
+    restarts_offset: usize,
+    num_restarts: usize,
+    offset: usize,
+    key: Vec<u8, LocalAlloc>,
+    // Range of the value of the entry returned by the last call to next, its key is in `key`.
+    last_value: Range<usize>,
+}
+
+impl<'a> BlockIter<'a> {
+    fn new(data: &'a [u8]) -> io::Result<Self> {
+        if data.len() < 4 {
+            return Err(corrupted("block is too small"));
+        }
+        let num_restarts = usize::try_from(u32::from_le_bytes(
+            data[data.len() - 4..].try_into().unwrap(),
+        ))
+        .unwrap();
+        let restarts_offset = num_restarts
+            .checked_mul(4)
+            .and_then(|len| data.len().checked_sub(4 + len))
+            .ok_or_else(|| corrupted("bad restart count"))?;
+        Ok(Self {
+            data,
+            restarts_offset,
+            num_restarts,
+            offset: 0,
+            key: Vec::new_in(LocalAlloc::new()),
+            last_value: 0..0,
+        })
+    }
+
+    fn restart(&self, idx: usize) -> usize {
+        let at = self.restarts_offset + idx * 4;
+        usize::try_from(u32::from_le_bytes(
+            self.data[at..at + 4].try_into().unwrap(),
+        ))
+        .unwrap()
+    }
+
+    fn next(&mut self) -> io::Result<Option<(&[u8], &[u8])>> {
+        if self.offset >= self.restarts_offset {
+            return Ok(None);
+        }
+        let mut pos = self.offset;
+        let shared = get_varint(self.data, &mut pos)?;
+        let unshared = get_varint(self.data, &mut pos)?;
+        let value_len = get_varint(self.data, &mut pos)?;
+        let end = pos
+            .checked_add(unshared)
+            .and_then(|end| end.checked_add(value_len))
+            .filter(|&end| end <= self.restarts_offset);
+        let end = match end {
+            Some(end) if shared <= self.key.len() => end,
+            _ => return Err(corrupted("bad entry in block")),
+        };
+        self.key.truncate(shared);
+        self.key.extend_from_slice(&self.data[pos..pos + unshared]);
+        let value = pos + unshared..end;
+        self.offset = value.end;
+        self.last_value = value.clone();
+        Ok(Some((&self.key, &self.data[value])))
+    }
+
+    // Moves to the first entry with a key greater than or equal to `target`, returns false if there is none. The entry
+    // is left in `key` and `last_value` as if it was returned by next.
+    fn seek(&mut self, target: &[u8]) -> io::Result<bool> {
+        // Find the first restart point with a key that isn't less than target, keys are stored in full at restart
+        // points. The entry is at or after the restart point before it.
+        let (mut lo, mut hi) = (0, self.num_restarts);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            self.offset = self.restart(mid);
+            self.key.clear();
+            let less = match self.next()? {
+                Some((key, _)) => key < target,
+                None => false,
+            };
+            if less {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        self.offset = if lo == 0 { 0 } else { self.restart(lo - 1) };
+        self.key.clear();
+        while let Some((key, _)) = self.next()? {
+            if key >= target {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+// Verifies the checksum of a block and returns its contents.
+fn check_block(block: &[u8]) -> io::Result<&[u8]> {
+    if block.len() < 4 {
+        return Err(corrupted("block is too small"));
+    }
+    let (data, crc) = block.split_at(block.len() - 4);
+    if crc32c(data) != u32::from_le_bytes(crc.try_into().unwrap()) {
+        return Err(corrupted("block checksum mismatch"));
+    }
+    Ok(data)
+}
+
+fn corrupted(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupted sst file: {}", msg),
+    )
+}
+
+fn put_varint(out: &mut Vec<u8, LocalAlloc>, mut v: u64) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn get_varint(data: &[u8], pos: &mut usize) -> io::Result<usize> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let b = *data
+            .get(*pos)
+            .ok_or_else(|| corrupted("truncated varint"))?;
+        *pos += 1;
+        v |= u64::from(b & 0x7f) << shift;
+        if b < 0x80 {
+            return usize::try_from(v).map_err(|_| corrupted("varint overflow"));
+        }
+    }
+    Err(corrupted("varint is too long"))
+}
+
+fn hash_key(key: &[u8]) -> u64 {
+    let mut hasher = XxHash64::new(0);
+    hasher.update(key);
+    hasher.finish()
+}
+
+// Bloom filter bits followed by the number of probes, probes are derived from a single hash by double hashing.
+fn build_bloom(hashes: &[u64], bits_per_key: usize) -> Vec<u8, LocalAlloc> {
+    let mut bloom = Vec::new_in(LocalAlloc::new());
+    if bits_per_key == 0 || hashes.is_empty() {
+        return bloom;
+    }
+    let num_probes = ((bits_per_key as f64 * 0.69) as u8).clamp(1, 30);
+    let num_bits = (hashes.len() * bits_per_key).max(64).next_multiple_of(8);
+    bloom.resize(num_bits / 8, 0);
+    for &hash in hashes {
+        for bit in probes(hash, num_probes, num_bits) {
+            bloom[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+    bloom.push(num_probes);
+    bloom
+}
+
+fn bloom_may_contain(bloom: &[u8], hash: u64) -> bool {
+    let (num_probes, bits) = match bloom.split_last() {
+        Some((&num_probes, bits)) => (num_probes, bits),
+        None => return true,
+    };
+    probes(hash, num_probes, bits.len() * 8).all(|bit| bits[bit / 8] & (1 << (bit % 8)) != 0)
+}
+
+fn probes(hash: u64, num_probes: u8, num_bits: usize) -> impl Iterator<Item = usize> {
+    let delta = hash.rotate_left(32);
+    (0..u64::from(num_probes))
+        .map(move |i| (hash.wrapping_add(i.wrapping_mul(delta)) % num_bits as u64) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::ExecutorConfig;
+
+    #[test]
+    fn test_sst() {
+        let path = std::env::temp_dir().join(format!("io2_test_sst_{}", std::process::id()));
+        let test_path = path.clone();
+        ExecutorConfig::new()
+            .run(async move {
+                let path = test_path;
+                let key = |i: u32| format!("key{:08}", i * 2);
+                let value = |i: u32| format!("value{}", i).repeat(usize::try_from(i % 7).unwrap());
+
+                for config in [
+                    SstConfig::new(),
+                    SstConfig::new()
+                        .prefix_compression(false)
+                        .bloom_bits_per_key(0)
+                        .block_size(100),
+                ] {
+                    let mut writer = SstWriter::create(&path, config).await.unwrap();
+                    for i in 0..10_000 {
+                        writer
+                            .add(key(i).as_bytes(), value(i).as_bytes())
+                            .await
+                            .unwrap();
+                    }
+                    let err = writer.add(key(5).as_bytes(), b"").await.unwrap_err();
+                    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+                    assert_eq!(writer.finish().await.unwrap(), 10_000);
+
+                    let sst = SstReader::open(&path).await.unwrap();
+                    assert_eq!(sst.num_entries(), 10_000);
+                    for i in [0, 1, 15, 16, 17, 777, 9_999] {
+                        let found = sst.get(key(i).as_bytes()).await.unwrap().unwrap();
+                        assert_eq!(found, value(i).as_bytes());
+                        // Odd numbers are between the keys.
+                        let missing = format!("key{:08}", i * 2 + 1);
+                        assert!(sst.get(missing.as_bytes()).await.unwrap().is_none());
+                    }
+                    assert!(sst.get(b"zzz").await.unwrap().is_none());
+
+                    let mut iter = sst.iter();
+                    let mut i = 0;
+                    while let Some(res) = iter.next().await {
+                        let (k, v) = res.unwrap();
+                        assert_eq!((k, v), (key(i).as_bytes(), value(i).as_bytes()));
+                        i += 1;
+                    }
+                    assert_eq!(i, 10_000);
+
+                    let mut iter = sst.iter_from(b"key00001001");
+                    let (k, _) = iter.next().await.unwrap().unwrap();
+                    assert_eq!(k, key(501).as_bytes());
+                    sst.close().await.unwrap();
+                }
+
+                // A flipped bit is detected by the block checksum.
+                let mut data = std::fs::read(&path).unwrap();
+                data[10] ^= 1;
+                std::fs::write(&path, &data).unwrap();
+                let sst = SstReader::open(&path).await.unwrap();
+                let err = sst.get(key(0).as_bytes()).await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+                // Offsets and sizes in the footer that would overflow are rejected.
+                let footer = data.len() - FOOTER_SIZE;
+                data[footer..footer + 8].copy_from_slice(&(u64::MAX - 10).to_le_bytes());
+                data[footer + 8..footer + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+                std::fs::write(&path, &data).unwrap();
+                let err = SstReader::open(&path).await.err().unwrap();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+                std::fs::remove_file(&path).unwrap();
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_block_entry_overflow() {
+        // An entry with a key length that overflows the offset arithmetic, followed by a single restart point.
+        let mut data = Vec::new_in(LocalAlloc::new());
+        put_varint(&mut data, 0);
+        put_varint(&mut data, u64::MAX);
+        put_varint(&mut data, 5);
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&1u32.to_le_bytes());
+        let mut iter = BlockIter::new(&data).unwrap();
+        let err = iter.next().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let err = BlockIter::new(&u32::MAX.to_le_bytes()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
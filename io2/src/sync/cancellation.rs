@@ -0,0 +1,249 @@
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::{Rc, Weak};
+use std::task::{Context, Poll};
+
+use crate::executor::{self, JoinHandle, CURRENT_TASK_CONTEXT};
+use crate::keymap::KeyMap;
+use crate::local_alloc::LocalAlloc;
+use crate::slab;
+
+struct Node {
+    cancelled: bool,
+    waiters: Vec<slab::Key, LocalAlloc>,
+    children: Vec<Weak<RefCell<Node>, LocalAlloc>, LocalAlloc>,
+    // Running tasks spawned with this token, their in-flight io is cancelled when the token is cancelled.
+    tasks: KeyMap<(), LocalAlloc>,
+}
+
+/// A token that can be used to signal cancellation to a tree of tasks.
+///
+/// Cancelling a token cancels all of its children. Tasks spawned with [spawn_with_token] get their in-flight io
+/// cancelled (IORING_OP_ASYNC_CANCEL) when the token is cancelled.
+///
+/// Cancellation never drops a task's future, since it might own buffers that are still being used by the kernel.
+/// Instead io operations of the task return errors and the task is expected to check [CancellationToken::is_cancelled]
+/// and return early.
+#[derive(Clone)]
+pub struct CancellationToken {
+    node: Rc<RefCell<Node>, LocalAlloc>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::with_state(false)
+    }
+
+    fn with_state(cancelled: bool) -> Self {
+        Self {
+            node: Rc::new_in(
+                RefCell::new(Node {
+                    cancelled,
+                    waiters: Vec::new_in(LocalAlloc::new()),
+                    children: Vec::new_in(LocalAlloc::new()),
+                    tasks: KeyMap::with_capacity_in(0, LocalAlloc::new()),
+                }),
+                LocalAlloc::new(),
+            ),
+        }
+    }
+
+    /// Creates a token that is cancelled when this token is cancelled, cancelling the child doesn't affect the parent.
+    pub fn child_token(&self) -> Self {
+        let mut node = self.node.borrow_mut();
+        let child = Self::with_state(node.cancelled);
+        node.children.retain(|c| c.strong_count() > 0);
+        node.children.push(Rc::downgrade(&child.node));
+        child
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.node.borrow().cancelled
+    }
+
+    pub fn cancel(&self) {
+        cancel_node(&self.node);
+    }
+
+    /// Resolves when the token is cancelled.
+    pub fn cancelled(&self) -> Cancelled<'_> {
+        Cancelled { token: self }
+    }
+
+    /// Wraps the future of a task so the io of the task is cancelled when the token is cancelled.
+    ///
+    /// The task is tracked from its first poll, since its id isn't known before it is spawned, until its future
+    /// finishes or is dropped.
+    pub(crate) fn track<F: Future>(&self, future: F) -> impl Future<Output = F::Output> {
+        let node = Rc::downgrade(&self.node);
+        async move {
+            let _tracked = TrackedTask::new(node, executor::current_task_id());
+            future.await
+        }
+    }
+}
+
+struct TrackedTask {
+    node: Weak<RefCell<Node>, LocalAlloc>,
+    task_id: slab::Key,
+}
+
+impl TrackedTask {
+    fn new(node: Weak<RefCell<Node>, LocalAlloc>, task_id: slab::Key) -> Self {
+        if let Some(node) = node.upgrade() {
+            let mut node = node.borrow_mut();
+            // If the token is already cancelled, the task has no io to cancel yet and it sees the cancelled token.
+            if !node.cancelled {
+                node.tasks.insert(task_id, ());
+            }
+        }
+        Self { node, task_id }
+    }
+}
+
+impl Drop for TrackedTask {
+    fn drop(&mut self) {
+        if let Some(node) = self.node.upgrade() {
+            node.borrow_mut().tasks.remove(&self.task_id);
+        }
+    }
+}
+
+fn cancel_node(node: &Rc<RefCell<Node>, LocalAlloc>) {
+    let (children, waiters, tasks) = {
+        let mut node = node.borrow_mut();
+        if node.cancelled {
+            return;
+        }
+        node.cancelled = true;
+        (
+            std::mem::replace(&mut node.children, Vec::new_in(LocalAlloc::new())),
+            std::mem::replace(&mut node.waiters, Vec::new_in(LocalAlloc::new())),
+            std::mem::replace(
+                &mut node.tasks,
+                KeyMap::with_capacity_in(0, LocalAlloc::new()),
+            ),
+        )
+    };
+
+    for task_id in waiters {
+        executor::notify_task(task_id);
+    }
+
+    CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+        if let Some(ctx) = ctx.as_mut() {
+            for &task_id in tasks.iter_keys() {
+                ctx.cancel_task_io(task_id);
+                ctx.notify(task_id);
+            }
+        }
+    });
+
+    for child in children {
+        if let Some(child) = child.upgrade() {
+            cancel_node(&child);
+        }
+    }
+}
+
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Cancelled<'token> {
+    token: &'token CancellationToken,
+}
+
+impl<'token> Future for Cancelled<'token> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut node = self.token.node.borrow_mut();
+        if node.cancelled {
+            Poll::Ready(())
+        } else {
+            let task_id = executor::current_task_id();
+            if !node.waiters.contains(&task_id) {
+                node.waiters.push(task_id);
+            }
+            Poll::Pending
+        }
+    }
+}
+
+/// Spawns a task that is tied to the given token. See [CancellationToken] for how cancellation is delivered.
+#[track_caller]
+pub fn spawn_with_token<T: 'static, F: Future<Output = T> + 'static>(
+    token: &CancellationToken,
+    future: F,
+) -> JoinHandle<T> {
+    let spawned_at = std::panic::Location::caller();
+    let future = token.track(future);
+    CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+        let ctx = ctx.as_mut().unwrap();
+        ctx.spawn_with_id(future, spawned_at).0
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::executor::{spawn, ExecutorConfig};
+    use crate::net::tcp::TcpListener;
+    use crate::time::sleep;
+
+    use super::*;
+
+    #[test]
+    fn test_cancellation_token() {
+        ExecutorConfig::new()
+            .run(async {
+                let token = CancellationToken::new();
+                let child = token.child_token();
+
+                let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+                let accept_token = child.clone();
+                let acceptor = spawn_with_token(&child, async move {
+                    // Nobody connects so this only returns because of the cancellation.
+                    let err = listener.accept().await.err().unwrap();
+                    assert!(accept_token.is_cancelled());
+                    err.raw_os_error()
+                });
+
+                let wait_token = child.child_token();
+                let waiter = spawn(async move {
+                    wait_token.cancelled().await;
+                    true
+                });
+
+                sleep(std::time::Duration::from_millis(5)).await;
+                assert!(!child.is_cancelled());
+                token.cancel();
+
+                assert_eq!(acceptor.await, Some(libc::ECANCELED));
+                assert!(waiter.await);
+                assert!(child.child_token().is_cancelled());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_finished_tasks_are_untracked() {
+        ExecutorConfig::new()
+            .run(async {
+                let token = CancellationToken::new();
+                let handles = (0..100)
+                    .map(|i| {
+                        spawn_with_token(&token, async move {
+                            sleep(std::time::Duration::from_millis(20)).await;
+                            i
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                sleep(std::time::Duration::from_millis(5)).await;
+                assert_eq!(token.node.borrow().tasks.len(), 100);
+                for (i, handle) in handles.into_iter().enumerate() {
+                    assert_eq!(handle.await, i);
+                }
+                assert!(token.node.borrow().tasks.is_empty());
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,205 @@
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::sync::Arc;
+use std::task::Wake;
+
+use io_uring::{opcode, types::Fd};
+
+use crate::executor::RawIo;
+
+/// A counter that other threads can add to and a task can wait on, backed by an eventfd.
+///
+/// This is for signals that come from outside of the executor, e.g. a thread pool finishing a job or a completion
+/// callback of a C library. [EventFd::write] never blocks and can be called from any thread, [EventFd::wait] waits
+/// through io_uring so the executor can sleep until the counter is non-zero. Wrapped in an `Arc` it can be turned
+/// into a [Waker](std::task::Waker) that adds one to the counter when it is woken.
+pub struct EventFd {
+    fd: OwnedFd,
+}
+
+impl EventFd {
+    pub fn new() -> io::Result<Self> {
+        Self::with_flags(0)
+    }
+
+    /// Creates an eventfd in semaphore mode, where [EventFd::wait] decrements the counter by one and returns 1
+    /// instead of taking the whole counter.
+    pub fn new_semaphore() -> io::Result<Self> {
+        Self::with_flags(libc::EFD_SEMAPHORE)
+    }
+
+    fn with_flags(flags: i32) -> io::Result<Self> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK | flags) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    /// Adds `n` to the counter.
+    ///
+    /// Fails with `WouldBlock` if the counter would overflow, which only happens if nobody is waiting on it.
+    pub fn write(&self, n: u64) -> io::Result<()> {
+        let res = unsafe {
+            libc::write(
+                self.fd.as_raw_fd(),
+                &n as *const u64 as *const libc::c_void,
+                8,
+            )
+        };
+        if res != 8 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Takes the value of the counter if it is non-zero, without waiting.
+    pub fn try_read(&self) -> io::Result<Option<u64>> {
+        let mut count = 0u64;
+        let res = unsafe {
+            libc::read(
+                self.fd.as_raw_fd(),
+                &mut count as *mut u64 as *mut libc::c_void,
+                8,
+            )
+        };
+        if res == 8 {
+            return Ok(Some(count));
+        }
+        let err = io::Error::last_os_error();
+        match err.kind() {
+            io::ErrorKind::WouldBlock => Ok(None),
+            _ => Err(err),
+        }
+    }
+
+    /// Waits until the counter is non-zero and takes its value, resetting it to zero.
+    pub async fn wait(&self) -> io::Result<u64> {
+        loop {
+            if let Some(count) = self.try_read()? {
+                return Ok(count);
+            }
+            // Poll doesn't point to any memory so it is fine if this future is dropped while it is in flight.
+            let res = unsafe {
+                RawIo::memory_free(
+                    opcode::PollAdd::new(Fd(self.fd.as_raw_fd()), libc::POLLIN as u32).build(),
+                )
+            }
+            .await;
+            if res < 0 {
+                return Err(io::Error::from_raw_os_error(-res));
+            }
+        }
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for EventFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl IntoRawFd for EventFd {
+    fn into_raw_fd(self) -> RawFd {
+        self.fd.into_raw_fd()
+    }
+}
+
+impl FromRawFd for EventFd {
+    /// `fd` has to be an eventfd with O_NONBLOCK set, [EventFd::wait] relies on reads not blocking.
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        Self {
+            fd: OwnedFd::from_raw_fd(fd),
+        }
+    }
+}
+
+impl TryFrom<OwnedFd> for EventFd {
+    type Error = io::Error;
+
+    /// Fails with `InvalidInput` if O_NONBLOCK isn't set on `fd`, [EventFd::wait] relies on reads not blocking.
+    fn try_from(fd: OwnedFd) -> io::Result<Self> {
+        let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFL) };
+        if flags == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if flags & libc::O_NONBLOCK == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "eventfd doesn't have O_NONBLOCK set",
+            ));
+        }
+        Ok(Self { fd })
+    }
+}
+
+impl From<EventFd> for OwnedFd {
+    fn from(efd: EventFd) -> Self {
+        efd.fd
+    }
+}
+
+impl Wake for EventFd {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        // Only fails if the counter would overflow, the waiter is woken already in that case.
+        let _ = self.write(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::executor::ExecutorConfig;
+
+    #[test]
+    fn test_event_fd() {
+        ExecutorConfig::new()
+            .run(async {
+                let efd = Arc::new(EventFd::new().unwrap());
+                assert_eq!(efd.try_read().unwrap(), None);
+                let thread = std::thread::spawn({
+                    let efd = efd.clone();
+                    move || {
+                        std::thread::sleep(Duration::from_millis(5));
+                        efd.write(2).unwrap();
+                        efd.write(3).unwrap();
+                    }
+                });
+                let mut total = 0;
+                while total < 5 {
+                    total += efd.wait().await.unwrap();
+                }
+                thread.join().unwrap();
+                assert_eq!(total, 5);
+
+                let sem = Arc::new(EventFd::new_semaphore().unwrap());
+                let waker = std::task::Waker::from(sem.clone());
+                waker.wake_by_ref();
+                waker.wake();
+                assert_eq!(sem.wait().await.unwrap(), 1);
+                assert_eq!(sem.wait().await.unwrap(), 1);
+                assert_eq!(sem.try_read().unwrap(), None);
+
+                let fd = OwnedFd::from(EventFd::new().unwrap());
+                assert!(EventFd::try_from(fd).is_ok());
+                let blocking = unsafe { OwnedFd::from_raw_fd(libc::eventfd(0, libc::EFD_CLOEXEC)) };
+                let err = EventFd::try_from(blocking).err().unwrap();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,212 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use crate::executor::{abort_task, current_task_id, CURRENT_TASK_CONTEXT};
+use crate::keymap::KeyMap;
+use crate::local_alloc::LocalAlloc;
+use crate::slab;
+use crate::sync::CancellationToken;
+
+// Results of finished tasks that weren't returned yet, with the ids of the tasks.
+type Finished<T> = Rc<RefCell<VecDeque<(slab::Key, T), LocalAlloc>>, LocalAlloc>;
+
+/// A group of tasks whose results are returned in the order they finish, e.g. the handlers of the connections of a
+/// server.
+///
+/// Tasks are tied to the [CancellationToken] of the set. [JoinSet::shutdown] cancels the token, which cancels the
+/// in-flight io of the remaining tasks, and waits for them to finish. Like with
+/// [spawn_with_token](crate::sync::cancellation::spawn_with_token), their futures aren't dropped then, they should
+/// check [JoinSet::token] and return early. Dropping the set aborts the remaining tasks instead: their io is cancelled
+/// and their futures are dropped once none of it is running in the kernel, without being polled again.
+///
+/// Tasks notify the task that spawned them when they finish, so [JoinSet::join_next] should be awaited by the task
+/// that owns the set.
+pub struct JoinSet<T> {
+    token: CancellationToken,
+    // Tasks whose result wasn't returned yet.
+    tasks: KeyMap<(), LocalAlloc>,
+    finished: Finished<T>,
+}
+
+impl<T: 'static> JoinSet<T> {
+    pub fn new() -> Self {
+        Self {
+            token: CancellationToken::new(),
+            tasks: KeyMap::with_capacity_in(16, LocalAlloc::new()),
+            finished: Rc::new_in(
+                RefCell::new(VecDeque::new_in(LocalAlloc::new())),
+                LocalAlloc::new(),
+            ),
+        }
+    }
+
+    /// Token that is cancelled when the set is dropped or shut down, tasks can wait on it or check it to stop early.
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+
+    /// Spawns a task into the set.
+    #[track_caller]
+    pub fn spawn<F: Future<Output = T> + 'static>(&mut self, future: F) {
+        let spawned_at = std::panic::Location::caller();
+        let finished = self.finished.clone();
+        let future = self.token.track(async move {
+            let out = future.await;
+            finished.borrow_mut().push_back((current_task_id(), out));
+        });
+        let (_, task_id) = CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+            let ctx = ctx.as_mut().unwrap();
+            ctx.spawn_with_id(future, spawned_at)
+        });
+        self.tasks.insert(task_id, ());
+    }
+
+    /// Number of tasks whose result wasn't returned yet.
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// Returns the result of the next task that finishes, `None` if the set is empty.
+    pub fn join_next(&mut self) -> JoinNext<'_, T> {
+        JoinNext { set: self }
+    }
+
+    /// Cancels the remaining tasks and waits for them to finish.
+    pub async fn shutdown(&mut self) {
+        self.token.cancel();
+        while self.join_next().await.is_some() {}
+    }
+}
+
+impl<T: 'static> Default for JoinSet<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for JoinSet<T> {
+    fn drop(&mut self) {
+        if self.tasks.is_empty() {
+            return;
+        }
+        self.token.cancel();
+        // Tasks that finished already are gone, aborting them does nothing.
+        for &task_id in self.tasks.iter_keys() {
+            abort_task(task_id);
+        }
+    }
+}
+
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct JoinNext<'set, T> {
+    set: &'set mut JoinSet<T>,
+}
+
+impl<T> Future for JoinNext<'_, T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Self::Output> {
+        let set = &mut *self.get_mut().set;
+        if set.tasks.is_empty() {
+            return Poll::Ready(None);
+        }
+        let next = set.finished.borrow_mut().pop_front();
+        match next {
+            Some((task_id, out)) => {
+                set.tasks.remove(&task_id);
+                Poll::Ready(Some(out))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::executor::ExecutorConfig;
+    use crate::net::tcp::TcpListener;
+    use crate::time::sleep;
+
+    #[test]
+    fn test_join_set() {
+        ExecutorConfig::new()
+            .run(async {
+                let mut set = JoinSet::new();
+                for i in [30u64, 10, 20] {
+                    set.spawn(async move {
+                        sleep(Duration::from_millis(i)).await;
+                        i
+                    });
+                }
+                assert_eq!(set.len(), 3);
+                let mut order = Vec::new();
+                while let Some(i) = set.join_next().await {
+                    order.push(i);
+                }
+                assert_eq!(order, [10, 20, 30]);
+
+                // Shutting the set down cancels the io of the tasks that are still running.
+                let cancelled = Rc::new(Cell::new(0));
+                let mut set = JoinSet::new();
+                for _ in 0..4 {
+                    let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+                    let token = set.token().clone();
+                    let cancelled = cancelled.clone();
+                    set.spawn(async move {
+                        // Nobody connects so this only returns because of the cancellation.
+                        let err = listener.accept().await.err().unwrap();
+                        assert!(token.is_cancelled());
+                        assert_eq!(err.raw_os_error(), Some(libc::ECANCELED));
+                        cancelled.set(cancelled.get() + 1);
+                    });
+                }
+                sleep(Duration::from_millis(5)).await;
+                set.shutdown().await;
+                assert_eq!(cancelled.get(), 4);
+                assert!(set.is_empty());
+
+                // Dropping the set drops the futures of the tasks, also of the ones that ignore the cancellation.
+                struct Dropped(Rc<Cell<u32>>);
+                impl Drop for Dropped {
+                    fn drop(&mut self) {
+                        self.0.set(self.0.get() + 1);
+                    }
+                }
+                let dropped = Rc::new(Cell::new(0));
+                let mut set = JoinSet::new();
+                for _ in 0..4 {
+                    let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+                    let guard = Dropped(dropped.clone());
+                    set.spawn(async move {
+                        let _guard = guard;
+                        loop {
+                            let _ = listener.accept().await;
+                        }
+                    });
+                }
+                let guard = Dropped(dropped.clone());
+                set.spawn(async move {
+                    let _guard = guard;
+                    sleep(Duration::from_secs(3600)).await;
+                });
+                sleep(Duration::from_millis(5)).await;
+                drop(set);
+                sleep(Duration::from_millis(5)).await;
+                assert_eq!(dropped.get(), 5);
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,9 @@
+pub mod cancellation;
+pub mod event_fd;
+pub mod join_set;
+pub mod semaphore;
+
+pub use cancellation::CancellationToken;
+pub use event_fd::EventFd;
+pub use join_set::JoinSet;
+pub use semaphore::{Semaphore, SemaphorePermit};
@@ -0,0 +1,184 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use crate::executor;
+use crate::local_alloc::LocalAlloc;
+use crate::slab;
+
+struct State {
+    permits: usize,
+    // Tasks waiting for a permit, in the order they started waiting.
+    waiters: VecDeque<slab::Key, LocalAlloc>,
+}
+
+impl State {
+    fn notify_front(&mut self) {
+        if self.permits > 0 {
+            if let Some(&task_id) = self.waiters.front() {
+                executor::notify_task(task_id);
+            }
+        }
+    }
+}
+
+/// Limits how many tasks can run a section at the same time, cloning it gives another handle to the same semaphore.
+///
+/// Permits are handed out in the order tasks started waiting for them.
+#[derive(Clone)]
+pub struct Semaphore {
+    state: Rc<RefCell<State>, LocalAlloc>,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        Self {
+            state: Rc::new_in(
+                RefCell::new(State {
+                    permits,
+                    waiters: VecDeque::new_in(LocalAlloc::new()),
+                }),
+                LocalAlloc::new(),
+            ),
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.state.borrow().permits
+    }
+
+    /// Waits for a permit, it is returned when the permit is dropped.
+    pub fn acquire(&self) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            registered: false,
+        }
+    }
+
+    /// Returns a permit if one is available and no task is waiting for one.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit> {
+        let mut state = self.state.borrow_mut();
+        if state.permits > 0 && state.waiters.is_empty() {
+            state.permits -= 1;
+            Some(self.permit())
+        } else {
+            None
+        }
+    }
+
+    fn permit(&self) -> SemaphorePermit {
+        SemaphorePermit {
+            semaphore: self.clone(),
+        }
+    }
+}
+
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Acquire<'semaphore> {
+    semaphore: &'semaphore Semaphore,
+    registered: bool,
+}
+
+impl<'semaphore> Future for Acquire<'semaphore> {
+    type Output = SemaphorePermit;
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let fut = self.get_mut();
+        let task_id = executor::current_task_id();
+        let mut state = fut.semaphore.state.borrow_mut();
+        let first = match state.waiters.front() {
+            Some(&front) => fut.registered && front == task_id,
+            None => true,
+        };
+        if state.permits > 0 && first {
+            state.permits -= 1;
+            if fut.registered {
+                state.waiters.pop_front();
+                fut.registered = false;
+                // There might be more permits for the next waiter.
+                state.notify_front();
+            }
+            return Poll::Ready(fut.semaphore.permit());
+        }
+        if !fut.registered {
+            fut.registered = true;
+            state.waiters.push_back(task_id);
+        }
+        Poll::Pending
+    }
+}
+
+impl<'semaphore> Drop for Acquire<'semaphore> {
+    fn drop(&mut self) {
+        if self.registered {
+            let mut state = self.semaphore.state.borrow_mut();
+            let task_id = executor::current_task_id();
+            state.waiters.retain(|&id| id != task_id);
+            state.notify_front();
+        }
+    }
+}
+
+/// A permit of a [Semaphore], it is released when this is dropped.
+pub struct SemaphorePermit {
+    semaphore: Semaphore,
+}
+
+impl Drop for SemaphorePermit {
+    fn drop(&mut self) {
+        let mut state = self.semaphore.state.borrow_mut();
+        state.permits += 1;
+        state.notify_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::executor::{spawn, ExecutorConfig};
+    use crate::time::sleep;
+
+    #[test]
+    fn test_semaphore() {
+        ExecutorConfig::new()
+            .run(async {
+                let semaphore = Semaphore::new(2);
+                let running = Rc::new(Cell::new(0));
+                let max_running = Rc::new(Cell::new(0));
+                let order = Rc::new(RefCell::new(Vec::new()));
+                let mut handles = Vec::new();
+                for i in 0..6 {
+                    let semaphore = semaphore.clone();
+                    let running = running.clone();
+                    let max_running = max_running.clone();
+                    let order = order.clone();
+                    handles.push(spawn(async move {
+                        let _permit = semaphore.acquire().await;
+                        order.borrow_mut().push(i);
+                        running.set(running.get() + 1);
+                        max_running.set(max_running.get().max(running.get()));
+                        sleep(std::time::Duration::from_millis(2)).await;
+                        running.set(running.get() - 1);
+                    }));
+                }
+                for handle in handles {
+                    handle.await;
+                }
+                assert_eq!(max_running.get(), 2);
+                assert_eq!(*order.borrow(), vec![0, 1, 2, 3, 4, 5]);
+                assert_eq!(semaphore.available_permits(), 2);
+
+                let permit = semaphore.try_acquire().unwrap();
+                let _other = semaphore.try_acquire().unwrap();
+                assert!(semaphore.try_acquire().is_none());
+                drop(permit);
+                assert!(semaphore.try_acquire().is_some());
+            })
+            .unwrap();
+    }
+}
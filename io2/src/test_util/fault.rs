@@ -0,0 +1,264 @@
+//! Fault injection for io operations.
+//!
+//! A [FaultPolicy] is a list of rules that are checked in order for every io operation queued on this thread. The first
+//! rule that matches the operation and fires decides the fault. This is intended for testing error handling and crash
+//! recovery of storage code built on io2, e.g. failing a percentage of writes to a data file or making reads of a
+//! specific file return less than requested.
+
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use io_uring::squeue;
+
+use crate::executor::is_read_write;
+
+use super::{set_entry_len, Intercept, IoInfo};
+
+thread_local! {
+    static POLICY: RefCell<Option<FaultPolicy>> = const { RefCell::new(None) };
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Fault {
+    /// Complete the operation with this errno without submitting it, e.g. `libc::EIO`.
+    Error(i32),
+    /// Submit the operation with its length capped to this many bytes.
+    ///
+    /// Only applies to read/write/send/recv operations, other operations don't match a rule with this fault.
+    Short(u32),
+    /// Submit the operation after this much time passes.
+    ///
+    /// This uses [crate::time::now] so it works with virtual time.
+    Latency(Duration),
+}
+
+#[derive(Clone, Debug)]
+pub struct FaultRule {
+    fault: Fault,
+    opcode: Option<u8>,
+    path: Option<PathBuf>,
+    probability: f64,
+    // Number of times the fault can still be injected, unlimited if None.
+    remaining: Option<u64>,
+}
+
+impl FaultRule {
+    /// Creates a rule that injects `fault` into every operation.
+    pub fn new(fault: Fault) -> Self {
+        Self {
+            fault,
+            opcode: None,
+            path: None,
+            probability: 1.0,
+            remaining: None,
+        }
+    }
+
+    /// Only match operations with this opcode, e.g. `io_uring::opcode::Write::CODE`.
+    pub fn opcode(mut self, opcode: u8) -> Self {
+        self.opcode = Some(opcode);
+        self
+    }
+
+    /// Only match operations on files under this path.
+    ///
+    /// The path of an operation is resolved from its fd so operations that open files by path aren't matched.
+    pub fn path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        let path = path.into();
+        // Paths read from /proc/self/fd are canonical.
+        self.path = Some(std::fs::canonicalize(&path).unwrap_or(path));
+        self
+    }
+
+    /// Inject the fault into a matching operation with this probability, between 0 and 1.
+    pub fn probability(mut self, probability: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&probability),
+            "probability must be between 0 and 1"
+        );
+        self.probability = probability;
+        self
+    }
+
+    /// Only inject the fault into the first `times` operations it fires for.
+    pub fn times(mut self, times: u64) -> Self {
+        self.remaining = Some(times);
+        self
+    }
+
+    fn matches(&self, info: &IoInfo, path: &mut Option<Option<PathBuf>>) -> bool {
+        if self.remaining == Some(0) {
+            return false;
+        }
+        if let Some(opcode) = self.opcode {
+            if info.opcode != opcode {
+                return false;
+            }
+        }
+        if let Fault::Short(_) = self.fault {
+            if !is_read_write(info.opcode) {
+                return false;
+            }
+        }
+        if let Some(prefix) = self.path.as_ref() {
+            match path.get_or_insert_with(|| info.path()) {
+                Some(path) if path.starts_with(prefix) => (),
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FaultPolicy {
+    rules: Vec<FaultRule>,
+    rng: u64,
+}
+
+impl FaultPolicy {
+    /// Creates an empty policy, `seed` makes the probabilistic rules deterministic.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rules: Vec::new(),
+            // xorshift state can't be zero.
+            rng: seed | 1,
+        }
+    }
+
+    pub fn rule(mut self, rule: FaultRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        // xorshift64*
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let x = self.rng.wrapping_mul(0x2545F4914F6CDD1D);
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn pick(&mut self, info: &IoInfo) -> Option<Fault> {
+        let mut path = None;
+        for i in 0..self.rules.len() {
+            if !self.rules[i].matches(info, &mut path) {
+                continue;
+            }
+            let probability = self.rules[i].probability;
+            if probability >= 1.0 || self.next_f64() < probability {
+                let rule = &mut self.rules[i];
+                if let Some(remaining) = rule.remaining.as_mut() {
+                    *remaining -= 1;
+                }
+                return Some(rule.fault);
+            }
+        }
+        None
+    }
+}
+
+/// Starts injecting faults into io operations queued on this thread according to `policy`.
+///
+/// Faults are injected until the returned guard is dropped. Operations that are mocked with [super::mock_io] are
+/// not affected.
+pub fn inject_faults(policy: FaultPolicy) -> FaultGuard {
+    POLICY.with_borrow_mut(|p| *p = Some(policy));
+    FaultGuard { _private: () }
+}
+
+pub struct FaultGuard {
+    _private: (),
+}
+
+impl Drop for FaultGuard {
+    fn drop(&mut self) {
+        POLICY.with_borrow_mut(|p| *p = None);
+    }
+}
+
+pub(crate) fn apply(mut entry: squeue::Entry, info: &IoInfo) -> Intercept {
+    let fault = POLICY.with_borrow_mut(|policy| policy.as_mut().and_then(|p| p.pick(info)));
+    match fault {
+        None => Intercept::Submit(entry),
+        Some(Fault::Error(errno)) => Intercept::Complete(-errno),
+        Some(Fault::Short(len)) => {
+            set_entry_len(&mut entry, info.len.min(len));
+            Intercept::Submit(entry)
+        }
+        Some(Fault::Latency(latency)) => Intercept::Delay(entry, crate::time::now() + latency),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::Instant;
+
+    use io_uring::opcode;
+
+    use crate::executor::ExecutorConfig;
+    use crate::fs::file::File;
+
+    use super::*;
+
+    #[test]
+    fn test_inject_faults() {
+        ExecutorConfig::new()
+            .run(async {
+                let file = File::open(Path::new("Cargo.toml"), libc::O_RDONLY, 0)
+                    .unwrap()
+                    .await
+                    .unwrap();
+                let mut buf = [0; 64];
+
+                let guard = inject_faults(
+                    FaultPolicy::new(0)
+                        .rule(
+                            FaultRule::new(Fault::Error(libc::EIO))
+                                .path("src")
+                                .opcode(opcode::Read::CODE),
+                        )
+                        .rule(FaultRule::new(Fault::Short(3)).path("Cargo.toml")),
+                );
+                assert_eq!(file.read(&mut buf, 0).await.unwrap(), 3);
+                std::mem::drop(guard);
+                assert_eq!(file.read(&mut buf, 0).await.unwrap(), 64);
+
+                let guard = inject_faults(
+                    FaultPolicy::new(0).rule(FaultRule::new(Fault::Error(libc::EIO)).times(1)),
+                );
+                assert_eq!(
+                    file.read(&mut buf, 0).await.unwrap_err().raw_os_error(),
+                    Some(libc::EIO)
+                );
+                assert_eq!(file.read(&mut buf, 0).await.unwrap(), 64);
+                std::mem::drop(guard);
+
+                let _guard = inject_faults(
+                    FaultPolicy::new(0)
+                        .rule(FaultRule::new(Fault::Latency(Duration::from_millis(20)))),
+                );
+                let start = Instant::now();
+                assert_eq!(file.read(&mut buf, 0).await.unwrap(), 64);
+                assert!(start.elapsed() >= Duration::from_millis(20));
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_fault_probability() {
+        let info = IoInfo {
+            opcode: opcode::Write::CODE,
+            fd: -1,
+            offset: 0,
+            len: 4096,
+        };
+        let mut policy =
+            FaultPolicy::new(42).rule(FaultRule::new(Fault::Error(libc::EIO)).probability(0.25));
+        let hits = (0..10000).filter(|_| policy.pick(&info).is_some()).count();
+        assert!((2000..3000).contains(&hits), "{}", hits);
+    }
+}
@@ -0,0 +1,149 @@
+//! Utilities for testing code that runs on io2.
+//!
+//! Virtual time is enabled with `ExecutorConfig::virtual_time` and driven with [crate::time::advance].
+
+pub mod fault;
+
+use std::cell::RefCell;
+use std::os::fd::RawFd;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use io_uring::squeue;
+
+pub use fault::{inject_faults, Fault, FaultGuard, FaultPolicy, FaultRule};
+
+/// Description of an io operation that is about to be submitted.
+#[derive(Clone, Copy, Debug)]
+pub struct IoInfo {
+    /// io_uring opcode, can be compared with `io_uring::opcode::*::CODE`.
+    pub opcode: u8,
+    pub fd: RawFd,
+    pub offset: u64,
+    pub len: u32,
+}
+
+impl IoInfo {
+    pub(crate) fn from_entry(entry: &squeue::Entry) -> Self {
+        // squeue::Entry is a repr(C) wrapper around io_uring_sqe but io-uring doesn't expose getters for these fields.
+        // Offsets are from the io_uring_sqe definition in linux/io_uring.h.
+        let sqe = unsafe {
+            std::slice::from_raw_parts(
+                entry as *const squeue::Entry as *const u8,
+                std::mem::size_of::<squeue::Entry>(),
+            )
+        };
+        Self {
+            opcode: sqe[0],
+            fd: RawFd::from_ne_bytes(sqe[4..8].try_into().unwrap()),
+            offset: u64::from_ne_bytes(sqe[8..16].try_into().unwrap()),
+            len: u32::from_ne_bytes(sqe[24..28].try_into().unwrap()),
+        }
+    }
+
+    /// Path of the file `fd` refers to, read from `/proc/self/fd`.
+    ///
+    /// Returns `None` if the fd doesn't refer to a file, e.g. it is a socket or the operation doesn't use an fd.
+    pub fn path(&self) -> Option<PathBuf> {
+        if self.fd < 0 {
+            return None;
+        }
+        let path = std::fs::read_link(format!("/proc/self/fd/{}", self.fd)).ok()?;
+        path.is_absolute().then_some(path)
+    }
+}
+
+fn set_entry_len(entry: &mut squeue::Entry, len: u32) {
+    let sqe = unsafe {
+        std::slice::from_raw_parts_mut(
+            entry as *mut squeue::Entry as *mut u8,
+            std::mem::size_of::<squeue::Entry>(),
+        )
+    };
+    sqe[24..28].copy_from_slice(&len.to_ne_bytes());
+}
+
+type IoHook = Box<dyn FnMut(&IoInfo) -> Option<i32>>;
+
+thread_local! {
+    static IO_HOOK: RefCell<Option<IoHook>> = const { RefCell::new(None) };
+}
+
+/// Installs a hook that is called for every io operation queued on this thread.
+///
+/// If the hook returns `Some(result)`, the operation isn't submitted to the kernel and it completes with `result`
+/// (negative errno for errors), otherwise it is submitted as usual. The hook is removed when the returned guard is dropped.
+///
+/// Mocked operations don't touch their buffers, so mocking successful reads only makes sense for code that
+/// doesn't look at the data.
+pub fn mock_io<F: FnMut(&IoInfo) -> Option<i32> + 'static>(hook: F) -> MockIoGuard {
+    IO_HOOK.with_borrow_mut(|h| *h = Some(Box::new(hook)));
+    MockIoGuard { _private: () }
+}
+
+pub struct MockIoGuard {
+    _private: (),
+}
+
+impl Drop for MockIoGuard {
+    fn drop(&mut self) {
+        IO_HOOK.with_borrow_mut(|h| *h = None);
+    }
+}
+
+/// What the executor should do with an operation that is being queued.
+pub(crate) enum Intercept {
+    Submit(squeue::Entry),
+    Complete(i32),
+    /// Submit the entry after the given time.
+    Delay(squeue::Entry, Instant),
+}
+
+pub(crate) fn intercept(entry: squeue::Entry) -> Intercept {
+    let info = IoInfo::from_entry(&entry);
+    let mocked = IO_HOOK.with_borrow_mut(|hook| match hook.as_mut() {
+        Some(hook) => hook(&info),
+        None => None,
+    });
+    match mocked {
+        Some(res) => Intercept::Complete(res),
+        None => fault::apply(entry, &info),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use io_uring::opcode;
+
+    use crate::executor::ExecutorConfig;
+    use crate::fs::file::File;
+
+    use super::*;
+
+    #[test]
+    fn test_mock_io() {
+        ExecutorConfig::new()
+            .run(async {
+                let file = File::open(Path::new("Cargo.toml"), libc::O_RDONLY, 0)
+                    .unwrap()
+                    .await
+                    .unwrap();
+
+                let _guard = mock_io(|info| {
+                    if info.opcode == opcode::Read::CODE && info.offset == 4096 {
+                        Some(-libc::EIO)
+                    } else {
+                        None
+                    }
+                });
+
+                let mut buf = [0; 16];
+                assert_eq!(file.read(&mut buf, 0).await.unwrap(), 16);
+                let err = file.read(&mut buf, 4096).await.unwrap_err();
+                assert_eq!(err.raw_os_error(), Some(libc::EIO));
+            })
+            .unwrap();
+    }
+}
@@ -5,11 +5,102 @@ use std::{
     time::{Duration, Instant},
 };
 
-use crate::executor::CURRENT_TASK_CONTEXT;
+use crate::executor::{TimerId, CURRENT_TASK_CONTEXT};
 
+#[cfg(feature = "test_util")]
+thread_local! {
+    static VIRTUAL_NOW: std::cell::Cell<Option<Instant>> = const { std::cell::Cell::new(None) };
+}
+
+/// Returns the current time as seen by the executor's timers.
+///
+/// This is the same as `Instant::now()` unless the executor is running with virtual time (`test_util` feature).
+#[inline]
+pub fn now() -> Instant {
+    #[cfg(feature = "test_util")]
+    if let Some(now) = VIRTUAL_NOW.with(|n| n.get()) {
+        return now;
+    }
+    Instant::now()
+}
+
+/// Moves virtual time forward, timers that expire in the meantime fire on the next iteration of the executor loop.
+///
+/// Panics if the executor on this thread isn't running with virtual time.
+#[cfg(feature = "test_util")]
+pub fn advance(duration: Duration) {
+    VIRTUAL_NOW.with(|n| {
+        let now = n
+            .get()
+            .expect("time::advance requires an executor running with ExecutorConfig::virtual_time");
+        n.set(Some(now.checked_add(duration).unwrap()));
+    });
+}
+
+#[cfg(feature = "test_util")]
+pub(crate) struct VirtualTimeGuard;
+
+#[cfg(feature = "test_util")]
+impl VirtualTimeGuard {
+    pub(crate) fn new() -> Self {
+        VIRTUAL_NOW.with(|n| n.set(Some(Instant::now())));
+        Self
+    }
+}
+
+#[cfg(feature = "test_util")]
+impl Drop for VirtualTimeGuard {
+    fn drop(&mut self) {
+        VIRTUAL_NOW.with(|n| n.set(None));
+    }
+}
+
+/// Future returned by [sleep] and [sleep_until], it can also be used as a timer handle.
+///
+/// The timer is removed from the executor when this is dropped.
 #[must_use = "futures do nothing unless you `.await` or poll them"]
 pub struct NotifyWhen {
-    timer: Option<Instant>,
+    deadline: Instant,
+    timer: Option<TimerId>,
+    cancelled: bool,
+}
+
+impl NotifyWhen {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Changes the deadline, this can be done while a task is waiting on the timer.
+    pub fn reset(&mut self, deadline: Instant) {
+        self.deadline = deadline;
+        self.cancelled = false;
+        if let Some(timer) = self.timer {
+            let registered = CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| match ctx.as_mut() {
+                Some(ctx) => ctx.reset_timer(timer, deadline),
+                None => false,
+            });
+            if !registered {
+                // Fired already, the waiting task is notified and registers the new deadline when it polls.
+                self.timer = None;
+            }
+        }
+    }
+
+    /// Stops the timer, a cancelled timer doesn't complete until it is reset.
+    pub fn cancel(&mut self) {
+        self.cancelled = true;
+        self.remove_timer();
+    }
+
+    fn remove_timer(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
+                if let Some(ctx) = ctx.as_mut() {
+                    ctx.cancel_timer(timer);
+                }
+            });
+        }
+    }
 }
 
 impl Future for NotifyWhen {
@@ -17,28 +108,44 @@ impl Future for NotifyWhen {
 
     fn poll(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Self::Output> {
         let fut = self.get_mut();
-        match fut.timer.take() {
-            Some(timer) => {
-                CURRENT_TASK_CONTEXT.with_borrow_mut(|ctx| {
-                    let ctx = ctx.as_mut().unwrap();
-                    ctx.notify_when(timer);
-                });
+        if fut.cancelled {
+            return Poll::Pending;
+        }
+        match fut.timer {
+            // Always yield on the first poll, even if the deadline already passed.
+            None => {
+                fut.timer = Some(
+                    CURRENT_TASK_CONTEXT
+                        .with_borrow_mut(|ctx| ctx.as_mut().unwrap().notify_when(fut.deadline)),
+                );
                 Poll::Pending
             }
-            None => Poll::Ready(()),
+            Some(_) if now() >= fut.deadline => {
+                fut.remove_timer();
+                Poll::Ready(())
+            }
+            // Polled because of something else, e.g. another future in the same task.
+            Some(_) => Poll::Pending,
         }
     }
 }
 
+impl Drop for NotifyWhen {
+    fn drop(&mut self) {
+        self.remove_timer();
+    }
+}
+
 pub fn sleep(duration: Duration) -> NotifyWhen {
-    let now = Instant::now();
-    let timer = now.checked_add(duration).unwrap();
-    NotifyWhen { timer: Some(timer) }
+    let now = now();
+    sleep_until(now.checked_add(duration).unwrap())
 }
 
 pub fn sleep_until(instant: Instant) -> NotifyWhen {
     NotifyWhen {
-        timer: Some(instant),
+        deadline: instant,
+        timer: None,
+        cancelled: false,
     }
 }
 
@@ -48,6 +155,80 @@ mod tests {
 
     use super::*;
 
+    #[cfg(feature = "test_util")]
+    #[test]
+    fn test_virtual_time() {
+        use std::{cell::Cell, rc::Rc};
+
+        use crate::executor::spawn;
+
+        let start = Instant::now();
+        ExecutorConfig::new()
+            .virtual_time()
+            .run(async {
+                let done = Rc::new(Cell::new(false));
+                let sleeper_done = done.clone();
+                let sleeper = spawn(async move {
+                    let start = now();
+                    sleep(Duration::from_secs(3600)).await;
+                    sleeper_done.set(true);
+                    now() - start
+                });
+                while !done.get() {
+                    advance(Duration::from_secs(600));
+                    // Let the sleeper run.
+                    sleep(Duration::ZERO).await;
+                }
+                let slept = sleeper.await;
+                assert!(slept >= Duration::from_secs(3600));
+                // This task can advance once more if it runs before the sleeper in the same tick.
+                assert!(slept <= Duration::from_secs(3600 + 2 * 600));
+            })
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_timer_reset_and_drop() {
+        ExecutorConfig::new()
+            .run(async {
+                let num_timers =
+                    || CURRENT_TASK_CONTEXT.with_borrow(|ctx| ctx.as_ref().unwrap().num_timers());
+
+                // Register the timer by polling it once.
+                let mut timer = sleep(Duration::from_secs(3600));
+                let mut polled = false;
+                std::future::poll_fn(|cx| {
+                    if polled {
+                        return Poll::Ready(());
+                    }
+                    polled = true;
+                    let _ = Pin::new(&mut timer).poll(cx);
+                    crate::executor::poll_next_tick();
+                    Poll::Pending
+                })
+                .await;
+                assert_eq!(num_timers(), 1);
+
+                let start = Instant::now();
+                timer.reset(now() + Duration::from_millis(5));
+                timer.await;
+                assert!(start.elapsed() < Duration::from_secs(1));
+                assert_eq!(num_timers(), 0);
+
+                let mut timer = sleep(Duration::from_secs(3600));
+                std::future::poll_fn(|cx| {
+                    let _ = Pin::new(&mut timer).poll(cx);
+                    Poll::Ready(())
+                })
+                .await;
+                assert_eq!(num_timers(), 1);
+                std::mem::drop(timer);
+                assert_eq!(num_timers(), 0);
+            })
+            .unwrap();
+    }
+
     #[test]
     #[ignore]
     fn test_sleep() {
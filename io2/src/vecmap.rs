@@ -3,6 +3,7 @@ use std::alloc::Allocator;
 pub struct VecMap<K: PartialEq, V, A: Allocator + Copy> {
     keys: Vec<K, A>,
     values: Vec<V, A>,
+    high_water_mark: usize,
 }
 
 impl<K: PartialEq, V, A: Allocator + Copy> VecMap<K, V, A> {
@@ -10,9 +11,34 @@ impl<K: PartialEq, V, A: Allocator + Copy> VecMap<K, V, A> {
         Self {
             keys: Vec::with_capacity_in(capacity, alloc),
             values: Vec::with_capacity_in(capacity, alloc),
+            high_water_mark: 0,
         }
     }
 
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Number of entries the map can hold without allocating.
+    pub fn capacity(&self) -> usize {
+        self.keys.capacity().min(self.values.capacity())
+    }
+
+    /// Largest number of entries the map held at the same time.
+    pub fn high_water_mark(&self) -> usize {
+        self.high_water_mark
+    }
+
+    /// Frees memory that isn't needed for the current entries, keeping space for at least `min_capacity` of them.
+    pub fn shrink_to(&mut self, min_capacity: usize) {
+        self.keys.shrink_to(min_capacity);
+        self.values.shrink_to(min_capacity);
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        self.shrink_to(0);
+    }
+
     pub fn clear(&mut self) {
         self.keys.clear();
         self.values.clear();
@@ -32,6 +58,7 @@ impl<K: PartialEq, V, A: Allocator + Copy> VecMap<K, V, A> {
 
         self.keys.push(key);
         self.values.push(value);
+        self.high_water_mark = self.high_water_mark.max(self.keys.len());
 
         None
     }
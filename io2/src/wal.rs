@@ -0,0 +1,789 @@
+//! Write-ahead log.
+//!
+//! Records are appended to segment files in a directory, each record on disk is:
+//!
+//! | length: u32 | crc32c of the length and the data: u32 | data |
+//!
+//! with integers in little endian. Records get consecutive sequence numbers starting from zero and each segment is
+//! named after the sequence number of its first record. A new segment is started when a record doesn't fit into the
+//! current one.
+//!
+//! Concurrent appends are committed together, the records that are queued while a write and fsync is running are
+//! written with the next one (group commit). Segments are written in whole blocks so they can be opened with O_DIRECT,
+//! the unused part of the last block is zero and a zeroed header marks the end of a segment.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use crate::block::{crc32c, crc32c_append};
+use crate::blocking::run_blocking;
+use crate::executor;
+use crate::fs::{remove_file, File};
+use crate::local_alloc::{alloc_aligned, LocalAlloc};
+use crate::slab;
+
+const HEADER_SIZE: usize = 8;
+// Write alignment when O_DIRECT is not used, matches the page size so rewriting the last block stays cheap.
+const BLOCK_SIZE: usize = 4096;
+const READ_SIZE: usize = 1024 * 1024;
+const SEGMENT_SUFFIX: &str = ".wal";
+
+#[derive(Clone)]
+pub struct WalConfig {
+    segment_size: u64,
+    preallocate: bool,
+    direct_io: bool,
+}
+
+impl WalConfig {
+    pub fn new() -> Self {
+        Self {
+            segment_size: 64 * 1024 * 1024,
+            preallocate: true,
+            direct_io: false,
+        }
+    }
+
+    /// Size after which a new segment is started, a record that is larger than this gets a segment of its own.
+    pub fn segment_size(mut self, segment_size: u64) -> Self {
+        assert!(segment_size > 0, "segment_size must be greater than zero");
+        self.segment_size = segment_size;
+        self
+    }
+
+    /// Allocates the whole segment with fallocate when it is created, so appends don't have to allocate blocks.
+    pub fn preallocate(mut self, preallocate: bool) -> Self {
+        self.preallocate = preallocate;
+        self
+    }
+
+    /// Opens segments with O_DIRECT so appends bypass the page cache.
+    pub fn direct_io(mut self, direct_io: bool) -> Self {
+        self.direct_io = direct_io;
+        self
+    }
+}
+
+impl Default for WalConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Records that are waiting to be written.
+struct Batch {
+    // Set if the batch starts a new segment, to the sequence number of its first record.
+    new_segment: Option<u64>,
+    data: Vec<u8, LocalAlloc>,
+}
+
+// The segment that is being appended to and the position in it, only the task that is flushing touches these.
+struct Tail {
+    file: File,
+    // Offset of the last block that is partially filled, everything before it is written.
+    block_offset: u64,
+    // Contents of the partial block, they are written again together with the next records.
+    block: Vec<u8, LocalAlloc>,
+}
+
+struct State {
+    dir: PathBuf,
+    config: WalConfig,
+    align: usize,
+    // First sequence numbers of the segments, in order.
+    segments: Vec<u64, LocalAlloc>,
+    next_seq: u64,
+    // Records below this are durable.
+    durable_seq: u64,
+    // Offset in the last segment where the next appended record goes, including records that are not written yet.
+    append_offset: u64,
+    pending: Vec<Batch, LocalAlloc>,
+    // Taken by the task that is flushing.
+    tail: Option<Tail>,
+    // Set after a write or fsync fails, the state of the log on disk is unknown after that so all appends fail.
+    failed: Option<(io::ErrorKind, String)>,
+    waiters: VecDeque<slab::Key, LocalAlloc>,
+}
+
+impl State {
+    fn check_failed(&self) -> io::Result<()> {
+        match &self.failed {
+            Some((kind, msg)) => Err(io::Error::new(
+                *kind,
+                format!("write-ahead log failed earlier: {}", msg),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A write-ahead log, cloning it gives another handle to the same log.
+#[derive(Clone)]
+pub struct Wal {
+    state: Rc<RefCell<State>, LocalAlloc>,
+}
+
+impl Wal {
+    /// Opens the log in `dir`, creating the directory and the first segment if they don't exist.
+    ///
+    /// The last segment is scanned to find where appending continues. A torn record at its end, left by a crash in the
+    /// middle of a write, is discarded.
+    pub async fn open(dir: &Path, config: WalConfig) -> io::Result<Self> {
+        let list_dir = dir.to_owned();
+        let mut segments = run_blocking(move || list_segments(&list_dir)).await??;
+
+        let (file, first_seq, end, num_records) = match segments.last() {
+            Some(&first_seq) => {
+                let path = segment_path(dir, first_seq);
+                let mut reader = SegmentReader::open(&path).await?;
+                let mut num_records = 0;
+                // A corrupted record ends the scan, it is the result of a torn write.
+                while let Ok(Some(_)) = reader.next_record().await {
+                    num_records += 1;
+                }
+                let end = reader.record_offset();
+                reader.close().await?;
+                let file = open_segment(&path, 0, config.direct_io).await?;
+                (file, first_seq, end, num_records)
+            }
+            None => {
+                segments.push(0);
+                let file = create_segment(dir, 0, &config).await?;
+                (file, 0, 0, 0)
+            }
+        };
+
+        let align = segment_alignment(&file, config.direct_io).await?;
+        let block_offset = end / align as u64 * align as u64;
+        let mut block = Vec::new_in(LocalAlloc::new());
+        if end > block_offset {
+            let mut buf = alloc_aligned(align, align).unwrap();
+            file.read_exact(buf.as_mut_slice(), block_offset).await?;
+            block
+                .extend_from_slice(&buf.as_slice()[..usize::try_from(end - block_offset).unwrap()]);
+        }
+
+        // Drop whatever follows the last valid record, so a torn write can't be mistaken for records later.
+        let zero_from = block_offset + if end > block_offset { align as u64 } else { 0 };
+        if file.file_size().await? > zero_from {
+            file.set_len(zero_from).await?;
+            if config.preallocate {
+                preallocate(&file, config.segment_size).await?;
+            }
+            file.sync_all().await?;
+        }
+
+        let next_seq = first_seq + num_records;
+        let mut segments_local = Vec::with_capacity_in(segments.len(), LocalAlloc::new());
+        segments_local.extend_from_slice(&segments);
+        Ok(Self {
+            state: Rc::new_in(
+                RefCell::new(State {
+                    dir: dir.to_owned(),
+                    config,
+                    align,
+                    segments: segments_local,
+                    next_seq,
+                    durable_seq: next_seq,
+                    append_offset: end,
+                    pending: Vec::new_in(LocalAlloc::new()),
+                    tail: Some(Tail {
+                        file,
+                        block_offset,
+                        block,
+                    }),
+                    failed: None,
+                    waiters: VecDeque::new_in(LocalAlloc::new()),
+                }),
+                LocalAlloc::new(),
+            ),
+        })
+    }
+
+    /// Sequence number the next appended record gets.
+    pub fn next_seq(&self) -> u64 {
+        self.state.borrow().next_seq
+    }
+
+    /// Sequence number below which all records are durable.
+    pub fn durable_seq(&self) -> u64 {
+        self.state.borrow().durable_seq
+    }
+
+    /// Appends a record and waits until it is durable, returns its sequence number.
+    ///
+    /// If a write or fsync fails the error is returned to every waiting append and the log doesn't accept appends
+    /// after that, it has to be opened again.
+    pub async fn append(&self, record: &[u8]) -> io::Result<u64> {
+        let seq = self.append_buffered(record)?;
+        self.wait_durable(seq).await?;
+        Ok(seq)
+    }
+
+    /// Queues a record without waiting for it to be written, returns its sequence number.
+    ///
+    /// The record is written by the next [Wal::append] or [Wal::sync], it is lost if neither is called.
+    pub fn append_buffered(&self, record: &[u8]) -> io::Result<u64> {
+        let len = u32::try_from(record.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "record is too large"))?;
+        let mut state = self.state.borrow_mut();
+        state.check_failed()?;
+        let seq = state.next_seq;
+        state.next_seq += 1;
+
+        let size = (HEADER_SIZE + record.len()) as u64;
+        let new_segment =
+            state.append_offset > 0 && state.append_offset + size > state.config.segment_size;
+        if new_segment {
+            state.append_offset = 0;
+        }
+        state.append_offset += size;
+        if new_segment || state.pending.is_empty() {
+            state.pending.push(Batch {
+                new_segment: new_segment.then_some(seq),
+                data: Vec::new_in(LocalAlloc::new()),
+            });
+        }
+        let data = &mut state.pending.last_mut().unwrap().data;
+        let crc = crc32c_append(crc32c(&len.to_le_bytes()), record);
+        data.extend_from_slice(&len.to_le_bytes());
+        data.extend_from_slice(&crc.to_le_bytes());
+        data.extend_from_slice(record);
+        Ok(seq)
+    }
+
+    /// Waits until all appended records, including the ones queued with [Wal::append_buffered], are durable.
+    pub async fn sync(&self) -> io::Result<()> {
+        match self.next_seq() {
+            0 => Ok(()),
+            next_seq => self.wait_durable(next_seq - 1).await,
+        }
+    }
+
+    async fn wait_durable(&self, seq: u64) -> io::Result<()> {
+        loop {
+            let tail = {
+                let mut state = self.state.borrow_mut();
+                if state.durable_seq > seq {
+                    return Ok(());
+                }
+                state.check_failed()?;
+                state.tail.take()
+            };
+            match tail {
+                Some(tail) => self.flush(tail).await,
+                None => {
+                    WaitForFlush {
+                        wal: self,
+                        registered: false,
+                    }
+                    .await
+                }
+            }
+        }
+    }
+
+    // Writes and syncs everything that is pending, then wakes the appends that were waiting for it.
+    async fn flush(&self, tail: Tail) {
+        let (batches, upto, dir, config, align) = {
+            let mut state = self.state.borrow_mut();
+            (
+                std::mem::replace(&mut state.pending, Vec::new_in(LocalAlloc::new())),
+                state.next_seq,
+                state.dir.clone(),
+                state.config.clone(),
+                state.align,
+            )
+        };
+
+        let mut guard = FlushGuard {
+            wal: self,
+            tail: Some(tail),
+            batches,
+            written: 0,
+        };
+        let mut res = Ok(());
+        while guard.written < guard.batches.len() {
+            let FlushGuard {
+                tail,
+                batches,
+                written,
+                ..
+            } = &mut guard;
+            res = self
+                .write_batch(
+                    tail.as_mut().unwrap(),
+                    &mut batches[*written],
+                    &dir,
+                    &config,
+                    align,
+                )
+                .await;
+            if res.is_err() {
+                break;
+            }
+            guard.written += 1;
+        }
+        if res.is_ok() {
+            res = guard.tail.as_ref().unwrap().file.sync_all().await;
+        }
+
+        let mut state = self.state.borrow_mut();
+        match res {
+            Ok(()) => state.durable_seq = upto,
+            Err(e) => state.failed = Some((e.kind(), e.to_string())),
+        }
+        drop(state);
+        drop(guard);
+    }
+
+    async fn write_batch(
+        &self,
+        tail: &mut Tail,
+        batch: &mut Batch,
+        dir: &Path,
+        config: &WalConfig,
+        align: usize,
+    ) -> io::Result<()> {
+        if let Some(first_seq) = batch.new_segment {
+            // Records of the previous segment have to be durable before any record of the new one.
+            tail.file.sync_all().await?;
+            let file = create_segment(dir, first_seq, config).await?;
+            *tail = Tail {
+                file,
+                block_offset: 0,
+                block: Vec::new_in(LocalAlloc::new()),
+            };
+            self.state.borrow_mut().segments.push(first_seq);
+            // The segment exists now, writing the batch again after a cancelled flush appends to it.
+            batch.new_segment = None;
+        }
+
+        let len = tail.block.len() + batch.data.len();
+        let mut buf = alloc_aligned(len.div_ceil(align) * align, align).unwrap();
+        buf.as_mut_slice()[..tail.block.len()].copy_from_slice(&tail.block);
+        buf.as_mut_slice()[tail.block.len()..len].copy_from_slice(&batch.data);
+        tail.file
+            .write_all(buf.as_slice(), tail.block_offset)
+            .await?;
+
+        let full = len / align * align;
+        tail.block.clear();
+        tail.block.extend_from_slice(&buf.as_slice()[full..len]);
+        tail.block_offset += full as u64;
+        Ok(())
+    }
+
+    /// Returns the records starting from `from_seq`.
+    ///
+    /// Records that are appended while replaying might or might not be returned.
+    pub fn replay(&self, from_seq: u64) -> Replay {
+        let state = self.state.borrow();
+        // Start from the last segment that begins at or before from_seq.
+        let start = state
+            .segments
+            .partition_point(|&first_seq| first_seq <= from_seq)
+            .saturating_sub(1);
+        Replay {
+            dir: state.dir.clone(),
+            segments: state.segments[start..].to_vec(),
+            from_seq,
+            next_seq: 0,
+            reader: None,
+        }
+    }
+
+    /// Removes segments that only hold records below `seq`, e.g. after a checkpoint made them unnecessary.
+    pub async fn remove_before(&self, seq: u64) -> io::Result<()> {
+        let (dir, to_remove) = {
+            let state = self.state.borrow();
+            // A segment can be removed if the next one starts at or before seq, the last segment is never removed.
+            let n = state
+                .segments
+                .partition_point(|&first_seq| first_seq <= seq);
+            let n = n.saturating_sub(1);
+            (state.dir.clone(), state.segments[..n].to_vec())
+        };
+        if to_remove.is_empty() {
+            return Ok(());
+        }
+        for &first_seq in to_remove.iter() {
+            remove_file(&segment_path(&dir, first_seq))?.await?;
+        }
+        self.state
+            .borrow_mut()
+            .segments
+            .retain(|first_seq| !to_remove.contains(first_seq));
+        sync_dir(&dir).await
+    }
+}
+
+/// Iterates over the records of a [Wal], see [Wal::replay].
+pub struct Replay {
+    dir: PathBuf,
+    segments: Vec<u64>,
+    from_seq: u64,
+    // Sequence number of the next record the reader returns.
+    next_seq: u64,
+    reader: Option<SegmentReader>,
+}
+
+impl Replay {
+    /// Returns the next record and its sequence number, `None` after the last record.
+    ///
+    /// A corrupted record in a segment other than the last one is returned as an `InvalidData` error. In the last
+    /// segment it is treated as the end of the log since it is the result of a torn write.
+    pub async fn next(&mut self) -> Option<io::Result<(u64, &[u8])>> {
+        loop {
+            if self.reader.is_none() {
+                if self.segments.is_empty() {
+                    return None;
+                }
+                let first_seq = self.segments.remove(0);
+                match SegmentReader::open(&segment_path(&self.dir, first_seq)).await {
+                    Ok(reader) => self.reader = Some(reader),
+                    Err(e) => {
+                        self.segments.clear();
+                        return Some(Err(e));
+                    }
+                }
+                self.next_seq = first_seq;
+            }
+
+            let is_last = self.segments.is_empty();
+            let reader = self.reader.as_mut().unwrap();
+            let record = match reader.next_record().await {
+                Ok(Some(record)) => record,
+                Ok(None) => {
+                    self.reader = None;
+                    continue;
+                }
+                Err(_) if is_last => {
+                    self.reader = None;
+                    continue;
+                }
+                Err(e) => {
+                    self.reader = None;
+                    self.segments.clear();
+                    return Some(Err(e));
+                }
+            };
+            let seq = self.next_seq;
+            self.next_seq += 1;
+            if seq >= self.from_seq {
+                return Some(Ok((seq, &self.reader.as_ref().unwrap().buf[record])));
+            }
+        }
+    }
+}
+
+// Reads the records of a segment with large buffered reads.
+struct SegmentReader {
+    file: File,
+    // File offset of buf[end].
+    file_offset: u64,
+    buf: Vec<u8, LocalAlloc>,
+    // Range of buf that holds data that is read but not consumed yet.
+    start: usize,
+    end: usize,
+    // Size of the record that was lent out by the last call to next_record, it is consumed on the next call.
+    lent: usize,
+    eof: bool,
+}
+
+impl SegmentReader {
+    async fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path, libc::O_RDONLY | libc::O_CLOEXEC, 0)?.await?;
+        let mut buf = Vec::new_in(LocalAlloc::new());
+        buf.resize(READ_SIZE, 0);
+        Ok(Self {
+            file,
+            file_offset: 0,
+            buf,
+            start: 0,
+            end: 0,
+            lent: 0,
+            eof: false,
+        })
+    }
+
+    // Offset of the record that the next call to next_record reads.
+    fn record_offset(&self) -> u64 {
+        self.file_offset - (self.end - self.start - self.lent) as u64
+    }
+
+    // Returns the range of buf that holds the next record, `None` at the end of the segment.
+    async fn next_record(&mut self) -> io::Result<Option<std::ops::Range<usize>>> {
+        self.start += std::mem::take(&mut self.lent);
+        if !self.fill(HEADER_SIZE).await? {
+            return Ok(None);
+        }
+        let header = &self.buf[self.start..self.start + HEADER_SIZE];
+        let len_bytes: [u8; 4] = header[..4].try_into().unwrap();
+        let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+        if len_bytes == [0; 4] && crc == 0 {
+            return Ok(None);
+        }
+        let len = usize::try_from(u32::from_le_bytes(len_bytes)).unwrap();
+        if !self.fill(HEADER_SIZE + len).await? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "write-ahead log segment ends in the middle of a record",
+            ));
+        }
+        let record = self.start + HEADER_SIZE..self.start + HEADER_SIZE + len;
+        if crc32c_append(crc32c(&len_bytes), &self.buf[record.clone()]) != crc {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "checksum mismatch in write-ahead log record",
+            ));
+        }
+        self.lent = HEADER_SIZE + len;
+        Ok(Some(record))
+    }
+
+    // Reads until `needed` bytes are buffered, returns false if the segment ends before that.
+    async fn fill(&mut self, needed: usize) -> io::Result<bool> {
+        while self.end - self.start < needed {
+            if self.eof {
+                return Ok(false);
+            }
+            self.buf.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+            if self.buf.len() < needed.max(READ_SIZE) {
+                self.buf.resize(needed.max(READ_SIZE), 0);
+            }
+            let n = self
+                .file
+                .read(&mut self.buf[self.end..], self.file_offset)
+                .await?;
+            if n == 0 {
+                self.eof = true;
+            }
+            self.end += n;
+            self.file_offset += n as u64;
+        }
+        Ok(true)
+    }
+
+    async fn close(self) -> io::Result<()> {
+        self.file.close().await
+    }
+}
+
+// Gives the tail back if the task that is flushing is dropped, so another task can flush, and wakes the waiting tasks.
+// Batches that weren't written are put back in front of the pending ones.
+struct FlushGuard<'wal> {
+    wal: &'wal Wal,
+    tail: Option<Tail>,
+    batches: Vec<Batch, LocalAlloc>,
+    // Number of batches that are completely written.
+    written: usize,
+}
+
+impl Drop for FlushGuard<'_> {
+    fn drop(&mut self) {
+        let mut state = self.wal.state.borrow_mut();
+        if self.written < self.batches.len() {
+            let mut batches = std::mem::replace(&mut self.batches, Vec::new_in(LocalAlloc::new()));
+            batches.drain(..self.written);
+            batches.append(&mut state.pending);
+            state.pending = batches;
+        }
+        state.tail = self.tail.take();
+        for task_id in state.waiters.drain(..) {
+            executor::notify_task(task_id);
+        }
+    }
+}
+
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+struct WaitForFlush<'wal> {
+    wal: &'wal Wal,
+    registered: bool,
+}
+
+impl<'wal> Future for WaitForFlush<'wal> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let fut = self.get_mut();
+        let task_id = executor::current_task_id();
+        let mut state = fut.wal.state.borrow_mut();
+        if fut.registered {
+            state.waiters.retain(|&id| id != task_id);
+            return Poll::Ready(());
+        }
+        fut.registered = true;
+        state.waiters.push_back(task_id);
+        Poll::Pending
+    }
+}
+
+fn segment_path(dir: &Path, first_seq: u64) -> PathBuf {
+    dir.join(format!("{:020}{}", first_seq, SEGMENT_SUFFIX))
+}
+
+fn list_segments(dir: &Path) -> io::Result<Vec<u64>> {
+    std::fs::create_dir_all(dir)?;
+    let mut segments = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let first_seq = name
+            .to_str()
+            .and_then(|name| name.strip_suffix(SEGMENT_SUFFIX))
+            .and_then(|seq| seq.parse::<u64>().ok());
+        if let Some(first_seq) = first_seq {
+            segments.push(first_seq);
+        }
+    }
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+async fn open_segment(path: &Path, extra_flags: i32, direct_io: bool) -> io::Result<File> {
+    let mut flags = libc::O_RDWR | libc::O_CLOEXEC | extra_flags;
+    if direct_io {
+        flags |= libc::O_DIRECT;
+    }
+    // openat2 rejects a mode without O_CREAT.
+    let mode = if flags & libc::O_CREAT != 0 { 0o644 } else { 0 };
+    File::open(path, flags, mode)?.await
+}
+
+async fn create_segment(dir: &Path, first_seq: u64, config: &WalConfig) -> io::Result<File> {
+    let file = open_segment(
+        &segment_path(dir, first_seq),
+        libc::O_CREAT | libc::O_EXCL,
+        config.direct_io,
+    )
+    .await?;
+    if config.preallocate {
+        preallocate(&file, config.segment_size).await?;
+    }
+    file.sync_all().await?;
+    sync_dir(dir).await?;
+    Ok(file)
+}
+
+async fn preallocate(file: &File, segment_size: u64) -> io::Result<()> {
+    match file.allocate(0, segment_size).await {
+        // Not every filesystem supports fallocate, the segment is allocated while writing then.
+        Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+        res => res,
+    }
+}
+
+async fn segment_alignment(file: &File, direct_io: bool) -> io::Result<usize> {
+    if !direct_io {
+        return Ok(BLOCK_SIZE);
+    }
+    let statx = file.statx().await?;
+    if statx.stx_dio_mem_align == 0 || statx.stx_dio_offset_align == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "direct_io is not supported on the write-ahead log directory",
+        ));
+    }
+    let align = statx.stx_dio_mem_align.max(statx.stx_dio_offset_align);
+    Ok(usize::try_from(align).unwrap().max(BLOCK_SIZE))
+}
+
+async fn sync_dir(dir: &Path) -> io::Result<()> {
+    let dir = File::open(dir, libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC, 0)?.await?;
+    dir.sync_all().await?;
+    dir.close().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Seek, SeekFrom, Write};
+
+    use super::*;
+    use crate::executor::{spawn, ExecutorConfig};
+
+    #[test]
+    fn test_wal() {
+        let dir = std::env::temp_dir().join(format!("io2_test_wal_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let test_dir = dir.clone();
+        ExecutorConfig::new()
+            .run(async move {
+                let dir = test_dir;
+                let config = WalConfig::new().segment_size(3 * BLOCK_SIZE as u64);
+                let wal = Wal::open(&dir, config.clone()).await.unwrap();
+
+                // Concurrent appends are committed in groups and rotate through several segments.
+                let mut handles = Vec::new();
+                for task in 0..10u8 {
+                    let wal = wal.clone();
+                    handles.push(spawn(async move {
+                        let mut seqs = Vec::new();
+                        for i in 0..20u8 {
+                            let record = vec![task; 200 + usize::from(i)];
+                            seqs.push(wal.append(&record).await.unwrap());
+                        }
+                        seqs
+                    }));
+                }
+                let mut seqs = Vec::new();
+                for handle in handles {
+                    seqs.extend(handle.await);
+                }
+                seqs.sort_unstable();
+                assert_eq!(seqs, (0..200).collect::<Vec<u64>>());
+                let num_segments = list_segments(&dir).unwrap().len();
+                assert!(num_segments > 2);
+                drop(wal);
+
+                // A torn record after the last one is discarded when opening.
+                let last = *list_segments(&dir).unwrap().last().unwrap();
+                let wal = Wal::open(&dir, config.clone()).await.unwrap();
+                let end = wal.state.borrow().append_offset;
+                drop(wal);
+                let mut file = std::fs::OpenOptions::new()
+                    .write(true)
+                    .open(segment_path(&dir, last))
+                    .unwrap();
+                file.seek(SeekFrom::Start(end)).unwrap();
+                file.write_all(&[50, 0, 0, 0, 1, 2, 3, 4, 5]).unwrap();
+                drop(file);
+
+                let wal = Wal::open(&dir, config).await.unwrap();
+                assert_eq!(wal.next_seq(), 200);
+                assert_eq!(wal.append(b"last").await.unwrap(), 200);
+
+                let mut replay = wal.replay(0);
+                let mut count = 0;
+                while let Some(res) = replay.next().await {
+                    let (seq, record) = res.unwrap();
+                    assert_eq!(seq, count);
+                    if seq == 200 {
+                        assert_eq!(record, b"last");
+                    } else {
+                        assert!(record.len() >= 200 && record.iter().all(|&b| b == record[0]));
+                    }
+                    count += 1;
+                }
+                assert_eq!(count, 201);
+
+                let mut replay = wal.replay(150);
+                assert_eq!(replay.next().await.unwrap().unwrap().0, 150);
+
+                wal.remove_before(150).await.unwrap();
+                assert!(list_segments(&dir).unwrap().len() < num_segments);
+                let mut replay = wal.replay(150);
+                assert_eq!(replay.next().await.unwrap().unwrap().0, 150);
+            })
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}